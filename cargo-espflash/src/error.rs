@@ -10,6 +10,13 @@ use thiserror::Error;
 #[derive(Debug, Diagnostic, Error)]
 #[non_exhaustive]
 pub enum Error {
+    #[error("Failed to build the application")]
+    #[diagnostic(
+        code(cargo_espflash::build_failed),
+        help("See the output of `cargo build` above for the cause")
+    )]
+    BuildFailed,
+
     #[error("The current workspace is invalid, and could not be loaded")]
     #[diagnostic(
         code(cargo_espflash::invalid_workspace),
@@ -1,26 +1,58 @@
-use std::{
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
-};
+use std::{fs, net::SocketAddr, path::PathBuf};
 
-use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
 use espflash::{
     cli::{
-        self, board_info, checksum_md5, completions, config::Config, connect, erase_flash,
-        erase_partitions, erase_region, flash_elf_image, make_flash_data, monitor::monitor,
-        parse_uint32, partition_table, print_board_info, read_flash, save_elf_as_image,
-        serial_monitor, ChecksumMd5Args, CompletionsArgs, ConnectArgs, EraseFlashArgs,
-        EraseRegionArgs, EspflashProgress, FlashConfigArgs, MonitorArgs, PartitionTableArgs,
-        ReadFlashArgs,
+        self, activate_ota_partition, app_info, board_info, check_elf_image, check_embedded_flash,
+        check_image_digest, check_update, checksum_md5, completions,
+        config::Config,
+        connect,
+        coredump::{coredump, CoreDumpArgs},
+        dry_run_elf_image,
+        efuse::{efuse, EfuseArgs},
+        encryption::{encryption, EncryptionArgs},
+        erase_flash, erase_partitions, erase_region,
+        events::{emit_finished, parse_event_server_addr, start_event_server},
+        exit_code::ExitCode,
+        factory_reset,
+        filesystem::{filesystem, FsArgs},
+        flash_elf_image, flash_idf_build,
+        hooks::FlashHooks,
+        idf::IdfBuild,
+        image_info, make_flash_data, make_flash_settings,
+        monitor::monitor,
+        nvs::{nvs, NvsArgs},
+        ota::{ota, OtaArgs},
+        ota_activation_target,
+        output::{
+            set_output_format, set_progress_display, set_verbosity, OutputFormat, ProgressDisplay,
+            Verbosity,
+        },
+        parse_uint32, partition_table, print_board_info,
+        provision::{production_images, provision, ProductionImagesArgs, ProvisionArgs},
+        read_flash, read_sfdp,
+        report::{run_with_report, verify_report},
+        resolve_auto_ota,
+        rf_calibration::{rf_calibration, RfCalibrationArgs},
+        save_elf_as_image,
+        script::{run_script, RunScriptArgs},
+        serial_monitor, serve,
+        shell::{shell, ShellArgs},
+        stub::{stub, StubArgs},
+        verify_signature, write_pre_encrypted, AppInfoArgs, CheckUpdateArgs, ChecksumMd5Args,
+        CompletionsArgs, ConnectArgs, EraseFlashArgs, EraseRegionArgs, EspflashProgress,
+        FactoryResetArgs, FlashConfigArgs, ImageInfoArgs, MonitorArgs, PartitionTableArgs,
+        ReadFlashArgs, ServeArgs, VerifySignatureArgs,
     },
     error::Error,
-    flasher::parse_partition_table,
+    file_format::load_segments,
+    flasher::{parse_partition_table, RetryPolicy},
+    image_format::update_bootloader_header,
     logging::initialize_logger,
-    targets::{Chip, XtalFrequency},
+    targets::XtalFrequency,
     update::check_for_update,
 };
-use log::{debug, info, LevelFilter};
+use log::{debug, info};
 use miette::{IntoDiagnostic, Result, WrapErr};
 
 #[derive(Debug, Parser)]
@@ -28,15 +60,64 @@ use miette::{IntoDiagnostic, Result, WrapErr};
 pub struct Cli {
     #[command(subcommand)]
     subcommand: Commands,
+    /// Format of the information printed by commands such as `board-info`,
+    /// `image-info` and `partition-table`
+    ///
+    /// With `json`, stdout only holds JSON, and logs and other text are written
+    /// to stderr.
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+    /// Only print warnings, errors and the information requested, leaving out
+    /// progress bars and the information printed while flashing
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print debug logs, or with `-vv` trace logs and every frame of the serial
+    /// protocol
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// How the progress of writing to flash is shown
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = ProgressDisplay::Bar,
+        env = "ESPFLASH_PROGRESS"
+    )]
+    progress: ProgressDisplay,
+    /// Do not show the progress of writing to flash
+    #[arg(long, global = true)]
+    no_progress: bool,
+    /// Broadcast progress, status and monitor events over a WebSocket on the
+    /// given address, or on the given port of the loopback interface
+    ///
+    /// The address listened on is printed to stderr, a port of 0 being chosen
+    /// by the system.
+    #[arg(long, global = true, value_name = "ADDR", value_parser = parse_event_server_addr)]
+    event_server: Option<SocketAddr>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Print the description of the app on a connected target device
+    ///
+    /// Reads the application description embedded by ESP-IDF in the app
+    /// booted by the bootloader, or in the app partition given with
+    /// '--partition', and prints its project name, version, build time,
+    /// ESP-IDF version and the SHA256 digest of its ELF file. Only the
+    /// headers of the app are read.
+    AppInfo(AppInfoArgs),
     /// Print information about a connected target device
     ///
     /// Automatically detects and prints the chip type, crystal frequency, flash
     /// size, chip features, and MAC address of a connected target device.
     BoardInfo(ConnectArgs),
+    /// Check whether a connected target device is running an application
+    ///
+    /// Compares the app booted by the bootloader, or the one in the app
+    /// partition given with '--partition', with an ELF file or application
+    /// image, without writing to the device. Exits with status 0 if the device
+    /// is running the application, and 8 if it needs to be updated.
+    CheckUpdate(CheckUpdateArgs),
     /// Generate completions for the given shell
     ///
     /// The completions are printed to stdout, and can be redirected as needed.
@@ -44,12 +125,40 @@ enum Commands {
     /// depending on which shell is being used; consult your shell's
     /// documentation to determine the appropriate path.
     Completions(CompletionsArgs),
+    /// Read and convert core dumps
+    ///
+    /// 'coredump read' reads the core dump saved by a crashed application from
+    /// the `coredump` partition of the connected target device, and 'coredump
+    /// convert' converts a core dump which was already read. Both produce an
+    /// ELF core file, which GDB loads along with the ELF file of the
+    /// application.
+    Coredump(CoreDumpArgs),
+    /// Read and burn eFuses
+    ///
+    /// 'efuse burn-key' burns a key with a given purpose to a key block of the
+    /// connected target device, and 'efuse burn-custom-mac' burns a custom MAC
+    /// address, which 'efuse read-mac' prints along with the factory one.
+    /// 'efuse read-protect' and 'efuse write-protect' protect eFuse blocks.
+    /// Burning eFuses cannot be undone.
+    Efuse(EfuseArgs),
+    /// Prepare devices for flash encryption
+    ///
+    /// 'encryption prepare' burns a flash encryption key into the eFuses of
+    /// the connected target device and enables flash encryption, optionally
+    /// writing an encrypted merged image first. Burning eFuses cannot be
+    /// undone; keep the saved key to encrypt later images.
+    Encryption(EncryptionArgs),
     /// Erase Flash entirely
     EraseFlash(EraseFlashArgs),
     /// Erase specified partitions
     EraseParts(ErasePartsArgs),
-    /// Erase specified region
+    /// Erase specified regions
     EraseRegion(EraseRegionArgs),
+    /// Erase the OTA data and NVS partitions of the connected target device
+    ///
+    /// The factory app is booted after the device is reset, with clean
+    /// settings. Additional partitions may be erased using '--erase-parts'.
+    FactoryReset(FactoryResetArgs),
     /// Flash an application in ELF format to a connected target device
     ///
     /// Given a path to an ELF file, first convert it into the appropriate
@@ -62,25 +171,93 @@ enum Commands {
     ///
     /// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/app_image_format.html
     Flash(FlashArgs),
+    /// Work with filesystem partitions
+    ///
+    /// 'fs download' reads a FAT, SPIFFS or LittleFS partition from the
+    /// connected target device and unpacks its files into a directory, and
+    /// 'fs unpack' does the same for an image file. 'fs generate-fat' builds
+    /// a FAT image, with ESP-IDF's wear levelling layer, from a directory.
+    Fs(FsArgs),
     /// Hold the target device in reset
     HoldInReset(ConnectArgs),
+    /// Display information about an application or bootloader image
+    ///
+    /// Prints the image's header and segments, and checks that its checksum and
+    /// appended SHA256 digest match its contents. Images with a mismatched
+    /// digest are rejected by the bootloader; use '--fix-hash' to recalculate
+    /// them.
+    ImageInfo(ImageInfoArgs),
     /// Open the serial monitor without flashing the connected target device
     Monitor(MonitorArgs),
-    /// Convert partition tables between CSV and binary format
+    /// Generate, decode and edit NVS partitions
+    ///
+    /// Individual keys may be set with 'nvs set NAMESPACE.KEY VALUE', which
+    /// updates the NVS partition in place, writing back only the pages
+    /// affected by the change. Partition images may be generated from the CSV
+    /// files used by ESP-IDF, and are encrypted when given the keys of an
+    /// `nvs_keys` partition.
+    Nvs(NvsArgs),
+    /// Show and change the rollback state of OTA apps
+    ///
+    /// 'ota set-state STATE' changes the state recorded in the `otadata`
+    /// partition for the booted app, or the app in the partition given with
+    /// '--partition'. Marking the booted app as invalid makes the bootloader
+    /// roll back to the previous app, and marking it as valid recovers an app
+    /// left in the pending-verify state.
+    Ota(OtaArgs),
+    /// Convert partition tables between CSV, binary and TOML format
     ///
     /// Uses the ESP-IDF format for partition tables; please refer to the
     /// ESP-IDF documentation for more information on this format:
     ///
     /// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-guides/partition-tables.html
     ///
-    /// Allows for conversion between formats via the '--to-csv' and
-    /// '--to-binary' options, plus the ability to print a partition table
-    /// in tabular format.
+    /// Partition tables may also be written in TOML. Allows for conversion
+    /// between formats via the '--to-csv', '--to-binary' and '--to-toml'
+    /// options, plus the ability to print a partition table in tabular
+    /// format. Two partition tables, or a partition table and the
+    /// one on the target device, may be compared using the 'diff'
+    /// subcommand.
     PartitionTable(PartitionTableArgs),
+    /// Generate a factory image for each device of a device list, for
+    /// programming by a contract manufacturer
+    ///
+    /// Each device's NVS partition is filled in from an NVS template and
+    /// written into a copy of a merged factory image, as saved by 'save-image
+    /// --merge'. The digests of the images are listed in a manifest.
+    ProductionImages(ProductionImagesArgs),
+    /// Provision devices with unique data written to their NVS partition
+    ///
+    /// An NVS template in CSV or TOML format is filled in with the values of
+    /// the next device in a CSV device list, and written to each device
+    /// attached. The devices provisioned are recorded in a log, so that they
+    /// are skipped by later runs.
+    Provision(ProvisionArgs),
     /// Read SPI flash content
     ReadFlash(ReadFlashArgs),
+    /// Print the SFDP parameter tables of the target device's flash chip
+    ///
+    /// Most flash chips describe their capacity, supported erase operations
+    /// and timings in Serial Flash Discoverable Parameter (SFDP) tables, as
+    /// standardised by JEDEC in JESD216. The Basic Flash Parameter Table is
+    /// decoded, and the contents of each table are printed.
+    ReadSfdp(ConnectArgs),
     /// Reset the target device
     Reset(ConnectArgs),
+    /// Back up and restore RF calibration data
+    ///
+    /// ESP-IDF applications store the results of the RF calibration they
+    /// perform on first boot in the `phy` namespace of the NVS partition.
+    /// Back it up before erasing the whole flash, and restore it afterwards,
+    /// to keep the original calibration.
+    RfCalibration(RfCalibrationArgs),
+    /// Run a sequence of operations listed in a script over a single
+    /// connection
+    ///
+    /// Scripts are TOML files listing operations such as `erase-region`,
+    /// `write-bin`, `write-partition`, `verify` and `reset`, which are run in
+    /// order without reconnecting to the target device.
+    RunScript(RunScriptArgs),
     /// Generate a binary application image and save it to a local disk
     ///
     /// If the '--merge' option is used, then the bootloader, partition table,
@@ -88,6 +265,31 @@ enum Commands {
     /// Otherwise, each segment will be saved as individual binaries, prefixed
     /// with their intended addresses in flash.
     SaveImage(SaveImageArgs),
+    /// Share a serial port with other instances of espflash over the network
+    ///
+    /// Takes ownership of the serial port and listens for clients on a TCP
+    /// socket. Monitor clients receive the device's serial output, while a
+    /// flash client can temporarily take exclusive control of the port; once
+    /// it disconnects, control is returned to the monitor clients.
+    Serve(ServeArgs),
+    /// Open an interactive shell on a connected target device
+    ///
+    /// Connects once, then runs commands such as `read 0x9000 0x1000`,
+    /// `erase nvs`, `md5 0x10000 0x100000` and `reset` as they are entered,
+    /// without reconnecting to the target device.
+    Shell(ShellArgs),
+    /// Manage the flash stubs loaded onto the target device
+    ///
+    /// Newer releases of the flash stubs may be downloaded from
+    /// esp-flasher-stub, and are used in preference to the ones embedded in
+    /// this version until removed.
+    Stub(StubArgs),
+    /// Verify the Secure Boot V2 signatures of a signed image
+    ///
+    /// Checks that each signature block matches the image, and prints the
+    /// digest of its public key, as burned into the eFuses. Keys which must
+    /// have signed the image may be given with '--key'.
+    VerifySignature(VerifySignatureArgs),
     /// Write a binary file to a specific address in a target device's flash
     WriteBin(WriteBinArgs),
     /// Calculate the MD5 checksum of the given region
@@ -122,7 +324,16 @@ struct FlashArgs {
     #[clap(flatten)]
     flash_args: cli::FlashArgs,
     /// ELF image to flash
-    image: PathBuf,
+    #[arg(required_unless_present = "idf_build_dir")]
+    image: Option<PathBuf>,
+    /// Flash the artifacts of an ESP-IDF project, as listed in
+    /// `flasher_args.json` or `flash_args` in its build directory
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["image", "ram", "check", "activate", "erase_parts", "erase_data_parts", "dry_run"]
+    )]
+    idf_build_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -140,25 +351,63 @@ struct SaveImageArgs {
 
 /// Writes a binary file to a specific address in the chip's flash
 #[derive(Debug, Args)]
+#[command(allow_missing_positional = true)]
 #[non_exhaustive]
 struct WriteBinArgs {
     /// Address at which to write the binary file
+    ///
+    /// Must be left out for Intel HEX files, which contain their own
+    /// addresses.
     #[arg(value_parser = parse_uint32)]
-    pub addr: u32,
-    /// File containing the binary data to write
-    pub bin_file: String,
+    pub addr: Option<u32>,
+    /// File containing the binary (.bin) or Intel HEX (.hex) data to write
+    pub bin_file: PathBuf,
+    /// Recalculate the checksum and SHA256 digest of an application image if
+    /// they do not match its contents, rather than refusing to write it
+    #[arg(long)]
+    pub fix_hash: bool,
+    /// Don't compress data before writing it to the flash
+    #[arg(long)]
+    pub no_compress: bool,
+    /// Write a JSON report of the operation to this file, describing the
+    /// device and each artifact written along with its digests
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+    /// Flash settings to patch into the header of a bootloader
+    ///
+    /// Only applied when the binary is written to the bootloader's address.
+    #[clap(flatten)]
+    pub flash_config_args: FlashConfigArgs,
     /// Connection configuration
     #[clap(flatten)]
     connect_args: ConnectArgs,
 }
 
-fn main() -> Result<()> {
+fn main() {
     miette::set_panic_hook();
-    initialize_logger(LevelFilter::Info);
 
+    let result = run();
+    emit_finished(&result);
+
+    if let Err(err) = result {
+        eprintln!("Error: {err:?}");
+        ExitCode::of(&err).exit();
+    }
+}
+
+fn run() -> Result<()> {
     // Attempt to parse any provided comand-line arguments, or print the help
     // message and terminate if the invocation is not correct.
-    let args = Cli::parse().subcommand;
+    let cli = Cli::parse();
+    let verbosity = Verbosity::from_flags(cli.quiet, cli.verbose);
+    initialize_logger(verbosity.log_level());
+    set_verbosity(verbosity);
+    set_output_format(cli.output_format);
+    set_progress_display(ProgressDisplay::from_flags(cli.progress, cli.no_progress));
+    if let Some(addr) = cli.event_server {
+        start_event_server(addr)?;
+    }
+    let args = cli.subcommand;
     debug!("{:#?}", args);
 
     // Only check for updates once the command-line arguments have been processed,
@@ -172,19 +421,47 @@ fn main() -> Result<()> {
     // Execute the correct action based on the provided subcommand and its
     // associated arguments.
     match args {
+        Commands::AppInfo(args) => app_info(args, &config),
         Commands::BoardInfo(args) => board_info(&args, &config),
+        Commands::CheckUpdate(args) => {
+            if !check_update(args, &config)? {
+                ExitCode::UpdateAvailable.exit();
+            }
+            Ok(())
+        }
         Commands::Completions(args) => completions(&args, &mut Cli::command(), "espflash"),
+        Commands::Coredump(args) => coredump(args, &config),
+        Commands::Efuse(args) => efuse(args, &config),
+        Commands::Encryption(args) => encryption(args, &config),
         Commands::EraseFlash(args) => erase_flash(args, &config),
         Commands::EraseParts(args) => erase_parts(args, &config),
         Commands::EraseRegion(args) => erase_region(args, &config),
-        Commands::Flash(args) => flash(args, &config),
+        Commands::FactoryReset(args) => factory_reset(args, &config),
+        Commands::Flash(args) => run_with_report(args.flash_args.report.clone().as_deref(), || {
+            flash(args, &config)
+        }),
+        Commands::Fs(args) => filesystem(args, &config),
         Commands::HoldInReset(args) => hold_in_reset(args, &config),
+        Commands::ImageInfo(args) => image_info(args),
         Commands::Monitor(args) => serial_monitor(args, &config),
-        Commands::PartitionTable(args) => partition_table(args),
+        Commands::Nvs(args) => nvs(args, &config),
+        Commands::Ota(args) => ota(args, &config),
+        Commands::PartitionTable(args) => partition_table(args, &config),
+        Commands::ProductionImages(args) => production_images(args),
+        Commands::Provision(args) => provision(args, &config),
         Commands::ReadFlash(args) => read_flash(args, &config),
+        Commands::ReadSfdp(args) => read_sfdp(&args, &config),
         Commands::Reset(args) => reset(args, &config),
+        Commands::RfCalibration(args) => rf_calibration(args, &config),
+        Commands::RunScript(args) => run_script(args, &config),
         Commands::SaveImage(args) => save_image(args, &config),
-        Commands::WriteBin(args) => write_bin(args, &config),
+        Commands::Serve(args) => serve(args, &config),
+        Commands::Shell(args) => shell(args, &config),
+        Commands::Stub(args) => stub(args),
+        Commands::VerifySignature(args) => verify_signature(args),
+        Commands::WriteBin(args) => {
+            run_with_report(args.report.clone().as_deref(), || write_bin(args, &config))
+        }
         Commands::ChecksumMd5(args) => checksum_md5(&args, &config),
     }
 }
@@ -203,9 +480,10 @@ pub fn erase_parts(args: ErasePartsArgs, config: &Config) -> Result<()> {
     info!("Erasing the following partitions: {:?}", args.erase_parts);
 
     erase_partitions(&mut flasher, partition_table, Some(args.erase_parts), None)?;
+    let chip = flasher.chip();
     flasher
         .connection()
-        .reset_after(!args.connect_args.no_stub)?;
+        .reset_after(!args.connect_args.no_stub, chip)?;
 
     info!("Specified partitions successfully erased!");
 
@@ -230,12 +508,54 @@ fn hold_in_reset(args: ConnectArgs, config: &Config) -> Result<()> {
 }
 
 fn flash(args: FlashArgs, config: &Config) -> Result<()> {
+    if let Some(path) = &args.flash_args.dry_run {
+        let chip = args
+            .connect_args
+            .chip
+            .or(config.chip)
+            .ok_or(Error::ElfChipRequired)?;
+        // Clap requires an image when no build directory is provided, and a
+        // dry run conflicts with a build directory
+        let image = args.image.as_deref().unwrap();
+        let elf_data = fs::read(image).into_diagnostic()?;
+        let flash_data = make_flash_data(
+            args.flash_args.image,
+            &args.flash_config_args,
+            config,
+            None,
+            None,
+        )?;
+
+        return dry_run_elf_image(
+            &elf_data,
+            chip,
+            flash_data,
+            &args.flash_args.pre_encrypted,
+            path,
+            !args.flash_args.no_verify,
+            !args.flash_args.no_skip,
+            XtalFrequency::default(chip),
+        );
+    }
+
+    let mut hooks = FlashHooks::start(&args.flash_args, &args.connect_args, config)?;
+    if let Some(image) = args.image.as_deref().or(args.idf_build_dir.as_deref()) {
+        hooks.set_image(image);
+    }
+
     let mut flasher = connect(
         &args.connect_args,
         config,
         args.flash_args.no_verify,
         args.flash_args.no_skip,
     )?;
+    flasher.set_compression(!args.flash_args.no_compress);
+    flasher.set_retry_policy(
+        RetryPolicy::none()
+            .with_retries(args.flash_args.retries)
+            .with_reconnect(args.flash_args.reconnect),
+    );
+    hooks.connected(&mut flasher);
     flasher.verify_minimum_revision(args.flash_args.image.min_chip_rev)?;
 
     // If the user has provided a flash size via a command-line argument, we'll
@@ -243,55 +563,97 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
     if let Some(flash_size) = args.flash_config_args.flash_size {
         flasher.set_flash_size(flash_size);
     } else if let Some(flash_size) = config.flash.size {
-        flasher.set_flash_size(flash_size);
+        // The configured size is that of the boot flash
+        if flasher.boot_flash_size().is_none() {
+            flasher.set_flash_size(flash_size);
+        }
     }
 
     print_board_info(&mut flasher)?;
+    if args.flash_args.check_embedded_flash {
+        check_embedded_flash(&mut flasher)?;
+    }
 
     let chip = flasher.chip();
     let target = chip.into_target();
     let target_xtal_freq = target.crystal_freq(flasher.connection())?;
 
-    // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(&args.image).into_diagnostic()?;
+    let elf_data = if let Some(build_dir) = &args.idf_build_dir {
+        let build = IdfBuild::load(build_dir)?;
+        let flash_settings = make_flash_settings(&args.flash_config_args, config);
+        flash_idf_build(&mut flasher, &build, flash_settings)?;
 
-    if args.flash_args.ram {
-        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+        // The application's ELF file is only needed by the monitor
+        build.app_elf.map(fs::read).transpose().into_diagnostic()?
     } else {
-        let flash_data = make_flash_data(
-            args.flash_args.image,
-            &args.flash_config_args,
-            config,
-            None,
-            None,
-        )?;
+        // Read the ELF data from the build path and load it to the target.
+        // Clap requires an image when no build directory is provided
+        let image = args.image.as_deref().unwrap();
+        let elf_data = fs::read(image).into_diagnostic()?;
 
-        if args.flash_args.erase_parts.is_some() || args.flash_args.erase_data_parts.is_some() {
-            erase_partitions(
-                &mut flasher,
-                flash_data.partition_table.clone(),
-                args.flash_args.erase_parts,
-                args.flash_args.erase_data_parts,
+        if args.flash_args.ram {
+            flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+        } else {
+            let mut flash_data = make_flash_data(
+                args.flash_args.image,
+                &args.flash_config_args,
+                config,
+                None,
+                None,
             )?;
+            resolve_auto_ota(&mut flasher, &mut flash_data)?;
+
+            let activation_target = if args.flash_args.activate {
+                Some(ota_activation_target(&flash_data)?)
+            } else {
+                None
+            };
+
+            if args.flash_args.check {
+                check_elf_image(&mut flasher, &elf_data, flash_data, target_xtal_freq)?;
+                return Ok(hooks.finish()?);
+            }
+
+            if args.flash_args.erase_parts.is_some() || args.flash_args.erase_data_parts.is_some() {
+                erase_partitions(
+                    &mut flasher,
+                    flash_data.partition_table.clone(),
+                    args.flash_args.erase_parts,
+                    args.flash_args.erase_data_parts,
+                )?;
+            }
+
+            flash_elf_image(&mut flasher, &elf_data, flash_data, target_xtal_freq)?;
+            if !args.flash_args.pre_encrypted.is_empty() {
+                write_pre_encrypted(&mut flasher, &args.flash_args.pre_encrypted)?;
+            }
+
+            if let Some((partition_table, label)) = activation_target {
+                activate_ota_partition(&mut flasher, &partition_table, &label)?;
+            }
         }
 
-        flash_elf_image(&mut flasher, &elf_data, flash_data, target_xtal_freq)?;
-    }
+        Some(elf_data)
+    };
+
+    verify_report(&mut flasher)?;
+    hooks.finish()?;
 
     if args.flash_args.monitor {
         let pid = flasher.get_usb_pid()?;
 
         // The 26MHz ESP32-C2's need to be treated as a special case.
-        let default_baud = if chip == Chip::Esp32c2 && target_xtal_freq == XtalFrequency::_26Mhz {
-            // 115_200 * 26 MHz / 40 MHz = 74_880
-            74_880
-        } else {
-            115_200
-        };
+        let default_baud =
+            if chip.rom_assumes_40mhz_xtal() && target_xtal_freq == XtalFrequency::_26Mhz {
+                // 115_200 * 26 MHz / 40 MHz = 74_880
+                74_880
+            } else {
+                115_200
+            };
 
         monitor(
             flasher.into_serial(),
-            Some(&elf_data),
+            elf_data.as_deref(),
             pid,
             args.flash_args.monitor_baud.unwrap_or(default_baud),
             args.flash_args.log_format,
@@ -310,6 +672,7 @@ fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
     // Since we have no `Flasher` instance and as such cannot print the board
     // information, we will print whatever information we _do_ have.
     println!("Chip type:         {}", args.save_image_args.chip);
+    println!("Format:            {}", args.save_image_args.format);
     println!("Merge:             {}", args.save_image_args.merge);
     println!("Skip padding:      {}", args.save_image_args.skip_padding);
 
@@ -334,21 +697,44 @@ fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
         args.save_image_args.merge,
         args.save_image_args.skip_padding,
         xtal_freq,
+        args.save_image_args.format,
+        args.save_image_args.manifest,
+        args.save_image_args.pad_to,
     )?;
 
     Ok(())
 }
 
 fn write_bin(args: WriteBinArgs, config: &Config) -> Result<()> {
+    let mut segments = load_segments(&args.bin_file, args.addr)?;
+    for segment in &mut segments {
+        check_image_digest(segment.data.to_mut(), args.fix_hash)?;
+    }
+
     let mut flasher = connect(&args.connect_args, config, false, false)?;
+    flasher.set_compression(!args.no_compress);
     print_board_info(&mut flasher)?;
 
-    let mut f = File::open(&args.bin_file).into_diagnostic()?;
-    let size = f.metadata().into_diagnostic()?.len();
-    let mut buffer = Vec::with_capacity(size.try_into().into_diagnostic()?);
-    f.read_to_end(&mut buffer).into_diagnostic()?;
+    let chip = flasher.chip();
+    let flash_settings = make_flash_settings(&args.flash_config_args, config);
+    if let Some(flash_size) = flash_settings.size {
+        flasher.set_flash_size(flash_size);
+    }
+    let patch_bootloader = flash_settings.mode.is_some()
+        || flash_settings.size.is_some()
+        || flash_settings.freq.is_some();
+
+    if let Some(boot_addr) = chip.bootloader_addr().filter(|_| patch_bootloader) {
+        for segment in segments.iter_mut().filter(|s| s.addr == boot_addr) {
+            debug!("Updating the flash settings in the bootloader header");
+            update_bootloader_header(segment.data.to_mut(), chip, &flash_settings)?;
+        }
+    }
+
+    flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
+    verify_report(&mut flasher)?;
 
-    flasher.write_bin_to_flash(args.addr, &buffer, Some(&mut EspflashProgress::default()))?;
+    info!("Binary successfully written to flash!");
 
     Ok(())
 }
@@ -4,13 +4,19 @@
 //! files; the [Config] type handles the loading and saving of this
 //! configuration file.
 //!
+//! The user configuration file is merged with the nearest project
+//! configuration file, an `espflash.toml` in the current directory or one of
+//! its ancestors, whose settings take precedence. Relative paths in project
+//! configuration files are relative to the directory containing them, so that
+//! they remain valid wherever the project is checked out.
+//!
 //! [cargo-espflash]: https://crates.io/crates/cargo-espflash
 //! [espflash]: https://crates.io/crates/espflash
 
 use std::{
-    ffi::OsStr,
+    env,
     fs::{create_dir_all, read_to_string, write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use directories::ProjectDirs;
@@ -21,6 +27,10 @@ use serialport::UsbPortInfo;
 
 use crate::error::Error;
 use crate::flasher::FlashSettings;
+use crate::targets::Chip;
+
+/// Name of configuration files
+const CONFIG_FILE_NAME: &str = "espflash.toml";
 
 /// A configured, known serial connection
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
@@ -81,6 +91,9 @@ pub struct Config {
     /// Bootloader path
     #[serde(default)]
     pub bootloader: Option<PathBuf>,
+    /// Target device
+    #[serde(default)]
+    pub chip: Option<Chip>,
     /// Preferred serial port connection information
     #[serde(default)]
     pub connection: Connection,
@@ -93,39 +106,53 @@ pub struct Config {
     /// Flash settings
     #[serde(default)]
     pub flash: FlashSettings,
+    /// Command to run before flashing
+    #[serde(default)]
+    pub pre_flash_hook: Option<String>,
+    /// Command to run after flashing
+    #[serde(default)]
+    pub post_flash_hook: Option<String>,
     /// Path of the file to save the configuration to
     #[serde(skip)]
     save_path: PathBuf,
 }
 
 impl Config {
-    /// Gets the path to the configuration file.
+    /// Gets the path to the user configuration file.
     pub fn get_config_path() -> Result<PathBuf, Error> {
-        let local_config = std::env::current_dir()?.join("espflash.toml");
-        if local_config.exists() {
-            return Ok(local_config);
-        }
-        if let Some(parent_folder) = std::env::current_dir()?.parent() {
-            let workspace_config = parent_folder.join("espflash.toml");
-            if workspace_config.exists() {
-                return Ok(workspace_config);
-            }
-        }
-
         let project_dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
-        let global_config = project_dirs.config_dir().join("espflash.toml");
+        let global_config = project_dirs.config_dir().join(CONFIG_FILE_NAME);
         Ok(global_config)
     }
 
-    /// Load configuration from the configuration file
+    /// Gets the path to the project configuration file, the nearest one in the
+    /// current directory or its ancestors, if any.
+    pub fn get_project_config_path() -> Result<Option<PathBuf>, Error> {
+        let global_config = Self::get_config_path()?;
+
+        let project_config = env::current_dir()?
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file() && *path != global_config);
+
+        Ok(project_config)
+    }
+
+    /// Load configuration from the user and project configuration files
     pub fn load() -> Result<Self> {
         let file = Self::get_config_path()?;
 
-        let mut config = if let Ok(data) = read_to_string(&file) {
-            toml::from_str(&data).into_diagnostic()?
-        } else {
-            Self::default()
-        };
+        let mut config = Self::read(&file)?.unwrap_or_default();
+        if let Some(project_file) = Self::get_project_config_path()? {
+            debug!("Project config: {}", project_file.display());
+
+            let mut project = Self::read(&project_file)?.unwrap_or_default();
+            if let Some(dir) = project_file.parent() {
+                project.resolve_paths(dir);
+            }
+
+            config = project.or(config);
+        }
 
         if let Some(table) = &config.partition_table {
             match table.extension() {
@@ -135,7 +162,7 @@ impl Config {
         }
 
         if let Some(bootloader) = &config.bootloader {
-            if bootloader.extension() != Some(OsStr::new("bin")) {
+            if !matches!(bootloader.extension(), Some(ext) if ext == "bin" || ext == "hex") {
                 return Err(Error::InvalidBootloaderPath.into());
             }
         }
@@ -145,9 +172,64 @@ impl Config {
         Ok(config)
     }
 
-    /// Save configuration to the configuration file
+    /// Read a configuration file, if it exists
+    fn read(path: &Path) -> Result<Option<Self>> {
+        let Ok(data) = read_to_string(path) else {
+            return Ok(None);
+        };
+
+        let config = toml::from_str(&data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        Ok(Some(config))
+    }
+
+    /// Make relative paths relative to the given directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        for path in [&mut self.bootloader, &mut self.partition_table]
+            .into_iter()
+            .flatten()
+        {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Combine two configurations, using the settings of `other` where this
+    /// configuration has none
+    fn or(self, other: Self) -> Self {
+        Self {
+            baudrate: self.baudrate.or(other.baudrate),
+            bootloader: self.bootloader.or(other.bootloader),
+            chip: self.chip.or(other.chip),
+            connection: Connection {
+                serial: self.connection.serial.or(other.connection.serial),
+            },
+            partition_table: self.partition_table.or(other.partition_table),
+            usb_device: self
+                .usb_device
+                .into_iter()
+                .chain(other.usb_device)
+                .collect(),
+            flash: FlashSettings::new(
+                self.flash.mode.or(other.flash.mode),
+                self.flash.size.or(other.flash.size),
+                self.flash.freq.or(other.flash.freq),
+            ),
+            pre_flash_hook: self.pre_flash_hook.or(other.pre_flash_hook),
+            post_flash_hook: self.post_flash_hook.or(other.post_flash_hook),
+            save_path: other.save_path,
+        }
+    }
+
+    /// Save configuration to the user configuration file
+    ///
+    /// Only the settings of the user configuration file are saved, so that
+    /// those of project configuration files are not copied into it.
     pub fn save_with<F: Fn(&mut Self)>(&self, modify_fn: F) -> Result<()> {
-        let mut copy = self.clone();
+        let mut copy = Self::read(&self.save_path)?.unwrap_or_default();
         modify_fn(&mut copy);
 
         let serialized = toml::to_string(&copy)
@@ -165,6 +247,7 @@ impl Config {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flasher::{FlashMode, FlashSize};
     use serde::Deserialize;
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -204,6 +287,46 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn merges_project_config() {
+        let user: Config = toml::from_str(
+            r#"
+            baudrate = 115200
+            partition_table = "/home/user/partitions.csv"
+
+            [connection]
+            serial = "/dev/ttyUSB0"
+
+            [flash]
+            mode = "dio"
+            size = "4MB"
+            "#,
+        )
+        .unwrap();
+        let mut project: Config = toml::from_str(
+            r#"
+            baudrate = 921600
+            partition_table = "partitions.csv"
+
+            [flash]
+            size = "8MB"
+            "#,
+        )
+        .unwrap();
+
+        project.resolve_paths(Path::new("/project"));
+        let config = project.or(user);
+
+        assert_eq!(config.baudrate, Some(921600));
+        assert_eq!(
+            config.partition_table,
+            Some(PathBuf::from("/project/partitions.csv"))
+        );
+        assert_eq!(config.connection.serial.as_deref(), Some("/dev/ttyUSB0"));
+        assert!(matches!(config.flash.mode, Some(FlashMode::Dio)));
+        assert!(matches!(config.flash.size, Some(FlashSize::_8Mb)));
+    }
+
     #[test]
     fn test_parse_u16_hex() {
         // Valid hexadecimal input with 1 digit
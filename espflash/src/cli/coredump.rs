@@ -0,0 +1,144 @@
+//! Retrieval and conversion of core dumps
+//!
+//! `espflash coredump read` reads the core dump saved by a crashed application
+//! from the `coredump` partition, and `espflash coredump convert` converts a
+//! core dump which has already been read, e.g. with `read-flash`. Either way,
+//! the result is an ELF core file which GDB loads along with the ELF file of
+//! the application.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use esp_idf_part::{DataType, SubType, Type};
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{config::Config, connect, parse_uint32, ConnectArgs};
+use crate::{
+    coredump::{CoreDump, CoreDumpData},
+    error::Error,
+    flasher::parse_partition_table,
+};
+
+/// Read and convert core dumps
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct CoreDumpArgs {
+    #[command(subcommand)]
+    pub action: CoreDumpAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum CoreDumpAction {
+    /// Read the core dump from the `coredump` partition of the device, and
+    /// save it as an ELF core file
+    Read(CoreDumpReadArgs),
+    /// Convert a core dump read from the `coredump` partition to an ELF core
+    /// file
+    Convert(CoreDumpConvertArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct CoreDumpReadArgs {
+    /// File to save the core dump to
+    #[arg(value_name = "FILE")]
+    pub output: PathBuf,
+    /// Save the core dump as it is stored in flash, without converting it
+    #[arg(long)]
+    pub raw: bool,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct CoreDumpConvertArgs {
+    /// Core dump, as read from the `coredump` partition
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+    /// File to save the ELF core file to
+    #[arg(value_name = "OUTPUT")]
+    pub output: PathBuf,
+}
+
+/// Read or convert a core dump
+pub fn coredump(args: CoreDumpArgs, config: &Config) -> Result<()> {
+    match args.action {
+        CoreDumpAction::Read(args) => read(args, config),
+        CoreDumpAction::Convert(args) => {
+            let data = fs::read(&args.input)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open {}", args.input.display()))?;
+
+            save_elf_core(&CoreDump::parse(&data)?, &args.output)
+        }
+    }
+}
+
+fn read(args: CoreDumpReadArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+
+    let partition = partition_table
+        .partitions()
+        .iter()
+        .find(|p| p.ty() == Type::Data && p.subtype() == SubType::Data(DataType::Coredump))
+        .ok_or_else(|| Error::PartitionNotFound("coredump".into()))?;
+
+    let data = flasher.read_flash_data(partition.offset(), partition.size(), 0x1000, 64)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    let core_dump = CoreDump::parse(&data)?;
+    if args.raw {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        fs::write(&args.output, &data[..len])
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", args.output.display()))?;
+
+        info!("Core dump saved to {}", args.output.display());
+
+        return Ok(());
+    }
+
+    save_elf_core(&core_dump, &args.output)
+}
+
+fn save_elf_core(core_dump: &CoreDump, output: &Path) -> Result<()> {
+    if let CoreDumpData::Binary { tasks, .. } = &core_dump.data {
+        info!("Core dump in the binary format, with {} tasks", tasks.len());
+    }
+
+    fs::write(output, core_dump.to_elf()?)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", output.display()))?;
+
+    info!(
+        "ELF core file saved to {}, load it in GDB along with the ELF file of the application",
+        output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,462 @@
+//! Reading and burning eFuses
+//!
+//! `espflash efuse` reads and burns individual eFuses, such as keys and the
+//! custom MAC address, and protects eFuse blocks from being written or read. The options shared by the commands which burn eFuses are also
+//! defined here.
+//!
+//! Burning eFuses cannot be undone, so these commands ask for confirmation
+//! before burning them. With `--efuse-virtual` they are burned in a local eFuse
+//! image instead of the device's eFuses, which allows a provisioning sequence
+//! to be rehearsed and its effects reviewed before it is run against a device.
+
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use strum::VariantNames;
+
+use super::{config::Config, connect, ConnectArgs};
+use crate::{
+    efuse::{
+        block_name, diff, parse_block, DeviceEfuses, EfuseBackend, Efuses, KeyPurpose,
+        VirtualEfuses, KEY_BLOCK_COUNT, KEY_LEN, MAC_LEN,
+    },
+    error::Error,
+};
+
+/// Read and burn eFuses
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EfuseArgs {
+    #[command(subcommand)]
+    pub action: EfuseAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum EfuseAction {
+    /// Burn a custom MAC address to the CUSTOM_MAC eFuse
+    ///
+    /// Applications must set it as their base MAC address to use it. This
+    /// permanently modifies the device.
+    BurnCustomMac(BurnCustomMacArgs),
+    /// Burn a key to a key block, setting its purpose
+    ///
+    /// The key block and its purpose are write protected, and the key is read
+    /// protected unless its purpose requires it to remain readable. This
+    /// permanently modifies the device.
+    BurnKey(BurnKeyArgs),
+    /// Print the factory and custom MAC addresses
+    ReadMac(ReadMacArgs),
+    /// Read protect eFuse blocks, so that only the hardware can use them
+    ///
+    /// Only the key blocks and BLOCK_SYS_DATA2 can be read protected. This
+    /// permanently modifies the device.
+    ReadProtect(ProtectArgs),
+    /// Write protect eFuse blocks, so that none of their bits can be burned
+    ///
+    /// This permanently modifies the device.
+    WriteProtect(ProtectArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct BurnKeyArgs {
+    /// Key block to burn the key to, by name (such as BLOCK_KEY0) or key block
+    /// number
+    #[arg(value_name = "BLOCK", value_parser = parse_key_block)]
+    pub block: u8,
+    /// File holding the 32 byte key, in the byte order used by ESP-IDF. Keys
+    /// are reversed when their purpose requires it
+    #[arg(value_name = "KEYFILE")]
+    pub key: PathBuf,
+    /// Purpose of the key. XTS-AES-256 keys are burned as two halves, to two
+    /// key blocks with the XTS_AES_256_KEY_1 and XTS_AES_256_KEY_2 purposes
+    #[arg(value_name = "PURPOSE", value_parser = parse_key_purpose)]
+    pub purpose: KeyPurpose,
+    /// Leave the key readable by software
+    #[arg(long)]
+    pub no_read_protect: bool,
+    /// eFuse burning configuration
+    #[clap(flatten)]
+    pub efuse_args: EfuseBurnArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct BurnCustomMacArgs {
+    /// MAC address to burn, such as 02:00:00:12:34:56
+    #[arg(value_name = "MAC", value_parser = parse_mac)]
+    pub mac: [u8; MAC_LEN],
+    /// eFuse burning configuration
+    #[clap(flatten)]
+    pub efuse_args: EfuseBurnArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ProtectArgs {
+    /// Blocks to protect, by name (such as BLOCK_KEY0) or number
+    #[arg(value_name = "BLOCK", required = true, value_parser = parse_block_arg)]
+    pub blocks: Vec<u8>,
+    /// eFuse burning configuration
+    #[clap(flatten)]
+    pub efuse_args: EfuseBurnArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ReadMacArgs {
+    /// Read the eFuses from a local eFuse image instead of the device
+    #[arg(long, value_name = "FILE")]
+    pub efuse_virtual: Option<PathBuf>,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EfuseBurnArgs {
+    /// Burn the eFuses without asking for confirmation
+    #[arg(long)]
+    pub do_not_confirm: bool,
+    /// Burn the eFuses in a local eFuse image instead of the device's, created
+    /// with blank eFuses if it does not exist
+    #[arg(long, value_name = "FILE")]
+    pub efuse_virtual: Option<PathBuf>,
+}
+
+impl EfuseBurnArgs {
+    /// Fail early if confirmation is required but cannot be asked for
+    pub fn check_confirmation(&self) -> Result<()> {
+        if !self.do_not_confirm && self.efuse_virtual.is_none() && !io::stdin().is_terminal() {
+            return Err(Error::EfuseConfirmationRequired.into());
+        }
+
+        Ok(())
+    }
+
+    /// Ask for confirmation before burning eFuses
+    ///
+    /// Virtual eFuses are burned without confirmation, as their changes can be
+    /// undone.
+    pub fn confirm(&self, prompt: &str) -> Result<()> {
+        if self.do_not_confirm || self.efuse_virtual.is_some() {
+            return Ok(());
+        }
+
+        let confirmed = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("{prompt}? This cannot be undone"))
+            .interact_opt()
+            .map_err(Error::from)?
+            .ok_or(Error::Cancelled)?;
+
+        if !confirmed {
+            return Err(Error::Cancelled.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Read or burn eFuses
+pub fn efuse(args: EfuseArgs, config: &Config) -> Result<()> {
+    match args.action {
+        EfuseAction::BurnCustomMac(args) => burn_custom_mac(args, config),
+        EfuseAction::BurnKey(args) => burn_key(args, config),
+        EfuseAction::ReadMac(args) => read_mac(args, config),
+        EfuseAction::ReadProtect(args) => protect(args, config, Protection::Read),
+        EfuseAction::WriteProtect(args) => protect(args, config, Protection::Write),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Protection {
+    Read,
+    Write,
+}
+
+fn protect(args: ProtectArgs, config: &Config, protection: Protection) -> Result<()> {
+    let kind = match protection {
+        Protection::Read => "read",
+        Protection::Write => "write",
+    };
+    let blocks = args
+        .blocks
+        .iter()
+        .map(|block| block_name(*block))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    warn!("Protecting eFuse blocks cannot be undone, the protection can never be removed");
+    if let Protection::Read = protection {
+        warn!("Read protected blocks can no longer be read by software, including by espflash");
+    }
+
+    burn_efuses(
+        &args.efuse_args,
+        &args.connect_args,
+        config,
+        &format!("Permanently {kind} protect {blocks}"),
+        |efuses| {
+            // Check every block before burning any of them
+            for block in &args.blocks {
+                let protected = match protection {
+                    Protection::Read => efuses.read_protected(*block)?,
+                    Protection::Write => efuses.write_protected(*block)?,
+                };
+                if protected {
+                    return Err(Error::InvalidEfuseOperation(format!(
+                        "{} is already {kind} protected",
+                        block_name(*block)
+                    ))
+                    .into());
+                }
+            }
+
+            for block in &args.blocks {
+                match protection {
+                    Protection::Read => efuses.read_protect(*block)?,
+                    Protection::Write => efuses.write_protect(*block)?,
+                }
+                info!("{} {kind} protected", block_name(*block));
+            }
+
+            Ok(())
+        },
+    )
+}
+
+fn burn_key(args: BurnKeyArgs, config: &Config) -> Result<()> {
+    let data = fs::read(&args.key)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.key.display()))?;
+    let key: [u8; KEY_LEN] = data.try_into().map_err(|data: Vec<u8>| {
+        Error::InvalidEfuseOperation(format!(
+            "the key must be {KEY_LEN} bytes long, not {}",
+            data.len()
+        ))
+    })?;
+
+    let read_protect = !args.no_read_protect && !args.purpose.must_be_readable();
+    let purpose = args.purpose;
+    let block = args.block;
+
+    burn_efuses(
+        &args.efuse_args,
+        &args.connect_args,
+        config,
+        &format!("Burn the {purpose} key to key block {block}"),
+        |efuses| {
+            efuses.burn_key(block, &key, purpose, read_protect)?;
+            info!("{purpose} key burned to key block {block}");
+            if !read_protect {
+                warn!("The key in key block {block} remains readable by software");
+            }
+
+            Ok(())
+        },
+    )
+}
+
+fn burn_custom_mac(args: BurnCustomMacArgs, config: &Config) -> Result<()> {
+    let mac = format_mac(&args.mac);
+
+    burn_efuses(
+        &args.efuse_args,
+        &args.connect_args,
+        config,
+        &format!("Burn the custom MAC address {mac}"),
+        |efuses| {
+            efuses.burn_custom_mac(&args.mac)?;
+            info!("Custom MAC address {mac} burned");
+
+            Ok(())
+        },
+    )
+}
+
+fn read_mac(args: ReadMacArgs, config: &Config) -> Result<()> {
+    with_efuses(
+        args.efuse_virtual.as_deref(),
+        &args.connect_args,
+        config,
+        false,
+        |efuses| {
+            println!("Factory MAC: {}", format_mac(&efuses.factory_mac()?));
+            match efuses.custom_mac()? {
+                Some(mac) => println!("Custom MAC:  {}", format_mac(&mac)),
+                None => println!("Custom MAC:  not burned"),
+            }
+
+            Ok(())
+        },
+    )
+}
+
+/// Burn eFuses, in the device or a virtual eFuse image, after asking for
+/// confirmation, and print the eFuses which changed
+pub fn burn_efuses(
+    efuse_args: &EfuseBurnArgs,
+    connect_args: &ConnectArgs,
+    config: &Config,
+    prompt: &str,
+    f: impl FnOnce(&mut Efuses<&mut dyn EfuseBackend>) -> Result<()>,
+) -> Result<()> {
+    efuse_args.check_confirmation()?;
+
+    with_efuses(
+        efuse_args.efuse_virtual.as_deref(),
+        connect_args,
+        config,
+        true,
+        |efuses| {
+            efuse_args.confirm(prompt)?;
+
+            let before = efuses.read_all()?;
+            f(efuses)?;
+            print_efuse_changes(&before, &efuses.read_all()?);
+
+            Ok(())
+        },
+    )
+}
+
+/// Access the eFuses of the device, or of a virtual eFuse image if a path is
+/// given, saving the image afterwards if `save` is set
+pub fn with_efuses<T>(
+    efuse_virtual: Option<&Path>,
+    connect_args: &ConnectArgs,
+    config: &Config,
+    save: bool,
+    f: impl FnOnce(&mut Efuses<&mut dyn EfuseBackend>) -> Result<T>,
+) -> Result<T> {
+    if let Some(path) = efuse_virtual {
+        let mut backend = load_virtual_efuses(path)?;
+        let value = f(&mut Efuses::new(&mut backend as &mut dyn EfuseBackend))?;
+        if save {
+            save_virtual_efuses(path, &backend)?;
+        }
+
+        return Ok(value);
+    }
+
+    let mut flasher = connect(connect_args, config, false, false)?;
+    let chip = flasher.chip();
+
+    let mut backend = DeviceEfuses::new(flasher.connection(), chip)?;
+    let value = f(&mut Efuses::new(&mut backend as &mut dyn EfuseBackend))?;
+
+    flasher
+        .connection()
+        .reset_after(!connect_args.no_stub, chip)?;
+
+    Ok(value)
+}
+
+/// Load a virtual eFuse image, or blank eFuses if it does not exist yet
+pub fn load_virtual_efuses(path: &Path) -> Result<VirtualEfuses> {
+    if !path.exists() {
+        info!("Creating virtual eFuses in {}", path.display());
+        return Ok(VirtualEfuses::blank());
+    }
+
+    let data = fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(VirtualEfuses::from_bytes(&data)?)
+}
+
+/// Save a virtual eFuse image
+pub fn save_virtual_efuses(path: &Path, efuses: &VirtualEfuses) -> Result<()> {
+    fs::write(path, efuses.to_bytes())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    info!("Virtual eFuses saved to {}", path.display());
+
+    Ok(())
+}
+
+/// Print the changes made to the eFuses by burning them
+pub fn print_efuse_changes(before: &[Vec<u32>], after: &[Vec<u32>]) {
+    let changes = diff(before, after);
+    if changes.is_empty() {
+        println!("No eFuses changed");
+        return;
+    }
+
+    println!("eFuse changes:");
+    for change in changes {
+        println!("  {:<20} {} -> {}", change.name, change.old, change.new);
+    }
+}
+
+/// Parse a MAC address, with its bytes separated by colons or dashes
+fn parse_mac(value: &str) -> Result<[u8; MAC_LEN], String> {
+    let bytes = value
+        .split([':', '-'])
+        .map(|byte| match byte.len() {
+            2 => u8::from_str_radix(byte, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("'{value}' is not a MAC address, such as 02:00:00:12:34:56"))
+}
+
+fn parse_key_block(value: &str) -> Result<u8, String> {
+    let first_key_block = parse_block("BLOCK_KEY0").unwrap();
+
+    match value.parse::<u8>() {
+        Ok(key) if key < KEY_BLOCK_COUNT => Some(key),
+        Ok(_) => None,
+        Err(_) => parse_block(value)
+            .and_then(|block| block.checked_sub(first_key_block))
+            .filter(|key| *key < KEY_BLOCK_COUNT),
+    }
+    .ok_or_else(|| {
+        format!(
+            "'{value}' is not a key block, which are BLOCK_KEY0 to BLOCK_KEY{}",
+            KEY_BLOCK_COUNT - 1
+        )
+    })
+}
+
+fn parse_key_purpose(value: &str) -> Result<KeyPurpose, String> {
+    value.parse().map_err(|_| {
+        format!(
+            "'{value}' is not a key purpose, which are: {}",
+            KeyPurpose::VARIANTS.join(", ")
+        )
+    })
+}
+
+fn parse_block_arg(value: &str) -> Result<u8, String> {
+    parse_block(value).ok_or_else(|| format!("'{value}' is not an eFuse block"))
+}
+
+fn format_mac(mac: &[u8]) -> String {
+    mac.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,273 @@
+//! Preparation of devices for flash encryption
+//!
+//! `espflash encryption prepare` burns a flash encryption key into one of the
+//! key blocks of the device's eFuses, read protecting it so that only the
+//! flash encryption hardware can use it, and enables flash encryption.
+//!
+//! As the key cannot be read back, a new key is saved to a file before it is
+//! burned, so that later images can be encrypted on the host. A merged image
+//! may be encrypted with the key and written to the device first, so that the
+//! device boots into it with flash encryption already enabled, rather than
+//! encrypting its flash in place on the first boot.
+//!
+//! Burning eFuses cannot be undone: a device whose key is lost can only be
+//! updated by its own firmware. The eFuses can be burned in a local eFuse
+//! image with `--efuse-virtual` to review the changes first, in which case
+//! neither the device nor the key file are modified.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{
+    config::Config,
+    connect,
+    efuse::{load_virtual_efuses, print_efuse_changes, save_virtual_efuses, EfuseBurnArgs},
+    parse_uint32,
+    provision::image_partition_table,
+    ConnectArgs,
+};
+use crate::{
+    efuse::{DeviceEfuses, EfuseBackend, Efuses, KeyPurpose, KEY_BLOCK_COUNT},
+    error::Error,
+    flash_encryption::{encrypt_merged_image, generate_key, XTS_AES_128_KEY_LEN},
+    flasher::parse_partition_table,
+};
+
+/// Prepare devices for flash encryption
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EncryptionArgs {
+    #[command(subcommand)]
+    pub action: EncryptionAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum EncryptionAction {
+    /// Burn a flash encryption key into the eFuses and enable flash
+    /// encryption, optionally writing an encrypted image first
+    ///
+    /// This permanently modifies the device.
+    Prepare(EncryptionPrepareArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EncryptionPrepareArgs {
+    /// File holding the 32 byte XTS-AES-128 key to burn. A new random key is
+    /// generated if not provided
+    #[arg(long, value_name = "FILE")]
+    pub key: Option<PathBuf>,
+    /// File to save the generated key to, which must not exist yet
+    #[arg(
+        long,
+        value_name = "FILE",
+        required_unless_present = "key",
+        conflicts_with = "key"
+    )]
+    pub save_key: Option<PathBuf>,
+    /// Key block to burn the key to, the first unused one if not provided
+    #[arg(
+        long,
+        value_name = "BLOCK",
+        value_parser = clap::value_parser!(u8).range(0..KEY_BLOCK_COUNT as i64)
+    )]
+    pub key_block: Option<u8>,
+    /// Enable flash encryption in release mode, so that it can never be
+    /// disabled
+    #[arg(long)]
+    pub release: bool,
+    /// Merged image to encrypt and write to the device before burning the
+    /// eFuses, as saved by `save-image --merge`
+    #[arg(long, value_name = "FILE")]
+    pub image: Option<PathBuf>,
+    /// Partition table of the image, read from the image if not provided
+    #[arg(long, value_name = "FILE", requires = "image")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// eFuse burning configuration
+    #[clap(flatten)]
+    pub efuse_args: EfuseBurnArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// Prepare a device for flash encryption
+pub fn encryption(args: EncryptionArgs, config: &Config) -> Result<()> {
+    match args.action {
+        EncryptionAction::Prepare(args) => prepare(args, config),
+    }
+}
+
+fn prepare(args: EncryptionPrepareArgs, config: &Config) -> Result<()> {
+    args.efuse_args.check_confirmation()?;
+
+    let key = match &args.key {
+        Some(path) => read_key(path)?,
+        None => generate_key()?,
+    };
+    let image = args
+        .image
+        .as_ref()
+        .map(|path| {
+            fs::read(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to open {}", path.display()))
+        })
+        .transpose()?;
+
+    if let Some(path) = &args.efuse_args.efuse_virtual {
+        let mut efuses = Efuses::new(load_virtual_efuses(path)?);
+        let key_block = select_key_block(&mut efuses, args.key_block)?;
+
+        if args.save_key.is_some() {
+            warn!("The key is not saved when burning virtual eFuses");
+        }
+        if image.is_some() {
+            warn!("The image is not written when burning virtual eFuses");
+        }
+
+        burn(&mut efuses, key_block, &key, args.release)?;
+        save_virtual_efuses(path, efuses.backend())?;
+
+        return Ok(());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let chip = flasher.chip();
+
+    let key_block = select_key_block(
+        &mut Efuses::new(DeviceEfuses::new(flasher.connection(), chip)?),
+        args.key_block,
+    )?;
+
+    args.efuse_args.confirm(&format!(
+        "Burn the flash encryption key to key block {key_block} and enable flash encryption in \
+         {} mode",
+        mode(args.release)
+    ))?;
+
+    // The key must be kept before it becomes unreadable
+    if let Some(path) = &args.save_key {
+        save_key(path, &key)?;
+        info!("Flash encryption key saved to {}", path.display());
+    }
+
+    if let Some(image) = image {
+        let partition_table = match &args.partition_table {
+            Some(path) => parse_partition_table(path)?,
+            None => image_partition_table(&image, args.partition_table_offset)?,
+        };
+        let encrypted = encrypt_merged_image(
+            &key,
+            &image,
+            chip.bootloader_addr().unwrap_or_default(),
+            args.partition_table_offset,
+            &partition_table,
+        )?;
+
+        flasher.write_bin_to_flash(0, &encrypted, None)?;
+        info!("Encrypted image written to the device");
+    }
+
+    burn(
+        &mut Efuses::new(DeviceEfuses::new(flasher.connection(), chip)?),
+        key_block,
+        &key,
+        args.release,
+    )?;
+
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    Ok(())
+}
+
+fn mode(release: bool) -> &'static str {
+    if release {
+        "release"
+    } else {
+        "development"
+    }
+}
+
+/// Check that flash encryption can be enabled, and choose the key block to burn
+/// the key to
+fn select_key_block<B: EfuseBackend>(efuses: &mut Efuses<B>, key_block: Option<u8>) -> Result<u8> {
+    if efuses.flash_encryption_enabled()? {
+        return Err(
+            Error::InvalidEfuseOperation("flash encryption is already enabled".into()).into(),
+        );
+    }
+
+    let key_block = match key_block {
+        Some(block) if !efuses.key_block_unused(block)? => {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "key block {block} is already in use"
+            ))
+            .into());
+        }
+        Some(block) => block,
+        None => efuses
+            .unused_key_block()?
+            .ok_or_else(|| Error::InvalidEfuseOperation("all key blocks are in use".into()))?,
+    };
+
+    Ok(key_block)
+}
+
+/// Burn the key and enable flash encryption, printing the eFuses which changed
+fn burn<B: EfuseBackend>(
+    efuses: &mut Efuses<B>,
+    key_block: u8,
+    key: &[u8; XTS_AES_128_KEY_LEN],
+    release: bool,
+) -> Result<()> {
+    let before = efuses.read_all()?;
+
+    efuses.burn_key(key_block, key, KeyPurpose::XtsAes128Key, true)?;
+    info!("Flash encryption key burned to key block {key_block}");
+
+    efuses.enable_flash_encryption(release)?;
+    info!("Flash encryption enabled in {} mode", mode(release));
+
+    print_efuse_changes(&before, &efuses.read_all()?);
+
+    Ok(())
+}
+
+fn read_key(path: &Path) -> Result<[u8; XTS_AES_128_KEY_LEN]> {
+    let data = fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    let key = data.try_into().map_err(|data: Vec<u8>| {
+        Error::InvalidFlashEncryption(format!(
+            "the key must be {XTS_AES_128_KEY_LEN} bytes long, not {}",
+            data.len()
+        ))
+    })?;
+
+    Ok(key)
+}
+
+fn save_key(path: &Path, key: &[u8]) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(key))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,341 @@
+//! Events broadcast to editors and other tools over a local WebSocket
+//!
+//! With `--event-server ADDR`, such as `--event-server 0`, espflash accepts
+//! WebSocket connections on the given address, and sends every client a text
+//! message for each event of the running command. A port alone is listened on
+//! by the loopback interface. The address listened on is printed to stderr as
+//! `Event server listening on ws://ADDR`, so that a port chosen by the system
+//! can be found. Events which occur while no client is connected are not sent
+//! later.
+//!
+//! Handshakes from web pages other than those served by this machine, as told
+//! by their `Origin` header, are rejected, so that a website visited while
+//! espflash runs cannot follow it.
+//!
+//! Each message is a JSON object, whose `event` field gives the kind of event:
+//!
+//! | `event`           | Other fields                  | Sent when                             |
+//! | ----------------- | ----------------------------- | ------------------------------------- |
+//! | `connected`       | `chip`, `port`                | connected to the target device        |
+//! | `progress_start`  | `address`, `total`            | writing a segment started             |
+//! | `progress`        | `address`, `current`, `total` | a block of the segment was written    |
+//! | `progress_finish` | `address`                     | writing the segment finished          |
+//! | `log`             | `line`                        | the serial monitor decoded a line     |
+//! | `finished`        | `success`, `error`            | the command finished                  |
+//!
+//! `port` is `null` when the serial port is not known, and `error` is `null`
+//! when the command succeeded. Messages sent by clients are ignored.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+/// GUID appended to the key of a client to accept its handshake, as specified
+/// by RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Timeout of the handshake and of sending events, after which a client is
+/// disconnected, so that it cannot stall the running command
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client of the event server, which is written to by one event at a time
+type Client = Arc<Mutex<TcpStream>>;
+
+/// Clients of the event server, if one was started
+static CLIENTS: OnceLock<Mutex<Vec<Client>>> = OnceLock::new();
+
+/// An event of the running command
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// Connected to the target device
+    Connected { chip: String, port: Option<String> },
+    /// Writing a segment started
+    ProgressStart { address: u32, total: usize },
+    /// A block of the segment was written
+    Progress {
+        address: u32,
+        current: usize,
+        total: usize,
+    },
+    /// Writing the segment finished
+    ProgressFinish { address: u32 },
+    /// The serial monitor decoded a line
+    Log { line: &'a str },
+    /// The command finished
+    Finished {
+        success: bool,
+        error: Option<String>,
+    },
+}
+
+/// Parse the address of the event server, either as a socket address or as a
+/// port of the loopback interface
+pub fn parse_event_server_addr(addr: &str) -> Result<SocketAddr, String> {
+    match addr.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+        Err(_) => addr
+            .parse()
+            .map_err(|_| format!("'{addr}' is neither a port nor a socket address")),
+    }
+}
+
+/// Start the event server on the given address, printing the address it
+/// listens on to stderr
+///
+/// Only the first event server started is used.
+pub fn start_event_server(addr: SocketAddr) -> Result<SocketAddr> {
+    if !addr.ip().is_loopback() {
+        warn!("The event server listens on {addr}, which may be reachable from other machines");
+    }
+
+    let listener = TcpListener::bind(addr)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to start the event server on {addr}"))?;
+    let local_addr = listener.local_addr().into_diagnostic()?;
+
+    if CLIENTS.set(Mutex::new(Vec::new())).is_err() {
+        debug!("An event server is already running, not using ws://{local_addr}");
+        return Ok(local_addr);
+    }
+    eprintln!("Event server listening on ws://{local_addr}");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream.and_then(accept) {
+                Ok(stream) => clients().push(Arc::new(Mutex::new(stream))),
+                Err(err) => debug!("Event server client rejected: {err}"),
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// Send an event to every client of the event server, if one was started
+///
+/// Clients which cannot be written to are disconnected. They are written to
+/// without holding the list of clients, so that a slow client does not hold
+/// up new clients from being accepted.
+pub fn emit(event: Event<'_>) {
+    if CLIENTS.get().is_none() {
+        return;
+    }
+
+    let message = match serde_json::to_string(&event) {
+        Ok(message) => message,
+        Err(err) => {
+            warn!("Failed to serialize event: {err}");
+            return;
+        }
+    };
+
+    let frame = text_frame(&message);
+    let failed = clients()
+        .clone()
+        .into_iter()
+        .filter(|client| {
+            let mut stream = client.lock().unwrap_or_else(PoisonError::into_inner);
+            stream.write_all(&frame).is_err()
+        })
+        .collect::<Vec<_>>();
+
+    if !failed.is_empty() {
+        clients().retain(|client| !failed.iter().any(|failed| Arc::ptr_eq(failed, client)));
+    }
+}
+
+/// Send the `finished` event for the result of the command
+pub fn emit_finished(result: &Result<()>) {
+    emit(Event::Finished {
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|err| format!("{err:#}")),
+    });
+}
+
+fn clients() -> MutexGuard<'static, Vec<Client>> {
+    CLIENTS
+        .get()
+        .expect("event server not started")
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Perform the opening handshake of a WebSocket connection
+fn accept(mut stream: TcpStream) -> io::Result<TcpStream> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut key = None;
+    let mut origin = None;
+    let mut reader = BufReader::new(&mut stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    if let Some(origin) = origin.filter(|origin| !is_local_origin(origin)) {
+        stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("handshake from origin {origin}"),
+        ));
+    }
+
+    let Some(key) = key else {
+        stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a WebSocket handshake",
+        ));
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    )?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+/// Could the page sending the given `Origin` header only be served by this
+/// machine?
+///
+/// Browsers send the origin of the page opening a connection, whereas other
+/// clients usually send none. Pages loaded from files and by editors have
+/// origins whose scheme is not HTTP, while sandboxed pages send `null`.
+fn is_local_origin(origin: &str) -> bool {
+    let Some((scheme, authority)) = origin.split_once("://") else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return true;
+    }
+
+    let authority = authority.split('/').next().unwrap_or_default();
+    let host = match authority.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Value of the `Sec-WebSocket-Accept` header answering the given key
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Unmasked WebSocket frame holding the given text message
+fn text_frame(message: &str) -> Vec<u8> {
+    let payload = message.as_bytes();
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+
+    // FIN and the text opcode
+    frame.push(0x81);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_handshake_and_frames() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaKnfjnEkSWsm8Hxh8="
+        );
+
+        assert_eq!(text_frame("Hello"), b"\x81\x05Hello");
+
+        let frame = text_frame(&"a".repeat(300));
+        assert_eq!(frame[..4], [0x81, 126, 0x01, 0x2C]);
+        assert_eq!(frame.len(), 304);
+
+        let event = Event::ProgressFinish { address: 0x10000 };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"progress_finish","address":65536}"#
+        );
+    }
+
+    #[test]
+    fn checks_origin() {
+        assert!(is_local_origin("http://localhost:3000"));
+        assert!(is_local_origin("https://127.0.0.1"));
+        assert!(is_local_origin("http://[::1]:8080"));
+        assert!(is_local_origin("vscode-webview://1234abcd"));
+
+        assert!(!is_local_origin("https://example.com"));
+        assert!(!is_local_origin("http://localhost.example.com"));
+        assert!(!is_local_origin("http://192.168.1.10:8080"));
+        assert!(!is_local_origin("null"));
+    }
+
+    #[test]
+    fn parses_addr() {
+        assert_eq!(
+            parse_event_server_addr("9001").unwrap(),
+            "127.0.0.1:9001".parse().unwrap()
+        );
+        assert_eq!(
+            parse_event_server_addr("0.0.0.0:9001").unwrap(),
+            "0.0.0.0:9001".parse().unwrap()
+        );
+        assert!(parse_event_server_addr("localhost").is_err());
+    }
+}
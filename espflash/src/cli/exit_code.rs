@@ -0,0 +1,106 @@
+//! Exit codes of the command-line tools
+//!
+//! Failures are assigned an exit code by class, so that scripts and CI jobs
+//! can react to them without parsing error messages. The codes are stable:
+//! new classes are given new codes, and existing codes are never reassigned.
+//!
+//! | Code  | Meaning                                                      |
+//! | ----- | ------------------------------------------------------------ |
+//! | `0`   | Success                                                      |
+//! | `1`   | Any other failure                                            |
+//! | `2`   | Invalid command-line arguments                               |
+//! | `3`   | The serial port was not found                                |
+//! | `4`   | Connecting to the target device failed                       |
+//! | `5`   | The target device is not the expected chip                   |
+//! | `6`   | Verifying the flash contents failed                          |
+//! | `7`   | Building the application failed                              |
+//! | `8`   | `check-update`: the device is not running the given app      |
+//! | `130` | The operation was cancelled by the user                      |
+
+use std::process;
+
+use miette::Report;
+
+use crate::error::{ConnectionError, Error};
+
+/// Exit code of a command-line tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitCode {
+    /// Success
+    Success = 0,
+    /// Any other failure
+    Failure = 1,
+    /// Invalid command-line arguments, as reported by `clap`
+    Usage = 2,
+    /// The serial port was not found
+    PortNotFound = 3,
+    /// Connecting to the target device failed
+    ConnectionFailed = 4,
+    /// The target device is not the expected chip
+    WrongChip = 5,
+    /// Verifying the flash contents failed
+    VerifyFailed = 6,
+    /// Building the application failed
+    BuildFailed = 7,
+    /// The target device is not running the given application
+    UpdateAvailable = 8,
+    /// The operation was cancelled by the user
+    Cancelled = 130,
+}
+
+impl ExitCode {
+    /// Exit code of an error, that of the outermost error of its chain which
+    /// belongs to a class
+    pub fn of(report: &Report) -> Self {
+        report
+            .chain()
+            .find_map(|err| err.downcast_ref::<Error>().and_then(classify))
+            .unwrap_or(ExitCode::Failure)
+    }
+
+    /// Terminate the process with this exit code
+    pub fn exit(self) -> ! {
+        process::exit(self as i32)
+    }
+}
+
+fn classify(err: &Error) -> Option<ExitCode> {
+    let code = match err {
+        Error::NoSerial | Error::SerialNotFound(_) => ExitCode::PortNotFound,
+        Error::Connection(ConnectionError::DeviceNotFound) => ExitCode::PortNotFound,
+        Error::Connection(_) => ExitCode::ConnectionFailed,
+        Error::ChipDetectError(_)
+        | Error::ChipMismatch(..)
+        | Error::ElfChipMismatch { .. }
+        | Error::IdfBuildChipMismatch { .. } => ExitCode::WrongChip,
+        Error::VerifyFailed => ExitCode::VerifyFailed,
+        Error::Cancelled => ExitCode::Cancelled,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use miette::{miette, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        let report = Err::<(), _>(Error::VerifyFailed)
+            .wrap_err("Operation 2 failed")
+            .unwrap_err();
+        assert_eq!(ExitCode::of(&report), ExitCode::VerifyFailed);
+
+        let report = Report::new(Error::Connection(ConnectionError::DeviceNotFound));
+        assert_eq!(ExitCode::of(&report), ExitCode::PortNotFound);
+
+        let report = Report::new(Error::Connection(ConnectionError::NoSyncReply));
+        assert_eq!(ExitCode::of(&report), ExitCode::ConnectionFailed);
+
+        assert_eq!(ExitCode::of(&miette!("Something else")), ExitCode::Failure);
+    }
+}
@@ -0,0 +1,309 @@
+//! Filesystem partitions
+//!
+//! `espflash fs download` reads a filesystem partition from the device and
+//! unpacks the files it holds into a directory on the host, which makes it
+//! possible to inspect data written by the application, such as logs or
+//! configuration files. `espflash fs unpack` does the same for an image which
+//! has already been read, and `espflash fs generate-fat` builds a FAT image,
+//! optionally wrapped in the wear levelling layer, from a directory.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{config::Config, connect, parse_uint32, ConnectArgs};
+use crate::{
+    error::{Error, MissingPartition},
+    filesystem::{
+        self,
+        fat::{self, FatConfig},
+        spiffs::SpiffsConfig,
+        FsEntry, FsType,
+    },
+    flasher::parse_partition_table,
+};
+
+/// Work with filesystem partitions
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FsArgs {
+    #[command(subcommand)]
+    pub action: FsAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum FsAction {
+    /// Read a filesystem partition and unpack its files into a directory
+    Download(FsDownloadArgs),
+    /// Build a FAT image from the contents of a directory
+    GenerateFat(FsGenerateFatArgs),
+    /// Unpack the files of a filesystem image into a directory
+    Unpack(FsUnpackArgs),
+}
+
+/// Configuration of SPIFFS images
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct SpiffsArgs {
+    /// SPIFFS logical page size
+    #[arg(long, default_value = "256", value_name = "BYTES")]
+    pub spiffs_page_size: usize,
+    /// SPIFFS logical block size
+    #[arg(long, default_value = "4096", value_name = "BYTES")]
+    pub spiffs_block_size: usize,
+    /// Maximum length of SPIFFS object names
+    #[arg(long, default_value = "32", value_name = "BYTES")]
+    pub spiffs_obj_name_len: usize,
+}
+
+impl From<&SpiffsArgs> for SpiffsConfig {
+    fn from(args: &SpiffsArgs) -> Self {
+        Self {
+            page_size: args.spiffs_page_size,
+            block_size: args.spiffs_block_size,
+            obj_name_len: args.spiffs_obj_name_len,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FsDownloadArgs {
+    /// Label of the filesystem partition
+    #[arg(long, value_name = "LABEL")]
+    pub partition: String,
+    /// Directory to unpack the files into
+    #[arg(value_name = "DIR")]
+    pub output: PathBuf,
+    /// Type of the filesystem, detected from its contents if not provided
+    #[arg(long = "type", value_name = "TYPE", value_enum)]
+    pub fs_type: Option<FsType>,
+    /// SPIFFS configuration
+    #[clap(flatten)]
+    pub spiffs: SpiffsArgs,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FsGenerateFatArgs {
+    /// Directory holding the files to put in the image
+    #[arg(value_name = "DIR")]
+    pub input: PathBuf,
+    /// File to write the image to
+    #[arg(value_name = "FILE")]
+    pub output: PathBuf,
+    /// Size of the partition which the image is written to
+    #[arg(long, value_name = "SIZE", value_parser = parse_uint32)]
+    pub size: u32,
+    /// Build a plain FAT image, for partitions mounted without wear levelling
+    #[arg(long)]
+    pub no_wear_levelling: bool,
+    /// Size of the FAT sectors
+    #[arg(long, default_value = "4096", value_name = "BYTES")]
+    pub sector_size: usize,
+    /// Number of sectors in each cluster
+    #[arg(long, default_value = "1", value_name = "COUNT")]
+    pub sectors_per_cluster: usize,
+    /// Number of copies of the allocation table
+    #[arg(long, default_value = "1", value_name = "COUNT")]
+    pub fat_count: usize,
+    /// Maximum number of entries in the root directory
+    #[arg(long, default_value = "512", value_name = "COUNT")]
+    pub root_entries: usize,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FsUnpackArgs {
+    /// Filesystem image, as read from the partition
+    #[arg(value_name = "IMAGE")]
+    pub input: PathBuf,
+    /// Directory to unpack the files into
+    #[arg(value_name = "DIR")]
+    pub output: PathBuf,
+    /// Type of the filesystem, detected from its contents if not provided
+    #[arg(long = "type", value_name = "TYPE", value_enum)]
+    pub fs_type: Option<FsType>,
+    /// SPIFFS configuration
+    #[clap(flatten)]
+    pub spiffs: SpiffsArgs,
+}
+
+/// Work with filesystem partitions
+pub fn filesystem(args: FsArgs, config: &Config) -> Result<()> {
+    match args.action {
+        FsAction::Download(args) => download(args, config),
+        FsAction::GenerateFat(args) => generate_fat(args),
+        FsAction::Unpack(args) => unpack_image(args),
+    }
+}
+
+fn download(args: FsDownloadArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+
+    let partition = partition_table
+        .find(&args.partition)
+        .ok_or_else(|| MissingPartition::from(args.partition.clone()))?;
+
+    let data = flasher.read_flash_data(partition.offset(), partition.size(), 0x1000, 64)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    let fs_type = args.fs_type.unwrap_or_else(|| FsType::detect(&data));
+    let entries = filesystem::read(&data, fs_type, &(&args.spiffs).into())?;
+
+    unpack(&entries, &args.output)?;
+
+    let files = entries
+        .iter()
+        .filter(|entry| matches!(entry, FsEntry::File { .. }))
+        .count();
+    info!(
+        "{files} files of the {fs_type} partition `{}` unpacked to {}",
+        args.partition,
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+fn unpack_image(args: FsUnpackArgs) -> Result<()> {
+    let data = fs::read(&args.input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", args.input.display()))?;
+
+    let fs_type = args.fs_type.unwrap_or_else(|| FsType::detect(&data));
+    let entries = filesystem::read(&data, fs_type, &(&args.spiffs).into())?;
+
+    unpack(&entries, &args.output)?;
+
+    let files = entries
+        .iter()
+        .filter(|entry| matches!(entry, FsEntry::File { .. }))
+        .count();
+    info!(
+        "{files} files of the {fs_type} image {} unpacked to {}",
+        args.input.display(),
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+fn generate_fat(args: FsGenerateFatArgs) -> Result<()> {
+    let mut entries = Vec::new();
+    collect(&args.input, "", &mut entries)?;
+
+    let config = FatConfig {
+        sector_size: args.sector_size,
+        sectors_per_cluster: args.sectors_per_cluster,
+        fat_count: args.fat_count,
+        root_entries: args.root_entries,
+    };
+    let image = fat::build(
+        &entries,
+        args.size as usize,
+        &config,
+        !args.no_wear_levelling,
+    )?;
+
+    fs::write(&args.output, image)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", args.output.display()))?;
+
+    let files = entries
+        .iter()
+        .filter(|entry| matches!(entry, FsEntry::File { .. }))
+        .count();
+    info!(
+        "FAT image with {files} files written to {}",
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+/// Read the files and directories below a directory on the host, in a stable
+/// order so that the images built from them are reproducible
+fn collect(dir: &Path, prefix: &str, entries: &mut Vec<FsEntry>) -> Result<()> {
+    let mut children = fs::read_dir(dir)
+        .and_then(|children| children.collect::<Result<Vec<_>, _>>())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", dir.display()))?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let name = child.file_name().to_string_lossy().into_owned();
+        let path = format!("{prefix}{name}");
+        let host_path = child.path();
+
+        if host_path.is_dir() {
+            entries.push(FsEntry::Directory { path: path.clone() });
+            collect(&host_path, &format!("{path}/"), entries)?;
+        } else {
+            let data = fs::read(&host_path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", host_path.display()))?;
+            entries.push(FsEntry::File { path, data });
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the files and directories of a filesystem to a directory
+fn unpack(entries: &[FsEntry], dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    for entry in entries {
+        let path = dir.join(entry.host_path()?);
+
+        match entry {
+            FsEntry::Directory { .. } => fs::create_dir_all(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create {}", path.display()))?,
+            FsEntry::File { data, .. } => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)
+                        .into_diagnostic()
+                        .wrap_err_with(|| format!("Failed to create {}", parent.display()))?;
+                }
+                fs::write(&path, data)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+            }
+        }
+
+        info!("{}", entry.path());
+    }
+
+    Ok(())
+}
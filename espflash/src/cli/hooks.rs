@@ -0,0 +1,168 @@
+//! Commands run before and after flashing
+//!
+//! The pre-flash hook is run before connecting to the target device, so that
+//! it can, for example, stop another program using its serial port. The
+//! post-flash hook is run once flashing has finished, successfully or not, and
+//! before the serial monitor is opened.
+//!
+//! Hooks are run by the system shell with the following environment variables:
+//!
+//! - `ESPFLASH_PORT`: serial port of the target device, when known
+//! - `ESPFLASH_CHIP`: target device, when known
+//! - `ESPFLASH_IMAGE`: path of the image being flashed, when known
+//! - `ESPFLASH_RESULT`: `success` or `failure`, only set for the post-flash
+//!   hook
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    cli::{config::Config, output::is_json, ConnectArgs, FlashArgs},
+    error::Error,
+    flasher::Flasher,
+    targets::Chip,
+};
+
+/// Hooks run before and after flashing a target device
+///
+/// If the hooks are dropped without being finished, such as when flashing
+/// returned an error, the post-flash hook is run with `ESPFLASH_RESULT` set to
+/// `failure`.
+#[derive(Debug)]
+pub struct FlashHooks {
+    post_flash: Option<String>,
+    port: Option<String>,
+    chip: Option<Chip>,
+    image: Option<PathBuf>,
+    finished: bool,
+}
+
+impl FlashHooks {
+    /// Run the pre-flash hook, if any
+    pub fn start(
+        flash_args: &FlashArgs,
+        connect_args: &ConnectArgs,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let hooks = FlashHooks {
+            post_flash: flash_args
+                .post_flash_hook
+                .clone()
+                .or_else(|| config.post_flash_hook.clone()),
+            port: connect_args
+                .port
+                .clone()
+                .or_else(|| config.connection.serial.clone()),
+            chip: connect_args.chip.or(config.chip),
+            image: None,
+            finished: false,
+        };
+
+        let pre_flash = flash_args
+            .pre_flash_hook
+            .as_deref()
+            .or(config.pre_flash_hook.as_deref());
+        if let Some(command) = pre_flash {
+            hooks.run("pre-flash", command, None)?;
+        }
+
+        Ok(hooks)
+    }
+
+    /// Record the serial port and chip of the connected target device
+    pub fn connected(&mut self, flasher: &mut Flasher) {
+        if let Some(port) = flasher.connection().port_name() {
+            self.port = Some(port);
+        }
+        self.chip = Some(flasher.chip());
+    }
+
+    /// Record the path of the image being flashed
+    pub fn set_image(&mut self, image: &Path) {
+        self.image = Some(image.to_path_buf());
+    }
+
+    /// Run the post-flash hook, if any, after flashing succeeded
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.finished = true;
+
+        match &self.post_flash {
+            Some(command) => self.run("post-flash", command, Some(true)),
+            None => Ok(()),
+        }
+    }
+
+    fn run(&self, hook: &'static str, command: &str, success: Option<bool>) -> Result<(), Error> {
+        debug!("Running {hook} hook: {command}");
+
+        let mut cmd = shell(command);
+        if let Some(port) = &self.port {
+            cmd.env("ESPFLASH_PORT", port);
+        }
+        if let Some(chip) = self.chip {
+            cmd.env("ESPFLASH_CHIP", chip.to_string());
+        }
+        if let Some(image) = &self.image {
+            cmd.env("ESPFLASH_IMAGE", image);
+        }
+        if let Some(success) = success {
+            cmd.env(
+                "ESPFLASH_RESULT",
+                if success { "success" } else { "failure" },
+            );
+        }
+        // Keep stdout for the JSON document
+        if is_json() {
+            cmd.stdout(Stdio::from(io::stderr()));
+        }
+
+        let status = cmd.status()?;
+        if !status.success() {
+            return Err(Error::HookFailed {
+                hook,
+                command: command.to_string(),
+                status: status.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for FlashHooks {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        if let Some(command) = &self.post_flash {
+            if let Err(err) = self.run("post-flash", command, Some(false)) {
+                warn!("{err}");
+            }
+        }
+    }
+}
+
+/// Command running the given command line with the system shell
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    cmd.arg(command);
+    cmd
+}
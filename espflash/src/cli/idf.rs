@@ -0,0 +1,283 @@
+//! ESP-IDF build output
+//!
+//! Projects built with ESP-IDF describe the artifacts to be flashed, and the
+//! settings to flash them with, in `flasher_args.json` and `flash_args` in
+//! their build directory. These are read so that ESP-IDF projects may be
+//! flashed without translating the arguments passed to esptool.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{de::IntoDeserializer, Deserialize};
+
+use crate::{
+    cli::parse_uint32,
+    error::Error,
+    flasher::{FlashFrequency, FlashMode, FlashSettings, FlashSize},
+    targets::Chip,
+};
+
+/// Contents of `flasher_args.json`
+#[derive(Debug, Deserialize)]
+struct FlasherArgs {
+    #[serde(default)]
+    flash_settings: HashMap<String, String>,
+    flash_files: HashMap<String, String>,
+    app: Option<FlasherArgsFile>,
+    #[serde(default)]
+    extra_esptool_args: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FlasherArgsFile {
+    file: String,
+}
+
+/// Artifacts of an ESP-IDF build, and the settings to flash them with
+#[derive(Debug, Default)]
+pub struct IdfBuild {
+    /// Chip the project was built for, if recorded
+    pub chip: Option<Chip>,
+    /// Flash settings the project was built with
+    pub flash_settings: FlashSettings,
+    /// Files to be written to flash, and their offsets
+    pub files: Vec<(u32, PathBuf)>,
+    /// ELF file of the application, if it could be found
+    pub app_elf: Option<PathBuf>,
+}
+
+impl IdfBuild {
+    /// Load the build output from an ESP-IDF build directory, preferring
+    /// `flasher_args.json` over `flash_args`
+    pub fn load(build_dir: &Path) -> Result<Self> {
+        let json = build_dir.join("flasher_args.json");
+        let mut build = if json.exists() {
+            let contents = fs::read_to_string(&json)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", json.display()))?;
+
+            Self::from_json(&contents)?
+        } else {
+            let args = build_dir.join("flash_args");
+            let contents = fs::read_to_string(&args)
+                .into_diagnostic()
+                .wrap_err_with(|| {
+                    format!(
+                        "Failed to read {}, is this an ESP-IDF build directory?",
+                        args.display()
+                    )
+                })?;
+
+            Self::from_flash_args(&contents)?
+        };
+
+        for (_, file) in &mut build.files {
+            *file = build_dir.join(&*file);
+        }
+        build.app_elf = build
+            .app_elf
+            .map(|elf| build_dir.join(elf))
+            .filter(|elf| elf.exists());
+
+        Ok(build)
+    }
+
+    fn from_json(contents: &str) -> Result<Self> {
+        let args: FlasherArgs = serde_json::from_str(contents).into_diagnostic()?;
+
+        let setting = |name: &str| args.flash_settings.get(name).map(String::as_str);
+        let flash_settings = FlashSettings::new(
+            setting("flash_mode")
+                .map(parse_flash_mode)
+                .transpose()?
+                .flatten(),
+            setting("flash_size")
+                .map(parse_flash_size)
+                .transpose()?
+                .flatten(),
+            setting("flash_freq")
+                .map(parse_flash_freq)
+                .transpose()?
+                .flatten(),
+        );
+
+        let chip = match args.extra_esptool_args.get("chip") {
+            Some(serde_json::Value::String(chip)) => Some(parse_chip(chip)?),
+            _ => None,
+        };
+
+        let mut files = args
+            .flash_files
+            .iter()
+            .map(|(offset, file)| Ok((parse_offset(offset)?, PathBuf::from(file))))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+
+        let app_elf = args
+            .app
+            .map(|app| Path::new(&app.file).with_extension("elf"));
+
+        Ok(Self {
+            chip,
+            flash_settings,
+            files,
+            app_elf,
+        })
+    }
+
+    fn from_flash_args(contents: &str) -> Result<Self> {
+        let mut words = contents.split_whitespace();
+        let mut build = Self::default();
+
+        while let Some(word) = words.next() {
+            let mut value = || {
+                words
+                    .next()
+                    .ok_or_else(|| Error::InvalidIdfBuild(format!("missing value for '{word}'")))
+            };
+
+            match word {
+                "--flash_mode" | "--flash-mode" => {
+                    build.flash_settings.mode = parse_flash_mode(value()?)?
+                }
+                "--flash_size" | "--flash-size" => {
+                    build.flash_settings.size = parse_flash_size(value()?)?
+                }
+                "--flash_freq" | "--flash-freq" => {
+                    build.flash_settings.freq = parse_flash_freq(value()?)?
+                }
+                // Other options, such as `--encrypt`, don't take a value
+                option if option.starts_with("--") => {}
+                offset => {
+                    let offset = parse_offset(offset)?;
+                    build.files.push((offset, PathBuf::from(value()?)));
+                }
+            }
+        }
+
+        build.files.sort();
+
+        Ok(build)
+    }
+}
+
+fn parse_offset(offset: &str) -> Result<u32> {
+    parse_uint32(offset)
+        .map_err(|_| Error::InvalidIdfBuild(format!("invalid offset '{offset}'")))
+        .into_diagnostic()
+}
+
+fn parse_chip(chip: &str) -> Result<Chip> {
+    Chip::from_str(chip)
+        .map_err(|_| Error::InvalidIdfBuild(format!("unsupported chip '{chip}'")))
+        .into_diagnostic()
+}
+
+/// Parse a flash mode, `keep` leaving the mode unchanged
+fn parse_flash_mode(mode: &str) -> Result<Option<FlashMode>, Error> {
+    if mode == "keep" {
+        return Ok(None);
+    }
+
+    FlashMode::deserialize(mode.into_deserializer())
+        .map(Some)
+        .map_err(|_: serde::de::value::Error| {
+            Error::InvalidIdfBuild(format!("invalid flash mode '{mode}'"))
+        })
+}
+
+/// Parse a flash size, such as `4MB`, `keep` leaving the size unchanged
+fn parse_flash_size(size: &str) -> Result<Option<FlashSize>, Error> {
+    if size == "keep" || size == "detect" {
+        return Ok(None);
+    }
+
+    FlashSize::deserialize(size.into_deserializer())
+        .map(Some)
+        .map_err(|_: serde::de::value::Error| {
+            Error::InvalidIdfBuild(format!("invalid flash size '{size}'"))
+        })
+}
+
+/// Parse a flash frequency in the format used by esptool, such as `80m`,
+/// `keep` leaving the frequency unchanged
+fn parse_flash_freq(freq: &str) -> Result<Option<FlashFrequency>, Error> {
+    if freq == "keep" {
+        return Ok(None);
+    }
+
+    let mhz = freq.trim_end_matches('m');
+    FlashFrequency::deserialize(format!("{mhz}MHz").into_deserializer())
+        .map(Some)
+        .map_err(|_: serde::de::value::Error| {
+            Error::InvalidIdfBuild(format!("invalid flash frequency '{freq}'"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "esp32c3")]
+    fn parses_flasher_args_json() {
+        let build = IdfBuild::from_json(
+            r#"{
+                "write_flash_args" : [ "--flash_mode", "dio", "--flash_size", "2MB", "--flash_freq", "80m" ],
+                "flash_settings" : {
+                    "flash_mode": "dio",
+                    "flash_size": "2MB",
+                    "flash_freq": "80m"
+                },
+                "flash_files" : {
+                    "0x10000" : "hello_world.bin",
+                    "0x0" : "bootloader/bootloader.bin",
+                    "0x8000" : "partition_table/partition-table.bin"
+                },
+                "app" : { "offset" : "0x10000", "file" : "hello_world.bin", "encrypted" : "false" },
+                "extra_esptool_args" : {
+                    "after"  : "hard_reset",
+                    "before" : "default_reset",
+                    "stub"   : true,
+                    "chip"   : "esp32c3"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(build.chip, Some(Chip::Esp32c3));
+        assert!(matches!(build.flash_settings.mode, Some(FlashMode::Dio)));
+        assert_eq!(build.flash_settings.size, Some(FlashSize::_2Mb));
+        assert_eq!(build.flash_settings.freq, Some(FlashFrequency::_80Mhz));
+        assert_eq!(
+            build.files,
+            [
+                (0x0, PathBuf::from("bootloader/bootloader.bin")),
+                (0x8000, PathBuf::from("partition_table/partition-table.bin")),
+                (0x10000, PathBuf::from("hello_world.bin")),
+            ]
+        );
+        assert_eq!(build.app_elf, Some(PathBuf::from("hello_world.elf")));
+    }
+
+    #[test]
+    fn parses_flash_args() {
+        let build = IdfBuild::from_flash_args(
+            "--flash_mode dio --flash_freq 40m --flash_size keep\n\
+             0x1000 bootloader/bootloader.bin\n\
+             0x10000 hello_world.bin\n\
+             0x8000 partition_table/partition-table.bin\n",
+        )
+        .unwrap();
+
+        assert_eq!(build.flash_settings.size, None);
+        assert_eq!(build.flash_settings.freq, Some(FlashFrequency::_40Mhz));
+        assert_eq!(build.files.len(), 3);
+        assert_eq!(build.files[0].0, 0x1000);
+    }
+}
@@ -12,39 +12,94 @@
 
 use std::{
     collections::HashMap,
-    fs,
-    io::Write,
+    env, fs,
+    io::{self, IsTerminal, Write},
+    net::{IpAddr, SocketAddr},
     num::ParseIntError,
     path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
 };
 
 use clap::Args;
 use clap_complete::Shell;
 use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use dialoguer::{theme::ColorfulTheme, Confirm};
 use esp_idf_part::{DataType, Partition, PartitionTable};
+use flate2::{write::GzEncoder, Compression};
 use indicatif::{style::ProgressStyle, HumanCount, ProgressBar};
 use log::{debug, info, warn};
+use md5::Md5;
 use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+use serde_json::json;
 use serialport::{FlowControl, SerialPortType, UsbPortInfo};
+use sha2::{Digest, Sha256};
 
 use self::{
     config::Config,
+    events::Event,
+    idf::IdfBuild,
     monitor::{monitor, LogFormat},
-    serial::get_serial_port_info,
+    output::{
+        is_json, is_quiet, print_json, progress_display, text, verbosity, ProgressDisplay,
+        Verbosity,
+    },
+    partition_diff::{partition_table_diff, PartitionTableAction},
+    serial::{find_port_info, get_serial_port_info},
 };
 use crate::{
-    connection::reset::{ResetAfterOperation, ResetBeforeOperation},
-    elf::ElfFirmwareImage,
+    connection::{
+        chaos::{ChaosConfig, ChaosPort},
+        reset::{usb_port_reset, ResetAfterOperation, ResetBeforeOperation},
+        rfc2217::{is_rfc2217_port, Rfc2217Port},
+        tcp::{is_tcp_port, TcpPort},
+        trace::Tracer,
+        Port,
+    },
+    elf::{ElfFirmwareImage, RomSegment, RtcSegments},
     error::{Error, MissingPartition, MissingPartitionTable},
+    file_format::{self, FileFormat},
+    flash_encryption::{ALIGNMENT, BLOCK_SIZE},
     flasher::{
-        parse_partition_table, FlashData, FlashFrequency, FlashMode, FlashSettings, FlashSize,
-        Flasher, ProgressCallbacks,
+        parse_partition_table, ConnectOptions, FlashData, FlashDataBuilder, FlashFrequency,
+        FlashMode, FlashSettings, FlashSize, FlashVoltage, Flasher, ProgressCallbacks,
+        SpiAttachParams, FLASH_SECTOR_SIZE,
+    },
+    image_format::{
+        partition_usage, signature_blocks, update_bootloader_header, AppDescriptor,
+        AppDescriptorOverrides, ImageFormat, ImageFormatKind, ImageInfo, SecureBootPublicKey,
+        TargetOs,
+    },
+    ota::{
+        inactive_ota_partition, ota_partitions, ota_slot, otadata_partition, AUTO_OTA,
+        OTADATA_SECTOR_SIZE,
     },
+    partition_table::{self, fit_app_partitions, to_toml},
+    remote::{is_remote_port, server::Server, RemotePort, Role, DEFAULT_PORT},
     targets::{Chip, XtalFrequency},
 };
 
 pub mod config;
+pub mod coredump;
+pub mod efuse;
+pub mod encryption;
+pub mod events;
+pub mod exit_code;
+pub mod filesystem;
+pub mod hooks;
+pub mod idf;
 pub mod monitor;
+pub mod nvs;
+pub mod ota;
+pub mod output;
+pub mod partition_diff;
+pub mod provision;
+pub mod report;
+pub mod rf_calibration;
+pub mod script;
+pub mod shell;
+pub mod stub;
 
 mod serial;
 
@@ -53,29 +108,83 @@ mod serial;
 #[non_exhaustive]
 pub struct ConnectArgs {
     /// Reset operation to perform after connecting to the target
-    #[arg(short = 'a', long, default_value = "hard-reset")]
+    #[arg(
+        short = 'a',
+        long,
+        default_value = "hard-reset",
+        env = "ESPFLASH_AFTER"
+    )]
     pub after: ResetAfterOperation,
     /// Baud rate at which to communicate with target device
     #[arg(short = 'B', long, env = "ESPFLASH_BAUD")]
     pub baud: Option<u32>,
     /// Reset operation to perform before connecting to the target
-    #[arg(short = 'b', long, default_value = "default-reset")]
+    #[arg(
+        short = 'b',
+        long,
+        default_value = "default-reset",
+        env = "ESPFLASH_BEFORE"
+    )]
     pub before: ResetBeforeOperation,
     /// Target device
-    #[arg(short = 'c', long)]
+    #[arg(short = 'c', long, env = "ESPFLASH_CHIP")]
     pub chip: Option<Chip>,
     /// Require confirmation before auto-connecting to a recognized device.
     #[arg(short = 'C', long)]
     pub confirm_port: bool,
+    /// Use RTS/CTS hardware flow control while transferring data, if the
+    /// serial adapter supports it
+    #[arg(long)]
+    pub flow_control: bool,
+    /// Override the supply voltage of the flash until the device is reset
+    ///
+    /// Required to access the flash of boards whose strapping pin or eFuses
+    /// select the wrong voltage. Only supported by the ESP32.
+    #[arg(long, value_name = "VOLTAGE", value_enum, default_value = "keep")]
+    pub flash_voltage: FlashVoltage,
+    /// GPIO pins the SPI flash is connected to, in the form CLK,Q,D,HD,CS,
+    /// or one of the presets `spi`, `hspi` and `pico-d4`
+    ///
+    /// Required for boards which connect the flash to pins other than those
+    /// selected by the eFuses. By default, the eFuse pins and those of the
+    /// ESP32-PICO-D4 are tried.
+    #[arg(long, value_name = "PINS")]
+    pub spi_connection: Option<SpiAttachParams>,
+    /// Target the external flash chip connected to the `--spi-connection`
+    /// pins, rather than the boot flash
+    ///
+    /// The size of the external flash is detected separately, and all erase,
+    /// write and read operations apply to it. Confirmation is required when
+    /// running interactively.
+    #[arg(long, requires = "spi_connection")]
+    pub external_flash: bool,
     /// List all available ports.
     #[arg(long)]
     pub list_all_ports: bool,
     /// Do not use the RAM stub for loading
     #[arg(long)]
     pub no_stub: bool,
-    /// Serial port connected to target device
+    /// Serial port connected to target device, `espflash://<HOST>:<PORT>` to
+    /// use a serial port shared by an espflash daemon,
+    /// `rfc2217://<HOST>:<PORT>` to use one shared by an RFC 2217 server, or
+    /// `tcp://<HOST>:<PORT>` to use one bridged over a plain TCP socket
     #[arg(short = 'p', long, env = "ESPFLASH_PORT")]
     pub port: Option<String>,
+    /// Authentication token for connections to, or clients of, an espflash
+    /// daemon
+    #[arg(long, env = "ESPFLASH_REMOTE_TOKEN", hide_env_values = true)]
+    pub remote_token: Option<String>,
+    /// Log every serial protocol frame sent to and received from the target
+    /// device to stderr
+    #[arg(long)]
+    pub trace: bool,
+    /// Write the serial protocol trace to the given file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    pub trace_file: Option<PathBuf>,
+    /// Inject faults into the serial connection, to test the recovery of the
+    /// serial protocol, such as `drop=0.001,corrupt=0.001,delay=0.01,seed=42`
+    #[arg(long, value_name = "FAULTS", hide = true)]
+    pub chaos: Option<ChaosConfig>,
 }
 
 /// Generate completions for the given shell
@@ -95,19 +204,48 @@ pub struct EraseFlashArgs {
     pub connect_args: ConnectArgs,
 }
 
-/// Erase specified region of flash
+/// Erase specified regions of flash
 #[derive(Debug, Args)]
 #[non_exhaustive]
 pub struct EraseRegionArgs {
     /// Connection configuration
     #[clap(flatten)]
     pub connect_args: ConnectArgs,
-    /// Offset to start erasing from
-    #[arg(value_name = "OFFSET", value_parser = parse_uint32)]
-    pub addr: u32,
-    /// Size of the region to erase
-    #[arg(value_name = "SIZE", value_parser = parse_uint32)]
-    pub size: u32,
+    /// Offset to start erasing from, and size of the region to erase. Several
+    /// regions may be erased by giving further pairs of OFFSET and SIZE
+    ///
+    /// Regions which are not aligned to 4KB flash sectors are extended to
+    /// whole sectors.
+    #[arg(
+        value_name = "OFFSET SIZE",
+        value_parser = parse_uint32,
+        num_args = 2..,
+        required = true
+    )]
+    pub regions: Vec<u32>,
+    /// Refuse to erase regions which are not aligned to 4KB flash sectors,
+    /// rather than extending them
+    #[arg(long)]
+    pub strict: bool,
+}
+
+/// Erase the OTA data and NVS partitions, so that the factory app is booted
+/// with clean settings
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FactoryResetArgs {
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Labels of additional partitions to be erased
+    #[arg(long, value_name = "LABELS", value_delimiter = ',')]
+    pub erase_parts: Vec<String>,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
 }
 
 /// Configure communication with the target device's flash
@@ -115,13 +253,31 @@ pub struct EraseRegionArgs {
 #[non_exhaustive]
 pub struct FlashConfigArgs {
     /// Flash frequency
-    #[arg(short = 'f', long, value_name = "FREQ", value_enum)]
+    #[arg(
+        short = 'f',
+        long,
+        value_name = "FREQ",
+        value_enum,
+        env = "ESPFLASH_FLASH_FREQ"
+    )]
     pub flash_freq: Option<FlashFrequency>,
     /// Flash mode to use
-    #[arg(short = 'm', long, value_name = "MODE", value_enum)]
+    #[arg(
+        short = 'm',
+        long,
+        value_name = "MODE",
+        value_enum,
+        env = "ESPFLASH_FLASH_MODE"
+    )]
     pub flash_mode: Option<FlashMode>,
     /// Flash size of the target
-    #[arg(short = 's', long, value_name = "SIZE", value_enum)]
+    #[arg(
+        short = 's',
+        long,
+        value_name = "SIZE",
+        value_enum,
+        env = "ESPFLASH_FLASH_SIZE"
+    )]
     pub flash_size: Option<FlashSize>,
 }
 
@@ -137,13 +293,18 @@ pub struct FlashArgs {
     #[arg(long, value_name = "PARTS", value_enum, value_delimiter = ',')]
     pub erase_data_parts: Option<Vec<DataType>>,
     /// Logging format.
-    #[arg(long, short = 'L', default_value = "serial", requires = "monitor")]
+    #[arg(
+        long,
+        short = 'L',
+        default_value = "serial",
+        env = "ESPFLASH_LOG_FORMAT"
+    )]
     pub log_format: LogFormat,
     /// Open a serial monitor after flashing
     #[arg(short = 'M', long)]
     pub monitor: bool,
     /// Baud rate at which to read console output
-    #[arg(long, requires = "monitor", value_name = "BAUD")]
+    #[arg(long, value_name = "BAUD", env = "ESPFLASH_MONITOR_BAUD")]
     pub monitor_baud: Option<u32>,
     /// Load the application to RAM instead of Flash
     #[arg(long)]
@@ -151,29 +312,220 @@ pub struct FlashArgs {
     /// Don't verify the flash contents after flashing
     #[arg(long)]
     pub no_verify: bool,
+    /// Don't compress data before writing it to the flash
+    ///
+    /// This is much slower, but may help when the loader's decompressor is
+    /// suspected of corrupting the data written.
+    #[arg(long)]
+    pub no_compress: bool,
+    /// Number of times the write of each segment is resumed after failing
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub retries: u32,
+    /// Reset and reconnect to the device if it stops responding while
+    /// writing, then resume the write
+    #[arg(long)]
+    pub reconnect: bool,
     /// Don't skip flashing of parts with matching checksum
     #[arg(long)]
     pub no_skip: bool,
+    /// Validate the image without writing it, reporting any problems
+    #[arg(
+        long,
+        conflicts_with_all = ["erase_parts", "erase_data_parts", "monitor", "ram"]
+    )]
+    pub check: bool,
+    /// After writing the app to an OTA app partition, update `otadata` so that
+    /// it is booted next
+    #[arg(long, requires = "target_app_partition", conflicts_with_all = ["check", "ram"])]
+    pub activate: bool,
+    /// Write a file which was encrypted ahead of time, such as by a secure build
+    /// server, at the given address after the application. May be specified
+    /// multiple times
+    ///
+    /// The files are written verbatim: they are not encrypted on the host or
+    /// by the device, and are always written rather than skipped when
+    /// unchanged.
+    #[arg(
+        long,
+        value_name = "ADDR=FILE",
+        value_parser = parse_pre_encrypted_file,
+        conflicts_with_all = ["check", "ram"]
+    )]
+    pub pre_encrypted: Vec<(u32, PathBuf)>,
+    /// Read the eFuses describing the flash embedded in the chip, and warn if
+    /// the flash size does not match it
+    #[arg(long)]
+    pub check_embedded_flash: bool,
+    /// Write a JSON report of the operation to this file, describing the
+    /// device and each artifact written along with its digests
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["monitor", "check"])]
+    pub report: Option<PathBuf>,
+    /// Build and validate everything which would be flashed, and write the
+    /// resulting flash contents to this file, padded to the flash size, instead
+    /// of to a target device
+    ///
+    /// No device is connected to, so the chip must be provided with `--chip`,
+    /// and the flash hooks are not run.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "erase_parts",
+            "erase_data_parts",
+            "monitor",
+            "ram",
+            "check",
+            "activate",
+            "check_embedded_flash",
+            "report",
+        ]
+    )]
+    pub dry_run: Option<PathBuf>,
+    /// Command to run before connecting to the target device
+    #[arg(long, value_name = "COMMAND")]
+    pub pre_flash_hook: Option<String>,
+    /// Command to run after flashing, successfully or not, and before opening
+    /// the serial monitor
+    #[arg(long, value_name = "COMMAND")]
+    pub post_flash_hook: Option<String>,
     #[clap(flatten)]
     pub image: ImageArgs,
 }
 
+/// Read the description of an application from a target device's flash
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct AppInfoArgs {
+    /// Label of the app partition to read, the one booted by the bootloader
+    /// if not provided
+    #[arg(long, value_name = "LABEL")]
+    pub partition: Option<String>,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// Check whether a target device is running a given application
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct CheckUpdateArgs {
+    /// ELF file or application image (.bin) to compare the device's app with
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+    /// Label of the app partition to check, the one booted by the bootloader
+    /// if not provided
+    #[arg(long, value_name = "LABEL")]
+    pub partition: Option<String>,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Flashing configuration, used to build the application image when an
+    /// ELF file is given
+    #[clap(flatten)]
+    pub flash_config_args: FlashConfigArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// Display information about an application or bootloader image
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ImageInfoArgs {
+    /// Image to inspect
+    #[arg(value_name = "FILE")]
+    pub image: PathBuf,
+    /// Recalculate the image's checksum and SHA256 digest if they do not match
+    /// its contents, and update the file
+    #[arg(long)]
+    pub fix_hash: bool,
+}
+
+/// Verify the Secure Boot V2 signatures of a signed image
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct VerifySignatureArgs {
+    /// Signed bootloader or application image
+    #[arg(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// Public key (PEM) which must have made a valid signature of the image.
+    /// May be given multiple times
+    #[arg(long, value_name = "FILE")]
+    pub key: Vec<PathBuf>,
+}
+
 /// Operations for partitions tables
 #[derive(Debug, Args)]
 #[non_exhaustive]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct PartitionTableArgs {
+    #[command(subcommand)]
+    action: Option<PartitionTableAction>,
     /// Optional output file name, if unset will output to stdout
     #[arg(short = 'o', long, value_name = "FILE")]
     output: Option<PathBuf>,
-    /// Input partition table
-    #[arg(value_name = "FILE")]
-    partition_table: PathBuf,
-    /// Convert CSV partition table to binary representation
-    #[arg(long, conflicts_with = "to_csv")]
+    /// Input partition table, in CSV, binary or TOML format
+    #[arg(
+        value_name = "FILE",
+        required_unless_present = "device",
+        conflicts_with = "device"
+    )]
+    partition_table: Option<PathBuf>,
+    /// Show how full each app partition would be with the given app image or
+    /// ELF file
+    ///
+    /// The chip must be given using `--chip` for ELF files.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["to_binary", "to_csv", "to_toml"])]
+    app: Option<PathBuf>,
+    /// Resize the app partitions to fit the given app image or ELF file, and
+    /// write the adjusted partition table
+    ///
+    /// The partitions following the first app partition are moved to follow
+    /// the resized ones. The table is written in CSV format, unless
+    /// `--to-binary` or `--to-toml` is given. The chip must be given using
+    /// `--chip` for ELF files.
+    #[arg(long, value_name = "FILE", conflicts_with = "app")]
+    fit: Option<PathBuf>,
+    /// Read the partition table from the target device, showing how full each
+    /// of its app partitions is
+    #[arg(long, conflicts_with_all = ["app", "fit", "to_binary", "to_csv", "to_toml"])]
+    device: bool,
+    /// Connection configuration, used with `--device`
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+    /// Size of the flash, used to show the unused space after the last
+    /// partition, and to check that partitions resized by `--fit` fit in the
+    /// flash
+    #[arg(long, value_name = "SIZE", value_enum, conflicts_with = "device")]
+    flash_size: Option<FlashSize>,
+    /// Offset of the partition table on the device, used with `--device`
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    partition_table_offset: u32,
+    /// Convert CSV or TOML partition table to binary representation
+    #[arg(long, conflicts_with_all = ["to_csv", "to_toml"])]
     to_binary: bool,
-    /// Convert binary partition table to CSV representation
-    #[arg(long, conflicts_with = "to_binary")]
+    /// Leave the MD5 checksum out of binary partition tables, for bootloaders
+    /// from ESP-IDF versions before v3.1
+    ///
+    /// The checksum is included by default, as required by bootloaders from
+    /// ESP-IDF v3.1 onwards which verify it.
+    #[arg(long, requires = "to_binary")]
+    no_md5: bool,
+    /// Convert binary or TOML partition table to CSV representation
+    #[arg(long, conflicts_with_all = ["to_binary", "to_toml"])]
     to_csv: bool,
+    /// Convert CSV or binary partition table to TOML representation
+    #[arg(long, conflicts_with_all = ["to_binary", "to_csv"])]
+    to_toml: bool,
 }
 
 /// Reads the content of flash memory and saves it to a file
@@ -200,6 +552,13 @@ pub struct ReadFlashArgs {
     /// Maximum number of un-acked packets
     #[arg(long, default_value = "64", value_parser = parse_uint32)]
     pub max_in_flight: u32,
+    /// Continue an interrupted read, appending to the file and reading only the
+    /// part of the region which it does not hold yet
+    #[arg(long, conflicts_with = "gzip")]
+    pub resume: bool,
+    /// Compress the file with gzip as it is written
+    #[arg(long)]
+    pub gzip: bool,
 }
 
 /// Save the image to disk instead of flashing to device
@@ -208,16 +567,27 @@ pub struct ReadFlashArgs {
 #[group(skip)]
 pub struct SaveImageArgs {
     /// Chip to create an image for
-    #[arg(long, value_enum)]
+    #[arg(long, value_enum, env = "ESPFLASH_CHIP")]
     pub chip: Chip,
     /// File name to save the generated image to
     pub file: PathBuf,
+    /// File format of the generated image
+    #[arg(long, value_enum, default_value_t = FileFormat::Bin)]
+    pub format: FileFormat,
     /// Boolean flag to merge binaries into single binary
     #[arg(long)]
     pub merge: bool,
     /// Don't pad the image to the flash size
     #[arg(long, short = 'P', requires = "merge")]
     pub skip_padding: bool,
+    /// Save every segment to be written to flash to its own file, prefixed by
+    /// its offset, along with a `manifest.json` file describing them
+    #[arg(long, conflicts_with = "merge")]
+    pub manifest: bool,
+    /// Pad the application image with 0xFF to the given size, or to the size
+    /// of the partition with the given label
+    #[arg(long, value_name = "SIZE|LABEL", conflicts_with_all = ["merge", "manifest"])]
+    pub pad_to: Option<PadTo>,
     /// Cristal frequency of the target
     #[arg(long, short = 'x')]
     pub xtal_freq: Option<XtalFrequency>,
@@ -229,21 +599,116 @@ pub struct SaveImageArgs {
 #[non_exhaustive]
 #[group(skip)]
 pub struct ImageArgs {
-    /// Path to a binary (.bin) bootloader file
+    /// Path to a binary (.bin) or Intel HEX (.hex) bootloader file
     #[arg(long, value_name = "FILE")]
     pub bootloader: Option<PathBuf>,
-    /// Path to a CSV file containing partition table
-    #[arg(long, short = 'T', value_name = "FILE")]
+    /// Don't write the bootloader, leaving the one already on the device in
+    /// place
+    #[arg(long, conflicts_with = "bootloader")]
+    pub no_bootloader: bool,
+    /// Path to a CSV, binary or TOML file containing partition table
+    #[arg(
+        long,
+        short = 'T',
+        value_name = "FILE",
+        env = "ESPFLASH_PARTITION_TABLE"
+    )]
     pub partition_table: Option<PathBuf>,
+    /// Don't write the partition table, leaving the one already on the device
+    /// in place
+    ///
+    /// The partition table is still used to locate the app partition, so it
+    /// must match the one on the device.
+    #[arg(long)]
+    pub no_partition_table: bool,
+    /// Leave the MD5 checksum out of the partition table
+    ///
+    /// Bootloaders from ESP-IDF versions before v3.1 do not support the
+    /// checksum, and fail to boot from tables which include it.
+    #[arg(long, conflicts_with = "no_partition_table")]
+    pub no_partition_table_md5: bool,
+    /// Write a file to the partition with the given label, in addition to the
+    /// application. May be specified multiple times
+    ///
+    /// The files are also included in merged images generated by `save-image`.
+    #[arg(long, value_name = "LABEL=FILE", value_parser = parse_partition_file)]
+    pub flash_file: Vec<(String, PathBuf)>,
     /// Partition table offset
     #[arg(long, value_name = "OFFSET")]
     pub partition_table_offset: Option<u32>,
-    /// Label of target app partition
+    /// Label of target app partition, or `auto-ota` to select the OTA app
+    /// partition which is not currently booted
     #[arg(long, value_name = "LABEL")]
     pub target_app_partition: Option<String>,
+    /// Fail if the image fills more than the given percentage of any partition
+    /// it is written to, such as the app partition
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    pub fail_if_over: Option<f32>,
     /// Minimum chip revision supported by image, in format: major.minor
     #[arg(long, default_value = "0.0", value_parser = parse_chip_rev)]
     pub min_chip_rev: u16,
+    /// Image format to generate
+    #[arg(long, value_enum, default_value_t = ImageFormatKind::EspBootloader)]
+    pub image_format: ImageFormatKind,
+    /// Path to an Ed25519 private key (PEM) used to sign MCUboot images
+    #[arg(long, value_name = "FILE")]
+    pub mcuboot_key: Option<PathBuf>,
+    /// Path to an RSA-3072 or ECDSA P-256 private key (PEM) used to sign the
+    /// bootloader and application for Secure Boot V2
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+    /// Command used to sign images for Secure Boot V2 in place of
+    /// `--sign-key`, such as one using an HSM or PKCS#11 token
+    ///
+    /// The command is split into the program and its arguments as a POSIX
+    /// shell would, so arguments containing spaces must be quoted. It is given
+    /// the SHA-256 digest to sign on its standard input, and in hexadecimal in
+    /// the `ESPFLASH_DIGEST` environment variable, and must write the
+    /// signature to its standard output.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with = "sign_key",
+        requires = "sign_public_key"
+    )]
+    pub sign_command: Option<String>,
+    /// Path to the public key (PEM) matching the key used by `--sign-command`
+    #[arg(long, value_name = "FILE", requires = "sign_command")]
+    pub sign_public_key: Option<PathBuf>,
+    /// Path to the flash encryption key used to encrypt the image on the host,
+    /// for devices with flash encryption enabled in release mode
+    ///
+    /// The bootloader, partition table, application and the partitions flagged
+    /// as `encrypted` in the partition table are encrypted, and the other
+    /// partitions are written in plaintext.
+    #[arg(long, value_name = "FILE")]
+    pub encryption_key: Option<PathBuf>,
+    /// Override the version recorded in the application description
+    #[arg(long, value_name = "VERSION")]
+    pub app_version: Option<String>,
+    /// Override the project name recorded in the application description
+    #[arg(long, value_name = "NAME")]
+    pub project_name: Option<String>,
+    /// Override the build time recorded in the application description, in
+    /// seconds since the Unix epoch
+    #[arg(long, value_name = "TIMESTAMP")]
+    pub build_time: Option<u64>,
+    /// Generate a reproducible image, by normalizing the build time recorded in
+    /// the application description to `SOURCE_DATE_EPOCH`, or the Unix epoch
+    #[arg(long)]
+    pub reproducible: bool,
+    /// How to handle sections placed in RTC memory, such as `.rtc.text` and
+    /// `.rtc.data`
+    #[arg(long, value_enum, default_value_t = RtcSegments::Load)]
+    pub rtc_segments: RtcSegments,
+    /// Operating system the application was built with, if it cannot be
+    /// detected from the ELF file
+    ///
+    /// Zephyr and NuttX applications are written in the format matching how
+    /// they are booted, unless another image format is selected; use
+    /// `esp-idf` for those booted by the ESP-IDF bootloader.
+    #[arg(long, value_enum)]
+    pub os: Option<TargetOs>,
 }
 
 /// Open the serial monitor without flashing
@@ -257,22 +722,76 @@ pub struct MonitorArgs {
     #[arg(short = 'e', long, value_name = "FILE")]
     elf: Option<PathBuf>,
     /// Avoids asking the user for interactions like resetting the device
-    #[arg(long)]
+    #[arg(long, env = "ESPFLASH_NON_INTERACTIVE")]
     non_interactive: bool,
-    /// Logging format.
-    #[arg(long, short = 'L', default_value = "serial", requires = "elf")]
+    /// Logging format, `defmt` requires `--elf`
+    #[arg(
+        long,
+        short = 'L',
+        default_value = "serial",
+        env = "ESPFLASH_LOG_FORMAT"
+    )]
     pub log_format: LogFormat,
 }
 
+/// Share a serial port with other instances of espflash over the network
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ServeArgs {
+    /// Baud rate at which to communicate with target device, restored whenever
+    /// a flash client detaches
+    #[arg(short = 'B', long, env = "ESPFLASH_BAUD")]
+    pub baud: Option<u32>,
+    /// IP address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+    /// Require confirmation before auto-connecting to a recognized device.
+    #[arg(short = 'C', long)]
+    pub confirm_port: bool,
+    /// Use RTS/CTS hardware flow control, if the serial adapter supports it
+    #[arg(long)]
+    pub flow_control: bool,
+    /// List all available ports.
+    #[arg(long)]
+    pub list_all_ports: bool,
+    /// TCP port to listen on
+    #[arg(long, default_value_t = DEFAULT_PORT)]
+    pub listen_port: u16,
+    /// Serial port connected to target device
+    #[arg(short = 'p', long, env = "ESPFLASH_PORT")]
+    pub port: Option<String>,
+    /// Authentication token which clients must present
+    #[arg(long, env = "ESPFLASH_REMOTE_TOKEN", hide_env_values = true)]
+    pub remote_token: Option<String>,
+}
+
 #[derive(Debug, Args)]
 #[non_exhaustive]
 pub struct ChecksumMd5Args {
-    /// Start address
-    #[clap(short, long, value_parser=parse_u32)]
-    address: u32,
-    /// Length
-    #[clap(short, long, value_parser=parse_u32)]
-    length: u32,
+    /// Start address, 0 by default when comparing with a file
+    #[clap(
+        short,
+        long,
+        visible_alias = "offset",
+        value_parser = parse_u32,
+        required_unless_present = "compare_file"
+    )]
+    address: Option<u32>,
+    /// Length, that of the file when comparing with a file
+    #[clap(
+        short,
+        long,
+        value_parser = parse_u32,
+        required_unless_present = "compare_file",
+        conflicts_with = "compare_file"
+    )]
+    length: Option<u32>,
+    /// Compare the region with this file, by computing its MD5 checksum on the
+    /// host rather than reading the region back
+    ///
+    /// Fails if the checksums differ.
+    #[clap(long, value_name = "FILE")]
+    compare_file: Option<PathBuf>,
     /// Connection configuration
     #[clap(flatten)]
     connect_args: ConnectArgs,
@@ -298,8 +817,139 @@ pub fn connect(
         );
     }
 
+    let (mut serial_port, port_info) = open_port(args, config, Role::Flash)?;
+    if let Some(chaos) = args.chaos {
+        let chaos_port = ChaosPort::new(serial_port, chaos);
+        warn!(
+            "Injecting faults into the serial connection: {}",
+            chaos_port.config()
+        );
+        serial_port = Box::new(chaos_port);
+    }
+
+    let tracer = match &args.trace_file {
+        Some(path) => Some(
+            Tracer::file(path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create trace file {}", path.display()))?,
+        ),
+        None if args.trace || verbosity() >= Verbosity::Trace => Some(Tracer::stderr()),
+        None => None,
+    };
+
+    // The device would switch to a baud rate which the bridge does not follow
+    let mut baud = args.baud.or(config.baudrate);
+    if baud.is_some() && args.port.as_deref().is_some_and(is_tcp_port) {
+        warn!("The baud rate of a TCP serial bridge is configured on the bridge, ignoring it");
+        baud = None;
+    }
+
+    let mut flasher = Flasher::connect(
+        serial_port,
+        port_info,
+        ConnectOptions {
+            speed: baud,
+            use_stub: !args.no_stub,
+            verify: !no_verify,
+            skip: !no_skip,
+            chip: args.chip.or(config.chip),
+            after_operation: args.after,
+            before_operation: args.before,
+            tracer,
+            flash_voltage: args.flash_voltage,
+            spi_connection: args.spi_connection.filter(|_| !args.external_flash),
+            stub_cache: Some(stub::stub_cache_dir()),
+        },
+    )?;
+
+    if args.flow_control {
+        debug!("Enabling hardware flow control");
+        flasher.connection().set_hardware_flow_control(true)?;
+    }
+
+    // The boot flash is detected as usual before attaching the external flash,
+    // so that both sizes are known
+    if let Some(spi_params) = args.spi_connection.filter(|_| args.external_flash) {
+        flasher.attach_external_flash(spi_params)?;
+
+        if io::stdin().is_terminal() && !confirm_external_flash(&mut flasher)? {
+            return Err(Error::Cancelled.into());
+        }
+    }
+
+    events::emit(Event::Connected {
+        chip: flasher.chip().to_string(),
+        port: flasher.connection().port_name(),
+    });
+
+    Ok(flasher)
+}
+
+/// Ask the user to confirm that the external flash should be modified
+fn confirm_external_flash(flasher: &mut Flasher) -> Result<bool, Error> {
+    let size = flasher.device_info()?.flash_size;
+
+    Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "All operations will target the {size} external flash, rather than the boot flash. Continue?"
+        ))
+        .interact_opt()?
+        .ok_or(Error::Cancelled)
+}
+
+/// Open the serial port, or connect to the espflash daemon sharing it, taking
+/// the given role, or to the RFC 2217 server or TCP bridge sharing it
+fn open_port(args: &ConnectArgs, config: &Config, role: Role) -> Result<(Port, UsbPortInfo)> {
+    // Neither RFC 2217 servers nor TCP bridges tell which device is attached
+    let unknown_port_info = UsbPortInfo {
+        vid: 0,
+        pid: 0,
+        serial_number: None,
+        manufacturer: None,
+        product: None,
+    };
+
+    if let Some(url) = args.port.as_deref().filter(|port| is_rfc2217_port(port)) {
+        info!("RFC 2217 serial port: '{}'", url);
+        info!("Connecting...");
+
+        let port = Rfc2217Port::connect(url, 115_200)?;
+
+        return Ok((Box::new(port), unknown_port_info));
+    }
+
+    if let Some(url) = args.port.as_deref().filter(|port| is_tcp_port(port)) {
+        info!("TCP serial bridge: '{}'", url);
+        info!("Connecting...");
+
+        let port = TcpPort::connect(url, 115_200)?;
+
+        return Ok((Box::new(port), unknown_port_info));
+    }
+
+    if let Some(url) = args.port.as_deref().filter(|port| is_remote_port(port)) {
+        info!("Remote serial port: '{}'", url);
+        info!("Connecting...");
+
+        let port = RemotePort::connect(url, role, args.remote_token.as_deref())?;
+        let port_info = UsbPortInfo {
+            vid: port.vid(),
+            pid: port.pid(),
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+
+        return Ok((Box::new(port), port_info));
+    }
+
     let port_info = get_serial_port_info(args, config)?;
 
+    if args.before == ResetBeforeOperation::UsbPortReset {
+        info!("Resetting USB port: '{}'", port_info.port_name);
+        usb_port_reset(&port_info.port_name)?;
+    }
+
     // Attempt to open the serial port and set its initial baud rate.
     info!("Serial port: '{}'", port_info.port_name);
     info!("Connecting...");
@@ -327,33 +977,306 @@ pub fn connect(
         _ => unreachable!(),
     };
 
-    Ok(Flasher::connect(
-        *Box::new(serial_port),
-        port_info,
-        args.baud.or(config.baudrate),
-        !args.no_stub,
-        !no_verify,
-        !no_skip,
-        args.chip,
-        args.after,
-        args.before,
-    )?)
+    Ok((Box::new(serial_port), port_info))
 }
 
 /// Connect to a target device and print information about its chip
 pub fn board_info(args: &ConnectArgs, config: &Config) -> Result<()> {
     let mut flasher = connect(args, config, true, true)?;
-    print_board_info(&mut flasher)?;
+
+    if is_json() {
+        let info = flasher.device_info()?;
+        print_json(&json!({
+            "chip": info.chip,
+            "revision": info.revision.map(|(major, minor)| format!("{major}.{minor}")),
+            "crystal_frequency": info.crystal_frequency.to_string(),
+            "flash_size": info.flash_size,
+            "boot_flash_size": flasher.boot_flash_size(),
+            "features": info.features,
+            "mac_address": info.mac_address,
+            "psram": info.psram,
+            "embedded_flash": info.embedded_flash,
+        }))?;
+    } else {
+        for line in board_info_lines(&mut flasher)? {
+            println!("{line}");
+        }
+    }
 
     Ok(())
 }
 
 /// Connect to a target device and calculate the checksum of the given region
 pub fn checksum_md5(args: &ChecksumMd5Args, config: &Config) -> Result<()> {
+    let address = args.address.unwrap_or(0);
+    let file_data = args
+        .compare_file
+        .as_ref()
+        .map(|path| fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e)))
+        .transpose()?;
+    // Clap requires a length unless a file is compared
+    let length = match &file_data {
+        Some(data) => data.len() as u32,
+        None => args.length.unwrap(),
+    };
+
     let mut flasher = connect(&args.connect_args, config, true, true)?;
 
-    let checksum = flasher.checksum_md5(args.address, args.length)?;
-    println!("0x{:x}", checksum);
+    let checksum = flasher.checksum_md5(address, length)?;
+    let Some(data) = file_data else {
+        if is_json() {
+            print_json(&json!({
+                "address": address,
+                "length": length,
+                "md5": format!("{checksum:032x}"),
+            }))?;
+        } else {
+            println!("0x{:x}", checksum);
+        }
+
+        return Ok(());
+    };
+
+    let file_checksum = u128::from_be_bytes(Md5::digest(&data).into());
+    let matches = checksum == file_checksum;
+    if is_json() {
+        print_json(&json!({
+            "address": address,
+            "length": length,
+            "md5": format!("{checksum:032x}"),
+            "file_md5": format!("{file_checksum:032x}"),
+            "match": matches,
+        }))?;
+    } else {
+        println!("Device: 0x{checksum:032x}");
+        println!("File:   0x{file_checksum:032x}");
+    }
+
+    if !matches {
+        return Err(Error::VerifyFailed).wrap_err_with(|| {
+            format!(
+                "The {length} bytes at 0x{address:08x} differ from {}",
+                args.compare_file.as_ref().unwrap().display()
+            )
+        });
+    }
+    info!("The {length} bytes at 0x{address:08x} match the file");
+
+    Ok(())
+}
+
+/// Connect to a target device and print the description of the application in
+/// one of its app partitions
+pub fn app_info(args: AppInfoArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+
+    let partition = match &args.partition {
+        Some(label) => partition_table
+            .find(label)
+            .filter(|partition| partition.ty() == esp_idf_part::Type::App)
+            .ok_or_else(|| Error::PartitionNotFound(label.clone()))?,
+        None => booted_partition(&mut flasher, &partition_table)?,
+    };
+    let desc = flasher.read_app_descriptor(partition)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    if is_json() {
+        return print_json(&json!({
+            "partition": partition.name(),
+            "app_descriptor": desc.as_ref().map(app_descriptor_json),
+        }));
+    }
+
+    println!("Partition:        {}", partition.name());
+    match desc {
+        Some(desc) => print_app_descriptor(&desc),
+        None => println!("The app has no application description"),
+    }
+
+    Ok(())
+}
+
+/// Connect to a target device and check whether the app in one of its app
+/// partitions is the given application, returning `false` if it needs to be
+/// updated
+///
+/// ELF files are converted to the application image `flash` would write with
+/// the same flash settings. The device is running the application if its app
+/// image has the same length and MD5 digest, which the stub computes without
+/// reading the image back.
+pub fn check_update(args: CheckUpdateArgs, config: &Config) -> Result<bool> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let data = fs::read(&args.file)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.file.display()))?;
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+
+    let app = if data.starts_with(b"\x7fELF") {
+        let chip = flasher.chip();
+        let xtal_freq = chip.into_target().crystal_freq(flasher.connection())?;
+        let flash_data = FlashDataBuilder::new()
+            .with_flash_settings(make_flash_settings(&args.flash_config_args, config))
+            .build()?;
+
+        let elf = ElfFirmwareImage::try_from(data.as_slice())?;
+        let image = chip.get_flash_image(&elf, flash_data, None, xtal_freq)?;
+        let mut app = Vec::new();
+        for segment in image.ota_segments() {
+            app.extend_from_slice(&segment.data);
+        }
+        app
+    } else {
+        data
+    };
+
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+    let partition = match &args.partition {
+        Some(label) => partition_table
+            .find(label)
+            .filter(|partition| partition.ty() == esp_idf_part::Type::App)
+            .ok_or_else(|| Error::PartitionNotFound(label.clone()))?,
+        None => booted_partition(&mut flasher, &partition_table)?,
+    };
+
+    let desc = flasher.read_app_descriptor(partition)?;
+    let up_to_date = match flasher.read_app_image_len(partition)? {
+        Some(len) if len == app.len() => {
+            let checksum = flasher.checksum_md5(partition.offset(), len as u32)?;
+            checksum.to_be_bytes() == Md5::digest(&app).as_slice()
+        }
+        _ => false,
+    };
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    println!("Partition:        {}", partition.name());
+    match desc {
+        Some(desc) => print_app_descriptor(&desc),
+        None => println!("The app has no application description"),
+    }
+    if let Some(desc) = AppDescriptor::from_image(&app) {
+        println!();
+        println!("{}:", args.file.display());
+        print_app_descriptor(&desc);
+    }
+    println!();
+
+    if up_to_date {
+        info!("The device is running this application");
+    } else {
+        info!("The device is not running this application, it needs to be updated");
+    }
+
+    Ok(up_to_date)
+}
+
+/// The app partition booted by the bootloader: the OTA app partition selected
+/// by the OTA data, or the factory app partition
+fn booted_partition<'t>(
+    flasher: &mut Flasher,
+    partition_table: &'t PartitionTable,
+) -> Result<&'t Partition> {
+    let partitions = ota_partitions(partition_table);
+    if !partitions.is_empty() && otadata_partition(partition_table).is_ok() {
+        let otadata = flasher.read_otadata(partition_table)?;
+        if let Some(slot) = otadata.active_slot(partitions.len()) {
+            return Ok(partitions[slot]);
+        }
+    }
+
+    Ok(partition_table
+        .find("factory")
+        .or_else(|| partition_table.find_by_type(esp_idf_part::Type::App))
+        .ok_or(Error::AppPartitionNotFound)?)
+}
+
+/// Application description, as printed in JSON
+fn app_descriptor_json(desc: &AppDescriptor) -> serde_json::Value {
+    json!({
+        "project_name": desc.project_name,
+        "version": desc.version,
+        "secure_version": desc.secure_version,
+        "date": desc.date,
+        "time": desc.time,
+        "idf_version": desc.idf_version,
+        "elf_sha256": hex::encode(desc.elf_sha256),
+    })
+}
+
+fn print_app_descriptor(desc: &AppDescriptor) {
+    println!("Project name:     {}", desc.project_name);
+    println!("App version:      {}", desc.version);
+    println!("Secure version:   {}", desc.secure_version);
+    println!("Compile time:     {} {}", desc.date, desc.time);
+    println!("ESP-IDF version:  {}", desc.idf_version);
+    println!("ELF file SHA256:  {}", hex::encode(desc.elf_sha256));
+}
+
+/// Connect to a target device and print the SFDP tables of its flash chip
+pub fn read_sfdp(args: &ConnectArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect(args, config, true, true)?;
+    let sfdp = flasher.read_sfdp()?;
+
+    println!("SFDP revision:     {}.{}", sfdp.revision.0, sfdp.revision.1);
+
+    if let Some(bfpt) = sfdp.basic_flash_parameters() {
+        let duration = |duration: Option<Duration>| {
+            duration.map_or_else(|| "unknown".to_string(), |duration| format!("{duration:?}"))
+        };
+
+        println!("Capacity:          {} bytes", bfpt.size);
+        if let Some(page_size) = bfpt.page_size {
+            println!("Page size:         {page_size} bytes");
+        }
+        println!("Page program time: {}", duration(bfpt.page_program_time));
+        println!("Chip erase time:   {}", duration(bfpt.chip_erase_time));
+        for erase in &bfpt.erase_types {
+            println!(
+                "Erase type:        {:#x} bytes, opcode {:#04x}, {}",
+                erase.size,
+                erase.opcode,
+                duration(erase.typical_time)
+            );
+        }
+    }
+
+    for parameter in &sfdp.parameters {
+        println!();
+        println!(
+            "Table {:#06x} (revision {}.{}) at {:#08x}:",
+            parameter.id, parameter.revision.0, parameter.revision.1, parameter.pointer
+        );
+        for (i, dword) in parameter.data.chunks(4).enumerate() {
+            let dword = dword
+                .iter()
+                .rev()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+            println!("  DWORD {:<2}  0x{dword}", i + 1);
+        }
+    }
 
     Ok(())
 }
@@ -395,29 +1318,147 @@ pub fn parse_chip_rev(chip_rev: &str) -> Result<u16> {
     Ok(major * 100 + minor)
 }
 
-/// Print information about a chip
-pub fn print_board_info(flasher: &mut Flasher) -> Result<()> {
-    let info = flasher.device_info()?;
+/// Parses a partition label and file path in the format: label=path
+pub fn parse_partition_file(arg: &str) -> Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((label, path)) if !label.is_empty() && !path.is_empty() => {
+            Ok((label.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(format!("expected LABEL=FILE, found `{arg}`")),
+    }
+}
 
-    print!("Chip type:         {}", info.chip);
-    if let Some((major, minor)) = info.revision {
-        println!(" (revision v{major}.{minor})");
-    } else {
-        println!();
+pub fn parse_pre_encrypted_file(arg: &str) -> Result<(u32, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((addr, path)) if !path.is_empty() => {
+            let addr = parse_u32(addr).map_err(|e| format!("invalid address `{addr}`: {e}"))?;
+            Ok((addr, PathBuf::from(path)))
+        }
+        _ => Err(format!("expected ADDR=FILE, found `{arg}`")),
     }
-    println!("Crystal frequency: {}", info.crystal_frequency);
-    println!("Flash size:        {}", info.flash_size);
-    println!("Features:          {}", info.features.join(", "));
-    println!("MAC address:       {}", info.mac_address);
+}
+
+/// Write files which were encrypted ahead of time to flash, verbatim
+///
+/// As the data is already encrypted, it is written with the plain write
+/// commands; the encrypted write commands would have the device encrypt it a
+/// second time.
+pub fn write_pre_encrypted(flasher: &mut Flasher, files: &[(u32, PathBuf)]) -> Result<()> {
+    let segments = read_pre_encrypted(files)?;
+    flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
+    info!("Pre-encrypted files written to flash");
 
     Ok(())
 }
 
-/// Open a serial monitor
-pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
-    let mut flasher = connect(&args.connect_args, config, true, true)?;
-    let pid = flasher.get_usb_pid()?;
+/// Read pre-encrypted files, checking that they are aligned as required by
+/// flash encryption
+fn read_pre_encrypted(files: &[(u32, PathBuf)]) -> Result<Vec<RomSegment<'static>>> {
+    let mut segments = Vec::new();
+    for (addr, path) in files {
+        let data =
+            fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+        if *addr as usize % BLOCK_SIZE != 0 || data.len() % ALIGNMENT != 0 {
+            return Err(Error::InvalidFlashEncryption(format!(
+                "{} must be written to an address aligned to {BLOCK_SIZE} bytes and be a multiple \
+                 of {ALIGNMENT} bytes long to have been encrypted",
+                path.display()
+            ))
+            .into());
+        }
+
+        segments.push(RomSegment {
+            addr: *addr,
+            data: data.into(),
+        });
+    }
+
+    Ok(segments)
+}
+
+/// Print information about a chip
+///
+/// Nothing is printed when quiet output was selected, as this is used to
+/// describe the chip before operating on it.
+pub fn print_board_info(flasher: &mut Flasher) -> Result<()> {
+    report::record_device(flasher)?;
+
+    if is_quiet() {
+        return Ok(());
+    }
+
+    for line in board_info_lines(flasher)? {
+        text!("{line}");
+    }
+
+    Ok(())
+}
+
+/// Lines of information about a chip
+fn board_info_lines(flasher: &mut Flasher) -> Result<Vec<String>> {
+    let info = flasher.device_info()?;
+    let mut lines = Vec::new();
+
+    match info.revision {
+        Some((major, minor)) => lines.push(format!(
+            "Chip type:         {} (revision v{major}.{minor})",
+            info.chip
+        )),
+        None => lines.push(format!("Chip type:         {}", info.chip)),
+    }
+    lines.push(format!("Crystal frequency: {}", info.crystal_frequency));
+    match flasher.boot_flash_size() {
+        Some(boot_flash_size) => {
+            lines.push(format!("Flash size:        {}", boot_flash_size));
+            lines.push(format!("External flash:    {}", info.flash_size));
+        }
+        None => lines.push(format!("Flash size:        {}", info.flash_size)),
+    }
+    lines.push(format!("Features:          {}", info.features.join(", ")));
+    lines.push(format!("MAC address:       {}", info.mac_address));
+    match info.psram {
+        Some(psram) => lines.push(format!("PSRAM:             {psram}")),
+        None => lines.push("PSRAM:             None embedded".to_string()),
+    }
+    if let Some(embedded_flash) = info.embedded_flash {
+        lines.push(format!("Embedded flash:    {embedded_flash}"));
+    }
+
+    Ok(lines)
+}
+
+/// Warn if the flash size does not match the flash embedded in the chip, as
+/// recorded in its eFuses
+///
+/// Chips booting from an external flash chip are not checked. The flash
+/// frequencies espflash can select are within the ratings of all embedded
+/// flash, so only the size is compared.
+pub fn check_embedded_flash(flasher: &mut Flasher) -> Result<()> {
+    if flasher.boot_flash_size().is_some() {
+        return Ok(());
+    }
+
+    let chip = flasher.chip();
+    let embedded_flash = chip
+        .into_target()
+        .embedded_flash_info(flasher.connection())?;
+
+    match embedded_flash.and_then(|embedded_flash| embedded_flash.size) {
+        Some(size) if size != flasher.flash_size().size() => warn!(
+            "The flash size is {}, but the {chip} has {}MB of embedded flash according to its \
+             eFuses",
+            flasher.flash_size(),
+            size / 1024 / 1024
+        ),
+        Some(_) => {}
+        None => info!("No embedded flash size is recorded in the eFuses of the {chip}"),
+    }
 
+    Ok(())
+}
+
+/// Open a serial monitor
+pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
     let elf = if let Some(elf_path) = args.elf {
         let path = fs::canonicalize(elf_path).into_diagnostic()?;
         let data = fs::read(path).into_diagnostic()?;
@@ -427,11 +1468,34 @@ pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
         None
     };
 
+    // When attaching to an espflash daemon, join as a monitor client so that
+    // the device can still be flashed by other clients in the meantime.
+    if args
+        .connect_args
+        .port
+        .as_deref()
+        .is_some_and(is_remote_port)
+    {
+        let (serial, port_info) = open_port(&args.connect_args, config, Role::Monitor)?;
+
+        return monitor(
+            serial,
+            elf.as_deref(),
+            port_info.pid,
+            args.connect_args.baud.unwrap_or(115_200),
+            args.log_format,
+            !args.non_interactive,
+        );
+    }
+
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+    let pid = flasher.get_usb_pid()?;
+
     let chip = flasher.chip();
     let target = chip.into_target();
 
     // The 26MHz ESP32-C2's need to be treated as a special case.
-    let default_baud = if chip == Chip::Esp32c2
+    let default_baud = if chip.rom_assumes_40mhz_xtal()
         && target.crystal_freq(flasher.connection())? == XtalFrequency::_26Mhz
     {
         // 115_200 * 26 MHz / 40 MHz = 74_880
@@ -450,6 +1514,40 @@ pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
     )
 }
 
+/// Share the serial port with other instances of espflash over the network
+pub fn serve(args: ServeArgs, config: &Config) -> Result<()> {
+    let port_info = find_port_info(
+        args.port.as_deref(),
+        args.list_all_ports,
+        args.confirm_port,
+        config,
+    )?;
+    let baud = args.baud.unwrap_or(115_200);
+    let flow_control = if args.flow_control {
+        FlowControl::Hardware
+    } else {
+        FlowControl::None
+    };
+
+    let serial = serialport::new(&port_info.port_name, baud)
+        .flow_control(flow_control)
+        .open()
+        .map_err(Error::from)
+        .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name))?;
+
+    let (vid, pid) = match port_info.port_type {
+        SerialPortType::UsbPort(info) => (info.vid, info.pid),
+        _ => (0, 0),
+    };
+
+    info!("Serving serial port '{}'", port_info.port_name);
+    Server::new(serial, baud, vid, pid)
+        .with_token(args.remote_token)
+        .run(SocketAddr::new(args.bind, args.listen_port))?;
+
+    Ok(())
+}
+
 /// Convert the provided firmware image from ELF to binary
 pub fn save_elf_as_image(
     elf_data: &[u8],
@@ -459,59 +1557,117 @@ pub fn save_elf_as_image(
     merge: bool,
     skip_padding: bool,
     xtal_freq: XtalFrequency,
+    format: FileFormat,
+    manifest: bool,
+    pad_to: Option<PadTo>,
 ) -> Result<()> {
-    let image = ElfFirmwareImage::try_from(elf_data)?;
+    let image = ElfFirmwareImage::try_from(elf_data)?.with_rtc_segments(flash_data.rtc_segments);
+
+    if manifest && format != FileFormat::Bin {
+        return Err(Error::UnsupportedManifestFormat(format).into());
+    }
 
-    if merge {
+    if format != FileFormat::Bin {
+        let image = chip.get_flash_image(&image, flash_data, None, xtal_freq)?;
+
+        display_image_size(image.as_ref());
+
+        // When merging, all segments are written to their flash addresses,
+        // otherwise only the application is written.
+        let mut segments = if merge {
+            image.flash_segments().collect::<Vec<_>>()
+        } else {
+            image.ota_segments().collect::<Vec<_>>()
+        };
+
+        if let Some(pad_to) = &pad_to {
+            pad_image(chip, image.as_ref(), &mut segments, pad_to)?;
+        }
+
+        let data = match format {
+            FileFormat::Hex => file_format::ihex::to_ihex(&segments).into_bytes(),
+            FileFormat::Srec => file_format::srec::to_srec(&segments).into_bytes(),
+            FileFormat::Uf2 if merge => file_format::uf2::to_uf2(&segments, chip.uf2_family_id()),
+            // UF2 bootloaders such as TinyUF2 expect the application to be
+            // addressed relative to the start of its partition
+            FileFormat::Uf2 => {
+                let base = segments.iter().map(|s| s.addr).min().unwrap_or_default();
+                let segments = segments
+                    .into_iter()
+                    .map(|segment| RomSegment {
+                        addr: segment.addr - base,
+                        data: segment.data,
+                    })
+                    .collect::<Vec<_>>();
+
+                file_format::uf2::to_uf2(&segments, chip.uf2_family_id())
+            }
+            FileFormat::Bin => unreachable!(),
+        };
+
+        fs::write(&image_path, data).into_diagnostic()?;
+    } else if merge {
         // To get a chip revision, the connection is needed
         // For simplicity, the revision None is used
-        let image =
-            chip.into_target()
-                .get_flash_image(&image, flash_data.clone(), None, xtal_freq)?;
-
-        display_image_size(image.app_size(), image.part_size());
-
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(image_path)
-            .into_diagnostic()?;
-
-        for segment in image.flash_segments() {
-            let padding_bytes = vec![
-                0xffu8;
-                segment.addr as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
-            file.write_all(&segment.data).into_diagnostic()?;
-        }
-
-        if !skip_padding {
-            // Take flash_size as input parameter, if None, use default value of 4Mb
-            let padding_bytes = vec![
-                0xffu8;
-                flash_data.flash_settings.size.unwrap_or_default().size()
-                    as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
+        let image = chip.get_flash_image(&image, flash_data.clone(), None, xtal_freq)?;
+
+        display_image_size(image.as_ref());
+
+        let segments = image.flash_segments().collect::<Vec<_>>();
+        let data = merge_segments(segments, flash_data.flash_settings.size, !skip_padding)?;
+
+        fs::write(&image_path, data).into_diagnostic()?;
+    } else if manifest {
+        let flash_settings = flash_data.flash_settings;
+        let image = chip.get_flash_image(&image, flash_data, None, xtal_freq)?;
+
+        display_image_size(image.as_ref());
+
+        let mut segments = image.flash_segments().collect::<Vec<_>>();
+        segments.sort_by_key(|segment| segment.addr);
+
+        let mut files = Vec::new();
+        for segment in segments {
+            let part_path = segment_path(&image_path, segment.addr);
+            fs::write(&part_path, &segment.data).into_diagnostic()?;
+
+            files.push(ManifestFile {
+                file: part_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                offset: segment.addr,
+                size: segment.data.len() as u32,
+                sha256: hex::encode(Sha256::digest(&segment.data)),
+            });
         }
+
+        let manifest = ImageManifest {
+            chip: chip.to_string(),
+            flash_mode: flash_settings.mode,
+            flash_size: flash_settings.size,
+            flash_freq: flash_settings.freq,
+            files,
+        };
+        let manifest_path = image_path.with_file_name("manifest.json");
+        let manifest = serde_json::to_string_pretty(&manifest).into_diagnostic()?;
+        fs::write(manifest_path, manifest).into_diagnostic()?;
     } else {
-        let image = chip
-            .into_target()
-            .get_flash_image(&image, flash_data, None, xtal_freq)?;
+        let image = chip.get_flash_image(&image, flash_data, None, xtal_freq)?;
+
+        display_image_size(image.as_ref());
 
-        display_image_size(image.app_size(), image.part_size());
+        let mut parts = image.ota_segments().collect::<Vec<_>>();
+        if let Some(pad_to) = &pad_to {
+            pad_image(chip, image.as_ref(), &mut parts, pad_to)?;
+        }
 
-        let parts = image.ota_segments().collect::<Vec<_>>();
         match parts.as_slice() {
             [single] => fs::write(&image_path, &single.data).into_diagnostic()?,
             parts => {
                 for part in parts {
-                    let part_path = format!("{:#x}_{}", part.addr, image_path.display());
-                    fs::write(part_path, &part.data).into_diagnostic()?
+                    fs::write(segment_path(&image_path, part.addr), &part.data).into_diagnostic()?
                 }
             }
         }
@@ -522,54 +1678,297 @@ pub fn save_elf_as_image(
     Ok(())
 }
 
-/// Displays the image or app size
-pub(crate) fn display_image_size(app_size: u32, part_size: Option<u32>) {
-    if let Some(part_size) = part_size {
-        let percent = app_size as f32 / part_size as f32 * 100.0;
+/// Size to pad an application image to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PadTo {
+    /// Size in bytes
+    Size(u32),
+    /// Size of the partition with the given label
+    Partition(String),
+}
+
+impl FromStr for PadTo {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match parse_uint32(s) {
+            Ok(size) => PadTo::Size(size),
+            Err(_) => PadTo::Partition(s.to_string()),
+        })
+    }
+}
+
+/// Pad the application image with 0xFF so that it fills the requested size
+fn pad_image(
+    chip: Chip,
+    image: &dyn ImageFormat<'_>,
+    segments: &mut [RomSegment<'_>],
+    pad_to: &PadTo,
+) -> Result<()> {
+    let size = match pad_to {
+        PadTo::Size(size) => *size,
+        PadTo::Partition(label) => {
+            let partition_table = image.partition_table().ok_or(MissingPartitionTable)?;
+            partition_table
+                .find(label)
+                .ok_or_else(|| Error::PartitionNotFound(label.clone()))?
+                .size()
+        }
+    };
+
+    let [segment] = segments else {
+        return Err(Error::UnsupportedFeature {
+            chip,
+            feature: "padding images made up of multiple segments".into(),
+        }
+        .into());
+    };
+
+    let len = segment.data.len() as u32;
+    if len > size {
+        return Err(Error::ElfTooBig(len, size).into());
+    }
+
+    segment.data.to_mut().resize(size as usize, 0xFF);
+
+    Ok(())
+}
+
+/// Path of the file a segment is saved to, prefixing the file name of the
+/// image with the segment's offset
+fn segment_path(image_path: &Path, addr: u32) -> PathBuf {
+    let file_name = image_path.file_name().unwrap_or_default().to_string_lossy();
+
+    image_path.with_file_name(format!("{addr:#x}_{file_name}"))
+}
+
+/// Description of the files saved by `save-image`, so that they may be written
+/// to flash by other tools
+#[derive(Debug, Serialize)]
+struct ImageManifest {
+    chip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flash_mode: Option<FlashMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flash_size: Option<FlashSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flash_freq: Option<FlashFrequency>,
+    files: Vec<ManifestFile>,
+}
+
+/// A file to be written to flash at the given offset
+#[derive(Debug, Serialize)]
+struct ManifestFile {
+    file: String,
+    offset: u32,
+    size: u32,
+    sha256: String,
+}
+
+/// Displays how full each partition the image writes to is, or the size of the
+/// app if the image does not use a partition table
+pub(crate) fn display_image_size<'a>(image: &(dyn ImageFormat<'a> + 'a)) {
+    let usage = partition_usage(image);
+
+    if is_json() {
+        let partitions = usage
+            .iter()
+            .map(|usage| {
+                json!({
+                    "label": usage.label,
+                    "type": usage.ty.to_string(),
+                    "offset": usage.offset,
+                    "size": usage.size,
+                    "used": usage.used,
+                })
+            })
+            .collect::<Vec<_>>();
+
         println!(
-            "App/part. size:    {}/{} bytes, {:.2}%",
-            HumanCount(app_size as u64),
-            HumanCount(part_size as u64),
-            percent
+            "{:#}",
+            json!({
+                "app_size": image.app_size(),
+                "part_size": image.part_size(),
+                "partitions": partitions,
+            })
         );
-    } else {
-        println!("App size:          {} bytes", HumanCount(app_size as u64));
+
+        return;
+    }
+    if is_quiet() {
+        return;
     }
+
+    if usage.is_empty() {
+        let app_size = image.app_size();
+        if let Some(part_size) = image.part_size() {
+            let percent = app_size as f32 / part_size as f32 * 100.0;
+            println!(
+                "App/part. size:    {}/{} bytes, {:.2}%",
+                HumanCount(app_size as u64),
+                HumanCount(part_size as u64),
+                percent
+            );
+        } else {
+            println!("App size:          {} bytes", HumanCount(app_size as u64));
+        }
+
+        return;
+    }
+
+    let mut pretty = Table::new();
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Partition")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Type")
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Used").add_attribute(Attribute::Bold),
+            Cell::new("Size")
+                .fg(Color::Yellow)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Usage").add_attribute(Attribute::Bold),
+            Cell::new("Headroom").add_attribute(Attribute::Bold),
+        ]);
+
+    for usage in usage {
+        let percent = usage.percent();
+        let color = if percent < 90.0 {
+            Color::Green
+        } else if percent <= 100.0 {
+            Color::Yellow
+        } else {
+            Color::Red
+        };
+
+        pretty.add_row(vec![
+            Cell::new(&usage.label).fg(Color::Green),
+            Cell::new(usage.ty.to_string()).fg(Color::Cyan),
+            Cell::new(format!("{} bytes", HumanCount(usage.used as u64))),
+            Cell::new(format!("{} bytes", HumanCount(usage.size as u64))).fg(Color::Yellow),
+            Cell::new(format!("{percent:.2}%")).fg(color),
+            Cell::new(format!(
+                "{} bytes, {:.2}%",
+                HumanCount(usage.size.saturating_sub(usage.used) as u64),
+                100.0 - percent
+            )),
+        ]);
+    }
+
+    println!("{pretty}");
 }
 
+/// Number of dots showing the whole progress of a segment
+const PROGRESS_DOTS: usize = 50;
+
 /// Progress callback implementations for use in `cargo-espflash` and `espflash`
+///
+/// Progress is shown as selected by [set_progress_display](output::set_progress_display).
 #[derive(Default)]
 pub struct EspflashProgress {
     pb: Option<ProgressBar>,
+    display: ProgressDisplay,
+    addr: u32,
+    len: usize,
+    /// Dots or tenths of the progress shown so far
+    shown: usize,
+}
+
+impl EspflashProgress {
+    /// Show the progress up to `current`, when it is shown as dots or
+    /// percentages
+    fn show(&mut self, current: usize) {
+        let len = self.len.max(1);
+        let current = current.min(len);
+
+        match self.display {
+            ProgressDisplay::Dots => {
+                let dots = current * PROGRESS_DOTS / len;
+                if dots > self.shown {
+                    eprint!("{}", ".".repeat(dots - self.shown));
+                    let _ = io::stderr().flush();
+                    self.shown = dots;
+                }
+            }
+            ProgressDisplay::Percent => {
+                let tenths = current * 10 / len;
+                if tenths > self.shown {
+                    eprintln!("{:#X}: {}%", self.addr, tenths * 10);
+                    self.shown = tenths;
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 impl ProgressCallbacks for EspflashProgress {
     /// Initialize the progress bar
     fn init(&mut self, addr: u32, len: usize) {
-        let pb = ProgressBar::new(len as u64)
-            .with_message(format!("{addr:#X}"))
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} {msg}")
-                    .unwrap()
-                    .progress_chars("=> "),
-            );
-
-        self.pb = Some(pb);
+        report::record_write_started();
+        events::emit(Event::ProgressStart {
+            address: addr,
+            total: len,
+        });
+        self.addr = addr;
+        self.len = len;
+        self.shown = 0;
+        self.display = progress_display();
+
+        self.pb = match self.display {
+            ProgressDisplay::Bar => Some(
+                ProgressBar::new(len as u64)
+                    .with_message(format!("{addr:#X}"))
+                    .with_style(
+                        ProgressStyle::default_bar()
+                            .template("[{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} {msg}")
+                            .unwrap()
+                            .progress_chars("=> "),
+                    ),
+            ),
+            ProgressDisplay::Dots => {
+                eprint!("{addr:#X} ");
+                None
+            }
+            _ => None,
+        };
     }
 
     /// Update the progress bar
     fn update(&mut self, current: usize) {
+        events::emit(Event::Progress {
+            address: self.addr,
+            current,
+            total: self.len,
+        });
+
         if let Some(ref pb) = self.pb {
             pb.set_position(current as u64);
         }
+        self.show(current);
     }
 
     /// End the progress bar
     fn finish(&mut self) {
+        report::record_write_finished();
+        events::emit(Event::ProgressFinish { address: self.addr });
+
         if let Some(ref pb) = self.pb {
             pb.finish();
         }
+        self.show(self.len);
+        if self.display == ProgressDisplay::Dots {
+            eprintln!(" done");
+        }
+    }
+
+    /// Record the segment in the report, if one is being recorded
+    fn segment(&mut self, addr: u32, data: &[u8]) {
+        report::record_segment(addr, data);
     }
 }
 
@@ -582,9 +1981,10 @@ pub fn erase_flash(args: EraseFlashArgs, config: &Config) -> Result<()> {
     info!("Erasing Flash...");
 
     flasher.erase_flash()?;
+    let chip = flasher.chip();
     flasher
         .connection()
-        .reset_after(!args.connect_args.no_stub)?;
+        .reset_after(!args.connect_args.no_stub, chip)?;
 
     info!("Flash has been erased!");
 
@@ -596,21 +1996,234 @@ pub fn erase_region(args: EraseRegionArgs, config: &Config) -> Result<()> {
         return Err(Error::StubRequired).into_diagnostic();
     }
 
+    let regions = erase_regions(&args.regions, args.strict)?;
+
     let mut flasher = connect(&args.connect_args, config, true, true)?;
 
-    info!(
-        "Erasing region at 0x{:08x} ({} bytes)",
-        args.addr, args.size
-    );
+    // Check every region before erasing any of them
+    let flash_size = flasher.flash_size().size();
+    for &(start, end) in &regions {
+        if end > flash_size as u64 {
+            return Err(Error::FlashRegionOutOfBounds {
+                offset: start as u32,
+                size: (end - start).min(u32::MAX as u64) as u32,
+                flash_size,
+            }
+            .into());
+        }
+    }
+
+    for (start, end) in regions {
+        let (offset, size) = (start as u32, (end - start) as u32);
+        info!("Erasing region at 0x{offset:08x} ({size} bytes)");
+        flasher.erase_region(offset, size)?;
+    }
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
 
-    flasher.erase_region(args.addr, args.size)?;
+    Ok(())
+}
+
+/// Resolve pairs of offset and size into the ranges of flash to erase
+///
+/// Each region is extended to whole flash sectors, with a warning, unless
+/// `strict` is set, in which case unaligned regions are refused. Overlapping
+/// and adjacent regions are merged, so that each sector is erased once.
+fn erase_regions(regions: &[u32], strict: bool) -> Result<Vec<(u64, u64)>, Error> {
+    const SECTOR_SIZE: u64 = FLASH_SECTOR_SIZE as u64;
+
+    let mut ranges = Vec::new();
+    for region in regions.chunks(2) {
+        let &[offset, size] = region else {
+            return Err(Error::MissingEraseRegionSize(region[0]));
+        };
+
+        let start = offset as u64 / SECTOR_SIZE * SECTOR_SIZE;
+        let end = (offset as u64 + size as u64).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        if (start, end) != (offset as u64, offset as u64 + size as u64) {
+            if strict {
+                return Err(Error::UnalignedEraseRegion { offset, size });
+            }
+            warn!(
+                "Extending the region at 0x{offset:08x} ({size} bytes) to whole sectors, \
+                 at 0x{start:08x} ({} bytes)",
+                end - start
+            );
+        }
+
+        ranges.push((start, end));
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Erase the OTA data and NVS partitions, along with any additional partitions
+/// requested, so that the factory app is booted with clean settings
+pub fn factory_reset(args: FactoryResetArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+
+    let mut parts_to_erase = HashMap::new();
+    for part in partition_table.partitions() {
+        if part.ty() == esp_idf_part::Type::Data
+            && matches!(
+                part.subtype(),
+                esp_idf_part::SubType::Data(DataType::Ota | DataType::Nvs)
+            )
+        {
+            parts_to_erase.insert(part.offset(), part);
+        }
+    }
+    for label in args.erase_parts {
+        let part = partition_table
+            .find(label.as_str())
+            .ok_or_else(|| MissingPartition::from(label))?;
+        parts_to_erase.insert(part.offset(), part);
+    }
+
+    if parts_to_erase.is_empty() {
+        warn!("The partition table contains no partitions to erase");
+    }
+
+    for part in parts_to_erase.values() {
+        erase_partition(&mut flasher, part)?;
+    }
+
+    let chip = flasher.chip();
     flasher
         .connection()
-        .reset_after(!args.connect_args.no_stub)?;
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    info!("Factory reset has completed!");
+
+    Ok(())
+}
+
+/// Build the flash contents which flashing an ELF image would produce, and
+/// write them to a file instead of a target device
+///
+/// The image is built and validated as when flashing it, including any
+/// pre-encrypted files, and the file is padded to the size of the flash, which
+/// is assumed to have been erased beforehand. As no device is connected, the
+/// image is built for any revision of the chip.
+#[allow(clippy::too_many_arguments)]
+pub fn dry_run_elf_image(
+    elf_data: &[u8],
+    chip: Chip,
+    flash_data: FlashData,
+    pre_encrypted: &[(u32, PathBuf)],
+    image_path: &Path,
+    verify: bool,
+    skip: bool,
+    xtal_freq: XtalFrequency,
+) -> Result<()> {
+    let image = ElfFirmwareImage::try_from(elf_data)?.with_rtc_segments(flash_data.rtc_segments);
+    let flash_size = flash_data.flash_settings.size;
+    let image = chip.get_flash_image(&image, flash_data, None, xtal_freq)?;
+
+    display_image_size(image.as_ref());
+
+    let mut segments = image.flash_segments().collect::<Vec<_>>();
+    segments.extend(read_pre_encrypted(pre_encrypted)?);
+
+    let flash_size = flash_size.unwrap_or_default();
+    for segment in &segments {
+        let size = segment.data.len() as u32;
+        if segment.addr.saturating_add(size) > flash_size.size() {
+            return Err(Error::FlashRegionOutOfBounds {
+                offset: segment.addr,
+                size,
+                flash_size: flash_size.size(),
+            }
+            .into());
+        }
+
+        let plan = match (skip, verify) {
+            (true, true) => ", skipped if unchanged on the device, verified after writing",
+            (true, false) => ", skipped if unchanged on the device",
+            (false, true) => ", verified after writing",
+            (false, false) => "",
+        };
+        info!(
+            "Would write {} bytes at 0x{:08x}{plan}",
+            segment.data.len(),
+            segment.addr
+        );
+    }
+
+    let data = merge_segments(segments, Some(flash_size), true)?;
+    fs::write(image_path, data)
+        .map_err(|e| Error::FileOpenError(image_path.display().to_string(), e))?;
+
+    info!(
+        "Dry run has completed, the flash contents were written to {}",
+        image_path.display()
+    );
 
     Ok(())
 }
 
+/// Place each segment at its flash address, filling any gaps with 0xFF, so
+/// that the merged image does not depend on the order of the segments
+///
+/// The image must fit within the flash, if its size is known, and is padded to
+/// the size of the flash (4MB if not known) when `pad` is set.
+fn merge_segments(
+    mut segments: Vec<RomSegment<'_>>,
+    flash_size: Option<FlashSize>,
+    pad: bool,
+) -> Result<Vec<u8>> {
+    segments.sort_by_key(|segment| segment.addr);
+
+    let mut data = Vec::new();
+    for segment in segments {
+        let end = segment.addr as usize + segment.data.len();
+        if data.len() < end {
+            data.resize(end, 0xFF);
+        }
+        data[segment.addr as usize..end].copy_from_slice(&segment.data);
+    }
+
+    if let Some(flash_size) = flash_size {
+        if data.len() > flash_size.size() as usize {
+            return Err(Error::FlashRegionOutOfBounds {
+                offset: 0,
+                size: data.len() as u32,
+                flash_size: flash_size.size(),
+            }
+            .into());
+        }
+    }
+
+    if pad {
+        let flash_size = flash_size.unwrap_or_default().size() as usize;
+        if data.len() < flash_size {
+            data.resize(flash_size, 0xFF);
+        }
+    }
+
+    Ok(data)
+}
+
 /// Write an ELF image to a target device's flash
 pub fn flash_elf_image(
     flasher: &mut Flasher,
@@ -631,6 +2244,135 @@ pub fn flash_elf_image(
     Ok(())
 }
 
+/// Write the artifacts of an ESP-IDF build to a target device's flash
+///
+/// The flash settings the project was built with are used, unless they are
+/// overridden by `flash_settings`.
+pub fn flash_idf_build(
+    flasher: &mut Flasher,
+    build: &IdfBuild,
+    flash_settings: FlashSettings,
+) -> Result<()> {
+    let chip = flasher.chip();
+    if let Some(build_chip) = build.chip.filter(|build_chip| *build_chip != chip) {
+        return Err(Error::IdfBuildChipMismatch {
+            build: build_chip,
+            chip,
+        }
+        .into());
+    }
+
+    let flash_settings = FlashSettings::new(
+        flash_settings.mode.or(build.flash_settings.mode),
+        flash_settings.size.or(build.flash_settings.size),
+        flash_settings.freq.or(build.flash_settings.freq),
+    );
+    if let Some(flash_size) = flash_settings.size {
+        flasher.set_flash_size(flash_size);
+    }
+
+    let mut segments = Vec::new();
+    for (offset, path) in &build.files {
+        let data =
+            fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+        segments.push(RomSegment {
+            addr: *offset,
+            data: data.into(),
+        });
+    }
+
+    // The bootloader is built with the project's flash settings, so it only
+    // changes when they are overridden
+    if let Some(boot_addr) = chip.bootloader_addr() {
+        for segment in segments.iter_mut().filter(|s| s.addr == boot_addr) {
+            update_bootloader_header(segment.data.to_mut(), chip, &flash_settings)?;
+        }
+    }
+
+    flasher.write_bins_to_flash(&segments, Some(&mut EspflashProgress::default()))?;
+    info!("Flashing has completed!");
+
+    Ok(())
+}
+
+/// Resolve the `auto-ota` target app partition to the OTA app partition which
+/// is not currently booted, by reading the device's OTA data
+pub fn resolve_auto_ota(flasher: &mut Flasher, flash_data: &mut FlashData) -> Result<()> {
+    if flash_data.target_app_partition.as_deref() != Some(AUTO_OTA) {
+        return Ok(());
+    }
+
+    let partition_table = flash_data
+        .partition_table
+        .as_ref()
+        .ok_or(MissingPartitionTable)?;
+
+    let otadata = flasher.read_otadata(partition_table)?;
+    let partition = inactive_ota_partition(partition_table, &otadata)?;
+    info!(
+        "Writing the app to the inactive OTA partition '{}'",
+        partition.name()
+    );
+
+    flash_data.target_app_partition = Some(partition.name().to_string());
+
+    Ok(())
+}
+
+/// Check that the app is written to an OTA app partition, returning the
+/// partition table and its label so that it may be activated after flashing
+pub fn ota_activation_target(flash_data: &FlashData) -> Result<(PartitionTable, String)> {
+    let partition_table = flash_data
+        .partition_table
+        .clone()
+        .ok_or(MissingPartitionTable)?;
+    let label = flash_data
+        .target_app_partition
+        .clone()
+        .ok_or(Error::AppPartitionNotFound)?;
+
+    ota_slot(&partition_table, &label)?;
+
+    Ok((partition_table, label))
+}
+
+/// Update the device's OTA data, so that the OTA app partition with the given
+/// label is booted next
+pub fn activate_ota_partition(
+    flasher: &mut Flasher,
+    partition_table: &PartitionTable,
+    label: &str,
+) -> Result<()> {
+    let (slot, slot_count) = ota_slot(partition_table, label)?;
+
+    let mut otadata = flasher.read_otadata(partition_table)?;
+    let index = otadata.select(slot, slot_count);
+
+    let offset = otadata_partition(partition_table)?.offset();
+    let segment = RomSegment {
+        addr: offset + (index * OTADATA_SECTOR_SIZE) as u32,
+        data: otadata.entries[index].to_sector().into(),
+    };
+    flasher.write_bins_to_flash(&[segment], None)?;
+
+    info!("The app in partition '{label}' will be booted next");
+
+    Ok(())
+}
+
+/// Validate an ELF image for a target device, without writing it to flash
+pub fn check_elf_image(
+    flasher: &mut Flasher,
+    elf_data: &[u8],
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+) -> Result<()> {
+    flasher.check_elf_image(elf_data, flash_data, xtal_freq)?;
+    info!("The image is valid, nothing was written to flash");
+
+    Ok(())
+}
+
 /// Erase one or more partitions by label or [DataType]
 pub fn erase_partitions(
     flasher: &mut Flasher,
@@ -701,38 +2443,109 @@ pub fn read_flash(args: ReadFlashArgs, config: &Config) -> Result<()> {
         return Err(Error::StubRequired.into());
     }
 
+    // The file holds the start of the region when resuming
+    let done = match fs::metadata(&args.file) {
+        Ok(metadata) if args.resume => metadata.len(),
+        _ => 0,
+    };
+    if done >= args.size as u64 {
+        if done > args.size as u64 {
+            warn!(
+                "'{}' is larger than the region of {} bytes",
+                args.file.display(),
+                args.size
+            );
+        }
+        info!("'{}' already holds the whole region", args.file.display());
+        return Ok(());
+    }
+    let (addr, size) = (args.addr + done as u32, args.size - done as u32);
+    if done > 0 {
+        info!("Resuming the read at 0x{addr:08x}, with {done} bytes already read");
+    }
+
     let mut flasher = connect(&args.connect_args, config, false, false)?;
     print_board_info(&mut flasher)?;
-    flasher.read_flash(
-        args.addr,
-        args.size,
-        args.block_size,
-        args.max_in_flight,
-        args.file,
-    )?;
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(args.resume)
+        .truncate(!args.resume)
+        .open(&args.file)
+        .map_err(|e| Error::FileOpenError(args.file.display().to_string(), e))?;
+
+    if args.gzip {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        flasher.read_flash_to_writer(
+            addr,
+            size,
+            args.block_size,
+            args.max_in_flight,
+            &mut encoder,
+        )?;
+        encoder.finish().map_err(Error::from)?;
+    } else {
+        flasher.read_flash_to_writer(addr, size, args.block_size, args.max_in_flight, &mut file)?;
+    }
+
+    info!(
+        "Flash content successfully read and written to '{}'!",
+        args.file.display()
+    );
 
     Ok(())
 }
 
 /// Convert and display CSV and binary partition tables
-pub fn partition_table(args: PartitionTableArgs) -> Result<()> {
-    if args.to_binary {
-        let table = parse_partition_table(&args.partition_table)?;
+pub fn partition_table(args: PartitionTableArgs, config: &Config) -> Result<()> {
+    if let Some(PartitionTableAction::Diff(args)) = args.action {
+        return partition_table_diff(args, config);
+    }
 
-        // Use either stdout or a file if provided for the output.
-        let mut writer: Box<dyn Write> = if let Some(output) = args.output {
-            Box::new(fs::File::create(output).into_diagnostic()?)
-        } else {
-            Box::new(std::io::stdout())
-        };
+    if args.device {
+        if args.connect_args.no_stub {
+            return Err(Error::StubRequired.into());
+        }
+
+        let mut flasher = connect(&args.connect_args, config, false, false)?;
+        let table = flasher.read_partition_table(args.partition_table_offset)?;
+
+        let mut usage = HashMap::new();
+        for part in app_partitions(&table) {
+            usage.insert(part.offset(), flasher.read_app_image_len(part)?);
+        }
+
+        print_partition_table(&table, &usage, Some(flasher.flash_size()))?;
+
+        return Ok(());
+    }
 
-        writer
-            .write_all(&table.to_bin().into_diagnostic()?)
-            .into_diagnostic()?;
-    } else if args.to_csv {
-        let input = fs::read(&args.partition_table).into_diagnostic()?;
-        let table = PartitionTable::try_from_bytes(input).into_diagnostic()?;
+    // Either the file or `--device` is required
+    let path = args.partition_table.as_deref().unwrap();
+    let mut table = parse_partition_table(path)?;
+
+    if let Some(app) = &args.fit {
+        let app_size = app_image_len(app, args.connect_args.chip.or(config.chip))?;
+        table = fit_app_partitions(
+            &table,
+            app_size as u32,
+            args.flash_size.map(FlashSize::size),
+        )?;
+    }
+
+    // The resized table is written as CSV, unless another format was selected
+    let output = if args.to_binary {
+        Some(partition_table::to_bin(&table, !args.no_md5)?)
+    } else if args.to_toml {
+        Some(to_toml(&table)?.into_bytes())
+    } else if args.to_csv || args.fit.is_some() {
+        Some(table.to_csv().into_diagnostic()?.into_bytes())
+    } else {
+        None
+    };
 
+    if let Some(output) = output {
         // Use either stdout or a file if provided for the output.
         let mut writer: Box<dyn Write> = if let Some(output) = args.output {
             Box::new(fs::File::create(output).into_diagnostic()?)
@@ -740,59 +2553,393 @@ pub fn partition_table(args: PartitionTableArgs) -> Result<()> {
             Box::new(std::io::stdout())
         };
 
-        writer
-            .write_all(table.to_csv().into_diagnostic()?.as_bytes())
-            .into_diagnostic()?;
+        writer.write_all(&output).into_diagnostic()?;
+    } else {
+        let mut usage = HashMap::new();
+        if let Some(app) = &args.app {
+            let len = app_image_len(app, args.connect_args.chip.or(config.chip))?;
+
+            for part in app_partitions(&table) {
+                usage.insert(part.offset(), Some(len));
+            }
+        }
+
+        print_partition_table(&table, &usage, args.flash_size)?;
+    }
+
+    Ok(())
+}
+
+/// Display information about an image, optionally fixing its checksum and
+/// digest
+pub fn image_info(args: ImageInfoArgs) -> Result<()> {
+    let mut data = fs::read(&args.image)
+        .map_err(|e| Error::FileOpenError(args.image.display().to_string(), e))?;
+    let info = ImageInfo::parse(&data)?;
+
+    if is_json() {
+        let segments = info
+            .segments
+            .iter()
+            .map(|segment| {
+                json!({
+                    "addr": segment.addr,
+                    "offset": segment.offset,
+                    "size": segment.size,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        print_json(&json!({
+            "entry": info.entry,
+            "chip_id": info.chip_id,
+            "min_chip_rev": format!(
+                "{}.{}",
+                info.min_chip_rev_full / 100,
+                info.min_chip_rev_full % 100
+            ),
+            "flash_mode": info.flash_mode,
+            "flash_config": info.flash_config,
+            "segments": segments,
+            "checksum": info.checksum,
+            "checksum_valid": info.checksum_valid(),
+            "digest": info.digest.map(hex::encode),
+            "digest_valid": info.digest_valid(),
+            "app_descriptor": AppDescriptor::from_image(&data).map(|desc| app_descriptor_json(&desc)),
+        }))?;
     } else {
-        let input = fs::read(&args.partition_table).into_diagnostic()?;
-        let table = PartitionTable::try_from(input).into_diagnostic()?;
+        print_image_info(&info, &data);
+    }
 
-        pretty_print(table);
+    check_image_digest(&mut data, args.fix_hash)?;
+    if args.fix_hash {
+        fs::write(&args.image, &data).into_diagnostic()?;
     }
 
     Ok(())
 }
 
-/// Pretty print a partition table
-fn pretty_print(table: PartitionTable) {
+fn print_image_info(info: &ImageInfo, data: &[u8]) {
+    println!("Entry point:      {:#010x}", info.entry);
+    println!("Chip ID:          {}", info.chip_id);
+    println!(
+        "Min. chip rev.:   v{}.{}",
+        info.min_chip_rev_full / 100,
+        info.min_chip_rev_full % 100
+    );
+    println!("Flash mode:       {:#04x}", info.flash_mode);
+    println!("Flash config:     {:#04x}", info.flash_config);
+    println!("Segments:");
+    for segment in &info.segments {
+        println!(
+            "  {:#010x} ({} bytes at offset {:#x})",
+            segment.addr, segment.size, segment.offset
+        );
+    }
+
+    let status = |valid: bool| if valid { "valid" } else { "invalid" };
+    println!(
+        "Checksum:         {:#04x} ({})",
+        info.checksum,
+        status(info.checksum_valid())
+    );
+    match &info.digest {
+        Some(digest) => println!(
+            "SHA256 digest:    {} ({})",
+            hex::encode(digest),
+            status(info.digest_valid() == Some(true))
+        ),
+        None => println!("SHA256 digest:    not appended"),
+    }
+
+    if let Some(desc) = AppDescriptor::from_image(data) {
+        print_app_descriptor(&desc);
+    }
+}
+
+/// Verify the Secure Boot V2 signature blocks of an image, and that each of
+/// the given keys made a valid signature
+pub fn verify_signature(args: VerifySignatureArgs) -> Result<()> {
+    let data = fs::read(&args.image)
+        .map_err(|e| Error::FileOpenError(args.image.display().to_string(), e))?;
+    let keys = args
+        .key
+        .iter()
+        .map(|path| {
+            let pem = fs::read_to_string(path)
+                .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+            Ok((path, SecureBootPublicKey::from_pem(&pem)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let blocks = signature_blocks(&data)?;
+    for block in &blocks {
+        let matching = keys
+            .iter()
+            .find(|(_, key)| *key == block.public_key)
+            .map(|(path, _)| format!(", key {}", path.display()))
+            .unwrap_or_default();
+
+        println!(
+            "Signature block {}: {}, key digest {} ({}{matching})",
+            block.index,
+            block.public_key.algorithm(),
+            hex::encode(block.public_key.digest()),
+            if block.valid { "valid" } else { "invalid" },
+        );
+    }
+
+    if let Some(block) = blocks.iter().find(|block| !block.valid) {
+        return Err(Error::InvalidSecureBootSignature(format!(
+            "signature block {} does not match the image",
+            block.index
+        ))
+        .into());
+    }
+
+    for (path, key) in &keys {
+        if !blocks.iter().any(|block| block.public_key == *key) {
+            return Err(Error::InvalidSecureBootSignature(format!(
+                "the image is not signed with {}",
+                path.display()
+            ))
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Ensure that the checksum and digest of an image match its contents
+///
+/// If `fix` is set they are recalculated when they do not match, otherwise an
+/// error is returned. Data which is not an application or bootloader image is
+/// left untouched.
+pub fn check_image_digest(data: &mut [u8], fix: bool) -> Result<()> {
+    let Ok(info) = ImageInfo::parse(data) else {
+        return Ok(());
+    };
+
+    if info.checksum_valid() && info.digest_valid() != Some(false) {
+        return Ok(());
+    }
+
+    if !fix {
+        return Err(Error::ImageDigestMismatch.into());
+    }
+
+    info.fix(data);
+    warn!("Recalculated the checksum and SHA256 digest of the image");
+
+    Ok(())
+}
+
+/// Length of an application image, or of the image built from an ELF file for
+/// the given chip
+fn app_image_len(path: &Path, chip: Option<Chip>) -> Result<usize> {
+    let data = fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+    if !data.starts_with(b"\x7fELF") {
+        return Ok(ImageInfo::parse(&data).map_or(data.len(), |info| info.len));
+    }
+
+    let chip = chip.ok_or(Error::ElfChipRequired)?;
+    let elf = ElfFirmwareImage::try_from(data.as_slice())?;
+    let flash_data = FlashDataBuilder::new().build()?;
+
+    // The image is built for the default partition table, whose app partition
+    // it may well not fit in
+    match chip.get_flash_image(&elf, flash_data, None, XtalFrequency::default(chip)) {
+        Ok(image) => Ok(image.app_size() as usize),
+        Err(Error::ElfTooBig(app_size, _)) => Ok(app_size as usize),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn app_partitions(table: &PartitionTable) -> impl Iterator<Item = &Partition> {
+    table
+        .partitions()
+        .iter()
+        .filter(|part| part.ty() == esp_idf_part::Type::App)
+}
+
+/// Print a partition table, as JSON if selected and otherwise as a table
+///
+/// See [pretty_print] for the meaning of the arguments.
+fn print_partition_table(
+    table: &PartitionTable,
+    usage: &HashMap<u32, Option<usize>>,
+    flash_size: Option<FlashSize>,
+) -> Result<()> {
+    if !is_json() {
+        pretty_print(table, usage, flash_size);
+        return Ok(());
+    }
+
+    let mut partitions = table.partitions().iter().collect::<Vec<_>>();
+    partitions.sort_by_key(|p| p.offset());
+
+    let partitions = partitions
+        .into_iter()
+        .map(|p| {
+            let mut partition = json!({
+                "name": p.name(),
+                "type": p.ty().to_string(),
+                "subtype": p.subtype().to_string(),
+                "offset": p.offset(),
+                "size": p.size(),
+                "encrypted": p.encrypted(),
+            });
+            if let Some(len) = usage.get(&p.offset()) {
+                partition["used"] = json!(len.unwrap_or(0));
+            }
+
+            partition
+        })
+        .collect::<Vec<_>>();
+
+    print_json(&json!({
+        "flash_size": flash_size,
+        "partitions": partitions,
+    }))
+}
+
+/// Pretty print a partition table, along with any unused space between its
+/// partitions
+///
+/// `usage` contains the length of the image in each app partition, by offset,
+/// or `None` if the partition is empty. If the size of the flash is known, the
+/// unused space after the last partition is shown too.
+fn pretty_print(
+    table: &PartitionTable,
+    usage: &HashMap<u32, Option<usize>>,
+    flash_size: Option<FlashSize>,
+) {
     let mut pretty = Table::new();
 
+    let mut header = vec![
+        Cell::new("Name")
+            .fg(Color::Green)
+            .add_attribute(Attribute::Bold),
+        Cell::new("Type")
+            .fg(Color::Cyan)
+            .add_attribute(Attribute::Bold),
+        Cell::new("SubType")
+            .fg(Color::Magenta)
+            .add_attribute(Attribute::Bold),
+        Cell::new("Offset")
+            .fg(Color::Red)
+            .add_attribute(Attribute::Bold),
+        Cell::new("Size")
+            .fg(Color::Yellow)
+            .add_attribute(Attribute::Bold),
+        Cell::new("Flags")
+            .fg(Color::DarkCyan)
+            .add_attribute(Attribute::Bold),
+    ];
+    if !usage.is_empty() {
+        header.push(Cell::new("Used").add_attribute(Attribute::Bold));
+    }
+
     pretty
         .load_preset(UTF8_FULL)
         .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
-        .set_header(vec![
-            Cell::new("Name")
-                .fg(Color::Green)
-                .add_attribute(Attribute::Bold),
-            Cell::new("Type")
-                .fg(Color::Cyan)
-                .add_attribute(Attribute::Bold),
-            Cell::new("SubType")
-                .fg(Color::Magenta)
-                .add_attribute(Attribute::Bold),
-            Cell::new("Offset")
-                .fg(Color::Red)
-                .add_attribute(Attribute::Bold),
-            Cell::new("Size")
-                .fg(Color::Yellow)
-                .add_attribute(Attribute::Bold),
-            Cell::new("Encrypted")
-                .fg(Color::DarkCyan)
-                .add_attribute(Attribute::Bold),
-        ]);
+        .set_header(header);
+
+    let unused_row = |offset: u32, size: u32| {
+        vec![
+            Cell::new("(unused)").fg(Color::DarkGrey),
+            Cell::new(""),
+            Cell::new(""),
+            Cell::new(format!("{offset:#x}")).fg(Color::DarkGrey),
+            Cell::new(format_size(size)).fg(Color::DarkGrey),
+        ]
+    };
 
-    for p in table.partitions() {
-        pretty.add_row(vec![
+    let mut partitions = table.partitions().iter().collect::<Vec<_>>();
+    partitions.sort_by_key(|p| p.offset());
+
+    let mut used = 0u64;
+    let mut unused = 0u64;
+    let mut end = None;
+
+    for p in partitions {
+        if let Some(end) = end.filter(|end| p.offset() > *end) {
+            pretty.add_row(unused_row(end, p.offset() - end));
+            unused += (p.offset() - end) as u64;
+        }
+
+        let mut row = vec![
             Cell::new(p.name()).fg(Color::Green),
             Cell::new(p.ty().to_string()).fg(Color::Cyan),
             Cell::new(p.subtype().to_string()).fg(Color::Magenta),
             Cell::new(format!("{:#x}", p.offset())).fg(Color::Red),
-            Cell::new(format!("{:#x} ({}KiB)", p.size(), p.size() / 1024)).fg(Color::Yellow),
-            Cell::new(p.encrypted()).fg(Color::DarkCyan),
-        ]);
+            Cell::new(format_size(p.size())).fg(Color::Yellow),
+            Cell::new(if p.encrypted() { "encrypted" } else { "" }).fg(Color::DarkCyan),
+        ];
+        match usage.get(&p.offset()) {
+            Some(Some(len)) => {
+                let percent = *len as u64 * 100 / p.size().max(1) as u64;
+                let color = match percent {
+                    0..=89 => Color::Green,
+                    90..=100 => Color::Yellow,
+                    _ => Color::Red,
+                };
+                row.push(Cell::new(format!("{} ({percent}%)", format_size(*len as u32))).fg(color));
+            }
+            Some(None) => row.push(Cell::new("empty").fg(Color::DarkGrey)),
+            None if !usage.is_empty() => row.push(Cell::new("")),
+            None => {}
+        }
+        pretty.add_row(row);
+
+        used += p.size() as u64;
+        end = Some(end.unwrap_or(0).max(p.offset() + p.size()));
+    }
+
+    let end = end.unwrap_or(0);
+    if let Some(flash_size) = flash_size {
+        if end < flash_size.size() {
+            pretty.add_row(unused_row(end, flash_size.size() - end));
+            unused += (flash_size.size() - end) as u64;
+        }
     }
 
     println!("{pretty}");
+
+    println!("Partitions: {used:#x} bytes ({}KiB)", used / 1024);
+    println!(
+        "Unused:     {unused:#x} bytes ({}KiB){}",
+        unused / 1024,
+        if flash_size.is_some() {
+            ""
+        } else {
+            ", not including the space after the last partition"
+        }
+    );
+    if let Some(flash_size) = flash_size {
+        if end > flash_size.size() {
+            warn!(
+                "The partition table ends at {end:#x}, past the end of the {} flash",
+                format_size(flash_size.size())
+            );
+        }
+    }
+}
+
+fn format_size(size: u32) -> String {
+    format!("{size:#x} ({}KiB)", size / 1024)
+}
+
+/// Parses a percentage between 0 and 100, with or without a `%` sign.
+fn parse_percent(input: &str) -> Result<f32, String> {
+    input
+        .trim_end_matches('%')
+        .parse::<f32>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("`{input}` is not a percentage between 0 and 100"))
 }
 
 /// Parses a string as a 32-bit unsigned integer.
@@ -827,19 +2974,86 @@ pub fn make_flash_data(
         .or(default_partition_table);
 
     if let Some(path) = &bootloader {
-        println!("Bootloader:        {}", path.display());
+        text!("Bootloader:        {}", path.display());
     }
     if let Some(path) = &partition_table {
-        println!("Partition table:   {}", path.display());
+        text!("Partition table:   {}", path.display());
     }
 
-    let flash_settings = make_flash_settings(flash_config_args, config);
-    FlashData::new(
-        bootloader,
-        partition_table,
-        image_args.partition_table_offset,
-        image_args.target_app_partition,
-        flash_settings,
-        image_args.min_chip_rev,
-    )
+    // Reproducible builds use SOURCE_DATE_EPOCH as their build time, if provided
+    // https://reproducible-builds.org/specs/source-date-epoch/
+    let build_time = image_args.build_time.or_else(|| {
+        if !image_args.reproducible {
+            return None;
+        }
+
+        let epoch = env::var("SOURCE_DATE_EPOCH").ok()?;
+
+        match epoch.parse() {
+            Ok(epoch) => Some(epoch),
+            Err(_) => {
+                warn!("Ignoring invalid SOURCE_DATE_EPOCH: {epoch}");
+                None
+            }
+        }
+    });
+
+    let app_descriptor = AppDescriptorOverrides {
+        version: image_args.app_version,
+        project_name: image_args.project_name,
+        build_time,
+        reproducible: image_args.reproducible,
+    };
+
+    let mut builder = FlashDataBuilder::new()
+        .with_flash_settings(make_flash_settings(flash_config_args, config))
+        .with_min_chip_rev(image_args.min_chip_rev)
+        .with_image_format(image_args.image_format)
+        .with_app_descriptor(app_descriptor)
+        .with_rtc_segments(image_args.rtc_segments)
+        .with_skip_bootloader(image_args.no_bootloader)
+        .with_skip_partition_table(image_args.no_partition_table)
+        .with_skip_partition_table_md5(image_args.no_partition_table_md5);
+
+    if let Some(path) = bootloader {
+        builder = builder.with_bootloader(path);
+    }
+    if let Some(path) = partition_table {
+        builder = builder.with_partition_table(path);
+    }
+    if let Some(offset) = image_args.partition_table_offset {
+        builder = builder.with_partition_table_offset(offset);
+    }
+    if let Some(label) = image_args.target_app_partition {
+        builder = builder.with_target_app_partition(label);
+    }
+    if let Some(path) = image_args.mcuboot_key.as_deref() {
+        builder = builder.with_mcuboot_key(path);
+    }
+    if let Some(path) = image_args.sign_key.as_deref() {
+        builder = builder.with_sign_key(path);
+    }
+    if let Some((command, path)) = image_args
+        .sign_command
+        .zip(image_args.sign_public_key.as_deref())
+    {
+        let command = shell_words::split(&command).map_err(|e| {
+            Error::SecureBootSigningFailed(format!("`{command}` cannot be parsed: {e}"))
+        })?;
+        builder = builder.with_sign_command(command, path);
+    }
+    if let Some(path) = image_args.encryption_key.as_deref() {
+        builder = builder.with_encryption_key(path);
+    }
+    for (label, path) in &image_args.flash_file {
+        builder = builder.with_partition_file(label.clone(), path);
+    }
+    if let Some(os) = image_args.os {
+        builder = builder.with_os(os);
+    }
+    if let Some(percent) = image_args.fail_if_over {
+        builder = builder.with_max_partition_usage(percent);
+    }
+
+    builder.build()
 }
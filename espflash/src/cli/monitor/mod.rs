@@ -21,13 +21,11 @@ use crossterm::{
 };
 use log::error;
 use miette::{IntoDiagnostic, Result};
-#[cfg(feature = "serialport")]
-use serialport::SerialPort;
 use strum::{Display, EnumIter, EnumString, VariantNames};
 
 use crate::{
     cli::monitor::parser::{InputParser, ResolvingPrinter},
-    connection::{reset::reset_after_flash, Port},
+    connection::{reset::reset_after_flash, transport::FlasherTransport, Port},
 };
 
 pub mod parser;
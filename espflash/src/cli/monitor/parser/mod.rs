@@ -10,7 +10,10 @@ use crossterm::{
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use crate::cli::monitor::{line_endings::normalized, symbols::Symbols};
+use crate::cli::{
+    events::{self, Event},
+    monitor::{line_endings::normalized, symbols::Symbols},
+};
 
 pub trait InputParser {
     fn feed(&mut self, bytes: &[u8], out: &mut dyn Write);
@@ -155,6 +158,8 @@ impl<W: Write> Write for ResolvingPrinter<'_, W> {
             // Remember to begin a new line after we have printed this one!
             self.writer.queue(Print("\r\n"))?;
 
+            events::emit(Event::Log { line: &line });
+
             // If we have loaded some symbols...
             if let Some(symbols) = self.symbols.as_ref() {
                 // Try to print the names of addresses in the current line.
@@ -0,0 +1,375 @@
+//! Editing of NVS partitions on the target device
+//!
+//! `espflash nvs set` changes individual values stored in the device's NVS
+//! partition, such as Wi-Fi credentials or calibration data, without
+//! reflashing the whole partition. Only the pages affected by the change are
+//! written back, so the other values are kept.
+//!
+//! NVS partitions may also be generated from the CSV files used by ESP-IDF's
+//! `nvs_partition_gen.py`, and decoded, optionally using NVS encryption. The
+//! decoded values may be exported as CSV, which `espflash nvs generate`
+//! accepts, or as JSON.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand, ValueEnum};
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use esp_idf_part::{DataType, Partition, PartitionTable, SubType};
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+
+use super::{config::Config, connect, parse_uint32, ConnectArgs};
+use crate::{
+    error::{Error, MissingPartition},
+    flasher::parse_partition_table,
+    nvs::{parse_entries, to_csv, BlobEncoding, Nvs, NvsEntry, NvsKeys, NvsType, NvsValue},
+};
+
+/// Generate, decode and edit NVS partitions
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct NvsArgs {
+    #[command(subcommand)]
+    pub action: NvsAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum NvsAction {
+    /// Print or export the values stored in an NVS partition image
+    #[command(alias = "dump")]
+    Decode(NvsDecodeArgs),
+    /// Generate an NVS partition image from a CSV file, in the format used by
+    /// ESP-IDF's nvs_partition_gen.py, or a TOML file
+    Generate(NvsGenerateArgs),
+    /// Generate an `nvs_keys` partition image holding new random NVS
+    /// encryption keys
+    GenerateKeys(NvsGenerateKeysArgs),
+    /// Set the value of a key, creating the key and its namespace if they do
+    /// not exist
+    Set(NvsSetArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct NvsDecodeArgs {
+    /// NVS partition image to decode
+    #[arg(value_name = "FILE")]
+    pub input: PathBuf,
+    /// `nvs_keys` partition image holding the keys the partition is encrypted
+    /// with
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// Format to print the values in
+    #[arg(long, value_enum, default_value_t = NvsDecodeFormat::Table)]
+    pub format: NvsDecodeFormat,
+    /// Encoding of blobs in the CSV and JSON formats
+    #[arg(long, value_enum, default_value_t = BlobEncoding::Hex)]
+    pub blob_encoding: BlobEncoding,
+    /// File to write the values to, instead of printing them
+    #[arg(short = 'o', long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+}
+
+/// Format of the values printed by `nvs decode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum NvsDecodeFormat {
+    /// Human-readable table
+    Table,
+    /// CSV file in the format used by `nvs_partition_gen.py`
+    Csv,
+    /// JSON array of entries
+    Json,
+}
+
+/// An entry of the JSON format of `nvs decode`
+///
+/// Blobs and strings are given in the encoding of the CSV format, and integers
+/// as numbers.
+#[derive(Debug, Serialize)]
+struct JsonEntry {
+    namespace: String,
+    key: String,
+    #[serde(rename = "type")]
+    ty: String,
+    encoding: String,
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct NvsGenerateArgs {
+    /// CSV or TOML file describing the contents of the partition
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+    /// File to write the NVS partition image to
+    #[arg(value_name = "FILE")]
+    pub output: PathBuf,
+    /// Size of the partition, a multiple of 4096 bytes
+    #[arg(long, value_parser = parse_uint32)]
+    pub size: u32,
+    /// Encrypt the partition with the keys held in this `nvs_keys` partition
+    /// image
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// Encrypt the partition with new random keys, writing them to this file
+    /// as an `nvs_keys` partition image
+    #[arg(long, value_name = "FILE", conflicts_with = "keys")]
+    pub generate_keys: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct NvsGenerateKeysArgs {
+    /// File to write the `nvs_keys` partition image to
+    #[arg(value_name = "FILE")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct NvsSetArgs {
+    /// Key to set, given as `NAMESPACE.KEY`
+    #[arg(value_name = "NAMESPACE.KEY", value_parser = parse_key)]
+    pub key: (String, String),
+    /// Value to set the key to
+    #[arg(value_name = "VALUE", allow_hyphen_values = true)]
+    pub value: String,
+    /// Type of the value, with blobs given in hexadecimal
+    #[arg(short = 't', long = "type", value_enum, default_value = "string")]
+    pub ty: NvsType,
+    /// Label of the NVS partition
+    #[arg(long, value_name = "LABEL", default_value = "nvs")]
+    pub partition: String,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// `nvs_keys` partition image holding the keys the NVS partition is
+    /// encrypted with
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// Generate, decode or edit NVS partitions
+pub fn nvs(args: NvsArgs, config: &Config) -> Result<()> {
+    match args.action {
+        NvsAction::Decode(args) => decode(args),
+        NvsAction::Generate(args) => generate(args),
+        NvsAction::GenerateKeys(args) => {
+            write_keys(&args.output, &NvsKeys::generate()?)?;
+            info!("NVS encryption keys written to {}", args.output.display());
+
+            Ok(())
+        }
+        NvsAction::Set(args) => set(args, config),
+    }
+}
+
+fn decode(args: NvsDecodeArgs) -> Result<()> {
+    let keys = args.keys.as_deref().map(read_keys).transpose()?;
+    let data = fs::read(&args.input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.input.display()))?;
+
+    let entries = Nvs::parse(data, keys)?.entries()?;
+
+    let output = match args.format {
+        NvsDecodeFormat::Table => table(entries),
+        NvsDecodeFormat::Csv => to_csv(&entries, args.blob_encoding)?,
+        NvsDecodeFormat::Json => {
+            let entries = entries
+                .into_iter()
+                .map(|entry| json_entry(entry, args.blob_encoding))
+                .collect::<Vec<_>>();
+            let mut json = serde_json::to_string_pretty(&entries).into_diagnostic()?;
+            json.push('\n');
+
+            json
+        }
+    };
+
+    match &args.output {
+        Some(path) => fs::write(path, output)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?,
+        None => print!("{output}"),
+    }
+
+    Ok(())
+}
+
+/// Format entries as a table
+fn table(entries: Vec<NvsEntry>) -> String {
+    let mut pretty = Table::new();
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Namespace").add_attribute(Attribute::Bold),
+            Cell::new("Key")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Type").add_attribute(Attribute::Bold),
+            Cell::new("Value").add_attribute(Attribute::Bold),
+        ]);
+
+    for entry in entries {
+        pretty.add_row(vec![
+            Cell::new(entry.namespace),
+            Cell::new(entry.key).fg(Color::Green),
+            Cell::new(entry.value.ty()),
+            Cell::new(entry.value),
+        ]);
+    }
+
+    format!("{pretty}\n")
+}
+
+fn json_entry(entry: NvsEntry, blob_encoding: BlobEncoding) -> JsonEntry {
+    let ty = entry.value.ty().to_string();
+    let (encoding, text) = entry.value.encode(blob_encoding);
+    let value = match entry.value {
+        NvsValue::U8(value) => value.into(),
+        NvsValue::I8(value) => value.into(),
+        NvsValue::U16(value) => value.into(),
+        NvsValue::I16(value) => value.into(),
+        NvsValue::U32(value) => value.into(),
+        NvsValue::I32(value) => value.into(),
+        NvsValue::U64(value) => value.into(),
+        NvsValue::I64(value) => value.into(),
+        _ => text.into(),
+    };
+
+    JsonEntry {
+        namespace: entry.namespace,
+        key: entry.key,
+        ty,
+        encoding,
+        value,
+    }
+}
+
+fn generate(args: NvsGenerateArgs) -> Result<()> {
+    let description = fs::read_to_string(&args.input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.input.display()))?;
+    let entries = parse_entries(&description, &args.input)?;
+
+    let keys = match (&args.keys, &args.generate_keys) {
+        (Some(path), _) => Some(read_keys(path)?),
+        (None, Some(path)) => {
+            let keys = NvsKeys::generate()?;
+            write_keys(path, &keys)?;
+            info!("NVS encryption keys written to {}", path.display());
+
+            Some(keys)
+        }
+        (None, None) => None,
+    };
+
+    let mut nvs = Nvs::new(args.size as usize, keys)?;
+    for entry in &entries {
+        nvs.set(&entry.namespace, &entry.key, &entry.value)?;
+    }
+
+    fs::write(&args.output, nvs.to_bytes())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", args.output.display()))?;
+
+    info!(
+        "NVS partition with {} entries written to {}",
+        entries.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+fn set(args: NvsSetArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let (namespace, key) = &args.key;
+    let value = NvsValue::parse(args.ty, &args.value)?;
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+
+    let partition = nvs_partition(&partition_table, &args.partition)?;
+
+    let keys = args.keys.as_deref().map(read_keys).transpose()?;
+    let data = flasher.read_flash_data(partition.offset(), partition.size(), 0x1000, 64)?;
+    let mut nvs = Nvs::parse(data, keys)?;
+    nvs.set(namespace, key, &value)?;
+
+    for (offset, page) in nvs.modified_pages() {
+        flasher.write_bin_to_flash(partition.offset() + offset, &page, None)?;
+    }
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    info!("Set `{namespace}.{key}` to {value}");
+
+    Ok(())
+}
+
+/// Find the NVS partition with the given label
+pub(crate) fn nvs_partition<'a>(
+    partition_table: &'a PartitionTable,
+    label: &str,
+) -> Result<&'a Partition> {
+    let partition = partition_table
+        .find(label)
+        .ok_or_else(|| MissingPartition::from(label.to_string()))?;
+
+    if partition.subtype() != SubType::Data(DataType::Nvs) {
+        return Err(
+            Error::InvalidNvs(format!("partition `{label}` is not an NVS partition")).into(),
+        );
+    }
+    if partition.encrypted() {
+        return Err(Error::InvalidNvs(format!("partition `{label}` is encrypted")).into());
+    }
+
+    Ok(partition)
+}
+
+pub(crate) fn read_keys(path: &Path) -> Result<NvsKeys> {
+    let data = fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(NvsKeys::from_partition(&data)?)
+}
+
+fn write_keys(path: &Path, keys: &NvsKeys) -> Result<()> {
+    fs::write(path, keys.to_partition())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
+
+fn parse_key(input: &str) -> Result<(String, String), String> {
+    let (namespace, key) = input
+        .split_once('.')
+        .ok_or_else(|| format!("`{input}` is not of the form `NAMESPACE.KEY`"))?;
+
+    Ok((namespace.to_string(), key.to_string()))
+}
@@ -0,0 +1,180 @@
+//! OTA rollback state
+//!
+//! With rollback enabled, the ESP-IDF bootloader boots a newly selected OTA
+//! app once in the `pending-verify` state, and rolls back to the previous app
+//! unless the new one marks itself as `valid`. `espflash ota status` shows the
+//! state recorded in the `otadata` partition, and `espflash ota set-state`
+//! changes it, so that rollback can be exercised by test rigs and devices stuck
+//! in `pending-verify` can be recovered without changes to the app.
+
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use log::info;
+use miette::Result;
+
+use super::{config::Config, connect, parse_uint32, ConnectArgs};
+use crate::{
+    elf::RomSegment,
+    error::Error,
+    flasher::parse_partition_table,
+    ota::{ota_partitions, ota_slot, otadata_partition, OtaImageState, OTADATA_SECTOR_SIZE},
+};
+
+/// Show and change the state of OTA apps
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaArgs {
+    #[command(subcommand)]
+    pub action: OtaAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum OtaAction {
+    /// Set the state of the booted OTA app, or of the app in the given
+    /// partition
+    SetState(OtaSetStateArgs),
+    /// Show which OTA app is booted, and its state
+    Status(OtaStatusArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaSetStateArgs {
+    /// State to set
+    #[arg(value_name = "STATE", value_enum)]
+    pub state: OtaImageState,
+    /// Label of the OTA app partition holding the app, the booted one if not
+    /// provided
+    #[arg(long, value_name = "LABEL")]
+    pub partition: Option<String>,
+    /// Partition table configuration
+    #[clap(flatten)]
+    pub partition_table_args: OtaPartitionTableArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaStatusArgs {
+    /// Partition table configuration
+    #[clap(flatten)]
+    pub partition_table_args: OtaPartitionTableArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaPartitionTableArgs {
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+}
+
+/// Show or change the state of OTA apps
+pub fn ota(args: OtaArgs, config: &Config) -> Result<()> {
+    match args.action {
+        OtaAction::SetState(args) => set_state(args, config),
+        OtaAction::Status(args) => status(args, config),
+    }
+}
+
+fn status(args: OtaStatusArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table_args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_args.partition_table_offset)?,
+    };
+    let otadata = flasher.read_otadata(&partition_table)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    let partitions = ota_partitions(&partition_table);
+    match otadata.active_slot(partitions.len()) {
+        Some(slot) => {
+            let entry = otadata.active_entry().unwrap();
+            println!("Booted app:        {}", partitions[slot].name());
+            println!("Sequence number:   {}", entry.seq);
+            println!("State:             {}", entry.state());
+        }
+        None => println!("Booted app:        factory"),
+    }
+
+    // Apps which were rolled back from are no longer booted, but keep their
+    // state until another app is selected
+    for (slot, partition) in partitions.iter().enumerate() {
+        if otadata.active_slot(partitions.len()) == Some(slot) {
+            continue;
+        }
+        if let Some(index) = otadata.state_entry(Some(slot), partitions.len()) {
+            println!(
+                "Previous app:      {} ({})",
+                partition.name(),
+                otadata.entries[index].state()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn set_state(args: OtaSetStateArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let partition_table = match &args.partition_table_args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_args.partition_table_offset)?,
+    };
+    let mut otadata = flasher.read_otadata(&partition_table)?;
+
+    let partitions = ota_partitions(&partition_table);
+    let slot = match &args.partition {
+        Some(label) => Some(ota_slot(&partition_table, label)?.0),
+        None => None,
+    };
+    let index = otadata.state_entry(slot, partitions.len()).ok_or_else(|| {
+        Error::OtaAppNotSelected(match &args.partition {
+            Some(label) => format!("partition `{label}`"),
+            None => "any OTA app partition".into(),
+        })
+    })?;
+    let label = partitions[otadata.entries[index].slot(partitions.len())]
+        .name()
+        .to_string();
+
+    otadata.entries[index].set_state(args.state);
+
+    let offset = otadata_partition(&partition_table)?.offset();
+    let segment = RomSegment {
+        addr: offset + (index * OTADATA_SECTOR_SIZE) as u32,
+        data: otadata.entries[index].to_sector().into(),
+    };
+    flasher.write_bins_to_flash(&[segment], None)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    info!("The app in partition '{label}' is now {}", args.state);
+
+    Ok(())
+}
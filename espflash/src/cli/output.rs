@@ -0,0 +1,181 @@
+//! Format and verbosity of the information printed by commands
+//!
+//! Commands print information for people to read by default. With
+//! `--output-format json`, commands such as `board-info`, `checksum-md5`,
+//! `image-info` and `partition-table` instead print a single JSON document to
+//! stdout, as does `flash` to describe the image it wrote. Logs, progress bars
+//! and any other text are written to stderr, so that stdout only holds JSON.
+//!
+//! `-q` leaves out progress bars, the information printed about the chip and
+//! image while flashing, and logs other than warnings and errors. `-v` adds
+//! debug logs, and `-vv` adds trace logs along with every frame of the serial
+//! protocol.
+//!
+//! `--progress` selects how the progress of writing to flash is shown: as
+//! progress bars, as rows of dots, as a line for every tenth of the progress,
+//! or not at all, which `--no-progress` also selects. Dots and percentages do
+//! not redraw the terminal, so they suit dumb terminals, CI logs and the
+//! interleaved output of several devices flashed concurrently.
+
+use std::sync::OnceLock;
+
+use log::LevelFilter;
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+
+/// Output format selected on the command line
+static OUTPUT_FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+/// Verbosity selected on the command line
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+/// Display of progress selected on the command line
+static PROGRESS_DISPLAY: OnceLock<ProgressDisplay> = OnceLock::new();
+
+/// Format of the information printed by commands
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[non_exhaustive]
+pub enum OutputFormat {
+    /// Text for people to read
+    #[default]
+    Text,
+    /// JSON, for scripts
+    Json,
+}
+
+/// Select the format of the information printed by commands
+///
+/// Only the first format selected is used.
+pub fn set_output_format(format: OutputFormat) {
+    let _ = OUTPUT_FORMAT.set(format);
+}
+
+/// Format of the information printed by commands
+pub fn output_format() -> OutputFormat {
+    OUTPUT_FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Was JSON output selected?
+pub fn is_json() -> bool {
+    output_format() == OutputFormat::Json
+}
+
+/// How much commands print
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum Verbosity {
+    /// Only warnings, errors and the information requested
+    Quiet,
+    /// Informational logs, progress bars and summaries
+    #[default]
+    Normal,
+    /// Debug logs
+    Verbose,
+    /// Trace logs and the frames of the serial protocol
+    Trace,
+}
+
+impl Verbosity {
+    /// Verbosity selected by the `-q` and `-v` flags, the latter of which may
+    /// be given more than once
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Trace,
+        }
+    }
+
+    /// Most detailed level of the logs which are shown
+    pub fn log_level(self) -> LevelFilter {
+        match self {
+            Verbosity::Quiet => LevelFilter::Warn,
+            Verbosity::Normal => LevelFilter::Info,
+            Verbosity::Verbose => LevelFilter::Debug,
+            Verbosity::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Select how much commands print
+///
+/// Only the first verbosity selected is used.
+pub fn set_verbosity(verbosity: Verbosity) {
+    let _ = VERBOSITY.set(verbosity);
+}
+
+/// How much commands print
+pub fn verbosity() -> Verbosity {
+    VERBOSITY.get().copied().unwrap_or_default()
+}
+
+/// Was quiet output selected?
+pub fn is_quiet() -> bool {
+    verbosity() == Verbosity::Quiet
+}
+
+/// How the progress of writing to flash is shown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[non_exhaustive]
+pub enum ProgressDisplay {
+    /// Progress bars, redrawn as the progress changes
+    #[default]
+    Bar,
+    /// A row of dots for each segment, growing as the progress changes
+    Dots,
+    /// A line for every tenth of the progress of each segment
+    Percent,
+    /// No progress is shown
+    None,
+}
+
+impl ProgressDisplay {
+    /// Display selected by the `--progress` and `--no-progress` flags
+    pub fn from_flags(progress: ProgressDisplay, no_progress: bool) -> Self {
+        if no_progress {
+            ProgressDisplay::None
+        } else {
+            progress
+        }
+    }
+}
+
+/// Select how the progress of writing to flash is shown
+///
+/// Only the first display selected is used.
+pub fn set_progress_display(display: ProgressDisplay) {
+    let _ = PROGRESS_DISPLAY.set(display);
+}
+
+/// How the progress of writing to flash is shown, which is not at all when
+/// quiet output was selected
+pub fn progress_display() -> ProgressDisplay {
+    if is_quiet() {
+        return ProgressDisplay::None;
+    }
+
+    PROGRESS_DISPLAY.get().copied().unwrap_or_default()
+}
+
+/// Print a value as JSON to stdout
+pub(crate) fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value).into_diagnostic()?);
+
+    Ok(())
+}
+
+/// Print a line of text to stdout, or to stderr when JSON output was selected
+///
+/// Nothing is printed when quiet output was selected.
+macro_rules! text {
+    ($($arg:tt)*) => {
+        if $crate::cli::output::is_quiet() {
+            // Nothing is printed
+        } else if $crate::cli::output::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use text;
@@ -0,0 +1,330 @@
+//! Comparison of partition tables
+//!
+//! Devices updated over the air keep the partition table they were
+//! manufactured with, so an application relying on a different layout must not
+//! be deployed to them. `espflash partition-table diff` shows how two
+//! partition tables differ, matching partitions by their labels.
+
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use esp_idf_part::{Partition, PartitionTable};
+use miette::Result;
+
+use super::{config::Config, connect, parse_uint32, ConnectArgs};
+use crate::{error::Error, flasher::parse_partition_table};
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum PartitionTableAction {
+    /// Compare two partition tables, or a partition table with the one on the
+    /// target device
+    Diff(PartitionTableDiffArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct PartitionTableDiffArgs {
+    /// Partition table to compare, in CSV, binary or TOML format
+    #[arg(value_name = "FILE")]
+    pub partition_table: PathBuf,
+    /// Partition table to compare FILE with, showing the changes from FILE to
+    /// this table
+    #[arg(value_name = "OTHER", required_unless_present = "device")]
+    pub other: Option<PathBuf>,
+    /// Compare the partition table on the target device with FILE, showing the
+    /// changes from the device's table to FILE
+    #[arg(long, conflicts_with = "other")]
+    pub device: bool,
+    /// Connection configuration, used with `--device`
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Offset of the partition table on the device, used with `--device`
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Exit with an error if the partition tables differ
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// A difference between two partition tables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionChange {
+    /// The partition only exists in the new table
+    Added {
+        name: String,
+        offset: u32,
+        size: u32,
+    },
+    /// The partition only exists in the old table
+    Removed {
+        name: String,
+        offset: u32,
+        size: u32,
+    },
+    /// The partition's offset changed
+    Moved { name: String, from: u32, to: u32 },
+    /// The partition's size changed
+    Resized { name: String, from: u32, to: u32 },
+    /// The partition's type or subtype changed
+    Retyped {
+        name: String,
+        from: String,
+        to: String,
+    },
+    /// The partition's flags changed
+    FlagsChanged {
+        name: String,
+        from: String,
+        to: String,
+    },
+}
+
+/// Compare two partition tables or a partition table and the one on the
+/// device
+pub fn partition_table_diff(args: PartitionTableDiffArgs, config: &Config) -> Result<()> {
+    let (old, new) = if args.device {
+        if args.connect_args.no_stub {
+            return Err(Error::StubRequired.into());
+        }
+
+        let mut flasher = connect(&args.connect_args, config, false, false)?;
+        let old = flasher.read_partition_table(args.partition_table_offset)?;
+
+        (old, parse_partition_table(&args.partition_table)?)
+    } else {
+        // Either `OTHER` or `--device` is required
+        let other = args.other.as_ref().unwrap();
+
+        (
+            parse_partition_table(&args.partition_table)?,
+            parse_partition_table(other)?,
+        )
+    };
+
+    let changes = diff(&old, &new);
+    if changes.is_empty() {
+        println!("The partition tables are identical");
+        return Ok(());
+    }
+
+    pretty_print(&changes);
+
+    if args.check {
+        return Err(Error::PartitionTablesDiffer.into());
+    }
+
+    Ok(())
+}
+
+/// Determine the changes from the `old` to the `new` partition table
+///
+/// Partitions are matched by their labels. Removed partitions are listed
+/// first, followed by the changes to the remaining partitions and any added
+/// partitions, each in order of their offsets.
+pub fn diff(old: &PartitionTable, new: &PartitionTable) -> Vec<PartitionChange> {
+    let mut changes = Vec::new();
+
+    for part in sorted(old) {
+        let name = part.name().to_string();
+        if new.find(&name).is_none() {
+            changes.push(PartitionChange::Removed {
+                name,
+                offset: part.offset(),
+                size: part.size(),
+            });
+        }
+    }
+
+    for part in sorted(new) {
+        let name = part.name().to_string();
+        let Some(old) = old.find(&name) else {
+            changes.push(PartitionChange::Added {
+                name,
+                offset: part.offset(),
+                size: part.size(),
+            });
+            continue;
+        };
+
+        if (old.ty(), old.subtype()) != (part.ty(), part.subtype()) {
+            changes.push(PartitionChange::Retyped {
+                name: name.clone(),
+                from: format!("{}/{}", old.ty(), old.subtype()),
+                to: format!("{}/{}", part.ty(), part.subtype()),
+            });
+        }
+        if old.offset() != part.offset() {
+            changes.push(PartitionChange::Moved {
+                name: name.clone(),
+                from: old.offset(),
+                to: part.offset(),
+            });
+        }
+        if old.size() != part.size() {
+            changes.push(PartitionChange::Resized {
+                name: name.clone(),
+                from: old.size(),
+                to: part.size(),
+            });
+        }
+        if old.encrypted() != part.encrypted() {
+            changes.push(PartitionChange::FlagsChanged {
+                name,
+                from: flags(old),
+                to: flags(part),
+            });
+        }
+    }
+
+    changes
+}
+
+fn sorted(table: &PartitionTable) -> Vec<&Partition> {
+    let mut partitions = table.partitions().iter().collect::<Vec<_>>();
+    partitions.sort_by_key(|part| part.offset());
+
+    partitions
+}
+
+fn flags(part: &Partition) -> String {
+    if part.encrypted() {
+        "encrypted".into()
+    } else {
+        "none".into()
+    }
+}
+
+fn pretty_print(changes: &[PartitionChange]) {
+    let mut pretty = Table::new();
+
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Change").add_attribute(Attribute::Bold),
+            Cell::new("Name")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Old").add_attribute(Attribute::Bold),
+            Cell::new("New").add_attribute(Attribute::Bold),
+        ]);
+
+    let location = |offset: u32, size: u32| format!("{offset:#x}, {size:#x} ({}KiB)", size / 1024);
+
+    for change in changes {
+        let (kind, color, name, old, new) = match change {
+            PartitionChange::Added { name, offset, size } => (
+                "added",
+                Color::Green,
+                name,
+                String::new(),
+                location(*offset, *size),
+            ),
+            PartitionChange::Removed { name, offset, size } => (
+                "removed",
+                Color::Red,
+                name,
+                location(*offset, *size),
+                String::new(),
+            ),
+            PartitionChange::Moved { name, from, to } => (
+                "moved",
+                Color::Yellow,
+                name,
+                format!("{from:#x}"),
+                format!("{to:#x}"),
+            ),
+            PartitionChange::Resized { name, from, to } => (
+                "resized",
+                Color::Yellow,
+                name,
+                format!("{from:#x} ({}KiB)", from / 1024),
+                format!("{to:#x} ({}KiB)", to / 1024),
+            ),
+            PartitionChange::Retyped { name, from, to } => {
+                ("retyped", Color::Yellow, name, from.clone(), to.clone())
+            }
+            PartitionChange::FlagsChanged { name, from, to } => (
+                "flags changed",
+                Color::Yellow,
+                name,
+                from.clone(),
+                to.clone(),
+            ),
+        };
+
+        pretty.add_row(vec![
+            Cell::new(kind).fg(color),
+            Cell::new(name).fg(Color::Green),
+            Cell::new(old),
+            Cell::new(new),
+        ]);
+    }
+
+    println!("{pretty}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(csv: &str) -> PartitionTable {
+        PartitionTable::try_from_str(csv).unwrap()
+    }
+
+    #[test]
+    fn identical_tables_have_no_changes() {
+        let csv = "
+nvs,      data, nvs,     0x9000,  0x6000
+factory,  app,  factory, 0x10000, 0x100000
+";
+
+        assert!(diff(&table(csv), &table(csv)).is_empty());
+    }
+
+    #[test]
+    fn detects_changes() {
+        let old = table(
+            "
+nvs,      data, nvs,     0x9000,  0x6000
+phy_init, data, phy,     0xf000,  0x1000
+factory,  app,  factory, 0x10000, 0x100000
+",
+        );
+        let new = table(
+            "
+nvs,      data, nvs,     0x9000,   0x5000
+factory,  app,  factory, 0x20000,  0x100000
+storage,  data, fat,     0x120000, 0x10000, encrypted
+",
+        );
+
+        assert_eq!(
+            diff(&old, &new),
+            [
+                PartitionChange::Removed {
+                    name: "phy_init".into(),
+                    offset: 0xf000,
+                    size: 0x1000
+                },
+                PartitionChange::Resized {
+                    name: "nvs".into(),
+                    from: 0x6000,
+                    to: 0x5000
+                },
+                PartitionChange::Moved {
+                    name: "factory".into(),
+                    from: 0x10000,
+                    to: 0x20000
+                },
+                PartitionChange::Added {
+                    name: "storage".into(),
+                    offset: 0x120000,
+                    size: 0x10000
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,523 @@
+//! Factory provisioning of per-device data
+//!
+//! `espflash provision` writes unique data, such as serial numbers, keys and
+//! certificates, to the NVS partition of each device on a production line. The
+//! partition is described by a template in the CSV or TOML format accepted by
+//! `nvs generate`, in which `{{COLUMN}}` placeholders are replaced with the
+//! values of the device's row in a CSV device list.
+//!
+//! Each device provisioned is recorded in a log, identified by the first column
+//! of its row. Devices which are already recorded are skipped, so that the
+//! next run continues with the following devices in the list.
+//!
+//! For devices programmed by a contract manufacturer, `espflash
+//! production-images` instead bakes each device's NVS partition into a copy
+//! of a merged factory image, and lists the resulting images along with their
+//! digests in a manifest.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::Args;
+use esp_idf_part::PartitionTable;
+use log::{info, warn};
+use md5::Md5;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use super::{
+    config::Config,
+    connect,
+    nvs::{nvs_partition, read_keys},
+    parse_uint32, ConnectArgs,
+};
+use crate::{
+    error::Error,
+    flasher::{parse_partition_table, PARTITION_TABLE_SIZE},
+    nvs::{parse_entries, split_csv_row, Nvs, NvsKeys},
+};
+
+const LOG_HEADER: &str = "time,device,port,mac,partition,offset,size,keys";
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ProvisionArgs {
+    /// NVS partition template in CSV or TOML format, in which `{{COLUMN}}`
+    /// placeholders are replaced with the values of the device's row
+    ///
+    /// The `{{mac}}` and `{{port}}` placeholders are replaced with the MAC
+    /// address of the device and the serial port it is connected to.
+    #[arg(value_name = "TEMPLATE")]
+    pub template: PathBuf,
+    /// CSV file listing the devices to provision, whose first row names the
+    /// columns and whose first column identifies each device
+    #[arg(long, value_name = "FILE")]
+    pub devices: PathBuf,
+    /// Serial ports of the devices to provision, each provisioned with the
+    /// next device in the list. A single device is provisioned using the
+    /// usual port selection if not provided
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    pub ports: Vec<String>,
+    /// Label of the NVS partition
+    #[arg(long, value_name = "LABEL", default_value = "nvs")]
+    pub partition: String,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Encrypt the NVS partition with the keys held in this `nvs_keys`
+    /// partition image
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// CSV file to which a record of each provisioned device is appended
+    #[arg(long, value_name = "FILE", default_value = "provisioning-log.csv")]
+    pub log: PathBuf,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ProductionImagesArgs {
+    /// Merged factory image, as saved by `save-image --merge`
+    #[arg(value_name = "IMAGE")]
+    pub image: PathBuf,
+    /// NVS partition template in CSV or TOML format, in which `{{COLUMN}}`
+    /// placeholders are replaced with the values of the device's row
+    #[arg(value_name = "TEMPLATE")]
+    pub template: PathBuf,
+    /// CSV file listing the devices to generate images for, whose first row
+    /// names the columns and whose first column identifies each device
+    #[arg(long, value_name = "FILE")]
+    pub devices: PathBuf,
+    /// Label of the NVS partition
+    #[arg(long, value_name = "LABEL", default_value = "nvs")]
+    pub partition: String,
+    /// Input partition table, read from the image if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table within the image
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Encrypt the NVS partitions with the keys held in this `nvs_keys`
+    /// partition image
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// Directory to write the images and their manifest to
+    #[arg(long, value_name = "DIR", default_value = "production")]
+    pub output_dir: PathBuf,
+}
+
+/// Description of the images generated by `production-images`
+#[derive(Debug, Serialize)]
+struct ProductionManifest {
+    image: String,
+    sha256: String,
+    partition: String,
+    offset: u32,
+    size: u32,
+    devices: Vec<DeviceImage>,
+}
+
+/// The image of a single device, to be written to flash at offset 0
+#[derive(Debug, Serialize)]
+struct DeviceImage {
+    device: String,
+    file: String,
+    size: u32,
+    sha256: String,
+    md5: String,
+    nvs_sha256: String,
+}
+
+/// A row of the device list
+#[derive(Debug)]
+struct Device {
+    id: String,
+    values: HashMap<String, String>,
+}
+
+/// Provision the attached devices with the next devices of the device list
+pub fn provision(args: ProvisionArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub && args.partition_table.is_none() {
+        return Err(Error::StubRequired.into());
+    }
+
+    let template = fs::read_to_string(&args.template)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.template.display()))?;
+    let devices = fs::read_to_string(&args.devices)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.devices.display()))?;
+    let provisioned = read_log(&args.log)?;
+    let keys = args.keys.as_deref().map(read_keys).transpose()?;
+
+    let mut pending = parse_devices(&devices)?
+        .into_iter()
+        .filter(|device| !provisioned.contains(&device.id))
+        .peekable();
+    if pending.peek().is_none() {
+        return Err(Error::NoDevicesToProvision.into());
+    }
+
+    let ports = if args.ports.is_empty() {
+        vec![args.connect_args.port.clone()]
+    } else {
+        args.ports.iter().cloned().map(Some).collect()
+    };
+
+    for port in ports {
+        let Some(device) = pending.next() else {
+            warn!("All devices in the device list have been provisioned");
+            break;
+        };
+
+        let connect_args = ConnectArgs {
+            port,
+            ..args.connect_args.clone()
+        };
+        provision_device(
+            &args,
+            &connect_args,
+            config,
+            &template,
+            &device,
+            keys.as_ref(),
+        )
+        .wrap_err_with(|| format!("Failed to provision device `{}`", device.id))?;
+    }
+
+    Ok(())
+}
+
+fn provision_device(
+    args: &ProvisionArgs,
+    connect_args: &ConnectArgs,
+    config: &Config,
+    template: &str,
+    device: &Device,
+    keys: Option<&NvsKeys>,
+) -> Result<()> {
+    let mut flasher = connect(connect_args, config, false, false)?;
+    let mac = flasher.device_info()?.mac_address;
+    let port = connect_args.port.clone().unwrap_or_default();
+
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+    let partition = nvs_partition(&partition_table, &args.partition)?;
+
+    let mut values = device.values.clone();
+    values.insert("mac".into(), mac.clone());
+    values.insert("port".into(), port.clone());
+    let description = substitute(template, &values)?;
+    let entries = parse_entries(&description, &args.template)?;
+
+    let mut nvs = Nvs::new(partition.size() as usize, keys.cloned())?;
+    for entry in &entries {
+        nvs.set(&entry.namespace, &entry.key, &entry.value)?;
+    }
+    flasher.write_bin_to_flash(partition.offset(), &nvs.to_bytes(), None)?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!connect_args.no_stub, chip)?;
+
+    let keys = entries
+        .iter()
+        .map(|entry| format!("{}.{}", entry.namespace, entry.key))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    append_log(
+        &args.log,
+        &[
+            time.to_string(),
+            device.id.clone(),
+            port,
+            mac.clone(),
+            partition.name().to_string(),
+            format!("{:#x}", partition.offset()),
+            format!("{:#x}", partition.size()),
+            keys,
+        ],
+    )?;
+
+    info!(
+        "Provisioned device `{}` ({mac}) with {} entries in partition '{}'",
+        device.id,
+        entries.len(),
+        partition.name()
+    );
+
+    Ok(())
+}
+
+/// Generate a factory image with its own NVS partition for each device of the
+/// device list
+/// Read the partition table of a merged image
+pub(crate) fn image_partition_table(image: &[u8], offset: u32) -> Result<PartitionTable> {
+    let table = image
+        .get(offset as usize..)
+        .and_then(|table| table.get(..PARTITION_TABLE_SIZE as usize))
+        .ok_or_else(|| {
+            Error::InvalidPartitionTable("the image does not contain a partition table".into())
+        })?;
+
+    Ok(PartitionTable::try_from_bytes(table.to_vec()).map_err(Error::from)?)
+}
+
+pub fn production_images(args: ProductionImagesArgs) -> Result<()> {
+    let image = fs::read(&args.image)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.image.display()))?;
+    let template = fs::read_to_string(&args.template)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.template.display()))?;
+    let devices = fs::read_to_string(&args.devices)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.devices.display()))?;
+    let devices = parse_devices(&devices)?;
+    let keys = args.keys.as_deref().map(read_keys).transpose()?;
+
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => image_partition_table(&image, args.partition_table_offset)?,
+    };
+    let partition = nvs_partition(&partition_table, &args.partition)?;
+    let (offset, size) = (partition.offset() as usize, partition.size() as usize);
+
+    // Images ending before the NVS partition are padded to cover it
+    let mut base = image.clone();
+    if base.len() < offset + size {
+        base.resize(offset + size, 0xff);
+    }
+
+    fs::create_dir_all(&args.output_dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", args.output_dir.display()))?;
+
+    let mut files = HashSet::new();
+    let mut images = Vec::new();
+
+    for device in &devices {
+        let description = substitute(&template, &device.values)?;
+        let entries = parse_entries(&description, &args.template)
+            .wrap_err_with(|| format!("Failed to generate the NVS of device `{}`", device.id))?;
+
+        let mut nvs = Nvs::new(size, keys.clone())?;
+        for entry in &entries {
+            nvs.set(&entry.namespace, &entry.key, &entry.value)?;
+        }
+        let nvs = nvs.to_bytes();
+
+        let mut data = base.clone();
+        data[offset..][..size].copy_from_slice(&nvs);
+
+        let file = format!("{}.bin", file_name(&device.id));
+        if !files.insert(file.clone()) {
+            return Err(Error::InvalidDeviceList(format!(
+                "more than one device is named `{}`",
+                device.id
+            ))
+            .into());
+        }
+
+        let path = args.output_dir.join(&file);
+        fs::write(&path, &data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+
+        images.push(DeviceImage {
+            device: device.id.clone(),
+            file,
+            size: data.len() as u32,
+            sha256: hex::encode(Sha256::digest(&data)),
+            md5: hex::encode(Md5::digest(&data)),
+            nvs_sha256: hex::encode(Sha256::digest(&nvs)),
+        });
+    }
+
+    let manifest = ProductionManifest {
+        image: args
+            .image
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        sha256: hex::encode(Sha256::digest(&image)),
+        partition: partition.name().to_string(),
+        offset: partition.offset(),
+        size: partition.size(),
+        devices: images,
+    };
+    let manifest_path = args.output_dir.join("manifest.json");
+    let manifest = serde_json::to_string_pretty(&manifest).into_diagnostic()?;
+    fs::write(&manifest_path, manifest)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", manifest_path.display()))?;
+
+    info!(
+        "Images of {} devices written to {}",
+        devices.len(),
+        args.output_dir.display()
+    );
+
+    Ok(())
+}
+
+/// A file name for a device, replacing any characters which are not safe to
+/// use in file names
+fn file_name(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Parse the device list, whose first row names the columns
+fn parse_devices(csv: &str) -> Result<Vec<Device>, Error> {
+    let mut rows = csv
+        .lines()
+        .enumerate()
+        .filter(|(_, row)| !row.trim().is_empty() && !row.trim_start().starts_with('#'))
+        .map(|(line, row)| {
+            split_csv_row(row.trim()).ok_or_else(|| {
+                Error::InvalidDeviceList(format!("unterminated quote at line {}", line + 1))
+            })
+        });
+
+    let header = rows
+        .next()
+        .transpose()?
+        .ok_or_else(|| Error::InvalidDeviceList("the list is empty".into()))?;
+
+    rows.map(|row| {
+        let row = row?;
+        let id = row.first().cloned().unwrap_or_default();
+        if id.is_empty() {
+            return Err(Error::InvalidDeviceList(
+                "the first column of each device must identify it".into(),
+            ));
+        }
+
+        Ok(Device {
+            id,
+            values: header.iter().cloned().zip(row).collect(),
+        })
+    })
+    .collect()
+}
+
+/// Replace the `{{NAME}}` placeholders of a template with their values
+fn substitute(template: &str, values: &HashMap<String, String>) -> Result<String, Error> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or_else(|| {
+            Error::InvalidProvisioningTemplate("a placeholder is not terminated".into())
+        })?;
+        let name = rest[start + 2..start + end].trim();
+        let value = values.get(name).ok_or_else(|| {
+            Error::InvalidProvisioningTemplate(format!("the device list has no column `{name}`"))
+        })?;
+
+        output.push_str(&rest[..start]);
+        output.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Identifiers of the devices recorded in the log
+fn read_log(path: &Path) -> Result<HashSet<String>> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+
+    let log = fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(log
+        .lines()
+        .skip(1)
+        .filter_map(split_csv_row)
+        .filter_map(|row| row.get(1).cloned())
+        .collect())
+}
+
+fn append_log(path: &Path, record: &[String]) -> Result<()> {
+    let exists = path.exists();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    let record = record
+        .iter()
+        .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    if !exists {
+        writeln!(file, "{LOG_HEADER}").into_diagnostic()?;
+    }
+    writeln!(file, "{record}")
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substitutes_placeholders() {
+        let values = HashMap::from([
+            ("serial".to_string(), "SN-0001".to_string()),
+            ("mac".to_string(), "aa:bb:cc:dd:ee:ff".to_string()),
+        ]);
+
+        assert_eq!(
+            substitute(
+                "serial,data,string,{{ serial }}\nmac,data,string,{{mac}}",
+                &values
+            )
+            .unwrap(),
+            "serial,data,string,SN-0001\nmac,data,string,aa:bb:cc:dd:ee:ff"
+        );
+        assert!(substitute("{{unknown}}", &values).is_err());
+        assert!(substitute("{{serial", &values).is_err());
+    }
+
+    #[test]
+    fn parses_devices() {
+        let devices = parse_devices("serial,ssid\nSN-0001,\"AP, 1\"\nSN-0002,AP2\n").unwrap();
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, "SN-0001");
+        assert_eq!(devices[0].values["ssid"], "AP, 1");
+        assert_eq!(devices[1].values["serial"], "SN-0002");
+    }
+}
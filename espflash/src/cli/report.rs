@@ -0,0 +1,201 @@
+//! Machine-readable reports of flash operations
+//!
+//! With `--report FILE`, `flash` and `write-bin` write a JSON record of the
+//! operation once it has finished, successfully or not, giving production
+//! lines an audit trail for each device:
+//!
+//! - the version of espflash, when the operation started and how long it took
+//! - its result, and the error if it failed
+//! - the chip, its revision and its MAC address
+//! - each artifact written, with its offset, size, MD5 and SHA-256 digests,
+//!   whether it was written or skipped as unchanged, how long writing it took,
+//!   and whether the flash contents were verified to match it afterwards
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use md5::Md5;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, flasher::Flasher};
+
+/// Report of the operation in progress, if one was requested
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Record of a flash operation
+#[derive(Debug, Serialize)]
+struct Report {
+    espflash_version: &'static str,
+    started_at: u64,
+    duration_ms: u64,
+    result: &'static str,
+    error: Option<String>,
+    device: Option<Device>,
+    artifacts: Vec<Artifact>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// Target device an operation was run on
+#[derive(Debug, Serialize)]
+struct Device {
+    chip: String,
+    revision: Option<String>,
+    mac: String,
+}
+
+/// Data written to flash
+#[derive(Debug, Serialize)]
+struct Artifact {
+    offset: u32,
+    size: u32,
+    md5: String,
+    sha256: String,
+    written: bool,
+    duration_ms: Option<u64>,
+    verified: Option<bool>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+/// Run an operation, writing a report of it to the given path, if any
+pub fn run_with_report(path: Option<&Path>, operation: impl FnOnce() -> Result<()>) -> Result<()> {
+    let Some(path) = path else {
+        return operation();
+    };
+
+    *lock() = Some(Report {
+        espflash_version: env!("CARGO_PKG_VERSION"),
+        started_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default(),
+        duration_ms: 0,
+        result: "success",
+        error: None,
+        device: None,
+        artifacts: Vec::new(),
+        started: Instant::now(),
+    });
+
+    let result = operation();
+
+    let mut report = lock().take().unwrap();
+    report.duration_ms = report.started.elapsed().as_millis() as u64;
+    if let Err(err) = &result {
+        report.result = "failure";
+        report.error = Some(format!("{err:#}"));
+    }
+
+    let json = serde_json::to_string_pretty(&report).into_diagnostic()?;
+    fs::write(path, json)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write the report to {}", path.display()))?;
+
+    result
+}
+
+fn lock() -> MutexGuard<'static, Option<Report>> {
+    REPORT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Is a report being recorded?
+fn is_recording() -> bool {
+    lock().is_some()
+}
+
+/// Record the target device in the report, if one is being recorded
+pub(crate) fn record_device(flasher: &mut Flasher) -> Result<()> {
+    if !is_recording() {
+        return Ok(());
+    }
+
+    let info = flasher.device_info()?;
+    if let Some(report) = lock().as_mut() {
+        report.device = Some(Device {
+            chip: info.chip.to_string(),
+            revision: info
+                .revision
+                .map(|(major, minor)| format!("{major}.{minor}")),
+            mac: info.mac_address,
+        });
+    }
+
+    Ok(())
+}
+
+/// Record a segment about to be written, if a report is being recorded
+pub(crate) fn record_segment(addr: u32, data: &[u8]) {
+    if let Some(report) = lock().as_mut() {
+        report.artifacts.push(Artifact {
+            offset: addr,
+            size: data.len() as u32,
+            md5: hex::encode(Md5::digest(data)),
+            sha256: hex::encode(Sha256::digest(data)),
+            written: false,
+            duration_ms: None,
+            verified: None,
+            started: None,
+        });
+    }
+}
+
+/// Record that writing the last segment recorded started
+pub(crate) fn record_write_started() {
+    if let Some(artifact) = lock().as_mut().and_then(|r| r.artifacts.last_mut()) {
+        artifact.written = true;
+        artifact.started = Some(Instant::now());
+    }
+}
+
+/// Record that writing the last segment recorded finished
+pub(crate) fn record_write_finished() {
+    if let Some(artifact) = lock().as_mut().and_then(|r| r.artifacts.last_mut()) {
+        artifact.duration_ms = artifact
+            .started
+            .map(|started| started.elapsed().as_millis() as u64);
+    }
+}
+
+/// Compare the flash contents with each artifact recorded in the report, if
+/// one is being recorded
+///
+/// Returns an error if any of them do not match, once the results have been
+/// recorded.
+pub fn verify_report(flasher: &mut Flasher) -> Result<()> {
+    let regions = match lock().as_ref() {
+        Some(report) => report
+            .artifacts
+            .iter()
+            .map(|artifact| (artifact.offset, artifact.size, artifact.md5.clone()))
+            .collect::<Vec<_>>(),
+        None => return Ok(()),
+    };
+
+    let mut verified = Vec::with_capacity(regions.len());
+    for (offset, size, md5) in regions {
+        let checksum = flasher.checksum_md5(offset, size)?;
+        verified.push(format!("{checksum:032x}") == md5);
+    }
+
+    let all_verified = verified.iter().all(|verified| *verified);
+    if let Some(report) = lock().as_mut() {
+        for (artifact, verified) in report.artifacts.iter_mut().zip(verified) {
+            artifact.verified = Some(verified);
+        }
+    }
+
+    if !all_verified {
+        return Err(Error::VerifyFailed.into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Backup and restore of RF calibration data
+//!
+//! ESP-IDF applications store the results of the RF calibration they perform
+//! on first boot in the `phy` namespace of the default NVS partition. Erasing
+//! the flash discards them, so the next boot performs a full calibration again,
+//! which may give worse results than the original one, for instance when the
+//! antenna is no longer in its factory environment.
+//!
+//! `espflash rf-calibration backup` saves the calibration data to a file, as a
+//! small NVS partition image which `espflash nvs decode` can also read, and
+//! `espflash rf-calibration restore` writes it back to the NVS partition once
+//! the device has been erased and reflashed.
+
+use std::{fs, path::PathBuf};
+
+use clap::{Args, Subcommand};
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{
+    config::Config,
+    connect,
+    nvs::{nvs_partition, read_keys},
+    parse_uint32, ConnectArgs,
+};
+use crate::{
+    error::Error,
+    flasher::{parse_partition_table, Flasher},
+    nvs::{Nvs, NVS_PAGE_SIZE},
+};
+
+/// NVS namespace holding the RF calibration data
+const PHY_NAMESPACE: &str = "phy";
+/// Size of the NVS partition images holding backups
+const BACKUP_SIZE: usize = 4 * NVS_PAGE_SIZE;
+
+/// Back up and restore RF calibration data
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RfCalibrationArgs {
+    #[command(subcommand)]
+    pub action: RfCalibrationAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum RfCalibrationAction {
+    /// Save the RF calibration data stored in the NVS partition to a file
+    Backup(RfCalibrationBackupArgs),
+    /// Write RF calibration data saved by `rf-calibration backup` to the NVS
+    /// partition
+    Restore(RfCalibrationRestoreArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RfCalibrationBackupArgs {
+    /// File to save the RF calibration data to
+    #[arg(value_name = "FILE")]
+    pub output: PathBuf,
+    /// NVS partition configuration
+    #[clap(flatten)]
+    pub nvs_args: RfCalibrationNvsArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RfCalibrationRestoreArgs {
+    /// File holding the RF calibration data to restore
+    #[arg(value_name = "FILE")]
+    pub input: PathBuf,
+    /// NVS partition configuration
+    #[clap(flatten)]
+    pub nvs_args: RfCalibrationNvsArgs,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RfCalibrationNvsArgs {
+    /// Label of the NVS partition holding the RF calibration data
+    #[arg(long, value_name = "LABEL", default_value = "nvs")]
+    pub partition: String,
+    /// Input partition table, read from the device if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// `nvs_keys` partition image holding the keys the NVS partition is
+    /// encrypted with
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+}
+
+/// Back up or restore RF calibration data
+pub fn rf_calibration(args: RfCalibrationArgs, config: &Config) -> Result<()> {
+    match args.action {
+        RfCalibrationAction::Backup(args) => backup(args, config),
+        RfCalibrationAction::Restore(args) => restore(args, config),
+    }
+}
+
+fn backup(args: RfCalibrationBackupArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let (_, nvs) = read_nvs(&mut flasher, &args.nvs_args)?;
+
+    let entries = nvs
+        .entries()?
+        .into_iter()
+        .filter(|entry| entry.namespace == PHY_NAMESPACE)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Err(Error::RfCalibrationNotFound(args.nvs_args.partition).into());
+    }
+
+    let mut backup = Nvs::new(BACKUP_SIZE, None)?;
+    for entry in &entries {
+        backup.set(&entry.namespace, &entry.key, &entry.value)?;
+    }
+
+    fs::write(&args.output, backup.to_bytes())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", args.output.display()))?;
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    info!(
+        "{} RF calibration values saved to {}",
+        entries.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+fn restore(args: RfCalibrationRestoreArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let data = fs::read(&args.input)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", args.input.display()))?;
+    let entries = Nvs::parse(data, None)?
+        .entries()?
+        .into_iter()
+        .filter(|entry| entry.namespace == PHY_NAMESPACE)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Err(Error::InvalidNvs(format!(
+            "{} holds no RF calibration data",
+            args.input.display()
+        ))
+        .into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    let (offset, mut nvs) = read_nvs(&mut flasher, &args.nvs_args)?;
+    for entry in &entries {
+        nvs.set(&entry.namespace, &entry.key, &entry.value)?;
+    }
+
+    for (page_offset, page) in nvs.modified_pages() {
+        flasher.write_bin_to_flash(offset + page_offset, &page, None)?;
+    }
+
+    let chip = flasher.chip();
+    flasher
+        .connection()
+        .reset_after(!args.connect_args.no_stub, chip)?;
+
+    info!("{} RF calibration values restored", entries.len());
+
+    Ok(())
+}
+
+/// Read the NVS partition from the device, returning its offset along with it
+fn read_nvs(flasher: &mut Flasher, args: &RfCalibrationNvsArgs) -> Result<(u32, Nvs)> {
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.read_partition_table(args.partition_table_offset)?,
+    };
+    let partition = nvs_partition(&partition_table, &args.partition)?;
+
+    let keys = args.keys.as_deref().map(read_keys).transpose()?;
+    let data = flasher.read_flash_data(partition.offset(), partition.size(), 0x1000, 64)?;
+
+    Ok((partition.offset(), Nvs::parse(data, keys)?))
+}
@@ -0,0 +1,386 @@
+//! Sequences of operations run over a single connection
+//!
+//! `espflash run-script` runs the operations listed in a TOML script in order,
+//! connecting to the target device once rather than for each operation, which
+//! makes provisioning sequences both faster and reproducible:
+//!
+//! ```toml
+//! # Partition table used to find partitions by label, read from the device at
+//! # `partition_table_offset` (0x8000 by default) if not provided
+//! partition_table = "partitions.csv"
+//!
+//! [[operation]]
+//! op = "erase-region"
+//! address = 0x9000
+//! size = 0x6000
+//!
+//! [[operation]]
+//! op = "write-bin"
+//! address = 0x10000
+//! file = "app.bin"
+//!
+//! [[operation]]
+//! op = "write-partition"
+//! partition = "storage"
+//! file = "storage.bin"
+//!
+//! [[operation]]
+//! op = "verify"
+//! address = 0x10000
+//! file = "app.bin"
+//!
+//! [[operation]]
+//! op = "reset"
+//! ```
+//!
+//! The `erase-partition` and `verify-partition` operations take a `partition`
+//! label in place of an address. Relative paths are relative to the directory
+//! containing the script.
+//!
+//! A `reset` operation can only be the last operation of a script. Without
+//! one, the device is reset according to `--after` once the script has run.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use esp_idf_part::PartitionTable;
+use log::{error, info};
+use md5::{Digest, Md5};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+
+use super::{config::Config, connect, print_board_info, ConnectArgs, EspflashProgress};
+use crate::{
+    error::Error,
+    flasher::{parse_partition_table, Flasher},
+};
+
+/// Run a sequence of operations over a single connection
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RunScriptArgs {
+    /// Script listing the operations to run, in TOML format
+    #[arg(value_name = "SCRIPT")]
+    pub script: PathBuf,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// A script of operations
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// Partition table used to find partitions by label
+    #[serde(default)]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[serde(default = "default_partition_table_offset")]
+    pub partition_table_offset: u32,
+    /// Operations to run, in order
+    #[serde(default, rename = "operation")]
+    pub operations: Vec<Operation>,
+}
+
+fn default_partition_table_offset() -> u32 {
+    0x8000
+}
+
+/// An operation on a connected target device
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Operation {
+    /// Erase a region of flash
+    EraseRegion { address: u32, size: u32 },
+    /// Erase a partition
+    ErasePartition { partition: String },
+    /// Write a binary file to flash
+    WriteBin { address: u32, file: PathBuf },
+    /// Write a binary file to a partition
+    WritePartition { partition: String, file: PathBuf },
+    /// Check that flash holds the contents of a file
+    Verify { address: u32, file: PathBuf },
+    /// Check that a partition starts with the contents of a file
+    VerifyPartition { partition: String, file: PathBuf },
+    /// Reset the device
+    Reset,
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::EraseRegion { address, size } => {
+                write!(f, "Erase {size:#x} bytes at {address:#x}")
+            }
+            Operation::ErasePartition { partition } => write!(f, "Erase partition `{partition}`"),
+            Operation::WriteBin { address, file } => {
+                write!(f, "Write '{}' at {address:#x}", file.display())
+            }
+            Operation::WritePartition { partition, file } => {
+                write!(f, "Write '{}' to partition `{partition}`", file.display())
+            }
+            Operation::Verify { address, file } => {
+                write!(f, "Verify '{}' at {address:#x}", file.display())
+            }
+            Operation::VerifyPartition { partition, file } => {
+                write!(f, "Verify '{}' in partition `{partition}`", file.display())
+            }
+            Operation::Reset => write!(f, "Reset"),
+        }
+    }
+}
+
+impl Script {
+    /// Load a script, resolving the paths it contains relative to its
+    /// directory
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+        let mut script = Self::parse(&data)
+            .wrap_err_with(|| format!("Failed to parse script {}", path.display()))?;
+
+        if let Some(dir) = path.parent() {
+            script.resolve_paths(dir);
+        }
+
+        Ok(script)
+    }
+
+    /// Parse a script
+    pub fn parse(data: &str) -> Result<Self> {
+        let script: Self = toml::from_str(data).into_diagnostic()?;
+
+        let reset = script
+            .operations
+            .iter()
+            .position(|op| *op == Operation::Reset);
+        if reset.is_some_and(|index| index + 1 != script.operations.len()) {
+            return Err(Error::ScriptResetNotLast.into());
+        }
+
+        Ok(script)
+    }
+
+    /// Make relative paths relative to the given directory
+    fn resolve_paths(&mut self, dir: &Path) {
+        let files = self.operations.iter_mut().filter_map(|op| match op {
+            Operation::WriteBin { file, .. }
+            | Operation::WritePartition { file, .. }
+            | Operation::Verify { file, .. }
+            | Operation::VerifyPartition { file, .. } => Some(file),
+            _ => None,
+        });
+
+        for path in files.chain(self.partition_table.as_mut()) {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+}
+
+/// Partition table of the device operations are run on, loaded when first
+/// needed
+#[derive(Debug)]
+pub struct Partitions {
+    path: Option<PathBuf>,
+    offset: u32,
+    table: Option<PartitionTable>,
+}
+
+impl Partitions {
+    /// Partition table read from a file, if provided, or from the device at
+    /// the given offset
+    pub fn new(path: Option<PathBuf>, offset: u32) -> Self {
+        Self {
+            path,
+            offset,
+            table: None,
+        }
+    }
+
+    /// Offset and size of the partition with the given label
+    pub(crate) fn find(&mut self, flasher: &mut Flasher, label: &str) -> Result<(u32, u32)> {
+        if self.table.is_none() {
+            let table = match &self.path {
+                Some(path) => parse_partition_table(path)?,
+                None => flasher.read_partition_table(self.offset)?,
+            };
+            self.table = Some(table);
+        }
+
+        let partition = self
+            .table
+            .as_ref()
+            .and_then(|table| table.find(label))
+            .ok_or_else(|| Error::PartitionNotFound(label.to_string()))?;
+
+        Ok((partition.offset(), partition.size()))
+    }
+}
+
+impl Operation {
+    /// Run the operation on a connected target device
+    pub fn run(&self, flasher: &mut Flasher, partitions: &mut Partitions) -> Result<()> {
+        match self {
+            Operation::EraseRegion { address, size } => flasher.erase_region(*address, *size)?,
+            Operation::ErasePartition { partition } => {
+                let (offset, size) = partitions.find(flasher, partition)?;
+                flasher.erase_region(offset, size)?;
+            }
+            Operation::WriteBin { address, file } => {
+                let data = read_file(file)?;
+                flasher.write_bin_to_flash(
+                    *address,
+                    &data,
+                    Some(&mut EspflashProgress::default()),
+                )?;
+            }
+            Operation::WritePartition { partition, file } => {
+                let (offset, part_size) = partitions.find(flasher, partition)?;
+                let data = read_file(file)?;
+                if data.len() as u32 > part_size {
+                    return Err(Error::PartitionFileTooBig {
+                        label: partition.clone(),
+                        size: data.len() as u32,
+                        part_size,
+                    }
+                    .into());
+                }
+
+                flasher.write_bin_to_flash(
+                    offset,
+                    &data,
+                    Some(&mut EspflashProgress::default()),
+                )?;
+            }
+            Operation::Verify { address, file } => verify(flasher, *address, file)?,
+            Operation::VerifyPartition { partition, file } => {
+                let (offset, _) = partitions.find(flasher, partition)?;
+                verify(flasher, offset, file)?;
+            }
+            Operation::Reset => flasher.connection().reset()?,
+        }
+
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+    Ok(data)
+}
+
+/// Compare the MD5 checksum of a region of flash with that of a file
+fn verify(flasher: &mut Flasher, address: u32, file: &Path) -> Result<()> {
+    let data = read_file(file)?;
+    let checksum = flasher.checksum_md5(address, data.len() as u32)?;
+
+    if checksum.to_be_bytes()[..] != Md5::digest(&data)[..] {
+        error!(
+            "The flash contents at {address:#x} do not match '{}'",
+            file.display()
+        );
+        return Err(Error::VerifyFailed.into());
+    }
+
+    Ok(())
+}
+
+/// Run the operations of a script over a single connection
+pub fn run_script(args: RunScriptArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let script = Script::load(&args.script)?;
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher)?;
+
+    let mut partitions = Partitions::new(script.partition_table, script.partition_table_offset);
+    let count = script.operations.len();
+    for (index, op) in script.operations.iter().enumerate() {
+        info!("[{}/{count}] {op}", index + 1);
+        op.run(&mut flasher, &mut partitions)
+            .wrap_err_with(|| format!("Operation {} failed: {op}", index + 1))?;
+    }
+
+    if script.operations.last() != Some(&Operation::Reset) {
+        let chip = flasher.chip();
+        flasher.connection().reset_after(true, chip)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_script() {
+        let mut script = Script::parse(
+            r#"
+            partition_table = "partitions.csv"
+
+            [[operation]]
+            op = "erase-region"
+            address = 0x9000
+            size = 0x6000
+
+            [[operation]]
+            op = "write-partition"
+            partition = "storage"
+            file = "storage.bin"
+
+            [[operation]]
+            op = "reset"
+            "#,
+        )
+        .unwrap();
+        script.resolve_paths(Path::new("/scripts"));
+
+        assert_eq!(
+            script.partition_table,
+            Some(PathBuf::from("/scripts/partitions.csv"))
+        );
+        assert_eq!(script.partition_table_offset, 0x8000);
+        assert_eq!(
+            script.operations,
+            [
+                Operation::EraseRegion {
+                    address: 0x9000,
+                    size: 0x6000
+                },
+                Operation::WritePartition {
+                    partition: "storage".into(),
+                    file: PathBuf::from("/scripts/storage.bin"),
+                },
+                Operation::Reset,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_reset_before_last_operation() {
+        let result = Script::parse(
+            r#"
+            [[operation]]
+            op = "reset"
+
+            [[operation]]
+            op = "erase-region"
+            address = 0x9000
+            size = 0x6000
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+}
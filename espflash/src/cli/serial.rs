@@ -17,6 +17,22 @@ use crate::{
 pub fn get_serial_port_info(
     matches: &ConnectArgs,
     config: &Config,
+) -> Result<SerialPortInfo, Error> {
+    find_port_info(
+        matches.port.as_deref(),
+        matches.list_all_ports,
+        matches.confirm_port,
+        config,
+    )
+}
+
+/// Return the information of the given serial port, or of the one configured
+/// or selected by the user if none is given
+pub fn find_port_info(
+    port: Option<&str>,
+    list_all_ports: bool,
+    confirm_port: bool,
+    config: &Config,
 ) -> Result<SerialPortInfo, Error> {
     // A serial port should be specified either as a command-line argument or in a
     // configuration file. In the case that both have been provided the command-line
@@ -33,14 +49,14 @@ pub fn get_serial_port_info(
     // doesn't work (on Windows) with "dummy" device paths like `COM4`. That's
     // the reason we need to handle Windows/Posix differently.
 
-    let ports = detect_usb_serial_ports(matches.list_all_ports).unwrap_or_default();
+    let ports = detect_usb_serial_ports(list_all_ports).unwrap_or_default();
 
-    if let Some(serial) = &matches.port {
+    if let Some(serial) = port {
         find_serial_port(&ports, serial)
     } else if let Some(serial) = &config.connection.serial {
         find_serial_port(&ports, serial)
     } else {
-        let (port, matches) = select_serial_port(ports, config, matches.confirm_port)?;
+        let (port, matches) = select_serial_port(ports, config, confirm_port)?;
 
         match &port.port_type {
             SerialPortType::UsbPort(usb_info) if !matches => {
@@ -204,6 +220,14 @@ const KNOWN_DEVICES: &[UsbDevice] = &[
         vid: 0x1a86,
         pid: 0x7523,
     }, // QinHeng Electronics CH340 serial converter
+    UsbDevice {
+        vid: 0x303a,
+        pid: 0x1001,
+    }, // Espressif USB-Serial-JTAG (ESP32-C3/C6/H2/P4/S3)
+    UsbDevice {
+        vid: 0x303a,
+        pid: 0x0002,
+    }, // Espressif USB-OTG CDC in download mode (ESP32-P4/S2/S3)
 ];
 
 /// Ask the user to select a serial port from a list of detected serial ports.
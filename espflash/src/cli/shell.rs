@@ -0,0 +1,281 @@
+//! Interactive shell for exploring a connected target device
+//!
+//! `espflash shell` connects to the target device once, then runs the commands
+//! entered one line at a time, which is much faster than reconnecting for
+//! every invocation of `espflash` while debugging. Regions are given either as
+//! an address and a size or as the label of a partition:
+//!
+//! ```text
+//! espflash> read 0x9000 0x100
+//! espflash> erase nvs
+//! espflash> md5 0x10000 0x100000
+//! espflash> reset
+//! ```
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result};
+
+use super::{
+    config::Config,
+    connect, parse_u32, parse_uint32, print_board_info,
+    script::{Operation, Partitions},
+    ConnectArgs,
+};
+use crate::{error::Error, flasher::Flasher};
+
+const HELP: &str = "\
+Commands:
+  read <REGION> [FILE]           Print the contents of a region, or save them to a file
+  md5 <REGION>                   Print the MD5 checksum of a region
+  erase <REGION>                 Erase a region
+  write <ADDRESS|LABEL> <FILE>   Write a file at an address or to a partition
+  verify <ADDRESS|LABEL> <FILE>  Check that flash holds the contents of a file
+  reset                          Reset the target device and exit
+  help                           Print this message
+  exit                           Exit, resetting the target device according to `--after`
+
+A <REGION> is either an address and a size, or the label of a partition.";
+
+/// Open an interactive shell on a connected target device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ShellArgs {
+    /// Partition table used to find partitions by label, read from the device
+    /// if not provided
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[arg(long, default_value = "0x8000", value_parser = parse_uint32)]
+    pub partition_table_offset: u32,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+}
+
+/// A region of flash
+#[derive(Debug, PartialEq, Eq)]
+enum Region {
+    Range { address: u32, size: u32 },
+    Partition(String),
+}
+
+/// A command entered in the shell
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Read {
+        region: Region,
+        file: Option<PathBuf>,
+    },
+    Md5(Region),
+    Operation(Operation),
+    Help,
+    Exit,
+}
+
+/// Parse a line entered in the shell, which may be empty
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return Ok(None);
+    };
+
+    let command = match name {
+        "read" => Command::Read {
+            region: region(&mut words)?,
+            file: words.next().map(PathBuf::from),
+        },
+        "md5" => Command::Md5(region(&mut words)?),
+        "erase" => Command::Operation(match region(&mut words)? {
+            Region::Range { address, size } => Operation::EraseRegion { address, size },
+            Region::Partition(partition) => Operation::ErasePartition { partition },
+        }),
+        "write" | "verify" => {
+            let target = word(&mut words, "an address or partition label")?;
+            let file = PathBuf::from(word(&mut words, "a file")?);
+
+            Command::Operation(match (name, parse_u32(target)) {
+                ("write", Ok(address)) => Operation::WriteBin { address, file },
+                ("write", Err(_)) => Operation::WritePartition {
+                    partition: target.to_string(),
+                    file,
+                },
+                (_, Ok(address)) => Operation::Verify { address, file },
+                (_, Err(_)) => Operation::VerifyPartition {
+                    partition: target.to_string(),
+                    file,
+                },
+            })
+        }
+        "reset" => Command::Operation(Operation::Reset),
+        "help" | "?" => Command::Help,
+        "exit" | "quit" => Command::Exit,
+        _ => return Err(format!("Unknown command `{name}`, type `help` for a list")),
+    };
+
+    match words.next() {
+        Some(extra) => Err(format!("Unexpected argument `{extra}`")),
+        None => Ok(Some(command)),
+    }
+}
+
+fn word<'a>(words: &mut impl Iterator<Item = &'a str>, what: &str) -> Result<&'a str, String> {
+    words.next().ok_or_else(|| format!("Expected {what}"))
+}
+
+fn region<'a>(words: &mut impl Iterator<Item = &'a str>) -> Result<Region, String> {
+    let first = word(words, "an address or partition label")?;
+
+    match parse_u32(first) {
+        Ok(address) => {
+            let size = word(words, "a size")?;
+            let size = parse_u32(size).map_err(|e| format!("Invalid size `{size}`: {e}"))?;
+
+            Ok(Region::Range { address, size })
+        }
+        Err(_) => Ok(Region::Partition(first.to_string())),
+    }
+}
+
+/// Run a command other than `help` and `exit`
+fn run_command(flasher: &mut Flasher, partitions: &mut Partitions, command: Command) -> Result<()> {
+    match command {
+        Command::Read { region, file } => {
+            let (address, size) = resolve(flasher, partitions, &region)?;
+            let data = flasher.read_flash_data(address, size, 0x1000, 64)?;
+
+            match file {
+                Some(file) => fs::write(&file, data).into_diagnostic()?,
+                None => print_hex_dump(address, &data),
+            }
+        }
+        Command::Md5(region) => {
+            let (address, size) = resolve(flasher, partitions, &region)?;
+            println!("{:032x}", flasher.checksum_md5(address, size)?);
+        }
+        Command::Operation(op) => op.run(flasher, partitions)?,
+        Command::Help | Command::Exit => {}
+    }
+
+    Ok(())
+}
+
+fn resolve(
+    flasher: &mut Flasher,
+    partitions: &mut Partitions,
+    region: &Region,
+) -> Result<(u32, u32)> {
+    match region {
+        Region::Range { address, size } => Ok((*address, *size)),
+        Region::Partition(label) => partitions.find(flasher, label),
+    }
+}
+
+fn print_hex_dump(address: u32, data: &[u8]) {
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let hex = chunk
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = chunk
+            .iter()
+            .map(|&byte| match byte {
+                b' '..=b'~' => byte as char,
+                _ => '.',
+            })
+            .collect::<String>();
+
+        println!("{:08x}  {hex:<47}  |{ascii}|", address + 16 * index as u32);
+    }
+}
+
+/// Connect to a target device and run the commands entered on stdin until
+/// `exit` or `reset` is entered, or stdin is closed
+pub fn shell(args: ShellArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher)?;
+    println!("Type `help` for a list of commands");
+
+    let mut partitions = Partitions::new(args.partition_table, args.partition_table_offset);
+    let mut stdin = io::stdin().lock();
+    let mut line = String::new();
+
+    loop {
+        print!("espflash> ");
+        io::stdout().flush().into_diagnostic()?;
+
+        line.clear();
+        if stdin.read_line(&mut line).into_diagnostic()? == 0 {
+            println!();
+            break;
+        }
+
+        match parse_command(&line) {
+            Ok(None) => {}
+            Ok(Some(Command::Help)) => println!("{HELP}"),
+            Ok(Some(Command::Exit)) => break,
+            Ok(Some(Command::Operation(Operation::Reset))) => {
+                flasher.connection().reset()?;
+                return Ok(());
+            }
+            Ok(Some(command)) => {
+                if let Err(err) = run_command(&mut flasher, &mut partitions, command) {
+                    eprintln!("{err:?}");
+                }
+            }
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+
+    let chip = flasher.chip();
+    flasher.connection().reset_after(true, chip)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("  \n"), Ok(None));
+        assert_eq!(
+            parse_command("read 0x9000 0x1000\n"),
+            Ok(Some(Command::Read {
+                region: Region::Range {
+                    address: 0x9000,
+                    size: 0x1000
+                },
+                file: None,
+            }))
+        );
+        assert_eq!(
+            parse_command("erase nvs"),
+            Ok(Some(Command::Operation(Operation::ErasePartition {
+                partition: "nvs".into()
+            })))
+        );
+        assert_eq!(
+            parse_command("write 0x10000 app.bin"),
+            Ok(Some(Command::Operation(Operation::WriteBin {
+                address: 0x10000,
+                file: PathBuf::from("app.bin"),
+            })))
+        );
+
+        assert!(parse_command("md5 0x10000").is_err());
+        assert!(parse_command("reset now").is_err());
+        assert!(parse_command("format").is_err());
+    }
+}
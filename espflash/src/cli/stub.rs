@@ -0,0 +1,321 @@
+//! Flash stub updates
+//!
+//! The flash stubs are embedded in espflash, however fixes to them are released
+//! by esp-flasher-stub more often than espflash itself is. `espflash stub
+//! update` downloads the stubs from a release into a cache directory, from
+//! which they are loaded in preference to the embedded copies.
+//!
+//! Each release lists the SHA-256 digests of its stubs in `SHA256SUMS`, which
+//! is signed using Ed25519 in `SHA256SUMS.sig`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose, Engine as _};
+use clap::{Args, Subcommand};
+use directories::ProjectDirs;
+use ed25519_dalek::{pkcs8::DecodePublicKey, Signature, VerifyingKey};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    error::Error,
+    flasher::stubs::{sha256_hex, stub_file_name, FlashStub, StubManifest, STUB_MANIFEST},
+    targets::Chip,
+};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/esp-rs/esp-flasher-stub/releases";
+const CHECKSUMS: &str = "SHA256SUMS";
+const SIGNATURE: &str = "SHA256SUMS.sig";
+
+/// Manage the flash stubs loaded onto the target device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct StubArgs {
+    #[command(subcommand)]
+    pub action: StubAction,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum StubAction {
+    /// Download the flash stubs from a release of esp-flasher-stub
+    ///
+    /// The downloaded stubs are used in preference to the ones embedded in
+    /// espflash, until they are removed using `stub clear`.
+    Update(StubUpdateArgs),
+    /// Print the release of the flash stubs in use
+    Status,
+    /// Remove the downloaded flash stubs, reverting to the embedded ones
+    Clear,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct StubUpdateArgs {
+    /// Tag of the release to download, rather than the latest release
+    #[arg(long, value_name = "TAG")]
+    pub tag: Option<String>,
+    /// Ed25519 public key in PEM format, used to verify the signature of the
+    /// release's digests
+    #[arg(long, value_name = "FILE")]
+    pub public_key: Option<PathBuf>,
+    /// Only check the digests listed in the release, without verifying their
+    /// signature
+    #[arg(long, conflicts_with = "public_key")]
+    pub insecure: bool,
+}
+
+/// A release, as described by the GitHub API
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Directory the flash stubs are downloaded to
+pub fn stub_cache_dir() -> PathBuf {
+    let project_dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
+    project_dirs.cache_dir().join("stubs")
+}
+
+/// Update, print or remove the downloaded flash stubs
+pub fn stub(args: StubArgs) -> Result<()> {
+    let dir = stub_cache_dir();
+
+    match args.action {
+        StubAction::Update(args) => update(&args, &dir),
+        StubAction::Status => status(&dir),
+        StubAction::Clear => {
+            if dir.exists() {
+                fs::remove_dir_all(&dir)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to remove {}", dir.display()))?;
+            }
+            info!("Using the flash stubs embedded in espflash");
+
+            Ok(())
+        }
+    }
+}
+
+fn update(args: &StubUpdateArgs, dir: &Path) -> Result<()> {
+    let public_key = match (&args.public_key, args.insecure) {
+        (Some(path), _) => Some(read_public_key(path)?),
+        (None, true) => {
+            warn!("The signature of the release will not be verified");
+            None
+        }
+        (None, false) => return Err(Error::StubPublicKeyRequired.into()),
+    };
+
+    let url = match &args.tag {
+        Some(tag) => format!("{RELEASES_URL}/tags/{tag}"),
+        None => format!("{RELEASES_URL}/latest"),
+    };
+    info!("Fetching release information from {url}");
+    let release: Release = get(&url)?
+        .into_json()
+        .map_err(|e| Error::StubDownload(e.to_string()))?;
+
+    let download = |name: &str| -> Result<Vec<u8>, Error> {
+        let asset = release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| {
+                Error::StubDownload(format!("release {} has no {name}", release.tag_name))
+            })?;
+
+        let mut data = Vec::new();
+        get(&asset.browser_download_url)?
+            .into_reader()
+            .read_to_end(&mut data)
+            .map_err(|e| Error::StubDownload(e.to_string()))?;
+
+        Ok(data)
+    };
+
+    let checksums = download(CHECKSUMS)?;
+    if let Some(public_key) = public_key {
+        verify_signature(&public_key, &checksums, &download(SIGNATURE)?)?;
+    }
+    let digests = parse_checksums(&String::from_utf8_lossy(&checksums));
+
+    let mut manifest = StubManifest {
+        version: release.tag_name.clone(),
+        files: HashMap::new(),
+    };
+    let mut stubs = Vec::new();
+
+    for chip in Chip::iter() {
+        let Some(name) = stub_file_name(chip) else {
+            continue;
+        };
+        if !release.assets.iter().any(|asset| asset.name == name) {
+            warn!("Release {} has no flash stub for {chip}", release.tag_name);
+            continue;
+        }
+
+        let data = download(name)?;
+        let digest = digests.get(name).ok_or_else(|| {
+            Error::InvalidFlashStub(format!("{name} is not listed in {CHECKSUMS}"))
+        })?;
+        if sha256_hex(&data) != *digest {
+            return Err(Error::InvalidFlashStub(format!("digest of {name} does not match")).into());
+        }
+        FlashStub::from_toml(&String::from_utf8_lossy(&data))?;
+
+        manifest.files.insert(name.to_string(), digest.clone());
+        stubs.push((name, data));
+    }
+
+    if stubs.is_empty() {
+        return Err(Error::StubDownload(format!(
+            "release {} contains no flash stubs",
+            release.tag_name
+        ))
+        .into());
+    }
+
+    // The manifest is written last, so that an interrupted update is detected
+    // and the embedded stubs are used instead
+    if dir.exists() {
+        fs::remove_dir_all(dir).into_diagnostic()?;
+    }
+    fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    for (name, data) in &stubs {
+        fs::write(dir.join(name), data).into_diagnostic()?;
+    }
+    fs::write(
+        dir.join(STUB_MANIFEST),
+        toml::to_string(&manifest).into_diagnostic()?,
+    )
+    .into_diagnostic()?;
+
+    info!(
+        "Downloaded {} flash stubs from release {}",
+        stubs.len(),
+        release.tag_name
+    );
+
+    Ok(())
+}
+
+fn status(dir: &Path) -> Result<()> {
+    if !dir.join(STUB_MANIFEST).exists() {
+        println!("Using the flash stubs embedded in espflash");
+        return Ok(());
+    }
+
+    let manifest = StubManifest::load(dir)?;
+    println!("Using flash stubs from release {}", manifest.version);
+
+    let mut files = manifest.files.keys().collect::<Vec<_>>();
+    files.sort();
+    for file in files {
+        println!("  {}", dir.join(file).display());
+    }
+
+    Ok(())
+}
+
+fn get(url: &str) -> Result<ureq::Response, Error> {
+    ureq::get(url)
+        .set(
+            "User-Agent",
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        )
+        .call()
+        .map_err(|e| Error::StubDownload(e.to_string()))
+}
+
+fn read_public_key(path: &Path) -> Result<VerifyingKey> {
+    let pem = fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    VerifyingKey::from_public_key_pem(&pem)
+        .map_err(|e| Error::StubSignature(format!("invalid public key: {e}")).into())
+}
+
+/// Verify the signature of the digests, which may be either raw or base64
+/// encoded
+fn verify_signature(public_key: &VerifyingKey, data: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let signature = match <[u8; 64]>::try_from(signature) {
+        Ok(signature) => signature,
+        Err(_) => general_purpose::STANDARD
+            .decode(String::from_utf8_lossy(signature).trim())
+            .ok()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or_else(|| Error::StubSignature("malformed signature".into()))?,
+    };
+
+    public_key
+        .verify_strict(data, &Signature::from_bytes(&signature))
+        .map_err(|e| Error::StubSignature(e.to_string()))
+}
+
+/// Parse the digests of the release's files, in the format produced by
+/// `sha256sum`
+fn parse_checksums(checksums: &str) -> HashMap<String, String> {
+    checksums
+        .lines()
+        .filter_map(|line| {
+            let (digest, name) = line.split_once(char::is_whitespace)?;
+            let name = name.trim_start().trim_start_matches('*');
+
+            Some((name.to_string(), digest.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    #[test]
+    fn parses_checksums() {
+        let digests = parse_checksums(
+            "0123ABCD  stub_flasher_32.toml\n\
+             4567cdef *stub_flasher_32c3.toml\n",
+        );
+
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests["stub_flasher_32.toml"], "0123abcd");
+        assert_eq!(digests["stub_flasher_32c3.toml"], "4567cdef");
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = signing_key.verifying_key();
+        let signature = signing_key.sign(b"digests").to_bytes();
+
+        assert!(verify_signature(&public_key, b"digests", &signature).is_ok());
+
+        let encoded = general_purpose::STANDARD.encode(signature);
+        assert!(verify_signature(&public_key, b"digests", encoded.as_bytes()).is_ok());
+
+        assert!(matches!(
+            verify_signature(&public_key, b"tampered", &signature),
+            Err(Error::StubSignature(_))
+        ));
+    }
+}
@@ -15,6 +15,7 @@ const MEM_END_TIMEOUT: Duration = Duration::from_millis(50);
 const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
 const FLASH_DEFLATE_END_TIMEOUT: Duration = Duration::from_secs(10);
 const FLASH_MD5_TIMEOUT: Duration = Duration::from_secs(8);
+const FLASH_MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
 
 /// Input data for SYNC command (36 bytes: 0x07 0x07 0x12 0x20, followed by 32 x 0x55)
 const SYNC_FRAME: [u8; 36] = [
@@ -60,6 +61,40 @@ pub enum CommandType {
     FlashEncryptedData = 0xD4,
     // Read SPI flash manufacturer and device id - Not part of the protocol
     FlashDetect = 0x9F,
+    // Read the SPI flash SFDP tables - Not part of the protocol
+    ReadSfdp = 0x5A,
+}
+
+impl From<u8> for CommandType {
+    fn from(opcode: u8) -> Self {
+        match opcode {
+            0x02 => CommandType::FlashBegin,
+            0x03 => CommandType::FlashData,
+            0x04 => CommandType::FlashEnd,
+            0x05 => CommandType::MemBegin,
+            0x06 => CommandType::MemEnd,
+            0x07 => CommandType::MemData,
+            0x08 => CommandType::Sync,
+            0x09 => CommandType::WriteReg,
+            0x0A => CommandType::ReadReg,
+            0x0B => CommandType::SpiSetParams,
+            0x0D => CommandType::SpiAttach,
+            0x0F => CommandType::ChangeBaudrate,
+            0x10 => CommandType::FlashDeflBegin,
+            0x11 => CommandType::FlashDeflData,
+            0x12 => CommandType::FlashDeflEnd,
+            0x13 => CommandType::FlashMd5,
+            0x14 => CommandType::GetSecurityInfo,
+            0xD0 => CommandType::EraseFlash,
+            0xD1 => CommandType::EraseRegion,
+            0xD2 => CommandType::ReadFlash,
+            0xD3 => CommandType::RunUserCode,
+            0xD4 => CommandType::FlashEncryptedData,
+            0x9F => CommandType::FlashDetect,
+            0x5A => CommandType::ReadSfdp,
+            _ => CommandType::Unknown,
+        }
+    }
 }
 
 impl CommandType {
@@ -76,21 +111,27 @@ impl CommandType {
     }
 
     /// Return a timeout based on the size
+    ///
+    /// The timeout is scaled by the amount of data the command operates on,
+    /// and is never shorter than the command's default timeout, so commands
+    /// operating on small amounts of data still fail quickly.
     pub fn timeout_for_size(&self, size: u32) -> Duration {
-        fn calc_timeout(timeout_per_mb: Duration, size: u32) -> Duration {
+        let calc_timeout = |timeout_per_mb: Duration| {
             let mb = size as f64 / 1_000_000.0;
             std::cmp::max(
-                FLASH_DEFLATE_END_TIMEOUT,
+                self.timeout(),
                 Duration::from_millis((timeout_per_mb.as_millis() as f64 * mb) as u64),
             )
-        }
+        };
         match self {
-            CommandType::FlashBegin | CommandType::FlashDeflBegin | CommandType::EraseRegion => {
-                calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
-            }
+            CommandType::FlashBegin
+            | CommandType::FlashDeflBegin
+            | CommandType::EraseRegion
+            | CommandType::EraseFlash => calc_timeout(ERASE_REGION_TIMEOUT_PER_MB),
             CommandType::FlashData | CommandType::FlashDeflData => {
-                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
+                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB)
             }
+            CommandType::FlashMd5 => calc_timeout(FLASH_MD5_TIMEOUT_PER_MB),
             _ => self.timeout(),
         }
     }
@@ -0,0 +1,385 @@
+//! Fault injection for robustness testing
+//!
+//! A [ChaosPort] wraps the serial port connected to a target device, and
+//! randomly drops, corrupts and delays the data passing through it, so that
+//! the recovery of the serial protocol from a poor connection can be
+//! exercised. The faults are drawn from a seeded pseudo-random generator, so
+//! that a failing run can be reproduced.
+//!
+//! ```rust,no_run
+//! use espflash::connection::{
+//!     chaos::{ChaosConfig, ChaosPort},
+//!     Port,
+//! };
+//!
+//! # fn example(port: Port) {
+//! let config: ChaosConfig = "drop=0.001,corrupt=0.001,seed=42".parse().unwrap();
+//! let port: Port = Box::new(ChaosPort::new(port, config));
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::trace;
+
+use super::{transport::FlasherTransport, Port};
+use crate::error::Error;
+
+/// Faults to inject into the data passing through a [ChaosPort]
+///
+/// Parsed from a comma-separated list of `KEY=VALUE` settings, such as
+/// `drop=0.01,corrupt=0.001,delay=0.05,max-delay=20,seed=42`:
+///
+/// | Key         | Value                                                 |
+/// | ----------- | ----------------------------------------------------- |
+/// | `drop`      | probability of dropping each byte                     |
+/// | `corrupt`   | probability of flipping a bit of each byte            |
+/// | `delay`     | probability of delaying each read and write           |
+/// | `max-delay` | longest delay, in milliseconds (50 by default)        |
+/// | `seed`      | seed of the fault generator, random if not provided   |
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct ChaosConfig {
+    /// Probability of dropping each byte
+    pub drop: f64,
+    /// Probability of flipping a bit of each byte
+    pub corrupt: f64,
+    /// Probability of delaying each read and write
+    pub delay: f64,
+    /// Longest delay of a read or write
+    pub max_delay: Duration,
+    /// Seed of the fault generator, random if not provided
+    pub seed: Option<u64>,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            corrupt: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(50),
+            seed: None,
+        }
+    }
+}
+
+impl ChaosConfig {
+    /// Set the probability of dropping each byte
+    pub fn with_drop(mut self, probability: f64) -> Self {
+        self.drop = probability;
+        self
+    }
+
+    /// Set the probability of flipping a bit of each byte
+    pub fn with_corrupt(mut self, probability: f64) -> Self {
+        self.corrupt = probability;
+        self
+    }
+
+    /// Set the probability and the longest duration of delaying each read and
+    /// write
+    pub fn with_delay(mut self, probability: f64, max_delay: Duration) -> Self {
+        self.delay = probability;
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the seed of the fault generator
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl FromStr for ChaosConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidChaosConfig(s.to_string());
+        let probability = |value: &str| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(invalid)
+        };
+
+        let mut config = Self::default();
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+
+            match key.trim() {
+                "drop" => config.drop = probability(value)?,
+                "corrupt" => config.corrupt = probability(value)?,
+                "delay" => config.delay = probability(value)?,
+                "max-delay" => {
+                    config.max_delay = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "seed" => config.seed = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ChaosConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drop={},corrupt={},delay={},max-delay={}",
+            self.drop,
+            self.corrupt,
+            self.delay,
+            self.max_delay.as_millis()
+        )?;
+        if let Some(seed) = self.seed {
+            write!(f, ",seed={seed}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A serial port which injects faults into the data passing through it
+pub struct ChaosPort {
+    inner: Port,
+    config: ChaosConfig,
+    rng: XorShift,
+}
+
+impl ChaosPort {
+    /// Wrap a serial port, injecting the given faults
+    ///
+    /// When no seed is provided, one is derived from the current time and
+    /// recorded in the [configuration](Self::config), so that the faults can be
+    /// reproduced.
+    pub fn new(port: impl FlasherTransport + 'static, mut config: ChaosConfig) -> Self {
+        let seed = *config.seed.get_or_insert_with(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+
+        Self {
+            inner: Box::new(port),
+            config,
+            rng: XorShift::new(seed),
+        }
+    }
+
+    /// The configuration of the injected faults
+    pub fn config(&self) -> &ChaosConfig {
+        &self.config
+    }
+
+    /// The wrapped serial port
+    pub fn inner(&self) -> &Port {
+        &self.inner
+    }
+
+    /// Unwrap the serial port, no longer injecting faults
+    pub fn into_inner(self) -> Port {
+        self.inner
+    }
+
+    /// Sleep for a random duration, if a delay is drawn
+    fn maybe_delay(&mut self) {
+        if self.rng.chance(self.config.delay) {
+            let max = self.config.max_delay.as_millis() as u64;
+            let delay = Duration::from_millis(self.rng.next() % (max + 1));
+            trace!("Delaying serial port by {delay:?}");
+            sleep(delay);
+        }
+    }
+
+    /// Drop and corrupt bytes of the given data, returning the bytes kept
+    fn mangle(&mut self, data: &[u8]) -> Vec<u8> {
+        data.iter()
+            .filter_map(|&byte| {
+                if self.rng.chance(self.config.drop) {
+                    trace!("Dropping byte {byte:#04x}");
+                    return None;
+                }
+
+                if self.rng.chance(self.config.corrupt) {
+                    let corrupted = byte ^ (1 << (self.rng.next() % 8));
+                    trace!("Corrupting byte {byte:#04x} to {corrupted:#04x}");
+                    return Some(corrupted);
+                }
+
+                Some(byte)
+            })
+            .collect()
+    }
+}
+
+impl Read for ChaosPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.maybe_delay();
+
+        // Dropping every byte read must not be mistaken for the end of the
+        // stream, so read again until the port times out
+        loop {
+            let len = self.inner.read(buf)?;
+            if len == 0 {
+                return Ok(0);
+            }
+
+            let kept = self.mangle(&buf[..len]);
+            if !kept.is_empty() {
+                buf[..kept.len()].copy_from_slice(&kept);
+                return Ok(kept.len());
+            }
+        }
+    }
+}
+
+impl Write for ChaosPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.maybe_delay();
+
+        let kept = self.mangle(buf);
+        self.inner.write_all(&kept)?;
+
+        // Dropped bytes are reported as written, as they would be by a serial
+        // port losing them on the wire
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl FlasherTransport for ChaosPort {
+    fn name(&self) -> Option<String> {
+        self.inner.name()
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        self.inner.baud_rate()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        self.inner.set_rts(level)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        self.inner.set_dtr(level)
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        self.inner.can_change_baud_rate()
+    }
+
+    fn has_control_lines(&self) -> bool {
+        self.inner.has_control_lines()
+    }
+
+    fn set_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        self.inner.set_flow_control(enabled)
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        self.inner.bytes_to_read()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.inner.clear_input()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::fd::RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+/// Small, fast pseudo-random generator for drawing faults, which is not
+/// suitable for anything else
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero
+        Self((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+
+        x
+    }
+
+    /// Draw an event with the given probability
+    fn chance(&mut self, probability: f64) -> bool {
+        // The top 53 bits form a uniformly distributed float in [0, 1)
+        let sample = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+
+        probability > 0.0 && sample < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config() {
+        let config: ChaosConfig = "drop=0.01, corrupt=0.5,delay=1,max-delay=20,seed=42"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config,
+            ChaosConfig::default()
+                .with_drop(0.01)
+                .with_corrupt(0.5)
+                .with_delay(1.0, Duration::from_millis(20))
+                .with_seed(42)
+        );
+        assert_eq!(config.to_string().parse::<ChaosConfig>().unwrap(), config);
+
+        assert!("drop=2".parse::<ChaosConfig>().is_err());
+        assert!("jitter=0.1".parse::<ChaosConfig>().is_err());
+        assert!("seed".parse::<ChaosConfig>().is_err());
+    }
+
+    #[test]
+    fn draws_faults_deterministically() {
+        let mut a = XorShift::new(7);
+        let mut b = XorShift::new(7);
+        assert!((0..100).all(|_| a.next() == b.next()));
+
+        let mut rng = XorShift::new(0);
+        assert!((0..100).all(|_| !rng.chance(0.0)));
+        assert!((0..100).all(|_| rng.chance(1.0)));
+
+        let hits = (0..10_000).filter(|_| rng.chance(0.1)).count();
+        assert!((800..1200).contains(&hits));
+    }
+}
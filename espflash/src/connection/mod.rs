@@ -8,12 +8,12 @@ use std::{
     io::{BufWriter, Read, Write},
     iter::zip,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use regex::Regex;
-use serialport::{SerialPort, UsbPortInfo};
+use serialport::{FlowControl, SerialPortType, UsbPortInfo};
 use slip_codec::SlipDecoder;
 
 #[cfg(unix)]
@@ -21,26 +21,48 @@ use self::reset::UnixTightReset;
 use self::{
     encoder::SlipEncoder,
     reset::{
-        construct_reset_strategy_sequence, hard_reset, reset_after_flash, ClassicReset,
-        ResetAfterOperation, ResetBeforeOperation, ResetStrategy, UsbJtagSerialReset,
+        construct_reset_strategy_sequence, hard_reset, reset_after_flash, watchdog_reset,
+        ClassicReset, ResetAfterOperation, ResetBeforeOperation, ResetStrategy, UsbJtagSerialReset,
     },
+    trace::{Direction, Tracer},
+    transport::FlasherTransport,
 };
 use crate::{
     command::{Command, CommandType},
     connection::reset::soft_reset,
     error::{ConnectionError, Error, ResultExt, RomError, RomErrorKind},
+    flasher::CancellationToken,
+    targets::Chip,
 };
 
+pub mod chaos;
 pub mod reset;
+pub mod rfc2217;
+pub mod tcp;
+pub mod trace;
+pub mod transport;
 
 const MAX_CONNECT_ATTEMPTS: usize = 7;
 const MAX_SYNC_ATTEMPTS: usize = 5;
 pub(crate) const USB_SERIAL_JTAG_PID: u16 = 0x1001;
+/// USB PID of the USB-OTG CDC console of the ESP32-S2 and ESP32-S3 ROM
+pub(crate) const USB_OTG_PID: u16 = 0x0002;
+const ESPRESSIF_VID: u16 = 0x303a;
+/// Time allowed for a USB-OTG device to re-enumerate after being reset
+const REENUMERATION_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[cfg(unix)]
-pub type Port = serialport::TTYPort;
+pub type NativePort = serialport::TTYPort;
 #[cfg(windows)]
-pub type Port = serialport::COMPort;
+pub type NativePort = serialport::COMPort;
+
+/// A serial port connected to a target device
+///
+/// This is usually a serial port attached to the host, but may be any
+/// [FlasherTransport], such as a port shared over the network by an espflash
+/// daemon, an RFC 2217 server or a TCP bridge, one connected to an emulated
+/// device for testing, or one injecting faults into another.
+pub type Port = Box<dyn FlasherTransport>;
 
 #[derive(Debug, Clone)]
 pub enum CommandResponseValue {
@@ -103,6 +125,9 @@ pub struct Connection {
     decoder: SlipDecoder,
     after_operation: ResetAfterOperation,
     before_operation: ResetBeforeOperation,
+    tracer: Option<Tracer>,
+    flow_control: bool,
+    cancellation: Option<CancellationToken>,
 }
 
 impl Connection {
@@ -118,9 +143,62 @@ impl Connection {
             decoder: SlipDecoder::new(),
             after_operation,
             before_operation,
+            tracer: None,
+            flow_control: false,
+            cancellation: None,
+        }
+    }
+
+    /// Name of the serial port, if known
+    pub fn port_name(&self) -> Option<String> {
+        self.serial.name()
+    }
+
+    /// Log every frame sent to and received from the target device
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Check the given token between the blocks of long-running operations
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = Some(token);
+    }
+
+    /// Can long-running operations be cancelled?
+    pub(crate) fn is_cancellable(&self) -> bool {
+        self.cancellation.is_some()
+    }
+
+    /// Fail with [Error::Cancelled] if the operation has been cancelled
+    pub(crate) fn check_cancelled(&self) -> Result<(), Error> {
+        match &self.cancellation {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
         }
     }
 
+    /// Enable or disable RTS/CTS hardware flow control
+    ///
+    /// As the reset sequences are driven using the RTS line, flow control is
+    /// automatically disabled again before the device is reset.
+    pub fn set_hardware_flow_control(&mut self, enabled: bool) -> Result<(), Error> {
+        self.serial.set_flow_control(enabled)?;
+        self.flow_control = enabled;
+
+        Ok(())
+    }
+
+    /// Disable hardware flow control, if it has been enabled, so that the RTS
+    /// line can be used for resetting the device
+    fn release_flow_control(&mut self) -> Result<(), Error> {
+        if self.flow_control {
+            debug!("Disabling hardware flow control");
+            self.set_hardware_flow_control(false)?;
+        }
+
+        Ok(())
+    }
+
     /// Initialize a connection with a device
     pub fn begin(&mut self) -> Result<(), Error> {
         let port_name = self.serial.name().unwrap_or_default();
@@ -137,6 +215,12 @@ impl Connection {
                 }
                 Err(e) => {
                     debug!("Failed to reset, error {:#?}, retrying", e);
+
+                    // The port disappears if the device resets at an unexpected
+                    // time, so it must be reopened before trying again
+                    if self.is_usb_otg() && is_disconnect(&e) {
+                        self.wait_for_reenumeration()?;
+                    }
                 }
             }
         }
@@ -156,9 +240,11 @@ impl Connection {
         let mut boot_mode = String::new();
         let mut boot_log_detected = false;
         let mut buff: Vec<u8>;
-        if self.before_operation != ResetBeforeOperation::NoReset {
+        // Without control lines, the device must already be in download mode
+        if self.before_operation != ResetBeforeOperation::NoReset && self.serial.has_control_lines()
+        {
             // Reset the chip to bootloader (download mode)
-            reset_strategy.reset(&mut self.serial)?;
+            self.reset_with(|serial| reset_strategy.reset(serial))?;
 
             let available_bytes = self.serial.bytes_to_read()?;
             buff = vec![0; available_bytes as usize];
@@ -249,16 +335,22 @@ impl Connection {
 
     // Reset the device
     pub fn reset(&mut self) -> Result<(), Error> {
+        self.release_flow_control()?;
         reset_after_flash(&mut self.serial, self.port_info.pid)?;
 
         Ok(())
     }
 
     // Reset the device taking into account the reset after argument
-    pub fn reset_after(&mut self, is_stub: bool) -> Result<(), Error> {
+    pub fn reset_after(&mut self, is_stub: bool, chip: Chip) -> Result<(), Error> {
         let pid = self.get_usb_pid()?;
+        self.release_flow_control()?;
 
         match self.after_operation {
+            ResetAfterOperation::HardReset if !self.serial.has_control_lines() => {
+                info!("Soft resetting, as the serial port has no control lines");
+                soft_reset(self, false, is_stub)
+            }
             ResetAfterOperation::HardReset => hard_reset(&mut self.serial, pid),
             ResetAfterOperation::NoReset => {
                 info!("Staying in bootloader");
@@ -270,13 +362,29 @@ impl Connection {
                 info!("Staying in flasher stub");
                 Ok(())
             }
+            ResetAfterOperation::StayInBootloader => {
+                info!("Resetting into the ROM bootloader");
+                self.reset_to_flash(false)
+            }
+            ResetAfterOperation::SoftReset => {
+                info!("Soft resetting");
+                soft_reset(self, false, is_stub)
+            }
+            ResetAfterOperation::WatchdogReset => {
+                info!("Resetting using the RTC watchdog");
+                watchdog_reset(self, chip)
+            }
         }
     }
 
     // Reset the device to flash mode
     pub fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
+        self.release_flow_control()?;
+
         if self.port_info.pid == USB_SERIAL_JTAG_PID {
             UsbJtagSerialReset.reset(&mut self.serial)
+        } else if self.is_usb_otg() {
+            self.reset_with(|serial| ClassicReset::new(extra_delay).reset(serial))
         } else {
             #[cfg(unix)]
             if UnixTightReset::new(extra_delay)
@@ -290,6 +398,123 @@ impl Connection {
         }
     }
 
+    /// Is the device connected using the USB-OTG console of the ESP32-S2 or
+    /// ESP32-S3?
+    ///
+    /// Unlike USB-Serial-JTAG, the USB-OTG peripheral is reset along with the
+    /// rest of the chip, so the device drops off the bus and re-enumerates
+    /// every time it is reset.
+    fn is_usb_otg(&self) -> bool {
+        self.serial.is_native()
+            && self.port_info.vid == ESPRESSIF_VID
+            && self.port_info.pid == USB_OTG_PID
+    }
+
+    /// Reset the device using the given strategy, reopening the serial port
+    /// once the device has re-enumerated if it is connected using USB-OTG
+    fn reset_with<F>(&mut self, reset: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Port) -> Result<(), Error>,
+    {
+        if !self.is_usb_otg() {
+            return reset(&mut self.serial);
+        }
+
+        // The device may disappear part way through the reset sequence, in which
+        // case the remaining steps fail
+        if let Err(e) = reset(&mut self.serial) {
+            if !is_disconnect(&e) {
+                return Err(e);
+            }
+            debug!("Device disconnected during reset: {e:?}");
+        }
+
+        self.wait_for_reenumeration()
+    }
+
+    /// Wait for a USB-OTG device to re-enumerate after being reset, and reopen
+    /// its serial port
+    ///
+    /// The port is matched by its serial number, as the operating system may
+    /// assign it a different name.
+    fn wait_for_reenumeration(&mut self) -> Result<(), Error> {
+        let baud = self.serial.baud_rate()?;
+        let timeout = self.serial.timeout();
+        let old_name = self.serial.name();
+
+        info!("Waiting for the USB device to re-enumerate...");
+
+        // Give the device time to drop off the bus, so that the old port is not
+        // mistaken for the new one
+        sleep(Duration::from_millis(500));
+
+        let deadline = Instant::now() + REENUMERATION_TIMEOUT;
+        loop {
+            if let Some((name, port_info)) = self.find_reenumerated_port(old_name.as_deref()) {
+                match serialport::new(&name, baud)
+                    .flow_control(FlowControl::None)
+                    .timeout(timeout)
+                    .open_native()
+                {
+                    Ok(serial) => {
+                        if old_name.as_deref() != Some(name.as_str()) {
+                            warn!("The serial port has changed to '{name}'");
+                        }
+                        debug!("Reopened serial port '{name}'");
+
+                        self.serial = Box::new(serial);
+                        self.port_info = port_info;
+                        self.flow_control = false;
+                        self.decoder = SlipDecoder::new();
+
+                        return Ok(());
+                    }
+                    // The port may be listed before it can be opened
+                    Err(e) => debug!("Failed to reopen serial port '{name}': {e}"),
+                }
+            }
+
+            if Instant::now() > deadline {
+                return Err(Error::Connection(ConnectionError::ReenumerationTimeout));
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Find the serial port of the device, preferring its previous name
+    fn find_reenumerated_port(&self, old_name: Option<&str>) -> Option<(String, UsbPortInfo)> {
+        let mut ports = serialport::available_ports()
+            .ok()?
+            .into_iter()
+            .filter_map(|port| match port.port_type {
+                SerialPortType::UsbPort(info)
+                    if info.vid == self.port_info.vid
+                        && match &self.port_info.serial_number {
+                            Some(serial_number) => {
+                                info.serial_number.as_ref() == Some(serial_number)
+                            }
+                            None => info.pid == self.port_info.pid,
+                        } =>
+                {
+                    Some((port.port_name, info))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // macOS lists each device as both a call-out (cu) and a dial-in (tty)
+        // port, of which the call-out port is used
+        ports.sort_by_key(|(name, _)| {
+            (
+                Some(name.as_str()) != old_name,
+                name.starts_with("/dev/tty."),
+            )
+        });
+
+        ports.into_iter().next()
+    }
+
     /// Set timeout for the serial port
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
         self.serial.set_timeout(timeout)?;
@@ -308,18 +533,18 @@ impl Connection {
         Ok(self.serial.baud_rate()?)
     }
 
+    /// Can the baud rate of the serial port be changed?
+    pub fn can_change_baud(&self) -> bool {
+        self.serial.can_change_baud_rate()
+    }
+
     /// Run a command with a timeout defined by the command type
     pub fn with_timeout<T, F>(&mut self, timeout: Duration, mut f: F) -> Result<T, Error>
     where
         F: FnMut(&mut Connection) -> Result<T, Error>,
     {
-        let old_timeout = {
-            let mut binding = Box::new(&mut self.serial);
-            let serial = binding.as_mut();
-            let old_timeout = serial.timeout();
-            serial.set_timeout(timeout)?;
-            old_timeout
-        };
+        let old_timeout = self.serial.timeout();
+        self.serial.set_timeout(timeout)?;
 
         let result = f(self);
 
@@ -388,10 +613,12 @@ impl Connection {
 
     /// Write raw data to the serial port
     pub fn write_raw(&mut self, data: u32) -> Result<(), Error> {
-        let mut binding = Box::new(&mut self.serial);
-        let serial = binding.as_mut();
-        serial.clear(serialport::ClearBuffer::Input)?;
-        let mut writer = BufWriter::new(serial);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.raw(Direction::Write, &data.to_le_bytes());
+        }
+
+        self.serial.clear_input()?;
+        let mut writer = BufWriter::new(&mut self.serial);
         let mut encoder = SlipEncoder::new(&mut writer)?;
         encoder.write_all(&data.to_le_bytes())?;
         encoder.finish()?;
@@ -402,13 +629,17 @@ impl Connection {
     /// Write a command to the serial port
     pub fn write_command(&mut self, command: Command) -> Result<(), Error> {
         debug!("Writing command: {:?}", command);
-        let mut binding = Box::new(&mut self.serial);
-        let serial = binding.as_mut();
 
-        serial.clear(serialport::ClearBuffer::Input)?;
-        let mut writer = BufWriter::new(serial);
+        let mut frame = Vec::new();
+        command.write(&mut frame)?;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.frame(Direction::Write, &frame);
+        }
+
+        self.serial.clear_input()?;
+        let mut writer = BufWriter::new(&mut self.serial);
         let mut encoder = SlipEncoder::new(&mut writer)?;
-        command.write(&mut encoder)?;
+        encoder.write_all(&frame)?;
         encoder.finish()?;
         writer.flush()?;
         Ok(())
@@ -466,6 +697,10 @@ impl Connection {
         loop {
             self.decoder.decode(&mut self.serial, &mut tmp)?;
             if tmp.len() >= len {
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.frame(Direction::Read, &tmp);
+                }
+
                 return Ok(Some(tmp));
             }
         }
@@ -478,7 +713,10 @@ impl Connection {
     }
 
     /// Turn a serial port into a [Port]
-    pub fn into_serial(self) -> Port {
+    pub fn into_serial(mut self) -> Port {
+        // Whoever takes over the port may want to reset the device, so make sure
+        // the RTS line is not left under the control of the UART.
+        self.release_flow_control().ok();
         self.serial
     }
 
@@ -488,6 +726,15 @@ impl Connection {
     }
 }
 
+/// Did the error occur because the device dropped off the bus?
+fn is_disconnect(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Connection(ConnectionError::DeviceNotFound | ConnectionError::Serial(_))
+            | Error::Flashing(ConnectionError::DeviceNotFound | ConnectionError::Serial(_))
+    )
+}
+
 mod encoder {
     use std::io::Write;
 
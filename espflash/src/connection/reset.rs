@@ -1,11 +1,8 @@
 //! Most of this module is copied from `esptool.py` (https://github.com/espressif/esptool/blob/a8586d02b1305ebc687d31783437a7f4d4dbb70f/esptool/reset.py)
 
-#[cfg(unix)]
-use std::{io, os::fd::AsRawFd};
-use std::{thread::sleep, time::Duration};
+use std::{io, thread::sleep, time::Duration};
 
 use log::debug;
-use serialport::SerialPort;
 use strum::{Display, EnumIter, EnumString, VariantNames};
 
 #[cfg(unix)]
@@ -13,28 +10,35 @@ use libc::ioctl;
 
 use crate::{
     command::{Command, CommandType},
-    connection::{Connection, Port, USB_SERIAL_JTAG_PID},
+    connection::{transport::FlasherTransport, Connection, Port, USB_SERIAL_JTAG_PID},
     error::Error,
     flasher::FLASH_WRITE_SIZE,
+    targets::Chip,
 };
 
 /// Default time to wait before releasing the boot pin after a reset
 const DEFAULT_RESET_DELAY: u64 = 50; // ms
 /// Amount of time to wait if the default reset delay does not work
 const EXTRA_RESET_DELAY: u64 = 500; // ms
+/// Key which must be written to the RTC watchdog's write protect register
+/// before its configuration can be changed
+const RTC_WDT_WKEY: u32 = 0x50D8_3AA1;
+/// Maximum amount of time to wait for a USB device to be re-enumerated
+#[cfg(target_os = "linux")]
+const USB_ENUMERATION_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Some strategy for resting a target device
 pub trait ResetStrategy {
     fn reset(&self, serial_port: &mut Port) -> Result<(), Error>;
 
     fn set_dtr(&self, serial_port: &mut Port, level: bool) -> Result<(), Error> {
-        serial_port.write_data_terminal_ready(level)?;
+        serial_port.set_dtr(level)?;
 
         Ok(())
     }
 
     fn set_rts(&self, serial_port: &mut Port, level: bool) -> Result<(), Error> {
-        serial_port.write_request_to_send(level)?;
+        serial_port.set_rts(level)?;
 
         Ok(())
     }
@@ -46,7 +50,15 @@ pub trait ResetStrategy {
         dtr_level: bool,
         rts_level: bool,
     ) -> Result<(), Error> {
-        let fd = serial_port.as_raw_fd();
+        let Some(fd) = serial_port.raw_fd() else {
+            // The lines of a remote serial port can't be set simultaneously, so
+            // fall back to setting them one after the other.
+            self.set_dtr(serial_port, dtr_level)?;
+            self.set_rts(serial_port, rts_level)?;
+
+            return Ok(());
+        };
+
         let mut status: i32 = 0;
         match unsafe { ioctl(fd, libc::TIOCMGET, &status) } {
             0 => (),
@@ -199,27 +211,27 @@ impl ResetStrategy for UsbJtagSerialReset {
 }
 
 /// Reset the target device
-pub fn reset_after_flash(serial: &mut Port, pid: u16) -> Result<(), serialport::Error> {
+pub fn reset_after_flash(serial: &mut Port, pid: u16) -> io::Result<()> {
     sleep(Duration::from_millis(100));
 
     if pid == USB_SERIAL_JTAG_PID {
-        serial.write_data_terminal_ready(false)?;
+        serial.set_dtr(false)?;
 
         sleep(Duration::from_millis(100));
 
-        serial.write_request_to_send(true)?;
-        serial.write_data_terminal_ready(false)?;
-        serial.write_request_to_send(true)?;
+        serial.set_rts(true)?;
+        serial.set_dtr(false)?;
+        serial.set_rts(true)?;
 
         sleep(Duration::from_millis(100));
 
-        serial.write_request_to_send(false)?;
+        serial.set_rts(false)?;
     } else {
-        serial.write_request_to_send(true)?;
+        serial.set_rts(true)?;
 
         sleep(Duration::from_millis(100));
 
-        serial.write_request_to_send(false)?;
+        serial.set_rts(false)?;
     }
 
     Ok(())
@@ -293,6 +305,104 @@ pub fn soft_reset(
     Ok(())
 }
 
+/// Reset the chip by triggering the RTC watchdog timer
+pub fn watchdog_reset(connection: &mut Connection, chip: Chip) -> Result<(), Error> {
+    debug!("Using WatchdogReset reset strategy");
+
+    let regs = chip
+        .into_target()
+        .rtc_wdt_registers()
+        .ok_or_else(|| Error::UnsupportedFeature {
+            chip,
+            feature: "watchdog reset".into(),
+        })?;
+
+    // Unlock the watchdog configuration registers, set a short timeout for the
+    // first stage, enable the watchdog with a system reset on expiry, and then
+    // lock the registers again.
+    connection.write_reg(regs.write_protect(), RTC_WDT_WKEY, None)?;
+    connection.write_reg(regs.config1(), 2000, None)?;
+    connection.write_reg(regs.config0(), (1 << 31) | (5 << 28) | (1 << 8) | 2, None)?;
+    connection.write_reg(regs.write_protect(), 0, None)?;
+
+    Ok(())
+}
+
+/// Re-enumerate the USB device providing the given serial port
+///
+/// This toggles the device's `authorized` attribute in sysfs, which has the same
+/// effect as unplugging and re-plugging the device, and then waits for the
+/// serial port to disappear and re-appear. Writing to the attribute usually
+/// requires root privileges, or a suitable udev rule.
+#[cfg(target_os = "linux")]
+pub fn usb_port_reset(port_name: &str) -> Result<(), Error> {
+    use std::{fs, path::Path, time::Instant};
+
+    debug!("Using UsbPortReset reset strategy");
+
+    let failed = |reason: String| Error::UsbPortReset(reason);
+
+    let tty = Path::new(port_name)
+        .file_name()
+        .ok_or_else(|| failed(format!("'{port_name}' is not a serial device")))?;
+    let sysfs_path = fs::canonicalize(Path::new("/sys/class/tty").join(tty).join("device"))
+        .map_err(|e| failed(format!("'{port_name}' is not a USB serial device ({e})")))?;
+
+    // Depending on the driver, the tty's device is either the USB interface or
+    // a child of it; the USB device itself is the closest ancestor describing a
+    // vendor ID.
+    let device = sysfs_path
+        .ancestors()
+        .find(|path| path.join("idVendor").exists())
+        .ok_or_else(|| failed(format!("'{port_name}' is not a USB serial device")))?;
+    let authorized = device.join("authorized");
+
+    // Wait until the serial port exists, or no longer exists
+    let wait_for_port = |exists: bool, what: &str| {
+        let deadline = Instant::now() + USB_ENUMERATION_TIMEOUT;
+        while Path::new(port_name).exists() != exists {
+            if Instant::now() > deadline {
+                return Err(failed(format!(
+                    "'{port_name}' did not {what} after resetting the USB device"
+                )));
+            }
+            sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    };
+
+    let write_authorized = |value: &str| {
+        fs::write(&authorized, value)
+            .map_err(|e| failed(format!("could not write to {} ({e})", authorized.display())))
+    };
+
+    debug!("Re-authorizing USB device {}", device.display());
+    // The old serial port must be gone before waiting for it to re-appear,
+    // otherwise the stale device node could be reopened while the device is
+    // still being enumerated again.
+    write_authorized("0")?;
+    wait_for_port(false, "disappear")?;
+    write_authorized("1")?;
+
+    // Wait for the device to be enumerated again, and for its serial port to
+    // re-appear. Give udev a moment afterwards to apply any permissions.
+    wait_for_port(true, "re-appear")?;
+    sleep(Duration::from_millis(200));
+
+    Ok(())
+}
+
+/// Re-enumerate the USB device providing the given serial port
+///
+/// This is only supported on Linux; on all other platforms a warning is printed
+/// and the port is left untouched.
+#[cfg(not(target_os = "linux"))]
+pub fn usb_port_reset(_port_name: &str) -> Result<(), Error> {
+    log::warn!("Resetting the USB port is only supported on Linux, skipping");
+
+    Ok(())
+}
+
 /// Construct a sequence of reset strategies based on the OS and chip.
 ///
 /// Returns a [Vec] containing one or more reset strategies to be attempted
@@ -310,7 +420,8 @@ pub fn construct_reset_strategy_sequence(
 
     // USB-to-Serial bridge
     #[cfg(unix)]
-    if cfg!(unix) && !port_name.starts_with("rfc2217:") {
+    if cfg!(unix) && !port_name.starts_with("rfc2217:") && !crate::remote::is_remote_port(port_name)
+    {
         return vec![
             Box::new(UnixTightReset::new(false)),
             Box::new(UnixTightReset::new(true)),
@@ -345,6 +456,10 @@ pub enum ResetBeforeOperation {
     NoResetNoSync,
     /// Reset sequence for USB-JTAG-Serial peripheral
     UsbReset,
+    /// Re-enumerates the USB device providing the serial port, as if it had
+    /// been unplugged and plugged back in, before using the default reset
+    /// sequence. Only supported on Linux.
+    UsbPortReset,
 }
 
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
@@ -361,4 +476,13 @@ pub enum ResetAfterOperation {
     NoReset,
     /// Leaves the chip in the stub bootloader, no reset is performed.
     NoResetNoStub,
+    /// Resets the chip while holding IO0 low, so that it re-enters the ROM
+    /// bootloader (download mode) once the operation has completed.
+    StayInBootloader,
+    /// Resets the chip in software, without toggling any of the serial control
+    /// lines, and runs the user code.
+    SoftReset,
+    /// Resets the chip by arming the RTC watchdog timer and letting it expire,
+    /// which is useful when the DTR/RTS lines are not wired to the chip.
+    WatchdogReset,
 }
@@ -0,0 +1,479 @@
+//! Serial ports shared over the network with RFC 2217
+//!
+//! Serial port servers such as `ser2net` expose their ports over Telnet,
+//! using the Com Port Control Option described by [RFC 2217] to change the
+//! baud rate and drive the control lines. An [Rfc2217Port] connects to such a
+//! server, given a URL of the form `rfc2217://<HOST>:<PORT>`, so that devices
+//! attached to it can be flashed and monitored as if they were attached to the
+//! host.
+//!
+//! [RFC 2217]: https://www.rfc-editor.org/rfc/rfc2217
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::transport::FlasherTransport;
+use crate::error::Error;
+
+/// URL scheme used to refer to a serial port shared with RFC 2217
+pub const URL_SCHEME: &str = "rfc2217://";
+
+/// Does the given port name refer to a serial port shared with RFC 2217?
+pub fn is_rfc2217_port(port: &str) -> bool {
+    port.starts_with(URL_SCHEME)
+}
+
+// Telnet commands
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// Telnet options
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// Com Port Control Option commands, sent by the client; the server replies to
+// each with the same command plus 100
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+
+// Values of `SET_CONTROL`
+const CONTROL_NO_FLOW_CONTROL: u8 = 1;
+const CONTROL_HARDWARE_FLOW_CONTROL: u8 = 3;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+
+// Values of `PURGE_DATA`
+const PURGE_RECEIVE: u8 = 1;
+
+/// Data received from the server which has not yet been read
+#[derive(Default)]
+struct Received {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Buffer shared between an [Rfc2217Port] and its reader thread
+#[derive(Default)]
+struct Incoming {
+    received: Mutex<Received>,
+    available: Condvar,
+}
+
+/// A serial port shared with RFC 2217
+///
+/// Data received from the server is buffered by a background thread, which
+/// also answers the server's option negotiation, so that reads can honour the
+/// port's timeout just like a native serial port.
+pub struct Rfc2217Port {
+    name: String,
+    // Shared with the reader thread, which replies to negotiation requests
+    stream: Arc<Mutex<TcpStream>>,
+    incoming: Arc<Incoming>,
+    baud: u32,
+    timeout: Duration,
+}
+
+impl Rfc2217Port {
+    /// Connect to the server at the given `rfc2217://<HOST>:<PORT>` URL, and
+    /// configure the serial port for 8N1 at the given baud rate
+    pub fn connect(url: &str, baud: u32) -> Result<Self, Error> {
+        let addr = url
+            .strip_prefix(URL_SCHEME)
+            .filter(|addr| {
+                addr.rsplit_once(':')
+                    .is_some_and(|(_, port)| !port.ends_with(']'))
+            })
+            .ok_or_else(|| Error::InvalidRemotePort(url.to_string()))?;
+
+        debug!("Connecting to RFC 2217 server at {addr}");
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let incoming = Arc::new(Incoming::default());
+        let mut reader = stream.try_clone()?;
+        let stream = Arc::new(Mutex::new(stream));
+        let (shared, replies) = (incoming.clone(), stream.clone());
+        thread::spawn(move || receive(&mut reader, &shared, &replies));
+
+        let mut port = Self {
+            name: url.to_string(),
+            stream,
+            incoming,
+            baud,
+            timeout: Duration::from_secs(3),
+        };
+
+        let mut negotiation = Vec::new();
+        for option in [BINARY, SUPPRESS_GO_AHEAD, COM_PORT_OPTION] {
+            negotiation.extend([IAC, WILL, option]);
+        }
+        for option in [BINARY, SUPPRESS_GO_AHEAD] {
+            negotiation.extend([IAC, DO, option]);
+        }
+        port.send(&negotiation)?;
+
+        port.set_baud_rate(baud)?;
+        port.command(SET_DATASIZE, &[8])?;
+        // No parity, one stop bit
+        port.command(SET_PARITY, &[1])?;
+        port.command(SET_STOPSIZE, &[1])?;
+        port.command(SET_CONTROL, &[CONTROL_NO_FLOW_CONTROL])?;
+
+        Ok(port)
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.stream.lock().unwrap().write_all(data)
+    }
+
+    /// Send a Com Port Control Option command
+    fn command(&self, command: u8, value: &[u8]) -> io::Result<()> {
+        self.send(&subnegotiation(command, value))
+    }
+}
+
+/// Encode a Com Port Control Option command
+fn subnegotiation(command: u8, value: &[u8]) -> Vec<u8> {
+    let mut frame = vec![IAC, SB, COM_PORT_OPTION, command];
+    frame.extend(escape(value));
+    frame.extend([IAC, SE]);
+
+    frame
+}
+
+/// Double every `IAC` byte in data sent to the server
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+
+    escaped
+}
+
+/// Something received from the server
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    /// A byte of serial data
+    Data(u8),
+    /// An option negotiation request, such as `DO BINARY`
+    Negotiation(u8, u8),
+    /// A subnegotiation, without its framing
+    Subnegotiation(Vec<u8>),
+}
+
+/// Decoder splitting the stream received from the server into [Event]s
+#[derive(Debug, Default)]
+enum Decoder {
+    #[default]
+    Data,
+    Command,
+    Negotiation(u8),
+    Subnegotiation(Vec<u8>),
+    SubnegotiationCommand(Vec<u8>),
+}
+
+impl Decoder {
+    fn decode(&mut self, byte: u8) -> Option<Event> {
+        let (state, event) = match (std::mem::take(self), byte) {
+            (Decoder::Data, IAC) => (Decoder::Command, None),
+            (Decoder::Data, _) => (Decoder::Data, Some(Event::Data(byte))),
+            (Decoder::Command, IAC) => (Decoder::Data, Some(Event::Data(IAC))),
+            (Decoder::Command, WILL | WONT | DO | DONT) => (Decoder::Negotiation(byte), None),
+            (Decoder::Command, SB) => (Decoder::Subnegotiation(Vec::new()), None),
+            // Other commands, such as `NOP`, carry no meaning for a serial port
+            (Decoder::Command, _) => (Decoder::Data, None),
+            (Decoder::Negotiation(command), _) => {
+                (Decoder::Data, Some(Event::Negotiation(command, byte)))
+            }
+            (Decoder::Subnegotiation(payload), IAC) => {
+                (Decoder::SubnegotiationCommand(payload), None)
+            }
+            (Decoder::Subnegotiation(mut payload), _) => {
+                payload.push(byte);
+                (Decoder::Subnegotiation(payload), None)
+            }
+            (Decoder::SubnegotiationCommand(payload), SE) => {
+                (Decoder::Data, Some(Event::Subnegotiation(payload)))
+            }
+            (Decoder::SubnegotiationCommand(mut payload), _) => {
+                payload.push(byte);
+                (Decoder::Subnegotiation(payload), None)
+            }
+        };
+        *self = state;
+
+        event
+    }
+}
+
+/// Receive data from the server until the connection is closed, answering
+/// the option negotiation requests it makes along the way
+fn receive(stream: &mut TcpStream, incoming: &Incoming, replies: &Mutex<TcpStream>) {
+    let mut decoder = Decoder::default();
+    let mut buf = [0; 4096];
+
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => {
+                debug!("Connection to RFC 2217 server closed");
+                break;
+            }
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("Connection to RFC 2217 server closed: {e}");
+                break;
+            }
+        };
+
+        let mut data = Vec::with_capacity(len);
+        for &byte in &buf[..len] {
+            match decoder.decode(byte) {
+                Some(Event::Data(byte)) => data.push(byte),
+                Some(Event::Negotiation(command, option)) => {
+                    if let Some(reply) = negotiation_reply(command, option) {
+                        let _ = replies.lock().unwrap().write_all(&reply);
+                    }
+                }
+                Some(Event::Subnegotiation(payload)) => {
+                    debug!("Received RFC 2217 subnegotiation: {payload:02x?}")
+                }
+                None => {}
+            }
+        }
+
+        if !data.is_empty() {
+            incoming.received.lock().unwrap().data.extend(data);
+            incoming.available.notify_all();
+        }
+    }
+
+    incoming.received.lock().unwrap().closed = true;
+    incoming.available.notify_all();
+}
+
+/// The reply to an option negotiation request from the server, if any
+///
+/// The options requested when connecting are accepted without a reply, as
+/// the request acknowledges ours, and any other option is refused.
+fn negotiation_reply(command: u8, option: u8) -> Option<[u8; 3]> {
+    let ours = matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION);
+
+    match command {
+        DO if !ours => Some([IAC, WONT, option]),
+        WILL if !ours || option == COM_PORT_OPTION => Some([IAC, DONT, option]),
+        _ => None,
+    }
+}
+
+impl Read for Rfc2217Port {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut received = self.incoming.received.lock().unwrap();
+
+        loop {
+            if !received.data.is_empty() {
+                let len = buf.len().min(received.data.len());
+                for (dst, src) in buf.iter_mut().zip(received.data.drain(..len)) {
+                    *dst = src;
+                }
+
+                return Ok(len);
+            }
+
+            if received.closed {
+                return Err(io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "Connection to the RFC 2217 server was closed",
+                ));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+
+            received = self
+                .incoming
+                .available
+                .wait_timeout(received, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Write for Rfc2217Port {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(&escape(buf))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.lock().unwrap().flush()
+    }
+}
+
+impl FlasherTransport for Rfc2217Port {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(self.baud)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        self.baud = baud_rate;
+
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        let control = if level {
+            CONTROL_RTS_ON
+        } else {
+            CONTROL_RTS_OFF
+        };
+
+        self.command(SET_CONTROL, &[control])
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        let control = if level {
+            CONTROL_DTR_ON
+        } else {
+            CONTROL_DTR_OFF
+        };
+
+        self.command(SET_CONTROL, &[control])
+    }
+
+    fn set_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        let control = if enabled {
+            CONTROL_HARDWARE_FLOW_CONTROL
+        } else {
+            CONTROL_NO_FLOW_CONTROL
+        };
+
+        self.command(SET_CONTROL, &[control])
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(self.incoming.received.lock().unwrap().data.len() as u32)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.command(PURGE_DATA, &[PURGE_RECEIVE])?;
+        self.incoming.received.lock().unwrap().data.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_stream() {
+        let stream = [
+            b'a',
+            IAC,
+            IAC,
+            b'b',
+            IAC,
+            DO,
+            BINARY,
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            101,
+            0,
+            1,
+            IAC,
+            IAC,
+            IAC,
+            SE,
+            b'c',
+        ];
+
+        let mut decoder = Decoder::default();
+        let events: Vec<_> = stream
+            .into_iter()
+            .filter_map(|byte| decoder.decode(byte))
+            .collect();
+
+        assert_eq!(
+            events,
+            [
+                Event::Data(b'a'),
+                Event::Data(IAC),
+                Event::Data(b'b'),
+                Event::Negotiation(DO, BINARY),
+                Event::Subnegotiation(vec![COM_PORT_OPTION, 101, 0, 1, IAC]),
+                Event::Data(b'c'),
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(
+            subnegotiation(SET_BAUDRATE, &0x00ff_c200_u32.to_be_bytes()),
+            [
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_BAUDRATE,
+                0x00,
+                IAC,
+                IAC,
+                0xc2,
+                0x00,
+                IAC,
+                SE
+            ]
+        );
+
+        assert_eq!(negotiation_reply(DO, BINARY), None);
+        assert_eq!(negotiation_reply(DO, 1), Some([IAC, WONT, 1]));
+        assert_eq!(negotiation_reply(WILL, 1), Some([IAC, DONT, 1]));
+    }
+}
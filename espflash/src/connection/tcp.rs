@@ -0,0 +1,218 @@
+//! Serial ports bridged over a plain TCP socket
+//!
+//! Bridges such as ESP-LINK or `socat` forward a serial port's data over a
+//! TCP socket without any framing, and without any way of changing the baud
+//! rate or driving the control lines. A [TcpPort] connects to such a bridge,
+//! given a URL of the form `tcp://<HOST>:<PORT>`.
+//!
+//! As the device cannot be reset into its bootloader, it must already be
+//! waiting for a download when connecting, which some bridges take care of.
+//! Once flashed, the device is reset using commands sent to the loader rather
+//! than the control lines.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::debug;
+
+use super::transport::FlasherTransport;
+use crate::error::Error;
+
+/// URL scheme used to refer to a serial port bridged over TCP
+pub const URL_SCHEME: &str = "tcp://";
+
+/// Does the given port name refer to a serial port bridged over TCP?
+pub fn is_tcp_port(port: &str) -> bool {
+    port.starts_with(URL_SCHEME)
+}
+
+/// Shortest read timeout, as sockets do not accept a zero timeout
+const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+/// A serial port bridged over a plain TCP socket
+pub struct TcpPort {
+    name: String,
+    stream: TcpStream,
+    baud: u32,
+    timeout: Duration,
+}
+
+impl TcpPort {
+    /// Connect to the bridge at the given `tcp://<HOST>:<PORT>` URL
+    ///
+    /// The baud rate is that configured on the bridge, which cannot be changed
+    /// from here.
+    pub fn connect(url: &str, baud: u32) -> Result<Self, Error> {
+        let addr = url
+            .strip_prefix(URL_SCHEME)
+            .filter(|addr| {
+                addr.rsplit_once(':')
+                    .is_some_and(|(_, port)| !port.ends_with(']'))
+            })
+            .ok_or_else(|| Error::InvalidRemotePort(url.to_string()))?;
+
+        debug!("Connecting to TCP serial bridge at {addr}");
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        let mut port = Self {
+            name: url.to_string(),
+            stream,
+            baud,
+            timeout: Duration::ZERO,
+        };
+        port.set_timeout(Duration::from_secs(3))?;
+
+        Ok(port)
+    }
+
+    /// Run an operation on the socket without blocking
+    fn nonblocking<T>(&self, f: impl FnOnce(&TcpStream) -> io::Result<T>) -> io::Result<T> {
+        self.stream.set_nonblocking(true)?;
+        let result = f(&self.stream);
+        self.stream.set_nonblocking(false)?;
+
+        result
+    }
+}
+
+impl Read for TcpPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Connection to the TCP serial bridge was closed",
+            )),
+            // Sockets report an expired timeout differently across platforms
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Write for TcpPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl FlasherTransport for TcpPort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(self.baud)
+    }
+
+    /// The baud rate is configured on the bridge, so this fails unless it is
+    /// unchanged
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        if baud_rate != self.baud {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "The baud rate of a TCP serial bridge cannot be changed",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        false
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream
+            .set_read_timeout(Some(timeout.max(MIN_TIMEOUT)))?;
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    /// The control lines are not bridged, so this does nothing
+    fn set_rts(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The control lines are not bridged, so this does nothing
+    fn set_dtr(&mut self, _level: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn has_control_lines(&self) -> bool {
+        false
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        let mut buf = [0; 4096];
+        match self.nonblocking(|stream| stream.peek(&mut buf)) {
+            Ok(len) => Ok(len as u32),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        self.nonblocking(|mut stream| loop {
+            match stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn bridges_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tcp://{}", listener.local_addr().unwrap());
+
+        let mut port = TcpPort::connect(&url, 115_200).unwrap();
+        let (mut bridge, _) = listener.accept().unwrap();
+
+        port.write_all(b"sync").unwrap();
+        let mut buf = [0; 4];
+        bridge.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"sync");
+
+        bridge.write_all(b"stale").unwrap();
+        while port.bytes_to_read().unwrap() < 5 {}
+        port.clear_input().unwrap();
+        assert_eq!(port.bytes_to_read().unwrap(), 0);
+
+        port.set_timeout(Duration::ZERO).unwrap();
+        let err = port.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        assert!(!port.has_control_lines());
+        assert!(!port.can_change_baud_rate());
+        port.set_baud_rate(115_200).unwrap();
+        let err = port.set_baud_rate(921_600).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(port.baud_rate().unwrap(), 115_200);
+        assert!(TcpPort::connect("tcp://localhost", 115_200).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Trace logging of the serial protocol
+//!
+//! When enabled, every SLIP frame which is sent to or received from the target
+//! device is logged in a readable hex format, along with its decoded opcode,
+//! length, checksum and a timestamp relative to the start of the trace.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+use crate::command::CommandType;
+
+/// Number of bytes printed per line of a hex dump
+const BYTES_PER_LINE: usize = 16;
+
+/// Direction of a traced frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Frame written to the target device
+    Write,
+    /// Frame read from the target device
+    Read,
+}
+
+/// Logs serial protocol frames to stderr or to a file
+pub struct Tracer {
+    sink: Box<dyn Write + Send>,
+    start: Instant,
+    last: Instant,
+}
+
+impl Tracer {
+    /// Create a new tracer which writes to the given sink
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        let now = Instant::now();
+
+        Self {
+            sink,
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Create a new tracer which writes to stderr
+    pub fn stderr() -> Self {
+        Self::new(Box::new(io::stderr()))
+    }
+
+    /// Create a new tracer which writes to the file at the given path,
+    /// truncating it if it already exists
+    pub fn file(path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+
+        Ok(Self::new(Box::new(BufWriter::new(file))))
+    }
+
+    /// Log a single, decoded (un-escaped) SLIP frame
+    pub fn frame(&mut self, direction: Direction, frame: &[u8]) {
+        // Tracing is a debugging aid; failing to write the trace should never
+        // cause the operation being traced to fail.
+        let _ = self.write_frame(direction, frame);
+    }
+
+    /// Log raw data which was written outside of a command packet
+    pub fn raw(&mut self, direction: Direction, data: &[u8]) {
+        let _ = self.write_raw(direction, data);
+    }
+
+    fn write_frame(&mut self, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        self.write_timestamp(direction)?;
+
+        if frame.len() < 8 {
+            writeln!(self.sink, "malformed frame, len={}", frame.len())?;
+        } else {
+            let opcode = frame[1];
+            let size = u16::from_le_bytes([frame[2], frame[3]]);
+            let word = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+
+            match direction {
+                Direction::Write => writeln!(
+                    self.sink,
+                    "{} ({:#04x}) len={} checksum={:#010x}",
+                    CommandType::from(opcode),
+                    opcode,
+                    size,
+                    word
+                )?,
+                Direction::Read => writeln!(
+                    self.sink,
+                    "{} ({:#04x}) len={} value={:#010x}",
+                    CommandType::from(opcode),
+                    opcode,
+                    size,
+                    word
+                )?,
+            }
+        }
+
+        self.write_dump(frame)
+    }
+
+    fn write_raw(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        self.write_timestamp(direction)?;
+        writeln!(self.sink, "raw len={}", data.len())?;
+
+        self.write_dump(data)
+    }
+
+    fn write_timestamp(&mut self, direction: Direction) -> io::Result<()> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.start);
+        let delta = now.duration_since(self.last);
+        self.last = now;
+
+        let arrow = match direction {
+            Direction::Write => "-->",
+            Direction::Read => "<--",
+        };
+
+        write!(
+            self.sink,
+            "[{:>10.3}ms +{:>8.3}ms] {} ",
+            elapsed.as_secs_f64() * 1000.0,
+            delta.as_secs_f64() * 1000.0,
+            arrow
+        )
+    }
+
+    fn write_dump(&mut self, data: &[u8]) -> io::Result<()> {
+        for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+            writeln!(self.sink, "    {:04x}: {}", i * BYTES_PER_LINE, hex(line))?;
+        }
+
+        self.sink.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,345 @@
+//! Connect to a target device over a custom transport
+//!
+//! A [Flasher](crate::flasher::Flasher) usually talks to the device over a
+//! serial port attached to the host. Any other byte stream which carries the
+//! loader's protocol, such as a USB bulk endpoint, a TCP bridge or a mock used
+//! in tests, can be used instead by implementing [FlasherTransport] for it.
+//!
+//! ```rust,no_run
+//! use std::{io, net::TcpStream, time::Duration};
+//!
+//! use espflash::connection::{transport::FlasherTransport, Port};
+//! # use std::io::{Read, Write};
+//!
+//! struct Bridge(TcpStream);
+//!
+//! # impl io::Read for Bridge {
+//! #     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+//! # }
+//! # impl io::Write for Bridge {
+//! #     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+//! #     fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+//! # }
+//! impl FlasherTransport for Bridge {
+//!     fn baud_rate(&self) -> io::Result<u32> {
+//!         Ok(115_200)
+//!     }
+//!
+//!     fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//!
+//!     fn timeout(&self) -> Duration {
+//!         self.0.read_timeout().ok().flatten().unwrap_or_default()
+//!     }
+//!
+//!     fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+//!         self.0.set_read_timeout(Some(timeout))
+//!     }
+//!
+//!     fn set_rts(&mut self, _level: bool) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//!
+//!     fn set_dtr(&mut self, _level: bool) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # fn example() -> io::Result<()> {
+//! let port: Port = Box::new(Bridge(TcpStream::connect("192.168.1.10:3333")?));
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, FlowControl, SerialPort};
+
+use super::NativePort;
+
+/// A byte stream connected to a target device
+///
+/// Only what the loader's protocol relies upon is required: reading and
+/// writing with a timeout, changing the baud rate, and driving the RTS and DTR
+/// lines used to reset the device into its bootloader. Transports without
+/// control lines may ignore them, as long as the device is otherwise put in
+/// its bootloader, and connect with [ResetBeforeOperation::NoReset].
+///
+/// [ResetBeforeOperation::NoReset]: super::reset::ResetBeforeOperation::NoReset
+pub trait FlasherTransport: Read + Write + Send {
+    /// Name of the transport, as shown to the user
+    fn name(&self) -> Option<String> {
+        None
+    }
+
+    /// Current baud rate
+    fn baud_rate(&self) -> io::Result<u32>;
+
+    /// Change the baud rate, once the device has been told to do the same
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()>;
+
+    /// Can the baud rate be changed? The device is only told to switch to
+    /// another baud rate if so.
+    fn can_change_baud_rate(&self) -> bool {
+        true
+    }
+
+    /// Time a read waits for data before failing with
+    /// [io::ErrorKind::TimedOut]
+    fn timeout(&self) -> Duration;
+
+    /// Change the time a read waits for data
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Set the level of the RTS line
+    fn set_rts(&mut self, level: bool) -> io::Result<()>;
+
+    /// Set the level of the DTR line
+    fn set_dtr(&mut self, level: bool) -> io::Result<()>;
+
+    /// Can the device be reset using the RTS and DTR lines?
+    fn has_control_lines(&self) -> bool {
+        true
+    }
+
+    /// Enable or disable hardware flow control, which is not supported unless
+    /// this is overridden
+    fn set_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        if enabled {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Flow control is not supported by this transport",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Number of bytes which can be read without waiting
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(0)
+    }
+
+    /// Discard any data received but not read yet
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Is this a serial port attached to the host, which can be reopened if
+    /// the device re-enumerates?
+    fn is_native(&self) -> bool {
+        false
+    }
+
+    /// The raw file descriptor of a serial port attached to the host, through
+    /// which the RTS and DTR lines can be set at the same time
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl<T: FlasherTransport + ?Sized> FlasherTransport for Box<T> {
+    fn name(&self) -> Option<String> {
+        (**self).name()
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        (**self).baud_rate()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        (**self).set_baud_rate(baud_rate)
+    }
+
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        (**self).set_rts(level)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        (**self).set_dtr(level)
+    }
+
+    fn can_change_baud_rate(&self) -> bool {
+        (**self).can_change_baud_rate()
+    }
+
+    fn has_control_lines(&self) -> bool {
+        (**self).has_control_lines()
+    }
+
+    fn set_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        (**self).set_flow_control(enabled)
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        (**self).bytes_to_read()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        (**self).clear_input()
+    }
+
+    fn is_native(&self) -> bool {
+        (**self).is_native()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
+
+impl FlasherTransport for NativePort {
+    fn name(&self) -> Option<String> {
+        SerialPort::name(self)
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(SerialPort::baud_rate(self)?)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        Ok(SerialPort::set_baud_rate(self, baud_rate)?)
+    }
+
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self, timeout)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        Ok(self.write_request_to_send(level)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        Ok(self.write_data_terminal_ready(level)?)
+    }
+
+    fn set_flow_control(&mut self, enabled: bool) -> io::Result<()> {
+        let flow_control = if enabled {
+            FlowControl::Hardware
+        } else {
+            FlowControl::None
+        };
+
+        Ok(SerialPort::set_flow_control(self, flow_control)?)
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(SerialPort::bytes_to_read(self)?)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+
+    fn is_native(&self) -> bool {
+        true
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::connection::Port;
+
+    #[derive(Default)]
+    struct Loopback {
+        data: VecDeque<u8>,
+        baud: u32,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.data.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl FlasherTransport for Loopback {
+        fn baud_rate(&self) -> io::Result<u32> {
+            Ok(self.baud)
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            self.baud = baud_rate;
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_rts(&mut self, _level: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_dtr(&mut self, _level: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn clear_input(&mut self) -> io::Result<()> {
+            self.data.clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn forwards_to_transport() {
+        let mut port: Port = Box::new(Loopback::default());
+
+        port.set_baud_rate(921_600).unwrap();
+        assert_eq!(port.baud_rate().unwrap(), 921_600);
+        port.set_rts(true).unwrap();
+        assert!(port.has_control_lines());
+
+        port.write_all(b"abc").unwrap();
+        let mut buf = [0; 2];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ab");
+
+        port.clear_input().unwrap();
+        assert_eq!(port.read(&mut buf).unwrap(), 0);
+
+        assert!(port.set_flow_control(true).is_err());
+        assert!(port.set_flow_control(false).is_ok());
+    }
+}
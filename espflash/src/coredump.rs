@@ -0,0 +1,620 @@
+//! Core dumps
+//!
+//! When an ESP-IDF application crashes, it can save the state of all of its
+//! tasks to the `coredump` partition. The core dump begins with a
+//! `core_dump_header_t`, and ends with a checksum of everything that precedes
+//! it. In between, it holds either an ELF core file, which is the default since
+//! ESP-IDF v4.2, or the binary format used before, made up of the TCB and stack
+//! of each task followed by additional memory segments.
+//!
+//! Either way, [CoreDump::to_elf] produces an ELF core file which can be loaded
+//! by GDB along with the ELF file of the application.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-guides/core_dump.html
+
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, ota::esp_rom_crc32_le};
+
+/// Length of the header of the binary format, and of the ELF format before
+/// v2.1
+const HEADER_LEN: usize = 20;
+/// Length of the header of the first version of the binary format, which has
+/// no memory segments
+const HEADER_V1_LEN: usize = 16;
+/// Length of the header of the ELF format since v2.1, which includes the chip
+/// revision
+const HEADER_V2_1_LEN: usize = 24;
+const TASK_HEADER_LEN: usize = 12;
+const SEGMENT_HEADER_LEN: usize = 8;
+
+const CRC32_LEN: usize = 4;
+const SHA256_LEN: usize = 32;
+
+/// Major version of the binary format
+const VERSION_BIN: u8 = 0;
+/// Major version of the ELF format
+const VERSION_ELF: u8 = 1;
+
+/// Chip IDs of the chips with an Xtensa CPU: ESP32, ESP32-S2 and ESP32-S3
+const XTENSA_CHIP_IDS: [u16; 3] = [0, 2, 9];
+
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+
+const ELF_HEADER_LEN: usize = 52;
+const PROGRAM_HEADER_LEN: usize = 32;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+/// Length of `struct elf_prstatus` preceding the registers
+const PRSTATUS_LEN: usize = 72;
+/// Offset of `pr_pid` in `struct elf_prstatus`
+const PRSTATUS_PID_OFFSET: usize = 24;
+
+/// Xtensa exception frame (`XtExcFrame`) layout, in words
+const XT_STK_EXIT: usize = 0;
+const XT_STK_PC: usize = 1;
+const XT_STK_PS: usize = 2;
+const XT_STK_AR_START: usize = 3;
+const XT_STK_AR_NUM: usize = 16;
+const XT_STK_SAR: usize = 19;
+const XT_STK_LBEG: usize = 22;
+const XT_STK_LEND: usize = 23;
+const XT_STK_LCOUNT: usize = 24;
+const XT_STK_FRMSZ: usize = 25;
+/// Xtensa solicited frame (`XtSolFrame`) layout, in words
+const XT_SOL_PC: usize = 1;
+const XT_SOL_PS: usize = 2;
+const XT_SOL_AR_START: usize = 4;
+const XT_SOL_AR_NUM: usize = 4;
+
+/// Xtensa register indices in the `prstatus` notes expected by GDB
+const XT_REG_PC: usize = 0;
+const XT_REG_PS: usize = 2;
+const XT_REG_LBEG: usize = 3;
+const XT_REG_LEND: usize = 4;
+const XT_REG_LCOUNT: usize = 5;
+const XT_REG_SAR: usize = 6;
+const XT_REG_AR_START: usize = 64;
+const XT_REG_NUM: usize = 129;
+
+/// Bits of the Xtensa `PS` register
+const XT_PS_EXCM: u32 = 1 << 4;
+const XT_PS_UM: u32 = 1 << 5;
+
+/// The RISC-V exception frame (`RvExcFrame`) begins with `mepc` followed by
+/// `x1` to `x31`, which is the layout of the `prstatus` notes expected by GDB
+const RV_REG_NUM: usize = 32;
+
+/// Format of a core dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreDumpFormat {
+    /// Binary format, used before ESP-IDF v4.2
+    Binary,
+    /// ELF format
+    Elf,
+}
+
+/// Checksum of a core dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Checksum {
+    Crc32,
+    Sha256,
+}
+
+impl Checksum {
+    fn len(self) -> usize {
+        match self {
+            Checksum::Crc32 => CRC32_LEN,
+            Checksum::Sha256 => SHA256_LEN,
+        }
+    }
+
+    fn verify(self, data: &[u8], checksum: &[u8]) -> bool {
+        match self {
+            Checksum::Crc32 => esp_rom_crc32_le(0, data).to_le_bytes() == checksum,
+            Checksum::Sha256 => Sha256::digest(data).as_slice() == checksum,
+        }
+    }
+}
+
+/// A task saved in a core dump in the binary format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDumpTask {
+    /// Address of the task control block
+    pub tcb_addr: u32,
+    /// Stack pointer of the task
+    pub stack_start: u32,
+    /// End of the stack of the task
+    pub stack_end: u32,
+    /// Contents of the task control block
+    pub tcb: Vec<u8>,
+    /// Contents of the stack, from its lowest address
+    pub stack: Vec<u8>,
+}
+
+impl CoreDumpTask {
+    fn stack_addr(&self) -> u32 {
+        self.stack_start.min(self.stack_end)
+    }
+}
+
+/// A memory segment saved in a core dump in the binary format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreDumpSegment {
+    /// Address of the segment
+    pub addr: u32,
+    /// Contents of the segment
+    pub data: Vec<u8>,
+}
+
+/// Contents of a core dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoreDumpData {
+    /// Tasks and memory segments saved in the binary format, with the task
+    /// which crashed first
+    Binary {
+        tasks: Vec<CoreDumpTask>,
+        segments: Vec<CoreDumpSegment>,
+    },
+    /// ELF core file
+    Elf(Vec<u8>),
+}
+
+/// A core dump, as saved to the `coredump` partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[doc(alias = "core_dump_header_t")]
+pub struct CoreDump {
+    /// ID of the chip the core dump was saved on
+    pub chip_id: u16,
+    /// Version of the core dump format
+    pub version: u16,
+    /// Contents of the core dump
+    pub data: CoreDumpData,
+}
+
+impl CoreDump {
+    /// Parse a core dump, as read from the beginning of the `coredump`
+    /// partition
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let word = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let len = match word(0) {
+            None | Some(0) | Some(u32::MAX) => return Err(Error::CoreDumpNotFound),
+            Some(len) => len as usize,
+        };
+        if len > data.len() {
+            return Err(invalid(format!(
+                "its length of {len:#x} bytes exceeds the {:#x} bytes available",
+                data.len()
+            )));
+        }
+        let data = &data[..len];
+
+        let version = word(4).ok_or_else(|| invalid("its header is truncated"))?;
+        let chip_id = (version >> 16) as u16;
+        let version = version as u16;
+        let [minor, major] = version.to_le_bytes();
+
+        let (format, header_len, checksum) = match (major, minor) {
+            (VERSION_BIN, 1) => (CoreDumpFormat::Binary, HEADER_V1_LEN, Checksum::Crc32),
+            (VERSION_BIN, _) => (CoreDumpFormat::Binary, HEADER_LEN, Checksum::Crc32),
+            (VERSION_ELF, 0..=3) => (CoreDumpFormat::Elf, HEADER_LEN, checksum_for(minor)),
+            (VERSION_ELF, _) => (CoreDumpFormat::Elf, HEADER_V2_1_LEN, checksum_for(minor)),
+            _ => {
+                return Err(invalid(format!(
+                    "its version {major}.{minor} is unsupported"
+                )))
+            }
+        };
+
+        if len < header_len + checksum.len() {
+            return Err(invalid("its header is truncated"));
+        }
+        let (contents, expected) = data.split_at(len - checksum.len());
+        if !checksum.verify(contents, expected) {
+            return Err(invalid("its checksum does not match its contents"));
+        }
+
+        let body = &contents[header_len..];
+        let data = match format {
+            CoreDumpFormat::Elf => {
+                if !body.starts_with(b"\x7fELF") {
+                    return Err(invalid("it does not hold an ELF file"));
+                }
+                CoreDumpData::Elf(body.to_vec())
+            }
+            CoreDumpFormat::Binary => {
+                let tasks = word(8).unwrap() as usize;
+                let tcb_len = word(12).unwrap() as usize;
+                let segments = if header_len == HEADER_LEN {
+                    word(16).unwrap() as usize
+                } else {
+                    0
+                };
+
+                parse_binary(body, tasks, tcb_len, segments)?
+            }
+        };
+
+        Ok(Self {
+            chip_id,
+            version,
+            data,
+        })
+    }
+
+    /// Format of the core dump
+    pub fn format(&self) -> CoreDumpFormat {
+        match self.data {
+            CoreDumpData::Binary { .. } => CoreDumpFormat::Binary,
+            CoreDumpData::Elf(_) => CoreDumpFormat::Elf,
+        }
+    }
+
+    /// Convert the core dump to an ELF core file
+    ///
+    /// Core dumps in the ELF format are returned as they are. For core dumps
+    /// in the binary format, the registers of each task are recovered from the
+    /// exception frame at the top of its stack.
+    pub fn to_elf(&self) -> Result<Vec<u8>, Error> {
+        let (tasks, segments) = match &self.data {
+            CoreDumpData::Elf(elf) => return Ok(elf.clone()),
+            CoreDumpData::Binary { tasks, segments } => (tasks, segments),
+        };
+
+        let xtensa = XTENSA_CHIP_IDS.contains(&self.chip_id);
+
+        let mut notes = Vec::new();
+        for task in tasks {
+            if task.stack_start > task.stack_end {
+                return Err(invalid(format!(
+                    "the stack of task {:#010x} grows upwards, which is unsupported",
+                    task.tcb_addr
+                )));
+            }
+
+            let registers = if xtensa {
+                xtensa_registers(task)?
+            } else {
+                riscv_registers(task)?
+            };
+
+            let mut prstatus = vec![0; PRSTATUS_LEN];
+            prstatus[PRSTATUS_PID_OFFSET..][..4].copy_from_slice(&task.tcb_addr.to_le_bytes());
+            prstatus.extend(registers.iter().flat_map(|reg| reg.to_le_bytes()));
+
+            push_note(&mut notes, NT_PRSTATUS, &prstatus);
+        }
+
+        let loads = tasks
+            .iter()
+            .flat_map(|task| {
+                [
+                    (task.tcb_addr, task.tcb.as_slice()),
+                    (task.stack_addr(), task.stack.as_slice()),
+                ]
+            })
+            .chain(segments.iter().map(|seg| (seg.addr, seg.data.as_slice())))
+            .filter(|(_, data)| !data.is_empty())
+            .collect::<Vec<_>>();
+
+        let machine = if xtensa { EM_XTENSA } else { EM_RISCV };
+
+        Ok(build_elf_core(machine, &notes, &loads))
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidCoreDump(reason.into())
+}
+
+/// ELF format versions with odd minor versions use SHA-256 checksums
+fn checksum_for(minor: u8) -> Checksum {
+    if minor % 2 == 1 {
+        Checksum::Sha256
+    } else {
+        Checksum::Crc32
+    }
+}
+
+fn parse_binary(
+    mut body: &[u8],
+    tasks: usize,
+    tcb_len: usize,
+    segments: usize,
+) -> Result<CoreDumpData, Error> {
+    let word =
+        |data: &[u8], index: usize| u32::from_le_bytes(data[index * 4..][..4].try_into().unwrap());
+
+    // The counts come from the core dump, so are not trusted to size
+    // allocations: each task and segment is only added once it has been read
+    let mut parsed_tasks = Vec::new();
+    for _ in 0..tasks {
+        let header = take(&mut body, TASK_HEADER_LEN, "a task header")?;
+        let tcb_addr = word(header, 0);
+        let stack_start = word(header, 1);
+        let stack_end = word(header, 2);
+
+        let tcb = take(&mut body, align(tcb_len), "a task control block")?[..tcb_len].to_vec();
+        let stack_len = stack_start.abs_diff(stack_end) as usize;
+        let stack = take(&mut body, align(stack_len), "a task stack")?[..stack_len].to_vec();
+
+        parsed_tasks.push(CoreDumpTask {
+            tcb_addr,
+            stack_start,
+            stack_end,
+            tcb,
+            stack,
+        });
+    }
+
+    let mut parsed_segments = Vec::new();
+    for _ in 0..segments {
+        let header = take(&mut body, SEGMENT_HEADER_LEN, "a memory segment header")?;
+        let addr = word(header, 0);
+        let len = word(header, 1) as usize;
+        let data = take(&mut body, align(len), "a memory segment")?[..len].to_vec();
+
+        parsed_segments.push(CoreDumpSegment { addr, data });
+    }
+
+    Ok(CoreDumpData::Binary {
+        tasks: parsed_tasks,
+        segments: parsed_segments,
+    })
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize, what: &str) -> Result<&'a [u8], Error> {
+    if body.len() < len {
+        return Err(invalid(format!("{what} is truncated")));
+    }
+    let (data, rest) = body.split_at(len);
+    *body = rest;
+
+    Ok(data)
+}
+
+fn align(len: usize) -> usize {
+    len.next_multiple_of(4)
+}
+
+fn stack_words(task: &CoreDumpTask, len: usize) -> Result<Vec<u32>, Error> {
+    if task.stack.len() < len * 4 {
+        return Err(invalid(format!(
+            "the stack of task {:#010x} is too small to hold an exception frame",
+            task.tcb_addr
+        )));
+    }
+
+    Ok(task.stack[..len * 4]
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect())
+}
+
+fn xtensa_registers(task: &CoreDumpTask) -> Result<Vec<u32>, Error> {
+    let mut registers = vec![0; XT_REG_NUM];
+
+    if stack_words(task, 1)?[XT_STK_EXIT] != 0 {
+        // Exception frame, saved when the task was interrupted
+        let frame = stack_words(task, XT_STK_FRMSZ)?;
+        registers[XT_REG_PC] = frame[XT_STK_PC];
+        registers[XT_REG_PS] = frame[XT_STK_PS];
+        registers[XT_REG_AR_START..][..XT_STK_AR_NUM]
+            .copy_from_slice(&frame[XT_STK_AR_START..][..XT_STK_AR_NUM]);
+        registers[XT_REG_SAR] = frame[XT_STK_SAR];
+        registers[XT_REG_LBEG] = frame[XT_STK_LBEG];
+        registers[XT_REG_LEND] = frame[XT_STK_LEND];
+        registers[XT_REG_LCOUNT] = frame[XT_STK_LCOUNT];
+    } else {
+        // Solicited frame, saved when the task yielded
+        let frame = stack_words(task, XT_SOL_AR_START + XT_SOL_AR_NUM)?;
+        registers[XT_REG_PC] = frame[XT_SOL_PC];
+        registers[XT_REG_PS] = frame[XT_SOL_PS];
+        registers[XT_REG_AR_START..][..XT_SOL_AR_NUM]
+            .copy_from_slice(&frame[XT_SOL_AR_START..][..XT_SOL_AR_NUM]);
+    }
+
+    // GDB cannot unwind windowed calls when PS.EXCM is set, so clear it for
+    // tasks running in user mode (PS.UM), as esp-coredump does
+    if registers[XT_REG_PS] & XT_PS_UM != 0 {
+        registers[XT_REG_PS] &= !XT_PS_EXCM;
+    }
+
+    Ok(registers)
+}
+
+fn riscv_registers(task: &CoreDumpTask) -> Result<Vec<u32>, Error> {
+    stack_words(task, RV_REG_NUM)
+}
+
+fn push_note(notes: &mut Vec<u8>, ty: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+
+    notes.extend((NAME.len() as u32).to_le_bytes());
+    notes.extend((desc.len() as u32).to_le_bytes());
+    notes.extend(ty.to_le_bytes());
+    notes.extend(NAME);
+    notes.resize(align(notes.len()), 0);
+    notes.extend(desc);
+    notes.resize(align(notes.len()), 0);
+}
+
+/// Build a 32-bit little-endian ELF core file with a single note segment
+/// followed by the given loadable segments
+fn build_elf_core(machine: u16, notes: &[u8], loads: &[(u32, &[u8])]) -> Vec<u8> {
+    let phnum = 1 + loads.len();
+    let mut offset = ELF_HEADER_LEN + phnum * PROGRAM_HEADER_LEN;
+
+    let mut elf = Vec::new();
+    elf.extend(b"\x7fELF");
+    elf.extend([1, 1, 1]); // ELFCLASS32, ELFDATA2LSB, EV_CURRENT
+    elf.resize(16, 0);
+    elf.extend(ET_CORE.to_le_bytes());
+    elf.extend(machine.to_le_bytes());
+    elf.extend(1u32.to_le_bytes()); // e_version
+    elf.extend(0u32.to_le_bytes()); // e_entry
+    elf.extend((ELF_HEADER_LEN as u32).to_le_bytes()); // e_phoff
+    elf.extend(0u32.to_le_bytes()); // e_shoff
+    elf.extend(0u32.to_le_bytes()); // e_flags
+    elf.extend((ELF_HEADER_LEN as u16).to_le_bytes());
+    elf.extend((PROGRAM_HEADER_LEN as u16).to_le_bytes());
+    elf.extend((phnum as u16).to_le_bytes());
+    elf.extend([0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+    let mut program_header = |ty: u32, addr: u32, len: usize, flags: u32| {
+        elf.extend(ty.to_le_bytes());
+        elf.extend((offset as u32).to_le_bytes());
+        elf.extend(addr.to_le_bytes()); // p_vaddr
+        elf.extend(addr.to_le_bytes()); // p_paddr
+        elf.extend((len as u32).to_le_bytes()); // p_filesz
+        elf.extend((len as u32).to_le_bytes()); // p_memsz
+        elf.extend(flags.to_le_bytes());
+        elf.extend(4u32.to_le_bytes()); // p_align
+        offset += len;
+    };
+
+    program_header(PT_NOTE, 0, notes.len(), 0);
+    for (addr, data) in loads {
+        program_header(PT_LOAD, *addr, data.len(), PF_R | PF_W);
+    }
+
+    elf.extend(notes);
+    for (_, data) in loads {
+        elf.extend(*data);
+    }
+
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_header(version: u32, header: &[u32], body: &[u8], checksum: Checksum) -> Vec<u8> {
+        let header_len = 8 + header.len() * 4;
+        let len = header_len + body.len() + checksum.len();
+
+        let mut data = Vec::new();
+        data.extend((len as u32).to_le_bytes());
+        data.extend(version.to_le_bytes());
+        data.extend(header.iter().flat_map(|word| word.to_le_bytes()));
+        data.extend(body);
+        match checksum {
+            Checksum::Crc32 => data.extend(esp_rom_crc32_le(0, &data).to_le_bytes()),
+            Checksum::Sha256 => data.extend(Sha256::digest(&data)),
+        }
+        // Trailing erased flash
+        data.extend([0xff; 16]);
+
+        data
+    }
+
+    fn binary_core_dump() -> Vec<u8> {
+        let mut frame = [0u32; XT_STK_FRMSZ];
+        frame[XT_STK_EXIT] = 1;
+        frame[XT_STK_PC] = 0x400d_1234;
+        frame[XT_STK_PS] = 0x0006_0030;
+        frame[XT_STK_AR_START + 1] = 0x3ffb_1000;
+
+        let mut body = Vec::new();
+        // Task header
+        body.extend(0x3ffb_0000u32.to_le_bytes());
+        body.extend(0x3ffb_1000u32.to_le_bytes());
+        body.extend(0x3ffb_1070u32.to_le_bytes());
+        // TCB
+        body.extend([0xaa; 6]);
+        body.extend([0; 2]);
+        // Stack
+        body.extend(frame.iter().flat_map(|word| word.to_le_bytes()));
+        body.extend([0xbb; 0x70 - XT_STK_FRMSZ * 4]);
+        // Memory segment
+        body.extend(0x3ffc_0000u32.to_le_bytes());
+        body.extend(4u32.to_le_bytes());
+        body.extend([0xcc; 4]);
+
+        with_header(0x0002, &[1, 6, 1], &body, Checksum::Crc32)
+    }
+
+    #[test]
+    fn converts_binary_core_dumps() {
+        let core_dump = CoreDump::parse(&binary_core_dump()).unwrap();
+        assert_eq!(core_dump.format(), CoreDumpFormat::Binary);
+        assert_eq!(core_dump.chip_id, 0);
+
+        let CoreDumpData::Binary { tasks, segments } = &core_dump.data else {
+            panic!("expected a binary core dump");
+        };
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].tcb, [0xaa; 6]);
+        assert_eq!(tasks[0].stack.len(), 0x70);
+        assert_eq!(segments[0].addr, 0x3ffc_0000);
+
+        let elf = core_dump.to_elf().unwrap();
+        let file = xmas_elf::ElfFile::new(&elf).unwrap();
+        assert_eq!(u16::from_le_bytes([elf[18], elf[19]]), EM_XTENSA);
+
+        let headers = file.program_iter().collect::<Vec<_>>();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[0].get_type(), Ok(xmas_elf::program::Type::Note));
+        assert_eq!(headers[1].virtual_addr(), 0x3ffb_0000);
+        assert_eq!(headers[2].virtual_addr(), 0x3ffb_1000);
+        assert_eq!(headers[3].virtual_addr(), 0x3ffc_0000);
+
+        let note = &elf[headers[0].offset() as usize..];
+        let desc = &note[20..];
+        let reg = |index: usize| {
+            u32::from_le_bytes(desc[PRSTATUS_LEN + index * 4..][..4].try_into().unwrap())
+        };
+        assert_eq!(
+            &desc[PRSTATUS_PID_OFFSET..][..4],
+            &0x3ffb_0000u32.to_le_bytes()
+        );
+        assert_eq!(reg(XT_REG_PC), 0x400d_1234);
+        assert_eq!(reg(XT_REG_PS), 0x0006_0020);
+        assert_eq!(reg(XT_REG_AR_START + 1), 0x3ffb_1000);
+    }
+
+    #[test]
+    fn extracts_elf_core_dumps() {
+        let mut body = b"\x7fELF".to_vec();
+        body.resize(64, 0);
+
+        let data = with_header((9 << 16) | 0x0103, &[0, 0, 0], &body, Checksum::Sha256);
+        let core_dump = CoreDump::parse(&data).unwrap();
+        assert_eq!(core_dump.format(), CoreDumpFormat::Elf);
+        assert_eq!(core_dump.chip_id, 9);
+        assert_eq!(core_dump.to_elf().unwrap(), body);
+    }
+
+    #[test]
+    fn rejects_invalid_core_dumps() {
+        assert!(matches!(
+            CoreDump::parse(&[0xff; 64]),
+            Err(Error::CoreDumpNotFound)
+        ));
+
+        let mut data = binary_core_dump();
+        data[30] ^= 1;
+        assert!(matches!(
+            CoreDump::parse(&data),
+            Err(Error::InvalidCoreDump(_))
+        ));
+
+        // Far more tasks than the core dump holds
+        let data = with_header(0x0002, &[u32::MAX, 6, 0], &[], Checksum::Crc32);
+        assert!(matches!(
+            CoreDump::parse(&data),
+            Err(Error::InvalidCoreDump(_))
+        ));
+    }
+}
@@ -0,0 +1,1070 @@
+//! eFuse programming
+//!
+//! eFuses are one-time programmable bits holding a chip's configuration and
+//! keys. Bits can only ever be changed from 0 to 1, and the blocks other than
+//! block 0 are protected by a Reed-Solomon code, so each of them can only be
+//! written once.
+//!
+//! Block 0 holds the configuration, including the read and write protection of
+//! every other field and block, and the purposes of the six key blocks. Key
+//! block `n` is eFuse block `4 + n`.
+//!
+//! Programming is supported for the chips sharing the eFuse controller of the
+//! ESP32-C3: the ESP32-C3, ESP32-C6, ESP32-H2 and ESP32-S3.
+
+use std::fmt;
+
+use strum::{Display, EnumString, FromRepr, VariantNames};
+
+use crate::error::Error;
+#[cfg(feature = "serialport")]
+use crate::{connection::Connection, targets::Chip};
+
+/// Number of key blocks
+pub const KEY_BLOCK_COUNT: u8 = 6;
+/// Length of the key blocks
+pub const KEY_LEN: usize = 32;
+
+/// Number of eFuse blocks
+pub const BLOCK_COUNT: u8 = 11;
+/// Names of the eFuse blocks, as used by espefuse
+const BLOCK_NAMES: [&str; BLOCK_COUNT as usize] = [
+    "BLOCK0",
+    "BLOCK1",
+    "BLOCK_SYS_DATA",
+    "BLOCK_USR_DATA",
+    "BLOCK_KEY0",
+    "BLOCK_KEY1",
+    "BLOCK_KEY2",
+    "BLOCK_KEY3",
+    "BLOCK_KEY4",
+    "BLOCK_KEY5",
+    "BLOCK_SYS_DATA2",
+];
+
+/// Length of a MAC address
+pub const MAC_LEN: usize = 6;
+
+/// eFuse block holding the factory MAC address
+const MAC_BLOCK: u8 = 1;
+/// eFuse block holding user data, including the custom MAC address
+const USR_DATA_BLOCK: u8 = 3;
+/// Byte offset of `CUSTOM_MAC` in `BLOCK_USR_DATA`
+const CUSTOM_MAC_OFFSET: usize = 25;
+/// eFuse block of the first key block
+const FIRST_KEY_BLOCK: u8 = 4;
+/// Number of 32-bit words in each block, other than block 0
+const BLOCK_WORDS: usize = 8;
+/// Number of 32-bit words in block 0
+const BLOCK0_WORDS: usize = 6;
+
+/// Word of block 0 holding the write protection bits
+const WR_DIS: usize = 0;
+const RD_DIS: Field = Field::new(1, 0, 7);
+const SPI_BOOT_CRYPT_CNT: Field = Field::new(2, 18, 3);
+const KEY_PURPOSES: [Field; KEY_BLOCK_COUNT as usize] = [
+    Field::new(2, 24, 4),
+    Field::new(2, 28, 4),
+    Field::new(3, 0, 4),
+    Field::new(3, 4, 4),
+    Field::new(3, 8, 4),
+    Field::new(3, 12, 4),
+];
+
+/// The fields of block 0 which are reported when they change
+const BLOCK0_FIELDS: [(&str, Field); 9] = [
+    ("WR_DIS", Field::new(WR_DIS, 0, 32)),
+    ("RD_DIS", RD_DIS),
+    ("SPI_BOOT_CRYPT_CNT", SPI_BOOT_CRYPT_CNT),
+    ("KEY_PURPOSE_0", KEY_PURPOSES[0]),
+    ("KEY_PURPOSE_1", KEY_PURPOSES[1]),
+    ("KEY_PURPOSE_2", KEY_PURPOSES[2]),
+    ("KEY_PURPOSE_3", KEY_PURPOSES[3]),
+    ("KEY_PURPOSE_4", KEY_PURPOSES[4]),
+    ("KEY_PURPOSE_5", KEY_PURPOSES[5]),
+];
+
+/// Write protection bit of `SPI_BOOT_CRYPT_CNT`
+const WR_DIS_SPI_BOOT_CRYPT_CNT: u32 = 4;
+/// Write protection bit of the purpose of the first key block
+const WR_DIS_KEY_PURPOSE: u32 = 8;
+/// Write protection bit of `RD_DIS`
+const WR_DIS_RD_DIS: u32 = 0;
+/// Write protection bit of `BLOCK1`, followed by those of the other blocks
+const WR_DIS_BLOCK1: u32 = 20;
+
+/// Purpose of a key block, as named by ESP-IDF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, FromRepr, VariantNames)]
+#[non_exhaustive]
+#[repr(u8)]
+#[strum(ascii_case_insensitive)]
+pub enum KeyPurpose {
+    #[strum(serialize = "USER")]
+    User = 0,
+    #[strum(serialize = "RESERVED")]
+    Reserved = 1,
+    #[strum(serialize = "XTS_AES_256_KEY_1")]
+    XtsAes256Key1 = 2,
+    #[strum(serialize = "XTS_AES_256_KEY_2")]
+    XtsAes256Key2 = 3,
+    #[strum(serialize = "XTS_AES_128_KEY")]
+    XtsAes128Key = 4,
+    #[strum(serialize = "HMAC_DOWN_ALL")]
+    HmacDownAll = 5,
+    #[strum(serialize = "HMAC_DOWN_JTAG")]
+    HmacDownJtag = 6,
+    #[strum(serialize = "HMAC_DOWN_DIGITAL_SIGNATURE")]
+    HmacDownDigitalSignature = 7,
+    #[strum(serialize = "HMAC_UP")]
+    HmacUp = 8,
+    #[strum(serialize = "SECURE_BOOT_DIGEST0")]
+    SecureBootDigest0 = 9,
+    #[strum(serialize = "SECURE_BOOT_DIGEST1")]
+    SecureBootDigest1 = 10,
+    #[strum(serialize = "SECURE_BOOT_DIGEST2")]
+    SecureBootDigest2 = 11,
+}
+
+impl KeyPurpose {
+    /// Whether keys of this purpose are burned in reverse byte order, as the
+    /// flash encryption hardware expects
+    pub fn is_reversed(&self) -> bool {
+        matches!(
+            self,
+            KeyPurpose::XtsAes128Key | KeyPurpose::XtsAes256Key1 | KeyPurpose::XtsAes256Key2
+        )
+    }
+
+    /// Whether keys of this purpose must remain readable, as the Secure Boot
+    /// digests are read by the bootloader rather than by the hardware
+    pub fn must_be_readable(&self) -> bool {
+        matches!(
+            self,
+            KeyPurpose::SecureBootDigest0
+                | KeyPurpose::SecureBootDigest1
+                | KeyPurpose::SecureBootDigest2
+        )
+    }
+}
+
+/// A field of block 0
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    word: usize,
+    shift: u32,
+    width: u32,
+}
+
+impl Field {
+    const fn new(word: usize, shift: u32, width: u32) -> Self {
+        Self { word, shift, width }
+    }
+
+    fn mask(&self) -> u32 {
+        (u32::MAX >> (32 - self.width)) << self.shift
+    }
+
+    fn get(&self, block: &[u32]) -> u32 {
+        (block[self.word] & self.mask()) >> self.shift
+    }
+
+    fn set(&self, block: &mut [u32], value: u32) {
+        block[self.word] |= (value << self.shift) & self.mask();
+    }
+}
+
+/// Access to the eFuse blocks of a chip
+pub trait EfuseBackend {
+    /// Read the words of an eFuse block
+    fn read_block(&mut self, block: u8) -> Result<Vec<u32>, Error>;
+
+    /// Program the bits which are set in `data` in an eFuse block
+    ///
+    /// The data of blocks other than block 0 must cover the whole block, as
+    /// its Reed-Solomon code is computed from it.
+    fn burn_block(&mut self, block: u8, data: &[u32]) -> Result<(), Error>;
+}
+
+impl<B: EfuseBackend + ?Sized> EfuseBackend for &mut B {
+    fn read_block(&mut self, block: u8) -> Result<Vec<u32>, Error> {
+        (**self).read_block(block)
+    }
+
+    fn burn_block(&mut self, block: u8, data: &[u32]) -> Result<(), Error> {
+        (**self).burn_block(block, data)
+    }
+}
+
+/// The eFuses of a chip
+#[derive(Debug)]
+pub struct Efuses<B> {
+    backend: B,
+}
+
+impl<B: EfuseBackend> Efuses<B> {
+    pub fn new(backend: B) -> Self {
+        Self { backend }
+    }
+
+    /// The backend the eFuses are accessed through
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Read all eFuse blocks, to compare them with [`diff`] after burning
+    pub fn read_all(&mut self) -> Result<Vec<Vec<u32>>, Error> {
+        (0..BLOCK_COUNT)
+            .map(|block| self.backend.read_block(block))
+            .collect()
+    }
+
+    /// Value of `SPI_BOOT_CRYPT_CNT`, which enables flash encryption when an
+    /// odd number of its bits are set
+    pub fn spi_boot_crypt_cnt(&mut self) -> Result<u8, Error> {
+        let block0 = self.backend.read_block(0)?;
+
+        Ok(SPI_BOOT_CRYPT_CNT.get(&block0) as u8)
+    }
+
+    /// Whether flash encryption is enabled
+    pub fn flash_encryption_enabled(&mut self) -> Result<bool, Error> {
+        Ok(self.spi_boot_crypt_cnt()?.count_ones() % 2 == 1)
+    }
+
+    /// Purpose of a key block
+    pub fn key_purpose(&mut self, key: u8) -> Result<KeyPurpose, Error> {
+        check_key_block(key)?;
+        let block0 = self.backend.read_block(0)?;
+        let purpose = KEY_PURPOSES[key as usize].get(&block0) as u8;
+
+        KeyPurpose::from_repr(purpose).ok_or_else(|| {
+            Error::InvalidEfuseOperation(format!(
+                "key block {key} has the unknown purpose {purpose}"
+            ))
+        })
+    }
+
+    /// Whether a key block is unused, that is empty, without a purpose and
+    /// not protected
+    pub fn key_block_unused(&mut self, key: u8) -> Result<bool, Error> {
+        check_key_block(key)?;
+        let block0 = self.backend.read_block(0)?;
+        let data = self.backend.read_block(FIRST_KEY_BLOCK + key)?;
+
+        let protected = block0[WR_DIS] & (key_block_wr_dis(key) | key_purpose_wr_dis(key)) != 0
+            || RD_DIS.get(&block0) & (1 << key) != 0;
+
+        Ok(!protected
+            && KEY_PURPOSES[key as usize].get(&block0) == KeyPurpose::User as u32
+            && data.iter().all(|word| *word == 0))
+    }
+
+    /// The first unused key block, if any
+    pub fn unused_key_block(&mut self) -> Result<Option<u8>, Error> {
+        for key in 0..KEY_BLOCK_COUNT {
+            if self.key_block_unused(key)? {
+                return Ok(Some(key));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Burn a key to an unused key block, setting its purpose and write
+    /// protecting both, and read protecting the key if requested
+    ///
+    /// The key is given in the byte order used by ESP-IDF's key files, and is
+    /// reversed if its purpose requires it.
+    pub fn burn_key(
+        &mut self,
+        key: u8,
+        data: &[u8; KEY_LEN],
+        purpose: KeyPurpose,
+        read_protect: bool,
+    ) -> Result<(), Error> {
+        if purpose == KeyPurpose::Reserved {
+            return Err(Error::InvalidEfuseOperation(
+                "keys cannot be burned with the RESERVED purpose".into(),
+            ));
+        }
+        if read_protect && purpose.must_be_readable() {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "{purpose} keys must remain readable, and cannot be read protected"
+            )));
+        }
+        if !self.key_block_unused(key)? {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "key block {key} is already in use"
+            )));
+        }
+
+        let mut data = *data;
+        if purpose.is_reversed() {
+            data.reverse();
+        }
+        let words = data
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        self.backend.burn_block(FIRST_KEY_BLOCK + key, &words)?;
+        if self.backend.read_block(FIRST_KEY_BLOCK + key)? != words {
+            return Err(Error::EfuseBurnFailed(format!(
+                "key block {key} does not hold the key after burning it"
+            )));
+        }
+
+        let mut block0 = vec![0; BLOCK0_WORDS];
+        KEY_PURPOSES[key as usize].set(&mut block0, purpose as u32);
+        block0[WR_DIS] |= key_block_wr_dis(key) | key_purpose_wr_dis(key);
+        if read_protect {
+            RD_DIS.set(&mut block0, 1 << key);
+        }
+        self.burn_block0(&block0)?;
+
+        if self.key_purpose(key)? != purpose {
+            return Err(Error::EfuseBurnFailed(format!(
+                "the purpose of key block {key} was not set"
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The MAC address burned into the chip at the factory
+    pub fn factory_mac(&mut self) -> Result<[u8; MAC_LEN], Error> {
+        let block = self.backend.read_block(MAC_BLOCK)?;
+        let bytes = (((block[1] as u64) << 32) | block[0] as u64).to_be_bytes();
+
+        Ok(bytes[2..].try_into().unwrap())
+    }
+
+    /// The custom MAC address, if one has been burned
+    pub fn custom_mac(&mut self) -> Result<Option<[u8; MAC_LEN]>, Error> {
+        let block = block_bytes(&self.backend.read_block(USR_DATA_BLOCK)?);
+        let mac: [u8; MAC_LEN] = block[CUSTOM_MAC_OFFSET..][..MAC_LEN].try_into().unwrap();
+
+        Ok(mac.iter().any(|byte| *byte != 0).then_some(mac))
+    }
+
+    /// Burn a custom MAC address to `CUSTOM_MAC`, for applications to use as
+    /// their base MAC address instead of the factory one
+    ///
+    /// `CUSTOM_MAC` is part of `BLOCK_USR_DATA`, which is protected by a
+    /// Reed-Solomon code rather than the CRC used on the ESP32, and so can
+    /// only be burned while the rest of the block is empty.
+    pub fn burn_custom_mac(&mut self, mac: &[u8; MAC_LEN]) -> Result<(), Error> {
+        if mac.iter().all(|byte| *byte == 0) || mac[0] & 1 != 0 {
+            return Err(Error::InvalidEfuseOperation(
+                "the custom MAC address must be a non-zero unicast address".into(),
+            ));
+        }
+        if self.custom_mac()?.is_some() {
+            return Err(Error::InvalidEfuseOperation(
+                "a custom MAC address has already been burned".into(),
+            ));
+        }
+
+        let mut block = block_bytes(&self.backend.read_block(USR_DATA_BLOCK)?);
+        if block.iter().any(|byte| *byte != 0) {
+            return Err(Error::InvalidEfuseOperation(
+                "BLOCK_USR_DATA already holds user data, so a custom MAC address can no longer \
+                 be burned"
+                    .into(),
+            ));
+        }
+
+        block[CUSTOM_MAC_OFFSET..][..MAC_LEN].copy_from_slice(mac);
+        let words = block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        self.backend.burn_block(USR_DATA_BLOCK, &words)?;
+
+        if self.custom_mac()? != Some(*mac) {
+            return Err(Error::EfuseBurnFailed(
+                "CUSTOM_MAC does not hold the MAC address after burning it".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether a block is write protected
+    pub fn write_protected(&mut self, block: u8) -> Result<bool, Error> {
+        let bit = block_wr_dis(block).ok_or_else(|| {
+            Error::InvalidEfuseOperation(format!("{} cannot be write protected", block_name(block)))
+        })?;
+
+        Ok(self.backend.read_block(0)?[WR_DIS] & bit != 0)
+    }
+
+    /// Whether a block is read protected
+    pub fn read_protected(&mut self, block: u8) -> Result<bool, Error> {
+        let bit = block_rd_dis(block).ok_or_else(|| {
+            Error::InvalidEfuseOperation(format!("{} cannot be read protected", block_name(block)))
+        })?;
+
+        Ok(RD_DIS.get(&self.backend.read_block(0)?) & bit != 0)
+    }
+
+    /// Write protect a block, so that none of its remaining bits can be burned
+    pub fn write_protect(&mut self, block: u8) -> Result<(), Error> {
+        if self.write_protected(block)? {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "{} is already write protected",
+                block_name(block)
+            )));
+        }
+
+        let mut block0 = vec![0; BLOCK0_WORDS];
+        block0[WR_DIS] = block_wr_dis(block).unwrap();
+        self.burn_block0(&block0)?;
+
+        if !self.write_protected(block)? {
+            return Err(Error::EfuseBurnFailed(format!(
+                "{} is not write protected after burning WR_DIS",
+                block_name(block)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Read protect a block, so that it can only be used by the hardware
+    ///
+    /// Secure Boot digests are read by the bootloader, so key blocks holding
+    /// them cannot be read protected.
+    pub fn read_protect(&mut self, block: u8) -> Result<(), Error> {
+        if self.read_protected(block)? {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "{} is already read protected",
+                block_name(block)
+            )));
+        }
+        if self.backend.read_block(0)?[WR_DIS] & (1 << WR_DIS_RD_DIS) != 0 {
+            return Err(Error::InvalidEfuseOperation(
+                "RD_DIS is write protected, so no more blocks can be read protected".into(),
+            ));
+        }
+        if block < FIRST_KEY_BLOCK + KEY_BLOCK_COUNT
+            && self
+                .key_purpose(block - FIRST_KEY_BLOCK)?
+                .must_be_readable()
+        {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "{} holds a Secure Boot digest, which must remain readable",
+                block_name(block)
+            )));
+        }
+
+        let mut block0 = vec![0; BLOCK0_WORDS];
+        RD_DIS.set(&mut block0, block_rd_dis(block).unwrap());
+        self.burn_block0(&block0)?;
+
+        if !self.read_protected(block)? {
+            return Err(Error::EfuseBurnFailed(format!(
+                "{} is not read protected after burning RD_DIS",
+                block_name(block)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Enable flash encryption, by setting one bit of `SPI_BOOT_CRYPT_CNT`
+    ///
+    /// In release mode, all its bits are set and it is write protected, so that
+    /// flash encryption can never be disabled.
+    pub fn enable_flash_encryption(&mut self, release: bool) -> Result<(), Error> {
+        if self.flash_encryption_enabled()? {
+            return Err(Error::InvalidEfuseOperation(
+                "flash encryption is already enabled".into(),
+            ));
+        }
+
+        let count = self.spi_boot_crypt_cnt()? as u32;
+        let value = if release {
+            SPI_BOOT_CRYPT_CNT.mask() >> SPI_BOOT_CRYPT_CNT.shift
+        } else {
+            // Set the lowest bit which is not set yet
+            count | (count + 1)
+        };
+
+        let mut block0 = vec![0; BLOCK0_WORDS];
+        SPI_BOOT_CRYPT_CNT.set(&mut block0, value);
+        if release {
+            block0[WR_DIS] |= 1 << WR_DIS_SPI_BOOT_CRYPT_CNT;
+        }
+        self.burn_block0(&block0)?;
+
+        if !self.flash_encryption_enabled()? {
+            return Err(Error::EfuseBurnFailed(
+                "flash encryption is not enabled after burning SPI_BOOT_CRYPT_CNT".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Burn the bits of block 0 which are not set yet
+    fn burn_block0(&mut self, data: &[u32]) -> Result<(), Error> {
+        let current = self.backend.read_block(0)?;
+        let data = data
+            .iter()
+            .zip(&current)
+            .map(|(new, current)| new & !current)
+            .collect::<Vec<_>>();
+
+        if data.iter().any(|word| *word != 0) {
+            self.backend.burn_block(0, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn check_key_block(key: u8) -> Result<(), Error> {
+    if key >= KEY_BLOCK_COUNT {
+        return Err(Error::InvalidEfuseOperation(format!(
+            "there is no key block {key}, the key blocks are numbered 0 to {}",
+            KEY_BLOCK_COUNT - 1
+        )));
+    }
+
+    Ok(())
+}
+
+fn key_block_wr_dis(key: u8) -> u32 {
+    block_wr_dis(FIRST_KEY_BLOCK + key).unwrap()
+}
+
+/// Write protection bit of a block, block 0 being protected field by field
+fn block_wr_dis(block: u8) -> Option<u32> {
+    (1..BLOCK_COUNT)
+        .contains(&block)
+        .then(|| 1 << (WR_DIS_BLOCK1 + block as u32 - 1))
+}
+
+/// Read protection bit of a block, only the key blocks and `BLOCK_SYS_DATA2`
+/// can be read protected
+fn block_rd_dis(block: u8) -> Option<u32> {
+    (FIRST_KEY_BLOCK..BLOCK_COUNT)
+        .contains(&block)
+        .then(|| 1 << (block - FIRST_KEY_BLOCK))
+}
+
+/// Number of an eFuse block, given either its name or number
+pub fn parse_block(name: &str) -> Option<u8> {
+    BLOCK_NAMES
+        .iter()
+        .position(|block| block.eq_ignore_ascii_case(name))
+        .map(|block| block as u8)
+        .or_else(|| name.parse().ok().filter(|block| *block < BLOCK_COUNT))
+}
+
+/// Name of an eFuse block
+pub fn block_name(block: u8) -> &'static str {
+    BLOCK_NAMES
+        .get(block as usize)
+        .copied()
+        .unwrap_or("unknown block")
+}
+
+fn key_purpose_wr_dis(key: u8) -> u32 {
+    1 << (WR_DIS_KEY_PURPOSE + key as u32)
+}
+
+/// eFuses held in memory, such as a local eFuse image used to rehearse burning
+/// eFuses without modifying a device
+///
+/// Images hold the blocks in order, as little-endian words: 24 bytes for block
+/// 0, followed by 32 bytes for each of the other blocks. As on a device, blocks
+/// other than block 0 can only be written once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualEfuses {
+    blocks: Vec<Vec<u32>>,
+}
+
+impl VirtualEfuses {
+    /// eFuses which have not been burned
+    pub fn blank() -> Self {
+        Self {
+            blocks: (0..BLOCK_COUNT)
+                .map(|block| vec![0; block_words(block)])
+                .collect(),
+        }
+    }
+
+    /// Load eFuses from an eFuse image
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let size = (BLOCK0_WORDS + (BLOCK_COUNT as usize - 1) * BLOCK_WORDS) * 4;
+        if data.len() != size {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "eFuse images must be {size} bytes long, not {}",
+                data.len()
+            )));
+        }
+
+        let mut words = data
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        let blocks = (0..BLOCK_COUNT)
+            .map(|block| words.by_ref().take(block_words(block)).collect())
+            .collect();
+
+        Ok(Self { blocks })
+    }
+
+    /// Save the eFuses as an eFuse image
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.blocks
+            .iter()
+            .flatten()
+            .flat_map(|word| word.to_le_bytes())
+            .collect()
+    }
+}
+
+impl EfuseBackend for VirtualEfuses {
+    fn read_block(&mut self, block: u8) -> Result<Vec<u32>, Error> {
+        let words = self.blocks.get(block as usize).cloned();
+
+        words
+            .ok_or_else(|| Error::InvalidEfuseOperation(format!("there is no eFuse block {block}")))
+    }
+
+    fn burn_block(&mut self, block: u8, data: &[u32]) -> Result<(), Error> {
+        let current = self.read_block(block)?;
+        if block != 0 && current.iter().any(|word| *word != 0) {
+            return Err(Error::EfuseBurnFailed(format!(
+                "{} has already been written, and is protected by a Reed-Solomon code",
+                block_name(block)
+            )));
+        }
+
+        for (word, new) in self.blocks[block as usize].iter_mut().zip(data) {
+            *word |= new;
+        }
+
+        Ok(())
+    }
+}
+
+/// Value of an eFuse field or block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EfuseValue {
+    Field(u32),
+    Block(Vec<u32>),
+}
+
+impl fmt::Display for EfuseValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EfuseValue::Field(value) => write!(f, "{value:#x}"),
+            EfuseValue::Block(words) => {
+                for byte in words.iter().flat_map(|word| word.to_le_bytes()) {
+                    write!(f, "{byte:02x}")?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A change made to the eFuses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfuseChange {
+    /// Name of the field or block
+    pub name: String,
+    pub old: EfuseValue,
+    pub new: EfuseValue,
+}
+
+/// Compare the eFuse blocks read before and after burning eFuses
+///
+/// The fields of block 0 are reported by name, and the other blocks as a whole.
+pub fn diff(before: &[Vec<u32>], after: &[Vec<u32>]) -> Vec<EfuseChange> {
+    let mut changes = Vec::new();
+
+    let (block0_before, block0_after) = (&before[0], &after[0]);
+    let mut reported = [0u32; BLOCK0_WORDS];
+    for (name, field) in BLOCK0_FIELDS {
+        reported[field.word] |= field.mask();
+
+        let (old, new) = (field.get(block0_before), field.get(block0_after));
+        if old != new {
+            changes.push(EfuseChange {
+                name: name.to_string(),
+                old: EfuseValue::Field(old),
+                new: EfuseValue::Field(new),
+            });
+        }
+    }
+    for (word, mask) in reported.iter().enumerate() {
+        let (old, new) = (block0_before[word] & !mask, block0_after[word] & !mask);
+        if old != new {
+            changes.push(EfuseChange {
+                name: format!("BLOCK0 word {word}"),
+                old: EfuseValue::Field(old),
+                new: EfuseValue::Field(new),
+            });
+        }
+    }
+
+    for (block, (old, new)) in before.iter().zip(after).enumerate().skip(1) {
+        if old != new {
+            changes.push(EfuseChange {
+                name: BLOCK_NAMES[block].to_string(),
+                old: EfuseValue::Block(old.clone()),
+                new: EfuseValue::Block(new.clone()),
+            });
+        }
+    }
+
+    changes
+}
+
+/// Bytes of a block, in the order they are burned
+fn block_bytes(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Number of words in a block
+fn block_words(block: u8) -> usize {
+    if block == 0 {
+        BLOCK0_WORDS
+    } else {
+        BLOCK_WORDS
+    }
+}
+
+/// Compute the 12 byte Reed-Solomon code protecting a block
+///
+/// The code is computed over GF(2^8) with the primitive polynomial 0x11d, as
+/// `reedsolo.RSCodec(12)` does in espefuse.
+fn rs_encode(data: &[u8]) -> [u8; 12] {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x = 1u16;
+    for (i, value) in exp.iter_mut().enumerate().take(255) {
+        *value = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+    }
+    // Repeat the table, so that sums of logarithms can be looked up directly
+    exp.copy_within(..255, 255);
+    exp.copy_within(..2, 510);
+
+    let mul = |a: u8, b: u8| {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            exp[log[a as usize] as usize + log[b as usize] as usize]
+        }
+    };
+
+    // The generator polynomial, with the coefficient of the highest degree first
+    let mut generator = vec![1u8];
+    for root in &exp[..12] {
+        let mut next = vec![0; generator.len() + 1];
+        for (i, coefficient) in generator.iter().enumerate() {
+            next[i] ^= coefficient;
+            next[i + 1] ^= mul(*coefficient, *root);
+        }
+        generator = next;
+    }
+
+    let mut remainder = [0u8; 12];
+    for byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.rotate_left(1);
+        remainder[11] = 0;
+        for (r, g) in remainder.iter_mut().zip(&generator[1..]) {
+            *r ^= mul(*g, factor);
+        }
+    }
+
+    remainder
+}
+
+/// Registers of the eFuse controller, relative to its base address
+#[cfg(feature = "serialport")]
+mod regs {
+    pub const PGM_DATA: u32 = 0x000;
+    /// Address each block is read from, starting with the write protection
+    /// bits in block 0
+    pub const BLOCKS: [u32; 11] = [
+        0x02c, 0x044, 0x05c, 0x07c, 0x09c, 0x0bc, 0x0dc, 0x0fc, 0x11c, 0x13c, 0x15c,
+    ];
+    pub const CONF: u32 = 0x1cc;
+    pub const CMD: u32 = 0x1d4;
+    pub const DAC_CONF: u32 = 0x1e8;
+
+    pub const WRITE_OP_CODE: u32 = 0x5a5a;
+    pub const READ_OP_CODE: u32 = 0x5aa5;
+    pub const READ_CMD: u32 = 0x1;
+    pub const PGM_CMD: u32 = 0x2;
+}
+
+/// The eFuses of a connected device
+#[cfg(feature = "serialport")]
+#[derive(Debug)]
+pub struct DeviceEfuses<'a> {
+    connection: &'a mut Connection,
+    base: u32,
+    /// Offsets of the `WR_TIM_CONF1` and `WR_TIM_CONF2` registers
+    timing_regs: (u32, u32),
+}
+
+#[cfg(feature = "serialport")]
+impl<'a> DeviceEfuses<'a> {
+    /// Access the eFuses of a connected device, if programming them is
+    /// supported for the chip
+    #[allow(unreachable_patterns)]
+    pub fn new(connection: &'a mut Connection, chip: Chip) -> Result<Self, Error> {
+        let timing_regs = match chip {
+            #[cfg(feature = "esp32c3")]
+            Chip::Esp32c3 => (0x1f4, 0x1f8),
+            #[cfg(feature = "esp32s3")]
+            Chip::Esp32s3 => (0x1f4, 0x1f8),
+            #[cfg(feature = "esp32c6")]
+            Chip::Esp32c6 => (0x1f0, 0x1f4),
+            #[cfg(feature = "esp32h2")]
+            Chip::Esp32h2 => (0x1f0, 0x1f4),
+            _ => {
+                return Err(Error::UnsupportedFeature {
+                    chip,
+                    feature: "eFuse programming".into(),
+                })
+            }
+        };
+
+        Ok(Self {
+            connection,
+            base: chip.into_target().efuse_reg(),
+            timing_regs,
+        })
+    }
+
+    fn update_reg(&mut self, reg: u32, mask: u32, value: u32) -> Result<(), Error> {
+        let addr = self.base + reg;
+        let current = self.connection.read_reg(addr)?;
+        let shift = mask.trailing_zeros();
+
+        self.connection
+            .write_reg(addr, (current & !mask) | ((value << shift) & mask), None)
+    }
+
+    /// Run a command, waiting for the controller to complete it
+    fn command(&mut self, op_code: u32, cmd: u32) -> Result<(), Error> {
+        self.connection
+            .write_reg(self.base + regs::CONF, op_code, None)?;
+        self.connection
+            .write_reg(self.base + regs::CMD, cmd, None)?;
+
+        for _ in 0..100 {
+            if self.connection.read_reg(self.base + regs::CMD)? & 0x3 == 0 {
+                return Ok(());
+            }
+        }
+
+        Err(Error::EfuseBurnFailed(
+            "the eFuse controller did not complete the command".into(),
+        ))
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl EfuseBackend for DeviceEfuses<'_> {
+    fn read_block(&mut self, block: u8) -> Result<Vec<u32>, Error> {
+        let addr = *regs::BLOCKS.get(block as usize).ok_or_else(|| {
+            Error::InvalidEfuseOperation(format!("there is no eFuse block {block}"))
+        })?;
+
+        (0..block_words(block) as u32)
+            .map(|word| self.connection.read_reg(self.base + addr + word * 4))
+            .collect()
+    }
+
+    fn burn_block(&mut self, block: u8, data: &[u32]) -> Result<(), Error> {
+        if block as usize >= regs::BLOCKS.len() || data.len() > block_words(block) {
+            return Err(Error::InvalidEfuseOperation(format!(
+                "invalid data for eFuse block {block}"
+            )));
+        }
+
+        // The data is followed by the Reed-Solomon code of the block
+        let mut words = [0u32; BLOCK_WORDS + 3];
+        words[..data.len()].copy_from_slice(data);
+        if block != 0 {
+            let bytes = words[..BLOCK_WORDS]
+                .iter()
+                .flat_map(|word| word.to_le_bytes())
+                .collect::<Vec<_>>();
+            for (word, code) in words[BLOCK_WORDS..]
+                .iter_mut()
+                .zip(rs_encode(&bytes).chunks(4))
+            {
+                *word = u32::from_le_bytes(code.try_into().unwrap());
+            }
+        }
+
+        // Programming timing for a 40 MHz APB clock, as set by espefuse
+        let (wr_tim_conf1, wr_tim_conf2) = self.timing_regs;
+        self.update_reg(regs::DAC_CONF, 0xff << 9, 0xff)?;
+        self.update_reg(regs::DAC_CONF, 0xff, 0x28)?;
+        self.update_reg(wr_tim_conf1, 0xffff << 8, 0x3000)?;
+        self.update_reg(wr_tim_conf2, 0xffff, 0x190)?;
+
+        for (index, word) in words.iter().enumerate() {
+            self.connection.write_reg(
+                self.base + regs::PGM_DATA + index as u32 * 4,
+                *word,
+                None,
+            )?;
+        }
+        self.command(regs::WRITE_OP_CODE, regs::PGM_CMD | ((block as u32) << 2))?;
+
+        for index in 0..words.len() as u32 {
+            self.connection
+                .write_reg(self.base + regs::PGM_DATA + index * 4, 0, None)?;
+        }
+
+        // Reload the eFuses, so that the new values can be read
+        self.command(regs::READ_OP_CODE, regs::READ_CMD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_reed_solomon_code() {
+        let data = (0..32).collect::<Vec<u8>>();
+        let code = rs_encode(&data);
+
+        assert_eq!(
+            code,
+            [0xa0, 0x4c, 0x47, 0x0d, 0x3f, 0xfc, 0xb2, 0x03, 0xda, 0xe9, 0xf4, 0x13]
+        );
+    }
+
+    #[test]
+    fn burns_keys() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        let key = std::array::from_fn(|i| i as u8);
+
+        assert_eq!(efuses.unused_key_block().unwrap(), Some(0));
+        efuses
+            .burn_key(0, &key, KeyPurpose::XtsAes128Key, true)
+            .unwrap();
+
+        let blocks = &efuses.backend().blocks;
+        // The key is burned in reverse byte order
+        assert_eq!(blocks[4][0], 0x1c1d_1e1f);
+        assert_eq!(RD_DIS.get(&blocks[0]), 1);
+        assert_eq!(blocks[0][WR_DIS], 1 << 23 | 1 << 8);
+
+        assert_eq!(efuses.key_purpose(0).unwrap(), KeyPurpose::XtsAes128Key);
+        assert_eq!(efuses.unused_key_block().unwrap(), Some(1));
+        assert!(efuses.burn_key(0, &key, KeyPurpose::HmacUp, false).is_err());
+
+        assert!(efuses
+            .burn_key(1, &key, KeyPurpose::SecureBootDigest0, true)
+            .is_err());
+        efuses
+            .burn_key(1, &key, KeyPurpose::SecureBootDigest0, false)
+            .unwrap();
+        // Secure Boot digests are not reversed
+        assert_eq!(efuses.backend().blocks[5][0], 0x0302_0100);
+    }
+
+    #[test]
+    fn enables_flash_encryption() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        assert!(!efuses.flash_encryption_enabled().unwrap());
+
+        efuses.enable_flash_encryption(false).unwrap();
+        assert_eq!(efuses.spi_boot_crypt_cnt().unwrap(), 0b001);
+        assert!(efuses.enable_flash_encryption(false).is_err());
+
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        efuses.enable_flash_encryption(true).unwrap();
+        assert_eq!(efuses.spi_boot_crypt_cnt().unwrap(), 0b111);
+        assert_eq!(
+            efuses.backend().blocks[0][WR_DIS],
+            1 << WR_DIS_SPI_BOOT_CRYPT_CNT
+        );
+    }
+
+    #[test]
+    fn burns_custom_macs() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        let mac = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+        assert_eq!(efuses.custom_mac().unwrap(), None);
+        assert!(efuses.burn_custom_mac(&[0x01, 0, 0, 0, 0, 0]).is_err());
+
+        efuses.burn_custom_mac(&mac).unwrap();
+        assert_eq!(efuses.custom_mac().unwrap(), Some(mac));
+        assert_eq!(efuses.backend().blocks[3][6], 0x2211_0200);
+        assert!(efuses.burn_custom_mac(&mac).is_err());
+    }
+
+    #[test]
+    fn protects_blocks() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+
+        assert_eq!(parse_block("block_usr_data"), Some(3));
+        assert_eq!(parse_block("10"), Some(10));
+        assert_eq!(parse_block("11"), None);
+
+        efuses.write_protect(3).unwrap();
+        assert!(efuses.write_protected(3).unwrap());
+        assert_eq!(efuses.backend().blocks[0][WR_DIS], 1 << 22);
+        assert!(efuses.write_protect(3).is_err());
+        assert!(efuses.write_protect(0).is_err());
+
+        efuses.read_protect(10).unwrap();
+        assert!(efuses.read_protected(10).unwrap());
+        assert!(efuses.read_protect(3).is_err());
+
+        efuses
+            .burn_key(0, &[1; KEY_LEN], KeyPurpose::SecureBootDigest0, false)
+            .unwrap();
+        assert!(efuses.read_protect(4).is_err());
+    }
+
+    #[test]
+    fn reports_changes() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        let before = efuses.read_all().unwrap();
+        efuses
+            .burn_key(1, &[0xff; KEY_LEN], KeyPurpose::XtsAes128Key, true)
+            .unwrap();
+        let after = efuses.read_all().unwrap();
+
+        let names = diff(&before, &after)
+            .into_iter()
+            .map(|change| change.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["WR_DIS", "RD_DIS", "KEY_PURPOSE_1", "BLOCK_KEY1"]);
+    }
+
+    #[test]
+    fn round_trips_virtual_efuses() {
+        let mut efuses = Efuses::new(VirtualEfuses::blank());
+        efuses.enable_flash_encryption(false).unwrap();
+
+        let data = efuses.backend().to_bytes();
+        assert_eq!(data.len(), 344);
+        assert_eq!(VirtualEfuses::from_bytes(&data).unwrap(), *efuses.backend());
+        assert!(VirtualEfuses::from_bytes(&data[1..]).is_err());
+    }
+}
@@ -5,17 +5,22 @@ use std::{
     cmp::Ordering,
     fmt::{Debug, Formatter},
     mem::take,
-    ops::AddAssign,
+    ops::{AddAssign, Range},
 };
 
+use log::warn;
+use serde::{Deserialize, Serialize};
+use strum::{Display, IntoEnumIterator};
 use xmas_elf::{
     program::Type,
-    sections::{SectionData, ShType},
+    sections::{SectionData, SectionHeader, ShType},
+    symbol_table::Entry,
     ElfFile,
 };
 
 use crate::{
     error::{ElfError, Error},
+    image_format::TargetOs,
     targets::Chip,
 };
 
@@ -45,16 +50,111 @@ pub trait FirmwareImage<'a> {
                 .filter(move |segment| !chip.into_target().addr_is_flash(segment.addr)),
         )
     }
+
+    /// Chips which the firmware image may have been built for
+    fn compatible_chips(&'a self) -> Vec<Chip> {
+        Chip::iter().collect()
+    }
+
+    /// Ensure that the segments do not overlap, and lie either entirely within
+    /// or entirely outside of the memory mapped flash of the chip
+    fn check_segments(&'a self, _chip: Chip) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Operating system the firmware image was built with, if it can be
+    /// identified
+    fn os(&'a self) -> Option<TargetOs> {
+        None
+    }
+}
+
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+
+/// Handling of sections placed in RTC memory
+///
+/// Sections which are not initialized from the image, such as those holding
+/// data which persists across deep sleep, are never included.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum RtcSegments {
+    /// Include the sections in the image, to be loaded by the bootloader
+    #[default]
+    Load,
+    /// Leave the sections out of the image
+    Skip,
 }
 
 /// A firmware image built from an ELF file
 pub struct ElfFirmwareImage<'a> {
     elf: ElfFile<'a>,
+    rtc_segments: RtcSegments,
 }
 
 impl<'a> ElfFirmwareImage<'a> {
     pub fn new(elf: ElfFile<'a>) -> Self {
-        Self { elf }
+        Self {
+            elf,
+            rtc_segments: RtcSegments::default(),
+        }
+    }
+
+    /// Set how sections placed in RTC memory are handled
+    pub fn with_rtc_segments(mut self, rtc_segments: RtcSegments) -> Self {
+        if rtc_segments == RtcSegments::Skip {
+            for header in self.rtc_sections() {
+                warn!(
+                    "Skipping RTC memory section '{}' at {:#010x} ({} bytes)",
+                    header.get_name(&self.elf).unwrap_or_default(),
+                    header.address(),
+                    header.size()
+                );
+            }
+        }
+
+        self.rtc_segments = rtc_segments;
+        self
+    }
+
+    /// Sections with contents which are included in the image
+    fn loaded_sections(&self) -> impl Iterator<Item = SectionHeader<'a>> + '_ {
+        self.elf.section_iter().filter(move |header| {
+            header.size() > 0
+                && header.get_type() == Ok(ShType::ProgBits)
+                && header.offset() > 0
+                && header.address() > 0
+                && (self.rtc_segments == RtcSegments::Load
+                    || !self
+                        .rtc_sections()
+                        .any(|rtc| rtc.address() == header.address()))
+        })
+    }
+
+    /// Names of the symbols in the ELF file's symbol tables
+    fn symbol_names(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.elf
+            .section_iter()
+            .filter_map(move |header| match header.get_data(&self.elf) {
+                Ok(SectionData::SymbolTable32(entries)) => Some(entries),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(move |entry| entry.get_name(&self.elf).ok())
+    }
+
+    /// Sections with contents which are placed in RTC memory
+    fn rtc_sections(&self) -> impl Iterator<Item = SectionHeader<'a>> + '_ {
+        self.elf.section_iter().filter(move |header| {
+            header.size() > 0
+                && header.get_type() == Ok(ShType::ProgBits)
+                && header
+                    .get_name(&self.elf)
+                    .is_ok_and(|name| name.starts_with(".rtc"))
+        })
     }
 }
 
@@ -75,28 +175,143 @@ impl<'a> FirmwareImage<'a> for ElfFirmwareImage<'a> {
         self.elf.header.pt2.entry_point() as u32
     }
 
+    /// Chips which the ELF file may have been built for
+    ///
+    /// These are inferred from the architecture of the ELF file and, when any
+    /// of its segments are mapped to flash, the flash address ranges of each
+    /// chip. Since the address ranges of many chips overlap, this cannot
+    /// identify a single chip, but is sufficient to reject mismatched images.
+    fn compatible_chips(&'a self) -> Vec<Chip> {
+        // e_machine immediately follows e_ident and e_type in both ELF32 and ELF64
+        let machine = u16::from_le_bytes([self.elf.input[18], self.elf.input[19]]);
+        let chips = Chip::iter().filter(|chip| match machine {
+            EM_XTENSA => chip.is_xtensa(),
+            EM_RISCV => !chip.is_xtensa(),
+            _ => true,
+        });
+
+        let segments = self.segments().collect::<Vec<_>>();
+        let maps_flash = |chip: &Chip| {
+            let target = chip.into_target();
+            segments
+                .iter()
+                .any(|segment| target.addr_is_flash(segment.addr))
+        };
+
+        // Images which are only loaded into RAM have no segments mapped to flash
+        let chips = chips.collect::<Vec<_>>();
+        if chips.iter().any(maps_flash) {
+            chips.into_iter().filter(maps_flash).collect()
+        } else {
+            chips
+        }
+    }
+
+    /// Operating system the ELF file was built with, identified by the entry
+    /// function of its kernel
+    ///
+    /// Returns `None` if no such function is found, or if the functions of
+    /// several kernels are found.
+    fn os(&'a self) -> Option<TargetOs> {
+        let mut detected = None;
+        for name in self.symbol_names() {
+            let os = match name {
+                "z_cstart" => TargetOs::Zephyr,
+                "nx_start" => TargetOs::Nuttx,
+                _ => continue,
+            };
+
+            match detected {
+                Some(other) if other != os => {
+                    warn!("Unable to detect the operating system of the ELF file, both {other} and {os} symbols were found");
+                    return None;
+                }
+                _ => detected = Some(os),
+            }
+        }
+
+        detected
+    }
+
     fn segments(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
-        Box::new(
-            self.elf
-                .section_iter()
-                .filter(|header| {
-                    header.size() > 0
-                        && header.get_type() == Ok(ShType::ProgBits)
-                        && header.offset() > 0
-                        && header.address() > 0
-                })
-                .flat_map(move |header| {
-                    let addr = header.address() as u32;
-                    let data = match header.get_data(&self.elf) {
-                        Ok(SectionData::Undefined(data)) => data,
-                        _ => return None,
-                    };
-                    Some(CodeSegment::new(addr, data))
-                }),
-        )
+        Box::new(self.loaded_sections().flat_map(move |header| {
+            let addr = header.address() as u32;
+            let data = match header.get_data(&self.elf) {
+                Ok(SectionData::Undefined(data)) => data,
+                _ => return None,
+            };
+            Some(CodeSegment::new(addr, data))
+        }))
     }
 
+    fn check_segments(&'a self, chip: Chip) -> Result<(), Error> {
+        let target = chip.into_target();
+
+        let mut sections = self
+            .loaded_sections()
+            .map(|header| {
+                let name = header.get_name(&self.elf).unwrap_or("<unknown>");
+                let start = header.address();
+                (name, start..start + header.size())
+            })
+            .collect::<Vec<_>>();
+        sections.sort_by_key(|(_, range)| range.start);
+
+        let describe = |(name, range): &(&str, Range<u64>)| {
+            format!(
+                "'{name}' ({:#010x}..{:#010x}, {} bytes)",
+                range.start,
+                range.end,
+                range.end - range.start
+            )
+        };
+
+        let mut problems = Vec::new();
+
+        // Compare each section with the preceding section which ends last, so
+        // that overlaps with sections other than the adjacent one are found
+        let mut furthest: Option<&(&str, Range<u64>)> = None;
+        for section in &sections {
+            if let Some(previous) = furthest {
+                if section.1.start < previous.1.end {
+                    problems.push(format!(
+                        "{} overlaps {}",
+                        describe(previous),
+                        describe(section)
+                    ));
+                }
+            }
+
+            if furthest.map_or(true, |previous| section.1.end > previous.1.end) {
+                furthest = Some(section);
+            }
+
+            let (start, end) = (section.1.start as u32, section.1.end as u32 - 1);
+            if target.addr_is_flash(start) != target.addr_is_flash(end) {
+                problems.push(format!(
+                    "{} extends beyond the memory mapped flash of the {chip}",
+                    describe(section)
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidSegments(problems.join("\n")))
+        }
+    }
+
+    /// Firmware image segments, with their associated load addresses
+    ///
+    /// Images built from these segments are copied from flash as a whole, so
+    /// segments placed in RTC memory are never included.
     fn segments_with_load_addresses(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+        let rtc_addrs = self
+            .rtc_sections()
+            .map(|header| header.address())
+            .collect::<Vec<_>>();
+
         Box::new(
             self.elf
                 .program_iter()
@@ -105,6 +320,20 @@ impl<'a> FirmwareImage<'a> for ElfFirmwareImage<'a> {
                         && header.get_type() == Ok(Type::Load)
                         && header.offset() > 0
                 })
+                .filter(move |header| {
+                    let range = header.virtual_addr()..header.virtual_addr() + header.mem_size();
+                    if !rtc_addrs.iter().any(|addr| range.contains(addr)) {
+                        return true;
+                    }
+
+                    if self.rtc_segments == RtcSegments::Load {
+                        warn!(
+                            "Skipping the RTC memory segment at {:#010x}, which this image format cannot load",
+                            range.start
+                        );
+                    }
+                    false
+                })
                 .flat_map(move |header| {
                     let addr = header.physical_addr() as u32;
                     let from = header.offset() as usize;
@@ -13,7 +13,9 @@ use crate::cli::monitor::parser::esp_defmt::DefmtError;
 #[cfg(feature = "serialport")]
 use crate::command::CommandType;
 use crate::{
+    file_format::FileFormat,
     flasher::{FlashFrequency, FlashSize},
+    image_format::ImageFormatKind,
     targets::Chip,
 };
 #[cfg(feature = "serialport")]
@@ -31,6 +33,10 @@ pub enum Error {
     #[diagnostic(code(espflash::cancelled))]
     Cancelled,
 
+    #[error("Operation was cancelled as the async runtime is shutting down")]
+    #[diagnostic(code(espflash::runtime_shutdown))]
+    RuntimeShutdown,
+
     #[error("Unrecognized magic value: {0:#x}")]
     #[diagnostic(
         code(espflash::chip_detect_error),
@@ -47,6 +53,26 @@ pub enum Error {
     )]
     ChipMismatch(String, String),
 
+    #[error("The ELF file was not built for the {chip}, it appears to be for: {compatible}")]
+    #[diagnostic(
+        code(espflash::elf_chip_mismatch),
+        help("Ensure that the application was built for the correct target")
+    )]
+    ElfChipMismatch { chip: Chip, compatible: String },
+
+    #[error("The ESP-IDF project was built for the {build}, but the connected chip is the {chip}")]
+    #[diagnostic(
+        code(espflash::idf_build_chip_mismatch),
+        help(
+            "Ensure that the project was built for the correct target, using `idf.py set-target`"
+        )
+    )]
+    IdfBuildChipMismatch { build: Chip, chip: Chip },
+
+    #[error("The ESP-IDF build output is invalid: {0}")]
+    #[diagnostic(code(espflash::invalid_idf_build))]
+    InvalidIdfBuild(String),
+
     #[error("Chip not argument provided, this is required when using the `--before no-reset-no-sync` option")]
     #[diagnostic(
         code(espflash::chip_not_provided),
@@ -54,6 +80,13 @@ pub enum Error {
     )]
     ChipNotProvided,
 
+    #[error("The chip must be provided to build an image from an ELF file")]
+    #[diagnostic(
+        code(espflash::elf_chip_required),
+        help("Ensure that you provide the `-c/--chip` option with the proper chip")
+    )]
+    ElfChipRequired,
+
     #[error("Corrupt data, expected {0:2x?} bytes but receved {1:2x?} bytes")]
     #[diagnostic(code(espflash::read_flash::corrupt_data))]
     CorruptData(usize, usize),
@@ -79,10 +112,139 @@ pub enum Error {
     )]
     ElfTooBig(u32, u32),
 
+    #[error("Partition `{label}` is {percent:.2}% full, more than the limit of {limit}%")]
+    #[diagnostic(
+        code(espflash::partition_usage_exceeded),
+        help("Reduce the size of the data written to the partition or increase the size of the partition")
+    )]
+    PartitionUsageExceeded {
+        label: String,
+        percent: f32,
+        limit: f32,
+    },
+
+    #[error("Partition `{0}` was not found in the partition table")]
+    #[diagnostic(code(espflash::partition_not_found))]
+    PartitionNotFound(String),
+
+    #[error("The file for partition `{label}` of {size:#x} bytes does not fit in the partition of {part_size:#x} bytes")]
+    #[diagnostic(
+        code(espflash::partition_file_too_big),
+        help("Reduce the size of the file or increase the size of the partition")
+    )]
+    PartitionFileTooBig {
+        label: String,
+        size: u32,
+        part_size: u32,
+    },
+
+    #[error(
+        "Files can only be written to partitions when the image format uses a partition table"
+    )]
+    #[diagnostic(
+        code(espflash::partition_files_without_table),
+        help("Provide a partition table using `--partition-table`")
+    )]
+    PartitionFilesWithoutTable,
+
+    #[error("The file for partition `{0}` overlaps with the image being flashed")]
+    #[diagnostic(
+        code(espflash::partition_file_overlap),
+        help("Files can only be written to partitions other than the target app partition")
+    )]
+    PartitionFileOverlap(String),
+
+    #[error("The OTA data partition is invalid")]
+    #[diagnostic(code(espflash::invalid_ota_data))]
+    InvalidOtaData,
+
+    #[error("No OTA app partition is available to write to without overwriting the running app")]
+    #[diagnostic(
+        code(espflash::no_inactive_ota_partition),
+        help("The partition table must contain at least two OTA app partitions")
+    )]
+    NoInactiveOtaPartition,
+
+    #[error("The OTA data does not select an app in {0}")]
+    #[diagnostic(
+        code(espflash::ota_app_not_selected),
+        help("The state can only be set for apps which were selected by the OTA data, the factory app has no state")
+    )]
+    OtaAppNotSelected(String),
+
+    #[error("Partition `{0}` is not an OTA app partition")]
+    #[diagnostic(
+        code(espflash::not_ota_partition),
+        help(
+            "Only OTA app partitions can be activated, use `--target-app-partition` to select one"
+        )
+    )]
+    NotOtaPartition(String),
+
+    #[error("The region at {offset:#x} of {size:#x} bytes exceeds the flash size of {flash_size:#x} bytes")]
+    #[diagnostic(
+        code(espflash::flash_region_out_of_bounds),
+        help("If the flash size was not detected correctly, specify it using `--flash-size`")
+    )]
+    FlashRegionOutOfBounds {
+        offset: u32,
+        size: u32,
+        flash_size: u32,
+    },
+
+    #[error("The region at {offset:#x} of {size:#x} bytes is not aligned to 4KB flash sectors")]
+    #[diagnostic(
+        code(espflash::unaligned_erase_region),
+        help("Flash is erased in whole sectors, so the offset and size of the region must be multiples of 0x1000")
+    )]
+    UnalignedEraseRegion { offset: u32, size: u32 },
+
+    #[error("The size of the region at {0:#x} to erase is missing")]
+    #[diagnostic(
+        code(espflash::missing_erase_region_size),
+        help("Regions to erase are given as pairs of OFFSET and SIZE")
+    )]
+    MissingEraseRegionSize(u32),
+
+    #[error("Flash beyond the first 16MB can only be written using the flash stub")]
+    #[diagnostic(
+        code(espflash::rom_flash_address_limit),
+        help("The ROM loader only supports 3-byte flash addresses; do not use `--no-stub`")
+    )]
+    RomFlashAddressLimit,
+
+    #[error("The ELF file contains invalid segments:\n{0}")]
+    #[diagnostic(
+        code(espflash::invalid_segments),
+        help("Check the memory layout in the linker script used to build the application")
+    )]
+    InvalidSegments(String),
+
     #[error("Failed to connect to on-device flash")]
     #[diagnostic(code(espflash::flash_connect))]
     FlashConnect,
 
+    #[error("Invalid SPI flash connection: '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_spi_connection),
+        help("Expected `spi`, `hspi`, `pico-d4`, or the GPIO numbers of the flash pins in the form CLK,Q,D,HD,CS, such as `6,17,8,11,16`")
+    )]
+    InvalidSpiConnection(String),
+
+    #[error("Invalid fault injection settings: '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_chaos_config),
+        help("Expected a comma-separated list of `drop`, `corrupt` and `delay` probabilities between 0 and 1, `max-delay` in milliseconds and `seed`, such as `drop=0.01,seed=42`")
+    )]
+    InvalidChaosConfig(String),
+
+    #[error("The pins of the external flash must be provided")]
+    #[diagnostic(
+        code(espflash::external_flash_pins),
+        help("Use `--spi-connection CLK,Q,D,HD,CS` to select the GPIO pins the external flash is connected to")
+    )]
+    ExternalFlashPins,
+
     #[error("Expected MD5 digest (16 bytes), received: {0:#x} bytes")]
     #[diagnostic(code(espflash::read_flash::incorrect_digest_length))]
     IncorrectDigestLength(usize),
@@ -94,7 +256,16 @@ pub enum Error {
     #[error("The provided bootloader binary is invalid")]
     InvalidBootloader,
 
-    #[error("Specified bootloader path is not a .bin file")]
+    #[error("Binary is not set up correctly to support direct boot")]
+    #[diagnostic(
+        code(espflash::invalid_direct_boot),
+        help(
+            "See the following page for documentation on how to set up your binary for direct boot:\nhttps://github.com/espressif/esp32c3-direct-boot-example"
+        )
+    )]
+    InvalidDirectBootBinary,
+
+    #[error("Specified bootloader path is not a .bin or .hex file")]
     #[diagnostic(code(espflash::invalid_bootloader_path))]
     InvalidBootloaderPath,
 
@@ -109,10 +280,32 @@ pub enum Error {
     #[error(transparent)]
     IoError(#[from] io::Error),
 
+    #[error("Invalid Intel HEX file, line {line}: {reason}")]
+    #[diagnostic(code(espflash::invalid_intel_hex))]
+    InvalidIntelHex { line: usize, reason: String },
+
+    #[error("Intel HEX files contain their own addresses, so no address may be given for them")]
+    #[diagnostic(code(espflash::unexpected_address))]
+    UnexpectedAddress,
+
+    #[error("An address must be given to write a raw binary file")]
+    #[diagnostic(
+        code(espflash::missing_address),
+        help("Only Intel HEX files, with the `.hex` extension, contain their own addresses")
+    )]
+    MissingAddress,
+
     #[error("Specified partition table path is not a .bin or .csv file")]
     #[diagnostic(code(espflash::invalid_partition_table_path))]
     InvalidPartitionTablePath,
 
+    #[error("The remote serial port '{0}' is invalid")]
+    #[diagnostic(
+        code(espflash::invalid_remote_port),
+        help("Remote serial ports are specified as `espflash://<HOST>:<PORT>`, `rfc2217://<HOST>:<PORT>` for RFC 2217 servers, or `tcp://<HOST>:<PORT>` for TCP serial bridges")
+    )]
+    InvalidRemotePort(String),
+
     #[error("No serial ports could be detected")]
     #[diagnostic(
         code(espflash::no_serial),
@@ -124,6 +317,13 @@ pub enum Error {
     #[diagnostic(code(espflash::read_flash::read_more_than_expected))]
     ReadMoreThanExpected,
 
+    #[error("The espflash daemon refused the connection: {0}")]
+    #[diagnostic(
+        code(espflash::remote_refused),
+        help("Make sure that the correct `--remote-token` is provided, and that no other flash client is attached to the daemon")
+    )]
+    RemoteRefused(String),
+
     #[error("This command requires using the RAM stub")]
     #[diagnostic(
         code(espflash::stub_required),
@@ -138,10 +338,105 @@ pub enum Error {
     )]
     SerialNotFound(String),
 
+    #[error("The flash chip does not provide SFDP parameter tables")]
+    #[diagnostic(
+        code(espflash::sfdp_unsupported),
+        help(
+            "Older flash chips, and some external flash chips, do not support the RDSFDP command"
+        )
+    )]
+    SfdpUnsupported,
+
     #[error("The {chip} does not support {feature}")]
     #[diagnostic(code(espflash::unsupported_feature))]
     UnsupportedFeature { chip: Chip, feature: String },
 
+    #[error("{0} requires espflash to be built with the `{1}` feature")]
+    #[diagnostic(code(espflash::feature_disabled))]
+    FeatureDisabled(&'static str, &'static str),
+
+    #[error("The flash stub is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_flash_stub),
+        help("Run `espflash stub update` to download the stubs again, or `espflash stub clear` to use the ones embedded in espflash")
+    )]
+    InvalidFlashStub(String),
+
+    #[error("Failed to download the flash stubs: {0}")]
+    #[diagnostic(code(espflash::stub_download))]
+    StubDownload(String),
+
+    #[error("The signature of the flash stub release could not be verified: {0}")]
+    #[diagnostic(
+        code(espflash::stub_signature),
+        help("The release may have been tampered with, or signed with a different key")
+    )]
+    StubSignature(String),
+
+    #[error("No public key was provided to verify the flash stub release with")]
+    #[diagnostic(
+        code(espflash::stub_public_key_required),
+        help("Use `--public-key` to provide the Ed25519 public key the release is signed with, or `--insecure` to only check the digests listed in the release")
+    )]
+    StubPublicKeyRequired,
+
+    #[error("The signing key is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_signing_key),
+        help("The key must be an Ed25519 private key in PKCS#8 PEM format")
+    )]
+    InvalidSigningKey(String),
+
+    #[error("The Secure Boot signing key is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::secure_boot::invalid_key),
+        help("The key must be an RSA-3072 or ECDSA P-256 private key in PEM format")
+    )]
+    InvalidSecureBootKey(String),
+
+    #[error("The Secure Boot signature is invalid: {0}")]
+    #[diagnostic(code(espflash::secure_boot::invalid_signature))]
+    InvalidSecureBootSignature(String),
+
+    #[error("Failed to sign the image for Secure Boot: {0}")]
+    #[diagnostic(code(espflash::secure_boot::signing_failed))]
+    SecureBootSigningFailed(String),
+
+    #[error("The application description could not be found in the image")]
+    #[diagnostic(
+        code(espflash::app_descriptor_not_found),
+        help("Only applications built using ESP-IDF, or which otherwise embed an `esp_app_desc_t`, may have their version, project name or build time overridden")
+    )]
+    AppDescriptorNotFound,
+
+    #[error("The application {field} may be at most {max} characters long")]
+    #[diagnostic(code(espflash::app_descriptor_field_too_long))]
+    AppDescriptorFieldTooLong { field: &'static str, max: usize },
+
+    #[error("The file is not a valid application image: {0}")]
+    #[diagnostic(code(espflash::invalid_image))]
+    InvalidImage(String),
+
+    #[error("The checksum or SHA256 digest of the image does not match its contents")]
+    #[diagnostic(
+        code(espflash::image_digest_mismatch),
+        help(
+            "The bootloader will refuse to boot this image; use `--fix-hash` to recalculate them"
+        )
+    )]
+    ImageDigestMismatch,
+
+    #[error("The {chip} does not support the {format} image format")]
+    #[diagnostic(code(espflash::unsupported_image_format))]
+    UnsupportedImageFormat { chip: Chip, format: ImageFormatKind },
+
+    #[error("A manifest can not be saved for images in the {0} file format")]
+    #[diagnostic(
+        code(espflash::unsupported_manifest_format),
+        help("Use the `bin` file format when saving a manifest")
+    )]
+    UnsupportedManifestFormat(FileFormat),
+
     #[error("Flash chip not supported, unrecognized flash ID: {0:#x}")]
     #[diagnostic(code(espflash::unrecognized_flash))]
     UnsupportedFlash(u8),
@@ -164,6 +459,13 @@ pub enum Error {
         found_minor: u16,
     },
 
+    #[error("Failed to reset the USB port: {0}")]
+    #[diagnostic(
+        code(espflash::usb_port_reset),
+        help("Resetting the USB port requires write access to the USB device's `authorized` attribute in sysfs, which usually means running as root")
+    )]
+    UsbPortReset(String),
+
     #[error("Failed to parse chip revision: {chip_rev}. Chip revision must be in the format `major.minor`")]
     #[diagnostic(code(espflash::cli::parse_chip_rev_error))]
     ParseChipRevError { chip_rev: String },
@@ -202,6 +504,23 @@ pub enum Error {
     #[diagnostic(code(espflash::dialoguer_error))]
     DialoguerError(#[from] dialoguer::Error),
 
+    #[cfg(feature = "cli")]
+    #[error("The {hook} hook `{command}` failed: {status}")]
+    #[diagnostic(code(espflash::hook_failed))]
+    HookFailed {
+        hook: &'static str,
+        command: String,
+        status: String,
+    },
+
+    #[cfg(feature = "cli")]
+    #[error("The `reset` operation must be the last operation of a script")]
+    #[diagnostic(
+        code(espflash::script_reset_not_last),
+        help("Split the script in two, or remove the `reset` operation")
+    )]
+    ScriptResetNotLast,
+
     #[error("Internal Error")]
     InternalError,
 
@@ -210,6 +529,122 @@ pub enum Error {
 
     #[error("Failed to parse partition table")]
     Partition(#[from] esp_idf_part::Error),
+
+    #[error("The partition table is invalid: {0}")]
+    #[diagnostic(code(espflash::invalid_partition_table))]
+    InvalidPartitionTable(String),
+
+    #[error("The partition tables differ")]
+    #[diagnostic(code(espflash::partition_tables_differ))]
+    PartitionTablesDiffer,
+
+    #[error("The NVS partition is invalid: {0}")]
+    #[diagnostic(code(espflash::nvs::invalid))]
+    InvalidNvs(String),
+
+    #[error("No RF calibration data was found in the NVS partition `{0}`")]
+    #[diagnostic(
+        code(espflash::nvs::rf_calibration_not_found),
+        help("Run an application using Wi-Fi or Bluetooth first, so that it calibrates the RF and stores the calibration data")
+    )]
+    RfCalibrationNotFound(String),
+
+    #[error("The NVS key `{0}` is invalid")]
+    #[diagnostic(
+        code(espflash::nvs::invalid_key),
+        help("Keys and namespaces must be between 1 and 15 characters long")
+    )]
+    InvalidNvsKey(String),
+
+    #[error("The NVS value is invalid: {0}")]
+    #[diagnostic(code(espflash::nvs::invalid_value))]
+    InvalidNvsValue(String),
+
+    #[error("The NVS partition has no space left for the value")]
+    #[diagnostic(
+        code(espflash::nvs::full),
+        help("Erase the NVS partition, or use a partition table with a larger NVS partition")
+    )]
+    NvsFull,
+
+    #[error("The NVS encryption keys are invalid: {0}")]
+    #[diagnostic(
+        code(espflash::nvs::invalid_keys),
+        help(
+            "Provide the contents of an `nvs_keys` partition, as generated by `nvs generate-keys`"
+        )
+    )]
+    InvalidNvsKeys(String),
+
+    #[error("Invalid NVS CSV file at line {line}: {reason}")]
+    #[diagnostic(code(espflash::nvs::invalid_csv))]
+    InvalidNvsCsv { line: usize, reason: String },
+
+    #[error("Invalid NVS TOML file: {0}")]
+    #[diagnostic(code(espflash::nvs::invalid_toml))]
+    InvalidNvsToml(String),
+
+    #[error("No core dump was found in the core dump partition")]
+    #[diagnostic(
+        code(espflash::coredump::not_found),
+        help("Make sure that the application saves core dumps to flash, with `CONFIG_ESP_COREDUMP_ENABLE_TO_FLASH`")
+    )]
+    CoreDumpNotFound,
+
+    #[error("The filesystem image is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::fs::invalid),
+        help("Check the filesystem type, and for SPIFFS the page and block sizes")
+    )]
+    InvalidFilesystem(String),
+
+    #[error("The files do not fit in a filesystem of {0:#x} bytes")]
+    #[diagnostic(
+        code(espflash::fs::full),
+        help("Use a larger partition, or remove some of the files")
+    )]
+    FilesystemFull(usize),
+
+    #[error("The core dump is invalid: {0}")]
+    #[diagnostic(code(espflash::coredump::invalid))]
+    InvalidCoreDump(String),
+
+    #[error("The provisioning template is invalid: {0}")]
+    #[diagnostic(code(espflash::provision::invalid_template))]
+    InvalidProvisioningTemplate(String),
+
+    #[error("The device list is invalid: {0}")]
+    #[diagnostic(code(espflash::provision::invalid_device_list))]
+    InvalidDeviceList(String),
+
+    #[error("All devices in the device list have already been provisioned")]
+    #[diagnostic(
+        code(espflash::provision::no_devices),
+        help("Add devices to the device list, or use a different log file with `--log`")
+    )]
+    NoDevicesToProvision,
+
+    #[error("Flash encryption failed: {0}")]
+    #[diagnostic(code(espflash::flash_encryption::invalid))]
+    InvalidFlashEncryption(String),
+
+    #[error("The eFuses cannot be burned as requested: {0}")]
+    #[diagnostic(code(espflash::efuse::invalid_operation))]
+    InvalidEfuseOperation(String),
+
+    #[error("Failed to burn the eFuses: {0}")]
+    #[diagnostic(
+        code(espflash::efuse::burn_failed),
+        help("Read the eFuses back to check which of them were burned before retrying")
+    )]
+    EfuseBurnFailed(String),
+
+    #[error("Burning eFuses requires confirmation")]
+    #[diagnostic(
+        code(espflash::efuse::confirmation_required),
+        help("Burning eFuses cannot be undone. Pass `--do-not-confirm` to burn them without an interactive confirmation")
+    )]
+    EfuseConfirmationRequired,
 }
 
 #[cfg(feature = "serialport")]
@@ -252,6 +687,13 @@ pub enum ConnectionError {
     )]
     DeviceNotFound,
 
+    #[error("The device stopped responding")]
+    #[diagnostic(
+        code(espflash::device_not_responding),
+        help("The connection to the device has been lost, try reconnecting to the device")
+    )]
+    DeviceNotResponding,
+
     #[error("Received packet has invalid SLIP framing")]
     #[diagnostic(
         code(espflash::slip_framing),
@@ -290,6 +732,13 @@ pub enum ConnectionError {
     #[diagnostic(code(espflash::serial_error))]
     Serial(#[source] serialport::Error),
 
+    #[error("The USB device did not re-enumerate after being reset")]
+    #[diagnostic(
+        code(espflash::reenumeration_timeout),
+        help("The device drops off the bus each time it is reset when using the USB-OTG console, ensure that it is still connected")
+    )]
+    ReenumerationTimeout,
+
     #[error("Wrong boot mode detected ({0})! The chip needs to be in download mode.")]
     #[diagnostic(code(espflash::wrong_boot_mode))]
     WrongBootMode(String),
@@ -0,0 +1,240 @@
+//! Intel HEX
+//!
+//! Intel HEX files are text files consisting of records, each of which holds
+//! up to 255 bytes of data along with the lower 16 bits of its address. The
+//! upper 16 bits of the address are set by separate extended linear address
+//! records.
+//!
+//! https://en.wikipedia.org/wiki/Intel_HEX
+
+use std::{borrow::Cow, fmt::Write};
+
+use crate::{elf::RomSegment, error::Error};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Amount of data written to each data record
+const RECORD_DATA_SIZE: usize = 16;
+
+/// Convert the given segments into an Intel HEX file
+pub fn to_ihex(segments: &[RomSegment<'_>]) -> String {
+    let mut ihex = String::new();
+    let mut upper_addr = None;
+
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(RECORD_DATA_SIZE).enumerate() {
+            let addr = segment.addr + (i * RECORD_DATA_SIZE) as u32;
+
+            // A record may not cross a 64KiB boundary, as only the lower 16 bits
+            // of its address are stored in the record itself.
+            let split = (0x1_0000 - (addr & 0xffff) as usize).min(chunk.len());
+            for (addr, chunk) in [
+                (addr, &chunk[..split]),
+                (addr + split as u32, &chunk[split..]),
+            ] {
+                if chunk.is_empty() {
+                    continue;
+                }
+
+                if upper_addr != Some(addr >> 16) {
+                    upper_addr = Some(addr >> 16);
+                    write_record(
+                        &mut ihex,
+                        EXTENDED_LINEAR_ADDRESS,
+                        0,
+                        &((addr >> 16) as u16).to_be_bytes(),
+                    );
+                }
+
+                write_record(&mut ihex, DATA, addr as u16, chunk);
+            }
+        }
+    }
+
+    write_record(&mut ihex, END_OF_FILE, 0, &[]);
+
+    ihex
+}
+
+fn write_record(ihex: &mut String, kind: u8, addr: u16, data: &[u8]) {
+    let [addr_hi, addr_lo] = addr.to_be_bytes();
+    let mut record = vec![data.len() as u8, addr_hi, addr_lo, kind];
+    record.extend_from_slice(data);
+
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+
+    ihex.push(':');
+    for byte in record {
+        write!(ihex, "{byte:02X}").unwrap();
+    }
+    ihex.push('\n');
+}
+
+/// Parse an Intel HEX file into segments
+///
+/// Data records at contiguous addresses are merged into a single segment,
+/// and the resulting segments are sorted by address. Records which overlap,
+/// or which extend beyond the 32-bit address space, are rejected.
+pub fn from_ihex(ihex: &str) -> Result<Vec<RomSegment<'static>>, Error> {
+    // Each segment is recorded along with the line it starts at, so that
+    // overlapping segments can be reported once sorted
+    let mut segments: Vec<(u32, Vec<u8>, usize)> = Vec::new();
+    let mut base_addr = 0u32;
+
+    for (line_no, line) in ihex.lines().enumerate() {
+        let invalid = |reason: &str| Error::InvalidIntelHex {
+            line: line_no + 1,
+            reason: reason.to_string(),
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| invalid("record does not start with ':'"))?;
+        if record.len() % 2 != 0 || !record.is_ascii() {
+            return Err(invalid("record is not valid hexadecimal"));
+        }
+
+        let record = (0..record.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&record[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid("record is not valid hexadecimal"))?;
+
+        let [len, addr_hi, addr_lo, kind, ..] = record[..] else {
+            return Err(invalid("record is too short"));
+        };
+        if record.len() != len as usize + 5 {
+            return Err(invalid("record length does not match its contents"));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(invalid("record checksum is incorrect"));
+        }
+
+        let data = &record[4..record.len() - 1];
+
+        match kind {
+            DATA => {
+                let addr = base_addr as u64 + u16::from_be_bytes([addr_hi, addr_lo]) as u64;
+                if addr + data.len() as u64 > 1 << 32 {
+                    return Err(invalid("record extends beyond the 32-bit address space"));
+                }
+                let addr = addr as u32;
+
+                match segments.last_mut() {
+                    Some((start, segment, _))
+                        if *start as u64 + segment.len() as u64 == addr as u64 =>
+                    {
+                        segment.extend_from_slice(data)
+                    }
+                    _ => segments.push((addr, data.to_vec(), line_no + 1)),
+                }
+            }
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS => {
+                let [hi, lo] = data[..] else {
+                    return Err(invalid("address record must contain 2 bytes"));
+                };
+                let value = u16::from_be_bytes([hi, lo]) as u32;
+
+                base_addr = if kind == EXTENDED_LINEAR_ADDRESS {
+                    value << 16
+                } else {
+                    value << 4
+                };
+            }
+            // The entry point has no meaning when writing to flash
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            _ => return Err(invalid(&format!("unknown record type {kind:#04x}"))),
+        }
+    }
+
+    segments.sort_by_key(|(addr, ..)| *addr);
+
+    for pair in segments.windows(2) {
+        let [(prev_addr, prev_data, _), (addr, _, line)] = pair else {
+            unreachable!()
+        };
+        if *prev_addr as u64 + prev_data.len() as u64 > *addr as u64 {
+            return Err(Error::InvalidIntelHex {
+                line: *line,
+                reason: format!("record at {addr:#x} overlaps data written earlier"),
+            });
+        }
+    }
+
+    Ok(segments
+        .into_iter()
+        .map(|(addr, data, _)| RomSegment {
+            addr,
+            data: Cow::Owned(data),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let segments = vec![
+            RomSegment {
+                addr: 0xfff8,
+                data: Cow::Owned((0..40).collect()),
+            },
+            RomSegment {
+                addr: 0x10_0000,
+                data: Cow::Owned(vec![0xAA; 3]),
+            },
+        ];
+
+        let ihex = to_ihex(&segments);
+        assert!(ihex.starts_with(":020000040000FA\n"));
+        assert!(ihex.ends_with(":00000001FF\n"));
+
+        let parsed = from_ihex(&ihex).unwrap();
+        assert_eq!(parsed.len(), 2);
+        for (parsed, segment) in parsed.iter().zip(&segments) {
+            assert_eq!(parsed.addr, segment.addr);
+            assert_eq!(parsed.data, segment.data);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        assert!(matches!(
+            from_ihex(":0100000000FE\n"),
+            Err(Error::InvalidIntelHex { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_overlapping_records() {
+        let ihex = ":020000001122CB\n:0100010033CB\n";
+        assert!(matches!(
+            from_ihex(ihex),
+            Err(Error::InvalidIntelHex { line: 2, .. })
+        ));
+
+        // Beyond the 32-bit address space
+        let ihex = ":02000004FFFFFC\n:02FFFF001122CD\n";
+        assert!(matches!(
+            from_ihex(ihex),
+            Err(Error::InvalidIntelHex { line: 2, .. })
+        ));
+    }
+}
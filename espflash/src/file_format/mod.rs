@@ -0,0 +1,91 @@
+//! File formats which flash images can be loaded from and saved in
+//!
+//! By default images are saved as raw binaries, however many bootloaders,
+//! programmers and toolchains exchange firmware using container formats which
+//! record the address of each segment alongside its data.
+
+use std::{borrow::Cow, fs, path::Path};
+
+use serde::Deserialize;
+use strum::{Display, EnumIter, EnumString, VariantNames};
+
+use crate::{elf::RomSegment, error::Error};
+
+pub mod ihex;
+pub mod srec;
+pub mod uf2;
+
+/// Supported output file formats
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Display,
+    EnumIter,
+    EnumString,
+    VariantNames,
+    Deserialize,
+)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    /// Raw binary
+    #[default]
+    Bin,
+    /// Intel HEX
+    Hex,
+    /// Motorola S-record
+    Srec,
+    /// USB Flashing Format, for use with UF2 bootloaders
+    Uf2,
+}
+
+/// Is the file at the given path an Intel HEX file?
+pub fn is_intel_hex(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("hex"))
+}
+
+/// Load the segments contained in the file at the given path
+///
+/// Intel HEX files contain the address of each of their segments, so `addr`
+/// must not be given for them. Any other file is treated as a raw binary to be
+/// placed at `addr`, which is then required.
+pub fn load_segments(path: &Path, addr: Option<u32>) -> Result<Vec<RomSegment<'static>>, Error> {
+    let data = fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+    match (is_intel_hex(path), addr) {
+        (true, None) => ihex::from_ihex(&String::from_utf8_lossy(&data)),
+        (true, Some(_)) => Err(Error::UnexpectedAddress),
+        (false, Some(addr)) => Ok(vec![RomSegment {
+            addr,
+            data: Cow::Owned(data),
+        }]),
+        (false, None) => Err(Error::MissingAddress),
+    }
+}
+
+/// Load the file at the given path as a raw binary
+///
+/// Intel HEX files are converted to a binary starting at the lowest address
+/// they contain, with any gaps between their segments filled with `0xFF`.
+pub fn load_binary(path: &Path) -> Result<Vec<u8>, Error> {
+    let segments = load_segments(path, (!is_intel_hex(path)).then_some(0))?;
+    let base = segments
+        .first()
+        .map(|segment| segment.addr)
+        .unwrap_or_default();
+
+    let mut data = Vec::new();
+    for segment in segments {
+        data.resize((segment.addr - base) as usize, 0xFF);
+        data.extend_from_slice(&segment.data);
+    }
+
+    Ok(data)
+}
@@ -0,0 +1,153 @@
+//! Motorola S-record
+//!
+//! S-record files are text files consisting of records, each of which holds
+//! a chunk of data along with its address. Addresses may be 16, 24 or 32 bits
+//! wide depending on the record type; the narrowest width able to address all
+//! of the data is used.
+//!
+//! https://en.wikipedia.org/wiki/SREC_(file_format)
+
+use std::fmt::Write;
+
+use crate::elf::RomSegment;
+
+/// Amount of data written to each data record
+const RECORD_DATA_SIZE: usize = 32;
+
+/// Width of the addresses used in a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressWidth {
+    Bits16,
+    Bits24,
+    Bits32,
+}
+
+impl AddressWidth {
+    /// Narrowest width able to represent the given address
+    fn for_addr(addr: u32) -> Self {
+        match addr {
+            0..=0xffff => AddressWidth::Bits16,
+            0x1_0000..=0xff_ffff => AddressWidth::Bits24,
+            _ => AddressWidth::Bits32,
+        }
+    }
+
+    fn len(self) -> usize {
+        match self {
+            AddressWidth::Bits16 => 2,
+            AddressWidth::Bits24 => 3,
+            AddressWidth::Bits32 => 4,
+        }
+    }
+
+    /// Record type used for data records
+    fn data_record(self) -> char {
+        match self {
+            AddressWidth::Bits16 => '1',
+            AddressWidth::Bits24 => '2',
+            AddressWidth::Bits32 => '3',
+        }
+    }
+
+    /// Record type used for the termination record
+    fn termination_record(self) -> char {
+        match self {
+            AddressWidth::Bits16 => '9',
+            AddressWidth::Bits24 => '8',
+            AddressWidth::Bits32 => '7',
+        }
+    }
+}
+
+/// Convert the given segments into an S-record file
+pub fn to_srec(segments: &[RomSegment<'_>]) -> String {
+    let last_addr = segments
+        .iter()
+        .filter(|segment| !segment.data.is_empty())
+        .map(|segment| segment.addr + segment.data.len() as u32 - 1)
+        .max()
+        .unwrap_or_default();
+    let width = AddressWidth::for_addr(last_addr);
+
+    let mut srec = String::new();
+    write_record(&mut srec, '0', 0, AddressWidth::Bits16, b"espflash");
+
+    let mut data_records = 0u32;
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(RECORD_DATA_SIZE).enumerate() {
+            let addr = segment.addr + (i * RECORD_DATA_SIZE) as u32;
+            write_record(&mut srec, width.data_record(), addr, width, chunk);
+            data_records += 1;
+        }
+    }
+
+    // The record count is optional, and can only be represented in 24 bits
+    match data_records {
+        0..=0xffff => write_record(&mut srec, '5', data_records, AddressWidth::Bits16, &[]),
+        0x1_0000..=0xff_ffff => {
+            write_record(&mut srec, '6', data_records, AddressWidth::Bits24, &[])
+        }
+        _ => {}
+    }
+
+    write_record(&mut srec, width.termination_record(), 0, width, &[]);
+
+    srec
+}
+
+fn write_record(srec: &mut String, kind: char, addr: u32, width: AddressWidth, data: &[u8]) {
+    let addr = &addr.to_be_bytes()[4 - width.len()..];
+
+    let mut record = vec![(addr.len() + data.len() + 1) as u8];
+    record.extend_from_slice(addr);
+    record.extend_from_slice(data);
+
+    let checksum = !record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(checksum);
+
+    srec.push('S');
+    srec.push(kind);
+    for byte in record {
+        write!(srec, "{byte:02X}").unwrap();
+    }
+    srec.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    #[test]
+    fn uses_narrowest_address_width() {
+        let segment = |addr| RomSegment {
+            addr,
+            data: Cow::Owned(vec![0x01, 0x02]),
+        };
+
+        let srec = to_srec(&[segment(0x1000)]);
+        let lines = srec.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "S00B0000657370666C6173689E",
+                "S10510000102E7",
+                "S5030001FB",
+                "S9030000FC"
+            ]
+        );
+
+        let srec = to_srec(&[segment(0x1000), segment(0x1_0000)]);
+        assert!(srec
+            .lines()
+            .skip(1)
+            .take(2)
+            .all(|line| line.starts_with("S2")));
+        assert!(srec.ends_with("S804000000FB\n"));
+
+        let srec = to_srec(&[segment(0x100_0000)]);
+        assert!(srec.contains("S307010000000102F4\n"));
+        assert!(srec.ends_with("S70500000000FA\n"));
+    }
+}
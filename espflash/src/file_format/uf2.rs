@@ -0,0 +1,111 @@
+//! USB Flashing Format (UF2)
+//!
+//! UF2 files consist of 512 byte blocks, each of which carries a chunk of data
+//! along with the address it is to be written to, allowing devices running a
+//! UF2 bootloader to be flashed by copying the file to a USB mass storage
+//! device.
+//!
+//! https://github.com/microsoft/uf2
+
+use crate::elf::RomSegment;
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The block's family ID field is set
+const UF2_FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+/// Size of a single UF2 block
+pub const UF2_BLOCK_SIZE: usize = 512;
+/// Amount of data carried by each block
+const UF2_PAYLOAD_SIZE: usize = 256;
+/// Size of the data field of a block
+const UF2_DATA_SIZE: usize = 476;
+
+/// Convert the given segments into a UF2 file for the given family ID
+///
+/// Each segment is split into blocks of 256 bytes, which are written to the
+/// segment's address plus their offset within the segment.
+pub fn to_uf2(segments: &[RomSegment<'_>], family_id: u32) -> Vec<u8> {
+    let blocks = segments
+        .iter()
+        .flat_map(|segment| {
+            segment
+                .data
+                .chunks(UF2_PAYLOAD_SIZE)
+                .enumerate()
+                .map(|(i, chunk)| (segment.addr + (i * UF2_PAYLOAD_SIZE) as u32, chunk))
+        })
+        .collect::<Vec<_>>();
+
+    let num_blocks = blocks.len() as u32;
+    let mut uf2 = Vec::with_capacity(blocks.len() * UF2_BLOCK_SIZE);
+
+    for (block_no, (addr, chunk)) in blocks.into_iter().enumerate() {
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            UF2_FLAG_FAMILY_ID_PRESENT,
+            addr,
+            chunk.len() as u32,
+            block_no as u32,
+            num_blocks,
+            family_id,
+        ];
+
+        for word in header {
+            uf2.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let mut data = [0u8; UF2_DATA_SIZE];
+        data[..chunk.len()].copy_from_slice(chunk);
+        uf2.extend_from_slice(&data);
+        uf2.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+    }
+
+    uf2
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn word(block: &[u8], index: usize) -> u32 {
+        u32::from_le_bytes(block[index * 4..][..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn splits_segments_into_blocks() {
+        let segments = [
+            RomSegment {
+                addr: 0x1000,
+                data: Cow::Owned(vec![0xAA; 300]),
+            },
+            RomSegment {
+                addr: 0x10000,
+                data: Cow::Owned(vec![0x55; 16]),
+            },
+        ];
+
+        let uf2 = to_uf2(&segments, 0xbfdd_4eee);
+        assert_eq!(uf2.len(), 3 * UF2_BLOCK_SIZE);
+
+        let blocks = uf2.chunks(UF2_BLOCK_SIZE).collect::<Vec<_>>();
+        let expected = [(0x1000, 256), (0x1100, 44), (0x10000, 16)];
+
+        for (block_no, (block, (addr, len))) in blocks.iter().zip(expected).enumerate() {
+            assert_eq!(word(block, 0), UF2_MAGIC_START0);
+            assert_eq!(word(block, 1), UF2_MAGIC_START1);
+            assert_eq!(word(block, 2), UF2_FLAG_FAMILY_ID_PRESENT);
+            assert_eq!(word(block, 3), addr);
+            assert_eq!(word(block, 4), len);
+            assert_eq!(word(block, 5), block_no as u32);
+            assert_eq!(word(block, 6), 3);
+            assert_eq!(word(block, 7), 0xbfdd_4eee);
+            assert_eq!(word(block, 127), UF2_MAGIC_END);
+        }
+    }
+}
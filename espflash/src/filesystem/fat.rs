@@ -0,0 +1,989 @@
+//! FAT filesystem images
+//!
+//! ESP-IDF uses FAT12 or FAT16 filesystems, depending on the number of
+//! clusters, with the sector size of the flash. Unless a partition is mounted
+//! read-only, it is wrapped in the [wear levelling](super::wear_levelling)
+//! layer, which [read] detects and [build] adds on request.
+//!
+//! Long file names are read and written as VFAT entries, so that names which
+//! are not valid 8.3 names are preserved.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+};
+
+use super::{wear_levelling, FsEntry};
+use crate::{error::Error, ota::esp_crc32};
+
+const DIR_ENTRY_LEN: usize = 32;
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const MEDIA_TYPE: u8 = 0xf8;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Set in the sequence number of the last long name entry
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+
+/// Set in the reserved byte of short entries whose base name is lowercase
+const CASE_LOWER_BASE: u8 = 0x08;
+/// Set in the reserved byte of short entries whose extension is lowercase
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Timestamps are not preserved, all entries are dated 2000-01-01
+const DATE: u16 = ((2000 - 1980) << 9) | (1 << 5) | 1;
+
+/// Maximum number of clusters of FAT12 and FAT16 filesystems
+const MAX_CLUSTERS_FAT12: usize = 4084;
+const MAX_CLUSTERS_FAT16: usize = 65524;
+
+/// Maximum directory depth, guarding against directories linked in a loop
+const MAX_DEPTH: usize = 32;
+
+/// Characters allowed in short names, in addition to letters and digits
+const SHORT_NAME_SYMBOLS: &str = "$%'-_@~`!(){}^#&";
+
+/// Layout of a FAT filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FatConfig {
+    /// Size of the sectors, which is the flash sector size in ESP-IDF
+    pub sector_size: usize,
+    /// Number of sectors in each cluster
+    pub sectors_per_cluster: usize,
+    /// Number of copies of the allocation table
+    pub fat_count: usize,
+    /// Maximum number of entries in the root directory
+    pub root_entries: usize,
+}
+
+impl Default for FatConfig {
+    /// The defaults of ESP-IDF's `fatfsgen.py`
+    fn default() -> Self {
+        Self {
+            sector_size: 4096,
+            sectors_per_cluster: 1,
+            fat_count: 1,
+            root_entries: 512,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+}
+
+impl FatType {
+    fn for_clusters(clusters: usize) -> Option<Self> {
+        if clusters <= MAX_CLUSTERS_FAT12 {
+            Some(FatType::Fat12)
+        } else if clusters <= MAX_CLUSTERS_FAT16 {
+            Some(FatType::Fat16)
+        } else {
+            None
+        }
+    }
+
+    /// Size of the allocation table for the given number of clusters
+    fn table_len(self, clusters: usize) -> usize {
+        match self {
+            FatType::Fat12 => ((clusters + 2) * 3).div_ceil(2),
+            FatType::Fat16 => (clusters + 2) * 2,
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+        }
+    }
+
+    fn is_end_of_chain(self, cluster: u32) -> bool {
+        match self {
+            FatType::Fat12 => cluster >= 0xff8,
+            FatType::Fat16 => cluster >= 0xfff8,
+        }
+    }
+
+    fn label(self) -> &'static [u8; 8] {
+        match self {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+        }
+    }
+}
+
+/// Location of the regions of a FAT filesystem
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    ty: FatType,
+    sector_size: usize,
+    cluster_size: usize,
+    fat_offset: usize,
+    fat_len: usize,
+    fat_count: usize,
+    root_offset: usize,
+    root_entries: usize,
+    data_offset: usize,
+    clusters: usize,
+}
+
+impl Layout {
+    fn cluster(&self, cluster: u32) -> usize {
+        self.data_offset + (cluster as usize - 2) * self.cluster_size
+    }
+
+    fn get(&self, fat: &[u8], cluster: u32) -> u32 {
+        let cluster = cluster as usize;
+        match self.ty {
+            FatType::Fat12 => {
+                let offset = cluster * 3 / 2;
+                let value = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+                if cluster % 2 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0xfff) as u32
+                }
+            }
+            FatType::Fat16 => u16::from_le_bytes([fat[cluster * 2], fat[cluster * 2 + 1]]) as u32,
+        }
+    }
+
+    fn set(&self, fat: &mut [u8], cluster: u32, value: u32) {
+        let cluster = cluster as usize;
+        match self.ty {
+            FatType::Fat12 => {
+                let offset = cluster * 3 / 2;
+                let old = u16::from_le_bytes([fat[offset], fat[offset + 1]]);
+                let new = if cluster % 2 == 1 {
+                    (old & 0x000f) | ((value as u16) << 4)
+                } else {
+                    (old & 0xf000) | (value as u16 & 0x0fff)
+                };
+                fat[offset..][..2].copy_from_slice(&new.to_le_bytes());
+            }
+            FatType::Fat16 => {
+                fat[cluster * 2..][..2].copy_from_slice(&(value as u16).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Read all files and directories from a FAT image, which may be wrapped in
+/// the wear levelling layer
+pub fn read(data: &[u8]) -> Result<Vec<FsEntry>, Error> {
+    let data = if wear_levelling::is_wear_levelled(data) {
+        Cow::Owned(wear_levelling::unwrap(data)?)
+    } else {
+        Cow::Borrowed(data)
+    };
+
+    FatReader::new(&data)?.entries()
+}
+
+/// Whether an image holds a FAT filesystem, which may be wrapped in the wear
+/// levelling layer
+pub fn is_fat(data: &[u8]) -> bool {
+    if wear_levelling::is_wear_levelled(data) {
+        return true;
+    }
+
+    data.len() > BOOT_SIGNATURE_OFFSET + 2
+        && data[BOOT_SIGNATURE_OFFSET..][..2] == [0x55, 0xaa]
+        && data[54..57] == *b"FAT"
+}
+
+struct FatReader<'a> {
+    data: &'a [u8],
+    layout: Layout,
+}
+
+impl<'a> FatReader<'a> {
+    fn new(data: &'a [u8]) -> Result<Self, Error> {
+        if data.len() < BOOT_SIGNATURE_OFFSET + 2
+            || data[BOOT_SIGNATURE_OFFSET..][..2] != [0x55, 0xaa]
+        {
+            return Err(invalid("the FAT boot sector was not found"));
+        }
+
+        let half = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]) as usize;
+        let word =
+            |offset: usize| u32::from_le_bytes(data[offset..][..4].try_into().unwrap()) as usize;
+
+        let sector_size = half(11);
+        let sectors_per_cluster = data[13] as usize;
+        let reserved = half(14);
+        let fat_count = data[16] as usize;
+        let root_entries = half(17);
+        let total_sectors = match half(19) {
+            0 => word(32),
+            sectors => sectors,
+        };
+        let fat_sectors = half(22);
+
+        if !sector_size.is_power_of_two()
+            || sector_size < 512
+            || sectors_per_cluster == 0
+            || fat_count == 0
+        {
+            return Err(invalid("the FAT boot sector is invalid"));
+        }
+        if fat_sectors == 0 {
+            return Err(invalid("FAT32 filesystems are unsupported"));
+        }
+
+        let root_sectors = (root_entries * DIR_ENTRY_LEN).div_ceil(sector_size);
+        let data_sector = reserved + fat_count * fat_sectors + root_sectors;
+        let clusters = total_sectors.saturating_sub(data_sector) / sectors_per_cluster;
+        let ty = FatType::for_clusters(clusters)
+            .ok_or_else(|| invalid("FAT32 filesystems are unsupported"))?;
+
+        let layout = Layout {
+            ty,
+            sector_size,
+            cluster_size: sector_size * sectors_per_cluster,
+            fat_offset: reserved * sector_size,
+            fat_len: fat_sectors * sector_size,
+            fat_count,
+            root_offset: (reserved + fat_count * fat_sectors) * sector_size,
+            root_entries,
+            data_offset: data_sector * sector_size,
+            clusters,
+        };
+
+        if data.len() < layout.data_offset + clusters * layout.cluster_size
+            || layout.fat_len < ty.table_len(clusters)
+        {
+            return Err(invalid("the FAT image is truncated"));
+        }
+
+        Ok(Self { data, layout })
+    }
+
+    fn entries(&self) -> Result<Vec<FsEntry>, Error> {
+        let root =
+            &self.data[self.layout.root_offset..][..self.layout.root_entries * DIR_ENTRY_LEN];
+
+        let mut entries = Vec::new();
+        self.read_dir(root, "", 0, &mut entries)?;
+
+        Ok(entries)
+    }
+
+    fn fat(&self) -> &'a [u8] {
+        &self.data[self.layout.fat_offset..][..self.layout.fat_len]
+    }
+
+    /// Read the contents of a cluster chain, up to the given length
+    fn read_chain(&self, first: u32, len: Option<usize>) -> Result<Vec<u8>, Error> {
+        let layout = &self.layout;
+        let mut data = Vec::new();
+
+        let mut cluster = first;
+        while len.map_or(true, |len| data.len() < len) {
+            if cluster < 2 || cluster as usize >= layout.clusters + 2 {
+                if len.is_none() && layout.ty.is_end_of_chain(cluster) {
+                    break;
+                }
+                return Err(invalid(format!("the cluster chain from {first} is broken")));
+            }
+            if data.len() > layout.clusters * layout.cluster_size {
+                return Err(invalid(format!("the cluster chain from {first} loops")));
+            }
+
+            data.extend_from_slice(&self.data[layout.cluster(cluster)..][..layout.cluster_size]);
+            cluster = layout.get(self.fat(), cluster);
+        }
+
+        if let Some(len) = len {
+            data.truncate(len);
+        }
+
+        Ok(data)
+    }
+
+    fn read_dir(
+        &self,
+        dir: &[u8],
+        path: &str,
+        depth: usize,
+        entries: &mut Vec<FsEntry>,
+    ) -> Result<(), Error> {
+        if depth > MAX_DEPTH {
+            return Err(invalid(format!(
+                "the directory `{path}` is nested too deeply"
+            )));
+        }
+
+        let mut long_name = LongName::default();
+        for entry in dir.chunks_exact(DIR_ENTRY_LEN) {
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => {
+                    long_name = LongName::default();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attr = entry[11];
+            if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                long_name.push(entry);
+                continue;
+            }
+
+            let name = long_name.take(entry).unwrap_or_else(|| short_name(entry));
+            if attr & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                continue;
+            }
+
+            let entry_path = if path.is_empty() {
+                name
+            } else {
+                format!("{path}/{name}")
+            };
+            let cluster = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+
+            if attr & ATTR_DIRECTORY != 0 {
+                let contents = self.read_chain(cluster, None)?;
+                entries.push(FsEntry::Directory {
+                    path: entry_path.clone(),
+                });
+                self.read_dir(&contents, &entry_path, depth + 1, entries)?;
+            } else {
+                let size = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as usize;
+                let data = if size == 0 {
+                    Vec::new()
+                } else {
+                    self.read_chain(cluster, Some(size))?
+                };
+                entries.push(FsEntry::File {
+                    path: entry_path,
+                    data,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Long name entries preceding a short entry, in reverse order
+#[derive(Debug, Default)]
+struct LongName {
+    parts: BTreeMap<u8, Vec<u16>>,
+    checksum: Option<u8>,
+}
+
+impl LongName {
+    fn push(&mut self, entry: &[u8]) {
+        let seq = entry[0];
+        if seq & LAST_LONG_ENTRY != 0 {
+            *self = LongName {
+                checksum: Some(entry[13]),
+                ..Default::default()
+            };
+        } else if self.checksum != Some(entry[13]) {
+            *self = LongName::default();
+            return;
+        }
+
+        let chars = [1..11, 14..26, 28..32]
+            .into_iter()
+            .flat_map(|range| entry[range].chunks_exact(2))
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        self.parts.insert(seq & !LAST_LONG_ENTRY, chars);
+    }
+
+    /// The long name of a short entry, if it is preceded by valid long name
+    /// entries
+    fn take(&mut self, entry: &[u8]) -> Option<String> {
+        let long_name = std::mem::take(self);
+        if long_name.checksum != Some(checksum(entry[..11].try_into().unwrap())) {
+            return None;
+        }
+
+        // Sequence numbers must run from 1 without gaps
+        if !long_name
+            .parts
+            .keys()
+            .copied()
+            .eq(1..=long_name.parts.len() as u8)
+        {
+            return None;
+        }
+
+        let chars = long_name
+            .parts
+            .into_values()
+            .flatten()
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+
+        String::from_utf16(&chars).ok()
+    }
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let mut base = entry[..8].to_vec();
+    // 0xe5 is a valid first character in some code pages, stored as 0x05
+    if base[0] == 0x05 {
+        base[0] = ENTRY_DELETED;
+    }
+
+    let decode = |bytes: &[u8], lower: bool| {
+        let text = String::from_utf8_lossy(bytes).trim_end().to_string();
+        if lower {
+            text.to_lowercase()
+        } else {
+            text
+        }
+    };
+
+    let base = decode(&base, entry[12] & CASE_LOWER_BASE != 0);
+    let ext = decode(&entry[8..11], entry[12] & CASE_LOWER_EXT != 0);
+
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidFilesystem(reason.into())
+}
+
+/// A directory being built, holding the indices of its children
+#[derive(Debug, Default)]
+struct Dir {
+    children: Vec<usize>,
+}
+
+/// Build a FAT image of the given size holding the given files and
+/// directories, optionally wrapped in the wear levelling layer
+///
+/// Parent directories which are not listed are created as needed.
+pub fn build(
+    entries: &[FsEntry],
+    size: usize,
+    config: &FatConfig,
+    wear_levelled: bool,
+) -> Result<Vec<u8>, Error> {
+    if !wear_levelled {
+        return FatBuilder::new(size, config)?.build(entries);
+    }
+
+    if config.sector_size != wear_levelling::WL_SECTOR_SIZE {
+        return Err(invalid(format!(
+            "wear levelling requires a sector size of {} bytes",
+            wear_levelling::WL_SECTOR_SIZE
+        )));
+    }
+    let fat_size = wear_levelling::wrapped_size(size).ok_or_else(|| Error::FilesystemFull(size))?;
+    let fat = FatBuilder::new(fat_size, config)?.build(entries)?;
+
+    wear_levelling::wrap(&fat, size)
+}
+
+struct FatBuilder {
+    image: Vec<u8>,
+    layout: Layout,
+    fat: Vec<u8>,
+    next_cluster: u32,
+}
+
+impl FatBuilder {
+    fn new(size: usize, config: &FatConfig) -> Result<Self, Error> {
+        if !config.sector_size.is_power_of_two()
+            || config.sector_size < 512
+            || config.sectors_per_cluster == 0
+            || config.fat_count == 0
+        {
+            return Err(invalid("the FAT configuration is invalid"));
+        }
+
+        let sector_size = config.sector_size;
+        let total_sectors = size / sector_size;
+        let root_sectors = (config.root_entries * DIR_ENTRY_LEN).div_ceil(sector_size);
+        let reserved = 1;
+
+        // The size of the allocation table depends on the number of clusters,
+        // and the other way around
+        let mut fat_sectors = 1;
+        let (ty, clusters) = loop {
+            let data_sectors = total_sectors
+                .checked_sub(reserved + config.fat_count * fat_sectors + root_sectors)
+                .ok_or(Error::FilesystemFull(size))?;
+            let clusters = data_sectors / config.sectors_per_cluster;
+            let ty = FatType::for_clusters(clusters).ok_or_else(|| {
+                invalid("the filesystem is too large for FAT12 or FAT16, use larger clusters")
+            })?;
+
+            let needed = ty.table_len(clusters).div_ceil(sector_size);
+            if needed <= fat_sectors {
+                break (ty, clusters);
+            }
+            fat_sectors = needed;
+        };
+
+        let layout = Layout {
+            ty,
+            sector_size,
+            cluster_size: sector_size * config.sectors_per_cluster,
+            fat_offset: reserved * sector_size,
+            fat_len: fat_sectors * sector_size,
+            fat_count: config.fat_count,
+            root_offset: (reserved + config.fat_count * fat_sectors) * sector_size,
+            root_entries: config.root_entries,
+            data_offset: (reserved + config.fat_count * fat_sectors + root_sectors) * sector_size,
+            clusters,
+        };
+
+        let mut fat = vec![0; layout.fat_len];
+        layout.set(&mut fat, 0, (ty.end_of_chain() & !0xff) | MEDIA_TYPE as u32);
+        layout.set(&mut fat, 1, ty.end_of_chain());
+
+        let mut image = vec![0; total_sectors * sector_size];
+        // The data region is left erased, as `fatfsgen.py` does
+        image[layout.data_offset..].fill(0xff);
+
+        Ok(Self {
+            image,
+            layout,
+            fat,
+            next_cluster: 2,
+        })
+    }
+
+    fn build(mut self, entries: &[FsEntry]) -> Result<Vec<u8>, Error> {
+        // Arrange the entries in a tree, with the root at index 0
+        let mut paths = vec![String::new()];
+        let mut dirs = BTreeMap::from([(0, Dir::default())]);
+        let mut files = BTreeMap::new();
+
+        for entry in entries {
+            let path = entry.host_path()?;
+            let components = path
+                .iter()
+                .map(|c| c.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+
+            let mut parent = 0;
+            for (depth, name) in components.iter().enumerate() {
+                let path = components[..=depth].join("/");
+                let is_file =
+                    depth + 1 == components.len() && matches!(entry, FsEntry::File { .. });
+
+                let existing = dirs[&parent]
+                    .children
+                    .iter()
+                    .copied()
+                    .find(|child| paths[*child].rsplit('/').next() == Some(name.as_str()));
+                let index = match existing {
+                    Some(index) if !is_file && dirs.contains_key(&index) => index,
+                    Some(_) => {
+                        return Err(invalid(format!("the path `{path}` is listed twice")));
+                    }
+                    None => {
+                        paths.push(path);
+                        let index = paths.len() - 1;
+                        dirs.get_mut(&parent).unwrap().children.push(index);
+                        index
+                    }
+                };
+
+                if is_file {
+                    if let FsEntry::File { data, .. } = entry {
+                        files.insert(index, data.as_slice());
+                    }
+                } else {
+                    dirs.entry(index).or_default();
+                }
+                parent = index;
+            }
+        }
+
+        self.write_dir(0, None, &paths, &dirs, &files)?;
+
+        let Self {
+            mut image,
+            layout,
+            fat,
+            ..
+        } = self;
+
+        for copy in 0..layout.fat_count {
+            image[layout.fat_offset + copy * layout.fat_len..][..layout.fat_len]
+                .copy_from_slice(&fat);
+        }
+        write_boot_sector(&mut image, &layout);
+
+        Ok(image)
+    }
+
+    /// Write a directory and its children, returning its first cluster
+    fn write_dir(
+        &mut self,
+        index: usize,
+        parent_cluster: Option<u32>,
+        paths: &[String],
+        dirs: &BTreeMap<usize, Dir>,
+        files: &BTreeMap<usize, &[u8]>,
+    ) -> Result<u32, Error> {
+        let children = &dirs[&index].children;
+        let is_root = parent_cluster.is_none();
+
+        // Encode the names of the children first, to know the size of the
+        // directory
+        let mut short_names = HashSet::new();
+        let names = children
+            .iter()
+            .map(|child| {
+                let name = paths[*child].rsplit('/').next().unwrap();
+                encode_name(name, &mut short_names)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let entry_count = names.iter().map(|(_, long)| long.len() + 1).sum::<usize>()
+            + if is_root { 0 } else { 2 };
+
+        let cluster = if is_root {
+            if entry_count > self.layout.root_entries {
+                return Err(invalid(format!(
+                    "the root directory holds more than {} entries",
+                    self.layout.root_entries
+                )));
+            }
+            0
+        } else {
+            // Directories are terminated by an empty entry or their end
+            self.allocate((entry_count * DIR_ENTRY_LEN).max(1))?
+        };
+
+        let mut raw = Vec::with_capacity((entry_count + 1) * DIR_ENTRY_LEN);
+        if let Some(parent) = parent_cluster {
+            raw.extend(dir_entry(b".          ", ATTR_DIRECTORY, cluster, 0));
+            raw.extend(dir_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+        }
+
+        for (child, (short, long)) in children.iter().zip(names) {
+            let (attr, first_cluster, size) = if dirs.contains_key(child) {
+                let first = self.write_dir(*child, Some(cluster), paths, dirs, files)?;
+                (ATTR_DIRECTORY, first, 0)
+            } else {
+                let data = files[child];
+                let first = if data.is_empty() {
+                    0
+                } else {
+                    let first = self.allocate(data.len())?;
+                    self.write_chain(first, data);
+                    first
+                };
+                (ATTR_ARCHIVE, first, data.len() as u32)
+            };
+
+            for long in long {
+                raw.extend(long);
+            }
+            raw.extend(dir_entry(&short, attr, first_cluster, size));
+        }
+
+        if is_root {
+            let offset = self.layout.root_offset;
+            self.image[offset..][..raw.len()].copy_from_slice(&raw);
+        } else {
+            // Unused entries of the directory's clusters must be zeroed
+            let len = self.chain_len(cluster) * self.layout.cluster_size;
+            raw.resize(len, 0);
+            self.write_chain(cluster, &raw);
+        }
+
+        Ok(cluster)
+    }
+
+    /// Allocate consecutive clusters holding the given number of bytes,
+    /// returning the first one
+    fn allocate(&mut self, len: usize) -> Result<u32, Error> {
+        let count = len.div_ceil(self.layout.cluster_size) as u32;
+        let first = self.next_cluster;
+        if (first + count) as usize > self.layout.clusters + 2 {
+            return Err(Error::FilesystemFull(self.image.len()));
+        }
+
+        for cluster in first..first + count {
+            let next = if cluster + 1 == first + count {
+                self.layout.ty.end_of_chain()
+            } else {
+                cluster + 1
+            };
+            self.layout.set(&mut self.fat, cluster, next);
+        }
+        self.next_cluster += count;
+
+        Ok(first)
+    }
+
+    fn chain_len(&self, first: u32) -> usize {
+        let mut len = 1;
+        let mut cluster = first;
+        while !self
+            .layout
+            .ty
+            .is_end_of_chain(self.layout.get(&self.fat, cluster))
+        {
+            cluster = self.layout.get(&self.fat, cluster);
+            len += 1;
+        }
+
+        len
+    }
+
+    /// Write data to clusters allocated by [Self::allocate]
+    fn write_chain(&mut self, first: u32, data: &[u8]) {
+        let offset = self.layout.cluster(first);
+        self.image[offset..][..data.len()].copy_from_slice(data);
+    }
+}
+
+fn write_boot_sector(image: &mut [u8], layout: &Layout) {
+    let total_sectors = image.len() / layout.sector_size;
+    let fat_sectors = layout.fat_len / layout.sector_size;
+
+    let mut boot = Vec::with_capacity(62);
+    boot.extend([0xeb, 0xfe, 0x90]);
+    boot.extend(b"MSDOS5.0");
+    boot.extend((layout.sector_size as u16).to_le_bytes());
+    boot.push((layout.cluster_size / layout.sector_size) as u8);
+    boot.extend(((layout.fat_offset / layout.sector_size) as u16).to_le_bytes());
+    boot.push(layout.fat_count as u8);
+    boot.extend((layout.root_entries as u16).to_le_bytes());
+    boot.extend(u16::try_from(total_sectors).unwrap_or(0).to_le_bytes());
+    boot.push(MEDIA_TYPE);
+    boot.extend((fat_sectors as u16).to_le_bytes());
+    boot.extend(0x3fu16.to_le_bytes()); // sectors per track
+    boot.extend(0xffu16.to_le_bytes()); // heads
+    boot.extend(0u32.to_le_bytes()); // hidden sectors
+    let total_sectors_32 = if total_sectors > u16::MAX as usize {
+        total_sectors as u32
+    } else {
+        0
+    };
+    boot.extend(total_sectors_32.to_le_bytes());
+    boot.push(0x80); // drive number
+    boot.push(0);
+    boot.push(0x29); // extended boot signature
+                     // The volume ID only needs to differ between volumes, so derive it from
+                     // the contents to keep images reproducible
+    boot.extend(esp_crc32(&image[layout.fat_offset..]).to_le_bytes());
+    boot.extend(b"NO NAME    ");
+    boot.extend(layout.ty.label());
+
+    image[..boot.len()].copy_from_slice(&boot);
+    image[BOOT_SIGNATURE_OFFSET..][..2].copy_from_slice(&[0x55, 0xaa]);
+}
+
+fn dir_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY_LEN] {
+    let mut entry = [0; DIR_ENTRY_LEN];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[16..18].copy_from_slice(&DATE.to_le_bytes()); // creation date
+    entry[18..20].copy_from_slice(&DATE.to_le_bytes()); // access date
+    entry[24..26].copy_from_slice(&DATE.to_le_bytes()); // modification date
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Encode a name as a short name, preceded by long name entries unless it is
+/// a valid 8.3 name already
+fn encode_name(
+    name: &str,
+    short_names: &mut HashSet<[u8; 11]>,
+) -> Result<([u8; 11], Vec<[u8; DIR_ENTRY_LEN]>), Error> {
+    let is_short_char =
+        |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SYMBOLS.contains(c);
+
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+
+    if (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && base.chars().chain(ext.chars()).all(is_short_char)
+    {
+        let short = pad_short_name(base, ext);
+        if short_names.insert(short) {
+            return Ok((short, Vec::new()));
+        }
+    }
+
+    // Generate a unique short name such as `LONGNA~1.TXT`
+    let clean = |text: &str, len: usize| {
+        text.to_ascii_uppercase()
+            .chars()
+            .filter(|c| is_short_char(*c))
+            .take(len)
+            .collect::<String>()
+    };
+    let base = clean(base, 6);
+    let ext = clean(ext, 3);
+
+    let short = (1..=999_999)
+        .map(|n| {
+            let suffix = format!("~{n}");
+            let base = &base[..base.len().min(8 - suffix.len())];
+            pad_short_name(&format!("{base}{suffix}"), &ext)
+        })
+        .find(|short| !short_names.contains(short))
+        .ok_or_else(|| invalid(format!("no short name is available for `{name}`")))?;
+    short_names.insert(short);
+
+    let chars = name.encode_utf16().collect::<Vec<_>>();
+    let count = chars.len().div_ceil(LONG_NAME_CHARS);
+    if count > 20 {
+        return Err(invalid(format!("the name `{name}` is too long")));
+    }
+
+    let checksum = checksum(&short);
+    let long = (1..=count)
+        .rev()
+        .map(|seq| {
+            // The name is terminated by a null character if it does not fill
+            // the last entry, which is then padded with 0xffff
+            let part = (0..LONG_NAME_CHARS).map(|i| {
+                let index = (seq - 1) * LONG_NAME_CHARS + i;
+                match index.cmp(&chars.len()) {
+                    std::cmp::Ordering::Less => chars[index],
+                    std::cmp::Ordering::Equal => 0,
+                    std::cmp::Ordering::Greater => 0xffff,
+                }
+            });
+
+            let mut entry = [0; DIR_ENTRY_LEN];
+            entry[0] = seq as u8 | if seq == count { LAST_LONG_ENTRY } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+
+            let offsets = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2));
+            for (offset, c) in offsets.zip(part) {
+                entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entry
+        })
+        .collect();
+
+    Ok((short, long))
+}
+
+fn pad_short_name(base: &str, ext: &str) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<FsEntry> {
+        vec![
+            FsEntry::File {
+                path: "README.TXT".into(),
+                data: b"Hello!".to_vec(),
+            },
+            FsEntry::File {
+                path: "config/wifi settings.json".into(),
+                data: b"{}".to_vec(),
+            },
+            FsEntry::File {
+                path: "config/large.bin".into(),
+                data: (0..10_000u32).map(|i| i as u8).collect(),
+            },
+            FsEntry::File {
+                path: "empty".into(),
+                data: Vec::new(),
+            },
+            FsEntry::Directory {
+                path: "logs".into(),
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trips_images() {
+        let image = build(&entries(), 0x10_0000, &FatConfig::default(), false).unwrap();
+        assert_eq!(image.len(), 0x10_0000);
+        assert!(is_fat(&image));
+        assert_eq!(&image[54..62], b"FAT12   ");
+
+        let read_back = read(&image).unwrap();
+        assert_eq!(
+            read_back.iter().map(FsEntry::path).collect::<Vec<_>>(),
+            [
+                "README.TXT",
+                "config",
+                "config/wifi settings.json",
+                "config/large.bin",
+                "empty",
+                "logs",
+            ]
+        );
+
+        for entry in entries() {
+            assert!(read_back.contains(&entry));
+        }
+    }
+
+    #[test]
+    fn round_trips_wear_levelled_images() {
+        let image = build(&entries(), 0x10_0000, &FatConfig::default(), true).unwrap();
+        assert_eq!(image.len(), 0x10_0000);
+        assert!(wear_levelling::is_wear_levelled(&image));
+
+        let read_back = read(&image).unwrap();
+        for entry in entries() {
+            assert!(read_back.contains(&entry));
+        }
+    }
+
+    #[test]
+    fn encodes_names() {
+        let mut short_names = HashSet::new();
+
+        let (short, long) = encode_name("BOOT.LOG", &mut short_names).unwrap();
+        assert_eq!(&short, b"BOOT    LOG");
+        assert!(long.is_empty());
+
+        let (short, long) = encode_name("boot.log", &mut short_names).unwrap();
+        assert_eq!(&short, b"BOOT~1  LOG");
+        assert_eq!(long.len(), 1);
+        assert_eq!(long[0][0], 1 | LAST_LONG_ENTRY);
+
+        let mut name = LongName::default();
+        name.push(&long[0]);
+        let mut entry = [0; DIR_ENTRY_LEN];
+        entry[..11].copy_from_slice(&short);
+        assert_eq!(name.take(&entry).as_deref(), Some("boot.log"));
+    }
+}
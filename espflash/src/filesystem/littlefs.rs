@@ -0,0 +1,473 @@
+//! LittleFS filesystem images
+//!
+//! LittleFS stores directories in metadata pairs, two blocks which are written
+//! alternately. Each block begins with a revision count followed by commits,
+//! each a list of tags with their attached data terminated by a CRC tag. Tags
+//! are big-endian and XORed with the preceding tag. The superblock is stored
+//! in the root directory, in the pair of blocks 0 and 1.
+//!
+//! Small files are stored inline in their directory, while larger ones are
+//! stored in a CTZ skip-list: a chain of blocks, each beginning with pointers to
+//! previous blocks of the file.
+//!
+//! https://github.com/littlefs-project/littlefs/blob/master/SPEC.md
+
+use std::collections::HashSet;
+
+use super::FsEntry;
+use crate::{error::Error, ota::esp_rom_crc32_le};
+
+const MAGIC: &[u8] = b"littlefs";
+/// Block size used by the `esp_littlefs` component
+const DEFAULT_BLOCK_SIZE: usize = 4096;
+/// Block sizes to look for the second block of the superblock pair with
+const BLOCK_SIZES: [usize; 8] = [512, 1024, 2048, 4096, 8192, 16384, 32768, 65536];
+
+const TYPE1_NAME: u16 = 0x000;
+const TYPE1_STRUCT: u16 = 0x200;
+const TYPE1_SPLICE: u16 = 0x400;
+const TYPE1_TAIL: u16 = 0x600;
+const TYPE2_CCRC: u16 = 0x500;
+
+const TYPE_REG: u16 = 0x001;
+const TYPE_DIR: u16 = 0x002;
+const TYPE_SUPERBLOCK: u16 = 0x0ff;
+const TYPE_CREATE: u16 = 0x401;
+const TYPE_DELETE: u16 = 0x4ff;
+const TYPE_DIRSTRUCT: u16 = 0x200;
+const TYPE_INLINESTRUCT: u16 = 0x201;
+const TYPE_CTZSTRUCT: u16 = 0x202;
+
+/// A metadata tag
+#[derive(Debug, Clone, Copy)]
+struct Tag(u32);
+
+impl Tag {
+    fn is_valid(self) -> bool {
+        self.0 & 0x8000_0000 == 0
+    }
+
+    fn type1(self) -> u16 {
+        ((self.0 & 0x7000_0000) >> 20) as u16
+    }
+
+    fn type2(self) -> u16 {
+        ((self.0 & 0x7800_0000) >> 20) as u16
+    }
+
+    fn type3(self) -> u16 {
+        ((self.0 & 0x7ff0_0000) >> 20) as u16
+    }
+
+    fn chunk(self) -> u8 {
+        ((self.0 & 0x0ff0_0000) >> 20) as u8
+    }
+
+    fn id(self) -> usize {
+        ((self.0 & 0x000f_fc00) >> 10) as usize
+    }
+
+    fn is_delete(self) -> bool {
+        self.0 & 0x3ff == 0x3ff
+    }
+
+    /// Length of the attached data
+    fn size(self) -> usize {
+        if self.is_delete() {
+            0
+        } else {
+            (self.0 & 0x3ff) as usize
+        }
+    }
+
+    /// Length of the tag and its attached data
+    fn dsize(self) -> usize {
+        4 + self.size()
+    }
+}
+
+/// An entry of a directory, as described by the tags with its ID
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    ty: u16,
+    name: Vec<u8>,
+    structure: Option<(u16, Vec<u8>)>,
+}
+
+/// The state of a metadata pair after its last valid commit
+#[derive(Debug, Clone, Default)]
+struct MetadataPair {
+    entries: Vec<Entry>,
+    /// The next metadata pair, and whether it continues this directory
+    tail: Option<([u32; 2], bool)>,
+}
+
+/// A LittleFS filesystem image
+#[derive(Debug)]
+pub struct LittleFs<'a> {
+    data: &'a [u8],
+    block_size: usize,
+}
+
+/// Whether an image holds a LittleFS filesystem
+pub fn is_littlefs(data: &[u8]) -> bool {
+    superblock(data).is_some()
+}
+
+/// Find the superblock, returning the block size recorded in it
+fn superblock(data: &[u8]) -> Option<usize> {
+    std::iter::once(0).chain(BLOCK_SIZES).find_map(|offset| {
+        let block = data.get(offset..)?;
+        if block.get(8..16)? != MAGIC {
+            return None;
+        }
+
+        let block = &block[..block.len().min(DEFAULT_BLOCK_SIZE)];
+        let pair = parse_block(block)?;
+        let entry = pair.entries.first().filter(|e| e.ty == TYPE_SUPERBLOCK)?;
+        match &entry.structure {
+            Some((TYPE_INLINESTRUCT, data)) if data.len() >= 12 => {
+                Some(u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize)
+            }
+            _ => None,
+        }
+    })
+}
+
+impl<'a> LittleFs<'a> {
+    /// Parse a LittleFS image
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        let block_size =
+            superblock(data).ok_or_else(|| invalid("the LittleFS superblock was not found"))?;
+        if !BLOCK_SIZES.contains(&block_size) {
+            return Err(invalid(format!(
+                "the LittleFS block size of {block_size} bytes is unsupported"
+            )));
+        }
+
+        Ok(Self { data, block_size })
+    }
+
+    /// Size of the blocks of the filesystem
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// All files and directories of the filesystem
+    pub fn entries(&self) -> Result<Vec<FsEntry>, Error> {
+        let mut entries = Vec::new();
+        let mut visited = HashSet::new();
+        self.read_dir([0, 1], "", &mut entries, &mut visited)?;
+
+        Ok(entries)
+    }
+
+    fn read_dir(
+        &self,
+        pair: [u32; 2],
+        path: &str,
+        entries: &mut Vec<FsEntry>,
+        visited: &mut HashSet<u32>,
+    ) -> Result<(), Error> {
+        let mut next = Some(pair);
+        while let Some(pair) = next {
+            if !visited.insert(pair[0].min(pair[1])) {
+                return Err(invalid(format!(
+                    "the metadata pair {pair:?} is linked twice"
+                )));
+            }
+
+            let dir = self.fetch(pair)?;
+            for entry in &dir.entries {
+                let name = String::from_utf8_lossy(&entry.name);
+                let entry_path = if path.is_empty() {
+                    name.into_owned()
+                } else {
+                    format!("{path}/{name}")
+                };
+
+                match (entry.ty, &entry.structure) {
+                    (TYPE_REG, structure) => {
+                        let data = match structure {
+                            Some((TYPE_INLINESTRUCT, data)) => data.clone(),
+                            Some((TYPE_CTZSTRUCT, ctz)) if ctz.len() >= 8 => {
+                                let head = u32::from_le_bytes(ctz[0..4].try_into().unwrap());
+                                let size = u32::from_le_bytes(ctz[4..8].try_into().unwrap());
+                                self.read_ctz(head, size as usize)?
+                            }
+                            _ => Vec::new(),
+                        };
+
+                        entries.push(FsEntry::File {
+                            path: entry_path,
+                            data,
+                        });
+                    }
+                    (TYPE_DIR, Some((TYPE_DIRSTRUCT, child))) if child.len() >= 8 => {
+                        let child = [
+                            u32::from_le_bytes(child[0..4].try_into().unwrap()),
+                            u32::from_le_bytes(child[4..8].try_into().unwrap()),
+                        ];
+
+                        entries.push(FsEntry::Directory {
+                            path: entry_path.clone(),
+                        });
+                        self.read_dir(child, &entry_path, entries, visited)?;
+                    }
+                    _ => {}
+                }
+            }
+
+            // Directories too large for a single metadata pair continue in the
+            // pair their hard tail points to
+            next = dir.tail.filter(|(_, hard)| *hard).map(|(pair, _)| pair);
+        }
+
+        Ok(())
+    }
+
+    fn block(&self, block: u32) -> Result<&'a [u8], Error> {
+        let start = block as usize * self.block_size;
+        self.data
+            .get(start..start + self.block_size)
+            .ok_or_else(|| invalid(format!("block {block} is outside of the image")))
+    }
+
+    /// Read the most recent valid state of a metadata pair
+    fn fetch(&self, pair: [u32; 2]) -> Result<MetadataPair, Error> {
+        let blocks = [self.block(pair[0])?, self.block(pair[1])?];
+        let revision = |block: &[u8]| u32::from_le_bytes(block[0..4].try_into().unwrap());
+
+        // Revision counts are compared as sequence numbers, which may wrap
+        let newest = if (revision(blocks[1]).wrapping_sub(revision(blocks[0])) as i32) > 0 {
+            1
+        } else {
+            0
+        };
+
+        parse_block(blocks[newest])
+            .or_else(|| parse_block(blocks[1 - newest]))
+            .ok_or_else(|| invalid(format!("the metadata pair {pair:?} holds no valid commit")))
+    }
+
+    /// Read a file stored in a CTZ skip-list
+    fn read_ctz(&self, head: u32, size: usize) -> Result<Vec<u8>, Error> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Follow the first pointer of each block back to the beginning of the
+        // file
+        let mut blocks = vec![head];
+        for _ in 0..self.ctz_index(size - 1) {
+            let block = self.block(*blocks.last().unwrap())?;
+            blocks.push(u32::from_le_bytes(block[0..4].try_into().unwrap()));
+        }
+        blocks.reverse();
+
+        let mut data = Vec::with_capacity(size);
+        for (index, block) in blocks.into_iter().enumerate() {
+            // Block `n` begins with `ctz(n) + 1` pointers, except for the first
+            let skip = match index {
+                0 => 0,
+                index => 4 * (index.trailing_zeros() as usize + 1),
+            };
+            let len = (self.block_size - skip).min(size - data.len());
+            data.extend_from_slice(&self.block(block)?[skip..skip + len]);
+        }
+
+        Ok(data)
+    }
+
+    /// Index of the block of a CTZ skip-list holding the given offset
+    fn ctz_index(&self, offset: usize) -> usize {
+        let len = self.block_size - 2 * 4;
+        let index = offset / len;
+        if index == 0 {
+            return 0;
+        }
+
+        (offset - 4 * ((index - 1).count_ones() as usize + 2)) / len
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidFilesystem(reason.into())
+}
+
+/// Replay the valid commits of a metadata block
+fn parse_block(block: &[u8]) -> Option<MetadataPair> {
+    let mut state = MetadataPair::default();
+    let mut committed = None;
+
+    let mut ptag = Tag(u32::MAX);
+    let mut offset = 0;
+    let mut commit_start = 0;
+
+    loop {
+        offset += ptag.dsize();
+        let Some(raw) = block.get(offset..offset + 4) else {
+            break;
+        };
+        let tag = Tag(u32::from_be_bytes(raw.try_into().unwrap()) ^ ptag.0);
+        if !tag.is_valid() || offset + tag.dsize() > block.len() {
+            break;
+        }
+        ptag = tag;
+
+        let data = &block[offset + 4..offset + tag.dsize()];
+
+        if tag.type2() == TYPE2_CCRC {
+            // `lfs_crc` does not invert its result
+            let crc = !esp_rom_crc32_le(0, &block[commit_start..offset + 4]);
+            if data.len() < 4 || crc != u32::from_le_bytes(data[..4].try_into().unwrap()) {
+                break;
+            }
+
+            // The valid bit of the next commit's tags may be inverted
+            ptag = Tag(ptag.0 ^ (((tag.chunk() & 1) as u32) << 31));
+            committed = Some(state.clone());
+            commit_start = offset + tag.dsize();
+
+            continue;
+        }
+
+        let id = tag.id();
+        match tag.type1() {
+            TYPE1_NAME => {
+                if id >= state.entries.len() {
+                    state.entries.resize(id + 1, Entry::default());
+                }
+                state.entries[id].ty = tag.type3();
+                state.entries[id].name = data.to_vec();
+            }
+            TYPE1_STRUCT => {
+                if let Some(entry) = state.entries.get_mut(id) {
+                    entry.structure = Some((tag.type3(), data.to_vec()));
+                }
+            }
+            TYPE1_SPLICE => match tag.type3() {
+                TYPE_CREATE if id <= state.entries.len() => {
+                    state.entries.insert(id, Entry::default());
+                }
+                TYPE_DELETE if id < state.entries.len() => {
+                    state.entries.remove(id);
+                }
+                _ => {}
+            },
+            TYPE1_TAIL if data.len() >= 8 => {
+                let pair = [
+                    u32::from_le_bytes(data[0..4].try_into().unwrap()),
+                    u32::from_le_bytes(data[4..8].try_into().unwrap()),
+                ];
+                state.tail = Some((pair, tag.chunk() & 1 == 1));
+            }
+            _ => {}
+        }
+    }
+
+    committed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// Build a metadata block holding a single commit
+    fn metadata_block(revision: u32, tags: &[(u16, usize, &[u8])]) -> Vec<u8> {
+        let mut block = revision.to_le_bytes().to_vec();
+        let mut ptag = u32::MAX;
+
+        let mut push = |block: &mut Vec<u8>, tag: u32, data: &[u8]| {
+            block.extend((tag ^ ptag).to_be_bytes());
+            block.extend(data);
+            ptag = tag;
+        };
+
+        for (ty, id, data) in tags {
+            let tag = ((*ty as u32) << 20) | ((*id as u32) << 10) | data.len() as u32;
+            push(&mut block, tag, data);
+        }
+
+        let tag = ((TYPE2_CCRC as u32) << 20) | (0x3ff << 10) | 4;
+        block.extend((tag ^ ptag).to_be_bytes());
+        let crc = !esp_rom_crc32_le(0, &block);
+        block.extend(crc.to_le_bytes());
+
+        block.resize(BLOCK_SIZE, 0xff);
+        block
+    }
+
+    #[test]
+    fn reads_files_and_directories() {
+        let mut superblock = Vec::new();
+        for word in [0x0002_0000u32, BLOCK_SIZE as u32, 8, 255, 0x7fff_ffff, 1022] {
+            superblock.extend(word.to_le_bytes());
+        }
+
+        // A file larger than two blocks, stored in a CTZ skip-list
+        let large = (0..1200u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut ctz = 6u32.to_le_bytes().to_vec();
+        ctz.extend((large.len() as u32).to_le_bytes());
+
+        let root = metadata_block(
+            1,
+            &[
+                (TYPE_SUPERBLOCK, 0, MAGIC),
+                (TYPE_INLINESTRUCT, 0, &superblock),
+                (TYPE_CREATE, 1, &[]),
+                (TYPE_REG, 1, b"hello.txt"),
+                (TYPE_INLINESTRUCT, 1, b"Hello!"),
+                (TYPE_CREATE, 2, &[]),
+                (TYPE_DIR, 2, b"logs"),
+                (TYPE_DIRSTRUCT, 2, &[2, 0, 0, 0, 3, 0, 0, 0]),
+            ],
+        );
+        let logs = metadata_block(
+            1,
+            &[
+                (TYPE_CREATE, 0, &[]),
+                (TYPE_REG, 0, b"boot.log"),
+                (TYPE_CTZSTRUCT, 0, &ctz),
+            ],
+        );
+
+        let mut image = vec![0xff; 8 * BLOCK_SIZE];
+        // The superblock pair was last written to block 1
+        image[..BLOCK_SIZE].copy_from_slice(&metadata_block(0, &[]));
+        image[BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(&root);
+        image[2 * BLOCK_SIZE..][..BLOCK_SIZE].copy_from_slice(&logs);
+
+        // Blocks 4, 5 and 6 of the skip-list, holding 512, 508 and 180 bytes
+        image[4 * BLOCK_SIZE..][..512].copy_from_slice(&large[..512]);
+        image[5 * BLOCK_SIZE..][..4].copy_from_slice(&4u32.to_le_bytes());
+        image[5 * BLOCK_SIZE + 4..][..508].copy_from_slice(&large[512..1020]);
+        image[6 * BLOCK_SIZE..][..4].copy_from_slice(&5u32.to_le_bytes());
+        image[6 * BLOCK_SIZE + 4..][..4].copy_from_slice(&4u32.to_le_bytes());
+        image[6 * BLOCK_SIZE + 8..][..180].copy_from_slice(&large[1020..]);
+
+        assert!(is_littlefs(&image));
+
+        let fs = LittleFs::parse(&image).unwrap();
+        assert_eq!(fs.block_size(), BLOCK_SIZE);
+        assert_eq!(
+            fs.entries().unwrap(),
+            [
+                FsEntry::File {
+                    path: "hello.txt".into(),
+                    data: b"Hello!".to_vec(),
+                },
+                FsEntry::Directory {
+                    path: "logs".into(),
+                },
+                FsEntry::File {
+                    path: "logs/boot.log".into(),
+                    data: large,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Filesystem images
+//!
+//! Data partitions often hold a filesystem which the application writes to,
+//! such as FAT, SPIFFS or LittleFS. The submodules read the contents of such
+//! filesystems from an image of the partition, so that the files can be
+//! inspected on the host, and [fat] also builds images.
+
+use std::path::PathBuf;
+
+use strum::Display;
+
+use crate::error::Error;
+
+pub mod fat;
+pub mod littlefs;
+pub mod spiffs;
+pub mod wear_levelling;
+
+/// Type of a filesystem
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+pub enum FsType {
+    /// FAT, with or without wear levelling
+    Fat,
+    /// LittleFS, as used by the `esp_littlefs` component
+    Littlefs,
+    /// SPIFFS
+    Spiffs,
+}
+
+impl FsType {
+    /// Detect the type of the filesystem held by a partition image
+    ///
+    /// FAT images are identified by their boot sector or wear levelling
+    /// configuration, LittleFS images by their superblock, and any other image
+    /// is assumed to be a SPIFFS image, which has no identifying header.
+    pub fn detect(data: &[u8]) -> Self {
+        if fat::is_fat(data) {
+            FsType::Fat
+        } else if littlefs::is_littlefs(data) {
+            FsType::Littlefs
+        } else {
+            FsType::Spiffs
+        }
+    }
+}
+
+/// A file or directory stored in a filesystem
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEntry {
+    /// A directory
+    Directory {
+        /// Path of the directory, relative to the root of the filesystem
+        path: String,
+    },
+    /// A file
+    File {
+        /// Path of the file, relative to the root of the filesystem
+        path: String,
+        /// Contents of the file
+        data: Vec<u8>,
+    },
+}
+
+impl FsEntry {
+    /// Path of the entry, relative to the root of the filesystem
+    pub fn path(&self) -> &str {
+        match self {
+            FsEntry::Directory { path } | FsEntry::File { path, .. } => path,
+        }
+    }
+
+    /// Path of the entry on the host, relative to the directory the
+    /// filesystem is unpacked to
+    ///
+    /// Paths which would escape that directory are rejected.
+    pub fn host_path(&self) -> Result<PathBuf, Error> {
+        let path = self.path();
+
+        let mut host_path = PathBuf::new();
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            if component == ".." || component.contains('\\') || component.contains(':') {
+                return Err(Error::InvalidFilesystem(format!(
+                    "the path `{path}` cannot be unpacked safely"
+                )));
+            }
+            host_path.push(component);
+        }
+
+        if host_path.as_os_str().is_empty() {
+            return Err(Error::InvalidFilesystem(format!(
+                "the path `{path}` is empty"
+            )));
+        }
+
+        Ok(host_path)
+    }
+}
+
+/// Read all files and directories from a filesystem image
+pub fn read(data: &[u8], ty: FsType, spiffs: &spiffs::SpiffsConfig) -> Result<Vec<FsEntry>, Error> {
+    match ty {
+        FsType::Fat => fat::read(data),
+        FsType::Littlefs => littlefs::LittleFs::parse(data)?.entries(),
+        FsType::Spiffs => spiffs::read(data, spiffs),
+    }
+}
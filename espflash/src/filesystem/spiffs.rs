@@ -0,0 +1,207 @@
+//! SPIFFS filesystem images
+//!
+//! SPIFFS divides the flash into blocks, each beginning with object lookup
+//! pages followed by pages belonging to objects. Each object, i.e. file, is
+//! made up of index pages, the first of which holds its name and size, and of
+//! data pages. Every page begins with a header holding the ID of its object,
+//! its span index within the object and flags, which are cleared as the page
+//! is written, finalized and deleted.
+//!
+//! The layout depends on the configuration SPIFFS was built with, which is
+//! described by [SpiffsConfig]. Its defaults match ESP-IDF's.
+//!
+//! https://github.com/pellepl/spiffs/wiki/Design-of-spiffs
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::FsEntry;
+use crate::error::Error;
+
+/// Length of the page header: object ID, span index and flags
+const PAGE_HEADER_LEN: usize = 5;
+/// Offset of the size in the object index header, after the aligned page header
+const INDEX_SIZE_OFFSET: usize = 8;
+const INDEX_TYPE_OFFSET: usize = 12;
+const INDEX_NAME_OFFSET: usize = 13;
+
+const OBJ_ID_FREE: u16 = 0xffff;
+const OBJ_ID_DELETED: u16 = 0;
+const OBJ_ID_INDEX_FLAG: u16 = 0x8000;
+
+const FLAG_USED: u8 = 1 << 0;
+const FLAG_FINAL: u8 = 1 << 1;
+const FLAG_INDEX: u8 = 1 << 2;
+const FLAG_IXDELE: u8 = 1 << 6;
+const FLAG_DELET: u8 = 1 << 7;
+
+const TYPE_FILE: u8 = 1;
+/// Size of objects which have not been written to yet
+const UNDEFINED_LEN: u32 = u32::MAX;
+
+/// Configuration of a SPIFFS filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiffsConfig {
+    /// Logical page size, `CONFIG_SPIFFS_PAGE_SIZE`
+    pub page_size: usize,
+    /// Logical block size, the flash sector size in ESP-IDF
+    pub block_size: usize,
+    /// Maximum length of object names, `CONFIG_SPIFFS_OBJ_NAME_LEN`
+    pub obj_name_len: usize,
+}
+
+impl Default for SpiffsConfig {
+    fn default() -> Self {
+        Self {
+            page_size: 256,
+            block_size: 4096,
+            obj_name_len: 32,
+        }
+    }
+}
+
+impl SpiffsConfig {
+    /// Number of object lookup pages at the beginning of each block
+    fn lookup_pages(&self) -> usize {
+        let pages_per_block = self.block_size / self.page_size;
+        (pages_per_block * 2 / self.page_size).max(1)
+    }
+}
+
+/// Read all files from a SPIFFS image
+///
+/// SPIFFS has no directories, but file names may contain slashes.
+pub fn read(data: &[u8], config: &SpiffsConfig) -> Result<Vec<FsEntry>, Error> {
+    if config.page_size < INDEX_NAME_OFFSET + config.obj_name_len
+        || config.block_size % config.page_size != 0
+        || config.block_size / config.page_size <= config.lookup_pages()
+    {
+        return Err(invalid(format!(
+            "a page size of {} bytes and a block size of {} bytes are incompatible",
+            config.page_size, config.block_size
+        )));
+    }
+
+    let mut files = BTreeMap::new();
+    let mut data_pages = HashMap::new();
+
+    for block in data.chunks_exact(config.block_size) {
+        let pages = block.chunks_exact(config.page_size);
+        for page in pages.skip(config.lookup_pages()) {
+            let obj_id = u16::from_le_bytes([page[0], page[1]]);
+            let span = u16::from_le_bytes([page[2], page[3]]);
+            let flags = page[4];
+
+            // Only consider pages which are used and finalized, but not deleted
+            if obj_id == OBJ_ID_FREE
+                || obj_id == OBJ_ID_DELETED
+                || flags & (FLAG_USED | FLAG_FINAL) != 0
+                || flags & FLAG_DELET == 0
+            {
+                continue;
+            }
+            let obj_id = obj_id & !OBJ_ID_INDEX_FLAG;
+
+            if flags & FLAG_INDEX != 0 {
+                data_pages.insert((obj_id, span), &page[PAGE_HEADER_LEN..]);
+            } else if span == 0 && flags & FLAG_IXDELE != 0 && page[INDEX_TYPE_OFFSET] == TYPE_FILE
+            {
+                let size = u32::from_le_bytes(page[INDEX_SIZE_OFFSET..][..4].try_into().unwrap());
+                let name = &page[INDEX_NAME_OFFSET..][..config.obj_name_len];
+                let name = name.split(|b| *b == 0).next().unwrap_or_default();
+
+                files.insert(obj_id, (String::from_utf8_lossy(name).into_owned(), size));
+            }
+        }
+    }
+
+    let mut entries = Vec::with_capacity(files.len());
+    for (obj_id, (name, size)) in files {
+        let size = if size == UNDEFINED_LEN {
+            0
+        } else {
+            size as usize
+        };
+
+        // Data pages are looked up by their span index rather than through the
+        // object index, which holds the same information
+        let mut contents = Vec::with_capacity(size);
+        for span in 0.. {
+            if contents.len() >= size {
+                break;
+            }
+            let page = data_pages
+                .get(&(obj_id, span))
+                .ok_or_else(|| invalid(format!("page {span} of the file `{name}` is missing")))?;
+            let len = page.len().min(size - contents.len());
+            contents.extend_from_slice(&page[..len]);
+        }
+
+        entries.push(FsEntry::File {
+            path: name.trim_start_matches('/').to_string(),
+            data: contents,
+        });
+    }
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+
+    Ok(entries)
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidFilesystem(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(obj_id: u16, span: u16, flags: u8, contents: &[u8]) -> Vec<u8> {
+        let mut page = obj_id.to_le_bytes().to_vec();
+        page.extend(span.to_le_bytes());
+        page.push(flags);
+        page.extend(contents);
+        page.resize(256, 0xff);
+        page
+    }
+
+    fn index_page(obj_id: u16, name: &str, size: u32, flags: u8) -> Vec<u8> {
+        let mut contents = vec![0xff; 3];
+        contents.extend(size.to_le_bytes());
+        contents.push(TYPE_FILE);
+        contents.extend(name.as_bytes());
+        contents.resize(3 + 4 + 1 + 32 + 4, 0);
+
+        page(obj_id | OBJ_ID_INDEX_FLAG, 0, flags, &contents)
+    }
+
+    #[test]
+    fn reads_files() {
+        const VALID_INDEX: u8 = !(FLAG_USED | FLAG_FINAL | FLAG_INDEX);
+        const VALID_DATA: u8 = !(FLAG_USED | FLAG_FINAL);
+
+        let large = (0..300u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut image = vec![0xff; 256];
+        image.extend(index_page(1, "/config/wifi.json", 2, VALID_INDEX));
+        image.extend(page(1, 0, VALID_DATA, b"{}"));
+        image.extend(index_page(2, "/log.txt", 300, VALID_INDEX));
+        image.extend(page(2, 1, VALID_DATA, &large[251..]));
+        image.extend(page(2, 0, VALID_DATA, &large[..251]));
+        // A deleted file
+        image.extend(index_page(3, "/old.txt", 0, VALID_INDEX & !FLAG_DELET));
+        image.resize(2 * 4096, 0xff);
+
+        assert_eq!(
+            read(&image, &SpiffsConfig::default()).unwrap(),
+            [
+                FsEntry::File {
+                    path: "config/wifi.json".into(),
+                    data: b"{}".to_vec(),
+                },
+                FsEntry::File {
+                    path: "log.txt".into(),
+                    data: large,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! ESP-IDF wear levelling layer
+//!
+//! FAT partitions mounted with `esp_vfs_fat_spiflash_mount_rw_wl` are accessed
+//! through a wear levelling layer, which spreads writes over the flash by
+//! periodically moving a spare "dummy" sector through the partition. The
+//! sectors of the FAT filesystem are therefore rotated, and the partition ends
+//! with two copies of the wear levelling state followed by its configuration.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/storage/wear-levelling.html
+
+use crate::{error::Error, ota::esp_crc32};
+
+/// Sector size used by the wear levelling layer
+pub const WL_SECTOR_SIZE: usize = 0x1000;
+
+/// Length of `wl_config_t` preceding its CRC
+const CONFIG_LEN: usize = 32;
+/// Length of `wl_config_t`, including its CRC and padding
+const CONFIG_HEADER_LEN: usize = 48;
+/// Length of `wl_state_t` preceding its CRC
+const STATE_LEN: usize = 60;
+/// Length of `wl_state_t`, including its CRC
+const STATE_HEADER_LEN: usize = 64;
+
+const VERSION: u32 = 2;
+const UPDATE_RATE: u32 = 16;
+const WRITE_SIZE: usize = 16;
+const TEMP_BUFF_SIZE: u32 = 32;
+
+/// Configuration of the wear levelling layer
+#[derive(Debug, Clone, Copy)]
+#[doc(alias = "wl_config_t")]
+struct Config {
+    full_mem_size: usize,
+    page_size: usize,
+    sector_size: usize,
+    write_size: usize,
+}
+
+/// State of the wear levelling layer
+#[derive(Debug, Clone, Copy)]
+#[doc(alias = "wl_state_t")]
+struct State {
+    max_pos: usize,
+    move_count: usize,
+}
+
+/// Location of the wear levelling state, and size of the wrapped data
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    state_size: usize,
+    state_addr: usize,
+    flash_size: usize,
+}
+
+impl Config {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let config = data.get(data.len().checked_sub(WL_SECTOR_SIZE)?..)?;
+        let word = |index: usize| {
+            u32::from_le_bytes(config[index * 4..][..4].try_into().unwrap()) as usize
+        };
+
+        if esp_crc32(&config[..CONFIG_LEN]) as usize != word(8) {
+            return None;
+        }
+
+        let config = Self {
+            full_mem_size: word(1),
+            page_size: word(2),
+            sector_size: word(3),
+            write_size: word(5),
+        };
+
+        let valid = config.full_mem_size == data.len()
+            && config.sector_size == WL_SECTOR_SIZE
+            && config.page_size.is_power_of_two()
+            && config.sector_size % config.page_size == 0
+            && config.write_size > 0;
+
+        valid.then_some(config)
+    }
+
+    fn layout(&self) -> Option<Layout> {
+        let records = self.full_mem_size / self.page_size;
+        let state_size =
+            (STATE_HEADER_LEN + records * self.write_size).next_multiple_of(self.sector_size);
+
+        let reserved = 2 * state_size + self.sector_size;
+        let pages = self.full_mem_size.checked_sub(reserved)? / self.page_size;
+
+        Some(Layout {
+            state_size,
+            state_addr: self.full_mem_size - reserved,
+            flash_size: pages.checked_sub(1)? * self.page_size,
+        })
+    }
+}
+
+impl State {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let word = |index: usize| u32::from_le_bytes(data[index * 4..][..4].try_into().unwrap());
+
+        if esp_crc32(&data[..STATE_LEN]) != word(15) {
+            return None;
+        }
+
+        Some(Self {
+            max_pos: word(1) as usize,
+            move_count: word(2) as usize,
+        })
+    }
+}
+
+/// Whether a partition image is wrapped in the wear levelling layer
+pub fn is_wear_levelled(data: &[u8]) -> bool {
+    Config::parse(data).is_some()
+}
+
+/// Size of the data which a partition of the given size can hold with wear
+/// levelling
+pub fn wrapped_size(partition_size: usize) -> Option<usize> {
+    default_config(partition_size)
+        .layout()
+        .map(|layout| layout.flash_size)
+}
+
+/// Extract the data from a partition image wrapped in the wear levelling
+/// layer, undoing the rotation of its sectors
+pub fn unwrap(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let config = Config::parse(data)
+        .ok_or_else(|| invalid("the wear levelling configuration is invalid"))?;
+    let layout = config
+        .layout()
+        .ok_or_else(|| invalid("the partition is too small for wear levelling"))?;
+
+    // The state is saved twice, in case power is lost while writing it
+    let (state_addr, state) = [layout.state_addr, layout.state_addr + layout.state_size]
+        .into_iter()
+        .find_map(|addr| State::parse(&data[addr..]).map(|state| (addr, state)))
+        .ok_or_else(|| invalid("both copies of the wear levelling state are invalid"))?;
+
+    let pages = layout.flash_size / config.page_size;
+    if state.max_pos != pages + 1 || state.move_count > pages {
+        return Err(invalid(
+            "the wear levelling state does not match its configuration",
+        ));
+    }
+
+    // A record is written after the state each time the dummy sector moves
+    let records = &data[state_addr + STATE_HEADER_LEN..];
+    let pos = records
+        .chunks_exact(config.write_size)
+        .take(state.max_pos)
+        .take_while(|record| record.iter().any(|b| *b != 0xff))
+        .count()
+        .min(state.max_pos - 1);
+
+    let mut unwrapped = Vec::with_capacity(layout.flash_size);
+    for page in 0..pages {
+        // `WL_Flash::calcAddr`
+        let mut addr = (layout.flash_size - state.move_count * config.page_size
+            + page * config.page_size)
+            % layout.flash_size;
+        if addr >= pos * config.page_size {
+            addr += config.page_size;
+        }
+
+        unwrapped.extend_from_slice(&data[addr..][..config.page_size]);
+    }
+
+    Ok(unwrapped)
+}
+
+/// Wrap data in the wear levelling layer, producing an image of a partition
+/// of the given size
+///
+/// The data must be exactly [wrapped_size] bytes long.
+pub fn wrap(data: &[u8], partition_size: usize) -> Result<Vec<u8>, Error> {
+    let config = default_config(partition_size);
+    let layout = config
+        .layout()
+        .filter(|layout| layout.flash_size == data.len())
+        .ok_or_else(|| {
+            invalid(format!(
+                "{:#x} bytes do not fill a wear levelled partition of {partition_size:#x} bytes",
+                data.len()
+            ))
+        })?;
+
+    let mut image = vec![0xff; config.page_size];
+    image.extend_from_slice(data);
+
+    let mut state = Vec::with_capacity(STATE_HEADER_LEN);
+    for word in [
+        0, // pos
+        (layout.flash_size / config.page_size + 1) as u32,
+        0, // move_count
+        0, // access_count
+        UPDATE_RATE,
+        config.sector_size as u32,
+        VERSION,
+        // The device ID only needs to differ between partitions, so derive
+        // it from the contents to keep images reproducible
+        esp_crc32(data),
+    ] {
+        state.extend_from_slice(&word.to_le_bytes());
+    }
+    state.resize(STATE_LEN, 0);
+    state.extend_from_slice(&esp_crc32(&state).to_le_bytes());
+    state.resize(layout.state_size, 0xff);
+
+    image.extend_from_slice(&state);
+    image.extend_from_slice(&state);
+
+    let mut config_sector = Vec::with_capacity(CONFIG_HEADER_LEN);
+    for word in [
+        0, // start_addr
+        config.full_mem_size as u32,
+        config.page_size as u32,
+        config.sector_size as u32,
+        UPDATE_RATE,
+        config.write_size as u32,
+        VERSION,
+        TEMP_BUFF_SIZE,
+    ] {
+        config_sector.extend_from_slice(&word.to_le_bytes());
+    }
+    config_sector.extend_from_slice(&esp_crc32(&config_sector).to_le_bytes());
+    config_sector.resize(CONFIG_HEADER_LEN, 0);
+    config_sector.resize(config.sector_size, 0xff);
+
+    image.extend_from_slice(&config_sector);
+
+    Ok(image)
+}
+
+fn default_config(partition_size: usize) -> Config {
+    Config {
+        full_mem_size: partition_size,
+        page_size: WL_SECTOR_SIZE,
+        sector_size: WL_SECTOR_SIZE,
+        write_size: WRITE_SIZE,
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidFilesystem(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unwraps_rotated_sectors() {
+        const PARTITION_SIZE: usize = 16 * WL_SECTOR_SIZE;
+
+        let size = wrapped_size(PARTITION_SIZE).unwrap();
+        assert_eq!(size, 12 * WL_SECTOR_SIZE);
+
+        let data = (0..size / WL_SECTOR_SIZE)
+            .flat_map(|sector| [sector as u8; WL_SECTOR_SIZE])
+            .collect::<Vec<_>>();
+
+        let mut image = wrap(&data, PARTITION_SIZE).unwrap();
+        assert_eq!(image.len(), PARTITION_SIZE);
+        assert!(is_wear_levelled(&image));
+        assert_eq!(unwrap(&image).unwrap(), data);
+
+        // Move the dummy sector forward by two sectors, as the wear levelling
+        // layer does over time
+        image.copy_within(WL_SECTOR_SIZE..3 * WL_SECTOR_SIZE, 0);
+        let records = 13 * WL_SECTOR_SIZE + STATE_HEADER_LEN;
+        image[records..][..2 * WRITE_SIZE].fill(0);
+
+        assert_eq!(unwrap(&image).unwrap(), data);
+    }
+}
@@ -0,0 +1,246 @@
+//! Flash encryption
+//!
+//! When flash encryption is enabled, the contents of the flash are encrypted
+//! using XTS-AES, with the flash address of each 128 byte block as the tweak.
+//! Images may be encrypted ahead of time on the host, so that they can be
+//! written to devices whose flash encryption key is read protected.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32c3/security/flash-encryption.html
+
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes128, Aes256,
+};
+use esp_idf_part::{PartitionTable, Type};
+use xts_mode::Xts128;
+
+use crate::{error::Error, flasher::PARTITION_TABLE_SIZE};
+
+/// Size of the blocks which are encrypted with the same tweak
+pub(crate) const BLOCK_SIZE: usize = 0x80;
+/// Alignment of the data encrypted
+pub(crate) const ALIGNMENT: usize = 0x10;
+
+/// Length of XTS-AES-128 flash encryption keys
+pub const XTS_AES_128_KEY_LEN: usize = 32;
+/// Length of XTS-AES-256 flash encryption keys
+pub const XTS_AES_256_KEY_LEN: usize = 64;
+
+/// Encrypt data to be written to flash at the given address, as the flash
+/// encryption hardware would, using an XTS-AES-128 or XTS-AES-256 key
+///
+/// The key is given in the byte order used by ESP-IDF's key files, which is
+/// the reverse of the order it is burned into the eFuses in. The data is
+/// padded with 0xFF to a multiple of 16 bytes.
+pub fn encrypt_flash_data(key: &[u8], address: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if address as usize % BLOCK_SIZE != 0 {
+        return Err(Error::InvalidFlashEncryption(format!(
+            "the address {address:#x} is not aligned to {BLOCK_SIZE} bytes"
+        )));
+    }
+
+    let mut data = data.to_vec();
+    data.resize(data.len().div_ceil(ALIGNMENT) * ALIGNMENT, 0xff);
+
+    let cipher = Cipher::new(key)?;
+    for (index, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+        let mut tweak = [0; 16];
+        tweak[..4].copy_from_slice(&(address + (index * BLOCK_SIZE) as u32).to_le_bytes());
+
+        // The hardware operates on the blocks in reverse byte order
+        block.reverse();
+        cipher.encrypt(block, tweak);
+        block.reverse();
+    }
+
+    Ok(data)
+}
+
+/// Generate a new random XTS-AES-128 flash encryption key
+pub fn generate_key() -> Result<[u8; XTS_AES_128_KEY_LEN], Error> {
+    let mut key = [0; XTS_AES_128_KEY_LEN];
+    getrandom::getrandom(&mut key)
+        .map_err(|e| Error::InvalidFlashEncryption(format!("failed to generate a key: {e}")))?;
+
+    Ok(key)
+}
+
+/// Whether data written to flash at the given address is encrypted on the
+/// device
+///
+/// The bootloader and the partition table, which precede the first partition,
+/// are always encrypted, as are the application partitions and the partitions
+/// flagged as encrypted. Without a partition table, as for images booted
+/// directly by the ROM bootloader, everything is encrypted.
+pub fn is_encrypted_address(partition_table: Option<&PartitionTable>, address: u32) -> bool {
+    let Some(partition_table) = partition_table else {
+        return true;
+    };
+
+    let partitions = partition_table.partitions();
+    let first_partition = partitions.iter().map(|p| p.offset()).min();
+    if first_partition.map_or(true, |offset| address < offset) {
+        return true;
+    }
+
+    partitions
+        .iter()
+        .find(|p| (p.offset()..p.offset() + p.size()).contains(&address))
+        .is_some_and(|p| p.ty() == Type::App || p.encrypted())
+}
+
+/// Encrypt the regions of a merged image which are encrypted on the device
+///
+/// These are the bootloader, the partition table, the application partitions
+/// and the partitions flagged as encrypted. The image is written to flash
+/// starting at address 0, and the remaining regions are left as is.
+pub fn encrypt_merged_image(
+    key: &[u8],
+    image: &[u8],
+    bootloader_addr: u32,
+    partition_table_offset: u32,
+    partition_table: &PartitionTable,
+) -> Result<Vec<u8>, Error> {
+    let mut regions = vec![
+        (
+            bootloader_addr,
+            partition_table_offset.saturating_sub(bootloader_addr),
+        ),
+        (partition_table_offset, PARTITION_TABLE_SIZE),
+    ];
+    regions.extend(
+        partition_table
+            .partitions()
+            .iter()
+            .filter(|p| p.ty() == Type::App || p.encrypted())
+            .map(|p| (p.offset(), p.size())),
+    );
+
+    let mut image = image.to_vec();
+    for (offset, size) in regions {
+        let start = (offset as usize).min(image.len());
+        let end = (offset as usize + size as usize).min(image.len());
+        if start == end {
+            continue;
+        }
+
+        let encrypted = encrypt_flash_data(key, offset, &image[start..end])?;
+        if start + encrypted.len() > image.len() {
+            image.resize(start + encrypted.len(), 0xff);
+        }
+        image[start..start + encrypted.len()].copy_from_slice(&encrypted);
+    }
+
+    Ok(image)
+}
+
+enum Cipher {
+    Aes128(Xts128<Aes128>),
+    Aes256(Xts128<Aes256>),
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Result<Self, Error> {
+        let cipher = match key.len() {
+            XTS_AES_128_KEY_LEN => {
+                let (key1, key2) = key.split_at(16);
+                Cipher::Aes128(Xts128::new(
+                    Aes128::new(GenericArray::from_slice(key1)),
+                    Aes128::new(GenericArray::from_slice(key2)),
+                ))
+            }
+            XTS_AES_256_KEY_LEN => {
+                let (key1, key2) = key.split_at(32);
+                Cipher::Aes256(Xts128::new(
+                    Aes256::new(GenericArray::from_slice(key1)),
+                    Aes256::new(GenericArray::from_slice(key2)),
+                ))
+            }
+            len => {
+                return Err(Error::InvalidFlashEncryption(format!(
+                    "keys must be {XTS_AES_128_KEY_LEN} or {XTS_AES_256_KEY_LEN} bytes long, not \
+                     {len}"
+                )))
+            }
+        };
+
+        Ok(cipher)
+    }
+
+    fn encrypt(&self, block: &mut [u8], tweak: [u8; 16]) {
+        match self {
+            Cipher::Aes128(cipher) => cipher.encrypt_sector(block, tweak),
+            Cipher::Aes256(cipher) => cipher.encrypt_sector(block, tweak),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypts_flash_data() {
+        let key = (0..32).collect::<Vec<u8>>();
+        let data = (0..=255).collect::<Vec<u8>>();
+
+        let encrypted = encrypt_flash_data(&key, 0x10000, &data).unwrap();
+
+        assert_eq!(
+            encrypted[..16],
+            [
+                0x2c, 0x2a, 0x28, 0x8e, 0x9e, 0x96, 0x64, 0x75, 0x87, 0x88, 0x30, 0x12, 0xb8, 0x1d,
+                0x17, 0x39
+            ]
+        );
+        assert_eq!(
+            encrypted[0x80..0x90],
+            [
+                0x37, 0x5f, 0x2c, 0xca, 0x5f, 0x63, 0xa7, 0x2d, 0xc9, 0xed, 0x0f, 0x8d, 0xc7, 0x5f,
+                0xfc, 0xd5
+            ]
+        );
+    }
+
+    #[test]
+    fn selects_encrypted_addresses() {
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x4000,\nnvs_keys,data,nvs_keys,0xd000,0x1000,encrypted\n\
+             factory,app,factory,0x10000,0x1000,\n",
+        )
+        .unwrap();
+
+        assert!(is_encrypted_address(Some(&table), 0x0));
+        assert!(is_encrypted_address(Some(&table), 0x8000));
+        assert!(!is_encrypted_address(Some(&table), 0x9000));
+        assert!(is_encrypted_address(Some(&table), 0xd000));
+        assert!(is_encrypted_address(Some(&table), 0x10000));
+        assert!(!is_encrypted_address(Some(&table), 0x20000));
+        assert!(is_encrypted_address(None, 0x20000));
+    }
+
+    #[test]
+    fn encrypts_merged_images() {
+        let key = (0..32).collect::<Vec<u8>>();
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x6000,\nfactory,app,factory,0x10000,0x1000,\n",
+        )
+        .unwrap();
+        let image = vec![0xff; 0x11000];
+
+        let encrypted = encrypt_merged_image(&key, &image, 0, 0x8000, &table).unwrap();
+
+        // The NVS partition is not encrypted
+        assert!(encrypted[0x9000..0x10000].iter().all(|b| *b == 0xff));
+        assert_eq!(
+            encrypted[0x10000..0x11000],
+            encrypt_flash_data(&key, 0x10000, &image[0x10000..]).unwrap()
+        );
+        assert_ne!(encrypted[..0x8000], image[..0x8000]);
+    }
+
+    #[test]
+    fn rejects_unaligned_addresses() {
+        assert!(encrypt_flash_data(&[0; 32], 0x10010, &[0; 16]).is_err());
+    }
+}
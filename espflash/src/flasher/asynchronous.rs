@@ -0,0 +1,194 @@
+//! Drive a [Flasher] from an asynchronous runtime, using blocking threads
+//!
+//! This is not an asynchronous implementation of the loader's protocol: it is
+//! a wrapper which runs the blocking [Flasher] on [tokio]'s pool of blocking
+//! threads, rather than duplicating the protocol for an asynchronous serial
+//! port such as `tokio-serial`. The runtime's worker threads are never
+//! blocked while a device is being flashed, however each operation in progress
+//! occupies one of the blocking threads until it completes, so the pool must
+//! be large enough for the number of devices flashed at once (512 threads by
+//! default, see [tokio::runtime::Builder::max_blocking_threads]).
+//!
+//! Operations on one [AsyncFlasher] run one at a time, in the order they are
+//! started, as they queue for a fair [tokio::sync::Mutex]. Dropping the future of an operation does not interrupt it: the
+//! next operation waits for it to finish. To interrupt it, give the flasher a
+//! [CancellationToken](crate::flasher::CancellationToken) using
+//! [AsyncFlasher::with_flasher], and cancel it instead.
+
+use std::{panic, sync::Arc};
+
+use serialport::UsbPortInfo;
+use tokio::{sync::Mutex, task};
+
+use crate::{
+    connection::Port,
+    error::Error,
+    flasher::{ConnectOptions, DeviceInfo, FlashData, Flasher, ProgressCallbacks},
+    targets::{Chip, XtalFrequency},
+};
+
+/// Progress callbacks which can be moved to the thread running an operation
+pub type SendProgressCallbacks = Box<dyn ProgressCallbacks + Send>;
+
+// Operations are moved to blocking threads along with the flasher
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Flasher>();
+};
+
+/// A [Flasher] whose operations can be awaited
+#[derive(Clone)]
+pub struct AsyncFlasher {
+    flasher: Arc<Mutex<Flasher>>,
+    chip: Chip,
+}
+
+impl AsyncFlasher {
+    /// Connect to a target device, as with [Flasher::connect]
+    pub async fn connect(
+        serial: Port,
+        port_info: UsbPortInfo,
+        options: ConnectOptions,
+    ) -> Result<Self, Error> {
+        let flasher = spawn(move || Flasher::connect(serial, port_info, options)).await?;
+
+        Ok(Self::from(flasher))
+    }
+
+    /// The chip type that the flasher is connected to
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// Run an arbitrary operation on the underlying [Flasher]
+    ///
+    /// This gives access to the operations which have no asynchronous
+    /// counterpart, such as changing the flasher's settings.
+    pub async fn with_flasher<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Flasher) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        // The lock is taken before the operation is moved to a blocking thread,
+        // so that operations run in the order they are started. A panic leaves
+        // the flasher in the same state as an error would.
+        let mut flasher = self.flasher.clone().lock_owned().await;
+        spawn(move || f(&mut flasher)).await
+    }
+
+    /// Read information about the connected device, as with
+    /// [Flasher::device_info]
+    pub async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        self.with_flasher(|flasher| flasher.device_info()).await
+    }
+
+    /// Load an ELF image to RAM and execute it, as with
+    /// [Flasher::load_elf_to_ram]
+    pub async fn load_elf_to_ram(
+        &self,
+        elf_data: Vec<u8>,
+        mut progress: Option<SendProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.with_flasher(move |flasher| {
+            flasher.load_elf_to_ram(&elf_data, callbacks(&mut progress))
+        })
+        .await
+    }
+
+    /// Load an ELF image to flash and execute it, as with
+    /// [Flasher::load_elf_to_flash]
+    pub async fn load_elf_to_flash(
+        &self,
+        elf_data: Vec<u8>,
+        flash_data: FlashData,
+        mut progress: Option<SendProgressCallbacks>,
+        xtal_freq: XtalFrequency,
+    ) -> Result<(), Error> {
+        self.with_flasher(move |flasher| {
+            flasher.load_elf_to_flash(&elf_data, flash_data, callbacks(&mut progress), xtal_freq)
+        })
+        .await
+    }
+
+    /// Load a bin image to flash at a specific address, as with
+    /// [Flasher::write_bin_to_flash]
+    pub async fn write_bin_to_flash(
+        &self,
+        addr: u32,
+        data: Vec<u8>,
+        mut progress: Option<SendProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.with_flasher(move |flasher| {
+            flasher.write_bin_to_flash(addr, &data, callbacks(&mut progress))
+        })
+        .await
+    }
+
+    /// Read a region of the flash, as with [Flasher::read_flash_data]
+    pub async fn read_flash(
+        &self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        self.with_flasher(move |flasher| {
+            flasher.read_flash_data(offset, size, block_size, max_in_flight)
+        })
+        .await
+    }
+
+    /// Get the MD5 digest of a region of the flash, as with
+    /// [Flasher::checksum_md5]
+    pub async fn checksum_md5(&self, addr: u32, length: u32) -> Result<u128, Error> {
+        self.with_flasher(move |flasher| flasher.checksum_md5(addr, length))
+            .await
+    }
+
+    /// Erase a region of the flash, as with [Flasher::erase_region]
+    pub async fn erase_region(&self, offset: u32, size: u32) -> Result<(), Error> {
+        self.with_flasher(move |flasher| flasher.erase_region(offset, size))
+            .await
+    }
+
+    /// Erase the whole flash, as with [Flasher::erase_flash]
+    pub async fn erase_flash(&self) -> Result<(), Error> {
+        self.with_flasher(|flasher| flasher.erase_flash()).await
+    }
+
+    /// Change the baud rate of the connection, as with [Flasher::change_baud]
+    pub async fn change_baud(&self, speed: u32) -> Result<(), Error> {
+        self.with_flasher(move |flasher| flasher.change_baud(speed))
+            .await
+    }
+}
+
+impl From<Flasher> for AsyncFlasher {
+    fn from(flasher: Flasher) -> Self {
+        AsyncFlasher {
+            chip: flasher.chip(),
+            flasher: Arc::new(Mutex::new(flasher)),
+        }
+    }
+}
+
+/// Run a blocking operation on [tokio]'s pool of blocking threads, resuming
+/// any panic it raised
+async fn spawn<T, F>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+        // Blocking tasks are only cancelled when the runtime shuts down
+        Err(_) => Err(Error::RuntimeShutdown),
+    }
+}
+
+/// Borrow the callbacks moved to the thread running an operation
+fn callbacks(progress: &mut Option<SendProgressCallbacks>) -> Option<&mut dyn ProgressCallbacks> {
+    let progress: &mut dyn ProgressCallbacks = progress.as_deref_mut()?;
+    Some(progress)
+}
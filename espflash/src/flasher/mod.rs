@@ -4,11 +4,27 @@
 //! application to a target device. It additionally provides some operations to
 //! read information from the target device.
 
-use std::{fs, path::Path, str::FromStr};
+use std::{
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 #[cfg(feature = "serialport")]
-use std::{borrow::Cow, io::Write, path::PathBuf, thread::sleep, time::Duration};
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    path::PathBuf,
+    thread::sleep,
+    time::Duration,
+};
 
+#[cfg(feature = "serialport")]
+use esp_idf_part::Partition;
 use esp_idf_part::PartitionTable;
 
 #[cfg(feature = "serialport")]
@@ -21,9 +37,15 @@ use serialport::UsbPortInfo;
 use strum::IntoEnumIterator;
 use strum::{Display, EnumIter, VariantNames};
 
+#[cfg(feature = "flash-encryption")]
+use crate::flash_encryption::{XTS_AES_128_KEY_LEN, XTS_AES_256_KEY_LEN};
 use crate::{
+    elf::RtcSegments,
     error::Error,
-    targets::{Chip, XtalFrequency},
+    file_format::load_binary,
+    image_format::{AppDescriptorOverrides, ImageFormatKind, PartitionFile, TargetOs},
+    partition_table,
+    targets::{Chip, EmbeddedFlashInfo, PsramInfo, XtalFrequency},
 };
 
 #[cfg(feature = "serialport")]
@@ -31,21 +53,34 @@ use crate::{
     command::{Command, CommandType},
     connection::{
         reset::{ResetAfterOperation, ResetBeforeOperation},
+        trace::Tracer,
         Connection, Port,
     },
     elf::{ElfFirmwareImage, FirmwareImage, RomSegment},
     error::{ConnectionError, ResultExt},
+    flasher::sfdp::Sfdp,
     flasher::stubs::{
         FlashStub, CHIP_DETECT_MAGIC_REG_ADDR, DEFAULT_TIMEOUT, EXPECTED_STUB_HANDSHAKE,
+        PING_TIMEOUT,
     },
+    image_format::{AppDescriptor, ImageInfo},
+    ota::{otadata_partition, OtaData, OTADATA_SIZE},
+    targets::{check_compatible_chip, flash_target::FlashTarget},
 };
 
 #[cfg(feature = "serialport")]
-pub use crate::targets::flash_target::ProgressCallbacks;
+pub use crate::targets::flash_target::{ProgressCallbacks, ProgressEvent};
 
 #[cfg(feature = "serialport")]
 pub(crate) use stubs::{FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE};
 
+#[cfg(feature = "async")]
+pub use self::asynchronous::AsyncFlasher;
+
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub mod asynchronous;
+pub mod sfdp;
 #[cfg(feature = "serialport")]
 pub(crate) mod stubs;
 
@@ -126,6 +161,115 @@ pub enum FlashMode {
     Dio,
     /// Dual Output (2 pins used for data)
     Dout,
+    /// Octal I/O (8 pins used for address & data), only supported by the
+    /// ESP32-S3
+    Opi,
+}
+
+impl FlashMode {
+    /// Encodes flash mode into the format used by the bootloader.
+    pub fn encode_flash_mode(self: FlashMode, chip: Chip) -> Result<u8, Error> {
+        match self {
+            // The bootloader determines that the flash is octal from the eFuses,
+            // while the header indicates the mode used by the ROM bootloader to
+            // read it, before it is switched into octal mode
+            FlashMode::Opi if chip.supports_octal_flash() => Ok(FlashMode::Dout as u8),
+            FlashMode::Opi => Err(Error::UnsupportedFeature {
+                chip,
+                feature: "octal flash".into(),
+            }),
+            mode => Ok(mode as u8),
+        }
+    }
+}
+
+/// Supply voltage of the flash, provided by the VDD_SDIO regulator
+///
+/// Note that only the ESP32 supports overriding the voltage.
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Display, VariantNames, Serialize, Deserialize,
+)]
+#[non_exhaustive]
+pub enum FlashVoltage {
+    /// Keep the voltage selected by the strapping pin or eFuses
+    #[default]
+    #[serde(rename = "keep")]
+    #[strum(serialize = "keep")]
+    Keep,
+    /// 1.8 V
+    #[cfg_attr(feature = "cli", value(name = "1.8"))]
+    #[serde(rename = "1.8V")]
+    #[strum(serialize = "1.8V")]
+    _1_8V,
+    /// 3.3 V
+    #[cfg_attr(feature = "cli", value(name = "3.3"))]
+    #[serde(rename = "3.3V")]
+    #[strum(serialize = "3.3V")]
+    _3_3V,
+}
+
+/// How writes to the flash are retried when they fail
+///
+/// When writing a segment fails because of a communication error, or because
+/// its contents could not be verified, the connection is resynchronised and
+/// the write resumes from the first chunk of the segment which does not match
+/// the flash, rather than starting over. By default, failed writes are not
+/// retried.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct RetryPolicy {
+    /// Number of times the write of each segment is resumed
+    pub retries: u32,
+    /// Reset and reconnect to the device if it no longer responds, rather
+    /// than giving up
+    pub reconnect: bool,
+}
+
+impl RetryPolicy {
+    /// Never retry a failed write, which is the default
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Resume the write of each segment up to this many times
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Reset and reconnect to the device if it no longer responds
+    pub fn with_reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+}
+
+/// Cancels a long-running operation from another thread
+///
+/// Once a token has been given to a [Flasher], writing to, erasing and reading
+/// the flash check it between blocks. When it is cancelled, the operation
+/// fails with [Error::Cancelled] and the device is reset as requested when
+/// connecting, as it is left part way through a command. A cancelled token
+/// stays cancelled, so a new one is needed for the next operation.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a token which has not been cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the operation checking this token, or the next one to start
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Has this token been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 }
 
 /// Supported flash sizes
@@ -300,6 +444,19 @@ pub struct FlashDataBuilder<'a> {
     target_app_partition: Option<String>,
     flash_settings: FlashSettings,
     min_chip_rev: u16,
+    image_format: ImageFormatKind,
+    mcuboot_key_path: Option<&'a Path>,
+    sign_key_path: Option<&'a Path>,
+    sign_command: Option<(Vec<String>, &'a Path)>,
+    encryption_key_path: Option<&'a Path>,
+    app_descriptor: AppDescriptorOverrides,
+    rtc_segments: RtcSegments,
+    skip_bootloader: bool,
+    skip_partition_table: bool,
+    skip_partition_table_md5: bool,
+    partition_files: Vec<(String, &'a Path)>,
+    os: Option<TargetOs>,
+    max_partition_usage: Option<f32>,
 }
 
 impl<'a> Default for FlashDataBuilder<'a> {
@@ -311,6 +468,19 @@ impl<'a> Default for FlashDataBuilder<'a> {
             target_app_partition: Default::default(),
             flash_settings: FlashSettings::default(),
             min_chip_rev: Default::default(),
+            image_format: Default::default(),
+            mcuboot_key_path: Default::default(),
+            sign_key_path: Default::default(),
+            sign_command: Default::default(),
+            encryption_key_path: Default::default(),
+            app_descriptor: Default::default(),
+            rtc_segments: Default::default(),
+            skip_bootloader: false,
+            skip_partition_table: false,
+            skip_partition_table_md5: false,
+            partition_files: Vec::new(),
+            os: None,
+            max_partition_usage: None,
         }
     }
 }
@@ -357,16 +527,213 @@ impl<'a> FlashDataBuilder<'a> {
         self
     }
 
+    /// Sets the image format.
+    pub fn with_image_format(mut self, image_format: ImageFormatKind) -> Self {
+        self.image_format = image_format;
+        self
+    }
+
+    /// Sets the path of the key used to sign MCUboot images.
+    pub fn with_mcuboot_key(mut self, mcuboot_key_path: &'a Path) -> Self {
+        self.mcuboot_key_path = Some(mcuboot_key_path);
+        self
+    }
+
+    /// Sets the path of the key used to sign images for Secure Boot V2.
+    pub fn with_sign_key(mut self, sign_key_path: &'a Path) -> Self {
+        self.sign_key_path = Some(sign_key_path);
+        self
+    }
+
+    /// Sets the external command used to sign images for Secure Boot V2, made
+    /// up of the program followed by its arguments, and the path of the
+    /// matching public key.
+    pub fn with_sign_command(mut self, command: Vec<String>, public_key_path: &'a Path) -> Self {
+        self.sign_command = Some((command, public_key_path));
+        self
+    }
+
+    /// Sets the path of the flash encryption key used to encrypt images on the
+    /// host.
+    pub fn with_encryption_key(mut self, encryption_key_path: &'a Path) -> Self {
+        self.encryption_key_path = Some(encryption_key_path);
+        self
+    }
+
+    /// Sets the overrides for the fields of the application description.
+    pub fn with_app_descriptor(mut self, app_descriptor: AppDescriptorOverrides) -> Self {
+        self.app_descriptor = app_descriptor;
+        self
+    }
+
+    /// Sets how sections placed in RTC memory are handled.
+    pub fn with_rtc_segments(mut self, rtc_segments: RtcSegments) -> Self {
+        self.rtc_segments = rtc_segments;
+        self
+    }
+
+    /// Sets whether writing the bootloader is skipped.
+    pub fn with_skip_bootloader(mut self, skip_bootloader: bool) -> Self {
+        self.skip_bootloader = skip_bootloader;
+        self
+    }
+
+    /// Sets whether writing the partition table is skipped.
+    pub fn with_skip_partition_table(mut self, skip_partition_table: bool) -> Self {
+        self.skip_partition_table = skip_partition_table;
+        self
+    }
+
+    /// Sets whether the MD5 checksum is left out of the partition table.
+    pub fn with_skip_partition_table_md5(mut self, skip_partition_table_md5: bool) -> Self {
+        self.skip_partition_table_md5 = skip_partition_table_md5;
+        self
+    }
+
+    /// Adds a file to be written to the partition with the given label.
+    pub fn with_partition_file(mut self, label: String, path: &'a Path) -> Self {
+        self.partition_files.push((label, path));
+        self
+    }
+
+    /// Sets the operating system the application was built with.
+    pub fn with_os(mut self, os: TargetOs) -> Self {
+        self.os = Some(os);
+        self
+    }
+
+    /// Sets the percentage of a partition the image may use at most.
+    pub fn with_max_partition_usage(mut self, percent: f32) -> Self {
+        self.max_partition_usage = Some(percent);
+        self
+    }
+
     /// Builds a [`FlashData`] object.
     pub fn build(self) -> Result<FlashData, Error> {
-        FlashData::new(
-            self.bootloader_path,
-            self.partition_table_path,
-            self.partition_table_offset,
-            self.target_app_partition,
-            self.flash_settings,
-            self.min_chip_rev,
-        )
+        let FlashDataBuilder {
+            bootloader_path: bootloader,
+            partition_table_path: partition_table,
+            partition_table_offset,
+            target_app_partition,
+            flash_settings,
+            min_chip_rev,
+            image_format,
+            mcuboot_key_path: mcuboot_key,
+            sign_key_path: sign_key,
+            sign_command,
+            encryption_key_path: encryption_key,
+            app_descriptor,
+            rtc_segments,
+            skip_bootloader,
+            skip_partition_table,
+            skip_partition_table_md5,
+            partition_files,
+            os,
+            max_partition_usage,
+        } = self;
+
+        // If the '--bootloader' option is provided, load the binary or Intel HEX
+        // file at the specified path.
+        let bootloader = if let Some(path) = bootloader {
+            let path = fs::canonicalize(path)
+                .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+            Some(load_binary(&path)?)
+        } else {
+            None
+        };
+
+        // If the '-T' option is provided, load the partition table from
+        // the CSV or binary file at the specified path.
+        let partition_table = match partition_table {
+            Some(path) => Some(parse_partition_table(path)?),
+            None => None,
+        };
+
+        // If the '--mcuboot-key' option is provided, load the signing key at the
+        // specified path.
+        let mcuboot_key = match mcuboot_key {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?,
+            ),
+            None => None,
+        };
+
+        // If the '--sign-key' option is provided, load the Secure Boot signing
+        // key at the specified path.
+        let sign_key = match sign_key {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?,
+            ),
+            None => None,
+        };
+
+        // If the '--sign-command' option is provided, load the public key
+        // matching the key used by the command.
+        let sign_command = match sign_command {
+            Some((command, path)) => Some((
+                command,
+                fs::read_to_string(path)
+                    .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?,
+            )),
+            None => None,
+        };
+
+        // If the '--encryption-key' option is provided, load the flash
+        // encryption key at the specified path.
+        let encryption_key = match encryption_key {
+            Some(path) => {
+                let key = fs::read(path)
+                    .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+                #[cfg(feature = "flash-encryption")]
+                if key.len() != XTS_AES_128_KEY_LEN && key.len() != XTS_AES_256_KEY_LEN {
+                    return Err(Error::InvalidFlashEncryption(format!(
+                        "the key must be {XTS_AES_128_KEY_LEN} or {XTS_AES_256_KEY_LEN} bytes \
+                         long, not {}",
+                        key.len()
+                    )));
+                }
+
+                Some(key)
+            }
+            None => None,
+        };
+
+        // Load the files to be written to partitions, which are located once
+        // the partition table is known.
+        let partition_files = partition_files
+            .into_iter()
+            .map(|(label, path)| {
+                let data = fs::read(path)
+                    .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+                Ok(PartitionFile { label, data })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(FlashData {
+            bootloader,
+            partition_table,
+            partition_table_offset,
+            target_app_partition,
+            flash_settings,
+            min_chip_rev,
+            image_format,
+            mcuboot_key,
+            sign_key,
+            sign_command,
+            encryption_key,
+            app_descriptor,
+            rtc_segments,
+            skip_bootloader,
+            skip_partition_table,
+            partition_files,
+            os,
+            max_partition_usage,
+            skip_partition_table_md5,
+        })
     }
 }
 
@@ -380,6 +747,26 @@ pub struct FlashData {
     pub target_app_partition: Option<String>,
     pub flash_settings: FlashSettings,
     pub min_chip_rev: u16,
+    pub image_format: ImageFormatKind,
+    pub mcuboot_key: Option<String>,
+    pub sign_key: Option<String>,
+    /// External signing command, as the program followed by its arguments,
+    /// and the public key matching its key, in PEM format
+    pub sign_command: Option<(Vec<String>, String)>,
+    /// Flash encryption key used to encrypt the image on the host
+    pub encryption_key: Option<Vec<u8>>,
+    pub app_descriptor: AppDescriptorOverrides,
+    pub rtc_segments: RtcSegments,
+    pub skip_bootloader: bool,
+    pub skip_partition_table: bool,
+    pub partition_files: Vec<PartitionFile>,
+    pub os: Option<TargetOs>,
+    /// Percentage of a partition the image may use at most, above which
+    /// building the image fails
+    pub max_partition_usage: Option<f32>,
+    /// Leave the MD5 checksum out of the partition table, for bootloaders from
+    /// ESP-IDF versions before v3.1
+    pub skip_partition_table_md5: bool,
 }
 
 impl FlashData {
@@ -391,33 +778,16 @@ impl FlashData {
         flash_settings: FlashSettings,
         min_chip_rev: u16,
     ) -> Result<Self, Error> {
-        // If the '--bootloader' option is provided, load the binary file at the
-        // specified path.
-        let bootloader = if let Some(path) = bootloader {
-            let data = fs::canonicalize(path)
-                .and_then(fs::read)
-                .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
-
-            Some(data)
-        } else {
-            None
-        };
-
-        // If the '-T' option is provided, load the partition table from
-        // the CSV or binary file at the specified path.
-        let partition_table = match partition_table {
-            Some(path) => Some(parse_partition_table(path)?),
-            None => None,
-        };
-
-        Ok(FlashData {
-            bootloader,
-            partition_table,
+        FlashDataBuilder {
+            bootloader_path: bootloader,
+            partition_table_path: partition_table,
             partition_table_offset,
             target_app_partition,
             flash_settings,
             min_chip_rev,
-        })
+            ..FlashDataBuilder::default()
+        }
+        .build()
     }
 }
 
@@ -467,7 +837,12 @@ impl SpiSetParams {
 }
 
 /// Parameters for attaching to a target devices SPI flash
-#[derive(Copy, Clone, Debug)]
+///
+/// The GPIO pins the flash is connected to are given, or 0 to use the pins
+/// selected by the eFuses. These may be parsed from esptool's
+/// `--spi-connection` format, either `CLK,Q,D,HD,CS` or one of the presets
+/// `spi`, `hspi` and `pico-d4`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct SpiAttachParams {
     clk: u8,
@@ -478,6 +853,14 @@ pub struct SpiAttachParams {
 }
 
 impl SpiAttachParams {
+    /// Largest GPIO number which may be encoded
+    const MAX_PIN: u8 = 0x3f;
+
+    /// Attach the flash connected to the given GPIO pins
+    pub const fn new(clk: u8, q: u8, d: u8, hd: u8, cs: u8) -> Self {
+        SpiAttachParams { clk, q, d, hd, cs }
+    }
+
     pub const fn default() -> Self {
         SpiAttachParams {
             clk: 0,
@@ -499,6 +882,22 @@ impl SpiAttachParams {
         }
     }
 
+    // The HSPI pins, which the ROM loader selects with a value of 1
+    pub const fn hspi() -> Self {
+        SpiAttachParams {
+            clk: 1,
+            q: 0,
+            d: 0,
+            hd: 0,
+            cs: 0,
+        }
+    }
+
+    /// Are the pins selected by the eFuses used?
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
     /// Encode the parameters into a byte array
     pub fn encode(self, stub: bool) -> Vec<u8> {
         let packed = ((self.hd as u32) << 24)
@@ -517,6 +916,34 @@ impl SpiAttachParams {
     }
 }
 
+impl FromStr for SpiAttachParams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "spi" => return Ok(Self::default()),
+            "hspi" => return Ok(Self::hspi()),
+            "pico-d4" => return Ok(Self::esp32_pico_d4()),
+            _ => {}
+        }
+
+        let pins = s
+            .split(',')
+            .map(|pin| {
+                pin.trim()
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|pin| *pin <= Self::MAX_PIN)
+            })
+            .collect::<Option<Vec<_>>>();
+
+        match pins.as_deref() {
+            Some(&[clk, q, d, hd, cs]) => Ok(Self::new(clk, q, d, hd, cs)),
+            _ => Err(Error::InvalidSpiConnection(s.to_string())),
+        }
+    }
+}
+
 /// Information about the connected device
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -532,20 +959,201 @@ pub struct DeviceInfo {
     pub features: Vec<String>,
     /// MAC address
     pub mac_address: String,
+    /// PSRAM embedded in the chip's package, if any
+    pub psram: Option<PsramInfo>,
+    /// Flash embedded in the chip's package, if any
+    pub embedded_flash: Option<EmbeddedFlashInfo>,
 }
 
 /// Parse a [PartitionTable] from the provided path
+///
+/// The partition table may be in CSV, binary or TOML format, TOML partition
+/// tables being recognised by their `.toml` extension.
 pub fn parse_partition_table(path: &Path) -> Result<PartitionTable, Error> {
     let data = fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
 
+    if partition_table::is_toml(path) {
+        #[cfg(feature = "toml")]
+        return partition_table::from_toml(&String::from_utf8_lossy(&data));
+        #[cfg(not(feature = "toml"))]
+        return Err(Error::FeatureDisabled(
+            "Reading TOML partition tables",
+            "toml",
+        ));
+    }
+
     Ok(PartitionTable::try_from(data)?)
 }
 
+/// Maximum size of a binary partition table
+pub(crate) const PARTITION_TABLE_SIZE: u32 = 0xc00;
+
+#[cfg(feature = "serialport")]
+/// Size of the chunks in which flash is read into a writer, each of which is
+/// checked and written out before the next is read
+const READ_FLASH_CHUNK_SIZE: u32 = 0x10000;
+
+#[cfg(feature = "serialport")]
+/// Size of the chunks of a segment which are checked before resuming a failed
+/// write, which must be a multiple of the sector size
+pub(crate) const RESUME_CHUNK_SIZE: usize = 0x8000;
+
+#[cfg(feature = "serialport")]
+/// Number of times the connection is resynchronised after a failed write,
+/// before reconnecting
+const MAX_RESYNC_ATTEMPTS: usize = 5;
+
+#[cfg(feature = "serialport")]
+/// Size of the regions in which the flash is erased when the operation can be
+/// cancelled, rather than erasing the whole chip at once
+const ERASE_FLASH_CHUNK_SIZE: u32 = 0x10_0000;
+
+#[cfg(feature = "serialport")]
+/// Could writing a segment succeed if attempted again?
+fn is_retryable(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Connection(_) | Error::Flashing(_) | Error::RomError(_) | Error::VerifyFailed
+    )
+}
+
 #[cfg(feature = "serialport")]
 /// List of SPI parameters to try while detecting flash size
 pub(crate) const TRY_SPI_PARAMS: [SpiAttachParams; 2] =
     [SpiAttachParams::default(), SpiAttachParams::esp32_pico_d4()];
 
+#[cfg(feature = "serialport")]
+/// How to connect to a target device with [Flasher::connect]
+///
+/// ```rust
+/// use espflash::{flasher::ConnectOptions, targets::Chip};
+///
+/// let options = ConnectOptions::new()
+///     .with_speed(921_600)
+///     .with_chip(Chip::Esp32c3);
+/// ```
+#[non_exhaustive]
+pub struct ConnectOptions {
+    /// Baud rate to switch to once connected, if higher than 115,200
+    pub speed: Option<u32>,
+    /// Load the flash stub, when one is available for the chip
+    pub use_stub: bool,
+    /// Verify the flash contents after writing them
+    pub verify: bool,
+    /// Skip writing segments which are already in the flash
+    pub skip: bool,
+    /// The chip expected to be connected, which must be given when not
+    /// syncing with the device
+    pub chip: Option<Chip>,
+    /// How to reset the device once done
+    pub after_operation: ResetAfterOperation,
+    /// How to reset the device into its bootloader when connecting
+    pub before_operation: ResetBeforeOperation,
+    /// Log the frames exchanged with the device
+    pub tracer: Option<Tracer>,
+    /// Override the voltage of the flash
+    pub flash_voltage: FlashVoltage,
+    /// Pins the flash is connected to, rather than those set in the eFuses
+    pub spi_connection: Option<SpiAttachParams>,
+    /// Directory of the stubs downloaded using `espflash stub update`, which
+    /// are used rather than the embedded ones
+    pub stub_cache: Option<PathBuf>,
+}
+
+#[cfg(feature = "serialport")]
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            speed: None,
+            use_stub: true,
+            verify: true,
+            skip: true,
+            chip: None,
+            after_operation: ResetAfterOperation::default(),
+            before_operation: ResetBeforeOperation::default(),
+            tracer: None,
+            flash_voltage: FlashVoltage::default(),
+            spi_connection: None,
+            stub_cache: None,
+        }
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl ConnectOptions {
+    /// Creates a new [`ConnectOptions`] object, matching the command-line
+    /// defaults
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the baud rate to switch to once connected.
+    pub fn with_speed(mut self, speed: u32) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
+    /// Sets whether to load the flash stub.
+    pub fn with_stub(mut self, use_stub: bool) -> Self {
+        self.use_stub = use_stub;
+        self
+    }
+
+    /// Sets whether to verify the flash contents after writing them.
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets whether to skip segments which are already in the flash.
+    pub fn with_skip(mut self, skip: bool) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// Sets the chip expected to be connected.
+    pub fn with_chip(mut self, chip: Chip) -> Self {
+        self.chip = Some(chip);
+        self
+    }
+
+    /// Sets how to reset the device once done.
+    pub fn with_after_operation(mut self, after_operation: ResetAfterOperation) -> Self {
+        self.after_operation = after_operation;
+        self
+    }
+
+    /// Sets how to reset the device into its bootloader when connecting.
+    pub fn with_before_operation(mut self, before_operation: ResetBeforeOperation) -> Self {
+        self.before_operation = before_operation;
+        self
+    }
+
+    /// Sets the tracer logging the frames exchanged with the device.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Sets the voltage of the flash.
+    pub fn with_flash_voltage(mut self, flash_voltage: FlashVoltage) -> Self {
+        self.flash_voltage = flash_voltage;
+        self
+    }
+
+    /// Sets the pins the flash is connected to.
+    pub fn with_spi_connection(mut self, spi_connection: SpiAttachParams) -> Self {
+        self.spi_connection = Some(spi_connection);
+        self
+    }
+
+    /// Sets the directory of the downloaded stubs.
+    pub fn with_stub_cache(mut self, stub_cache: PathBuf) -> Self {
+        self.stub_cache = Some(stub_cache);
+        self
+    }
+}
+
 #[cfg(feature = "serialport")]
 /// Connect to and flash a target device
 pub struct Flasher {
@@ -557,30 +1165,52 @@ pub struct Flasher {
     flash_size: FlashSize,
     /// Configuration for SPI attached flash (0 to use fused values)
     spi_params: SpiAttachParams,
+    /// Size of the boot flash, when an external flash chip is attached instead
+    boot_flash_size: Option<FlashSize>,
     /// Indicate RAM stub loader is in use
     use_stub: bool,
     /// Indicate verifying flash contents after flashing
     verify: bool,
     /// Indicate skipping of already flashed regions
     skip: bool,
+    /// Indicate compressing data before writing it to the flash
+    compress: bool,
+    /// How failed writes are retried
+    retry_policy: RetryPolicy,
+    /// The flash stub in use, to load again after reconnecting
+    stub: Option<FlashStub>,
+    /// The flash voltage override, to apply again after reconnecting
+    flash_voltage: FlashVoltage,
 }
 
 #[cfg(feature = "serialport")]
 impl Flasher {
+    /// Connect to a target device over the given port
     pub fn connect(
         serial: Port,
         port_info: UsbPortInfo,
-        speed: Option<u32>,
-        use_stub: bool,
-        verify: bool,
-        skip: bool,
-        chip: Option<Chip>,
-        after_operation: ResetAfterOperation,
-        before_operation: ResetBeforeOperation,
+        options: ConnectOptions,
     ) -> Result<Self, Error> {
+        let ConnectOptions {
+            speed,
+            use_stub,
+            verify,
+            skip,
+            chip,
+            after_operation,
+            before_operation,
+            tracer,
+            flash_voltage,
+            spi_connection,
+            stub_cache,
+        } = options;
+
         // Establish a connection to the device using the default baud rate of 115,200
         // and timeout of 3 seconds.
         let mut connection = Connection::new(serial, port_info, after_operation, before_operation);
+        if let Some(tracer) = tracer {
+            connection.set_tracer(tracer);
+        }
         connection.begin()?;
         connection.set_timeout(DEFAULT_TIMEOUT)?;
 
@@ -603,14 +1233,45 @@ impl Flasher {
             return Err(Error::ChipNotProvided);
         };
 
+        // Stubs downloaded using `espflash stub update` take precedence over the
+        // ones embedded in espflash
+        let stub = match &stub_cache {
+            Some(dir) => FlashStub::get_cached(detected_chip, dir),
+            None => FlashStub::get(detected_chip),
+        };
+
+        // Not all chips have a flash stub, in which case the ROM loader is used
+        let stub = match stub {
+            Some(stub) if use_stub => Some(stub),
+            None if use_stub => {
+                warn!("No flash stub is available for {detected_chip}, using the ROM loader");
+                None
+            }
+            _ => None,
+        };
+        let use_stub = stub.is_some();
+
+        // The ESP8266 ROM loader is unable to calculate the MD5 checksum of the flash
+        let (verify, skip) = if detected_chip.is_esp8266() && !use_stub && (verify || skip) {
+            warn!("The ESP8266 ROM loader is unable to verify the flash contents");
+            (false, false)
+        } else {
+            (verify, skip)
+        };
+
         let mut flasher = Flasher {
             connection,
             chip: detected_chip,
             flash_size: FlashSize::_4Mb,
             spi_params: SpiAttachParams::default(),
+            boot_flash_size: None,
             use_stub,
             verify,
             skip,
+            compress: true,
+            retry_policy: RetryPolicy::default(),
+            stub: stub.clone(),
+            flash_voltage,
         };
 
         if before_operation == ResetBeforeOperation::NoResetNoSync {
@@ -618,19 +1279,26 @@ impl Flasher {
         }
 
         // Load flash stub if enabled
-        if use_stub {
+        if let Some(stub) = stub {
             info!("Using flash stub");
-            flasher.load_stub()?;
+            flasher.load_stub(stub)?;
         }
 
-        flasher.spi_autodetect()?;
+        // The flash must be powered before it can be detected
+        flasher.set_flash_voltage(flash_voltage)?;
+
+        flasher.spi_autodetect(spi_connection)?;
 
         // Now that we have established a connection and detected the chip and flash
         // size, we can set the baud rate of the connection to the configured value.
         if let Some(baud) = speed {
             if baud > 115_200 {
-                warn!("Setting baud rate higher than 115,200 can cause issues");
-                flasher.change_baud(baud)?;
+                if flasher.chip.is_esp8266() && !flasher.use_stub {
+                    warn!("The ESP8266 ROM loader is unable to change the baud rate");
+                } else {
+                    warn!("Setting baud rate higher than 115,200 can cause issues");
+                    flasher.change_baud(baud)?;
+                }
             }
         }
 
@@ -641,21 +1309,37 @@ impl Flasher {
         self.flash_size = flash_size;
     }
 
+    /// Select how failed writes to the flash are retried
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Check the given token between the blocks of long-running operations,
+    /// so that they can be cancelled from another thread
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.connection.set_cancellation_token(token);
+    }
+
+    /// Enable or disable compressing data before it is written to the flash
+    ///
+    /// Compression is enabled by default. Disabling it is much slower, but
+    /// avoids relying on the loader's decompressor.
+    pub fn set_compression(&mut self, compress: bool) {
+        self.compress = compress;
+    }
+
     pub fn disable_watchdog(&mut self) -> Result<(), Error> {
-        let mut target = self
-            .chip
-            .flash_target(self.spi_params, self.use_stub, false, false);
+        let mut target =
+            self.chip
+                .flash_target(self.spi_params, self.use_stub, false, false, false);
         target.begin(&mut self.connection).flashing()?;
         Ok(())
     }
 
     /// Load flash stub
-    fn load_stub(&mut self) -> Result<(), Error> {
+    fn load_stub(&mut self, stub: FlashStub) -> Result<(), Error> {
         debug!("Loading flash stub for chip: {:?}", self.chip);
 
-        // Load flash stub
-        let stub = FlashStub::get(self.chip);
-
         let mut ram_target = self.chip.ram_target(
             Some(stub.entry()),
             self.chip
@@ -710,10 +1394,48 @@ impl Flasher {
         Ok(())
     }
 
-    fn spi_autodetect(&mut self) -> Result<(), Error> {
-        // Loop over all available SPI parameters until we find one that successfully
-        // reads the flash size.
-        for spi_params in TRY_SPI_PARAMS.iter().copied() {
+    /// Override the supply voltage of the flash until the device is reset
+    fn set_flash_voltage(&mut self, voltage: FlashVoltage) -> Result<(), Error> {
+        if voltage == FlashVoltage::Keep {
+            return Ok(());
+        }
+
+        let (reg, value) = self
+            .chip
+            .into_target()
+            .flash_voltage_override(voltage)
+            .ok_or_else(|| Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "overriding the flash voltage".into(),
+            })?;
+
+        info!("Setting flash voltage to {voltage}");
+        self.connection.write_reg(reg, value, None)?;
+
+        // Give the regulator time to settle
+        sleep(Duration::from_millis(100));
+
+        Ok(())
+    }
+
+    fn spi_autodetect(&mut self, spi_connection: Option<SpiAttachParams>) -> Result<(), Error> {
+        // The ESP8266 always uses the same pins for its flash
+        if self.chip.is_esp8266() && spi_connection.is_some_and(|p| !p.is_default()) {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "custom SPI flash pins".into(),
+            });
+        }
+
+        // Only the provided SPI parameters are used, otherwise loop over all
+        // available SPI parameters until we find one that successfully reads the
+        // flash size.
+        let try_spi_params = match spi_connection {
+            Some(spi_params) => vec![spi_params],
+            None => TRY_SPI_PARAMS.to_vec(),
+        };
+
+        for spi_params in try_spi_params {
             debug!("Attempting flash enable with: {:?}", spi_params);
 
             // Send `SpiAttach` to enable flash, in some instances this command
@@ -730,6 +1452,11 @@ impl Flasher {
                 self.flash_size = flash_size;
                 self.spi_params = spi_params;
 
+                // The ESP8266 ROM loader does not support setting the flash parameters
+                if self.chip.is_esp8266() && !self.use_stub {
+                    return Ok(());
+                }
+
                 let spi_set_params = SpiSetParams::default(self.flash_size.size());
                 self.connection.with_timeout(
                     CommandType::SpiSetParams.timeout(),
@@ -778,7 +1505,42 @@ impl Flasher {
         Ok(Some(flash_size))
     }
 
+    /// Read the SFDP tables of the attached flash chip, describing its
+    /// capacity, supported erase operations and timings
+    pub fn read_sfdp(&mut self) -> Result<Sfdp, Error> {
+        Sfdp::read(|addr, len| {
+            let mut data = Vec::with_capacity(len);
+            for offset in (0..len as u32).step_by(4) {
+                // The 24-bit address is followed by 8 dummy cycles
+                let addr = (addr + offset).to_be_bytes();
+                let word =
+                    self.spi_command(CommandType::ReadSfdp, &[addr[1], addr[2], addr[3], 0], 32)?;
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+            data.truncate(len);
+
+            Ok(data)
+        })
+    }
+
     fn enable_flash(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        // The ESP8266 ROM loader has no command to attach the flash, however it is
+        // attached when flashing begins
+        if self.chip.is_esp8266() && !self.use_stub {
+            self.connection
+                .with_timeout(CommandType::FlashBegin.timeout(), |connection| {
+                    connection.command(Command::FlashBegin {
+                        size: 0,
+                        blocks: 0,
+                        block_size: FLASH_WRITE_SIZE as u32,
+                        offset: 0,
+                        supports_encryption: false,
+                    })
+                })?;
+
+            return Ok(());
+        }
+
         self.connection
             .with_timeout(CommandType::SpiAttach.timeout(), |connection| {
                 connection.command(if self.use_stub {
@@ -797,7 +1559,7 @@ impl Flasher {
         data: &[u8],
         read_bits: u32,
     ) -> Result<u32, Error> {
-        assert!(read_bits < 32);
+        assert!(read_bits <= 32);
         assert!(data.len() < 64);
 
         let spi_registers = self.chip.into_target().spi_registers();
@@ -889,6 +1651,69 @@ impl Flasher {
         self.chip
     }
 
+    /// Attach the external flash chip connected to the given pins, so that all
+    /// subsequent flash operations target it rather than the boot flash
+    ///
+    /// The size of the external flash is detected separately, while the size
+    /// of the boot flash remains available from [Flasher::boot_flash_size].
+    pub fn attach_external_flash(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        if spi_params.is_default() {
+            return Err(Error::ExternalFlashPins);
+        }
+
+        let boot_flash_size = self.boot_flash_size.unwrap_or(self.flash_size);
+        self.spi_autodetect(Some(spi_params))?;
+        self.boot_flash_size = Some(boot_flash_size);
+
+        info!(
+            "Attached external flash ({}) with pins CLK={}, Q={}, D={}, HD={}, CS={}",
+            self.flash_size,
+            spi_params.clk,
+            spi_params.q,
+            spi_params.d,
+            spi_params.hd,
+            spi_params.cs
+        );
+
+        Ok(())
+    }
+
+    /// Size of the flash being targeted
+    pub fn flash_size(&self) -> FlashSize {
+        self.flash_size
+    }
+
+    /// Size of the boot flash, if an external flash chip is attached instead
+    pub fn boot_flash_size(&self) -> Option<FlashSize> {
+        self.boot_flash_size
+    }
+
+    /// Check that the device is still responding
+    ///
+    /// This performs a single register read, which is cheap enough to be done
+    /// before any long-running operation. If the device does not respond,
+    /// [ConnectionError::DeviceNotResponding] is returned, allowing callers to
+    /// re-establish the connection instead of timing out part way through the
+    /// operation.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        let result = self.connection.with_timeout(PING_TIMEOUT, |connection| {
+            connection.command(Command::ReadReg {
+                address: CHIP_DETECT_MAGIC_REG_ADDR,
+            })
+        });
+
+        match result {
+            Ok(_) => Ok(()),
+            // Any error reported by the device still means that it is responding.
+            Err(Error::RomError(_)) => Ok(()),
+            Err(Error::Connection(e)) => {
+                debug!("Device did not respond to ping: {:?}", e);
+                Err(Error::Connection(ConnectionError::DeviceNotResponding))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Read and print any information we can about the connected device
     pub fn device_info(&mut self) -> Result<DeviceInfo, Error> {
         let chip = self.chip();
@@ -902,6 +1727,8 @@ impl Flasher {
             .map(|s| s.to_string())
             .collect::<Vec<_>>();
         let mac_address = target.mac_address(self.connection())?;
+        let psram = target.psram_info(self.connection())?;
+        let embedded_flash = target.embedded_flash_info(self.connection())?;
 
         let info = DeviceInfo {
             chip,
@@ -910,6 +1737,8 @@ impl Flasher {
             flash_size: self.flash_size,
             features,
             mac_address,
+            psram,
+            embedded_flash,
         };
 
         Ok(info)
@@ -924,6 +1753,7 @@ impl Flasher {
         mut progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
         let image = ElfFirmwareImage::try_from(elf_data)?;
+        check_compatible_chip(&image, self.chip)?;
         if image.rom_segments(self.chip).next().is_some() {
             return Err(Error::ElfNotRamLoadable);
         }
@@ -937,12 +1767,28 @@ impl Flasher {
         target.begin(&mut self.connection).flashing()?;
 
         for segment in image.ram_segments(self.chip) {
+            let segment: RomSegment = segment.into();
+            let addr = segment.addr;
+            if let Some(cb) = progress.as_mut() {
+                cb.event(&ProgressEvent::SegmentStarted {
+                    addr,
+                    len: segment.data.len(),
+                });
+            }
             target
-                .write_segment(&mut self.connection, segment.into(), &mut progress)
+                .write_segment(&mut self.connection, segment, &mut progress)
                 .flashing()?;
+            if let Some(cb) = progress.as_mut() {
+                cb.event(&ProgressEvent::SegmentFinished { addr });
+            }
+        }
+
+        target.finish(&mut self.connection, true).flashing()?;
+        if let Some(cb) = progress.as_mut() {
+            cb.event(&ProgressEvent::Finished);
         }
 
-        target.finish(&mut self.connection, true).flashing()
+        Ok(())
     }
 
     /// Load an ELF image to flash and execute it
@@ -953,11 +1799,17 @@ impl Flasher {
         mut progress: Option<&mut dyn ProgressCallbacks>,
         xtal_freq: XtalFrequency,
     ) -> Result<(), Error> {
-        let image = ElfFirmwareImage::try_from(elf_data)?;
-
-        let mut target =
-            self.chip
-                .flash_target(self.spi_params, self.use_stub, self.verify, self.skip);
+        let image =
+            ElfFirmwareImage::try_from(elf_data)?.with_rtc_segments(flash_data.rtc_segments);
+        self.ping()?;
+
+        let mut target = self.chip.flash_target(
+            self.spi_params,
+            self.use_stub,
+            self.verify,
+            self.skip,
+            self.compress,
+        );
         target.begin(&mut self.connection).flashing()?;
 
         let chip_revision = Some(
@@ -966,24 +1818,69 @@ impl Flasher {
                 .chip_revision(&mut self.connection)?,
         );
 
-        let image = self.chip.into_target().get_flash_image(
-            &image,
-            flash_data,
-            chip_revision,
-            xtal_freq,
-        )?;
+        let image = self
+            .chip
+            .get_flash_image(&image, flash_data, chip_revision, xtal_freq)?;
 
         // When the `cli` feature is enabled, display the image size information.
         #[cfg(feature = "cli")]
-        crate::cli::display_image_size(image.app_size(), image.part_size());
+        crate::cli::display_image_size(image.as_ref());
 
         for segment in image.flash_segments() {
-            target
-                .write_segment(&mut self.connection, segment, &mut progress)
+            self.check_flash_region(segment.addr, segment.data.len() as u32)?;
+        }
+
+        for segment in image.flash_segments() {
+            let addr = segment.addr;
+            if let Some(cb) = progress.as_mut() {
+                cb.segment(segment.addr, &segment.data);
+                cb.event(&ProgressEvent::SegmentStarted {
+                    addr,
+                    len: segment.data.len(),
+                });
+            }
+            self.write_segment(target.as_mut(), segment, &mut progress)
                 .flashing()?;
+            if let Some(cb) = progress.as_mut() {
+                cb.event(&ProgressEvent::SegmentFinished { addr });
+            }
         }
 
         target.finish(&mut self.connection, true).flashing()?;
+        if let Some(cb) = progress.as_mut() {
+            cb.event(&ProgressEvent::Finished);
+        }
+
+        Ok(())
+    }
+
+    /// Build the image for an ELF file and check that it can be written to
+    /// flash, without writing it
+    pub fn check_elf_image(
+        &mut self,
+        elf_data: &[u8],
+        flash_data: FlashData,
+        xtal_freq: XtalFrequency,
+    ) -> Result<(), Error> {
+        let image =
+            ElfFirmwareImage::try_from(elf_data)?.with_rtc_segments(flash_data.rtc_segments);
+
+        let chip_revision = Some(
+            self.chip
+                .into_target()
+                .chip_revision(&mut self.connection)?,
+        );
+
+        let image = self
+            .chip
+            .get_flash_image(&image, flash_data, chip_revision, xtal_freq)?;
+
+        #[cfg(feature = "cli")]
+        crate::cli::display_image_size(image.as_ref());
+
+        for segment in image.flash_segments() {
+            self.check_flash_region(segment.addr, segment.data.len() as u32)?;
+        }
 
         Ok(())
     }
@@ -1012,34 +1909,213 @@ impl Flasher {
         segments: &[RomSegment],
         mut progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        let mut target = self
-            .chip
-            .flash_target(self.spi_params, self.use_stub, false, false);
+        for segment in segments {
+            self.check_flash_region(segment.addr, segment.data.len() as u32)?;
+        }
+
+        self.ping()?;
+
+        let mut target =
+            self.chip
+                .flash_target(self.spi_params, self.use_stub, false, false, self.compress);
         target.begin(&mut self.connection).flashing()?;
         for segment in segments {
-            target.write_segment(&mut self.connection, segment.borrow(), &mut progress)?;
+            if let Some(cb) = progress.as_mut() {
+                cb.segment(segment.addr, &segment.data);
+                cb.event(&ProgressEvent::SegmentStarted {
+                    addr: segment.addr,
+                    len: segment.data.len(),
+                });
+            }
+            self.write_segment(target.as_mut(), segment.borrow(), &mut progress)?;
+            if let Some(cb) = progress.as_mut() {
+                cb.event(&ProgressEvent::SegmentFinished { addr: segment.addr });
+            }
         }
         target.finish(&mut self.connection, true).flashing()?;
+        if let Some(cb) = progress.as_mut() {
+            cb.event(&ProgressEvent::Finished);
+        }
+
+        Ok(())
+    }
+
+    /// Write a segment to the flash, resuming the write according to the
+    /// [RetryPolicy] if it fails
+    fn write_segment(
+        &mut self,
+        target: &mut dyn FlashTarget,
+        segment: RomSegment,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let mut written = 0;
+        let mut retries = 0;
+
+        loop {
+            let remaining = RomSegment {
+                addr: segment.addr + written as u32,
+                data: Cow::Borrowed(&segment.data[written..]),
+            };
+
+            let err = match target.write_segment(&mut self.connection, remaining, progress) {
+                Ok(()) => return Ok(()),
+                Err(Error::Cancelled) => {
+                    self.reset_cancelled();
+                    return Err(Error::Cancelled);
+                }
+                Err(err) if retries < self.retry_policy.retries && is_retryable(&err) => err,
+                Err(err) => return Err(err),
+            };
+
+            retries += 1;
+            warn!(
+                "Writing the segment at address '0x{:x}' failed, retrying ({}/{}): {}",
+                segment.addr, retries, self.retry_policy.retries, err
+            );
+
+            self.recover(target)?;
+            written = self.intact_len(&segment, written)?;
+            if written > 0 {
+                info!(
+                    "Resuming the write at address '0x{:x}'",
+                    segment.addr + written as u32
+                );
+            }
+            if let Some(cb) = progress.as_mut() {
+                cb.event(&ProgressEvent::Retrying {
+                    addr: segment.addr,
+                    attempt: retries,
+                    resume_addr: segment.addr + written as u32,
+                });
+            }
+        }
+    }
+
+    /// Fail with [Error::Cancelled] if the operation has been cancelled,
+    /// resetting the device
+    fn check_cancelled(&mut self) -> Result<(), Error> {
+        let result = self.connection.check_cancelled();
+        if result.is_err() {
+            self.reset_cancelled();
+        }
+
+        result
+    }
+
+    /// Reset the device after an operation was cancelled, as it was left part
+    /// way through a command
+    fn reset_cancelled(&mut self) {
+        info!("Operation cancelled, resetting the device");
+        if let Err(err) = self.connection.reset_after(self.use_stub, self.chip) {
+            warn!("Failed to reset the device: {err}");
+        }
+    }
+
+    /// Get back in touch with the loader after a failed write, reconnecting if
+    /// allowed and needed
+    fn recover(&mut self, target: &mut dyn FlashTarget) -> Result<(), Error> {
+        // The loader discards whatever is left of the failed exchange once it
+        // receives the start of the next frame
+        if (0..MAX_RESYNC_ATTEMPTS).any(|_| self.connection.sync().is_ok()) {
+            return Ok(());
+        }
+
+        if !self.retry_policy.reconnect {
+            return Err(Error::Connection(ConnectionError::DeviceNotResponding));
+        }
+
+        info!("Reconnecting to the device");
+        let baud = self.connection.get_baud()?;
+        self.connection.set_baud(115_200)?;
+        self.connection.begin()?;
+        self.connection.set_timeout(DEFAULT_TIMEOUT)?;
+
+        if let Some(stub) = self.stub.clone() {
+            self.load_stub(stub)?;
+        }
+        self.set_flash_voltage(self.flash_voltage)?;
+        if baud != 115_200 {
+            self.change_baud(baud)?;
+        }
+
+        target.begin(&mut self.connection)
+    }
+
+    /// Length of the start of a segment found intact in the flash, checked in
+    /// sector-aligned chunks following the `verified` bytes already known to be
+    /// intact
+    fn intact_len(&mut self, segment: &RomSegment, verified: usize) -> Result<usize, Error> {
+        // Resuming part way through a sector would erase its start, and the
+        // ESP8266 ROM loader is unable to calculate the MD5 checksum of the flash
+        if segment.addr as usize % FLASH_SECTOR_SIZE != 0
+            || (self.chip.is_esp8266() && !self.use_stub)
+        {
+            return Ok(0);
+        }
+
+        let mut intact = verified;
+        while intact + RESUME_CHUNK_SIZE <= segment.data.len() {
+            let chunk = &segment.data[intact..][..RESUME_CHUNK_SIZE];
+            let flash_md5 = self.checksum_md5(segment.addr + intact as u32, chunk.len() as u32)?;
+            if Md5::digest(chunk).as_slice() != flash_md5.to_be_bytes() {
+                break;
+            }
+            intact += RESUME_CHUNK_SIZE;
+        }
+
+        Ok(intact)
+    }
+
+    /// Check that a region to be written lies within the flash, and can be
+    /// addressed by the loader in use
+    fn check_flash_region(&self, offset: u32, size: u32) -> Result<(), Error> {
+        let end = offset as u64 + size as u64;
+
+        if end > self.flash_size.size() as u64 {
+            return Err(Error::FlashRegionOutOfBounds {
+                offset,
+                size,
+                flash_size: self.flash_size.size(),
+            });
+        }
+
+        // Flash larger than 16MB requires 4-byte addressing, which is only
+        // supported by the flash stub
+        if !self.use_stub && end > FlashSize::_16Mb.size() as u64 {
+            return Err(Error::RomFlashAddressLimit);
+        }
 
         Ok(())
     }
 
     /// Get MD5 of region
     pub fn checksum_md5(&mut self, addr: u32, length: u32) -> Result<u128, Error> {
-        self.connection
-            .with_timeout(CommandType::FlashMd5.timeout(), |connection| {
+        self.connection.with_timeout(
+            CommandType::FlashMd5.timeout_for_size(length),
+            |connection| {
                 connection
                     .command(crate::command::Command::FlashMd5 {
                         offset: addr,
                         size: length,
                     })?
                     .try_into()
-            })
+            },
+        )
     }
 
     pub fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
         debug!("Change baud to: {}", speed);
 
+        // The device must not switch to a baud rate which the transport cannot
+        // follow, or the link would be lost
+        if !self.connection.can_change_baud() && speed != self.connection.get_baud()? {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The baud rate of the serial port cannot be changed",
+            )
+            .into());
+        }
+
         let prior_baud = match self.use_stub {
             true => self.connection.get_baud()?,
             false => 0,
@@ -1053,7 +2129,10 @@ impl Flasher {
         // The ROM code thinks it uses a 40 MHz XTAL. Recompute the baud rate in order
         // to trick the ROM code to set the correct baud rate for a 26 MHz XTAL.
         let mut new_baud = speed;
-        if self.chip == Chip::Esp32c2 && !self.use_stub && xtal_freq == XtalFrequency::_26Mhz {
+        if self.chip.rom_assumes_40mhz_xtal()
+            && !self.use_stub
+            && xtal_freq == XtalFrequency::_26Mhz
+        {
             new_baud = new_baud * 40 / 26;
         }
 
@@ -1081,6 +2160,11 @@ impl Flasher {
 
     pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
         debug!("Erasing region of 0x{:x}B at 0x{:08x}", size, offset);
+        if offset as usize % FLASH_SECTOR_SIZE != 0 || size as usize % FLASH_SECTOR_SIZE != 0 {
+            return Err(Error::UnalignedEraseRegion { offset, size });
+        }
+        self.check_flash_region(offset, size)?;
+        self.ping()?;
 
         self.connection.with_timeout(
             CommandType::EraseRegion.timeout_for_size(size),
@@ -1093,11 +2177,30 @@ impl Flasher {
 
     pub fn erase_flash(&mut self) -> Result<(), Error> {
         debug!("Erasing the entire flash");
+        self.ping()?;
 
-        self.connection
-            .with_timeout(CommandType::EraseFlash.timeout(), |connection| {
-                connection.command(Command::EraseFlash)
-            })?;
+        // The loader cannot be interrupted while it erases the whole chip, so
+        // the stub erases it one region at a time when it may be cancelled
+        if self.connection.is_cancellable() && self.use_stub {
+            let size = self.flash_size.size();
+            for offset in (0..size).step_by(ERASE_FLASH_CHUNK_SIZE as usize) {
+                self.check_cancelled()?;
+
+                let size = (size - offset).min(ERASE_FLASH_CHUNK_SIZE);
+                self.connection.with_timeout(
+                    CommandType::EraseRegion.timeout_for_size(size),
+                    |connection| connection.command(Command::EraseRegion { offset, size }),
+                )?;
+            }
+            self.connection.flush()?;
+
+            return Ok(());
+        }
+
+        self.connection.with_timeout(
+            CommandType::EraseFlash.timeout_for_size(self.flash_size.size()),
+            |connection| connection.command(Command::EraseFlash),
+        )?;
         sleep(Duration::from_secs_f32(0.05));
         self.connection.flush()?;
 
@@ -1112,16 +2215,70 @@ impl Flasher {
         max_in_flight: u32,
         file_path: PathBuf,
     ) -> Result<(), Error> {
-        debug!("Reading 0x{:x}B from 0x{:08x}", size, offset);
-
-        let mut data = Vec::new();
-
         let mut file = fs::OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
             .open(&file_path)?;
 
+        self.read_flash_to_writer(offset, size, block_size, max_in_flight, &mut file)?;
+
+        info!(
+            "Flash content successfully read and written to '{}'!",
+            file_path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Read a region of flash into a writer
+    ///
+    /// The region is read in chunks, each of which is checked and written out
+    /// before the next is read, so that whatever was read before a failure is
+    /// kept, and large regions need not be held in memory. Requires the flash
+    /// stub.
+    pub fn read_flash_to_writer(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let end = offset as u64 + size as u64;
+
+        let mut addr = offset as u64;
+        while addr < end {
+            let len = (end - addr).min(READ_FLASH_CHUNK_SIZE as u64) as u32;
+            let data = self.read_flash_data(addr as u32, len, block_size, max_in_flight)?;
+            writer.write_all(&data)?;
+            writer.flush()?;
+
+            addr += len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Read a region of flash
+    ///
+    /// Requires the flash stub.
+    pub fn read_flash_data(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        if !self.use_stub {
+            return Err(Error::StubRequired);
+        }
+
+        debug!("Reading 0x{:x}B from 0x{:08x}", size, offset);
+        self.ping()?;
+
+        let mut data = Vec::new();
+
         self.connection
             .with_timeout(CommandType::ReadFlash.timeout(), |connection| {
                 connection.command(Command::ReadFlash {
@@ -1133,6 +2290,8 @@ impl Flasher {
             })?;
 
         while data.len() < size as usize {
+            self.check_cancelled()?;
+
             let response = self.connection.read_response()?;
             let chunk: Vec<u8> = if let Some(response) = response {
                 response.value.try_into().unwrap()
@@ -1175,14 +2334,63 @@ impl Flasher {
             ));
         }
 
-        file.write_all(&data)?;
+        Ok(data)
+    }
 
-        info!(
-            "Flash content successfully read and written to '{}'!",
-            file_path.display()
-        );
+    /// Read the partition table at the given offset
+    pub fn read_partition_table(&mut self, offset: u32) -> Result<PartitionTable, Error> {
+        let data = self.read_flash_data(offset, PARTITION_TABLE_SIZE, 0x1000, 64)?;
 
-        Ok(())
+        Ok(PartitionTable::try_from_bytes(data)?)
+    }
+
+    /// Read the OTA data partition of the given partition table
+    pub fn read_otadata(&mut self, partition_table: &PartitionTable) -> Result<OtaData, Error> {
+        let partition = otadata_partition(partition_table)?;
+        let data = self.read_flash_data(partition.offset(), OTADATA_SIZE as u32, 0x1000, 64)?;
+
+        OtaData::parse(&data)
+    }
+
+    /// Application description of the image in the given partition, or `None`
+    /// if the image does not have one
+    ///
+    /// Only the headers of the image and the description are read.
+    pub fn read_app_descriptor(
+        &mut self,
+        partition: &Partition,
+    ) -> Result<Option<AppDescriptor>, Error> {
+        AppDescriptor::read(|offset, len| {
+            if offset.saturating_add(len) > partition.size() {
+                return Err(Error::InvalidImage(
+                    "the image extends past the end of its partition".into(),
+                ));
+            }
+
+            self.read_flash_data(partition.offset() + offset, len, 0x1000, 64)
+        })
+    }
+
+    /// Length of the application image in the given partition, or `None` if
+    /// the partition does not contain a valid image
+    ///
+    /// Only the headers of the image are read.
+    pub fn read_app_image_len(&mut self, partition: &Partition) -> Result<Option<usize>, Error> {
+        let result = ImageInfo::read_len(|offset, len| {
+            if offset.saturating_add(len) > partition.size() {
+                return Err(Error::InvalidImage(
+                    "the image extends past the end of its partition".into(),
+                ));
+            }
+
+            self.read_flash_data(partition.offset() + offset, len, 0x1000, 64)
+        });
+
+        match result {
+            Ok(len) if len <= partition.size() as usize => Ok(Some(len)),
+            Ok(_) | Err(Error::InvalidImage(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
     pub fn verify_minimum_revision(&mut self, minimum: u16) -> Result<(), Error> {
@@ -0,0 +1,288 @@
+//! Serial Flash Discoverable Parameters (SFDP)
+//!
+//! Most SPI flash chips describe their capabilities in a set of parameter
+//! tables, read using the `RDSFDP` (0x5A) command. The header at address 0
+//! lists the tables, the first of which is the mandatory Basic Flash Parameter
+//! Table (BFPT) containing the chip's density, erase types and timings.
+//!
+//! https://www.jedec.org/standards-documents/docs/jesd216b
+
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Signature at the start of the SFDP header, "SFDP"
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+/// Size of the SFDP header and of each parameter header
+const HEADER_LEN: usize = 8;
+/// ID of the Basic Flash Parameter Table
+const BASIC_FLASH_PARAMETERS_ID: u16 = 0xff00;
+
+/// SFDP tables read from a flash chip
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sfdp {
+    /// Major and minor revision of the SFDP standard supported by the chip
+    pub revision: (u8, u8),
+    /// Parameter tables, in the order they are listed in the header
+    pub parameters: Vec<SfdpParameter>,
+}
+
+/// A parameter table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SfdpParameter {
+    /// ID of the table, `0xff00` being the Basic Flash Parameter Table, and
+    /// other IDs ending in `0xff` being defined by JEDEC
+    pub id: u16,
+    /// Major and minor revision of the table
+    pub revision: (u8, u8),
+    /// Address of the table in the SFDP address space
+    pub pointer: u32,
+    /// Contents of the table
+    pub data: Vec<u8>,
+}
+
+/// An erase operation supported by the flash chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EraseType {
+    /// Size of the erased region in bytes
+    pub size: u32,
+    /// Instruction performing the erase
+    pub opcode: u8,
+    /// Typical time taken by the erase, if provided
+    pub typical_time: Option<Duration>,
+}
+
+/// Contents of the Basic Flash Parameter Table which are of use when flashing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BasicFlashParameters {
+    /// Capacity of the flash chip in bytes
+    pub size: u64,
+    /// Supported erase operations, smallest first
+    pub erase_types: Vec<EraseType>,
+    /// Size of a page, if provided
+    pub page_size: Option<u32>,
+    /// Typical time taken to program a page, if provided
+    pub page_program_time: Option<Duration>,
+    /// Typical time taken to erase the whole chip, if provided
+    pub chip_erase_time: Option<Duration>,
+}
+
+impl Sfdp {
+    /// Read the SFDP header and parameter tables, using `read` to read the
+    /// given number of bytes from an SFDP address
+    pub fn read(mut read: impl FnMut(u32, usize) -> Result<Vec<u8>, Error>) -> Result<Self, Error> {
+        let header = read(0, HEADER_LEN)?;
+        if header.len() < HEADER_LEN || word(&header, 0) != SFDP_SIGNATURE {
+            return Err(Error::SfdpUnsupported);
+        }
+
+        let revision = (header[5], header[4]);
+        let count = header[6] as usize + 1;
+
+        let parameter_headers = read(HEADER_LEN as u32, count * HEADER_LEN)?;
+        let parameters = parameter_headers
+            .chunks_exact(HEADER_LEN)
+            .map(|header| {
+                let id = u16::from_le_bytes([header[0], header[7]]);
+                let len = header[3] as usize * 4;
+                let pointer = word(header, 4) & 0x00ff_ffff;
+
+                Ok(SfdpParameter {
+                    id,
+                    revision: (header[2], header[1]),
+                    pointer,
+                    data: read(pointer, len)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            revision,
+            parameters,
+        })
+    }
+
+    /// Decode the Basic Flash Parameter Table, if present
+    pub fn basic_flash_parameters(&self) -> Option<BasicFlashParameters> {
+        let table = self
+            .parameters
+            .iter()
+            .find(|parameter| parameter.id == BASIC_FLASH_PARAMETERS_ID)?;
+
+        BasicFlashParameters::parse(&table.data)
+    }
+}
+
+impl BasicFlashParameters {
+    /// Parse the table, which must contain at least the nine DWORDs defined by
+    /// the original revision of JESD216
+    fn parse(data: &[u8]) -> Option<Self> {
+        let dword = |n: usize| data.get((n - 1) * 4..n * 4).map(|bytes| word(bytes, 0));
+
+        // Densities of 2 gigabits or more are given as a power of two
+        let density = dword(2)?;
+        let bits = if density & 0x8000_0000 == 0 {
+            density as u64 + 1
+        } else {
+            1u64.checked_shl(density & 0x7fff_ffff)?
+        };
+
+        // The erase times were added in JESD216A
+        let erase_times = dword(10);
+        let erase_time = |index: u32| {
+            let field = erase_times? >> (4 + index * 7);
+            let unit = match (field >> 5) & 0x3 {
+                0 => Duration::from_millis(1),
+                1 => Duration::from_millis(16),
+                2 => Duration::from_millis(128),
+                _ => Duration::from_secs(1),
+            };
+
+            Some(unit * ((field & 0x1f) + 1))
+        };
+
+        let erase_types = [dword(8)?, dword(9)?]
+            .into_iter()
+            .flat_map(|dword| [dword & 0xffff, dword >> 16])
+            .enumerate()
+            .filter_map(|(index, erase)| {
+                // An exponent of zero marks an unused erase type, while one too
+                // large for the size to fit can only come from a malformed table
+                let exponent = erase & 0xff;
+                if exponent == 0 {
+                    return None;
+                }
+
+                Some(EraseType {
+                    size: 1u32.checked_shl(exponent)?,
+                    opcode: (erase >> 8) as u8,
+                    typical_time: erase_time(index as u32),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let timings = dword(11);
+        let page_size = timings.map(|timings| 1 << ((timings >> 4) & 0xf));
+        let page_program_time = timings.map(|timings| {
+            let unit = if timings & (1 << 13) == 0 { 8 } else { 64 };
+            Duration::from_micros(unit * (((timings >> 8) & 0x1f) as u64 + 1))
+        });
+        let chip_erase_time = timings.map(|timings| {
+            let unit = match (timings >> 29) & 0x3 {
+                0 => Duration::from_millis(16),
+                1 => Duration::from_millis(256),
+                2 => Duration::from_secs(4),
+                _ => Duration::from_secs(64),
+            };
+            unit * (((timings >> 24) & 0x1f) + 1)
+        });
+
+        let mut parameters = Self {
+            size: bits / 8,
+            erase_types,
+            page_size,
+            page_program_time,
+            chip_erase_time,
+        };
+        parameters.erase_types.sort_by_key(|erase| erase.size);
+
+        Some(parameters)
+    }
+}
+
+fn word(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sfdp_data() -> Vec<u8> {
+        let mut data = vec![0xff; 0x80];
+        // Header: signature, revision 1.6, one parameter header
+        data[..8].copy_from_slice(&[b'S', b'F', b'D', b'P', 0x06, 0x01, 0x00, 0xff]);
+        // BFPT header: revision 1.6, 16 DWORDs at 0x30
+        data[8..16].copy_from_slice(&[0x00, 0x06, 0x01, 0x10, 0x30, 0x00, 0x00, 0xff]);
+
+        let bfpt = [
+            0xfff1_20e5u32,
+            // 32 Mbit
+            0x01ff_ffff,
+            0x6b08_eb44,
+            0xbb42_3b08,
+            0xffff_fffe,
+            0xff00_ffff,
+            0xeb44_ffff,
+            // 4 KiB with 0x20, 32 KiB with 0x52
+            0x520f_200c,
+            // 64 KiB with 0xd8
+            0x0000_d810,
+            // Erase times: 4 KiB 2 * 16ms, 32 KiB 9 * 16ms, 64 KiB 12 * 16ms
+            0x00ad_4211,
+            // Page size 256, page program 6 * 64us, chip erase 4 * 4s
+            0x4300_2581,
+        ];
+        for (i, dword) in bfpt.iter().enumerate() {
+            data[0x30 + i * 4..][..4].copy_from_slice(&dword.to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn reads_basic_flash_parameters() {
+        let data = sfdp_data();
+        let sfdp = Sfdp::read(|addr, len| Ok(data[addr as usize..][..len].to_vec())).unwrap();
+
+        assert_eq!(sfdp.revision, (1, 6));
+        assert_eq!(sfdp.parameters.len(), 1);
+        assert_eq!(sfdp.parameters[0].id, BASIC_FLASH_PARAMETERS_ID);
+        assert_eq!(sfdp.parameters[0].data.len(), 64);
+
+        let bfpt = sfdp.basic_flash_parameters().unwrap();
+        assert_eq!(bfpt.size, 4 * 1024 * 1024);
+        assert_eq!(
+            bfpt.erase_types
+                .iter()
+                .map(|erase| (erase.size, erase.opcode))
+                .collect::<Vec<_>>(),
+            [(0x1000, 0x20), (0x8000, 0x52), (0x10000, 0xd8)]
+        );
+        assert_eq!(
+            bfpt.erase_types[0].typical_time,
+            Some(Duration::from_millis(32))
+        );
+        assert_eq!(bfpt.page_size, Some(256));
+        assert_eq!(bfpt.page_program_time, Some(Duration::from_micros(384)));
+        assert_eq!(bfpt.chip_erase_time, Some(Duration::from_secs(16)));
+    }
+
+    #[test]
+    fn skips_malformed_erase_types() {
+        let mut data = sfdp_data();
+        // 2^255 bytes with 0x20, unused, 64 KiB with 0xd8, 2^32 bytes with 0xdc
+        data[0x4c..0x54].copy_from_slice(&[0xff, 0x20, 0x00, 0x52, 0x10, 0xd8, 0x20, 0xdc]);
+        let sfdp = Sfdp::read(|addr, len| Ok(data[addr as usize..][..len].to_vec())).unwrap();
+
+        let bfpt = sfdp.basic_flash_parameters().unwrap();
+        assert_eq!(
+            bfpt.erase_types
+                .iter()
+                .map(|erase| (erase.size, erase.opcode))
+                .collect::<Vec<_>>(),
+            [(0x10000, 0xd8)]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_signature() {
+        let data = vec![0xff; 0x10];
+        assert!(matches!(
+            Sfdp::read(|addr, len| Ok(data[addr as usize..][..len].to_vec())),
+            Err(Error::SfdpUnsupported)
+        ));
+    }
+}
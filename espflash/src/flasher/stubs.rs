@@ -1,9 +1,11 @@
-use std::time::Duration;
+use std::{collections::HashMap, fs, path::Path, time::Duration};
 
 use base64::{engine::general_purpose, Engine as _};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::targets::Chip;
+use crate::{error::Error, targets::Chip};
 
 /// Flash stub object (deserialized from TOML, converted from JSON as used by `esptool.py`)
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
@@ -23,37 +25,118 @@ pub struct FlashStub {
 pub(crate) const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
 pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 pub(crate) const EXPECTED_STUB_HANDSHAKE: &str = "OHAI";
+pub(crate) const PING_TIMEOUT: Duration = Duration::from_millis(500);
 
 pub(crate) const FLASH_SECTOR_SIZE: usize = 0x1000;
 pub(crate) const FLASH_WRITE_SIZE: usize = 0x400;
 
+/// Name of the file recording the release and digests of the cached stubs
+pub(crate) const STUB_MANIFEST: &str = "manifest.toml";
+
 // Include stub objects in binary
+#[cfg(feature = "esp32")]
 const STUB_32: &str = include_str!("../../resources/stubs/stub_flasher_32.toml");
+#[cfg(feature = "esp32c2")]
 const STUB_32C2: &str = include_str!("../../resources/stubs/stub_flasher_32c2.toml");
+#[cfg(feature = "esp32c3")]
 const STUB_32C3: &str = include_str!("../../resources/stubs/stub_flasher_32c3.toml");
+#[cfg(feature = "esp32c6")]
 const STUB_32C6: &str = include_str!("../../resources/stubs/stub_flasher_32c6.toml");
+#[cfg(feature = "esp32h2")]
 const STUB_32H2: &str = include_str!("../../resources/stubs/stub_flasher_32h2.toml");
+#[cfg(feature = "esp32p4")]
 const STUB_32P4: &str = include_str!("../../resources/stubs/stub_flasher_32p4.toml");
+#[cfg(feature = "esp32s2")]
 const STUB_32S2: &str = include_str!("../../resources/stubs/stub_flasher_32s2.toml");
+#[cfg(feature = "esp32s3")]
 const STUB_32S3: &str = include_str!("../../resources/stubs/stub_flasher_32s3.toml");
 
 impl FlashStub {
-    /// Fetch flash stub for the provided chip
-    pub fn get(chip: Chip) -> FlashStub {
+    /// Fetch flash stub for the provided chip, if one is available
+    pub fn get(chip: Chip) -> Option<FlashStub> {
         let s = match chip {
+            #[cfg(feature = "esp32")]
             Chip::Esp32 => STUB_32,
+            #[cfg(feature = "esp32c2")]
             Chip::Esp32c2 => STUB_32C2,
+            #[cfg(feature = "esp32c3")]
             Chip::Esp32c3 => STUB_32C3,
+            #[cfg(feature = "esp32c6")]
             Chip::Esp32c6 => STUB_32C6,
+            #[cfg(feature = "esp32h2")]
             Chip::Esp32h2 => STUB_32H2,
+            #[cfg(feature = "esp32p4")]
             Chip::Esp32p4 => STUB_32P4,
+            #[cfg(feature = "esp32s2")]
             Chip::Esp32s2 => STUB_32S2,
+            #[cfg(feature = "esp32s3")]
             Chip::Esp32s3 => STUB_32S3,
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => return None,
         };
 
         let stub: FlashStub = toml::from_str(s).unwrap();
 
-        stub
+        Some(stub)
+    }
+
+    /// Fetch flash stub for the provided chip, preferring a copy downloaded to
+    /// the given cache directory over the one embedded in espflash
+    pub fn get_cached(chip: Chip, dir: &Path) -> Option<FlashStub> {
+        match Self::load_cached(chip, dir) {
+            Ok(Some(stub)) => Some(stub),
+            Ok(None) => Self::get(chip),
+            Err(e) => {
+                warn!("Ignoring the cached flash stub for {chip}: {e}");
+                Self::get(chip)
+            }
+        }
+    }
+
+    fn load_cached(chip: Chip, dir: &Path) -> Result<Option<FlashStub>, Error> {
+        let Some(name) = stub_file_name(chip) else {
+            return Ok(None);
+        };
+        let path = dir.join(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        // The stub is checked against the digest recorded when it was
+        // downloaded, so that a corrupted cache is never loaded onto a device
+        let manifest = StubManifest::load(dir)?;
+        let data =
+            fs::read(&path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+        let expected = manifest.files.get(name).ok_or_else(|| {
+            Error::InvalidFlashStub(format!("{name} is not listed in the manifest"))
+        })?;
+        if sha256_hex(&data) != *expected {
+            return Err(Error::InvalidFlashStub(format!(
+                "digest of {name} does not match"
+            )));
+        }
+
+        let stub = Self::from_toml(&String::from_utf8_lossy(&data))?;
+        debug!(
+            "Using cached flash stub {name} from release {}",
+            manifest.version
+        );
+
+        Ok(Some(stub))
+    }
+
+    /// Parse a flash stub, checking that its segments are valid base64
+    pub fn from_toml(s: &str) -> Result<FlashStub, Error> {
+        let stub: FlashStub =
+            toml::from_str(s).map_err(|e| Error::InvalidFlashStub(e.message().to_string()))?;
+
+        for segment in [&stub.text, &stub.data] {
+            general_purpose::STANDARD
+                .decode(segment)
+                .map_err(|e| Error::InvalidFlashStub(e.to_string()))?;
+        }
+
+        Ok(stub)
     }
 
     /// Fetch stub entry point
@@ -74,6 +157,57 @@ impl FlashStub {
     }
 }
 
+/// Record of the flash stubs downloaded to a cache directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StubManifest {
+    /// Release the stubs were downloaded from
+    pub version: String,
+    /// SHA-256 digest of each stub file, as a hexadecimal string
+    pub files: HashMap<String, String>,
+}
+
+impl StubManifest {
+    /// Load the manifest from a cache directory
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        let path = dir.join(STUB_MANIFEST);
+        let data = fs::read_to_string(&path)
+            .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        toml::from_str(&data).map_err(|e| Error::InvalidFlashStub(e.message().to_string()))
+    }
+}
+
+/// Name of the file containing the flash stub for the provided chip
+pub fn stub_file_name(chip: Chip) -> Option<&'static str> {
+    let name = match chip {
+        #[cfg(feature = "esp32")]
+        Chip::Esp32 => "stub_flasher_32.toml",
+        #[cfg(feature = "esp32c2")]
+        Chip::Esp32c2 => "stub_flasher_32c2.toml",
+        #[cfg(feature = "esp32c3")]
+        Chip::Esp32c3 => "stub_flasher_32c3.toml",
+        #[cfg(feature = "esp32c6")]
+        Chip::Esp32c6 => "stub_flasher_32c6.toml",
+        #[cfg(feature = "esp32h2")]
+        Chip::Esp32h2 => "stub_flasher_32h2.toml",
+        #[cfg(feature = "esp32p4")]
+        Chip::Esp32p4 => "stub_flasher_32p4.toml",
+        #[cfg(feature = "esp32s2")]
+        Chip::Esp32s2 => "stub_flasher_32s2.toml",
+        #[cfg(feature = "esp32s3")]
+        Chip::Esp32s3 => "stub_flasher_32s3.toml",
+        #[cfg(feature = "esp8266")]
+        Chip::Esp8266 => return None,
+    };
+
+    Some(name)
+}
+
+/// SHA-256 digest of the data, as a hexadecimal string
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
 #[cfg(test)]
 mod tests {
     use strum::IntoEnumIterator;
@@ -85,7 +219,9 @@ mod tests {
     fn check_stub_encodings() {
         for c in Chip::iter() {
             // Stub must be valid json
-            let s = FlashStub::get(c);
+            let Some(s) = FlashStub::get(c) else {
+                continue;
+            };
 
             // Data decoded from b64
             let _ = s.text();
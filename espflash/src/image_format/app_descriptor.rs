@@ -0,0 +1,367 @@
+//! ESP-IDF application description
+//!
+//! ESP-IDF applications embed an `esp_app_desc_t` structure at the start of
+//! their first segment, recording the application's version, project name and
+//! build time among other things. These fields may be overridden when
+//! generating an image, without having to rebuild the application, and read
+//! back from images, including those in the flash of a device.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/misc_system_api.html#app-version
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+const ESP_MAGIC: u8 = 0xE9;
+
+const IMAGE_HEADER_LEN: usize = 24;
+const SEG_HEADER_LEN: usize = 8;
+const DIGEST_LEN: usize = 32;
+
+// Offsets and lengths of the fields of `esp_app_desc_t`
+const VERSION: (usize, usize) = (16, 32);
+const PROJECT_NAME: (usize, usize) = (48, 32);
+const TIME: (usize, usize) = (80, 16);
+const DATE: (usize, usize) = (96, 16);
+const IDF_VER: (usize, usize) = (112, 32);
+const APP_ELF_SHA256: (usize, usize) = (144, 32);
+const APP_DESC_LEN: usize = 256;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Application description read from an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[doc(alias = "esp_app_desc_t")]
+pub struct AppDescriptor {
+    /// Secure version, used for anti-rollback
+    pub secure_version: u32,
+    /// Application version
+    pub version: String,
+    /// Project name
+    pub project_name: String,
+    /// Build time, as given by `__TIME__`
+    pub time: String,
+    /// Build date, as given by `__DATE__`
+    pub date: String,
+    /// Version of ESP-IDF the application was built with
+    pub idf_version: String,
+    /// SHA256 digest of the application's ELF file
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    /// Read the application description from an image, if it has one
+    pub fn from_image(image: &[u8]) -> Option<Self> {
+        let offset = find_app_desc(image)?;
+
+        Some(Self::parse(&image[offset..][..APP_DESC_LEN]))
+    }
+
+    /// Read the application description from an image by reading only its
+    /// headers and the description itself
+    ///
+    /// `read` is called with an offset within the image and the number of
+    /// bytes to read, as for [ImageInfo::read_len](super::ImageInfo::read_len).
+    /// Returns `None` if the image has no application description.
+    pub fn read(
+        mut read: impl FnMut(u32, u32) -> Result<Vec<u8>, Error>,
+    ) -> Result<Option<Self>, Error> {
+        let header = read(0, IMAGE_HEADER_LEN as u32)?;
+        if header.len() < IMAGE_HEADER_LEN || header[0] != ESP_MAGIC {
+            return Err(Error::InvalidImage(
+                "the image does not begin with the expected magic byte".into(),
+            ));
+        }
+
+        let mut offset = IMAGE_HEADER_LEN as u32;
+        for _ in 0..header[1] {
+            let segment_header = read(offset, SEG_HEADER_LEN as u32)?;
+            let Some(length) = segment_header.get(4..8) else {
+                return Err(Error::InvalidImage("the image is truncated".into()));
+            };
+            let length = u32::from_le_bytes(length.try_into().unwrap());
+            offset += SEG_HEADER_LEN as u32;
+
+            if length as usize >= APP_DESC_LEN {
+                let desc = read(offset, APP_DESC_LEN as u32)?;
+                if desc.len() == APP_DESC_LEN && desc.starts_with(&APP_DESC_MAGIC.to_le_bytes()) {
+                    return Ok(Some(Self::parse(&desc)));
+                }
+            }
+
+            offset = offset
+                .checked_add(length)
+                .ok_or_else(|| Error::InvalidImage("the image is truncated".into()))?;
+        }
+
+        Ok(None)
+    }
+
+    fn parse(desc: &[u8]) -> Self {
+        let field = |(offset, len): (usize, usize)| {
+            let field = &desc[offset..][..len];
+            let field = field.split(|b| *b == 0).next().unwrap_or_default();
+
+            String::from_utf8_lossy(field).into_owned()
+        };
+
+        Self {
+            secure_version: u32::from_le_bytes(desc[4..8].try_into().unwrap()),
+            version: field(VERSION),
+            project_name: field(PROJECT_NAME),
+            time: field(TIME),
+            date: field(DATE),
+            idf_version: field(IDF_VER),
+            elf_sha256: desc[APP_ELF_SHA256.0..][..APP_ELF_SHA256.1]
+                .try_into()
+                .unwrap(),
+        }
+    }
+}
+
+/// Overrides for the fields of the application description
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AppDescriptorOverrides {
+    /// Application version
+    pub version: Option<String>,
+    /// Project name
+    pub project_name: Option<String>,
+    /// Build time, in seconds since the Unix epoch
+    pub build_time: Option<u64>,
+    /// Normalize the build time to the Unix epoch if it is not overridden, so
+    /// that an application always produces the same image
+    ///
+    /// Unlike the other overrides, this has no effect on images which do not
+    /// contain an application description.
+    pub reproducible: bool,
+}
+
+impl AppDescriptorOverrides {
+    /// Are any of the fields explicitly overridden?
+    pub fn is_empty(&self) -> bool {
+        self.version.is_none() && self.project_name.is_none() && self.build_time.is_none()
+    }
+
+    /// Apply the overrides to an application image
+    ///
+    /// The image must have its SHA256 digest appended, both it and the image's
+    /// checksum are updated to account for the modified fields.
+    pub(crate) fn apply(&self, image: &mut [u8]) -> Result<(), Error> {
+        let build_time = self.build_time.or(self.reproducible.then_some(0));
+        if self.is_empty() && build_time.is_none() {
+            return Ok(());
+        }
+
+        let Some(offset) = find_app_desc(image) else {
+            return if self.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::AppDescriptorNotFound)
+            };
+        };
+
+        let original = image[offset..][..APP_DESC_LEN].to_vec();
+        let desc = &mut image[offset..][..APP_DESC_LEN];
+
+        if let Some(version) = &self.version {
+            write_field(desc, VERSION, "version", version)?;
+        }
+        if let Some(project_name) = &self.project_name {
+            write_field(desc, PROJECT_NAME, "project name", project_name)?;
+        }
+        if let Some(build_time) = build_time {
+            let (date, time) = format_build_time(build_time);
+            write_field(desc, DATE, "date", &date)?;
+            write_field(desc, TIME, "time", &time)?;
+        }
+
+        // The checksum is the XOR of all segment data, so only the bytes which
+        // have changed need to be accounted for
+        let checksum_offset = image.len() - DIGEST_LEN - 1;
+        let checksum = original
+            .iter()
+            .zip(&image[offset..][..APP_DESC_LEN])
+            .fold(image[checksum_offset], |checksum, (old, new)| {
+                checksum ^ old ^ new
+            });
+        image[checksum_offset] = checksum;
+
+        let digest_offset = image.len() - DIGEST_LEN;
+        let digest = Sha256::digest(&image[..digest_offset]);
+        image[digest_offset..].copy_from_slice(&digest);
+
+        Ok(())
+    }
+}
+
+/// Find the offset of the application description within an image
+fn find_app_desc(image: &[u8]) -> Option<usize> {
+    let segment_count = *image.get(1)?;
+    let mut offset = IMAGE_HEADER_LEN;
+
+    // Padding segments may precede the segment containing the description
+    for _ in 0..segment_count {
+        let header = image.get(offset..offset + SEG_HEADER_LEN)?;
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        offset += SEG_HEADER_LEN;
+
+        let magic = image.get(offset..offset + 4)?;
+        if u32::from_le_bytes(magic.try_into().unwrap()) == APP_DESC_MAGIC && length >= APP_DESC_LEN
+        {
+            return Some(offset);
+        }
+
+        offset += length;
+    }
+
+    None
+}
+
+/// Write a string to a NUL terminated field
+fn write_field(
+    desc: &mut [u8],
+    (offset, len): (usize, usize),
+    name: &'static str,
+    value: &str,
+) -> Result<(), Error> {
+    if value.len() >= len {
+        return Err(Error::AppDescriptorFieldTooLong {
+            field: name,
+            max: len - 1,
+        });
+    }
+
+    let field = &mut desc[offset..][..len];
+    field.fill(0);
+    field[..value.len()].copy_from_slice(value.as_bytes());
+
+    Ok(())
+}
+
+/// Format a Unix timestamp in the same way as the `__DATE__` and `__TIME__`
+/// macros, which are used to populate the build time
+fn format_build_time(timestamp: u64) -> (String, String) {
+    let days = timestamp / 86_400;
+    let seconds = timestamp % 86_400;
+
+    // Convert days since the epoch to a civil date:
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    let date = format!("{} {day:>2} {year}", MONTHS[month as usize - 1]);
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+
+    (date, time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_build_time() {
+        assert_eq!(
+            format_build_time(0),
+            ("Jan  1 1970".into(), "00:00:00".into())
+        );
+        assert_eq!(
+            format_build_time(1_700_000_000),
+            ("Nov 14 2023".into(), "22:13:20".into())
+        );
+        assert_eq!(
+            format_build_time(951_782_400),
+            ("Feb 29 2000".into(), "00:00:00".into())
+        );
+    }
+
+    #[test]
+    fn patches_image() {
+        let mut desc = vec![0u8; APP_DESC_LEN];
+        desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        desc[VERSION.0..][..3].copy_from_slice(b"1.0");
+
+        let mut image = vec![0xE9, 1];
+        image.resize(IMAGE_HEADER_LEN, 0);
+        image.extend_from_slice(&0x3c00_0020u32.to_le_bytes());
+        image.extend_from_slice(&(APP_DESC_LEN as u32).to_le_bytes());
+        image.extend_from_slice(&desc);
+        image.resize(image.len() + 15, 0);
+        image.push(desc.iter().fold(0xef, |checksum, byte| checksum ^ byte));
+        image.extend_from_slice(&[0; DIGEST_LEN]);
+
+        let overrides = AppDescriptorOverrides {
+            version: Some("2.1.0".into()),
+            ..Default::default()
+        };
+        overrides.apply(&mut image).unwrap();
+
+        let offset = IMAGE_HEADER_LEN + SEG_HEADER_LEN;
+        let patched = &image[offset..][..APP_DESC_LEN];
+        assert_eq!(&patched[VERSION.0..][..6], b"2.1.0\0");
+
+        let desc = AppDescriptor::from_image(&image).unwrap();
+        assert_eq!(desc.version, "2.1.0");
+        assert_eq!(
+            AppDescriptor::read(|offset, len| {
+                Ok(image[offset as usize..][..len as usize].to_vec())
+            })
+            .unwrap(),
+            Some(desc)
+        );
+
+        let checksum = image[image.len() - DIGEST_LEN - 1];
+        assert_eq!(
+            checksum,
+            patched.iter().fold(0xef, |checksum, byte| checksum ^ byte)
+        );
+
+        let digest = Sha256::digest(&image[..image.len() - DIGEST_LEN]);
+        assert_eq!(&image[image.len() - DIGEST_LEN..], digest.as_slice());
+
+        let overrides = AppDescriptorOverrides {
+            project_name: Some("x".repeat(32)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            overrides.apply(&mut image),
+            Err(Error::AppDescriptorFieldTooLong { max: 31, .. })
+        ));
+    }
+
+    #[test]
+    fn reproducible_ignores_missing_descriptor() {
+        let mut image = vec![0xE9, 0];
+        image.resize(IMAGE_HEADER_LEN + 16 + DIGEST_LEN, 0);
+
+        let overrides = AppDescriptorOverrides {
+            reproducible: true,
+            ..Default::default()
+        };
+        assert!(overrides.apply(&mut image).is_ok());
+
+        let overrides = AppDescriptorOverrides {
+            build_time: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            overrides.apply(&mut image),
+            Err(Error::AppDescriptorNotFound)
+        ));
+    }
+}
@@ -0,0 +1,81 @@
+//! Direct boot application image format
+//!
+//! The ROM bootloader of the ESP32-C2, ESP32-C3, ESP32-C6 and ESP32-H2 is able
+//! to boot an application directly from flash, without a second-stage
+//! bootloader or partition table. The image is the application's segments
+//! mapped into the first 4MB of flash, and must contain a magic value which the
+//! ROM looks for before booting it.
+
+use std::iter::once;
+
+use crate::{
+    elf::{CodeSegment, FirmwareImage, RomSegment},
+    error::Error,
+    image_format::ImageFormat,
+};
+
+/// Magic value which the ROM bootloader looks for at the start of flash before
+/// booting an image directly
+const DIRECT_BOOT_MAGIC: &[u8] = &[0x1d, 0x04, 0xdb, 0xae, 0x1d, 0x04, 0xdb, 0xae];
+
+/// Image format for ESP32 family chips which boot the application directly,
+/// without a second-stage bootloader
+pub struct DirectBootFormat<'a> {
+    segment: RomSegment<'a>,
+}
+
+impl<'a> DirectBootFormat<'a> {
+    pub fn new(image: &'a dyn FirmwareImage<'a>, magic_offset: usize) -> Result<Self, Error> {
+        let mut segments = image
+            .segments_with_load_addresses()
+            .map(|mut segment| {
+                // Map the address to the first 4MB of address space
+                segment.addr %= 0x40_0000;
+                segment
+            })
+            .collect::<Vec<_>>();
+        segments.sort();
+
+        let mut segment = segments.iter().fold(CodeSegment::default(), |mut a, b| {
+            a += b;
+            a
+        });
+        segment.pad_align(4);
+
+        // An image too short to contain the magic value is not bootable either
+        let magic = segment
+            .data()
+            .get(magic_offset..magic_offset + DIRECT_BOOT_MAGIC.len());
+        if segment.addr != 0 || magic != Some(DIRECT_BOOT_MAGIC) {
+            return Err(Error::InvalidDirectBootBinary);
+        }
+
+        Ok(Self {
+            segment: segment.into(),
+        })
+    }
+}
+
+impl<'a> ImageFormat<'a> for DirectBootFormat<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.segment.borrow()))
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.segment.borrow()))
+    }
+
+    fn app_size(&self) -> u32 {
+        self.segment.data.len() as u32
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        None
+    }
+}
@@ -0,0 +1,280 @@
+//! ESP8266 application image formats
+//!
+//! The ESP8266 ROM bootloader boots a "version 1" image from the start of
+//! flash, consisting of a header followed by the segments to be loaded into
+//! RAM. The segment executed in place from flash (IROM) is not part of the
+//! image, and is instead written at its offset within the flash mapping.
+//!
+//! Applications booted by the second-stage bootloader from the ESP8266 SDK
+//! use the "version 2" format, which additionally contains the IROM segment
+//! and is written following the bootloader.
+//!
+//! https://docs.espressif.com/projects/esptool/en/latest/esp8266/advanced-topics/firmware-image-format.html
+
+use std::{borrow::Cow, io::Write};
+
+use bytemuck::{bytes_of, Pod, Zeroable};
+use flate2::Crc;
+
+use crate::{
+    elf::{CodeSegment, FirmwareImage, RomSegment},
+    error::Error,
+    flasher::{FlashFrequency, FlashMode, FlashSettings, FlashSize},
+    image_format::ImageFormat,
+    targets::Chip,
+};
+
+const ESP_CHECKSUM_MAGIC: u8 = 0xef;
+const ESP_MAGIC: u8 = 0xE9;
+const IMAGE_V2_MAGIC: u8 = 0xEA;
+/// Segment count of the version 2 image header, which is otherwise unused
+const IMAGE_V2_SEGMENT: u8 = 4;
+
+/// Start of the memory region mapped to flash
+const IROM_MAP_START: u32 = 0x4020_0000;
+/// Address at which the version 2 image is written, following the bootloader
+const IMAGE_V2_ADDR: u32 = 0x1000;
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+#[doc(alias = "esp_image_header_t")]
+struct ImageHeader {
+    magic: u8,
+    segment_count: u8,
+    flash_mode: u8,
+    /// ..4 bits are flash frequency, 4.. bits are flash chip size
+    flash_config: u8,
+    entry: u32,
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct SegmentHeader {
+    addr: u32,
+    length: u32,
+}
+
+/// Image format for the ESP8266
+pub struct Esp8266Format<'a> {
+    bootloader: Option<RomSegment<'a>>,
+    flash_segments: Vec<RomSegment<'a>>,
+    app_size: u32,
+}
+
+impl<'a> Esp8266Format<'a> {
+    /// Create a new ESP8266 image
+    ///
+    /// A version 2 image is created if a bootloader is provided, otherwise a
+    /// version 1 image which is booted directly by the ROM bootloader.
+    pub fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        flash_settings: FlashSettings,
+        bootloader: Option<Vec<u8>>,
+    ) -> Result<Self, Error> {
+        let mut irom_segments = image.rom_segments(Chip::Esp8266).collect::<Vec<_>>();
+        irom_segments.sort();
+        let irom = irom_segments.split_first().map(|(first, rest)| {
+            rest.iter().fold(first.clone(), |mut a, b| {
+                a += b;
+                a
+            })
+        });
+
+        let mut ram_segments = image.ram_segments(Chip::Esp8266).collect::<Vec<_>>();
+        ram_segments.sort();
+
+        let flash_size = encode_flash_size(flash_settings.size.unwrap_or_default())?;
+        let flash_freq = flash_settings
+            .freq
+            .unwrap_or(FlashFrequency::_40Mhz)
+            .encode_flash_frequency(Chip::Esp8266)?;
+
+        let flash_mode = flash_settings
+            .mode
+            .unwrap_or(FlashMode::Dio)
+            .encode_flash_mode(Chip::Esp8266)?;
+
+        let header = ImageHeader {
+            magic: ESP_MAGIC,
+            segment_count: ram_segments.len() as u8,
+            flash_mode,
+            flash_config: flash_size | flash_freq,
+            entry: image.entry(),
+        };
+
+        let mut data = Vec::new();
+        let mut flash_segments = Vec::new();
+
+        if let Some(bootloader) = bootloader {
+            let v2_header = ImageHeader {
+                magic: IMAGE_V2_MAGIC,
+                segment_count: IMAGE_V2_SEGMENT,
+                ..header
+            };
+            data.write_all(bytes_of(&v2_header))?;
+
+            // The IROM segment is stored with a load address of 0, and must end
+            // on a 16 byte boundary
+            if let Some(irom) = irom {
+                let mut irom = CodeSegment::new(0, irom.data());
+                let padding = (16 - irom.size() % 16) % 16;
+                irom += &[0u8; 16][..padding as usize];
+                save_segment(&mut data, &irom, 0)?;
+            }
+
+            save_ram_segments(&mut data, header, &ram_segments)?;
+
+            // Unlike the standard CRC32, a value of 0 is never produced
+            let mut crc = Crc::new();
+            crc.update(&data);
+            let crc = match crc.sum() {
+                crc if crc & 0x8000_0000 != 0 => crc ^ 0xffff_ffff,
+                crc => crc + 1,
+            };
+            data.write_all(&crc.to_le_bytes())?;
+
+            let app_size = data.len() as u32;
+            flash_segments.push(RomSegment {
+                addr: IMAGE_V2_ADDR,
+                data: Cow::Owned(data),
+            });
+
+            return Ok(Self {
+                bootloader: Some(RomSegment {
+                    addr: 0,
+                    data: Cow::Owned(bootloader),
+                }),
+                flash_segments,
+                app_size,
+            });
+        }
+
+        save_ram_segments(&mut data, header, &ram_segments)?;
+
+        let mut app_size = data.len() as u32;
+        flash_segments.push(RomSegment {
+            addr: 0,
+            data: Cow::Owned(data),
+        });
+
+        if let Some(irom) = irom {
+            app_size += irom.size();
+            flash_segments.push(RomSegment {
+                addr: irom.addr - IROM_MAP_START,
+                data: Cow::Owned(irom.data().to_vec()),
+            });
+        }
+
+        Ok(Self {
+            bootloader: None,
+            flash_segments,
+            app_size,
+        })
+    }
+}
+
+impl<'a> ImageFormat<'a> for Esp8266Format<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(
+            self.bootloader
+                .iter()
+                .chain(self.flash_segments.iter())
+                .map(RomSegment::borrow),
+        )
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(self.flash_segments.iter().map(RomSegment::borrow))
+    }
+
+    fn app_size(&self) -> u32 {
+        self.app_size
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        None
+    }
+
+    fn skip_bootloader(&mut self) {
+        self.bootloader = None;
+    }
+}
+
+/// Encode the flash size into the format used by the ESP8266 image header
+fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
+    use FlashSize::*;
+
+    let encoded = match size {
+        _512Kb => 0x00,
+        _256Kb => 0x10,
+        _1Mb => 0x20,
+        _2Mb => 0x30,
+        _4Mb => 0x40,
+        _8Mb => 0x80,
+        _16Mb => 0x90,
+        _ => return Err(Error::UnsupportedFlash(size as u8)),
+    };
+
+    Ok(encoded)
+}
+
+/// Write the image header followed by the RAM segments and their checksum
+fn save_ram_segments(
+    data: &mut Vec<u8>,
+    header: ImageHeader,
+    segments: &[CodeSegment],
+) -> Result<(), Error> {
+    data.write_all(bytes_of(&header))?;
+
+    let mut checksum = ESP_CHECKSUM_MAGIC;
+    for segment in segments {
+        checksum = save_segment(data, segment, checksum)?;
+    }
+
+    let padding = 15 - (data.len() % 16);
+    let padding = &[0u8; 16][0..padding];
+    data.write_all(padding)?;
+
+    data.write_all(&[checksum])?;
+
+    Ok(())
+}
+
+/// Stores a segment header and the segment data in the data buffer.
+fn save_segment(data: &mut Vec<u8>, segment: &CodeSegment, checksum: u8) -> Result<u8, Error> {
+    let header = SegmentHeader {
+        addr: segment.addr,
+        length: segment.size(),
+    };
+
+    data.write_all(bytes_of(&header))?;
+    data.write_all(segment.data())?;
+
+    Ok(segment
+        .data()
+        .iter()
+        .fold(checksum, |checksum, byte| checksum ^ byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_size() {
+        assert_eq!(std::mem::size_of::<ImageHeader>(), 8);
+    }
+
+    #[test]
+    fn flash_size_encoding() {
+        assert_eq!(encode_flash_size(FlashSize::_512Kb).unwrap(), 0x00);
+        assert_eq!(encode_flash_size(FlashSize::_4Mb).unwrap(), 0x40);
+        assert!(encode_flash_size(FlashSize::_32Mb).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! Images encrypted on the host for devices with flash encryption enabled
+//!
+//! In release mode the flash encryption key is read protected, and the device
+//! only encrypts data it writes itself, so images must be encrypted ahead of
+//! time. Only the regions the device decrypts are encrypted, and the other
+//! partitions are written in plaintext, as determined by the partition table.
+
+use std::{borrow::Cow, collections::HashMap};
+
+use esp_idf_part::PartitionTable;
+
+use crate::{
+    elf::RomSegment,
+    error::Error,
+    flash_encryption::{encrypt_flash_data, is_encrypted_address},
+    image_format::ImageFormat,
+};
+
+/// An image whose encrypted regions are encrypted with a flash encryption key
+pub(crate) struct FlashEncrypted<'a> {
+    image: Box<dyn ImageFormat<'a> + 'a>,
+    /// Encrypted segments, by the address they are written to
+    encrypted: HashMap<u32, Vec<u8>>,
+}
+
+impl<'a> FlashEncrypted<'a> {
+    pub fn new(image: Box<dyn ImageFormat<'a> + 'a>, key: &[u8]) -> Result<Self, Error> {
+        let mut encrypted = HashMap::new();
+
+        let partition_table = image.partition_table();
+        for segment in image.flash_segments().chain(image.ota_segments()) {
+            if encrypted.contains_key(&segment.addr)
+                || !is_encrypted_address(partition_table, segment.addr)
+            {
+                continue;
+            }
+
+            encrypted.insert(
+                segment.addr,
+                encrypt_flash_data(key, segment.addr, &segment.data)?,
+            );
+        }
+
+        Ok(Self { image, encrypted })
+    }
+
+    fn replace<'b>(&'b self, segment: RomSegment<'b>) -> RomSegment<'b> {
+        match self.encrypted.get(&segment.addr) {
+            Some(data) => RomSegment {
+                addr: segment.addr,
+                data: Cow::Borrowed(data),
+            },
+            None => segment,
+        }
+    }
+}
+
+impl<'a> ImageFormat<'a> for FlashEncrypted<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(
+            self.image
+                .flash_segments()
+                .map(|segment| self.replace(segment)),
+        )
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(
+            self.image
+                .ota_segments()
+                .map(|segment| self.replace(segment)),
+        )
+    }
+
+    fn app_size(&self) -> u32 {
+        self.image.app_size()
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        self.image.part_size()
+    }
+
+    fn skip_bootloader(&mut self) {
+        self.image.skip_bootloader();
+    }
+
+    fn skip_partition_table(&mut self) {
+        self.image.skip_partition_table();
+    }
+
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        self.image.partition_table()
+    }
+}
@@ -3,13 +3,15 @@
 use std::{borrow::Cow, io::Write, iter::once, mem::size_of};
 
 use bytemuck::{bytes_of, from_bytes, Pod, Zeroable};
-use esp_idf_part::{Partition, PartitionTable, Type};
+use esp_idf_part::PartitionTable;
 use sha2::{Digest, Sha256};
 
 use crate::{
     elf::{CodeSegment, FirmwareImage, RomSegment},
     error::Error,
     flasher::{FlashFrequency, FlashMode, FlashSettings, FlashSize},
+    image_format::{find_app_partition, AppDescriptorOverrides, ImageFormat, ImageInfo},
+    partition_table,
     targets::{Chip, Esp32Params},
 };
 
@@ -77,18 +79,22 @@ impl Default for ImageHeader {
 }
 
 impl ImageHeader {
-    /// Updates flash size and speed filed.
+    /// Updates flash size and speed filed, leaving those which are not
+    /// specified unchanged.
     pub fn write_flash_config(
         &mut self,
-        size: FlashSize,
-        freq: FlashFrequency,
+        size: Option<FlashSize>,
+        freq: Option<FlashFrequency>,
         chip: Chip,
     ) -> Result<(), Error> {
-        let flash_size = size.encode_flash_size()?;
-        let flash_speed = freq.encode_flash_frequency(chip)?;
-
         // bit field
-        self.flash_config = (flash_size << 4) | flash_speed;
+        if let Some(size) = size {
+            self.flash_config = (size.encode_flash_size()? << 4) | (self.flash_config & 0x0f);
+        }
+        if let Some(freq) = freq {
+            self.flash_config = (self.flash_config & 0xf0) | freq.encode_flash_frequency(chip)?;
+        }
+
         Ok(())
     }
 }
@@ -110,6 +116,9 @@ pub struct IdfBootloaderFormat<'a> {
     app_size: u32,
     part_size: u32,
     partition_table_offset: u32,
+    skip_bootloader: bool,
+    skip_partition_table: bool,
+    skip_partition_table_md5: bool,
 }
 
 impl<'a> IdfBootloaderFormat<'a> {
@@ -123,6 +132,7 @@ impl<'a> IdfBootloaderFormat<'a> {
         target_app_partition: Option<String>,
         bootloader: Option<Vec<u8>>,
         flash_settings: FlashSettings,
+        app_descriptor: AppDescriptorOverrides,
     ) -> Result<Self, Error> {
         let partition_table = partition_table.unwrap_or_else(|| {
             params.default_partition_table(flash_settings.size.map(|v| v.size()))
@@ -133,34 +143,16 @@ impl<'a> IdfBootloaderFormat<'a> {
             Cow::Borrowed(params.default_bootloader)
         };
 
-        // fetch the generated header from the bootloader
-        let mut header: ImageHeader = *from_bytes(&bootloader[0..size_of::<ImageHeader>()]);
-        if header.magic != ESP_MAGIC {
-            return Err(Error::InvalidBootloader);
-        }
-
         // update the header if a user has specified any custom arguments
-        if let Some(mode) = flash_settings.mode {
-            header.flash_mode = mode as u8;
-        }
-
-        header.write_flash_config(
-            flash_settings.size.unwrap_or_default(),
-            flash_settings.freq.unwrap_or(params.flash_freq),
-            chip,
-        )?;
-
-        bootloader.to_mut().splice(
-            0..size_of::<ImageHeader>(),
-            bytes_of(&header).iter().copied(),
+        let bootloader_settings = FlashSettings::new(
+            flash_settings.mode,
+            Some(flash_settings.size.unwrap_or_default()),
+            Some(flash_settings.freq.unwrap_or(params.flash_freq)),
         );
+        update_bootloader_header(bootloader.to_mut(), chip, &bootloader_settings)?;
 
-        // re-calculate hash of the bootloader - needed since we modified the header
-        let bootloader_len = bootloader.len();
-        let mut hasher = Sha256::new();
-        hasher.update(&bootloader[..bootloader_len - 32]);
-        let hash = hasher.finalize();
-        bootloader.to_mut()[bootloader_len - 32..].copy_from_slice(&hash);
+        // fetch the updated header from the bootloader
+        let mut header: ImageHeader = *from_bytes(&bootloader[0..size_of::<ImageHeader>()]);
 
         // write the header of the app
         // use the same settings as the bootloader
@@ -237,24 +229,10 @@ impl<'a> IdfBootloaderFormat<'a> {
         let hash = hasher.finalize();
         data.write_all(&hash)?;
 
-        let target_app_partition: &Partition =
-        // Use the target app partition if provided
-        if let Some(target_partition) = target_app_partition {
-            partition_table
-                .find(&target_partition)
-                .ok_or(Error::AppPartitionNotFound)?
-        } else {
+        app_descriptor.apply(&mut data)?;
 
-            // The default partition table contains the "factory" partition, and if a user
-            // provides a partition table via command-line then the validation step confirms
-            // that at least one "app" partition is present. We prefer the "factory"
-            // partition, and use any available "app" partitions if not present.
-
-            partition_table
-                .find("factory")
-                .or_else(|| partition_table.find_by_type(Type::App))
-                .ok_or(Error::AppPartitionNotFound)?
-        };
+        let target_app_partition =
+            find_app_partition(&partition_table, target_app_partition.as_deref())?;
 
         let app_size = data.len() as u32;
         let part_size = target_app_partition.size();
@@ -290,22 +268,68 @@ impl<'a> IdfBootloaderFormat<'a> {
             app_size,
             part_size,
             partition_table_offset,
+            skip_bootloader: false,
+            skip_partition_table: false,
+            skip_partition_table_md5: false,
         })
     }
+}
+
+/// Update the flash mode, size and frequency in the header of an ESP-IDF
+/// bootloader
+///
+/// Settings which are not specified are left unchanged. The SHA256 digest of
+/// the bootloader is recalculated, since it covers the header.
+pub fn update_bootloader_header(
+    bootloader: &mut [u8],
+    chip: Chip,
+    flash_settings: &FlashSettings,
+) -> Result<(), Error> {
+    let mut header: ImageHeader = match bootloader.get(..size_of::<ImageHeader>()) {
+        Some(bytes) => *from_bytes(bytes),
+        None => return Err(Error::InvalidBootloader),
+    };
+    if header.magic != ESP_MAGIC {
+        return Err(Error::InvalidBootloader);
+    }
+
+    if let Some(mode) = flash_settings.mode {
+        header.flash_mode = mode.encode_flash_mode(chip)?;
+    }
+    header.write_flash_config(flash_settings.size, flash_settings.freq, chip)?;
+
+    bootloader[..size_of::<ImageHeader>()].copy_from_slice(bytes_of(&header));
+
+    // re-calculate hash of the bootloader - needed since we modified the header.
+    // The bootloader may be followed by other data, e.g. in a merged binary, so
+    // the digest is located by parsing the image.
+    let info = ImageInfo::parse(bootloader).map_err(|_| Error::InvalidBootloader)?;
+    if info.digest.is_some() {
+        let digest_offset = info.len - 32;
+        let hash = Sha256::digest(&bootloader[..digest_offset]);
+        bootloader[digest_offset..info.len].copy_from_slice(&hash);
+    }
+
+    Ok(())
+}
 
-    pub fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+impl<'a> ImageFormat<'a> for IdfBootloaderFormat<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
     where
         'a: 'b,
     {
-        let bootloader_segment = RomSegment {
+        let bootloader_segment = (!self.skip_bootloader).then(|| RomSegment {
             addr: self.params.boot_addr,
             data: Cow::Borrowed(&self.bootloader),
-        };
+        });
 
-        let partition_table_segment = RomSegment {
+        let partition_table_segment = (!self.skip_partition_table).then(|| RomSegment {
             addr: self.partition_table_offset,
-            data: Cow::Owned(self.partition_table.to_bin().unwrap()),
-        };
+            data: Cow::Owned(
+                partition_table::to_bin(&self.partition_table, !self.skip_partition_table_md5)
+                    .unwrap(),
+            ),
+        });
 
         let app_segment = RomSegment {
             addr: self.flash_segment.addr,
@@ -313,26 +337,111 @@ impl<'a> IdfBootloaderFormat<'a> {
         };
 
         Box::new(
-            once(bootloader_segment)
-                .chain(once(partition_table_segment))
+            bootloader_segment
+                .into_iter()
+                .chain(partition_table_segment)
                 .chain(once(app_segment)),
         )
     }
 
-    pub fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
     where
         'a: 'b,
     {
         Box::new(once(self.flash_segment.borrow()))
     }
 
-    pub fn app_size(&self) -> u32 {
+    fn app_size(&self) -> u32 {
         self.app_size
     }
 
-    pub fn part_size(&self) -> Option<u32> {
+    fn part_size(&self) -> Option<u32> {
         Some(self.part_size)
     }
+
+    fn skip_bootloader(&mut self) {
+        self.skip_bootloader = true;
+    }
+
+    fn skip_partition_table(&mut self) {
+        self.skip_partition_table = true;
+    }
+
+    fn skip_partition_table_md5(&mut self) {
+        self.skip_partition_table_md5 = true;
+    }
+
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        Some(&self.partition_table)
+    }
+}
+
+/// Build an application image which is booted directly by the ROM bootloader,
+/// using the flash settings and chip ID of the default bootloader
+///
+/// The header only describes the RAM segments, which are written first and
+/// followed by their checksum, so that the ROM bootloader does not attempt to
+/// load the segments mapped from flash. These follow, aligned as usual, and
+/// are mapped by the application itself.
+pub(super) fn ram_only_header_image<'a>(
+    image: &'a dyn FirmwareImage<'a>,
+    chip: Chip,
+    min_rev_full: u16,
+    params: Esp32Params,
+    flash_settings: FlashSettings,
+) -> Result<Vec<u8>, Error> {
+    let mut header: ImageHeader =
+        *from_bytes(&params.default_bootloader[..size_of::<ImageHeader>()]);
+
+    if let Some(mode) = flash_settings.mode {
+        header.flash_mode = mode.encode_flash_mode(chip)?;
+    }
+    header.write_flash_config(
+        Some(flash_settings.size.unwrap_or_default()),
+        Some(flash_settings.freq.unwrap_or(params.flash_freq)),
+        chip,
+    )?;
+
+    header.entry = image.entry();
+    header.wp_pin = WP_PIN_DISABLED;
+    header.chip_id = params.chip_id;
+    header.min_chip_rev_full = min_rev_full;
+    // The ROM bootloader would expect the digest to immediately follow the
+    // checksum, so none is appended
+    header.append_digest = 0;
+
+    let mut data = bytes_of(&header).to_vec();
+
+    let mut flash_segments = merge_adjacent_segments(image.rom_segments(chip).collect());
+    let ram_segments = merge_adjacent_segments(image.ram_segments(chip).collect());
+
+    let mut checksum = ESP_CHECKSUM_MAGIC;
+    for segment in &ram_segments {
+        checksum = save_segment(&mut data, segment, checksum)?;
+    }
+
+    let padding = 15 - (data.len() % 16);
+    let padding = &[0u8; 16][0..padding];
+    data.write_all(padding)?;
+
+    data.write_all(&[checksum])?;
+
+    data[1] = ram_segments.len() as u8;
+
+    // The segments mapped from flash are written in the order used by the
+    // linker scripts of Zephyr and NuttX, matching esptool's `--ram-only-header`
+    flash_segments.reverse();
+    for segment in flash_segments {
+        let pad_len = get_segment_padding(data.len(), &segment);
+        if pad_len > 0 {
+            let padding = vec![0; pad_len as usize];
+            save_segment(&mut data, &CodeSegment::new(0, &padding), 0)?;
+        }
+
+        save_flash_segment(&mut data, segment, 0)?;
+    }
+
+    Ok(data)
 }
 
 /// Actual alignment (in data bytes) required for a segment header: positioned
@@ -427,16 +536,30 @@ mod tests {
     use super::*;
 
     #[test]
+    #[cfg(all(feature = "esp32c3", feature = "esp32s3"))]
     fn test_flash_config_write() {
         let mut header = ImageHeader::default();
         header
-            .write_flash_config(FlashSize::_4Mb, FlashFrequency::_40Mhz, Chip::Esp32c3)
+            .write_flash_config(
+                Some(FlashSize::_4Mb),
+                Some(FlashFrequency::_40Mhz),
+                Chip::Esp32c3,
+            )
             .unwrap();
         assert_eq!(header.flash_config, 0x20);
 
         header
-            .write_flash_config(FlashSize::_32Mb, FlashFrequency::_80Mhz, Chip::Esp32s3)
+            .write_flash_config(
+                Some(FlashSize::_32Mb),
+                Some(FlashFrequency::_80Mhz),
+                Chip::Esp32s3,
+            )
             .unwrap();
         assert_eq!(header.flash_config, 0x5F);
+
+        header
+            .write_flash_config(None, Some(FlashFrequency::_40Mhz), Chip::Esp32s3)
+            .unwrap();
+        assert_eq!(header.flash_config, 0x50);
     }
 }
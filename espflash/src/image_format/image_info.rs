@@ -0,0 +1,265 @@
+//! Inspection of existing ESP-IDF images
+//!
+//! Images generated by ESP-IDF, espflash or other tools may be parsed in order
+//! to display their contents, and to validate the checksum and SHA256 digest
+//! which the bootloader checks before booting them.
+
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+const ESP_CHECKSUM_MAGIC: u8 = 0xef;
+const ESP_MAGIC: u8 = 0xE9;
+const IMAGE_HEADER_LEN: usize = 24;
+const SEG_HEADER_LEN: usize = 8;
+const DIGEST_LEN: usize = 32;
+
+/// A segment of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Load address of the segment
+    pub addr: u32,
+    /// Offset of the segment's data within the image
+    pub offset: usize,
+    /// Size of the segment
+    pub size: u32,
+}
+
+/// Information about an ESP-IDF application or bootloader image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Entry point of the image
+    pub entry: u32,
+    /// Encoded flash mode
+    pub flash_mode: u8,
+    /// Encoded flash size and frequency
+    pub flash_config: u8,
+    /// ID of the chip which the image was built for
+    pub chip_id: u16,
+    /// Minimum chip revision supported by the image, in format: major * 100 +
+    /// minor
+    pub min_chip_rev_full: u16,
+    /// Segments of the image
+    pub segments: Vec<SegmentInfo>,
+    /// Checksum stored in the image
+    pub checksum: u8,
+    /// Checksum calculated from the image's segments
+    pub calculated_checksum: u8,
+    /// SHA256 digest stored in the image, if one was appended
+    pub digest: Option<[u8; DIGEST_LEN]>,
+    /// SHA256 digest calculated from the image's contents
+    pub calculated_digest: [u8; DIGEST_LEN],
+    /// Length of the image, including its digest
+    pub len: usize,
+}
+
+impl ImageInfo {
+    /// Parse an image
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidImage(reason.to_string());
+
+        let header = data
+            .get(..IMAGE_HEADER_LEN)
+            .ok_or_else(|| invalid("the image is too short to contain a header"))?;
+        if header[0] != ESP_MAGIC {
+            return Err(invalid(
+                "the image does not begin with the expected magic byte",
+            ));
+        }
+
+        let segment_count = header[1];
+        let word = |offset: usize| u32::from_le_bytes(data[offset..][..4].try_into().unwrap());
+        let half = |offset: usize| u16::from_le_bytes(data[offset..][..2].try_into().unwrap());
+
+        let mut segments = Vec::with_capacity(segment_count as usize);
+        let mut calculated_checksum = ESP_CHECKSUM_MAGIC;
+        let mut offset = IMAGE_HEADER_LEN;
+
+        for _ in 0..segment_count {
+            if data.len() < offset + SEG_HEADER_LEN {
+                return Err(invalid("the image is truncated"));
+            }
+            let addr = word(offset);
+            let size = word(offset + 4);
+            offset += SEG_HEADER_LEN;
+
+            let segment = data
+                .get(offset..offset + size as usize)
+                .ok_or_else(|| invalid("the image is truncated"))?;
+            calculated_checksum = segment
+                .iter()
+                .fold(calculated_checksum, |checksum, byte| checksum ^ byte);
+
+            segments.push(SegmentInfo { addr, offset, size });
+            offset += size as usize;
+        }
+
+        // The checksum is stored in the last byte of a 16 byte aligned block
+        let checksum_offset = offset + 15 - (offset % 16);
+        let checksum = *data
+            .get(checksum_offset)
+            .ok_or_else(|| invalid("the image is truncated"))?;
+
+        let mut len = checksum_offset + 1;
+        let calculated_digest = Sha256::digest(&data[..len]).into();
+
+        let digest = if header[23] == 1 {
+            let digest = data
+                .get(len..len + DIGEST_LEN)
+                .ok_or_else(|| invalid("the image is truncated"))?;
+            len += DIGEST_LEN;
+
+            Some(digest.try_into().unwrap())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            entry: word(4),
+            flash_mode: header[2],
+            flash_config: header[3],
+            chip_id: half(12),
+            min_chip_rev_full: half(15),
+            segments,
+            checksum,
+            calculated_checksum,
+            digest,
+            calculated_digest,
+            len,
+        })
+    }
+
+    /// Determine the length of an image by reading only its headers
+    ///
+    /// `read` is called with an offset within the image and the number of
+    /// bytes to read, allowing images in flash to be measured without reading
+    /// their contents.
+    pub fn read_len(
+        mut read: impl FnMut(u32, u32) -> Result<Vec<u8>, Error>,
+    ) -> Result<usize, Error> {
+        let invalid = |reason: &str| Error::InvalidImage(reason.to_string());
+
+        let header = read(0, IMAGE_HEADER_LEN as u32)?;
+        if header.len() < IMAGE_HEADER_LEN || header[0] != ESP_MAGIC {
+            return Err(invalid(
+                "the image does not begin with the expected magic byte",
+            ));
+        }
+
+        let mut offset = IMAGE_HEADER_LEN as u32;
+        for _ in 0..header[1] {
+            let segment_header = read(offset, SEG_HEADER_LEN as u32)?;
+            if segment_header.len() < SEG_HEADER_LEN {
+                return Err(invalid("the image is truncated"));
+            }
+            let size = u32::from_le_bytes(segment_header[4..8].try_into().unwrap());
+            offset = offset
+                .checked_add(SEG_HEADER_LEN as u32)
+                .and_then(|offset| offset.checked_add(size))
+                .ok_or_else(|| invalid("the image is truncated"))?;
+        }
+
+        let len = offset as usize + 16 - (offset as usize % 16);
+
+        Ok(if header[23] == 1 {
+            len + DIGEST_LEN
+        } else {
+            len
+        })
+    }
+
+    /// Does the stored checksum match the image's contents?
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.calculated_checksum
+    }
+
+    /// Does the stored digest match the image's contents?
+    ///
+    /// Returns `None` if the image does not have a digest appended.
+    pub fn digest_valid(&self) -> Option<bool> {
+        self.digest.map(|digest| digest == self.calculated_digest)
+    }
+
+    /// Update the checksum and digest of the image, so that they match the
+    /// image's contents
+    ///
+    /// `data` must be the same image which was parsed.
+    pub fn fix(&self, data: &mut [u8]) {
+        let checksum_offset = self.len - 1 - self.digest.map_or(0, |_| DIGEST_LEN);
+        data[checksum_offset] = self.calculated_checksum;
+
+        if self.digest.is_some() {
+            let digest = Sha256::digest(&data[..=checksum_offset]);
+            data[checksum_offset + 1..self.len].copy_from_slice(&digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![ESP_MAGIC, 1, 2, 0x20, 0x00, 0x00, 0x38, 0x40];
+        image.resize(IMAGE_HEADER_LEN, 0);
+        image[23] = 1;
+
+        image.extend_from_slice(&0x4038_0000u32.to_le_bytes());
+        image.extend_from_slice(&8u32.to_le_bytes());
+        image.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        image.resize(image.len() + 7, 0);
+        image.push(ESP_CHECKSUM_MAGIC ^ 1 ^ 2 ^ 3 ^ 4 ^ 5 ^ 6 ^ 7 ^ 8);
+
+        let digest = Sha256::digest(&image);
+        image.extend_from_slice(&digest);
+
+        image
+    }
+
+    #[test]
+    fn parses_valid_image() {
+        let image = image();
+        let info = ImageInfo::parse(&image).unwrap();
+
+        assert_eq!(info.entry, 0x4038_0000);
+        assert_eq!(
+            info.segments,
+            [SegmentInfo {
+                addr: 0x4038_0000,
+                offset: 32,
+                size: 8
+            }]
+        );
+        assert_eq!(info.len, image.len());
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(true));
+    }
+
+    #[test]
+    fn reads_len_from_headers() {
+        let image = image();
+        let len =
+            ImageInfo::read_len(
+                |offset, len| Ok(image[offset as usize..][..len as usize].to_vec()),
+            )
+            .unwrap();
+
+        assert_eq!(len, image.len());
+    }
+
+    #[test]
+    fn fixes_digest() {
+        let mut image = image();
+        image[32] = 0xff;
+
+        let info = ImageInfo::parse(&image).unwrap();
+        assert!(!info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(false));
+
+        info.fix(&mut image);
+
+        let info = ImageInfo::parse(&image).unwrap();
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(true));
+    }
+}
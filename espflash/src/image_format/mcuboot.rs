@@ -0,0 +1,220 @@
+//! MCUboot application image format
+//!
+//! Used by Zephyr and NuttX firmware booted by MCUboot. The image consists of
+//! a header, the application itself, and a trailer of type-length-value (TLV)
+//! entries containing the hash of the image and optionally its signature.
+//!
+//! https://docs.mcuboot.com/design.html#image-format
+
+use std::{borrow::Cow, iter::once, mem::size_of};
+
+use bytemuck::{bytes_of, Pod, Zeroable};
+#[cfg(feature = "secure-boot")]
+use ed25519_dalek::{pkcs8::DecodePrivateKey, Signer, SigningKey};
+use esp_idf_part::PartitionTable;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    elf::{CodeSegment, FirmwareImage, RomSegment},
+    error::Error,
+    image_format::{find_app_partition, ImageFormat},
+    targets::Esp32Params,
+};
+
+const IMAGE_MAGIC: u32 = 0x96f3_b83d;
+const IMAGE_TLV_INFO_MAGIC: u16 = 0x6907;
+
+#[cfg(feature = "secure-boot")]
+const IMAGE_TLV_KEYHASH: u8 = 0x01;
+const IMAGE_TLV_SHA256: u8 = 0x10;
+#[cfg(feature = "secure-boot")]
+const IMAGE_TLV_ED25519: u8 = 0x24;
+
+/// Offset of the primary slot, used when no partition table is provided
+const DEFAULT_PRIMARY_SLOT_ADDR: u32 = 0x2_0000;
+
+/// DER encoding of an Ed25519 `SubjectPublicKeyInfo`, minus the key itself
+#[cfg(feature = "secure-boot")]
+const ED25519_PUBLIC_KEY_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// MCUboot image header
+#[derive(Debug, Default, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+#[doc(alias = "image_header")]
+struct ImageHeader {
+    magic: u32,
+    load_addr: u32,
+    hdr_size: u16,
+    protect_tlv_size: u16,
+    img_size: u32,
+    flags: u32,
+    ver_major: u8,
+    ver_minor: u8,
+    ver_revision: u16,
+    ver_build_num: u32,
+    _pad: u32,
+}
+
+/// Image format for ESP32 family chips using MCUboot as their bootloader
+pub struct McubootFormat<'a> {
+    bootloader: Option<RomSegment<'a>>,
+    flash_segment: RomSegment<'a>,
+    app_size: u32,
+    part_size: Option<u32>,
+    partition_table: Option<PartitionTable>,
+}
+
+impl<'a> McubootFormat<'a> {
+    /// Create a new MCUboot image
+    ///
+    /// The image is written to the target app partition if a partition table
+    /// is provided, or the default primary slot otherwise. If `signing_key`
+    /// contains an Ed25519 private key in PKCS#8 PEM format, the image is
+    /// signed using it, which requires the `secure-boot` feature.
+    pub fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        params: Esp32Params,
+        partition_table: Option<PartitionTable>,
+        target_app_partition: Option<String>,
+        bootloader: Option<Vec<u8>>,
+        signing_key: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut segments = image.segments_with_load_addresses().collect::<Vec<_>>();
+        segments.sort();
+
+        let base = segments
+            .first()
+            .map(|segment| segment.addr)
+            .unwrap_or_default();
+        let mut payload = segments
+            .iter()
+            .fold(CodeSegment::new(base, &[]), |mut a, b| {
+                a += b;
+                a
+            });
+        payload.pad_align(4);
+
+        let header = ImageHeader {
+            magic: IMAGE_MAGIC,
+            hdr_size: size_of::<ImageHeader>() as u16,
+            img_size: payload.size(),
+            ..Default::default()
+        };
+
+        let mut data = bytes_of(&header).to_vec();
+        data.extend_from_slice(payload.data());
+
+        let hash = Sha256::digest(&data);
+
+        let mut tlvs = vec![(IMAGE_TLV_SHA256, hash.to_vec())];
+
+        if let Some(signing_key) = signing_key {
+            tlvs.extend(signature_tlvs(signing_key, &hash)?);
+        }
+
+        let tlv_total = 4 + tlvs.iter().map(|(_, value)| 4 + value.len()).sum::<usize>();
+        data.extend_from_slice(&IMAGE_TLV_INFO_MAGIC.to_le_bytes());
+        data.extend_from_slice(&(tlv_total as u16).to_le_bytes());
+
+        for (kind, value) in tlvs {
+            data.extend_from_slice(&[kind, 0]);
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(&value);
+        }
+
+        let (addr, part_size) = match &partition_table {
+            Some(table) => {
+                let partition = find_app_partition(table, target_app_partition.as_deref())?;
+
+                (partition.offset(), Some(partition.size()))
+            }
+            None => (DEFAULT_PRIMARY_SLOT_ADDR, None),
+        };
+
+        let app_size = data.len() as u32;
+        if let Some(part_size) = part_size {
+            if app_size > part_size {
+                return Err(Error::ElfTooBig(app_size, part_size));
+            }
+        }
+
+        let bootloader = bootloader.map(|bootloader| RomSegment {
+            addr: params.boot_addr,
+            data: Cow::Owned(bootloader),
+        });
+
+        Ok(Self {
+            bootloader,
+            flash_segment: RomSegment {
+                addr,
+                data: Cow::Owned(data),
+            },
+            app_size,
+            part_size,
+            partition_table,
+        })
+    }
+}
+
+impl<'a> ImageFormat<'a> for McubootFormat<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(
+            self.bootloader
+                .iter()
+                .map(RomSegment::borrow)
+                .chain(once(self.flash_segment.borrow())),
+        )
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    fn app_size(&self) -> u32 {
+        self.app_size
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        self.part_size
+    }
+
+    fn skip_bootloader(&mut self) {
+        self.bootloader = None;
+    }
+
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        self.partition_table.as_ref()
+    }
+}
+
+/// TLV entries holding the hash of the public key and the Ed25519 signature of
+/// the image's hash
+#[cfg(feature = "secure-boot")]
+fn signature_tlvs(signing_key: &str, hash: &[u8]) -> Result<[(u8, Vec<u8>); 2], Error> {
+    let key = SigningKey::from_pkcs8_pem(signing_key)
+        .map_err(|e| Error::InvalidSigningKey(e.to_string()))?;
+
+    let mut public_key = ED25519_PUBLIC_KEY_DER_PREFIX.to_vec();
+    public_key.extend_from_slice(key.verifying_key().as_bytes());
+
+    Ok([
+        (IMAGE_TLV_KEYHASH, Sha256::digest(&public_key).to_vec()),
+        (IMAGE_TLV_ED25519, key.sign(hash).to_bytes().to_vec()),
+    ])
+}
+
+#[cfg(not(feature = "secure-boot"))]
+fn signature_tlvs(_signing_key: &str, _hash: &[u8]) -> Result<[(u8, Vec<u8>); 2], Error> {
+    Err(Error::FeatureDisabled(
+        "Signing MCUboot images",
+        "secure-boot",
+    ))
+}
@@ -0,0 +1,265 @@
+//! ESP32 family application image formats
+//!
+//! Applications are usually booted by the second-stage bootloader from
+//! ESP-IDF, however they may also be booted by MCUboot, and some chips are
+//! able to boot applications directly from flash without any bootloader. The
+//! ESP8266 uses its own, simpler image format.
+//!
+//! Zephyr and NuttX applications are booted either by MCUboot or directly by
+//! the ROM bootloader, so the format used for them is selected according to
+//! the operating system the application was built with.
+
+use esp_idf_part::{Partition, PartitionTable, Type};
+use serde::Deserialize;
+use strum::{Display, EnumIter, EnumString, VariantNames};
+
+#[cfg(feature = "esp8266")]
+pub use self::esp8266::Esp8266Format;
+#[cfg(feature = "flash-encryption")]
+pub(crate) use self::flash_encrypted::FlashEncrypted;
+pub(crate) use self::partition_files::WithPartitionFiles;
+#[cfg(feature = "secure-boot")]
+pub(crate) use self::secure_boot::SecureBootSigned;
+#[cfg(feature = "secure-boot")]
+#[cfg_attr(docsrs, doc(cfg(feature = "secure-boot")))]
+pub use self::secure_boot::{
+    sign_image, signature_blocks, ExternalSigner, SecureBootKey, SecureBootPublicKey,
+    SecureBootSigner, SignatureBlock,
+};
+pub use self::{
+    app_descriptor::{AppDescriptor, AppDescriptorOverrides},
+    direct_boot::DirectBootFormat,
+    idf_bootloader::{update_bootloader_header, IdfBootloaderFormat},
+    image_info::{ImageInfo, SegmentInfo},
+    mcuboot::McubootFormat,
+    partition_files::PartitionFile,
+    raw::RawFormat,
+    simple_boot::SimpleBootFormat,
+};
+use crate::{
+    elf::{FirmwareImage, RomSegment},
+    error::Error,
+};
+
+mod app_descriptor;
+mod direct_boot;
+#[cfg(feature = "esp8266")]
+mod esp8266;
+#[cfg(feature = "flash-encryption")]
+mod flash_encrypted;
+mod idf_bootloader;
+mod image_info;
+mod mcuboot;
+mod partition_files;
+mod raw;
+#[cfg(feature = "secure-boot")]
+mod secure_boot;
+mod simple_boot;
+
+/// Magic value of the load header which Zephyr and NuttX place at the start of
+/// applications booted by MCUboot
+const ESP_LOAD_HEADER_MAGIC: u32 = 0xace6_37d3;
+
+/// Operations for working with application images
+pub trait ImageFormat<'a> {
+    /// Segments to be written to flash, including the bootloader and partition
+    /// table if required by the format
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b;
+
+    /// Segments making up the application itself, as used for OTA updates
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b;
+
+    /// Size of the application
+    fn app_size(&self) -> u32;
+
+    /// Size of the partition the application is written to, if any
+    fn part_size(&self) -> Option<u32>;
+
+    /// Leave the bootloader out of the segments written to flash
+    ///
+    /// Formats which do not include a bootloader are unaffected.
+    fn skip_bootloader(&mut self) {}
+
+    /// Leave the partition table out of the segments written to flash
+    ///
+    /// Formats which do not include a partition table are unaffected.
+    fn skip_partition_table(&mut self) {}
+
+    /// Leave the MD5 checksum out of the partition table written to flash, for
+    /// bootloaders from ESP-IDF versions before v3.1
+    ///
+    /// Formats which do not include a partition table are unaffected.
+    fn skip_partition_table_md5(&mut self) {}
+
+    /// Partition table used to locate the application, if any
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        None
+    }
+}
+
+/// Supported application image formats
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Display,
+    EnumIter,
+    EnumString,
+    VariantNames,
+    Deserialize,
+)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum ImageFormatKind {
+    /// Image booted by the second-stage bootloader from ESP-IDF
+    #[default]
+    EspBootloader,
+    /// Image booted directly by the ROM bootloader, without a second-stage
+    /// bootloader or partition table
+    DirectBoot,
+    /// Image booted by MCUboot, as used by Zephyr and NuttX
+    Mcuboot,
+    /// The ELF file's loadable segments, without any header
+    Raw,
+    /// Image booted directly by the ROM bootloader from the bootloader's
+    /// offset, with a header describing only its RAM segments, as used by
+    /// Zephyr and NuttX
+    SimpleBoot,
+}
+
+/// Operating systems whose applications are booted differently
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display, EnumString, Deserialize)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetOs {
+    /// ESP-IDF, or bare-metal applications booted by its bootloader
+    EspIdf,
+    /// Zephyr, booted by MCUboot or directly by the ROM bootloader
+    Zephyr,
+    /// NuttX, booted by MCUboot or directly by the ROM bootloader
+    Nuttx,
+}
+
+impl TargetOs {
+    /// Image format matching how an application built with the operating
+    /// system is booted
+    ///
+    /// Zephyr and NuttX applications built for MCUboot begin with a load
+    /// header, those without one are booted directly by the ROM bootloader.
+    pub fn image_format<'a>(&self, image: &'a dyn FirmwareImage<'a>) -> ImageFormatKind {
+        match self {
+            TargetOs::EspIdf => ImageFormatKind::EspBootloader,
+            TargetOs::Zephyr | TargetOs::Nuttx if has_load_header(image) => {
+                ImageFormatKind::Mcuboot
+            }
+            TargetOs::Zephyr | TargetOs::Nuttx => ImageFormatKind::SimpleBoot,
+        }
+    }
+}
+
+/// Does the segment with the lowest load address begin with the load header
+/// used by MCUboot?
+fn has_load_header<'a>(image: &'a dyn FirmwareImage<'a>) -> bool {
+    image
+        .segments_with_load_addresses()
+        .min()
+        .is_some_and(|segment| {
+            segment
+                .data()
+                .starts_with(&ESP_LOAD_HEADER_MAGIC.to_le_bytes())
+        })
+}
+
+/// Find the app partition to write the application to
+///
+/// Uses the target app partition if provided. Otherwise, the "factory"
+/// partition is preferred, falling back to any available "app" partition if
+/// it is not present.
+pub(crate) fn find_app_partition<'t>(
+    partition_table: &'t PartitionTable,
+    target_app_partition: Option<&str>,
+) -> Result<&'t Partition, Error> {
+    match target_app_partition {
+        Some(label) => partition_table.find(label),
+        None => partition_table
+            .find("factory")
+            .or_else(|| partition_table.find_by_type(Type::App)),
+    }
+    .ok_or(Error::AppPartitionNotFound)
+}
+
+/// How much of a partition is filled by the data an image writes to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PartitionUsage {
+    /// Label of the partition
+    pub label: String,
+    /// Type of the partition
+    pub ty: Type,
+    /// Offset of the partition
+    pub offset: u32,
+    /// Size of the partition
+    pub size: u32,
+    /// Number of bytes written to the partition
+    pub used: u32,
+}
+
+impl PartitionUsage {
+    /// Percentage of the partition which is used
+    pub fn percent(&self) -> f32 {
+        self.used as f32 / self.size.max(1) as f32 * 100.0
+    }
+}
+
+/// Usage of each partition an image writes to, such as the app partition and
+/// the partitions of any partition files, in order of their offsets
+///
+/// Images which do not use a partition table have no partitions.
+pub fn partition_usage<'a>(image: &(dyn ImageFormat<'a> + 'a)) -> Vec<PartitionUsage> {
+    let Some(partition_table) = image.partition_table() else {
+        return Vec::new();
+    };
+
+    let mut partitions = partition_table.partitions().iter().collect::<Vec<_>>();
+    partitions.sort_by_key(|partition| partition.offset());
+
+    partitions
+        .into_iter()
+        .filter_map(|partition| {
+            let start = partition.offset() as u64;
+            let end = start + partition.size() as u64;
+
+            let used = image
+                .flash_segments()
+                .map(|segment| {
+                    let segment_start = segment.addr as u64;
+                    let segment_end = segment_start + segment.data.len() as u64;
+
+                    segment_end
+                        .min(end)
+                        .saturating_sub(segment_start.max(start))
+                })
+                .sum::<u64>();
+
+            (used > 0).then(|| PartitionUsage {
+                label: partition.name().to_string(),
+                ty: partition.ty(),
+                offset: partition.offset(),
+                size: partition.size(),
+                used: used as u32,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,176 @@
+//! Files written to partitions alongside the application
+//!
+//! Data partitions, such as NVS or filesystem images, may be written in the
+//! same flash session as the application. Their offsets are resolved from the
+//! partition table used by the image.
+
+use std::borrow::Cow;
+
+use esp_idf_part::PartitionTable;
+
+use crate::{elf::RomSegment, error::Error, image_format::ImageFormat};
+
+/// Contents of a file to be written to the partition with the given label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionFile {
+    /// Label of the partition
+    pub label: String,
+    /// Data to be written to the start of the partition
+    pub data: Vec<u8>,
+}
+
+/// Image format which writes partition files in addition to the segments of
+/// another format
+pub(crate) struct WithPartitionFiles<'a> {
+    image: Box<dyn ImageFormat<'a> + 'a>,
+    segments: Vec<RomSegment<'a>>,
+}
+
+impl<'a> WithPartitionFiles<'a> {
+    pub fn new(
+        image: Box<dyn ImageFormat<'a> + 'a>,
+        files: Vec<PartitionFile>,
+    ) -> Result<Self, Error> {
+        let labels = files
+            .iter()
+            .map(|file| file.label.clone())
+            .collect::<Vec<_>>();
+        let segments = resolve_partition_files(image.partition_table(), files)?;
+
+        // The files must not overwrite the application, or the bootloader and
+        // partition table
+        for (label, segment) in labels.into_iter().zip(&segments) {
+            let end = segment.addr as u64 + segment.data.len() as u64;
+            let overlaps = image.flash_segments().any(|other| {
+                let other_end = other.addr as u64 + other.data.len() as u64;
+                (segment.addr as u64) < other_end && (other.addr as u64) < end
+            });
+
+            if overlaps {
+                return Err(Error::PartitionFileOverlap(label));
+            }
+        }
+
+        Ok(Self { image, segments })
+    }
+}
+
+impl<'a> ImageFormat<'a> for WithPartitionFiles<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(
+            self.image
+                .flash_segments()
+                .chain(self.segments.iter().map(RomSegment::borrow)),
+        )
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        self.image.ota_segments()
+    }
+
+    fn app_size(&self) -> u32 {
+        self.image.app_size()
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        self.image.part_size()
+    }
+
+    fn skip_bootloader(&mut self) {
+        self.image.skip_bootloader();
+    }
+
+    fn skip_partition_table(&mut self) {
+        self.image.skip_partition_table();
+    }
+
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        self.image.partition_table()
+    }
+}
+
+/// Resolve the offset of each file's partition, checking that the file fits
+/// within it
+fn resolve_partition_files<'a>(
+    partition_table: Option<&PartitionTable>,
+    files: Vec<PartitionFile>,
+) -> Result<Vec<RomSegment<'a>>, Error> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let partition_table = partition_table.ok_or(Error::PartitionFilesWithoutTable)?;
+
+    files
+        .into_iter()
+        .map(|file| {
+            let partition = partition_table
+                .find(&file.label)
+                .ok_or_else(|| Error::PartitionNotFound(file.label.clone()))?;
+
+            let size = file.data.len() as u32;
+            if size > partition.size() {
+                return Err(Error::PartitionFileTooBig {
+                    label: file.label,
+                    size,
+                    part_size: partition.size(),
+                });
+            }
+
+            Ok(RomSegment {
+                addr: partition.offset(),
+                data: Cow::Owned(file.data),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTITIONS: &str = "
+# Name,   Type, SubType, Offset,  Size
+nvs,      data, nvs,     0x9000,  0x6000
+factory,  app,  factory, 0x10000, 0x100000
+";
+
+    fn file(label: &str, size: usize) -> PartitionFile {
+        PartitionFile {
+            label: label.into(),
+            data: vec![0xAA; size],
+        }
+    }
+
+    #[test]
+    fn resolves_partition_offsets() {
+        let table = PartitionTable::try_from_str(PARTITIONS).unwrap();
+
+        let segments = resolve_partition_files(Some(&table), vec![file("nvs", 0x6000)]).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, 0x9000);
+
+        assert!(matches!(
+            resolve_partition_files(Some(&table), vec![file("nvs", 0x6001)]),
+            Err(Error::PartitionFileTooBig {
+                size: 0x6001,
+                part_size: 0x6000,
+                ..
+            })
+        ));
+        assert!(matches!(
+            resolve_partition_files(Some(&table), vec![file("storage", 1)]),
+            Err(Error::PartitionNotFound(_))
+        ));
+        assert!(matches!(
+            resolve_partition_files(None, vec![file("nvs", 1)]),
+            Err(Error::PartitionFilesWithoutTable)
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+use std::iter::once;
+
+use esp_idf_part::PartitionTable;
+
+use crate::{
+    elf::{CodeSegment, FirmwareImage, RomSegment},
+    error::Error,
+    flasher::FlashSettings,
+    image_format::{find_app_partition, ImageFormat},
+    targets::Esp32Params,
+};
+
+/// Image format containing only the ELF file's loadable segments, without any
+/// header, for use with custom bootloaders
+///
+/// The segments are laid out according to their load addresses, relative to
+/// the lowest one, and written to the target app partition.
+pub struct RawFormat<'a> {
+    flash_segment: RomSegment<'a>,
+    part_size: u32,
+    partition_table: PartitionTable,
+}
+
+impl<'a> RawFormat<'a> {
+    pub fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        params: Esp32Params,
+        partition_table: Option<PartitionTable>,
+        target_app_partition: Option<String>,
+        flash_settings: FlashSettings,
+    ) -> Result<Self, Error> {
+        let partition_table = partition_table.unwrap_or_else(|| {
+            params.default_partition_table(flash_settings.size.map(|v| v.size()))
+        });
+
+        let mut segments = image.segments_with_load_addresses().collect::<Vec<_>>();
+        segments.sort();
+
+        let base = segments
+            .first()
+            .map(|segment| segment.addr)
+            .unwrap_or_default();
+        let segment = segments
+            .iter()
+            .fold(CodeSegment::new(base, &[]), |mut a, b| {
+                a += b;
+                a
+            });
+
+        let partition = find_app_partition(&partition_table, target_app_partition.as_deref())?;
+        if segment.size() > partition.size() {
+            return Err(Error::ElfTooBig(segment.size(), partition.size()));
+        }
+
+        Ok(Self {
+            flash_segment: RomSegment {
+                addr: partition.offset(),
+                ..segment.into()
+            },
+            part_size: partition.size(),
+            partition_table,
+        })
+    }
+}
+
+impl<'a> ImageFormat<'a> for RawFormat<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    fn app_size(&self) -> u32 {
+        self.flash_segment.data.len() as u32
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        Some(self.part_size)
+    }
+
+    fn partition_table(&self) -> Option<&PartitionTable> {
+        Some(&self.partition_table)
+    }
+}
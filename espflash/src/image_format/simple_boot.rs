@@ -0,0 +1,69 @@
+//! Simple boot application image format
+//!
+//! Zephyr and NuttX applications may be booted directly by the ROM bootloader,
+//! without a second-stage bootloader or partition table. The image is written
+//! at the bootloader's offset, and its header only describes the segments to
+//! be loaded into RAM; the segments mapped from flash are mapped by the
+//! application itself during startup.
+//!
+//! This is the layout produced by `esptool.py elf2image --ram-only-header`.
+
+use std::{borrow::Cow, iter::once};
+
+use super::idf_bootloader::ram_only_header_image;
+use crate::{
+    elf::{FirmwareImage, RomSegment},
+    error::Error,
+    flasher::FlashSettings,
+    image_format::ImageFormat,
+    targets::{Chip, Esp32Params},
+};
+
+/// Image format for ESP32 family chips booting Zephyr or NuttX applications
+/// directly from the ROM bootloader
+pub struct SimpleBootFormat<'a> {
+    flash_segment: RomSegment<'a>,
+}
+
+impl<'a> SimpleBootFormat<'a> {
+    pub fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        chip: Chip,
+        min_rev_full: u16,
+        params: Esp32Params,
+        flash_settings: FlashSettings,
+    ) -> Result<Self, Error> {
+        let data = ram_only_header_image(image, chip, min_rev_full, params, flash_settings)?;
+
+        Ok(Self {
+            flash_segment: RomSegment {
+                addr: params.boot_addr,
+                data: Cow::Owned(data),
+            },
+        })
+    }
+}
+
+impl<'a> ImageFormat<'a> for SimpleBootFormat<'a> {
+    fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    fn app_size(&self) -> u32 {
+        self.flash_segment.data.len() as u32
+    }
+
+    fn part_size(&self) -> Option<u32> {
+        None
+    }
+}
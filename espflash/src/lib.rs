@@ -14,7 +14,7 @@
 //! [espflash] can also be used as a library:
 //!
 //! ```toml
-//! espflash = { version = "2.1", default-features = false }
+//! espflash = { version = "2.1", default-features = false, features = ["all-chips"] }
 //! ```
 //!
 //! We add `default-features` here to disable the `cli` feature, which is
@@ -22,11 +22,43 @@
 //! provide SemVer guarantees. You likely will not need any of these types or functions
 //! in your application so there's no use pulling in the extra dependencies.
 //!
+//! Support for each target device may instead be enabled individually, using
+//! the features named after each chip, such as `esp32c6`. The flash stubs and
+//! default bootloaders of the other chips are then left out of the binary.
+//!
+//! The `async` feature adds [flasher::AsyncFlasher], which runs the
+//! operations of a [flasher::Flasher] without blocking a `tokio` runtime.
+//!
+//! Signing images for Secure Boot and MCUboot requires the `secure-boot`
+//! feature, and encrypting images and NVS partitions requires the
+//! `flash-encryption` feature. Partition tables and NVS partitions described in
+//! TOML require the `toml` feature. All of these are enabled by the `cli`
+//! feature.
+//!
+//! The `testing` feature adds [testing::VirtualEsp], an emulated target device
+//! which can be connected to in place of a serial port, so that tools built on
+//! espflash can be tested without hardware.
+//!
 //! [espflash]: https://crates.io/crates/espflash
 //! [cargo-binstall]: https://github.com/cargo-bins/cargo-binstall
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+#[cfg(not(any(
+    feature = "esp32",
+    feature = "esp32c2",
+    feature = "esp32c3",
+    feature = "esp32c6",
+    feature = "esp32h2",
+    feature = "esp32p4",
+    feature = "esp32s2",
+    feature = "esp32s3",
+    feature = "esp8266",
+)))]
+compile_error!(
+    "At least one target device must be enabled, using the `all-chips` feature or the features named after each chip"
+);
+
 #[cfg(feature = "cli")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cli")))]
 pub mod cli;
@@ -35,11 +67,27 @@ pub mod command;
 #[cfg(feature = "serialport")]
 #[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
 pub mod connection;
+pub mod coredump;
+pub mod efuse;
 pub mod elf;
 pub mod error;
+pub mod file_format;
+pub mod filesystem;
+#[cfg(feature = "flash-encryption")]
+#[cfg_attr(docsrs, doc(cfg(feature = "flash-encryption")))]
+pub mod flash_encryption;
 pub mod flasher;
 pub mod image_format;
+pub mod nvs;
+pub mod ota;
+pub mod partition_table;
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub mod remote;
 pub mod targets;
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
 
 /// Logging utilities
 #[cfg(feature = "cli")]
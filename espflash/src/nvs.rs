@@ -0,0 +1,1465 @@
+//! Non-volatile storage (NVS) partitions
+//!
+//! ESP-IDF stores key-value pairs in NVS partitions, which are made up of 4 KiB
+//! pages each holding 126 entries of 32 bytes. Strings and blobs span several
+//! entries, and blobs are additionally split into chunks which may be stored in
+//! different pages. Entries are never modified in place: a value is updated by
+//! writing a new entry and marking the old one as erased in the page's entry
+//! state bitmap.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/storage/nvs_flash.html#internals
+
+#[cfg(feature = "toml")]
+use std::collections::BTreeMap;
+use std::{collections::BTreeSet, fmt, fs, path::Path};
+
+#[cfg(feature = "flash-encryption")]
+use aes::{
+    cipher::{generic_array::GenericArray, KeyInit},
+    Aes256,
+};
+use base64::{engine::general_purpose, Engine as _};
+#[cfg(feature = "toml")]
+use serde::Deserialize;
+use strum::{Display, EnumString, VariantNames};
+#[cfg(feature = "flash-encryption")]
+use xts_mode::Xts128;
+
+use crate::{error::Error, ota::esp_crc32, partition_table::is_toml};
+
+/// Size of an NVS page
+pub const NVS_PAGE_SIZE: usize = 0x1000;
+/// Maximum length of keys and namespace names
+pub const NVS_KEY_MAX_LEN: usize = 15;
+/// Length of each of the NVS encryption keys
+pub const NVS_ENCRYPTION_KEY_LEN: usize = 32;
+/// Size of the `nvs_keys` partition
+pub const NVS_KEYS_PARTITION_SIZE: usize = 0x1000;
+
+const ENTRY_LEN: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const BITMAP_OFFSET: usize = 32;
+const ENTRIES_OFFSET: usize = 64;
+
+const PAGE_UNINITIALIZED: u32 = 0xffff_ffff;
+const PAGE_ACTIVE: u32 = 0xffff_fffe;
+const PAGE_FULL: u32 = 0xffff_fffc;
+
+/// Page format version in which blobs are split into chunks
+const VERSION_2: u8 = 0xfe;
+
+const ENTRY_EMPTY: u8 = 0b11;
+const ENTRY_WRITTEN: u8 = 0b10;
+const ENTRY_ERASED: u8 = 0b00;
+
+/// Chunk index of entries which are not blob data
+const CHUNK_ANY: u8 = 0xff;
+/// First chunk index of each of the two versions of a blob
+const CHUNK_VERSIONS: [u8; 2] = [0x00, 0x80];
+
+const TYPE_U8: u8 = 0x01;
+const TYPE_STR: u8 = 0x21;
+const TYPE_BLOB: u8 = 0x41;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_IDX: u8 = 0x48;
+
+/// Type of an NVS value
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, VariantNames)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+pub enum NvsType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    /// UTF-8 string
+    String,
+    /// Binary data
+    Blob,
+}
+
+/// Encoding of blobs when exporting the contents of an NVS partition
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Display, EnumString, VariantNames)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+pub enum BlobEncoding {
+    /// Hexadecimal, the `hex2bin` encoding of `nvs_partition_gen.py`
+    #[default]
+    Hex,
+    /// Base64
+    Base64,
+}
+
+/// A value stored in an NVS partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NvsValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    String(String),
+    Blob(Vec<u8>),
+}
+
+impl NvsValue {
+    /// Parse a value of the given type
+    ///
+    /// Integers may be given in decimal or hexadecimal, and blobs in
+    /// hexadecimal.
+    pub fn parse(ty: NvsType, value: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidNvsValue(format!("`{value}` is not a valid {ty}"));
+        let integer = || parse_integer(value).ok_or_else(invalid);
+
+        let value = match ty {
+            NvsType::U8 => NvsValue::U8(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::I8 => NvsValue::I8(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::U16 => NvsValue::U16(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::I16 => NvsValue::I16(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::U32 => NvsValue::U32(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::I32 => NvsValue::I32(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::U64 => NvsValue::U64(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::I64 => NvsValue::I64(integer()?.try_into().map_err(|_| invalid())?),
+            NvsType::String => NvsValue::String(value.to_string()),
+            NvsType::Blob => NvsValue::Blob(parse_hex(value).ok_or_else(invalid)?),
+        };
+
+        Ok(value)
+    }
+
+    /// Encoding and text of the value, as written in the CSV files used by
+    /// `nvs_partition_gen.py`
+    pub fn encode(&self, blob_encoding: BlobEncoding) -> (String, String) {
+        match self {
+            NvsValue::String(value) => ("string".into(), value.clone()),
+            NvsValue::Blob(value) => match blob_encoding {
+                BlobEncoding::Hex => ("hex2bin".into(), self.to_string()),
+                BlobEncoding::Base64 => ("base64".into(), general_purpose::STANDARD.encode(value)),
+            },
+            value => (value.ty().to_string(), value.to_string()),
+        }
+    }
+
+    /// Type of the value
+    pub fn ty(&self) -> NvsType {
+        match self {
+            NvsValue::U8(_) => NvsType::U8,
+            NvsValue::I8(_) => NvsType::I8,
+            NvsValue::U16(_) => NvsType::U16,
+            NvsValue::I16(_) => NvsType::I16,
+            NvsValue::U32(_) => NvsType::U32,
+            NvsValue::I32(_) => NvsType::I32,
+            NvsValue::U64(_) => NvsType::U64,
+            NvsValue::I64(_) => NvsType::I64,
+            NvsValue::String(_) => NvsType::String,
+            NvsValue::Blob(_) => NvsType::Blob,
+        }
+    }
+
+    /// Type code and data of integers, which are stored within a single entry
+    fn primitive(&self) -> Option<(u8, [u8; 8])> {
+        let (code, bytes) = match self {
+            NvsValue::U8(value) => (0x01, value.to_le_bytes().to_vec()),
+            NvsValue::I8(value) => (0x11, value.to_le_bytes().to_vec()),
+            NvsValue::U16(value) => (0x02, value.to_le_bytes().to_vec()),
+            NvsValue::I16(value) => (0x12, value.to_le_bytes().to_vec()),
+            NvsValue::U32(value) => (0x04, value.to_le_bytes().to_vec()),
+            NvsValue::I32(value) => (0x14, value.to_le_bytes().to_vec()),
+            NvsValue::U64(value) => (0x08, value.to_le_bytes().to_vec()),
+            NvsValue::I64(value) => (0x18, value.to_le_bytes().to_vec()),
+            NvsValue::String(_) | NvsValue::Blob(_) => return None,
+        };
+
+        let mut data = [0xff; 8];
+        data[..bytes.len()].copy_from_slice(&bytes);
+
+        Some((code, data))
+    }
+
+    /// Decode an integer from its type code and data
+    fn from_primitive(code: u8, data: &[u8; 8]) -> Option<Self> {
+        let value = match code {
+            0x01 => NvsValue::U8(data[0]),
+            0x11 => NvsValue::I8(data[0] as i8),
+            0x02 => NvsValue::U16(u16::from_le_bytes([data[0], data[1]])),
+            0x12 => NvsValue::I16(i16::from_le_bytes([data[0], data[1]])),
+            0x04 => NvsValue::U32(u32::from_le_bytes(data[..4].try_into().unwrap())),
+            0x14 => NvsValue::I32(i32::from_le_bytes(data[..4].try_into().unwrap())),
+            0x08 => NvsValue::U64(u64::from_le_bytes(*data)),
+            0x18 => NvsValue::I64(i64::from_le_bytes(*data)),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+}
+
+impl fmt::Display for NvsValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvsValue::U8(value) => write!(f, "{value}"),
+            NvsValue::I8(value) => write!(f, "{value}"),
+            NvsValue::U16(value) => write!(f, "{value}"),
+            NvsValue::I16(value) => write!(f, "{value}"),
+            NvsValue::U32(value) => write!(f, "{value}"),
+            NvsValue::I32(value) => write!(f, "{value}"),
+            NvsValue::U64(value) => write!(f, "{value}"),
+            NvsValue::I64(value) => write!(f, "{value}"),
+            NvsValue::String(value) => write!(f, "{value:?}"),
+            NvsValue::Blob(value) => value.iter().try_for_each(|byte| write!(f, "{byte:02x}")),
+        }
+    }
+}
+
+/// A written entry, along with any entries it spans
+#[derive(Debug, Clone)]
+struct Item {
+    page: usize,
+    index: usize,
+    ns: u8,
+    ty: u8,
+    span: usize,
+    chunk_index: u8,
+    key: String,
+    data: [u8; 8],
+}
+
+/// A value along with the namespace and key it is stored under
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub value: NvsValue,
+}
+
+/// Keys used to encrypt an NVS partition, as stored in the `nvs_keys`
+/// partition
+///
+/// Each entry of an encrypted NVS partition is encrypted using AES-XTS, with
+/// its address within the partition as the tweak. The page headers and entry
+/// state bitmaps are stored unencrypted. Encrypting and decrypting partitions
+/// requires the `flash-encryption` feature.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NvsKeys {
+    /// Key used to encrypt the entries
+    pub eky: [u8; NVS_ENCRYPTION_KEY_LEN],
+    /// Key used to encrypt the tweak
+    pub tky: [u8; NVS_ENCRYPTION_KEY_LEN],
+}
+
+impl NvsKeys {
+    /// Read the keys from the contents of an `nvs_keys` partition
+    pub fn from_partition(data: &[u8]) -> Result<Self, Error> {
+        let Some(keys) = data.get(..2 * NVS_ENCRYPTION_KEY_LEN + 4) else {
+            return Err(Error::InvalidNvsKeys("the partition is too small".into()));
+        };
+        let (keys, crc) = keys.split_at(2 * NVS_ENCRYPTION_KEY_LEN);
+
+        if keys.iter().all(|byte| *byte == 0xff) {
+            return Err(Error::InvalidNvsKeys("the partition is empty".into()));
+        }
+        if esp_crc32(keys) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(Error::InvalidNvsKeys(
+                "the CRC of the keys is invalid".into(),
+            ));
+        }
+
+        Ok(Self::from_bytes(keys))
+    }
+
+    /// Contents of an `nvs_keys` partition holding the keys
+    pub fn to_partition(&self) -> Vec<u8> {
+        let mut data = vec![0xff; NVS_KEYS_PARTITION_SIZE];
+        data[..NVS_ENCRYPTION_KEY_LEN].copy_from_slice(&self.eky);
+        data[NVS_ENCRYPTION_KEY_LEN..][..NVS_ENCRYPTION_KEY_LEN].copy_from_slice(&self.tky);
+        let crc = esp_crc32(&data[..2 * NVS_ENCRYPTION_KEY_LEN]);
+        data[2 * NVS_ENCRYPTION_KEY_LEN..][..4].copy_from_slice(&crc.to_le_bytes());
+
+        data
+    }
+
+    fn from_bytes(keys: &[u8]) -> Self {
+        let (eky, tky) = keys.split_at(NVS_ENCRYPTION_KEY_LEN);
+
+        Self {
+            eky: eky.try_into().unwrap(),
+            tky: tky.try_into().unwrap(),
+        }
+    }
+}
+
+#[cfg(feature = "flash-encryption")]
+impl NvsKeys {
+    /// Generate a new pair of random keys
+    pub fn generate() -> Result<Self, Error> {
+        let mut keys = [0; 2 * NVS_ENCRYPTION_KEY_LEN];
+        getrandom::getrandom(&mut keys)
+            .map_err(|e| Error::InvalidNvsKeys(format!("failed to generate keys: {e}")))?;
+
+        Ok(Self::from_bytes(&keys))
+    }
+
+    /// Encrypt the entries of a page in place
+    fn encrypt_page(&self, page: usize, data: &mut [u8]) {
+        let cipher = self.cipher();
+        for_each_used_entry(data, |index, entry| {
+            cipher.encrypt_sector(entry, entry_tweak(page, index));
+        });
+    }
+
+    /// Decrypt the entries of a page in place
+    fn decrypt_page(&self, page: usize, data: &mut [u8]) {
+        let cipher = self.cipher();
+        for_each_used_entry(data, |index, entry| {
+            cipher.decrypt_sector(entry, entry_tweak(page, index));
+        });
+    }
+
+    fn cipher(&self) -> Xts128<Aes256> {
+        Xts128::new(
+            Aes256::new(GenericArray::from_slice(&self.eky)),
+            Aes256::new(GenericArray::from_slice(&self.tky)),
+        )
+    }
+}
+
+// Partitions cannot be created with keys without the `flash-encryption`
+// feature, so their pages are never encrypted or decrypted
+#[cfg(not(feature = "flash-encryption"))]
+impl NvsKeys {
+    fn encrypt_page(&self, _page: usize, _data: &mut [u8]) {
+        unreachable!()
+    }
+
+    fn decrypt_page(&self, _page: usize, _data: &mut [u8]) {
+        unreachable!()
+    }
+}
+
+impl fmt::Debug for NvsKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Avoid leaking the keys into logs
+        f.debug_struct("NvsKeys").finish_non_exhaustive()
+    }
+}
+
+/// The contents of an NVS partition
+///
+/// Values may be read and written, after which only the modified pages need to
+/// be written back to flash.
+#[derive(Debug, Clone)]
+pub struct Nvs {
+    /// Contents of the partition, with any entries decrypted
+    data: Vec<u8>,
+    modified: BTreeSet<usize>,
+    keys: Option<NvsKeys>,
+}
+
+impl Nvs {
+    /// Create an empty NVS partition of the given size, encrypted if keys are
+    /// given
+    pub fn new(size: usize, keys: Option<NvsKeys>) -> Result<Self, Error> {
+        Self::parse(vec![0xff; size], keys)
+    }
+
+    /// Parse the contents of an NVS partition, decrypting it if keys are given
+    ///
+    /// Decrypting the partition requires the `flash-encryption` feature.
+    pub fn parse(mut data: Vec<u8>, keys: Option<NvsKeys>) -> Result<Self, Error> {
+        if data.is_empty() || data.len() % NVS_PAGE_SIZE != 0 {
+            return Err(Error::InvalidNvs(format!(
+                "its size of {:#x} bytes is not a multiple of the page size",
+                data.len()
+            )));
+        }
+
+        #[cfg(not(feature = "flash-encryption"))]
+        if keys.is_some() {
+            return Err(Error::FeatureDisabled(
+                "Encrypted NVS partitions",
+                "flash-encryption",
+            ));
+        }
+
+        if let Some(keys) = &keys {
+            for (page, data) in data.chunks_exact_mut(NVS_PAGE_SIZE).enumerate() {
+                keys.decrypt_page(page, data);
+            }
+        }
+
+        Ok(Self {
+            data,
+            modified: BTreeSet::new(),
+            keys,
+        })
+    }
+
+    /// Contents of the partition, encrypted if keys were given
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (0..self.page_count())
+            .flat_map(|page| self.page(page))
+            .collect()
+    }
+
+    /// Pages which were modified, along with their offsets within the
+    /// partition
+    pub fn modified_pages(&self) -> impl Iterator<Item = (u32, Vec<u8>)> + '_ {
+        self.modified
+            .iter()
+            .map(|page| ((page * NVS_PAGE_SIZE) as u32, self.page(*page)))
+    }
+
+    /// All values stored in the partition
+    ///
+    /// If writing a value was interrupted before the old one was erased, only
+    /// the newer value is returned, as ESP-IDF does.
+    pub fn entries(&self) -> Result<Vec<NvsEntry>, Error> {
+        let items = self.items();
+
+        items
+            .iter()
+            .enumerate()
+            .filter(|(index, item)| {
+                item.ns != 0
+                    && item.ty != TYPE_BLOB_DATA
+                    && !items[index + 1..].iter().any(|newer| {
+                        newer.ns == item.ns && newer.key == item.key && newer.ty != TYPE_BLOB_DATA
+                    })
+            })
+            .map(|(_, item)| {
+                let namespace = items
+                    .iter()
+                    .find(|ns| ns.ns == 0 && ns.ty == TYPE_U8 && ns.data[0] == item.ns)
+                    .map_or_else(|| format!("#{}", item.ns), |ns| ns.key.clone());
+
+                Ok(NvsEntry {
+                    namespace,
+                    key: item.key.clone(),
+                    value: self.value(&items, item)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Read the value of a key in a namespace
+    pub fn get(&self, namespace: &str, key: &str) -> Result<Option<NvsValue>, Error> {
+        let items = self.items();
+        let Some(ns) = namespace_index(&items, namespace) else {
+            return Ok(None);
+        };
+        let Some(item) = items
+            .iter()
+            .rev()
+            .find(|item| item.ns == ns && item.key == key && item.ty != TYPE_BLOB_DATA)
+        else {
+            return Ok(None);
+        };
+
+        self.value(&items, item).map(Some)
+    }
+
+    /// Set the value of a key in a namespace, creating the namespace if it
+    /// does not exist
+    ///
+    /// The new value is written to the active page, or to newly initialized
+    /// pages if it does not fit. One empty page is always kept, as ESP-IDF
+    /// requires it for garbage collection.
+    pub fn set(&mut self, namespace: &str, key: &str, value: &NvsValue) -> Result<(), Error> {
+        check_key(namespace)?;
+        check_key(key)?;
+
+        let items = self.items();
+        let ns = match namespace_index(&items, namespace) {
+            Some(ns) => ns,
+            None => {
+                let ns = items
+                    .iter()
+                    .filter(|item| item.ns == 0 && item.ty == TYPE_U8)
+                    .map(|item| item.data[0])
+                    .max()
+                    .unwrap_or(0)
+                    .checked_add(1)
+                    .filter(|ns| *ns != 0xff)
+                    .ok_or_else(|| Error::InvalidNvsValue("too many namespaces".into()))?;
+
+                let mut data = [0xff; 8];
+                data[0] = ns;
+                self.write_entry(0, TYPE_U8, CHUNK_ANY, namespace, data, &[])?;
+
+                ns
+            }
+        };
+
+        let old = items
+            .into_iter()
+            .filter(|item| item.ns == ns && item.key == key)
+            .collect::<Vec<_>>();
+
+        match value {
+            NvsValue::String(string) => {
+                let mut data = string.as_bytes().to_vec();
+                data.push(0);
+                self.write_variable(ns, TYPE_STR, key, &data)?;
+            }
+            NvsValue::Blob(blob) if self.version() == VERSION_2 => {
+                // Use the other version of the chunks than the existing blob, as
+                // ESP-IDF does
+                let start = match old.iter().find(|item| item.ty == TYPE_BLOB_IDX) {
+                    Some(idx) if idx.data[5] == CHUNK_VERSIONS[0] => CHUNK_VERSIONS[1],
+                    _ => CHUNK_VERSIONS[0],
+                };
+                self.write_blob(ns, key, blob, start)?;
+            }
+            NvsValue::Blob(blob) => self.write_variable(ns, TYPE_BLOB, key, blob)?,
+            value => {
+                let (code, data) = value.primitive().unwrap();
+                self.write_entry(ns, code, CHUNK_ANY, key, data, &[])?;
+            }
+        }
+
+        for item in old {
+            for index in item.index..item.index + item.span {
+                self.set_entry_state(item.page, index, ENTRY_ERASED);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode the value of an item
+    fn value(&self, items: &[Item], item: &Item) -> Result<NvsValue, Error> {
+        let (ns, key) = (item.ns, item.key.as_str());
+
+        let value = match item.ty {
+            TYPE_STR => {
+                let mut data = self.span_data(item)?;
+                // Strings are stored along with their null terminator
+                if data.last() == Some(&0) {
+                    data.pop();
+                }
+
+                NvsValue::String(String::from_utf8(data).map_err(|_| {
+                    Error::InvalidNvs(format!("the value of `{key}` is not a valid string"))
+                })?)
+            }
+            TYPE_BLOB => NvsValue::Blob(self.span_data(item)?),
+            TYPE_BLOB_IDX => {
+                let size = u32::from_le_bytes(item.data[..4].try_into().unwrap()) as usize;
+                let (count, start) = (item.data[4], item.data[5]);
+
+                // Chunks are looked up from the newest, in case stale chunks of
+                // an interrupted update were left behind
+                let mut data = Vec::with_capacity(size);
+                for chunk in 0..count {
+                    let chunk = items
+                        .iter()
+                        .rev()
+                        .find(|entry| {
+                            entry.ns == ns
+                                && entry.key == key
+                                && entry.ty == TYPE_BLOB_DATA
+                                && entry.chunk_index == start.wrapping_add(chunk)
+                        })
+                        .ok_or_else(|| {
+                            Error::InvalidNvs(format!("a chunk of the blob `{key}` is missing"))
+                        })?;
+                    data.extend(self.span_data(chunk)?);
+                }
+                if data.len() != size {
+                    return Err(Error::InvalidNvs(format!(
+                        "the blob `{key}` is not of the expected size"
+                    )));
+                }
+
+                NvsValue::Blob(data)
+            }
+            code => NvsValue::from_primitive(code, &item.data).ok_or_else(|| {
+                Error::InvalidNvs(format!("`{key}` has an unknown type {code:#04x}"))
+            })?,
+        };
+
+        Ok(value)
+    }
+
+    /// Write a string or version 1 blob, which must fit within a single page
+    fn write_variable(&mut self, ns: u8, ty: u8, key: &str, data: &[u8]) -> Result<(), Error> {
+        if data.len() > (ENTRIES_PER_PAGE - 1) * ENTRY_LEN {
+            return Err(Error::InvalidNvsValue(format!(
+                "the value of `{key}` is too large to fit in a page"
+            )));
+        }
+
+        self.write_entry(ns, ty, CHUNK_ANY, key, variable_header(data), data)
+    }
+
+    /// Write a blob as chunks filling the available space, followed by its
+    /// index
+    fn write_blob(&mut self, ns: u8, key: &str, blob: &[u8], start: u8) -> Result<(), Error> {
+        let mut count = 0u8;
+        let mut rest = blob;
+
+        while !rest.is_empty() {
+            if count == 0x80 {
+                return Err(Error::InvalidNvsValue(format!(
+                    "the blob `{key}` has too many chunks"
+                )));
+            }
+
+            // Each chunk needs at least one entry for its header and one for
+            // its data, and fills the remaining entries of the page
+            let (_, free) = self.reserve(2)?;
+            let (chunk, next) = rest.split_at(rest.len().min((free - 1) * ENTRY_LEN));
+            self.write_entry(
+                ns,
+                TYPE_BLOB_DATA,
+                start + count,
+                key,
+                variable_header(chunk),
+                chunk,
+            )?;
+
+            count += 1;
+            rest = next;
+        }
+
+        let mut data = [0xff; 8];
+        data[..4].copy_from_slice(&(blob.len() as u32).to_le_bytes());
+        data[4] = count;
+        data[5] = start;
+        self.write_entry(ns, TYPE_BLOB_IDX, CHUNK_ANY, key, data, &[])
+    }
+
+    /// Write an entry, followed by any data spanning additional entries
+    fn write_entry(
+        &mut self,
+        ns: u8,
+        ty: u8,
+        chunk_index: u8,
+        key: &str,
+        data: [u8; 8],
+        span_data: &[u8],
+    ) -> Result<(), Error> {
+        let span = 1 + span_data.len().div_ceil(ENTRY_LEN);
+        let (page, _) = self.reserve(span)?;
+        let index = self.next_free_entry(page);
+
+        let mut entry = [0xff; ENTRY_LEN];
+        entry[0] = ns;
+        entry[1] = ty;
+        entry[2] = span as u8;
+        entry[3] = chunk_index;
+        entry[8..24].fill(0);
+        entry[8..8 + key.len()].copy_from_slice(key.as_bytes());
+        entry[24..].copy_from_slice(&data);
+        let crc = entry_crc(&entry);
+        entry[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        let offset = page * NVS_PAGE_SIZE + ENTRIES_OFFSET + index * ENTRY_LEN;
+        self.data[offset..][..ENTRY_LEN].copy_from_slice(&entry);
+        self.data[offset + ENTRY_LEN..][..span_data.len()].copy_from_slice(span_data);
+
+        for index in index..index + span {
+            self.set_entry_state(page, index, ENTRY_WRITTEN);
+        }
+
+        Ok(())
+    }
+
+    /// Find a page with at least `span` free entries, initializing a new page
+    /// if the active one is too full, and return it along with the number of
+    /// free entries
+    fn reserve(&mut self, span: usize) -> Result<(usize, usize), Error> {
+        let pages = self.pages_by_seq();
+
+        if let Some(&page) = pages
+            .last()
+            .filter(|page| self.page_state(**page) == PAGE_ACTIVE)
+        {
+            let free = ENTRIES_PER_PAGE - self.next_free_entry(page);
+            if free >= span {
+                return Ok((page, free));
+            }
+
+            self.set_page_state(page, PAGE_FULL);
+        }
+
+        let mut uninitialized =
+            (0..self.page_count()).filter(|page| self.page_state(*page) == PAGE_UNINITIALIZED);
+        let (Some(page), Some(_)) = (uninitialized.next(), uninitialized.next()) else {
+            return Err(Error::NvsFull);
+        };
+
+        let seq = pages
+            .last()
+            .map_or(0, |page| self.page_seq(*page).wrapping_add(1));
+        let version = self.version();
+
+        let header = &mut self.data[page * NVS_PAGE_SIZE..][..BITMAP_OFFSET];
+        header[..4].copy_from_slice(&PAGE_ACTIVE.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        header[8] = version;
+        let crc = esp_crc32(&header[4..28]);
+        header[28..32].copy_from_slice(&crc.to_le_bytes());
+        self.modified.insert(page);
+
+        Ok((page, ENTRIES_PER_PAGE))
+    }
+
+    /// Written entries of the active and full pages, in the order the pages
+    /// were written
+    fn items(&self) -> Vec<Item> {
+        let mut items = Vec::new();
+
+        for page in self.pages_by_seq() {
+            let mut index = 0;
+            while index < ENTRIES_PER_PAGE {
+                if self.entry_state(page, index) != ENTRY_WRITTEN {
+                    index += 1;
+                    continue;
+                }
+
+                let entry = self.entry(page, index);
+                let span = entry[2] as usize;
+                if entry_crc(entry) != u32::from_le_bytes(entry[4..8].try_into().unwrap())
+                    || span == 0
+                    || index + span > ENTRIES_PER_PAGE
+                {
+                    index += 1;
+                    continue;
+                }
+
+                let key = &entry[8..24];
+                let key = &key[..key.iter().position(|b| *b == 0).unwrap_or(key.len())];
+
+                items.push(Item {
+                    page,
+                    index,
+                    ns: entry[0],
+                    ty: entry[1],
+                    span,
+                    chunk_index: entry[3],
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    data: entry[24..].try_into().unwrap(),
+                });
+                index += span;
+            }
+        }
+
+        items
+    }
+
+    /// Data of a string or blob, stored in the entries following its header
+    fn span_data(&self, item: &Item) -> Result<Vec<u8>, Error> {
+        let size = u16::from_le_bytes([item.data[0], item.data[1]]) as usize;
+        let crc = u32::from_le_bytes(item.data[4..].try_into().unwrap());
+
+        let offset = item.page * NVS_PAGE_SIZE + ENTRIES_OFFSET + (item.index + 1) * ENTRY_LEN;
+        let data = &self.data[offset..][..(item.span - 1) * ENTRY_LEN];
+        let data = data.get(..size).ok_or_else(|| {
+            Error::InvalidNvs(format!("the value of `{}` is truncated", item.key))
+        })?;
+
+        if esp_crc32(data) != crc {
+            return Err(Error::InvalidNvs(format!(
+                "the value of `{}` is corrupt",
+                item.key
+            )));
+        }
+
+        Ok(data.to_vec())
+    }
+
+    /// Active and full pages, in the order they were written
+    fn pages_by_seq(&self) -> Vec<usize> {
+        let mut pages = (0..self.page_count())
+            .filter(|page| {
+                let header = &self.data[page * NVS_PAGE_SIZE..][..BITMAP_OFFSET];
+                matches!(self.page_state(*page), PAGE_ACTIVE | PAGE_FULL)
+                    && esp_crc32(&header[4..28])
+                        == u32::from_le_bytes(header[28..32].try_into().unwrap())
+            })
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|page| self.page_seq(*page));
+
+        pages
+    }
+
+    /// Format version of the partition, the latest one if it is empty
+    fn version(&self) -> u8 {
+        self.pages_by_seq()
+            .last()
+            .map_or(VERSION_2, |page| self.data[page * NVS_PAGE_SIZE + 8])
+    }
+
+    /// Contents of a page, with its entries encrypted if keys were given
+    fn page(&self, page: usize) -> Vec<u8> {
+        let mut data = self.data[page * NVS_PAGE_SIZE..][..NVS_PAGE_SIZE].to_vec();
+
+        if let Some(keys) = &self.keys {
+            keys.encrypt_page(page, &mut data);
+        }
+
+        data
+    }
+
+    fn page_count(&self) -> usize {
+        self.data.len() / NVS_PAGE_SIZE
+    }
+
+    fn page_state(&self, page: usize) -> u32 {
+        u32::from_le_bytes(self.data[page * NVS_PAGE_SIZE..][..4].try_into().unwrap())
+    }
+
+    fn set_page_state(&mut self, page: usize, state: u32) {
+        self.data[page * NVS_PAGE_SIZE..][..4].copy_from_slice(&state.to_le_bytes());
+        self.modified.insert(page);
+    }
+
+    fn page_seq(&self, page: usize) -> u32 {
+        u32::from_le_bytes(
+            self.data[page * NVS_PAGE_SIZE + 4..][..4]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    fn entry(&self, page: usize, index: usize) -> &[u8] {
+        &self.data[page * NVS_PAGE_SIZE + ENTRIES_OFFSET + index * ENTRY_LEN..][..ENTRY_LEN]
+    }
+
+    fn entry_state(&self, page: usize, index: usize) -> u8 {
+        let byte = self.data[page * NVS_PAGE_SIZE + BITMAP_OFFSET + index / 4];
+        (byte >> ((index % 4) * 2)) & 0b11
+    }
+
+    fn set_entry_state(&mut self, page: usize, index: usize, state: u8) {
+        let byte = &mut self.data[page * NVS_PAGE_SIZE + BITMAP_OFFSET + index / 4];
+        let shift = (index % 4) * 2;
+        *byte = (*byte & !(0b11 << shift)) | (state << shift);
+        self.modified.insert(page);
+    }
+
+    /// Index of the entry following the last written or erased entry of a page
+    fn next_free_entry(&self, page: usize) -> usize {
+        (0..ENTRIES_PER_PAGE)
+            .rev()
+            .find(|index| self.entry_state(page, *index) != ENTRY_EMPTY)
+            .map_or(0, |index| index + 1)
+    }
+}
+
+/// Parse a CSV file describing the contents of an NVS partition, in the format
+/// used by ESP-IDF's `nvs_partition_gen.py`
+///
+/// Each row gives a key, its type (`namespace`, `data` or `file`), its
+/// encoding and its value. Each namespace row applies to the rows following
+/// it, and the values of `file` rows are read from the given path, relative to
+/// `dir`.
+pub fn parse_csv(csv: &str, dir: &Path) -> Result<Vec<NvsEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut namespace = None;
+
+    for (line, row) in csv.lines().enumerate() {
+        let line = line + 1;
+        let invalid = |reason: String| Error::InvalidNvsCsv { line, reason };
+
+        let row = row.trim();
+        if row.is_empty() || row.starts_with('#') {
+            continue;
+        }
+
+        let fields = split_csv_row(row).ok_or_else(|| invalid("unterminated quote".into()))?;
+        let field = |index: usize| fields.get(index).map_or("", String::as_str);
+        let (key, ty, encoding, value) = (field(0), field(1), field(2), field(3));
+
+        // Skip the header row
+        if (key, ty) == ("key", "type") {
+            continue;
+        }
+
+        if ty == "namespace" {
+            check_key(key)?;
+            namespace = Some(key.to_string());
+            continue;
+        }
+
+        let value = decode_value(ty, encoding, value, dir).map_err(invalid)?;
+
+        let namespace = namespace
+            .clone()
+            .ok_or_else(|| invalid("the first entry must be a namespace".into()))?;
+        check_key(key)?;
+
+        entries.push(NvsEntry {
+            namespace,
+            key: key.to_string(),
+            value,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Write entries to a CSV file in the format used by `nvs_partition_gen.py`,
+/// which [parse_csv] reads back
+///
+/// A namespace row is written whenever the namespace changes between entries.
+/// Strings containing line breaks cannot be represented in this format.
+pub fn to_csv(entries: &[NvsEntry], blob_encoding: BlobEncoding) -> Result<String, Error> {
+    let mut csv = String::from("key,type,encoding,value\n");
+    let mut namespace = None;
+
+    for entry in entries {
+        if namespace != Some(&entry.namespace) {
+            csv.push_str(&format!("{},namespace,,\n", csv_field(&entry.namespace)));
+            namespace = Some(&entry.namespace);
+        }
+
+        let (encoding, value) = entry.value.encode(blob_encoding);
+        if value.contains(['\n', '\r']) {
+            return Err(Error::InvalidNvsValue(format!(
+                "the value of `{}` contains a line break, which cannot be written to a CSV file",
+                entry.key
+            )));
+        }
+
+        csv.push_str(&format!(
+            "{},data,{encoding},{}\n",
+            csv_field(&entry.key),
+            csv_field(&value)
+        ));
+    }
+
+    Ok(csv)
+}
+
+/// Parse the description of the contents of an NVS partition read from
+/// `path`, in TOML format if it has the `.toml` extension and CSV format
+/// otherwise
+pub fn parse_entries(description: &str, path: &Path) -> Result<Vec<NvsEntry>, Error> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    if is_toml(path) {
+        #[cfg(feature = "toml")]
+        return parse_toml(description, dir);
+        #[cfg(not(feature = "toml"))]
+        return Err(Error::FeatureDisabled(
+            "Reading TOML NVS descriptions",
+            "toml",
+        ));
+    }
+
+    parse_csv(description, dir)
+}
+
+/// Parse a TOML file describing the contents of an NVS partition
+///
+/// Each table is a namespace, holding either strings or tables with the
+/// `type`, `encoding` and `value` columns of the CSV format:
+///
+/// ```toml
+/// [wifi]
+/// ssid = "MyAP"
+/// channel = { encoding = "u8", value = 6 }
+/// cert = { type = "file", encoding = "binary", value = "cert.der" }
+/// ```
+#[cfg(feature = "toml")]
+pub fn parse_toml(toml: &str, dir: &Path) -> Result<Vec<NvsEntry>, Error> {
+    let namespaces: BTreeMap<String, BTreeMap<String, TomlValue>> =
+        toml::from_str(toml).map_err(|e| Error::InvalidNvsToml(e.to_string()))?;
+
+    let mut entries = Vec::new();
+    for (namespace, values) in namespaces {
+        check_key(&namespace)?;
+
+        for (key, value) in values {
+            check_key(&key)?;
+
+            let value = match value {
+                TomlValue::String(value) => NvsValue::String(value),
+                TomlValue::Entry {
+                    ty,
+                    encoding,
+                    value,
+                } => {
+                    let value = match value {
+                        TomlField::Integer(value) => value.to_string(),
+                        TomlField::String(value) => value,
+                    };
+
+                    decode_value(&ty, &encoding, &value, dir)
+                        .map_err(|e| Error::InvalidNvsToml(format!("`{namespace}.{key}`: {e}")))?
+                }
+            };
+
+            entries.push(NvsEntry {
+                namespace: namespace.clone(),
+                key,
+                value,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(feature = "toml")]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TomlValue {
+    String(String),
+    Entry {
+        #[serde(rename = "type", default = "default_toml_type")]
+        ty: String,
+        encoding: String,
+        value: TomlField,
+    },
+}
+
+#[cfg(feature = "toml")]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TomlField {
+    Integer(i64),
+    String(String),
+}
+
+#[cfg(feature = "toml")]
+fn default_toml_type() -> String {
+    "data".into()
+}
+
+/// Decode a value given by its type (`data` or `file`), encoding and value, as
+/// written in a CSV file
+fn decode_value(ty: &str, encoding: &str, value: &str, dir: &Path) -> Result<NvsValue, String> {
+    match ty {
+        "data" if encoding == "binary" => {
+            Err("the `binary` encoding is only supported for files".into())
+        }
+        "data" => decode_data(encoding, value.as_bytes()),
+        "file" => {
+            let path = dir.join(value);
+            let data =
+                fs::read(&path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+            decode_data(encoding, &data)
+        }
+        _ => Err(format!("unknown type `{ty}`")),
+    }
+}
+
+/// Decode data using the given encoding
+fn decode_data(encoding: &str, data: &[u8]) -> Result<NvsValue, String> {
+    if encoding == "binary" {
+        return Ok(NvsValue::Blob(data.to_vec()));
+    }
+
+    let text = std::str::from_utf8(data).map_err(|_| "the value is not valid UTF-8".to_string())?;
+    let value = match encoding {
+        "string" => NvsValue::String(text.to_string()),
+        "hex2bin" => NvsValue::Blob(
+            parse_hex(text.trim()).ok_or_else(|| format!("`{text}` is not valid hexadecimal"))?,
+        ),
+        "base64" => NvsValue::Blob(
+            general_purpose::STANDARD
+                .decode(text.trim())
+                .map_err(|e| format!("`{text}` is not valid base64: {e}"))?,
+        ),
+        encoding => {
+            let ty = encoding
+                .parse::<NvsType>()
+                .ok()
+                .filter(|ty| !matches!(ty, NvsType::String | NvsType::Blob))
+                .ok_or_else(|| format!("unknown encoding `{encoding}`"))?;
+
+            NvsValue::parse(ty, text.trim()).map_err(|e| e.to_string())?
+        }
+    };
+
+    Ok(value)
+}
+
+/// Split a row of a CSV file into its fields, which may be quoted
+pub(crate) fn split_csv_row(row: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = row.chars().peekable();
+
+    loop {
+        let mut field = String::new();
+        while chars.peek().is_some_and(|c| *c == ' ') {
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next()? {
+                    '"' if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+            while chars.peek().is_some_and(|c| *c != ',') {
+                chars.next();
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                field.push(c);
+            }
+            field = field.trim_end().to_string();
+        }
+
+        fields.push(field);
+        if chars.next().is_none() {
+            return Some(fields);
+        }
+    }
+}
+
+/// Quote a field of a CSV file if [split_csv_row] would not read it back as is
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"'])
+        || field.starts_with([' ', '#'])
+        || field.ends_with(char::is_whitespace)
+    {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Call `f` with each entry of a page which is written or erased
+#[cfg(feature = "flash-encryption")]
+fn for_each_used_entry(page: &mut [u8], mut f: impl FnMut(usize, &mut [u8])) {
+    let (header, entries) = page.split_at_mut(ENTRIES_OFFSET);
+    let bitmap = &header[BITMAP_OFFSET..];
+
+    for (index, entry) in entries
+        .chunks_exact_mut(ENTRY_LEN)
+        .take(ENTRIES_PER_PAGE)
+        .enumerate()
+    {
+        if (bitmap[index / 4] >> ((index % 4) * 2)) & 0b11 != ENTRY_EMPTY {
+            f(index, entry);
+        }
+    }
+}
+
+/// The tweak used to encrypt an entry, which is its address within the
+/// partition
+#[cfg(feature = "flash-encryption")]
+fn entry_tweak(page: usize, index: usize) -> [u8; 16] {
+    ((page * NVS_PAGE_SIZE + ENTRIES_OFFSET + index * ENTRY_LEN) as u128).to_le_bytes()
+}
+
+/// Index of the namespace with the given name
+fn namespace_index(items: &[Item], namespace: &str) -> Option<u8> {
+    items
+        .iter()
+        .find(|item| item.ns == 0 && item.ty == TYPE_U8 && item.key == namespace)
+        .map(|item| item.data[0])
+}
+
+fn check_key(key: &str) -> Result<(), Error> {
+    if key.is_empty() || key.len() > NVS_KEY_MAX_LEN || key.contains('\0') {
+        return Err(Error::InvalidNvsKey(key.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Size and CRC of a string or blob, stored in the data of its first entry
+fn variable_header(data: &[u8]) -> [u8; 8] {
+    let mut header = [0xff; 8];
+    header[..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    header[4..].copy_from_slice(&esp_crc32(data).to_le_bytes());
+
+    header
+}
+
+/// The CRC of an entry, which excludes the CRC itself
+fn entry_crc(entry: &[u8]) -> u32 {
+    let mut data = entry[..4].to_vec();
+    data.extend_from_slice(&entry[8..ENTRY_LEN]);
+
+    esp_crc32(&data)
+}
+
+fn parse_integer(value: &str) -> Option<i128> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    if value.len() % 2 != 0 || !value.is_ascii() {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank(pages: usize) -> Nvs {
+        Nvs::new(pages * NVS_PAGE_SIZE, None).unwrap()
+    }
+
+    #[test]
+    fn sets_and_gets_values() {
+        let mut nvs = blank(3);
+        nvs.set("wifi", "ssid", &NvsValue::String("MyAP".into()))
+            .unwrap();
+        nvs.set("wifi", "channel", &NvsValue::U8(6)).unwrap();
+        nvs.set("cal", "offset", &NvsValue::I32(-42)).unwrap();
+        nvs.set("cal", "data", &NvsValue::Blob(vec![1, 2, 3]))
+            .unwrap();
+
+        assert_eq!(
+            nvs.get("wifi", "ssid").unwrap(),
+            Some(NvsValue::String("MyAP".into()))
+        );
+        assert_eq!(nvs.get("wifi", "channel").unwrap(), Some(NvsValue::U8(6)));
+        assert_eq!(nvs.get("cal", "offset").unwrap(), Some(NvsValue::I32(-42)));
+        assert_eq!(
+            nvs.get("cal", "data").unwrap(),
+            Some(NvsValue::Blob(vec![1, 2, 3]))
+        );
+        assert_eq!(nvs.get("wifi", "offset").unwrap(), None);
+        assert_eq!(nvs.modified_pages().count(), 1);
+    }
+
+    #[test]
+    fn writes_namespace_entry() {
+        let mut nvs = blank(2);
+        nvs.set("wifi", "channel", &NvsValue::U8(6)).unwrap();
+
+        let page = nvs.to_bytes();
+        // Header of an active page with sequence number 0 in version 2 format
+        assert_eq!(page[..9], [0xfe, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0xfe]);
+        // The namespace entry and the value are written
+        assert_eq!(page[BITMAP_OFFSET], 0b1111_1010);
+        assert_eq!(
+            page[ENTRIES_OFFSET..][..ENTRY_LEN],
+            [
+                0x00, 0x01, 0x01, 0xff, 0x59, 0x11, 0x31, 0x27, b'w', b'i', b'f', b'i', 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+            ]
+        );
+    }
+
+    #[test]
+    fn replaces_values() {
+        let mut nvs = blank(3);
+        nvs.set("wifi", "ssid", &NvsValue::String("MyAP".into()))
+            .unwrap();
+        nvs.set("wifi", "ssid", &NvsValue::String("OtherAP".into()))
+            .unwrap();
+        nvs.set("wifi", "ssid", &NvsValue::U16(1)).unwrap();
+
+        assert_eq!(nvs.get("wifi", "ssid").unwrap(), Some(NvsValue::U16(1)));
+        assert_eq!(
+            nvs.items().iter().filter(|item| item.key == "ssid").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn splits_blobs_across_pages() {
+        let mut nvs = blank(4);
+        let blob = (0..6000).map(|i| i as u8).collect::<Vec<_>>();
+        nvs.set("cal", "data", &NvsValue::Blob(blob.clone()))
+            .unwrap();
+
+        assert_eq!(nvs.get("cal", "data").unwrap(), Some(NvsValue::Blob(blob)));
+        assert_eq!(nvs.modified_pages().count(), 2);
+    }
+
+    #[test]
+    fn keeps_an_empty_page() {
+        let mut nvs = blank(2);
+        let blob = vec![0; 5000];
+
+        assert!(matches!(
+            nvs.set("cal", "data", &NvsValue::Blob(blob)),
+            Err(Error::NvsFull)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "flash-encryption")]
+    fn encrypts_entries() {
+        let keys = NvsKeys {
+            eky: [0x11; 32],
+            tky: [0x22; 32],
+        };
+        let mut nvs = Nvs::new(2 * NVS_PAGE_SIZE, Some(keys.clone())).unwrap();
+        nvs.set("wifi", "channel", &NvsValue::U8(6)).unwrap();
+
+        let data = nvs.to_bytes();
+        // Only the entries are encrypted
+        assert_eq!(data[..9], [0xfe, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0xfe]);
+        assert_eq!(
+            data[ENTRIES_OFFSET..][..ENTRY_LEN],
+            [
+                0x3e, 0xdb, 0x1d, 0xc1, 0xa8, 0xcc, 0x62, 0x5b, 0x9f, 0x9f, 0xdd, 0x3c, 0xa9, 0xe1,
+                0x65, 0x79, 0x05, 0xef, 0x95, 0x1d, 0xb4, 0x26, 0x8e, 0x74, 0x33, 0x35, 0xbc, 0x91,
+                0x8e, 0x38, 0x5a, 0x17
+            ]
+        );
+        assert!(data[ENTRIES_OFFSET + 2 * ENTRY_LEN..NVS_PAGE_SIZE]
+            .iter()
+            .all(|byte| *byte == 0xff));
+
+        let nvs = Nvs::parse(data, Some(keys)).unwrap();
+        assert_eq!(nvs.get("wifi", "channel").unwrap(), Some(NvsValue::U8(6)));
+    }
+
+    #[test]
+    fn converts_keys_partition() {
+        let keys = NvsKeys {
+            eky: [0x11; 32],
+            tky: [0x22; 32],
+        };
+
+        let data = keys.to_partition();
+        assert_eq!(data.len(), NVS_KEYS_PARTITION_SIZE);
+        assert_eq!(data[64..68], 0x3ccf_ef2c_u32.to_le_bytes());
+        assert_eq!(NvsKeys::from_partition(&data).unwrap(), keys);
+
+        assert!(NvsKeys::from_partition(&[0xff; NVS_KEYS_PARTITION_SIZE]).is_err());
+    }
+
+    #[test]
+    fn parses_csv() {
+        let csv = r#"key,type,encoding,value
+wifi,namespace,,
+ssid,data,string,"My AP, 2.4GHz"
+channel,data,u8,6
+cal,namespace,,
+offset,data,i32,-42
+data,data,hex2bin,deadbeef
+cert,data,base64,AQID
+"#;
+
+        let entries = parse_csv(csv, Path::new(".")).unwrap();
+        let entries = entries
+            .iter()
+            .map(|entry| (entry.namespace.as_str(), entry.key.as_str(), &entry.value))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            [
+                ("wifi", "ssid", &NvsValue::String("My AP, 2.4GHz".into())),
+                ("wifi", "channel", &NvsValue::U8(6)),
+                ("cal", "offset", &NvsValue::I32(-42)),
+                ("cal", "data", &NvsValue::Blob(vec![0xde, 0xad, 0xbe, 0xef])),
+                ("cal", "cert", &NvsValue::Blob(vec![1, 2, 3])),
+            ]
+        );
+
+        assert!(matches!(
+            parse_csv("ssid,data,string,MyAP", Path::new(".")),
+            Err(Error::InvalidNvsCsv { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn exports_csv() {
+        let mut nvs = blank(4);
+        let blob = (0..6000).map(|i| i as u8).collect::<Vec<_>>();
+        nvs.set(
+            "wifi",
+            "ssid",
+            &NvsValue::String("My \"AP\", 2.4GHz ".into()),
+        )
+        .unwrap();
+        nvs.set("cal", "data", &NvsValue::Blob(blob)).unwrap();
+        nvs.set("wifi", "channel", &NvsValue::I8(-6)).unwrap();
+
+        let entries = nvs.entries().unwrap();
+        assert_eq!(entries.len(), 3);
+
+        for encoding in [BlobEncoding::Hex, BlobEncoding::Base64] {
+            let csv = to_csv(&entries, encoding).unwrap();
+            assert_eq!(parse_csv(&csv, Path::new(".")).unwrap(), entries);
+        }
+
+        let string = NvsEntry {
+            namespace: "wifi".into(),
+            key: "ssid".into(),
+            value: NvsValue::String("line\nbreak".into()),
+        };
+        assert!(to_csv(&[string], BlobEncoding::Hex).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "toml")]
+    fn parses_toml() {
+        let toml = r#"
+[wifi]
+ssid = "MyAP"
+channel = { encoding = "u8", value = 6 }
+key = { encoding = "hex2bin", value = "0102" }
+"#;
+
+        let entries = parse_toml(toml, Path::new(".")).unwrap();
+        let entries = entries
+            .iter()
+            .map(|entry| (entry.namespace.as_str(), entry.key.as_str(), &entry.value))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            entries,
+            [
+                ("wifi", "channel", &NvsValue::U8(6)),
+                ("wifi", "key", &NvsValue::Blob(vec![1, 2])),
+                ("wifi", "ssid", &NvsValue::String("MyAP".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(
+            NvsValue::parse(NvsType::U16, "0x1234").unwrap(),
+            NvsValue::U16(0x1234)
+        );
+        assert_eq!(
+            NvsValue::parse(NvsType::I8, "-128").unwrap(),
+            NvsValue::I8(-128)
+        );
+        assert_eq!(
+            NvsValue::parse(NvsType::Blob, "deadbeef").unwrap(),
+            NvsValue::Blob(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert!(NvsValue::parse(NvsType::U8, "256").is_err());
+    }
+}
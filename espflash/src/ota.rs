@@ -0,0 +1,394 @@
+//! OTA data partition
+//!
+//! The `otadata` partition records which OTA app partition the ESP-IDF
+//! bootloader boots. It consists of two flash sectors, each beginning with an
+//! `esp_ota_select_entry_t`. The valid entry with the highest sequence number
+//! selects the app partition, counting from `ota_0`; if neither entry is valid,
+//! the factory app is booted.
+//!
+//! https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/ota.html#ota-data-partition
+
+use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
+use strum::Display;
+
+use crate::error::Error;
+
+/// Size of each of the two copies of the OTA data
+pub const OTADATA_SECTOR_SIZE: usize = 0x1000;
+/// Size of the OTA data partition
+pub const OTADATA_SIZE: usize = 2 * OTADATA_SECTOR_SIZE;
+
+const ENTRY_LEN: usize = 32;
+const SEQ_LABEL_LEN: usize = 20;
+
+/// Label used to select the inactive OTA app partition as the target
+pub const AUTO_OTA: &str = "auto-ota";
+
+/// State of an OTA app, used by the bootloader to roll back failed updates
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[doc(alias = "esp_ota_img_states_t")]
+#[strum(serialize_all = "kebab-case")]
+pub enum OtaImageState {
+    /// The app has been selected, but not yet booted
+    New,
+    /// The app has been booted once, and must mark itself as valid
+    PendingVerify,
+    /// The app has been marked as valid
+    Valid,
+    /// The app has been marked as invalid, and will not be booted
+    Invalid,
+    /// The app failed to mark itself as valid, and will not be booted
+    Aborted,
+    /// The state has not been set, rollback is disabled
+    Undefined,
+}
+
+impl OtaImageState {
+    fn from_u32(state: u32) -> Self {
+        match state {
+            0 => OtaImageState::New,
+            1 => OtaImageState::PendingVerify,
+            2 => OtaImageState::Valid,
+            3 => OtaImageState::Invalid,
+            4 => OtaImageState::Aborted,
+            _ => OtaImageState::Undefined,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            OtaImageState::New => 0,
+            OtaImageState::PendingVerify => 1,
+            OtaImageState::Valid => 2,
+            OtaImageState::Invalid => 3,
+            OtaImageState::Aborted => 4,
+            OtaImageState::Undefined => u32::MAX,
+        }
+    }
+}
+
+/// A copy of the OTA data
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[doc(alias = "esp_ota_select_entry_t")]
+pub struct OtaSelectEntry {
+    /// Sequence number, incremented each time a new app is selected
+    pub seq: u32,
+    /// Unused label
+    pub seq_label: [u8; SEQ_LABEL_LEN],
+    /// State of the selected app
+    pub state: u32,
+    /// CRC32 of the sequence number
+    pub crc: u32,
+}
+
+impl OtaSelectEntry {
+    /// Create an entry selecting the app with the given sequence number
+    pub fn new(seq: u32) -> Self {
+        Self {
+            seq,
+            seq_label: [0xff; SEQ_LABEL_LEN],
+            state: u32::MAX,
+            crc: esp_crc32(&seq.to_le_bytes()),
+        }
+    }
+
+    /// Parse an entry from the start of an OTA data sector
+    fn parse(data: &[u8]) -> Self {
+        let word = |offset: usize| u32::from_le_bytes(data[offset..][..4].try_into().unwrap());
+
+        Self {
+            seq: word(0),
+            seq_label: data[4..][..SEQ_LABEL_LEN].try_into().unwrap(),
+            state: word(24),
+            crc: word(28),
+        }
+    }
+
+    /// Encode the entry, padded with 0xFF to fill an OTA data sector
+    pub fn to_sector(&self) -> Vec<u8> {
+        let mut sector = vec![0xff; OTADATA_SECTOR_SIZE];
+        sector[..4].copy_from_slice(&self.seq.to_le_bytes());
+        sector[4..][..SEQ_LABEL_LEN].copy_from_slice(&self.seq_label);
+        sector[24..28].copy_from_slice(&self.state.to_le_bytes());
+        sector[28..32].copy_from_slice(&self.crc.to_le_bytes());
+
+        sector
+    }
+
+    /// State of the selected app
+    pub fn state(&self) -> OtaImageState {
+        OtaImageState::from_u32(self.state)
+    }
+
+    /// Set the state of the selected app
+    ///
+    /// The state is not covered by the CRC, so only it changes.
+    pub fn set_state(&mut self, state: OtaImageState) {
+        self.state = state.to_u32();
+    }
+
+    /// Is the entry used by the bootloader to select an app?
+    pub fn is_valid(&self) -> bool {
+        self.is_written()
+            && !matches!(
+                self.state(),
+                OtaImageState::Invalid | OtaImageState::Aborted
+            )
+    }
+
+    /// Has the entry been written, whatever the state of its app?
+    fn is_written(&self) -> bool {
+        self.seq != u32::MAX && self.crc == esp_crc32(&self.seq.to_le_bytes())
+    }
+
+    /// Index of the OTA app partition selected by the entry, given the number
+    /// of OTA app partitions
+    pub fn slot(&self, slot_count: usize) -> usize {
+        (self.seq.wrapping_sub(1) % slot_count as u32) as usize
+    }
+}
+
+/// Contents of the OTA data partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtaData {
+    /// Both copies of the OTA data
+    pub entries: [OtaSelectEntry; 2],
+}
+
+impl OtaData {
+    /// Parse the contents of the OTA data partition
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < OTADATA_SIZE {
+            return Err(Error::InvalidOtaData);
+        }
+
+        Ok(Self {
+            entries: [
+                OtaSelectEntry::parse(&data[..ENTRY_LEN]),
+                OtaSelectEntry::parse(&data[OTADATA_SECTOR_SIZE..][..ENTRY_LEN]),
+            ],
+        })
+    }
+
+    /// The entry used by the bootloader to select an app, if any
+    pub fn active_entry(&self) -> Option<&OtaSelectEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_valid())
+            .max_by_key(|entry| entry.seq)
+    }
+
+    /// Index of the OTA app partition booted by the bootloader, given the
+    /// number of OTA app partitions
+    ///
+    /// Returns `None` if the factory app is booted.
+    pub fn active_slot(&self, slot_count: usize) -> Option<usize> {
+        if slot_count == 0 {
+            return None;
+        }
+
+        // The bootloader uses wrapping arithmetic, so a sequence number of 0
+        // also selects an app
+        self.active_entry().map(|entry| entry.slot(slot_count))
+    }
+
+    /// Index of the entry holding the state of the app in an OTA app
+    /// partition, given its index and the number of OTA app partitions, or of
+    /// the active entry if no partition is given
+    ///
+    /// Unlike [OtaData::active_slot], apps marked as invalid or aborted are
+    /// found as well, so that their state may be reset.
+    pub fn state_entry(&self, slot: Option<usize>, slot_count: usize) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| match slot {
+                Some(slot) => {
+                    slot_count > 0 && entry.is_written() && entry.slot(slot_count) == slot
+                }
+                None => entry.is_valid(),
+            })
+            .max_by_key(|(_, entry)| entry.seq)
+            .map(|(index, _)| index)
+    }
+
+    /// Select the OTA app partition with the given index to be booted next, in
+    /// the same way as `esp_ota_set_boot_partition`
+    ///
+    /// The sequence number is advanced past the active entry's, and written to
+    /// the other entry so that the active one remains intact should writing it
+    /// be interrupted. Returns the index of the updated entry.
+    pub fn select(&mut self, slot: usize, slot_count: usize) -> usize {
+        let active = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.is_valid())
+            .max_by_key(|(_, entry)| entry.seq);
+
+        let mut seq = active.map_or(0, |(_, entry)| entry.seq) + 1;
+        while (seq - 1) as usize % slot_count != slot {
+            seq += 1;
+        }
+
+        let index = active.map_or(0, |(index, _)| (index + 1) % 2);
+        self.entries[index] = OtaSelectEntry::new(seq);
+
+        index
+    }
+}
+
+/// Calculate a CRC32 as `esp_rom_crc32_le(UINT32_MAX, ...)` does, which is
+/// used for the OTA data and NVS pages
+pub(crate) fn esp_crc32(data: &[u8]) -> u32 {
+    esp_rom_crc32_le(u32::MAX, data)
+}
+
+/// Calculate a CRC32 as `esp_rom_crc32_le` does, starting from `crc`
+pub(crate) fn esp_rom_crc32_le(crc: u32, data: &[u8]) -> u32 {
+    let crc = data.iter().fold(!crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    });
+
+    !crc
+}
+
+/// Find the OTA data partition
+pub fn otadata_partition(partition_table: &PartitionTable) -> Result<&Partition, Error> {
+    partition_table
+        .partitions()
+        .iter()
+        .find(|p| p.ty() == Type::Data && p.subtype() == SubType::Data(DataType::Ota))
+        .ok_or_else(|| Error::PartitionNotFound("otadata".into()))
+}
+
+/// OTA app partitions, ordered by their index
+pub fn ota_partitions(partition_table: &PartitionTable) -> Vec<&Partition> {
+    let mut partitions = partition_table
+        .partitions()
+        .iter()
+        .filter_map(|p| match p.subtype() {
+            SubType::App(app_type) => ota_index(app_type).map(|index| (index, p)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    partitions.sort_by_key(|(index, _)| *index);
+
+    partitions.into_iter().map(|(_, p)| p).collect()
+}
+
+/// Index of an OTA app partition subtype, `ota_0` being 0
+fn ota_index(app_type: AppType) -> Option<u8> {
+    match app_type as u8 {
+        subtype @ 0x10..=0x1f => Some(subtype - 0x10),
+        _ => None,
+    }
+}
+
+/// Index of the OTA app partition with the given label, and the number of OTA
+/// app partitions
+pub fn ota_slot(partition_table: &PartitionTable, label: &str) -> Result<(usize, usize), Error> {
+    let partitions = ota_partitions(partition_table);
+    let slot = partitions
+        .iter()
+        .position(|p| p.name() == label)
+        .ok_or_else(|| Error::NotOtaPartition(label.to_string()))?;
+
+    Ok((slot, partitions.len()))
+}
+
+/// Select the OTA app partition which is not booted by the bootloader, so that
+/// an app may be written to it without disturbing the running one
+pub fn inactive_ota_partition<'t>(
+    partition_table: &'t PartitionTable,
+    otadata: &OtaData,
+) -> Result<&'t Partition, Error> {
+    let partitions = ota_partitions(partition_table);
+
+    // When the factory app is running, the first OTA app partition is used
+    let active = otadata.active_slot(partitions.len());
+    let slot = active.map_or(0, |active| (active + 1) % partitions.len());
+
+    match partitions.get(slot) {
+        Some(partition) if Some(slot) != active => Ok(partition),
+        _ => Err(Error::NoInactiveOtaPartition),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn otadata(entries: [(u32, u32); 2]) -> Vec<u8> {
+        let mut data = vec![0xff; OTADATA_SIZE];
+        for (i, (seq, state)) in entries.into_iter().enumerate() {
+            let entry = &mut data[i * OTADATA_SECTOR_SIZE..][..ENTRY_LEN];
+            entry[..4].copy_from_slice(&seq.to_le_bytes());
+            entry[24..28].copy_from_slice(&state.to_le_bytes());
+            entry[28..].copy_from_slice(&esp_crc32(&seq.to_le_bytes()).to_le_bytes());
+        }
+
+        data
+    }
+
+    #[test]
+    fn calculates_crc() {
+        assert_eq!(esp_crc32(&1u32.to_le_bytes()), 0x4743_989a);
+    }
+
+    #[test]
+    fn selects_active_slot() {
+        let blank = OtaData::parse(&[0xff; OTADATA_SIZE]).unwrap();
+        assert_eq!(blank.active_slot(2), None);
+
+        let data = OtaData::parse(&otadata([(1, u32::MAX), (2, u32::MAX)])).unwrap();
+        assert_eq!(data.active_slot(2), Some(1));
+        assert_eq!(data.active_slot(3), Some(1));
+
+        // Invalid apps are not booted
+        let data = OtaData::parse(&otadata([(1, 2), (2, 3)])).unwrap();
+        assert_eq!(data.active_slot(2), Some(0));
+    }
+
+    #[test]
+    fn selects_slot() {
+        let mut data = OtaData::parse(&[0xff; OTADATA_SIZE]).unwrap();
+        assert_eq!(data.select(1, 2), 0);
+        assert_eq!(data.entries[0].seq, 2);
+        assert_eq!(data.active_slot(2), Some(1));
+
+        assert_eq!(data.select(0, 2), 1);
+        assert_eq!(data.entries[1].seq, 3);
+        assert_eq!(data.active_slot(2), Some(0));
+
+        let sector = data.entries[1].to_sector();
+        assert_eq!(OtaSelectEntry::parse(&sector), data.entries[1]);
+    }
+
+    #[test]
+    fn sets_state() {
+        let mut data = OtaData::parse(&otadata([(1, 2), (2, 1)])).unwrap();
+        assert_eq!(data.state_entry(None, 2), Some(1));
+
+        // Marking the running app as invalid rolls back to the other one
+        data.entries[1].set_state(OtaImageState::Invalid);
+        assert_eq!(data.active_slot(2), Some(0));
+        assert_eq!(data.state_entry(None, 2), Some(0));
+
+        // The invalid app is still found by its partition
+        assert_eq!(data.state_entry(Some(1), 2), Some(1));
+        data.entries[1].set_state(OtaImageState::Valid);
+        assert_eq!(data.active_slot(2), Some(1));
+        assert_eq!(data.entries[1].state(), OtaImageState::Valid);
+
+        let blank = OtaData::parse(&[0xff; OTADATA_SIZE]).unwrap();
+        assert_eq!(blank.state_entry(None, 2), None);
+    }
+}
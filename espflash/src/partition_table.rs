@@ -0,0 +1,395 @@
+//! TOML representation and transformation of partition tables
+//!
+//! In addition to the CSV and binary formats used by ESP-IDF, partition tables
+//! may be written in TOML, which is easier to write and comment by hand:
+//!
+//! ```toml
+//! [[partition]]
+//! name = "nvs"
+//! type = "data"
+//! subtype = "nvs"
+//! offset = 0x9000
+//! size = "24K"
+//!
+//! [[partition]]
+//! name = "factory"
+//! type = "app"
+//! subtype = "factory"
+//! size = "1M"
+//! flags = ["encrypted"]
+//! ```
+//!
+//! Each field takes the same values as the corresponding CSV column, given as
+//! either strings or integers. As in CSV files, the offset may be omitted, in
+//! which case the partition is placed after the previous one. Reading and
+//! writing TOML partition tables requires the `toml` feature.
+
+#[cfg(feature = "toml")]
+use std::fmt;
+use std::path::Path;
+
+use esp_idf_part::{PartitionTable, Type};
+#[cfg(feature = "toml")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Alignment of the offsets and sizes of app partitions
+const APP_PARTITION_ALIGN: u32 = 0x10000;
+/// Alignment of the offsets of data partitions
+const DATA_PARTITION_ALIGN: u32 = 0x1000;
+/// Size of each entry of a binary partition table
+const ENTRY_SIZE: usize = 32;
+/// Magic value of the entry holding the MD5 checksum of the partitions
+const MD5_MAGIC: [u8; 2] = [0xeb, 0xeb];
+
+/// Is the file at the given path a TOML partition table?
+pub fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
+}
+
+/// Parse a partition table in TOML format
+#[cfg(feature = "toml")]
+pub fn from_toml(toml: &str) -> Result<PartitionTable, Error> {
+    let table: TomlPartitionTable =
+        toml::from_str(toml).map_err(|e| Error::InvalidPartitionTable(e.to_string()))?;
+
+    let mut csv = String::new();
+    for partition in table.partition {
+        let fields = [
+            Some(partition.name),
+            Some(partition.ty.to_string()),
+            Some(partition.subtype.to_string()),
+            partition.offset.map(|offset| offset.to_string()),
+            Some(partition.size.to_string()),
+            Some(partition.flags.join(":")),
+        ];
+
+        let fields = fields
+            .into_iter()
+            .map(|field| {
+                let field = field.unwrap_or_default();
+                if field.contains([',', '#', '\n', '"']) {
+                    return Err(Error::InvalidPartitionTable(format!(
+                        "`{field}` contains an invalid character"
+                    )));
+                }
+
+                Ok(field)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+
+    Ok(PartitionTable::try_from_str(&csv)?)
+}
+
+/// Convert a partition table to TOML
+#[cfg(feature = "toml")]
+pub fn to_toml(table: &PartitionTable) -> Result<String, Error> {
+    let partition = csv_rows(table)?
+        .into_iter()
+        .map(|row| {
+            // Offsets and sizes are written as strings, so that they remain in
+            // hexadecimal
+            let [name, ty, subtype, offset, size, flags] = row;
+
+            TomlPartition {
+                name,
+                ty: Field::String(ty),
+                subtype: Field::String(subtype),
+                offset: Some(Field::String(offset)),
+                size: Field::String(size),
+                flags: flags
+                    .split(':')
+                    .map(str::trim)
+                    .filter(|flag| !flag.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            }
+        })
+        .collect();
+
+    toml::to_string(&TomlPartitionTable { partition })
+        .map_err(|e| Error::InvalidPartitionTable(e.to_string()))
+}
+
+/// Convert a partition table to its binary representation, optionally leaving
+/// out the MD5 checksum of the partitions
+///
+/// The checksum follows the partitions, and is verified by bootloaders from
+/// ESP-IDF v3.1 onwards. Older bootloaders treat it as an invalid partition and
+/// fail to boot, so it must be left out of tables written for them.
+pub fn to_bin(table: &PartitionTable, md5: bool) -> Result<Vec<u8>, Error> {
+    let mut bin = table.to_bin()?;
+
+    if !md5 {
+        let start = table.partitions().len() * ENTRY_SIZE;
+        if let Some(entry) = bin.get_mut(start..start + ENTRY_SIZE) {
+            if entry.starts_with(&MD5_MAGIC) {
+                entry.fill(0xff);
+            }
+        }
+    }
+
+    Ok(bin)
+}
+
+/// Resize the app partitions of a partition table to fit an application of
+/// `app_size` bytes
+///
+/// Every app partition is given the size of the application, rounded up to the
+/// 64 KiB alignment required of app partitions. Partitions before the first
+/// app partition keep their offsets, while the following partitions are moved
+/// to directly follow the resized ones, so that any space freed by shrinking
+/// the app partitions ends up after the last partition.
+///
+/// If `flash_size` is given, an error is returned if the resized partitions no
+/// longer fit in the flash.
+pub fn fit_app_partitions(
+    table: &PartitionTable,
+    app_size: u32,
+    flash_size: Option<u32>,
+) -> Result<PartitionTable, Error> {
+    let too_large = || Error::InvalidPartitionTable("the partitions exceed 4 GiB".into());
+    let app_part_size = align_up(app_size.max(1), APP_PARTITION_ALIGN).ok_or_else(too_large)?;
+
+    let mut rows = csv_rows(table)?
+        .into_iter()
+        .map(|row| {
+            let partition = table
+                .find(&row[0])
+                .ok_or_else(|| Error::InvalidPartitionTable(format!("no partition {}", row[0])))?;
+
+            Ok((partition, row))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    rows.sort_by_key(|(partition, _)| partition.offset());
+
+    let mut csv = String::new();
+    let mut end = None::<u32>;
+
+    for (partition, [name, ty, subtype, _, _, flags]) in rows {
+        let is_app = partition.ty() == Type::App;
+        let align = if is_app {
+            APP_PARTITION_ALIGN
+        } else {
+            DATA_PARTITION_ALIGN
+        };
+
+        let offset = match end {
+            Some(end) => align_up(end, align).ok_or_else(too_large)?,
+            None => partition.offset(),
+        };
+        let size = if is_app {
+            app_part_size
+        } else {
+            partition.size()
+        };
+
+        // Only partitions following an app partition are moved
+        if is_app || end.is_some() {
+            end = Some(offset.checked_add(size).ok_or_else(too_large)?);
+        }
+
+        csv.push_str(&format!(
+            "{name},{ty},{subtype},{offset:#x},{size:#x},{flags}\n"
+        ));
+    }
+
+    let table = PartitionTable::try_from_str(&csv)?;
+
+    let end = table
+        .partitions()
+        .iter()
+        .map(|p| p.offset().saturating_add(p.size()))
+        .max()
+        .unwrap_or_default();
+    if let Some(flash_size) = flash_size.filter(|flash_size| end > *flash_size) {
+        return Err(Error::InvalidPartitionTable(format!(
+            "the resized partitions end at {end:#x}, past the end of the {}KiB flash",
+            flash_size / 1024
+        )));
+    }
+
+    Ok(table)
+}
+
+fn align_up(value: u32, align: u32) -> Option<u32> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
+
+/// The fields of each partition, as written in a CSV partition table
+///
+/// The CSV representation gives the names of the types, subtypes and flags as
+/// they are written in partition tables.
+fn csv_rows(table: &PartitionTable) -> Result<Vec<[String; 6]>, Error> {
+    let csv = table
+        .to_csv()
+        .map_err(|e| Error::InvalidPartitionTable(e.to_string()))?;
+
+    let rows = csv
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+
+            std::array::from_fn(|index| fields.get(index).copied().unwrap_or_default().to_string())
+        })
+        .collect();
+
+    Ok(rows)
+}
+
+#[cfg(feature = "toml")]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPartitionTable {
+    #[serde(default)]
+    partition: Vec<TomlPartition>,
+}
+
+#[cfg(feature = "toml")]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPartition {
+    name: String,
+    #[serde(rename = "type")]
+    ty: Field,
+    subtype: Field,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<Field>,
+    size: Field,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    flags: Vec<String>,
+}
+
+/// A field which may be given as either an integer or a string, such as a
+/// size of `0x6000` or `"24K"`
+#[cfg(feature = "toml")]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Field {
+    Integer(u64),
+    String(String),
+}
+
+#[cfg(feature = "toml")]
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Integer(value) => write!(f, "{value:#x}"),
+            Field::String(value) => f.write_str(value),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "toml"))]
+mod tests {
+    use super::*;
+
+    const PARTITIONS: &str = r#"
+# Non-volatile storage
+[[partition]]
+name = "nvs"
+type = "data"
+subtype = "nvs"
+offset = 0x9000
+size = "24K"
+
+[[partition]]
+name = "factory"
+type = "app"
+subtype = "factory"
+offset = 0x10000
+size = 0x100000
+flags = ["encrypted"]
+"#;
+
+    #[test]
+    fn parses_toml() {
+        let table = from_toml(PARTITIONS).unwrap();
+        let csv = PartitionTable::try_from_str(
+            "
+nvs,      data, nvs,     0x9000,  0x6000
+factory,  app,  factory, 0x10000, 0x100000, encrypted
+",
+        )
+        .unwrap();
+
+        assert_eq!(table.to_bin().unwrap(), csv.to_bin().unwrap());
+    }
+
+    #[test]
+    fn converts_to_toml_and_back() {
+        let table = from_toml(PARTITIONS).unwrap();
+
+        let converted = from_toml(&to_toml(&table).unwrap()).unwrap();
+        assert_eq!(converted.to_bin().unwrap(), table.to_bin().unwrap());
+    }
+
+    #[test]
+    fn leaves_out_md5() {
+        let table = from_toml(PARTITIONS).unwrap();
+
+        let with_md5 = to_bin(&table, true).unwrap();
+        assert_eq!(&with_md5[64..66], &MD5_MAGIC);
+
+        let without_md5 = to_bin(&table, false).unwrap();
+        assert_eq!(without_md5.len(), with_md5.len());
+        assert_eq!(&without_md5[..64], &with_md5[..64]);
+        assert!(without_md5[64..].iter().all(|&byte| byte == 0xff));
+
+        let parsed = PartitionTable::try_from_bytes(without_md5).unwrap();
+        assert_eq!(parsed.to_bin().unwrap(), with_md5);
+    }
+
+    #[test]
+    fn fits_app_partitions() {
+        let table = PartitionTable::try_from_str(
+            "
+nvs,      data, nvs,     0x9000,   0x6000
+otadata,  data, ota,     0xf000,   0x1000
+ota_0,    app,  ota_0,   0x10000,  0x100000
+ota_1,    app,  ota_1,   0x110000, 0x100000
+storage,  data, fat,     0x210000, 0x10000
+",
+        )
+        .unwrap();
+
+        let fitted = fit_app_partitions(&table, 0x18123, Some(0x400000)).unwrap();
+        let layout = fitted
+            .partitions()
+            .iter()
+            .map(|p| (p.name().to_string(), p.offset(), p.size()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            layout,
+            [
+                ("nvs".to_string(), 0x9000, 0x6000),
+                ("otadata".to_string(), 0xf000, 0x1000),
+                ("ota_0".to_string(), 0x10000, 0x20000),
+                ("ota_1".to_string(), 0x30000, 0x20000),
+                ("storage".to_string(), 0x50000, 0x10000),
+            ]
+        );
+
+        assert!(matches!(
+            fit_app_partitions(&table, 0x200000, Some(0x400000)),
+            Err(Error::InvalidPartitionTable(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(matches!(
+            from_toml("[[partition]]\nname = \"nvs\"\nsise = 0x6000\n"),
+            Err(Error::InvalidPartitionTable(_))
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! Client side of the serial port sharing protocol
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use serialport::ClearBuffer;
+
+use super::{Message, Role, DEFAULT_PORT, URL_SCHEME};
+use crate::{connection::transport::FlasherTransport, error::Error};
+
+/// Data received from the daemon which has not yet been read
+#[derive(Default)]
+struct Received {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Buffer shared between a [RemotePort] and its reader thread
+#[derive(Default)]
+struct Incoming {
+    received: Mutex<Received>,
+    available: Condvar,
+}
+
+/// A serial port shared by an espflash daemon
+///
+/// Data received from the daemon is buffered by a background thread, so that
+/// reads can honour the port's timeout just like a native serial port.
+pub struct RemotePort {
+    name: String,
+    stream: TcpStream,
+    incoming: Arc<Incoming>,
+    baud: u32,
+    timeout: Duration,
+    vid: u16,
+    pid: u16,
+}
+
+impl RemotePort {
+    /// Connect to the daemon at the given `espflash://<HOST>:<PORT>` URL,
+    /// presenting the given authentication token, if any
+    pub fn connect(url: &str, role: Role, token: Option<&str>) -> Result<Self, Error> {
+        let addr = url
+            .strip_prefix(URL_SCHEME)
+            .filter(|addr| !addr.is_empty())
+            .ok_or_else(|| Error::InvalidRemotePort(url.to_string()))?;
+
+        // Fall back to the default port if none was given; IPv6 addresses must
+        // be enclosed in brackets, in which case any port follows the bracket.
+        let addr = match addr.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => addr.to_string(),
+            _ => format!("{addr}:{DEFAULT_PORT}"),
+        };
+
+        debug!("Connecting to espflash daemon at {addr}");
+        let mut stream = TcpStream::connect(&addr)?;
+        stream.set_nodelay(true)?;
+
+        Message::Hello {
+            role,
+            token: token.map(String::from),
+        }
+        .write(&mut stream)?;
+
+        let (vid, pid) = match Message::read(&mut stream)? {
+            Message::Welcome { vid, pid } => (vid, pid),
+            Message::Refused(reason) => return Err(Error::RemoteRefused(reason)),
+            message => {
+                return Err(Error::RemoteRefused(format!(
+                    "unexpected reply from daemon: {message:?}"
+                )))
+            }
+        };
+
+        let incoming = Arc::new(Incoming::default());
+        let mut reader = stream.try_clone()?;
+        let shared = incoming.clone();
+        thread::spawn(move || receive(&mut reader, &shared));
+
+        Ok(Self {
+            name: url.to_string(),
+            stream,
+            incoming,
+            baud: 115_200,
+            timeout: Duration::from_secs(3),
+            vid,
+            pid,
+        })
+    }
+
+    /// USB vendor ID of the shared serial port
+    pub fn vid(&self) -> u16 {
+        self.vid
+    }
+
+    /// USB product ID of the shared serial port
+    pub fn pid(&self) -> u16 {
+        self.pid
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        message.write(&mut self.stream)
+    }
+}
+
+/// Receive messages from the daemon until the connection is closed
+fn receive(stream: &mut TcpStream, incoming: &Incoming) {
+    loop {
+        match Message::read(stream) {
+            Ok(Message::Data(data)) => {
+                incoming.received.lock().unwrap().data.extend(data);
+                incoming.available.notify_all();
+            }
+            Ok(Message::Notice(notice)) => info!("{notice}"),
+            Ok(message) => debug!("Ignoring unexpected message from daemon: {message:?}"),
+            Err(e) => {
+                debug!("Connection to espflash daemon closed: {e}");
+                incoming.received.lock().unwrap().closed = true;
+                incoming.available.notify_all();
+                return;
+            }
+        }
+    }
+}
+
+impl Read for RemotePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut received = self.incoming.received.lock().unwrap();
+
+        loop {
+            if !received.data.is_empty() {
+                let len = buf.len().min(received.data.len());
+                for (dst, src) in buf.iter_mut().zip(received.data.drain(..len)) {
+                    *dst = src;
+                }
+
+                return Ok(len);
+            }
+
+            if received.closed {
+                return Err(io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "Connection to the espflash daemon was closed",
+                ));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+
+            received = self
+                .incoming
+                .available
+                .wait_timeout(received, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Write for RemotePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Message::Data(buf.to_vec()).write(&mut self.stream)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl FlasherTransport for RemotePort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(self.baud)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.send(Message::SetBaudRate(baud_rate))?;
+        self.baud = baud_rate;
+
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        self.send(Message::SetRts(level))
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        self.send(Message::SetDtr(level))
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(self.incoming.received.lock().unwrap().data.len() as u32)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.incoming.received.lock().unwrap().data.clear();
+        self.send(Message::Clear(ClearBuffer::Input))
+    }
+}
@@ -0,0 +1,265 @@
+//! Share a serial port between multiple clients over the network
+//!
+//! The `espflash serve` daemon owns the physical serial port and exposes it
+//! via a TCP socket. Any number of monitor clients can be attached to the
+//! daemon at once and will receive everything the device writes to the serial
+//! port. A single flash client can temporarily take over the port; while it is
+//! attached it has exclusive access to the port, and once it disconnects
+//! control of the port is returned to the monitor clients.
+//!
+//! Clients and the daemon communicate using a simple framed protocol, where
+//! each frame consists of a single byte identifying the type of message, the
+//! length of the payload as a little-endian `u32`, and then the payload
+//! itself. See [Message] for the available message types.
+//!
+//! Clients connect to a daemon by passing a URL of the form
+//! `espflash://<HOST>:<PORT>` as the serial port. If the daemon was started
+//! with an authentication token, clients must present the same token in order
+//! to be accepted.
+
+use std::io::{self, Read, Write};
+
+use serialport::ClearBuffer;
+
+pub use self::client::RemotePort;
+
+mod client;
+pub mod server;
+
+/// Default TCP port the daemon listens on
+pub const DEFAULT_PORT: u16 = 7777;
+
+/// URL scheme used to refer to a serial port shared by a daemon
+pub const URL_SCHEME: &str = "espflash://";
+
+/// Does the given port name refer to a serial port shared by a daemon?
+pub fn is_remote_port(port: &str) -> bool {
+    port.starts_with(URL_SCHEME)
+}
+
+/// Maximum payload size of a single frame
+const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// Maximum payload size of the hello message, which is read before the client
+/// has authenticated
+const MAX_HELLO_LEN: usize = 1024;
+
+const HELLO: u8 = 0x01;
+const WELCOME: u8 = 0x02;
+const REFUSED: u8 = 0x03;
+const DATA: u8 = 0x04;
+const SET_BAUD_RATE: u8 = 0x05;
+const SET_DTR: u8 = 0x06;
+const SET_RTS: u8 = 0x07;
+const CLEAR: u8 = 0x08;
+const NOTICE: u8 = 0x09;
+
+/// The role a client takes when connecting to the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Receives the device's serial output while no flash client is attached
+    Monitor,
+    /// Takes exclusive control of the serial port until it disconnects
+    Flash,
+}
+
+/// A message exchanged between a client and the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Sent by a client once connected, announcing its role and presenting its
+    /// authentication token, if any
+    Hello { role: Role, token: Option<String> },
+    /// Sent by the daemon when a client has been accepted, containing the USB
+    /// vendor and product IDs of the shared serial port
+    Welcome { vid: u16, pid: u16 },
+    /// Sent by the daemon when a client has been refused, with the reason
+    Refused(String),
+    /// Data to be written to, or which was read from, the serial port
+    Data(Vec<u8>),
+    /// Change the baud rate of the serial port
+    SetBaudRate(u32),
+    /// Set the level of the DTR control line
+    SetDtr(bool),
+    /// Set the level of the RTS control line
+    SetRts(bool),
+    /// Discard the contents of the serial port's buffers
+    Clear(ClearBuffer),
+    /// Informational message from the daemon, intended for the user
+    Notice(String),
+}
+
+impl Message {
+    /// Read a single message from the given reader
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Self::read_limited(reader, MAX_PAYLOAD_LEN)
+    }
+
+    /// Read a single message from the given reader, refusing payloads larger
+    /// than `max_len` bytes before allocating any memory for them
+    fn read_limited<R: Read>(reader: &mut R, max_len: usize) -> io::Result<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+
+        let kind = header[0];
+        let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+        if len > max_len {
+            return Err(invalid_data(format!("frame of {len} bytes is too large")));
+        }
+
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+
+        let message = match kind {
+            HELLO => {
+                let role = match payload.first() {
+                    Some(0) => Role::Monitor,
+                    Some(1) => Role::Flash,
+                    _ => return Err(invalid_data("invalid client role")),
+                };
+                let token = match &payload[1..] {
+                    [] => None,
+                    token => Some(String::from_utf8_lossy(token).into_owned()),
+                };
+
+                Message::Hello { role, token }
+            }
+            WELCOME => {
+                let ids: [u8; 4] = payload
+                    .get(..4)
+                    .and_then(|ids| ids.try_into().ok())
+                    .ok_or_else(|| invalid_data("truncated welcome message"))?;
+
+                Message::Welcome {
+                    vid: u16::from_le_bytes([ids[0], ids[1]]),
+                    pid: u16::from_le_bytes([ids[2], ids[3]]),
+                }
+            }
+            REFUSED => Message::Refused(String::from_utf8_lossy(&payload).into_owned()),
+            DATA => Message::Data(payload),
+            SET_BAUD_RATE => {
+                let baud: [u8; 4] = payload
+                    .get(..4)
+                    .and_then(|baud| baud.try_into().ok())
+                    .ok_or_else(|| invalid_data("truncated baud rate"))?;
+
+                Message::SetBaudRate(u32::from_le_bytes(baud))
+            }
+            SET_DTR => Message::SetDtr(payload.first().copied().unwrap_or_default() != 0),
+            SET_RTS => Message::SetRts(payload.first().copied().unwrap_or_default() != 0),
+            CLEAR => match payload.first() {
+                Some(0) => Message::Clear(ClearBuffer::Input),
+                Some(1) => Message::Clear(ClearBuffer::Output),
+                Some(2) => Message::Clear(ClearBuffer::All),
+                _ => return Err(invalid_data("invalid buffer")),
+            },
+            NOTICE => Message::Notice(String::from_utf8_lossy(&payload).into_owned()),
+            _ => return Err(invalid_data(format!("unknown message type {kind:#04x}"))),
+        };
+
+        Ok(message)
+    }
+
+    /// Write the message to the given writer
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let (kind, payload): (u8, Vec<u8>) = match self {
+            Message::Hello { role, token } => {
+                let mut payload = vec![match role {
+                    Role::Monitor => 0,
+                    Role::Flash => 1,
+                }];
+                if let Some(token) = token {
+                    payload.extend_from_slice(token.as_bytes());
+                }
+                (HELLO, payload)
+            }
+            Message::Welcome { vid, pid } => {
+                let mut payload = vid.to_le_bytes().to_vec();
+                payload.extend_from_slice(&pid.to_le_bytes());
+                (WELCOME, payload)
+            }
+            Message::Refused(reason) => (REFUSED, reason.as_bytes().to_vec()),
+            Message::Data(data) => (DATA, data.clone()),
+            Message::SetBaudRate(baud) => (SET_BAUD_RATE, baud.to_le_bytes().to_vec()),
+            Message::SetDtr(level) => (SET_DTR, vec![u8::from(*level)]),
+            Message::SetRts(level) => (SET_RTS, vec![u8::from(*level)]),
+            Message::Clear(buffer) => (
+                CLEAR,
+                vec![match buffer {
+                    ClearBuffer::Input => 0,
+                    ClearBuffer::Output => 1,
+                    ClearBuffer::All => 2,
+                }],
+            ),
+            Message::Notice(notice) => (NOTICE, notice.as_bytes().to_vec()),
+        };
+
+        let mut frame = Vec::with_capacity(5 + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            Message::Hello {
+                role: Role::Flash,
+                token: None,
+            },
+            Message::Hello {
+                role: Role::Monitor,
+                token: Some(String::from("secret")),
+            },
+            Message::Welcome {
+                vid: 0x303a,
+                pid: 0x1001,
+            },
+            Message::Refused(String::from("busy")),
+            Message::Data(vec![0xc0, 0x00, 0x08, 0xc0]),
+            Message::SetBaudRate(921_600),
+            Message::SetDtr(true),
+            Message::SetRts(false),
+            Message::Clear(ClearBuffer::Input),
+            Message::Notice(String::from("flash client attached")),
+        ];
+
+        for message in messages {
+            let mut buffer = Vec::new();
+            message.write(&mut buffer).unwrap();
+
+            let decoded = Message::read(&mut buffer.as_slice()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut frame = vec![DATA];
+        frame.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(Message::read(&mut frame.as_slice()).is_err());
+
+        let mut frame = Vec::new();
+        Message::Hello {
+            role: Role::Flash,
+            token: Some("x".repeat(MAX_HELLO_LEN)),
+        }
+        .write(&mut frame)
+        .unwrap();
+
+        assert!(Message::read(&mut frame.as_slice()).is_ok());
+        assert!(Message::read_limited(&mut frame.as_slice(), MAX_HELLO_LEN).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+//! The serial port sharing daemon
+//!
+//! The daemon owns the serial port, and spawns one thread which continuously
+//! reads from the port and forwards the data to the attached clients, along
+//! with one thread per connected client which handles the messages sent by
+//! that client.
+//!
+//! The number of connections is capped, and clients must say hello soon after
+//! connecting. Clients which stop reading the data sent to them are
+//! disconnected, rather than holding up the others.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serialport::SerialPort;
+
+use super::{Message, Role, MAX_HELLO_LEN};
+use crate::error::Error;
+
+/// Timeout used when reading from the serial port, which bounds how long it
+/// takes the reader thread to notice changes in the attached clients
+const SERIAL_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Time a client has to send its hello message once connected
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time a write to a client may take before the client is disconnected, which
+/// bounds how long a client that stops reading holds up the others
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum number of connections served at once, including those of clients
+/// which have not authenticated yet
+const MAX_CONNECTIONS: usize = 32;
+
+/// A client attached to the daemon
+///
+/// Each client's stream has its own lock, so that it can be written to without
+/// holding the lock on the shared state, and so that concurrent messages are
+/// not interleaved.
+#[derive(Clone)]
+struct Client {
+    id: usize,
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Client {
+    /// Send a message to the client
+    ///
+    /// A client which cannot be written to, such as one which has stopped
+    /// reading, is disconnected, so that its handler releases it as well.
+    fn send(&self, message: &Message) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let result = message.write(&mut *stream);
+        if result.is_err() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+
+        result
+    }
+}
+
+/// State shared between the daemon's threads
+#[derive(Default)]
+struct Clients {
+    /// The flash client currently holding the port, if any
+    flash: Option<Client>,
+    /// All attached monitor clients
+    monitors: Vec<Client>,
+    /// The ID to assign to the next client which connects
+    next_id: usize,
+}
+
+impl Clients {
+    /// The clients serial output is forwarded to: the flash client if one is
+    /// attached, or all monitor clients otherwise
+    fn output_clients(&self) -> Vec<Client> {
+        match &self.flash {
+            Some(flash) => vec![flash.clone()],
+            None => self.monitors.clone(),
+        }
+    }
+}
+
+/// Send a message to the given clients, removing the monitor clients which
+/// could not be written to
+///
+/// The lock on the shared state is not held while writing, so that a slow
+/// client does not hold up the other threads.
+fn broadcast(clients: &Mutex<Clients>, recipients: Vec<Client>, message: &Message) {
+    let failed = recipients
+        .into_iter()
+        .filter(|client| client.send(message).is_err())
+        .map(|client| client.id)
+        .collect::<Vec<_>>();
+
+    // A flash client which could not be written to has been disconnected; its
+    // handler will notice this and release the port.
+    if !failed.is_empty() {
+        clients
+            .lock()
+            .unwrap()
+            .monitors
+            .retain(|client| !failed.contains(&client.id));
+    }
+}
+
+/// Serial port sharing daemon
+pub struct Server {
+    serial: Box<dyn SerialPort>,
+    baud: u32,
+    vid: u16,
+    pid: u16,
+    token: Option<String>,
+    clients: Arc<Mutex<Clients>>,
+}
+
+impl Server {
+    /// Create a new daemon for the given serial port
+    ///
+    /// Whenever a flash client releases the port, its baud rate is restored to
+    /// `baud`. The USB vendor and product IDs of the port are passed on to the
+    /// clients, as they determine how the device is reset.
+    pub fn new(serial: Box<dyn SerialPort>, baud: u32, vid: u16, pid: u16) -> Self {
+        Self {
+            serial,
+            baud,
+            vid,
+            pid,
+            token: None,
+            clients: Arc::new(Mutex::new(Clients::default())),
+        }
+    }
+
+    /// Require clients to present the given authentication token
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Listen for clients on the given address, serving them until an error
+    /// occurs
+    pub fn run(self, addr: SocketAddr) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Listening on {}", listener.local_addr()?);
+
+        if self.token.is_none() && !addr.ip().is_loopback() {
+            warn!("No authentication token was provided, any client on the network may connect");
+        }
+
+        let mut reader = self.serial.try_clone()?;
+        reader.set_timeout(SERIAL_READ_TIMEOUT)?;
+
+        let clients = self.clients.clone();
+        thread::spawn(move || forward_serial_output(reader.as_mut(), &clients));
+
+        let serial = Arc::new(Mutex::new(self.serial));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept client: {e}");
+                    continue;
+                }
+            };
+
+            if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                warn!("Refusing client, as {MAX_CONNECTIONS} clients are already connected");
+                continue;
+            }
+            let connection = ConnectionSlot::take(&connections);
+
+            let handler = ClientHandler {
+                serial: serial.clone(),
+                clients: self.clients.clone(),
+                baud: self.baud,
+                vid: self.vid,
+                pid: self.pid,
+                token: self.token.clone(),
+            };
+
+            thread::spawn(move || {
+                let _connection = connection;
+                let peer = stream
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_default();
+
+                if let Err(e) = handler.handle(stream) {
+                    debug!("Client {peer} disconnected: {e}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// A connection counted towards [MAX_CONNECTIONS], until it is dropped
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Self(connections.clone())
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Continuously read from the serial port, forwarding the data to the flash
+/// client if one is attached, or to all monitor clients otherwise
+fn forward_serial_output(serial: &mut dyn SerialPort, clients: &Mutex<Clients>) {
+    let mut buffer = [0u8; 1024];
+
+    loop {
+        let len = match serial.read(&mut buffer) {
+            Ok(0) => continue,
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Failed to read from serial port: {e}");
+                return;
+            }
+        };
+
+        let message = Message::Data(buffer[..len].to_vec());
+        let recipients = clients.lock().unwrap().output_clients();
+
+        broadcast(clients, recipients, &message);
+    }
+}
+
+/// Handles the messages sent by a single client
+struct ClientHandler {
+    serial: Arc<Mutex<Box<dyn SerialPort>>>,
+    clients: Arc<Mutex<Clients>>,
+    baud: u32,
+    vid: u16,
+    pid: u16,
+    token: Option<String>,
+}
+
+impl ClientHandler {
+    fn handle(&self, mut stream: TcpStream) -> Result<(), Error> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))?;
+
+        // Only a small frame is accepted until the client has authenticated, so
+        // that unauthenticated clients cannot make the daemon allocate much
+        let role = match Message::read_limited(&mut stream, MAX_HELLO_LEN)? {
+            Message::Hello { token, .. } if !self.is_authorized(token.as_deref()) => {
+                warn!("Refusing client with invalid authentication token");
+                Message::Refused("invalid authentication token".into()).write(&mut stream)?;
+                return Ok(());
+            }
+            Message::Hello { role, .. } => role,
+            _ => {
+                Message::Refused("expected a hello message".into()).write(&mut stream)?;
+                return Ok(());
+            }
+        };
+
+        // Authenticated clients may take as long as they like between messages
+        stream.set_read_timeout(None)?;
+
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+
+        // Hold the client's stream until the welcome message has been sent, so
+        // that no serial output can be sent to it before then.
+        let mut welcome_writer = writer.lock().unwrap();
+
+        let registered = {
+            let mut clients = self.clients.lock().unwrap();
+
+            if role == Role::Flash && clients.flash.is_some() {
+                None
+            } else {
+                let id = clients.next_id;
+                clients.next_id += 1;
+
+                let client = Client {
+                    id,
+                    stream: writer.clone(),
+                };
+
+                let monitors = match role {
+                    Role::Monitor => {
+                        clients.monitors.push(client);
+                        Vec::new()
+                    }
+                    Role::Flash => {
+                        clients.flash = Some(client);
+                        clients.monitors.clone()
+                    }
+                };
+
+                Some((id, monitors))
+            }
+        };
+
+        let Some((id, monitors)) = registered else {
+            Message::Refused("another flash client is attached".into()).write(&mut stream)?;
+            return Ok(());
+        };
+
+        let welcome = Message::Welcome {
+            vid: self.vid,
+            pid: self.pid,
+        }
+        .write(&mut *welcome_writer);
+        drop(welcome_writer);
+
+        broadcast(
+            &self.clients,
+            monitors,
+            &Message::Notice("flash client attached, monitor output paused".into()),
+        );
+
+        info!("{role:?} client {id} attached");
+
+        let result = welcome
+            .map_err(Error::from)
+            .and_then(|()| self.serve(&mut stream, role));
+
+        let monitors = {
+            let mut clients = self.clients.lock().unwrap();
+            match role {
+                Role::Monitor => {
+                    clients.monitors.retain(|client| client.id != id);
+                    Vec::new()
+                }
+                Role::Flash => {
+                    clients.flash = None;
+
+                    // The flash client may have changed the baud rate, so restore
+                    // it before handing the port back to the monitor clients.
+                    let mut serial = self.serial.lock().unwrap();
+                    if let Err(e) = serial.set_baud_rate(self.baud) {
+                        warn!("Failed to restore baud rate: {e}");
+                    }
+                    serial.write_data_terminal_ready(false).ok();
+                    serial.write_request_to_send(false).ok();
+
+                    clients.monitors.clone()
+                }
+            }
+        };
+
+        broadcast(
+            &self.clients,
+            monitors,
+            &Message::Notice("flash client detached, monitor output resumed".into()),
+        );
+
+        info!("{role:?} client {id} detached");
+
+        result
+    }
+
+    /// Does the client's token match the one required by the daemon, if any?
+    fn is_authorized(&self, token: Option<&str>) -> bool {
+        match (&self.token, token) {
+            (None, _) => true,
+            (Some(expected), Some(token)) => {
+                constant_time_eq(expected.as_bytes(), token.as_bytes())
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Process the client's messages until it disconnects
+    fn serve(&self, stream: &mut TcpStream, role: Role) -> Result<(), Error> {
+        loop {
+            let message = match Message::read(stream) {
+                Ok(message) => message,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+
+            // Monitor clients may only access the port while no flash client is
+            // attached.
+            if role == Role::Monitor && self.clients.lock().unwrap().flash.is_some() {
+                continue;
+            }
+
+            let mut serial = self.serial.lock().unwrap();
+            match message {
+                Message::Data(data) => {
+                    serial.write_all(&data)?;
+                    serial.flush()?;
+                }
+                Message::SetBaudRate(baud) => serial.set_baud_rate(baud)?,
+                Message::SetDtr(level) => serial.write_data_terminal_ready(level)?,
+                Message::SetRts(level) => serial.write_request_to_send(level)?,
+                Message::Clear(buffer) => serial.clear(buffer)?,
+                message => debug!("Ignoring unexpected message from client: {message:?}"),
+            }
+        }
+    }
+}
+
+/// Compare two byte strings in constant time, so that the time taken does not
+/// reveal how much of a guessed authentication token is correct
+///
+/// Only the length of the token may be revealed.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -1,13 +1,18 @@
 use std::ops::Range;
 
 #[cfg(feature = "serialport")]
-use crate::{connection::Connection, targets::bytes_to_mac_addr};
+use crate::{
+    connection::Connection,
+    targets::{bytes_to_mac_addr, PsramBus, PsramInfo},
+};
 use crate::{
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    flasher::{FlashData, FlashFrequency, FlashVoltage},
+    image_format::ImageFormat,
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x00f0_1d83];
@@ -25,6 +30,12 @@ const UART_CLKDIV_MASK: u32 = 0xfffff;
 #[cfg(feature = "serialport")]
 const XTAL_CLK_DIVIDER: u32 = 1;
 
+const RTC_CNTL_SDIO_CONF_REG: u32 = 0x3ff4_8074;
+const RTC_CNTL_XPD_SDIO_REG: u32 = 1 << 31;
+const RTC_CNTL_SDIO_TIEH: u32 = 1 << 23;
+const RTC_CNTL_SDIO_FORCE: u32 = 1 << 22;
+const RTC_CNTL_SDIO_PD_EN: u32 = 1 << 21;
+
 /// ESP32 Target
 pub struct Esp32;
 
@@ -91,7 +102,7 @@ impl Target for Esp32 {
         if [2, 4, 5, 6].contains(&pkg_version) {
             features.push("Embedded Flash");
         }
-        if pkg_version == 6 {
+        if [6, 7].contains(&pkg_version) {
             features.push("Embedded PSRAM");
         }
 
@@ -116,6 +127,15 @@ impl Target for Esp32 {
         Ok(features)
     }
 
+    #[cfg(feature = "serialport")]
+    fn psram_info(&self, connection: &mut Connection) -> Result<Option<PsramInfo>, Error> {
+        // Only the ESP32-PICO-V3-02 and ESP32-D0WDR2-V3 embed PSRAM
+        Ok(match self.package_version(connection)? {
+            6 | 7 => Some(PsramInfo::new(2, PsramBus::Quad)),
+            _ => None,
+        })
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         let apb_ctl_date = connection.read_reg(0x3FF6_607C)?;
@@ -158,7 +178,7 @@ impl Target for Esp32 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let booloader: &'static [u8] = match xtal_freq {
             XtalFrequency::_40Mhz => {
                 include_bytes!("../../resources/bootloaders/esp32-bootloader.bin")
@@ -183,17 +203,7 @@ impl Target for Esp32 {
             booloader,
         );
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32,
-            flash_data.min_chip_rev,
-            params,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32, params, image, flash_data)
     }
 
     #[cfg(feature = "serialport")]
@@ -208,6 +218,17 @@ impl Target for Esp32 {
         Ok(bytes_to_mac_addr(bytes))
     }
 
+    fn flash_voltage_override(&self, voltage: FlashVoltage) -> Option<(u32, u32)> {
+        // Override the eFuse settings and enable the regulator; the voltage is
+        // 3.3V if TIEH is set, and 1.8V otherwise
+        let value = RTC_CNTL_SDIO_FORCE | RTC_CNTL_SDIO_PD_EN | RTC_CNTL_XPD_SDIO_REG;
+        match voltage {
+            FlashVoltage::Keep => None,
+            FlashVoltage::_1_8V => Some((RTC_CNTL_SDIO_CONF_REG, value)),
+            FlashVoltage::_3_3V => Some((RTC_CNTL_SDIO_CONF_REG, value | RTC_CNTL_SDIO_TIEH)),
+        }
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x3ff4_2000,
@@ -8,8 +8,11 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::{ImageFormat, ImageFormatKind},
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
@@ -94,7 +97,7 @@ impl Target for Esp32c2 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         let booloader: &'static [u8] = match xtal_freq {
             XtalFrequency::_40Mhz => {
                 debug!("Using 40MHz bootloader");
@@ -121,17 +124,7 @@ impl Target for Esp32c2 {
             booloader,
         );
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32c2,
-            flash_data.min_chip_rev,
-            params,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32c2, params, image, flash_data)
     }
 
     #[cfg(feature = "serialport")]
@@ -147,6 +140,15 @@ impl Target for Esp32c2 {
         Ok(bytes_to_mac_addr(bytes))
     }
 
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x6000_8000,
+            config0_offset: 0x84,
+            config1_offset: 0x88,
+            write_protect_offset: 0x9C,
+        })
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x6000_2000,
@@ -159,6 +161,16 @@ impl Target for Esp32c2 {
         }
     }
 
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[
+            ImageFormatKind::EspBootloader,
+            ImageFormatKind::DirectBoot,
+            ImageFormatKind::Mcuboot,
+            ImageFormatKind::Raw,
+            ImageFormatKind::SimpleBoot,
+        ]
+    }
+
     fn supported_build_targets(&self) -> &[&str] {
         &[
             "riscv32imac-unknown-none-elf",
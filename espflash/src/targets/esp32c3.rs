@@ -1,13 +1,16 @@
 use std::ops::Range;
 
 #[cfg(feature = "serialport")]
-use crate::connection::Connection;
+use crate::{connection::Connection, targets::EmbeddedFlashInfo};
 use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::{ImageFormat, ImageFormatKind},
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[
@@ -57,6 +60,28 @@ impl Target for Esp32c3 {
         Ok(vec!["WiFi", "BLE"])
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash_info(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<Option<EmbeddedFlashInfo>, Error> {
+        let blk1_word3 = self.read_efuse(connection, 20)?;
+        let blk1_word4 = self.read_efuse(connection, 21)?;
+
+        let size_mb = match (blk1_word3 >> 27) & 0x7 {
+            0 => return Ok(None),
+            1 => Some(4),
+            2 => Some(2),
+            3 => Some(1),
+            4 => Some(8),
+            _ => None,
+        };
+
+        Ok(Some(
+            EmbeddedFlashInfo::new(size_mb).with_fields((blk1_word3 >> 30) & 0x3, blk1_word4 & 0x7),
+        ))
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 22)? >> 24 & 0x3)
@@ -82,7 +107,7 @@ impl Target for Esp32c3 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32c3,
@@ -90,17 +115,16 @@ impl Target for Esp32c3 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32c3,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32c3, PARAMS, image, flash_data)
+    }
+
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x6000_8000,
+            config0_offset: 0x90,
+            config1_offset: 0x94,
+            write_protect_offset: 0xA8,
+        })
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -115,6 +139,16 @@ impl Target for Esp32c3 {
         }
     }
 
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[
+            ImageFormatKind::EspBootloader,
+            ImageFormatKind::DirectBoot,
+            ImageFormatKind::Mcuboot,
+            ImageFormatKind::Raw,
+            ImageFormatKind::SimpleBoot,
+        ]
+    }
+
     fn supported_build_targets(&self) -> &[&str] {
         &[
             "riscv32imac-unknown-none-elf",
@@ -6,8 +6,11 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::{ImageFormat, ImageFormatKind},
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0xD7B7_3E80];
@@ -84,7 +87,7 @@ impl Target for Esp32h2 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         if xtal_freq != XtalFrequency::_32Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32h2,
@@ -92,17 +95,16 @@ impl Target for Esp32h2 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32h2,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32h2, PARAMS, image, flash_data)
+    }
+
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x600B_1C00,
+            config0_offset: 0x0,
+            config1_offset: 0x4,
+            write_protect_offset: 0x18,
+        })
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -117,6 +119,16 @@ impl Target for Esp32h2 {
         }
     }
 
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[
+            ImageFormatKind::EspBootloader,
+            ImageFormatKind::DirectBoot,
+            ImageFormatKind::Mcuboot,
+            ImageFormatKind::Raw,
+            ImageFormatKind::SimpleBoot,
+        ]
+    }
+
     fn supported_build_targets(&self) -> &[&str] {
         &["riscv32imac-esp-espidf", "riscv32imac-unknown-none-elf"]
     }
@@ -1,4 +1,4 @@
-use std::ops::Range;
+use std::{collections::HashMap, ops::Range};
 
 #[cfg(feature = "serialport")]
 use crate::connection::Connection;
@@ -6,11 +6,14 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::{ImageFormat, ImageFormatKind},
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
-const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x0];
+const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x0, 0x0ADD_BAD0];
 
 const FLASH_RANGES: &[Range<u32>] = &[
     0x4000_0000..0x4C00_0000, // IROM
@@ -20,7 +23,7 @@ const FLASH_RANGES: &[Range<u32>] = &[
 const PARAMS: Esp32Params = Esp32Params::new(
     0x2000,
     0x1_0000,
-    0x3f_0000, // TODO: Update
+    0x3f_0000,
     18,
     FlashFrequency::_40Mhz,
     include_bytes!("../../resources/bootloaders/esp32p4-bootloader.bin"),
@@ -30,6 +33,7 @@ const PARAMS: Esp32Params = Esp32Params::new(
 pub struct Esp32p4;
 
 impl Esp32p4 {
+    /// Check if the magic value contains the specified value
     pub fn has_magic_value(value: u32) -> bool {
         CHIP_DETECT_MAGIC_VALUES.contains(&value)
     }
@@ -52,15 +56,15 @@ impl Target for Esp32p4 {
     }
 
     #[cfg(feature = "serialport")]
-    fn major_chip_version(&self, _connection: &mut Connection) -> Result<u32, Error> {
-        // TODO: https://github.com/espressif/esptool/blob/master/esptool/targets/esp32p4.py#L96
-        Ok(0)
+    fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+        let word19 = self.read_efuse(connection, 19)?;
+
+        Ok((((word19 >> 23) & 0x1) << 2) | ((word19 >> 4) & 0x3))
     }
 
     #[cfg(feature = "serialport")]
-    fn minor_chip_version(&self, _connection: &mut Connection) -> Result<u32, Error> {
-        // TODO: https://github.com/espressif/esptool/blob/master/esptool/targets/esp32p4.py#L92
-        Ok(0)
+    fn minor_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+        Ok(self.read_efuse(connection, 19)? & 0xf)
     }
 
     #[cfg(feature = "serialport")]
@@ -69,13 +73,21 @@ impl Target for Esp32p4 {
         Ok(XtalFrequency::_40Mhz)
     }
 
+    fn flash_frequency_encodings(&self) -> HashMap<FlashFrequency, u8> {
+        use FlashFrequency::*;
+
+        let encodings = [(_20Mhz, 0x2), (_40Mhz, 0x0), (_80Mhz, 0xF)];
+
+        HashMap::from(encodings)
+    }
+
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32p4,
@@ -83,17 +95,16 @@ impl Target for Esp32p4 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32p4,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32p4, PARAMS, image, flash_data)
+    }
+
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x5011_6000,
+            config0_offset: 0x0,
+            config1_offset: 0x4,
+            write_protect_offset: 0x18,
+        })
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -108,6 +119,10 @@ impl Target for Esp32p4 {
         }
     }
 
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[ImageFormatKind::EspBootloader, ImageFormatKind::Raw]
+    }
+
     fn supported_build_targets(&self) -> &[&str] {
         &["riscv32imafc-esp-espidf", "riscv32imafc-unknown-none-elf"]
     }
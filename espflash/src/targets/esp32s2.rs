@@ -1,13 +1,20 @@
 use std::ops::Range;
 
 #[cfg(feature = "serialport")]
-use crate::{connection::Connection, flasher::FLASH_WRITE_SIZE, targets::MAX_RAM_BLOCK_SIZE};
+use crate::{
+    connection::Connection,
+    flasher::FLASH_WRITE_SIZE,
+    targets::{EmbeddedFlashInfo, PsramBus, PsramInfo, MAX_RAM_BLOCK_SIZE},
+};
 use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::ImageFormat,
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x0000_07c6];
@@ -117,6 +124,29 @@ impl Target for Esp32s2 {
         Ok(features)
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash_info(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<Option<EmbeddedFlashInfo>, Error> {
+        Ok(match self.get_flash_version(connection)? {
+            0 => None,
+            1 => Some(EmbeddedFlashInfo::new(Some(2))),
+            2 => Some(EmbeddedFlashInfo::new(Some(4))),
+            _ => Some(EmbeddedFlashInfo::new(None)),
+        })
+    }
+
+    #[cfg(feature = "serialport")]
+    fn psram_info(&self, connection: &mut Connection) -> Result<Option<PsramInfo>, Error> {
+        Ok(match self.get_psram_version(connection)? {
+            0 => None,
+            1 => Some(PsramInfo::new(2, PsramBus::Quad)),
+            2 => Some(PsramInfo::new(4, PsramBus::Quad)),
+            _ => Some(PsramInfo::unknown()),
+        })
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 20)? >> 18 & 0x3)
@@ -151,7 +181,7 @@ impl Target for Esp32s2 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32s2,
@@ -159,17 +189,7 @@ impl Target for Esp32s2 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32s2,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32s2, PARAMS, image, flash_data)
     }
 
     #[cfg(feature = "serialport")]
@@ -181,6 +201,15 @@ impl Target for Esp32s2 {
         })
     }
 
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x3F40_8000,
+            config0_offset: 0x94,
+            config1_offset: 0x98,
+            write_protect_offset: 0xAC,
+        })
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x3f40_2000,
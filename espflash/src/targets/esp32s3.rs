@@ -1,13 +1,19 @@
 use std::ops::Range;
 
 #[cfg(feature = "serialport")]
-use crate::connection::Connection;
+use crate::{
+    connection::Connection,
+    targets::{EmbeddedFlashInfo, PsramBus, PsramInfo},
+};
 use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
+    image_format::ImageFormat,
+    targets::{
+        build_flash_image, Chip, Esp32Params, ReadEFuse, RtcWdtRegisters, SpiRegisters, Target,
+        XtalFrequency,
+    },
 };
 
 const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0x9];
@@ -64,6 +70,50 @@ impl Target for Esp32s3 {
         Ok(vec!["WiFi", "BLE"])
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash_info(
+        &self,
+        connection: &mut Connection,
+    ) -> Result<Option<EmbeddedFlashInfo>, Error> {
+        let blk1_word3 = self.read_efuse(connection, 20)?;
+        let blk1_word4 = self.read_efuse(connection, 21)?;
+
+        let size_mb = match (blk1_word3 >> 27) & 0x7 {
+            0 => return Ok(None),
+            1 => Some(8),
+            2 => Some(4),
+            _ => None,
+        };
+
+        Ok(Some(
+            EmbeddedFlashInfo::new(size_mb).with_fields((blk1_word3 >> 30) & 0x3, blk1_word4 & 0x7),
+        ))
+    }
+
+    #[cfg(feature = "serialport")]
+    fn psram_info(&self, connection: &mut Connection) -> Result<Option<PsramInfo>, Error> {
+        let blk1_word4 = self.read_efuse(connection, 21)?;
+        let psram_cap = ((blk1_word4 >> 3) & 0x3) | (((blk1_word4 >> 19) & 0x1) << 2);
+
+        // The larger capacities are only available as octal PSRAM
+        let mut psram = match psram_cap {
+            0 => return Ok(None),
+            1 => PsramInfo::new(8, PsramBus::Octal),
+            2 => PsramInfo::new(2, PsramBus::Quad),
+            3 => PsramInfo::new(16, PsramBus::Octal),
+            4 => PsramInfo::new(4, PsramBus::Quad),
+            _ => PsramInfo::unknown(),
+        };
+
+        psram.vendor = match (blk1_word4 >> 7) & 0x3 {
+            1 => Some("AP_3v3"),
+            2 => Some("AP_1v8"),
+            _ => None,
+        };
+
+        Ok(Some(psram))
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         let major = self.read_efuse(connection, 22)? >> 24 & 0x3;
@@ -101,7 +151,7 @@ impl Target for Esp32s3 {
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32s3,
@@ -109,17 +159,16 @@ impl Target for Esp32s3 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32s3,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        build_flash_image(Chip::Esp32s3, PARAMS, image, flash_data)
+    }
+
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        Some(RtcWdtRegisters {
+            base: 0x6000_8000,
+            config0_offset: 0x98,
+            config1_offset: 0x9C,
+            write_protect_offset: 0xB0,
+        })
     }
 
     fn spi_registers(&self) -> SpiRegisters {
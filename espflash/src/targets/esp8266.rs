@@ -0,0 +1,146 @@
+use std::ops::Range;
+
+#[cfg(feature = "serialport")]
+use crate::{connection::Connection, targets::bytes_to_mac_addr};
+use crate::{
+    elf::FirmwareImage,
+    error::Error,
+    flasher::FlashData,
+    image_format::{Esp8266Format, ImageFormat, ImageFormatKind},
+    targets::{ReadEFuse, SpiRegisters, Target, XtalFrequency},
+};
+
+const CHIP_DETECT_MAGIC_VALUES: &[u32] = &[0xfff0_c101];
+
+const FLASH_RANGES: &[Range<u32>] = &[
+    0x4020_0000..0x4030_0000, // IROM
+];
+
+// UART0_BASE_REG + 0x14
+#[cfg(feature = "serialport")]
+const UART_CLKDIV_REG: u32 = 0x6000_0014;
+#[cfg(feature = "serialport")]
+const UART_CLKDIV_MASK: u32 = 0xfffff;
+#[cfg(feature = "serialport")]
+const XTAL_CLK_DIVIDER: u32 = 2;
+
+/// ESP8266 Target
+pub struct Esp8266;
+
+impl Esp8266 {
+    /// Check if the magic value contains the specified value
+    pub fn has_magic_value(value: u32) -> bool {
+        CHIP_DETECT_MAGIC_VALUES.contains(&value)
+    }
+}
+
+impl ReadEFuse for Esp8266 {
+    fn efuse_reg(&self) -> u32 {
+        0x3ff0_0050
+    }
+}
+
+impl Target for Esp8266 {
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    }
+
+    #[cfg(feature = "serialport")]
+    fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
+        let word0 = self.read_efuse(connection, 0)?;
+        let word2 = self.read_efuse(connection, 2)?;
+
+        let mut features = vec!["WiFi"];
+
+        // The ESP8285 is an ESP8266 with embedded flash
+        if (word0 & (1 << 4)) != 0 || (word2 & (1 << 16)) != 0 {
+            features.push("Embedded Flash");
+        }
+
+        Ok(features)
+    }
+
+    #[cfg(feature = "serialport")]
+    fn major_chip_version(&self, _connection: &mut Connection) -> Result<u32, Error> {
+        Ok(0)
+    }
+
+    #[cfg(feature = "serialport")]
+    fn minor_chip_version(&self, _connection: &mut Connection) -> Result<u32, Error> {
+        Ok(0)
+    }
+
+    #[cfg(feature = "serialport")]
+    fn crystal_freq(&self, connection: &mut Connection) -> Result<XtalFrequency, Error> {
+        let uart_div = connection.read_reg(UART_CLKDIV_REG)? & UART_CLKDIV_MASK;
+        let est_xtal = (connection.get_baud()? * uart_div) / 1_000_000 / XTAL_CLK_DIVIDER;
+        let norm_xtal = if est_xtal > 33 {
+            XtalFrequency::_40Mhz
+        } else {
+            XtalFrequency::_26Mhz
+        };
+
+        Ok(norm_xtal)
+    }
+
+    fn get_flash_image<'a>(
+        &self,
+        image: &'a dyn FirmwareImage<'a>,
+        flash_data: FlashData,
+        _chip_revision: Option<(u32, u32)>,
+        _xtal_freq: XtalFrequency,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        Ok(Box::new(Esp8266Format::new(
+            image,
+            flash_data.flash_settings,
+            flash_data.bootloader,
+        )?))
+    }
+
+    #[cfg(feature = "serialport")]
+    fn mac_address(&self, connection: &mut Connection) -> Result<String, Error> {
+        let word0 = self.read_efuse(connection, 0)?;
+        let word1 = self.read_efuse(connection, 1)?;
+        let word3 = self.read_efuse(connection, 3)?;
+
+        // The OUI is either stored in eFuse, or selected by a flag
+        let oui = if word3 != 0 {
+            [(word3 >> 16) as u8, (word3 >> 8) as u8, word3 as u8]
+        } else if (word1 >> 16) & 0xff == 0 {
+            [0x18, 0xfe, 0x34]
+        } else {
+            [0xac, 0xd0, 0x74]
+        };
+
+        let bytes = [
+            oui[0],
+            oui[1],
+            oui[2],
+            (word1 >> 8) as u8,
+            word1 as u8,
+            (word0 >> 24) as u8,
+        ];
+
+        Ok(bytes_to_mac_addr(&bytes))
+    }
+
+    fn spi_registers(&self) -> SpiRegisters {
+        SpiRegisters {
+            base: 0x6000_0200,
+            usr_offset: 0x1c,
+            usr1_offset: 0x20,
+            usr2_offset: 0x24,
+            w0_offset: 0x40,
+            mosi_length_offset: None,
+            miso_length_offset: None,
+        }
+    }
+
+    fn supported_build_targets(&self) -> &[&str] {
+        &["xtensa-esp8266-none-elf"]
+    }
+
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[ImageFormatKind::EspBootloader]
+    }
+}
@@ -1,4 +1,13 @@
 use std::io::Write;
+#[cfg(feature = "serialport")]
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+};
 
 use flate2::{
     write::{ZlibDecoder, ZlibEncoder},
@@ -11,16 +20,25 @@ use md5::{Digest, Md5};
 use crate::{
     command::{Command, CommandType},
     connection::{Connection, USB_SERIAL_JTAG_PID},
-    flasher::ProgressCallbacks,
+    flasher::{ProgressCallbacks, ProgressEvent},
     targets::FlashTarget,
 };
 use crate::{
     elf::RomSegment,
     error::Error,
-    flasher::{SpiAttachParams, FLASH_SECTOR_SIZE},
+    flasher::{SpiAttachParams, FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE},
     targets::Chip,
 };
 
+/// Number of blocks prepared ahead of the one being written
+#[cfg(feature = "serialport")]
+const PIPELINE_DEPTH: usize = 4;
+
+/// Size of the pieces in which a segment is compressed, between which
+/// compression may be cancelled
+#[cfg(feature = "serialport")]
+const COMPRESS_CHUNK_SIZE: usize = 0x10000;
+
 /// Applications running from an ESP32's (or variant's) flash
 pub struct Esp32Target {
     chip: Chip,
@@ -28,6 +46,7 @@ pub struct Esp32Target {
     use_stub: bool,
     verify: bool,
     skip: bool,
+    compress: bool,
     need_deflate_end: bool,
 }
 
@@ -38,6 +57,7 @@ impl Esp32Target {
         use_stub: bool,
         verify: bool,
         skip: bool,
+        compress: bool,
     ) -> Self {
         Esp32Target {
             chip,
@@ -45,14 +65,455 @@ impl Esp32Target {
             use_stub,
             verify,
             skip,
+            compress,
             need_deflate_end: false,
         }
     }
+
+    #[cfg(feature = "serialport")]
+    /// Write a segment, unless it is skipped, using the blocks prepared by
+    /// [prepare_blocks], or without compressing it if none are provided
+    fn write_prepared(
+        &mut self,
+        connection: &mut Connection,
+        segment: &RomSegment,
+        flash_write_size: usize,
+        blocks: Option<Receiver<io::Result<Prepared>>>,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+
+        if self.skip {
+            let checksum_md5 = Md5::digest(&segment.data);
+            let flash_checksum_md5: u128 = connection.with_timeout(
+                CommandType::FlashMd5.timeout_for_size(segment.data.len() as u32),
+                |connection| {
+                    connection
+                        .command(crate::command::Command::FlashMd5 {
+                            offset: addr,
+                            size: segment.data.len() as u32,
+                        })?
+                        .try_into()
+                },
+            )?;
+
+            if checksum_md5.as_slice() == flash_checksum_md5.to_be_bytes() {
+                info!(
+                    "Segment at address '0x{:x}' has not changed, skipping write",
+                    addr
+                );
+                if let Some(cb) = progress.as_mut() {
+                    cb.event(&ProgressEvent::SegmentSkipped { addr });
+                }
+                return Ok(());
+            }
+        }
+
+        let Some(blocks) = blocks else {
+            return self.write_uncompressed(connection, segment, progress);
+        };
+
+        let block_count = if self.use_stub {
+            // The stub only uses the number of blocks to tell whether more
+            // compressed data is to follow, so an upper bound lets the write
+            // start before the whole segment is compressed. See
+            // `handle_flash_deflated_begin` and `handle_flash_deflated_data` in
+            // esptool's `flasher_stub/stub_write_flash.c`.
+            (max_compressed_len(segment.data.len()) + flash_write_size - 1) / flash_write_size
+        } else {
+            // The ROM loader expects exactly the number of blocks it is sent, which
+            // is only known once the whole segment is compressed
+            let Prepared::Compressed(compressed_len) = next_prepared(&blocks)? else {
+                return Err(Error::InternalError);
+            };
+            (compressed_len + flash_write_size - 1) / flash_write_size
+        };
+        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+
+        // round up to sector size
+        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+
+        connection.with_timeout(
+            CommandType::FlashDeflBegin.timeout_for_size(erase_size),
+            |connection| {
+                connection.command(Command::FlashDeflBegin {
+                    size: segment.data.len() as u32,
+                    blocks: block_count as u32,
+                    block_size: flash_write_size as u32,
+                    offset: addr,
+                    supports_encryption: !self.chip.is_esp32() && !self.use_stub,
+                })?;
+                Ok(())
+            },
+        )?;
+        self.need_deflate_end = true;
+
+        // Progress is reported in blocks of uncompressed data, as the number of
+        // compressed blocks may not be known yet
+        let total = (segment.data.len() + flash_write_size - 1) / flash_write_size;
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, total)
+        }
+
+        let mut written = 0;
+        let mut current = 0;
+
+        for sequence in 0.. {
+            connection.check_cancelled()?;
+
+            let (block, size) = match next_prepared(&blocks)? {
+                Prepared::Block(block, size) => (block, size),
+                Prepared::Done => break,
+                Prepared::Compressed(_) => return Err(Error::InternalError),
+            };
+
+            connection.with_timeout(
+                CommandType::FlashDeflData.timeout_for_size(size as u32),
+                |connection| {
+                    connection.command(Command::FlashDeflData {
+                        sequence,
+                        pad_to: 0,
+                        pad_byte: 0xff,
+                        data: &block,
+                    })?;
+                    Ok(())
+                },
+            )?;
+
+            written += size;
+
+            let blocks_written = (written + flash_write_size - 1) / flash_write_size;
+            if blocks_written > current {
+                current = blocks_written;
+                if let Some(cb) = progress.as_mut() {
+                    cb.update(current);
+                    cb.event(&ProgressEvent::BlockWritten {
+                        addr,
+                        current,
+                        total,
+                    });
+                }
+            }
+        }
+
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
+        }
+
+        if self.verify {
+            verify_region(
+                connection,
+                addr,
+                &segment.data,
+                0..segment.data.len(),
+                progress,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "serialport")]
+    /// Write a segment without compressing it, as required by the ESP8266 ROM
+    /// loader or requested by the user
+    fn write_uncompressed(
+        &mut self,
+        connection: &mut Connection,
+        segment: &RomSegment,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let addr = segment.addr;
+
+        let target = self.chip.into_target();
+        let flash_write_size = target.flash_write_size(connection)?;
+        let block_count = (segment.data.len() + flash_write_size - 1) / flash_write_size;
+        let rom_esp8266 = self.chip.is_esp8266() && !self.use_stub;
+        let erase_size = if rom_esp8266 {
+            esp8266_erase_size(addr, segment.data.len() as u32)
+        } else {
+            let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+            (erase_count * FLASH_SECTOR_SIZE) as u32
+        };
+
+        connection.with_timeout(
+            CommandType::FlashBegin.timeout_for_size(erase_size),
+            |connection| {
+                connection.command(Command::FlashBegin {
+                    size: erase_size,
+                    blocks: block_count as u32,
+                    block_size: flash_write_size as u32,
+                    offset: addr,
+                    supports_encryption: !self.chip.is_esp32()
+                        && !self.chip.is_esp8266()
+                        && !self.use_stub,
+                })?;
+                Ok(())
+            },
+        )?;
+
+        let chunks = segment.data.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, num_chunks)
+        }
+
+        for (i, block) in chunks.enumerate() {
+            connection.check_cancelled()?;
+
+            connection.with_timeout(
+                CommandType::FlashData.timeout_for_size(block.len() as u32),
+                |connection| {
+                    connection.command(Command::FlashData {
+                        sequence: i as u32,
+                        pad_to: flash_write_size,
+                        pad_byte: 0xff,
+                        data: block,
+                    })?;
+                    Ok(())
+                },
+            )?;
+
+            if let Some(cb) = progress.as_mut() {
+                cb.update(i + 1);
+                cb.event(&ProgressEvent::BlockWritten {
+                    addr,
+                    current: i + 1,
+                    total: num_chunks,
+                });
+            }
+        }
+
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
+        }
+
+        if self.verify && !rom_esp8266 {
+            verify_region(
+                connection,
+                addr,
+                &segment.data,
+                0..segment.data.len(),
+                progress,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check that `range` of a segment's `data`, written at `addr`, matches the
+/// contents of the flash
+#[cfg(feature = "serialport")]
+fn verify_region(
+    connection: &mut Connection,
+    addr: u32,
+    data: &[u8],
+    range: std::ops::Range<usize>,
+    progress: &mut Option<&mut dyn ProgressCallbacks>,
+) -> Result<(), Error> {
+    let checksum_md5 = Md5::digest(&data[range.clone()]);
+    let size = range.len() as u32;
+
+    if let Some(cb) = progress.as_mut() {
+        cb.event(&ProgressEvent::VerifyStarted {
+            addr: addr + range.start as u32,
+            len: range.len(),
+        });
+    }
+
+    let flash_checksum_md5: u128 =
+        connection.with_timeout(CommandType::FlashMd5.timeout_for_size(size), |connection| {
+            connection
+                .command(Command::FlashMd5 {
+                    offset: addr + range.start as u32,
+                    size,
+                })?
+                .try_into()
+        })?;
+
+    if checksum_md5.as_slice() != flash_checksum_md5.to_be_bytes() {
+        return Err(Error::VerifyFailed);
+    }
+
+    Ok(())
+}
+
+/// Data prepared in the background for writing a compressed segment
+#[cfg(feature = "serialport")]
+enum Prepared {
+    /// The whole segment was compressed to this many bytes
+    Compressed(usize),
+    /// The next compressed block, and the size of the data it decompresses to
+    Block(Vec<u8>, usize),
+    /// All of the blocks have been sent
+    Done,
+}
+
+/// Upper bound of the size of `len` bytes once compressed, as given by
+/// miniz's `mz_deflateBound`
+#[cfg(feature = "serialport")]
+fn max_compressed_len(len: usize) -> usize {
+    (128 + len * 110 / 100).max(128 + len + (len / (31 * 1024) + 1) * 5)
+}
+
+/// Compress a segment and split it into blocks of `block_size` bytes, sending
+/// them as they are prepared until the receiver is dropped
+///
+/// When `stream` is set, each block is sent as soon as enough compressed data
+/// is available. Otherwise the whole segment is compressed first, and its
+/// compressed size is sent ahead of the blocks. Compression stops early once
+/// `cancelled` is set.
+#[cfg(feature = "serialport")]
+fn prepare_blocks(
+    data: &[u8],
+    block_size: usize,
+    stream: bool,
+    cancelled: &AtomicBool,
+    sender: SyncSender<io::Result<Prepared>>,
+) {
+    if let Err(err) = try_prepare_blocks(data, block_size, stream, cancelled, &sender) {
+        let _ = sender.send(Err(err));
+    }
+}
+
+#[cfg(feature = "serialport")]
+fn try_prepare_blocks(
+    data: &[u8],
+    block_size: usize,
+    stream: bool,
+    cancelled: &AtomicBool,
+    sender: &SyncSender<io::Result<Prepared>>,
+) -> io::Result<()> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    // Decode the blocks to see how much data the device will have to save
+    let mut decoder = ZlibDecoder::new(Vec::new());
+
+    for chunk in data.chunks(COMPRESS_CHUNK_SIZE) {
+        if cancelled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        encoder.write_all(chunk)?;
+
+        // The encoder only ever appends to its output, so the complete blocks
+        // at its start can be taken away while compression continues
+        if stream && !send_blocks(encoder.get_mut(), block_size, &mut decoder, sender)? {
+            return Ok(());
+        }
+    }
+    let mut compressed = encoder.finish()?;
+
+    if !stream
+        && sender
+            .send(Ok(Prepared::Compressed(compressed.len())))
+            .is_err()
+    {
+        return Ok(());
+    }
+
+    if !send_blocks(&mut compressed, block_size, &mut decoder, sender)? {
+        return Ok(());
+    }
+    if !compressed.is_empty() {
+        let size = decoded_len(&compressed, &mut decoder)?;
+        if sender.send(Ok(Prepared::Block(compressed, size))).is_err() {
+            return Ok(());
+        }
+    }
+
+    let _ = sender.send(Ok(Prepared::Done));
+
+    Ok(())
+}
+
+/// Send each complete block at the start of `compressed`, removing them from
+/// it, and return whether the receiver is still listening
+#[cfg(feature = "serialport")]
+fn send_blocks(
+    compressed: &mut Vec<u8>,
+    block_size: usize,
+    decoder: &mut ZlibDecoder<Vec<u8>>,
+    sender: &SyncSender<io::Result<Prepared>>,
+) -> io::Result<bool> {
+    let complete = compressed.len() / block_size * block_size;
+    let complete = compressed.drain(..complete).collect::<Vec<_>>();
+
+    for block in complete.chunks(block_size) {
+        let size = decoded_len(block, decoder)?;
+        if sender
+            .send(Ok(Prepared::Block(block.to_vec(), size)))
+            .is_err()
+        {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Size of the data the next compressed `block` decompresses to
+#[cfg(feature = "serialport")]
+fn decoded_len(block: &[u8], decoder: &mut ZlibDecoder<Vec<u8>>) -> io::Result<usize> {
+    decoder.write_all(block)?;
+    decoder.flush()?;
+    let size = decoder.get_ref().len();
+    decoder.get_mut().clear();
+
+    Ok(size)
+}
+
+/// Receive the next data prepared by [prepare_blocks]
+#[cfg(feature = "serialport")]
+fn next_prepared(blocks: &Receiver<io::Result<Prepared>>) -> Result<Prepared, Error> {
+    // The sender is only dropped early if preparing the blocks panicked
+    blocks
+        .recv()
+        .map_err(|_| Error::InternalError)?
+        .map_err(Error::from)
+}
+
+/// Size to request be erased when writing `size` bytes at `offset` using the
+/// ESP8266 ROM loader
+///
+/// The ROM loader erases more sectors than it is asked to, so the size is
+/// adjusted to compensate. Ported from `esptool.py`.
+#[cfg(feature = "serialport")]
+fn esp8266_erase_size(offset: u32, size: u32) -> u32 {
+    const SECTORS_PER_BLOCK: u32 = 16;
+    const SECTOR_SIZE: u32 = FLASH_SECTOR_SIZE as u32;
+
+    let num_sectors = (size + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let start_sector = offset / SECTOR_SIZE;
+    let head_sectors = (SECTORS_PER_BLOCK - (start_sector % SECTORS_PER_BLOCK)).min(num_sectors);
+
+    if num_sectors < 2 * head_sectors {
+        (num_sectors + 1) / 2 * SECTOR_SIZE
+    } else {
+        (num_sectors - head_sectors) * SECTOR_SIZE
+    }
 }
 
 #[cfg(feature = "serialport")]
 impl FlashTarget for Esp32Target {
+    // Some chips are matched only when their support is enabled
+    #[allow(unreachable_patterns)]
     fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
+        if self.chip.is_esp8266() && !self.use_stub {
+            // The ESP8266 ROM loader has no command to attach the flash, however it is
+            // attached when flashing begins
+            connection.with_timeout(CommandType::FlashBegin.timeout(), |connection| {
+                connection.command(Command::FlashBegin {
+                    size: 0,
+                    blocks: 0,
+                    block_size: FLASH_WRITE_SIZE as u32,
+                    offset: 0,
+                    supports_encryption: false,
+                })
+            })?;
+
+            return Ok(());
+        }
+
         connection.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
             let command = if self.use_stub {
                 Command::SpiAttachStub {
@@ -74,6 +535,7 @@ impl FlashTarget for Esp32Target {
         //       explicitly disable the watchdog here.
         if connection.get_usb_pid()? == USB_SERIAL_JTAG_PID {
             match self.chip {
+                #[cfg(feature = "esp32c3")]
                 Chip::Esp32c3 => {
                     connection.command(Command::WriteReg {
                         address: 0x6000_80a8,
@@ -91,6 +553,7 @@ impl FlashTarget for Esp32Target {
                         mask: None,
                     })?; // WP enable
                 }
+                #[cfg(feature = "esp32s3")]
                 Chip::Esp32s3 => {
                     connection.command(Command::WriteReg {
                         address: 0x6000_80B0,
@@ -108,6 +571,7 @@ impl FlashTarget for Esp32Target {
                         mask: None,
                     })?; // WP enable
                 }
+                #[cfg(feature = "esp32c6")]
                 Chip::Esp32c6 => {
                     connection.command(Command::WriteReg {
                         address: 0x600B_1C18,
@@ -125,6 +589,24 @@ impl FlashTarget for Esp32Target {
                         mask: None,
                     })?; // WP enable
                 }
+                #[cfg(feature = "esp32p4")]
+                Chip::Esp32p4 => {
+                    connection.command(Command::WriteReg {
+                        address: 0x5011_6018,
+                        value: 0x50D8_3AA1,
+                        mask: None,
+                    })?; // WP disable
+                    connection.command(Command::WriteReg {
+                        address: 0x5011_6000,
+                        value: 0x0,
+                        mask: None,
+                    })?; // turn off RTC WDT
+                    connection.command(Command::WriteReg {
+                        address: 0x5011_6018,
+                        value: 0x0,
+                        mask: None,
+                    })?; // WP enable
+                }
                 _ => {}
             }
         }
@@ -138,128 +620,131 @@ impl FlashTarget for Esp32Target {
         segment: RomSegment,
         progress: &mut Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        let addr = segment.addr;
+        let target = self.chip.into_target();
+        let flash_write_size = target.flash_write_size(connection)?;
+        // The ESP8266 ROM loader is unable to decompress data, nor verify it once written
+        let compress = self.compress && !(self.chip.is_esp8266() && !self.use_stub);
 
-        let mut md5_hasher = Md5::new();
-        md5_hasher.update(&segment.data);
-        let checksum_md5 = md5_hasher.finalize();
+        // Only the stub can start writing before the whole segment is compressed
+        let stream = self.use_stub;
 
-        if self.skip {
-            let flash_checksum_md5: u128 =
-                connection.with_timeout(CommandType::FlashMd5.timeout(), |connection| {
-                    connection
-                        .command(crate::command::Command::FlashMd5 {
-                            offset: addr,
-                            size: segment.data.len() as u32,
-                        })?
-                        .try_into()
-                })?;
-
-            if checksum_md5.as_slice() == flash_checksum_md5.to_be_bytes() {
-                info!(
-                    "Segment at address '0x{:x}' has not changed, skipping write",
-                    addr
-                );
-                return Ok(());
+        let cancelled = AtomicBool::new(false);
+        thread::scope(|scope| {
+            // Compress the segment in the background while it is compared with
+            // the flash, and prepare each block while the previous ones are
+            // being written
+            let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+            if compress {
+                scope.spawn(|| {
+                    prepare_blocks(&segment.data, flash_write_size, stream, &cancelled, sender)
+                });
             }
-        }
 
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&segment.data)?;
-        let compressed = encoder.finish()?;
-
-        let target = self.chip.into_target();
-        let flash_write_size = target.flash_write_size(connection)?;
-        let block_count = (compressed.len() + flash_write_size - 1) / flash_write_size;
-        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+            let result = self.write_prepared(
+                connection,
+                &segment,
+                flash_write_size,
+                compress.then_some(receiver),
+                progress,
+            );
 
-        // round up to sector size
-        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+            // Stop compressing if the segment was skipped, or writing it failed
+            cancelled.store(true, Ordering::Relaxed);
 
-        connection.with_timeout(
-            CommandType::FlashDeflBegin.timeout_for_size(erase_size),
-            |connection| {
-                connection.command(Command::FlashDeflBegin {
-                    size: segment.data.len() as u32,
-                    blocks: block_count as u32,
-                    block_size: flash_write_size as u32,
-                    offset: addr,
-                    supports_encryption: self.chip != Chip::Esp32 && !self.use_stub,
-                })?;
-                Ok(())
-            },
-        )?;
-        self.need_deflate_end = true;
+            result
+        })
+    }
 
-        let chunks = compressed.chunks(flash_write_size);
-        let num_chunks = chunks.len();
+    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
+        if self.need_deflate_end {
+            connection.with_timeout(CommandType::FlashDeflEnd.timeout(), |connection| {
+                connection.command(Command::FlashDeflEnd { reboot: false })
+            })?;
+        }
 
-        if let Some(cb) = progress.as_mut() {
-            cb.init(addr, num_chunks)
+        if reboot {
+            connection.reset_after(self.use_stub, self.chip)?;
         }
 
-        // decode the chunks to see how much data the device will have to save
-        let mut decoder = ZlibDecoder::new(Vec::new());
-        let mut decoded_size = 0;
+        Ok(())
+    }
+}
 
-        for (i, block) in chunks.enumerate() {
-            decoder.write_all(block)?;
-            decoder.flush()?;
-            let size = decoder.get_ref().len() - decoded_size;
-            decoded_size = decoder.get_ref().len();
+#[cfg(all(test, feature = "serialport"))]
+mod tests {
+    use std::time::Instant;
 
-            connection.with_timeout(
-                CommandType::FlashDeflData.timeout_for_size(size as u32),
-                |connection| {
-                    connection.command(Command::FlashDeflData {
-                        sequence: i as u32,
-                        pad_to: 0,
-                        pad_byte: 0xff,
-                        data: block,
-                    })?;
-                    Ok(())
-                },
-            )?;
+    use super::*;
 
-            if let Some(cb) = progress.as_mut() {
-                cb.update(i + 1)
-            }
-        }
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
 
-        if let Some(cb) = progress.as_mut() {
-            cb.finish()
-        }
+    /// Collect the blocks prepared for `data`, stopping once the first one has
+    /// been received if `cancel_after_first` is set
+    fn prepare(data: &[u8], stream: bool, cancel_after_first: bool) -> (Vec<u8>, Instant) {
+        let cancelled = AtomicBool::new(false);
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut first_block = None;
+        let mut compressed = Vec::new();
 
-        if self.verify {
-            let flash_checksum_md5: u128 =
-                connection.with_timeout(CommandType::FlashMd5.timeout(), |connection| {
-                    connection
-                        .command(crate::command::Command::FlashMd5 {
-                            offset: addr,
-                            size: segment.data.len() as u32,
-                        })?
-                        .try_into()
-                })?;
+        thread::scope(|scope| {
+            scope.spawn(|| prepare_blocks(data, FLASH_WRITE_SIZE, stream, &cancelled, sender));
 
-            if checksum_md5.as_slice() != flash_checksum_md5.to_be_bytes() {
-                return Err(Error::VerifyFailed);
+            while let Ok(prepared) = receiver.recv() {
+                match prepared.unwrap() {
+                    Prepared::Compressed(len) => assert!(!stream && len > 0),
+                    Prepared::Block(block, _) => {
+                        first_block.get_or_insert_with(Instant::now);
+                        compressed.extend(block);
+                        if cancel_after_first {
+                            cancelled.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    Prepared::Done => break,
+                }
             }
-        }
+        });
 
-        Ok(())
+        (compressed, first_block.unwrap())
     }
 
-    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
-        if self.need_deflate_end {
-            connection.with_timeout(CommandType::FlashDeflEnd.timeout(), |connection| {
-                connection.command(Command::FlashDeflEnd { reboot: false })
-            })?;
-        }
+    #[test]
+    fn streams_blocks_while_compressing() {
+        let data = random_data(0x100000);
 
-        if reboot {
-            connection.reset_after(self.use_stub)?;
+        for stream in [true, false] {
+            let (compressed, _) = prepare(&data, stream, false);
+            let mut decoder = ZlibDecoder::new(Vec::new());
+            decoder.write_all(&compressed).unwrap();
+            assert_eq!(decoder.finish().unwrap(), data);
+            assert!(compressed.len() <= max_compressed_len(data.len()));
         }
 
-        Ok(())
+        // Streamed blocks are available once the first chunk is compressed, so
+        // cancelling then leaves most of the segment uncompressed
+        let (compressed, _) = prepare(&data, true, true);
+        assert!(compressed.len() < data.len() / 2);
+        let (compressed, _) = prepare(&data, false, true);
+        assert!(compressed.len() >= data.len());
+
+        let start = Instant::now();
+        let (_, streamed) = prepare(&data, true, false);
+        let streamed = streamed - start;
+        let start = Instant::now();
+        let (_, buffered) = prepare(&data, false, false);
+        let buffered = buffered - start;
+        assert!(
+            streamed * 4 < buffered,
+            "first block after {streamed:?} when streamed, {buffered:?} otherwise"
+        );
     }
 }
@@ -1,3 +1,5 @@
+use serde::Serialize;
+
 pub(crate) use self::ram::MAX_RAM_BLOCK_SIZE;
 pub use self::{esp32::Esp32Target, ram::RamTarget};
 use crate::{connection::Connection, elf::RomSegment, error::Error};
@@ -13,6 +15,46 @@ pub trait ProgressCallbacks {
     fn update(&mut self, current: usize);
     /// Finish some progress report
     fn finish(&mut self);
+    /// Called with each segment to be written to flash, before it is either
+    /// written or skipped because it is unchanged
+    fn segment(&mut self, _addr: u32, _data: &[u8]) {}
+    /// Called with a structured event for each step of an operation, in
+    /// addition to the other callbacks
+    fn event(&mut self, _event: &ProgressEvent) {}
+}
+
+/// A step of loading an image, for rendering progress or reporting it in a
+/// machine-readable form
+///
+/// Events are serialized as JSON objects whose `event` field gives the kind of
+/// event, such as `{"event":"block_written","addr":65536,"current":1,"total":4}`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// Writing the segment of `len` bytes at `addr` started
+    SegmentStarted { addr: u32, len: usize },
+    /// The segment at `addr` was not written, as the flash already holds it
+    SegmentSkipped { addr: u32 },
+    /// `current` out of the `total` blocks of the segment at `addr` were
+    /// written
+    BlockWritten {
+        addr: u32,
+        current: usize,
+        total: usize,
+    },
+    /// Checking the `len` bytes written at `addr` against the flash started
+    VerifyStarted { addr: u32, len: usize },
+    /// Writing the segment at `addr` failed, and is resumed from `resume_addr`
+    Retrying {
+        addr: u32,
+        attempt: u32,
+        resume_addr: u32,
+    },
+    /// Writing the segment at `addr` finished
+    SegmentFinished { addr: u32 },
+    /// Every segment was written
+    Finished,
 }
 
 /// Operations for interacting with a flash target
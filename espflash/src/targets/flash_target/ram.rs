@@ -2,7 +2,7 @@
 use crate::{
     command::{Command, CommandType},
     connection::Connection,
-    flasher::ProgressCallbacks,
+    flasher::{ProgressCallbacks, ProgressEvent},
     targets::FlashTarget,
 };
 use crate::{elf::RomSegment, error::Error};
@@ -68,7 +68,12 @@ impl FlashTarget for RamTarget {
             })?;
 
             if let Some(cb) = progress.as_mut() {
-                cb.update(i + 1)
+                cb.update(i + 1);
+                cb.event(&ProgressEvent::BlockWritten {
+                    addr,
+                    current: i + 1,
+                    total: num_chunks,
+                });
             }
         }
 
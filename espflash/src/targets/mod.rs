@@ -1,23 +1,48 @@
 //! Flashable target devices
 //!
-//! All ESP32 devices support booting via the ESP-IDF bootloader. It's also
-//! possible to write an application to and boot from RAM, where a bootloader is
-//! obviously not required either.
+//! All ESP32 devices support booting via the ESP-IDF bootloader, while the
+//! ESP8266 is booted directly by its ROM bootloader or by the bootloader from
+//! its SDK. It's also possible to write an application to and boot from RAM,
+//! where a bootloader is obviously not required either.
 
 use std::collections::HashMap;
 
 use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
+use log::info;
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumIter, EnumString, VariantNames};
 
+#[cfg(feature = "flash-encryption")]
+use crate::image_format::FlashEncrypted;
+#[cfg(feature = "secure-boot")]
+use crate::image_format::{
+    ExternalSigner, SecureBootKey, SecureBootPublicKey, SecureBootSigned, SecureBootSigner,
+};
+#[cfg(feature = "esp32")]
+use crate::targets::esp32::Esp32;
+#[cfg(feature = "esp32c2")]
+use crate::targets::esp32c2::Esp32c2;
+#[cfg(feature = "esp32c3")]
+use crate::targets::esp32c3::Esp32c3;
+#[cfg(feature = "esp32c6")]
+use crate::targets::esp32c6::Esp32c6;
+#[cfg(feature = "esp32h2")]
+use crate::targets::esp32h2::Esp32h2;
+#[cfg(feature = "esp32p4")]
+use crate::targets::esp32p4::Esp32p4;
+#[cfg(feature = "esp32s2")]
+use crate::targets::esp32s2::Esp32s2;
+#[cfg(feature = "esp32s3")]
+use crate::targets::esp32s3::Esp32s3;
+#[cfg(feature = "esp8266")]
+use crate::targets::esp8266::Esp8266;
 use crate::{
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{
-        esp32::Esp32, esp32c2::Esp32c2, esp32c3::Esp32c3, esp32c6::Esp32c6, esp32h2::Esp32h2,
-        esp32p4::Esp32p4, esp32s2::Esp32s2, esp32s3::Esp32s3,
+    flasher::{FlashData, FlashFrequency, FlashVoltage},
+    image_format::{
+        partition_usage, DirectBootFormat, IdfBootloaderFormat, ImageFormat, ImageFormatKind,
+        McubootFormat, RawFormat, SimpleBootFormat, WithPartitionFiles,
     },
 };
 
@@ -34,14 +59,24 @@ use crate::{
 /// Max partition size is 16 MB
 const MAX_PARTITION_SIZE: u32 = 16 * 1000 * 1024;
 
+#[cfg(feature = "esp32")]
 mod esp32;
+#[cfg(feature = "esp32c2")]
 mod esp32c2;
+#[cfg(feature = "esp32c3")]
 mod esp32c3;
+#[cfg(feature = "esp32c6")]
 mod esp32c6;
+#[cfg(feature = "esp32h2")]
 mod esp32h2;
+#[cfg(feature = "esp32p4")]
 mod esp32p4;
+#[cfg(feature = "esp32s2")]
 mod esp32s2;
+#[cfg(feature = "esp32s3")]
 mod esp32s3;
+#[cfg(feature = "esp8266")]
+mod esp8266;
 
 #[cfg(feature = "serialport")]
 pub(crate) mod flash_target;
@@ -71,75 +106,383 @@ pub enum XtalFrequency {
 impl XtalFrequency {
     pub fn default(chip: Chip) -> Self {
         match chip {
+            #[cfg(feature = "esp32")]
             Chip::Esp32 => Self::_40Mhz,
+            #[cfg(feature = "esp32c2")]
             Chip::Esp32c2 => Self::_40Mhz,
+            #[cfg(feature = "esp32c3")]
             Chip::Esp32c3 => Self::_40Mhz,
+            #[cfg(feature = "esp32c6")]
             Chip::Esp32c6 => Self::_40Mhz,
+            #[cfg(feature = "esp32h2")]
             Chip::Esp32h2 => Self::_32Mhz,
+            #[cfg(feature = "esp32p4")]
             Chip::Esp32p4 => Self::_40Mhz,
+            #[cfg(feature = "esp32s2")]
             Chip::Esp32s2 => Self::_40Mhz,
+            #[cfg(feature = "esp32s3")]
             Chip::Esp32s3 => Self::_40Mhz,
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => Self::_26Mhz,
+        }
+    }
+}
+
+/// Data bus of PSRAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PsramBus {
+    /// Quad SPI
+    Quad,
+    /// Octal SPI
+    Octal,
+}
+
+/// PSRAM embedded in a chip's package, as recorded in its eFuses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct PsramInfo {
+    /// Size in bytes, if known
+    pub size: Option<u32>,
+    /// Data bus, if known
+    pub bus: Option<PsramBus>,
+    /// Vendor and supply voltage, if recorded
+    pub vendor: Option<&'static str>,
+}
+
+impl PsramInfo {
+    pub(crate) fn new(size_mb: u32, bus: PsramBus) -> Self {
+        Self {
+            size: Some(size_mb * 1024 * 1024),
+            bus: Some(bus),
+            vendor: None,
+        }
+    }
+
+    /// PSRAM which is present, but whose size is not recognized
+    pub(crate) fn unknown() -> Self {
+        Self {
+            size: None,
+            bus: None,
+            vendor: None,
+        }
+    }
+}
+
+impl std::fmt::Display for PsramInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{}MB", size / 1024 / 1024)?,
+            None => write!(f, "Unknown size")?,
+        }
+        if let Some(bus) = self.bus {
+            write!(f, ", {bus}")?;
+        }
+        if let Some(vendor) = self.vendor {
+            write!(f, ", {vendor}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Flash embedded in a chip's package, as recorded in its eFuses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct EmbeddedFlashInfo {
+    /// Size in bytes, if known
+    pub size: Option<u32>,
+    /// Vendor, if recorded
+    pub vendor: Option<&'static str>,
+    /// Maximum operating temperature in degrees Celsius, if recorded
+    pub max_temperature: Option<u32>,
+}
+
+impl EmbeddedFlashInfo {
+    pub(crate) fn new(size_mb: Option<u32>) -> Self {
+        Self {
+            size: size_mb.map(|size_mb| size_mb * 1024 * 1024),
+            vendor: None,
+            max_temperature: None,
         }
     }
+
+    /// Decode the `FLASH_TEMP` and `FLASH_VENDOR` eFuse fields, which the
+    /// ESP32-C3 and ESP32-S3 share
+    pub(crate) fn with_fields(mut self, temperature: u32, vendor: u32) -> Self {
+        self.max_temperature = match temperature {
+            1 => Some(105),
+            2 => Some(85),
+            _ => None,
+        };
+        self.vendor = match vendor {
+            1 => Some("XMC"),
+            2 => Some("GD"),
+            3 => Some("FM"),
+            4 => Some("TT"),
+            5 => Some("ZBIT"),
+            _ => None,
+        };
+
+        self
+    }
+}
+
+impl std::fmt::Display for EmbeddedFlashInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{}MB", size / 1024 / 1024)?,
+            None => write!(f, "Unknown size")?,
+        }
+        if let Some(vendor) = self.vendor {
+            write!(f, ", {vendor}")?;
+        }
+        if let Some(temperature) = self.max_temperature {
+            write!(f, ", up to {temperature}°C")?;
+        }
+
+        Ok(())
+    }
 }
 
 /// All supported devices
+///
+/// Only the devices whose features are enabled are available, which by
+/// default is all of them.
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames)]
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Display,
+    EnumIter,
+    EnumString,
+    VariantNames,
+    Serialize,
+    Deserialize,
+)]
 #[non_exhaustive]
 #[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum Chip {
     /// ESP32
+    #[cfg(feature = "esp32")]
     Esp32,
     /// ESP32-C2, ESP8684
+    #[cfg(feature = "esp32c2")]
     Esp32c2,
     /// ESP32-C3, ESP8685
+    #[cfg(feature = "esp32c3")]
     Esp32c3,
     /// ESP32-C6
+    #[cfg(feature = "esp32c6")]
     Esp32c6,
     /// ESP32-H2
+    #[cfg(feature = "esp32h2")]
     Esp32h2,
     /// ESP32-P4
+    #[cfg(feature = "esp32p4")]
     Esp32p4,
     /// ESP32-S2
+    #[cfg(feature = "esp32s2")]
     Esp32s2,
     /// ESP32-S3
+    #[cfg(feature = "esp32s3")]
     Esp32s3,
+    /// ESP8266, ESP8285
+    #[cfg(feature = "esp8266")]
+    Esp8266,
 }
 
 impl Chip {
     pub fn from_magic(magic: u32) -> Result<Self, Error> {
-        if Esp32::has_magic_value(magic) {
-            Ok(Chip::Esp32)
-        } else if Esp32c2::has_magic_value(magic) {
-            Ok(Chip::Esp32c2)
-        } else if Esp32c3::has_magic_value(magic) {
-            Ok(Chip::Esp32c3)
-        } else if Esp32c6::has_magic_value(magic) {
-            Ok(Chip::Esp32c6)
-        } else if Esp32h2::has_magic_value(magic) {
-            Ok(Chip::Esp32h2)
-        } else if Esp32p4::has_magic_value(magic) {
-            Ok(Chip::Esp32p4)
-        } else if Esp32s2::has_magic_value(magic) {
-            Ok(Chip::Esp32s2)
-        } else if Esp32s3::has_magic_value(magic) {
-            Ok(Chip::Esp32s3)
-        } else {
-            Err(Error::ChipDetectError(magic))
+        #[cfg(feature = "esp32")]
+        {
+            if Esp32::has_magic_value(magic) {
+                return Ok(Chip::Esp32);
+            }
+        }
+        #[cfg(feature = "esp32c2")]
+        {
+            if Esp32c2::has_magic_value(magic) {
+                return Ok(Chip::Esp32c2);
+            }
         }
+        #[cfg(feature = "esp32c3")]
+        {
+            if Esp32c3::has_magic_value(magic) {
+                return Ok(Chip::Esp32c3);
+            }
+        }
+        #[cfg(feature = "esp32c6")]
+        {
+            if Esp32c6::has_magic_value(magic) {
+                return Ok(Chip::Esp32c6);
+            }
+        }
+        #[cfg(feature = "esp32h2")]
+        {
+            if Esp32h2::has_magic_value(magic) {
+                return Ok(Chip::Esp32h2);
+            }
+        }
+        #[cfg(feature = "esp32p4")]
+        {
+            if Esp32p4::has_magic_value(magic) {
+                return Ok(Chip::Esp32p4);
+            }
+        }
+        #[cfg(feature = "esp32s2")]
+        {
+            if Esp32s2::has_magic_value(magic) {
+                return Ok(Chip::Esp32s2);
+            }
+        }
+        #[cfg(feature = "esp32s3")]
+        {
+            if Esp32s3::has_magic_value(magic) {
+                return Ok(Chip::Esp32s3);
+            }
+        }
+        #[cfg(feature = "esp8266")]
+        {
+            if Esp8266::has_magic_value(magic) {
+                return Ok(Chip::Esp8266);
+            }
+        }
+
+        Err(Error::ChipDetectError(magic))
     }
 
     pub fn into_target(&self) -> Box<dyn Target> {
         match self {
+            #[cfg(feature = "esp32")]
             Chip::Esp32 => Box::new(Esp32),
+            #[cfg(feature = "esp32c2")]
             Chip::Esp32c2 => Box::new(Esp32c2),
+            #[cfg(feature = "esp32c3")]
             Chip::Esp32c3 => Box::new(Esp32c3),
+            #[cfg(feature = "esp32c6")]
             Chip::Esp32c6 => Box::new(Esp32c6),
+            #[cfg(feature = "esp32h2")]
             Chip::Esp32h2 => Box::new(Esp32h2),
+            #[cfg(feature = "esp32p4")]
             Chip::Esp32p4 => Box::new(Esp32p4),
+            #[cfg(feature = "esp32s2")]
             Chip::Esp32s2 => Box::new(Esp32s2),
+            #[cfg(feature = "esp32s3")]
             Chip::Esp32s3 => Box::new(Esp32s3),
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => Box::new(Esp8266),
+        }
+    }
+
+    /// Is the chip based on the Xtensa architecture, rather than RISC-V?
+    #[allow(unreachable_patterns)]
+    pub fn is_xtensa(&self) -> bool {
+        match self {
+            #[cfg(feature = "esp32")]
+            Chip::Esp32 => true,
+            #[cfg(feature = "esp32s2")]
+            Chip::Esp32s2 => true,
+            #[cfg(feature = "esp32s3")]
+            Chip::Esp32s3 => true,
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => true,
+            _ => false,
+        }
+    }
+
+    /// Is the chip the ESP32?
+    #[allow(unreachable_patterns)]
+    pub(crate) fn is_esp32(&self) -> bool {
+        match self {
+            #[cfg(feature = "esp32")]
+            Chip::Esp32 => true,
+            _ => false,
+        }
+    }
+
+    /// Is the chip the ESP8266, whose ROM loader lacks many of the commands
+    /// of the ESP32 family?
+    #[allow(unreachable_patterns)]
+    pub(crate) fn is_esp8266(&self) -> bool {
+        match self {
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => true,
+            _ => false,
+        }
+    }
+
+    /// Can the chip boot from octal flash?
+    #[allow(unreachable_patterns)]
+    pub(crate) fn supports_octal_flash(&self) -> bool {
+        match self {
+            #[cfg(feature = "esp32s3")]
+            Chip::Esp32s3 => true,
+            _ => false,
+        }
+    }
+
+    /// Does the ROM bootloader assume a 40 MHz crystal, whichever crystal is
+    /// fitted?
+    ///
+    /// The baud rates used by the ROM are then scaled when a different crystal
+    /// is fitted, which is the case for the ESP32-C2 with a 26 MHz crystal.
+    #[allow(unreachable_patterns)]
+    pub fn rom_assumes_40mhz_xtal(&self) -> bool {
+        match self {
+            #[cfg(feature = "esp32c2")]
+            Chip::Esp32c2 => true,
+            _ => false,
+        }
+    }
+
+    /// The UF2 family ID of the chip
+    ///
+    /// https://github.com/microsoft/uf2/blob/master/utils/uf2families.json
+    pub fn uf2_family_id(&self) -> u32 {
+        match self {
+            #[cfg(feature = "esp32")]
+            Chip::Esp32 => 0x1c5f_21b0,
+            #[cfg(feature = "esp32c2")]
+            Chip::Esp32c2 => 0x2b88_d29c,
+            #[cfg(feature = "esp32c3")]
+            Chip::Esp32c3 => 0xd42b_a06c,
+            #[cfg(feature = "esp32c6")]
+            Chip::Esp32c6 => 0x540d_df62,
+            #[cfg(feature = "esp32h2")]
+            Chip::Esp32h2 => 0x3327_26f6,
+            #[cfg(feature = "esp32p4")]
+            Chip::Esp32p4 => 0x3d30_8e94,
+            #[cfg(feature = "esp32s2")]
+            Chip::Esp32s2 => 0xbfdd_4eee,
+            #[cfg(feature = "esp32s3")]
+            Chip::Esp32s3 => 0xc47e_5767,
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => 0x7eab_61ed,
+        }
+    }
+
+    /// Address at which the second-stage bootloader from ESP-IDF is written
+    ///
+    /// Returns `None` for chips which do not use it.
+    #[allow(unreachable_patterns)]
+    pub fn bootloader_addr(&self) -> Option<u32> {
+        match self {
+            #[cfg(feature = "esp32")]
+            Chip::Esp32 => Some(0x1000),
+            #[cfg(feature = "esp32s2")]
+            Chip::Esp32s2 => Some(0x1000),
+            #[cfg(feature = "esp32p4")]
+            Chip::Esp32p4 => Some(0x2000),
+            #[cfg(feature = "esp8266")]
+            Chip::Esp8266 => None,
+            _ => Some(0x0),
         }
     }
 
@@ -150,8 +493,138 @@ impl Chip {
         use_stub: bool,
         verify: bool,
         skip: bool,
+        compress: bool,
     ) -> Box<dyn FlashTarget> {
-        Box::new(Esp32Target::new(*self, spi_params, use_stub, verify, skip))
+        Box::new(Esp32Target::new(
+            *self, spi_params, use_stub, verify, skip, compress,
+        ))
+    }
+
+    /// Build an image in the format selected by `flash_data`, after checking
+    /// that the format is supported by the chip
+    pub fn get_flash_image<'a>(
+        &self,
+        image: &'a dyn FirmwareImage<'a>,
+        mut flash_data: FlashData,
+        chip_revision: Option<(u32, u32)>,
+        xtal_freq: XtalFrequency,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        let target = self.into_target();
+
+        check_compatible_chip(image, *self)?;
+        image.check_segments(*self)?;
+
+        // Zephyr and NuttX applications are not booted by the ESP-IDF bootloader,
+        // so unless another format was selected, use the one matching how they
+        // are booted
+        if flash_data.image_format == ImageFormatKind::EspBootloader {
+            if let Some(os) = flash_data.os.or_else(|| image.os()) {
+                flash_data.image_format = os.image_format(image);
+                if flash_data.image_format != ImageFormatKind::EspBootloader {
+                    info!(
+                        "Using the {} image format for the {os} application",
+                        flash_data.image_format
+                    );
+                }
+            }
+        }
+
+        if !target.supports_image_format(flash_data.image_format) {
+            return Err(Error::UnsupportedImageFormat {
+                chip: *self,
+                format: flash_data.image_format,
+            });
+        }
+
+        #[cfg(not(feature = "secure-boot"))]
+        if flash_data.sign_key.is_some() || flash_data.sign_command.is_some() {
+            return Err(Error::FeatureDisabled(
+                "Signing images for Secure Boot",
+                "secure-boot",
+            ));
+        }
+        #[cfg(not(feature = "flash-encryption"))]
+        if flash_data.encryption_key.is_some() {
+            return Err(Error::FeatureDisabled(
+                "Encrypting images",
+                "flash-encryption",
+            ));
+        }
+
+        // Only images booted by the ESP-IDF bootloader are signed for Secure Boot
+        #[cfg(feature = "secure-boot")]
+        let signer: Option<Box<dyn SecureBootSigner>> =
+            match (&flash_data.sign_key, &flash_data.sign_command) {
+                (Some(pem), _) => Some(Box::new(SecureBootKey::from_pem(pem)?)),
+                (None, Some((command, public_key))) => Some(Box::new(ExternalSigner::new(
+                    command.clone(),
+                    SecureBootPublicKey::from_pem(public_key)?,
+                )?)),
+                (None, None) => None,
+            };
+        #[cfg(feature = "secure-boot")]
+        if let Some(signer) = &signer {
+            if flash_data.image_format != ImageFormatKind::EspBootloader {
+                return Err(Error::UnsupportedFeature {
+                    chip: *self,
+                    feature: format!("Secure Boot signing of {} images", flash_data.image_format),
+                });
+            }
+
+            signer.public_key().check_chip(*self)?;
+        }
+
+        let skip_bootloader = flash_data.skip_bootloader;
+        let skip_partition_table = flash_data.skip_partition_table;
+        let skip_partition_table_md5 = flash_data.skip_partition_table_md5;
+        let partition_files = flash_data.partition_files.clone();
+        #[cfg(feature = "flash-encryption")]
+        let encryption_key = flash_data.encryption_key.clone();
+        let max_partition_usage = flash_data.max_partition_usage;
+
+        let mut image = target.get_flash_image(image, flash_data, chip_revision, xtal_freq)?;
+        // The partition table must be complete before it is encrypted
+        if skip_partition_table_md5 {
+            image.skip_partition_table_md5();
+        }
+        #[cfg(feature = "secure-boot")]
+        if let Some(signer) = signer {
+            let bootloader_addr = self.bootloader_addr().unwrap_or_default();
+            image = Box::new(SecureBootSigned::new(
+                image,
+                signer.as_ref(),
+                bootloader_addr,
+            )?);
+        }
+        if !partition_files.is_empty() {
+            image = Box::new(WithPartitionFiles::new(image, partition_files)?);
+        }
+        // Encryption comes last, as signatures are made over the plaintext
+        #[cfg(feature = "flash-encryption")]
+        if let Some(key) = encryption_key {
+            image = Box::new(FlashEncrypted::new(image, &key)?);
+        }
+        if skip_bootloader {
+            image.skip_bootloader();
+        }
+        if skip_partition_table {
+            image.skip_partition_table();
+        }
+
+        if let Some(limit) = max_partition_usage {
+            if let Some(usage) = partition_usage(image.as_ref())
+                .into_iter()
+                .find(|usage| usage.percent() > limit)
+            {
+                return Err(Error::PartitionUsageExceeded {
+                    label: usage.label.clone(),
+                    percent: usage.percent(),
+                    limit,
+                });
+            }
+        }
+
+        Ok(image)
     }
 
     #[cfg(feature = "serialport")]
@@ -241,6 +714,28 @@ impl Esp32Params {
     }
 }
 
+/// RTC watchdog timer register addresses
+pub struct RtcWdtRegisters {
+    base: u32,
+    config0_offset: u32,
+    config1_offset: u32,
+    write_protect_offset: u32,
+}
+
+impl RtcWdtRegisters {
+    pub fn config0(&self) -> u32 {
+        self.base + self.config0_offset
+    }
+
+    pub fn config1(&self) -> u32 {
+        self.base + self.config1_offset
+    }
+
+    pub fn write_protect(&self) -> u32 {
+        self.base + self.write_protect_offset
+    }
+}
+
 /// SPI register addresses
 pub struct SpiRegisters {
     base: u32,
@@ -304,6 +799,24 @@ pub trait Target: ReadEFuse {
     /// Enumerate the chip's features, read from eFuse
     fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error>;
 
+    #[cfg(feature = "serialport")]
+    /// PSRAM embedded in the chip's package, as recorded in its eFuses
+    ///
+    /// PSRAM connected to the chip externally cannot be detected without
+    /// initializing the PSRAM controller, so is not reported.
+    fn psram_info(&self, _connection: &mut Connection) -> Result<Option<PsramInfo>, Error> {
+        Ok(None)
+    }
+
+    #[cfg(feature = "serialport")]
+    /// Flash embedded in the chip's package, as recorded in its eFuses
+    fn embedded_flash_info(
+        &self,
+        _connection: &mut Connection,
+    ) -> Result<Option<EmbeddedFlashInfo>, Error> {
+        Ok(None)
+    }
+
     #[cfg(feature = "serialport")]
     /// Determine the chip's revision number
     fn chip_revision(&self, connection: &mut Connection) -> Result<(u32, u32), Error> {
@@ -338,14 +851,18 @@ pub trait Target: ReadEFuse {
         Ok(FLASH_WRITE_SIZE)
     }
 
-    /// Build an image from the provided data for flashing
+    /// Build an image from the provided data for flashing, in the format
+    /// selected by `flash_data`
+    ///
+    /// The format must be one of [Target::supported_image_formats], as checked
+    /// by [Chip::get_flash_image].
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error>;
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error>;
 
     #[cfg(feature = "serialport")]
     /// What is the MAC address?
@@ -366,6 +883,18 @@ pub trait Target: ReadEFuse {
         Ok(MAX_RAM_BLOCK_SIZE)
     }
 
+    /// Register address and value which override the flash supply voltage, if
+    /// supported by the chip
+    fn flash_voltage_override(&self, _voltage: FlashVoltage) -> Option<(u32, u32)> {
+        None
+    }
+
+    /// RTC watchdog timer register addresses for a chip, if it can be reset
+    /// using the watchdog
+    fn rtc_wdt_registers(&self) -> Option<RtcWdtRegisters> {
+        None
+    }
+
     /// SPI register addresses for a chip
     fn spi_registers(&self) -> SpiRegisters;
 
@@ -376,6 +905,90 @@ pub trait Target: ReadEFuse {
     fn supports_build_target(&self, target: &str) -> bool {
         self.supported_build_targets().contains(&target)
     }
+
+    /// Image formats supported by a chip
+    fn supported_image_formats(&self) -> &[ImageFormatKind] {
+        &[
+            ImageFormatKind::EspBootloader,
+            ImageFormatKind::Mcuboot,
+            ImageFormatKind::Raw,
+            ImageFormatKind::SimpleBoot,
+        ]
+    }
+
+    /// Is the image format `format` supported by the chip?
+    fn supports_image_format(&self, format: ImageFormatKind) -> bool {
+        self.supported_image_formats().contains(&format)
+    }
+}
+
+/// Ensure that a firmware image may have been built for the chip
+pub(crate) fn check_compatible_chip<'a>(
+    image: &'a dyn FirmwareImage<'a>,
+    chip: Chip,
+) -> Result<(), Error> {
+    let compatible = image.compatible_chips();
+    if compatible.contains(&chip) {
+        return Ok(());
+    }
+
+    Err(Error::ElfChipMismatch {
+        chip,
+        compatible: compatible
+            .iter()
+            .map(|chip| chip.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    })
+}
+
+/// Build an image for an ESP32-series chip in the format selected by
+/// `flash_data`, which is one of the chip's supported image formats
+pub(crate) fn build_flash_image<'a>(
+    chip: Chip,
+    params: Esp32Params,
+    image: &'a dyn FirmwareImage<'a>,
+    flash_data: FlashData,
+) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+    let image: Box<dyn ImageFormat<'a> + 'a> = match flash_data.image_format {
+        ImageFormatKind::EspBootloader => Box::new(IdfBootloaderFormat::new(
+            image,
+            chip,
+            flash_data.min_chip_rev,
+            params,
+            flash_data.partition_table,
+            flash_data.partition_table_offset,
+            flash_data.target_app_partition,
+            flash_data.bootloader,
+            flash_data.flash_settings,
+            flash_data.app_descriptor,
+        )?),
+        ImageFormatKind::DirectBoot => Box::new(DirectBootFormat::new(image, 0)?),
+        ImageFormatKind::Mcuboot => Box::new(McubootFormat::new(
+            image,
+            params,
+            flash_data.partition_table,
+            flash_data.target_app_partition,
+            flash_data.bootloader,
+            flash_data.mcuboot_key.as_deref(),
+        )?),
+        ImageFormatKind::Raw => Box::new(RawFormat::new(
+            image,
+            params,
+            flash_data.partition_table,
+            flash_data.target_app_partition,
+            flash_data.flash_settings,
+        )?),
+        ImageFormatKind::SimpleBoot => Box::new(SimpleBootFormat::new(
+            image,
+            chip,
+            flash_data.min_chip_rev,
+            params,
+            flash_data.flash_settings,
+        )?),
+    };
+
+    Ok(image)
 }
 
 #[cfg(feature = "serialport")]
@@ -0,0 +1,1144 @@
+//! Emulated target devices for integration testing
+//!
+//! [VirtualEsp] emulates a target device at the level of the serial protocol:
+//! it answers the commands of the ROM loader and of the flash stub, including
+//! syncing, reading and writing registers, loading the stub, and writing,
+//! erasing, reading and checksumming an in-memory flash image. The device is
+//! reset by the DTR and RTS lines like a development board, entering the ROM
+//! loader when IO0 is held low and otherwise running its application, which
+//! ignores the serial protocol.
+//!
+//! Connecting to [VirtualEsp::port] rather than a serial port lets espflash
+//! and the tools built on it run their operations end to end without any
+//! hardware:
+//!
+//! ```rust,no_run
+//! use espflash::{
+//!     flasher::{ConnectOptions, Flasher},
+//!     targets::Chip,
+//!     testing::VirtualEsp,
+//! };
+//!
+//! let esp = VirtualEsp::new(Chip::Esp32c3);
+//! let mut flasher = Flasher::connect(esp.port(), esp.port_info(), ConnectOptions::new())?;
+//!
+//! flasher.write_bin_to_flash(0x10000, b"firmware", None)?;
+//! assert_eq!(&esp.flash()[0x10000..][..8], b"firmware");
+//! # Ok::<(), espflash::error::Error>(())
+//! ```
+//!
+//! Registers read from the device hold zero unless set with
+//! [VirtualEsp::with_register], other than the chip detection register and
+//! those used to detect the flash.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use flate2::write::ZlibDecoder;
+use md5::{Digest, Md5};
+use serialport::UsbPortInfo;
+
+use crate::{
+    command::CommandType,
+    connection::{transport::FlasherTransport, Port},
+    error::RomErrorKind,
+    flasher::{
+        stubs::{CHIP_DETECT_MAGIC_REG_ADDR, EXPECTED_STUB_HANDSHAKE, FLASH_SECTOR_SIZE},
+        FlashSize,
+    },
+    targets::Chip,
+};
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Initial value of the checksum of data blocks
+const CHECKSUM_INIT: u8 = 0xEF;
+
+/// JEDEC manufacturer and device ID reported by the emulated flash chip
+const FLASH_ID: u32 = 0x40EF;
+
+/// Bit of the SPI command register which starts a user-defined command
+const SPI_CMD_USR: u32 = 1 << 18;
+
+/// What an emulated target device is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VirtualState {
+    /// The ROM loader (download mode)
+    RomLoader,
+    /// The flash stub, once loaded by the ROM loader
+    Stub,
+    /// The application in flash, which ignores the serial protocol
+    Application,
+}
+
+/// An emulated target device
+///
+/// Clones share the same device, so a clone kept by a test can inspect the
+/// flash contents once espflash has operated on the device.
+#[derive(Clone)]
+pub struct VirtualEsp {
+    device: Arc<Mutex<Device>>,
+}
+
+impl VirtualEsp {
+    /// Emulate a device with the given chip and 4MB of erased flash, which is
+    /// waiting in the ROM loader
+    pub fn new(chip: Chip) -> Self {
+        Self {
+            device: Arc::new(Mutex::new(Device::new(chip, FlashSize::_4Mb))),
+        }
+    }
+
+    /// Replace the flash with erased flash of the given size
+    pub fn with_flash_size(self, size: FlashSize) -> Self {
+        {
+            let mut device = self.lock();
+            device.flash_size = size;
+            device.flash = vec![0xFF; size.size() as usize];
+        }
+
+        self
+    }
+
+    /// Set the value read from a register, such as an eFuse
+    pub fn with_register(self, address: u32, value: u32) -> Self {
+        self.lock().registers.insert(address, value);
+        self
+    }
+
+    /// The emulated chip
+    pub fn chip(&self) -> Chip {
+        self.lock().chip
+    }
+
+    /// What the device is running
+    pub fn state(&self) -> VirtualState {
+        self.lock().state
+    }
+
+    /// Copy of the contents of the flash
+    pub fn flash(&self) -> Vec<u8> {
+        self.lock().flash.clone()
+    }
+
+    /// Overwrite the flash at the given offset, as if it had been programmed
+    /// beforehand
+    ///
+    /// # Panics
+    ///
+    /// Panics if the data does not fit in the flash.
+    pub fn write_flash(&self, offset: u32, data: &[u8]) {
+        let offset = offset as usize;
+        self.lock().flash[offset..][..data.len()].copy_from_slice(data);
+    }
+
+    /// Value of a register
+    pub fn register(&self, address: u32) -> u32 {
+        self.lock().read_register(address)
+    }
+
+    /// Make the device write data to its serial port, such as the output of
+    /// its application
+    pub fn write_serial(&self, data: &[u8]) {
+        self.lock().output.extend(data);
+    }
+
+    /// Serial port connected to the device
+    pub fn port(&self) -> Port {
+        Box::new(VirtualPort {
+            device: self.device.clone(),
+            baud: 115_200,
+            timeout: Duration::from_secs(3),
+        })
+    }
+
+    /// USB information of the serial port connected to the device, as if it
+    /// were a USB-to-serial bridge
+    pub fn port_info(&self) -> UsbPortInfo {
+        UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: Some("Virtual ESP".into()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Device> {
+        self.device
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Transfer in progress on an emulated device
+enum Transfer {
+    /// Writing to flash, decompressing the data first if a decoder is given
+    Flash {
+        offset: u32,
+        written: u32,
+        decoder: Option<ZlibDecoder<Vec<u8>>>,
+    },
+    /// Sending a region of flash to the host
+    Read {
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        sent: u32,
+    },
+}
+
+/// State of an emulated device
+struct Device {
+    chip: Chip,
+    flash_size: FlashSize,
+    flash: Vec<u8>,
+    registers: HashMap<u32, u32>,
+    state: VirtualState,
+    transfer: Option<Transfer>,
+    /// Frame being received from the host
+    frame: Vec<u8>,
+    escaped: bool,
+    /// Data waiting to be read by the host
+    output: VecDeque<u8>,
+    rts: bool,
+    /// Was IO0 pulled low since the chip was last reset?
+    io0_low: bool,
+}
+
+impl Device {
+    fn new(chip: Chip, flash_size: FlashSize) -> Self {
+        Self {
+            chip,
+            flash_size,
+            flash: vec![0xFF; flash_size.size() as usize],
+            registers: HashMap::from([(CHIP_DETECT_MAGIC_REG_ADDR, magic_value(chip))]),
+            state: VirtualState::RomLoader,
+            transfer: None,
+            frame: Vec::new(),
+            escaped: false,
+            output: VecDeque::new(),
+            rts: false,
+            io0_low: false,
+        }
+    }
+
+    /// Set the DTR line, which drives IO0 low when asserted
+    fn set_dtr(&mut self, level: bool) {
+        if level {
+            self.io0_low = true;
+        }
+    }
+
+    /// Set the RTS line, which holds the chip in reset when asserted
+    fn set_rts(&mut self, level: bool) {
+        if self.rts && !level {
+            self.boot(self.io0_low);
+            self.io0_low = false;
+        }
+        self.rts = level;
+    }
+
+    /// Reset the chip, entering the ROM loader or running the application
+    fn boot(&mut self, download: bool) {
+        self.transfer = None;
+        self.frame.clear();
+
+        if download {
+            self.state = VirtualState::RomLoader;
+            self.output.extend(
+                b"ESP-ROM:virtual\r\nrst:0x1 (POWERON),boot:0x3 (DOWNLOAD(USB/UART0))\r\n\
+                  waiting for download\r\n",
+            );
+        } else {
+            self.state = VirtualState::Application;
+            self.output
+                .extend(b"ESP-ROM:virtual\r\nrst:0x1 (POWERON),boot:0x8 (SPI_FAST_FLASH_BOOT)\r\n");
+        }
+    }
+
+    /// Receive data from the host, handling each complete frame
+    fn receive(&mut self, data: &[u8]) {
+        for &byte in data {
+            match (self.escaped, byte) {
+                (false, END) => {
+                    if !self.frame.is_empty() {
+                        let frame = std::mem::take(&mut self.frame);
+                        self.handle_frame(&frame);
+                    }
+                }
+                (false, ESC) => self.escaped = true,
+                (true, ESC_END) => {
+                    self.frame.push(END);
+                    self.escaped = false;
+                }
+                (true, ESC_ESC) => {
+                    self.frame.push(ESC);
+                    self.escaped = false;
+                }
+                (_, byte) => {
+                    self.frame.push(byte);
+                    self.escaped = false;
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        if self.state == VirtualState::Application {
+            return;
+        }
+
+        // The host acknowledges each block of flash it reads
+        if frame.len() == 4 {
+            if let Some(Transfer::Read { .. }) = self.transfer {
+                let acked = u32::from_le_bytes(frame.try_into().unwrap());
+                self.send_read_block(acked);
+            }
+            return;
+        }
+
+        // Requests start with a direction byte of zero
+        if frame.len() < 8 || frame[0] != 0 {
+            return;
+        }
+
+        let op = frame[1];
+        let len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+        let checksum = u32::from_le_bytes(frame[4..8].try_into().unwrap());
+        let data = &frame[8..];
+
+        if data.len() != len {
+            self.respond_error(op, RomErrorKind::BadDataLen);
+            return;
+        }
+
+        if let Err(kind) = self.execute(op, checksum, data) {
+            self.respond_error(op, kind);
+        }
+    }
+
+    fn execute(&mut self, op: u8, checksum: u32, data: &[u8]) -> Result<(), RomErrorKind> {
+        let is_stub = self.state == VirtualState::Stub;
+
+        match CommandType::from(op) {
+            CommandType::Sync => {
+                // The ROM loader answers a sync request several times
+                for _ in 0..8 {
+                    self.respond(op, 0, &[]);
+                }
+            }
+            CommandType::ReadReg => {
+                let value = self.read_register(word(data, 0)?);
+                self.respond(op, value, &[]);
+            }
+            CommandType::WriteReg => {
+                for params in data.chunks(16) {
+                    self.write_register(word(params, 0)?, word(params, 1)?, word(params, 2)?);
+                }
+                self.respond(op, 0, &[]);
+            }
+            CommandType::MemBegin => self.respond(op, 0, &[]),
+            CommandType::MemData => {
+                block(data, checksum)?;
+                self.respond(op, 0, &[]);
+            }
+            CommandType::MemEnd => {
+                self.respond(op, 0, &[]);
+
+                // Jumping to the entry point of the flash stub starts it
+                if word(data, 0)? == 0 {
+                    self.state = VirtualState::Stub;
+                    self.send_frame(EXPECTED_STUB_HANDSHAKE.as_bytes());
+                }
+            }
+            CommandType::SpiAttach | CommandType::SpiSetParams | CommandType::ChangeBaudrate => {
+                self.respond(op, 0, &[]);
+            }
+            CommandType::FlashBegin | CommandType::FlashDeflBegin => {
+                let size = word(data, 0)?;
+                let offset = word(data, 3)?;
+                self.erase(offset, size)?;
+
+                let decoder = matches!(CommandType::from(op), CommandType::FlashDeflBegin)
+                    .then(|| ZlibDecoder::new(Vec::new()));
+                self.transfer = Some(Transfer::Flash {
+                    offset,
+                    written: 0,
+                    decoder,
+                });
+                self.respond(op, 0, &[]);
+            }
+            CommandType::FlashData | CommandType::FlashDeflData => {
+                let payload = block(data, checksum)?;
+                let Some(Transfer::Flash {
+                    offset,
+                    written,
+                    decoder,
+                }) = self.transfer.as_mut()
+                else {
+                    return Err(RomErrorKind::FailedToAct);
+                };
+
+                let payload = match decoder {
+                    Some(decoder) => {
+                        decoder
+                            .write_all(payload)
+                            .and_then(|_| decoder.flush())
+                            .map_err(|_| RomErrorKind::DeflateError)?;
+                        std::mem::take(decoder.get_mut())
+                    }
+                    None => payload.to_vec(),
+                };
+
+                let address = *offset + *written;
+                *written += payload.len() as u32;
+                self.program(address, &payload)?;
+                self.respond(op, 0, &[]);
+            }
+            CommandType::FlashEnd | CommandType::FlashDeflEnd => {
+                self.transfer = None;
+                self.respond(op, 0, &[]);
+
+                // A parameter of zero reboots the chip, while one makes the ROM
+                // loader run the application
+                match data.first() {
+                    Some(0) => self.boot(true),
+                    Some(_)
+                        if !is_stub && matches!(CommandType::from(op), CommandType::FlashEnd) =>
+                    {
+                        self.state = VirtualState::Application;
+                    }
+                    _ => {}
+                }
+            }
+            CommandType::FlashMd5 if is_stub || !self.chip.is_esp8266() => {
+                let offset = word(data, 0)?;
+                let size = word(data, 1)?;
+                let digest = Md5::digest(self.region(offset, size)?);
+
+                // The ROM loader sends the digest as hexadecimal text
+                if is_stub {
+                    self.respond(op, 0, &digest);
+                } else {
+                    let text = format!("{:032x}", u128::from_be_bytes(digest.into()));
+                    self.respond(op, 0, text.as_bytes());
+                }
+            }
+            CommandType::EraseFlash if is_stub => {
+                self.flash.fill(0xFF);
+                self.respond(op, 0, &[]);
+            }
+            CommandType::EraseRegion if is_stub => {
+                let offset = word(data, 0)?;
+                let size = word(data, 1)?;
+                if offset % FLASH_SECTOR_SIZE as u32 != 0 || size % FLASH_SECTOR_SIZE as u32 != 0 {
+                    return Err(RomErrorKind::BadDataLen);
+                }
+
+                self.erase(offset, size)?;
+                self.respond(op, 0, &[]);
+            }
+            CommandType::ReadFlash if is_stub => {
+                let offset = word(data, 0)?;
+                let size = word(data, 1)?;
+                let block_size = word(data, 2)?;
+                self.region(offset, size)?;
+                if block_size == 0 {
+                    return Err(RomErrorKind::BadBlocksize);
+                }
+
+                self.respond(op, 0, &[]);
+                self.transfer = Some(Transfer::Read {
+                    offset,
+                    size,
+                    block_size,
+                    sent: 0,
+                });
+                self.send_read_block(0);
+            }
+            CommandType::RunUserCode if is_stub => {
+                self.respond(op, 0, &[]);
+                self.state = VirtualState::Application;
+            }
+            _ if is_stub => return Err(RomErrorKind::InvalidCommand),
+            _ => return Err(RomErrorKind::InvalidMessage),
+        }
+
+        Ok(())
+    }
+
+    fn read_register(&self, address: u32) -> u32 {
+        self.registers.get(&address).copied().unwrap_or_default()
+    }
+
+    fn write_register(&mut self, address: u32, value: u32, mask: u32) {
+        let old = self.read_register(address);
+        let value = (old & !mask) | (value & mask);
+        self.registers.insert(address, value);
+
+        let spi = self.chip.into_target().spi_registers();
+        if address == spi.cmd() && value & SPI_CMD_USR != 0 {
+            // Run the SPI flash command, which completes immediately
+            let result = match self.read_register(spi.usr2()) as u8 {
+                op if op == CommandType::FlashDetect as u8 => {
+                    FLASH_ID | self.flash_size.size().trailing_zeros() << 16
+                }
+                _ => 0xFFFF_FFFF,
+            };
+            self.registers.insert(spi.w0(), result);
+            self.registers.insert(address, value & !SPI_CMD_USR);
+        }
+    }
+
+    /// Region of flash, if it lies within the flash
+    fn region(&self, offset: u32, size: u32) -> Result<&[u8], RomErrorKind> {
+        self.flash
+            .get(offset as usize..)
+            .and_then(|flash| flash.get(..size as usize))
+            .ok_or(RomErrorKind::FlashReadLengthError)
+    }
+
+    /// Erase the sectors overlapping a region of flash
+    fn erase(&mut self, offset: u32, size: u32) -> Result<(), RomErrorKind> {
+        if size == 0 {
+            return Ok(());
+        }
+
+        let sector = FLASH_SECTOR_SIZE as u32;
+        let start = offset / sector * sector;
+        let end = (offset + size).div_ceil(sector) * sector;
+
+        self.flash
+            .get_mut(start as usize..end as usize)
+            .ok_or(RomErrorKind::FlashWriteError)?
+            .fill(0xFF);
+
+        Ok(())
+    }
+
+    /// Program data into flash, which like NOR flash can only clear bits
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), RomErrorKind> {
+        let flash = self
+            .flash
+            .get_mut(offset as usize..)
+            .and_then(|flash| flash.get_mut(..data.len()))
+            .ok_or(RomErrorKind::FlashWriteError)?;
+
+        for (byte, value) in flash.iter_mut().zip(data) {
+            *byte &= value;
+        }
+
+        Ok(())
+    }
+
+    /// Send the next block of flash being read, once the host has
+    /// acknowledged the data sent so far, followed by its MD5 digest
+    fn send_read_block(&mut self, acked: u32) {
+        let Some(Transfer::Read {
+            offset,
+            size,
+            block_size,
+            sent,
+        }) = self.transfer
+        else {
+            return;
+        };
+
+        if acked < sent {
+            return;
+        }
+
+        if sent < size {
+            let len = block_size.min(size - sent);
+            let block = self.flash[(offset + sent) as usize..][..len as usize].to_vec();
+            self.send_frame(&block);
+            self.transfer = Some(Transfer::Read {
+                offset,
+                size,
+                block_size,
+                sent: sent + len,
+            });
+        } else {
+            let digest = Md5::digest(&self.flash[offset as usize..][..size as usize]);
+            self.send_frame(&digest);
+            self.transfer = None;
+        }
+    }
+
+    /// Send a response to a command
+    fn respond(&mut self, op: u8, value: u32, data: &[u8]) {
+        let mut body = data.to_vec();
+        body.resize(data.len() + self.status_len(), 0);
+        self.send_response(op, value, &body);
+    }
+
+    /// Send a response reporting that a command failed
+    fn respond_error(&mut self, op: u8, kind: RomErrorKind) {
+        let mut body = vec![1, kind as u8];
+        body.resize(self.status_len(), 0);
+        self.send_response(op, 0, &body);
+    }
+
+    fn send_response(&mut self, op: u8, value: u32, body: &[u8]) {
+        let mut frame = vec![1, op];
+        frame.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&value.to_le_bytes());
+        frame.extend_from_slice(body);
+        self.send_frame(&frame);
+    }
+
+    /// Number of status bytes ending each response
+    fn status_len(&self) -> usize {
+        if self.state == VirtualState::Stub || self.chip.is_esp8266() {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Send a SLIP frame to the host
+    fn send_frame(&mut self, data: &[u8]) {
+        self.output.push_back(END);
+        for &byte in data {
+            match byte {
+                END => self.output.extend([ESC, ESC_END]),
+                ESC => self.output.extend([ESC, ESC_ESC]),
+                _ => self.output.push_back(byte),
+            }
+        }
+        self.output.push_back(END);
+    }
+}
+
+/// Little-endian word of the parameters of a command
+fn word(data: &[u8], index: usize) -> Result<u32, RomErrorKind> {
+    data.get(index * 4..)
+        .and_then(|data| data.get(..4))
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(RomErrorKind::BadDataLen)
+}
+
+/// Data of a block sent to the device, after checking its size and checksum
+fn block(data: &[u8], checksum: u32) -> Result<&[u8], RomErrorKind> {
+    let size = word(data, 0)? as usize;
+    let payload = data.get(16..).ok_or(RomErrorKind::BadDataLen)?;
+    if payload.len() != size {
+        return Err(RomErrorKind::BadDataLen);
+    }
+
+    let expected = payload
+        .iter()
+        .fold(CHECKSUM_INIT, |check, byte| check ^ byte);
+    if checksum != expected as u32 {
+        return Err(RomErrorKind::BadDataChecksum);
+    }
+
+    Ok(payload)
+}
+
+/// Value of the chip detection register of a chip
+fn magic_value(chip: Chip) -> u32 {
+    match chip {
+        #[cfg(feature = "esp32")]
+        Chip::Esp32 => 0x00f0_1d83,
+        #[cfg(feature = "esp32c2")]
+        Chip::Esp32c2 => 0x7c41_a06f,
+        #[cfg(feature = "esp32c3")]
+        Chip::Esp32c3 => 0x1b31_506f,
+        #[cfg(feature = "esp32c6")]
+        Chip::Esp32c6 => 0x2ce0_806f,
+        #[cfg(feature = "esp32h2")]
+        Chip::Esp32h2 => 0xd7b7_3e80,
+        #[cfg(feature = "esp32p4")]
+        Chip::Esp32p4 => 0x0add_bad0,
+        #[cfg(feature = "esp32s2")]
+        Chip::Esp32s2 => 0x0000_07c6,
+        #[cfg(feature = "esp32s3")]
+        Chip::Esp32s3 => 0x0000_0009,
+        #[cfg(feature = "esp8266")]
+        Chip::Esp8266 => 0xfff0_c101,
+    }
+}
+
+/// Serial port connected to a [VirtualEsp]
+#[derive(Clone)]
+pub struct VirtualPort {
+    device: Arc<Mutex<Device>>,
+    baud: u32,
+    timeout: Duration,
+}
+
+impl VirtualPort {
+    fn lock(&self) -> MutexGuard<'_, Device> {
+        self.device
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for VirtualPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut device = self.lock();
+        if device.output.is_empty() {
+            // The device answers as soon as it receives a request, so nothing
+            // more will arrive before the timeout
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let len = buf.len().min(device.output.len());
+        for (byte, value) in buf.iter_mut().zip(device.output.drain(..len)) {
+            *byte = value;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Write for VirtualPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().receive(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FlasherTransport for VirtualPort {
+    fn name(&self) -> Option<String> {
+        Some("virtual".into())
+    }
+
+    fn baud_rate(&self) -> io::Result<u32> {
+        Ok(self.baud)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.baud = baud_rate;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> io::Result<()> {
+        self.lock().set_rts(level);
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+        self.lock().set_dtr(level);
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _enabled: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok(self.lock().output.len() as u32)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.lock().output.clear();
+        Ok(())
+    }
+}
+
+// The emulated device is an ESP32-C3 throughout
+#[cfg(all(test, feature = "esp32c3"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        connection::{
+            chaos::{ChaosConfig, ChaosPort},
+            reset::ResetAfterOperation,
+        },
+        error::Error,
+        flasher::{
+            CancellationToken, ConnectOptions, Flasher, ProgressCallbacks, ProgressEvent,
+            RetryPolicy, RESUME_CHUNK_SIZE,
+        },
+    };
+
+    fn connect(esp: &VirtualEsp, use_stub: bool) -> Flasher {
+        Flasher::connect(
+            esp.port(),
+            esp.port_info(),
+            ConnectOptions::new()
+                .with_stub(use_stub)
+                .with_after_operation(ResetAfterOperation::NoResetNoStub),
+        )
+        .unwrap()
+    }
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flashes_with_stub() {
+        let esp = VirtualEsp::new(Chip::Esp32c3);
+        let mut flasher = connect(&esp, true);
+        assert_eq!(flasher.chip(), Chip::Esp32c3);
+        assert_eq!(flasher.flash_size(), FlashSize::_4Mb);
+        assert_eq!(esp.state(), VirtualState::Stub);
+
+        let data = (0..0x5000).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(&esp.flash()[0x10000..][..data.len()], &data[..]);
+
+        let read = flasher
+            .read_flash_data(0x10000, 0x1800, 0x1000, 64)
+            .unwrap();
+        assert_eq!(read, data[..0x1800]);
+
+        flasher.erase_region(0x10000, 0x1000).unwrap();
+        assert!(esp.flash()[0x10000..0x11000].iter().all(|b| *b == 0xFF));
+        assert_eq!(
+            flasher.checksum_md5(0x11000, 0x1000).unwrap().to_be_bytes(),
+            Md5::digest(&data[0x1000..0x2000])[..]
+        );
+    }
+
+    #[test]
+    fn flashes_with_rom_loader() {
+        let esp = VirtualEsp::new(Chip::Esp32c3).with_flash_size(FlashSize::_8Mb);
+        let mut flasher = connect(&esp, false);
+        assert_eq!(flasher.flash_size(), FlashSize::_8Mb);
+        assert_eq!(esp.state(), VirtualState::RomLoader);
+
+        flasher
+            .write_bin_to_flash(0x2000, b"firmware", None)
+            .unwrap();
+        assert_eq!(&esp.flash()[0x2000..][..8], b"firmware");
+        assert!(flasher.read_flash_data(0x2000, 8, 0x1000, 64).is_err());
+
+        flasher.connection().reset().unwrap();
+        assert_eq!(esp.state(), VirtualState::Application);
+    }
+
+    #[test]
+    fn flashes_large_segments_identically() {
+        // Partly compressible data, spanning several compressed blocks
+        let data = random_data(0x60000)
+            .into_iter()
+            .enumerate()
+            .map(|(i, random)| {
+                if i % 0x2000 < 0x1000 {
+                    random
+                } else {
+                    (i / 0x100) as u8
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for use_stub in [true, false] {
+            let esp = VirtualEsp::new(Chip::Esp32c3);
+            let mut flasher = connect(&esp, use_stub);
+            flasher.write_bin_to_flash(0x20000, &data, None).unwrap();
+
+            let flash = esp.flash();
+            assert_eq!(&flash[0x20000..][..data.len()], &data[..]);
+            assert!(flash[..0x20000].iter().all(|b| *b == 0xFF));
+            assert!(flash[0x20000 + data.len()..].iter().all(|b| *b == 0xFF));
+        }
+    }
+
+    #[derive(Default)]
+    struct Events(Vec<ProgressEvent>);
+
+    impl ProgressCallbacks for Events {
+        fn init(&mut self, _addr: u32, _total: usize) {}
+        fn update(&mut self, _current: usize) {}
+        fn finish(&mut self) {}
+
+        fn event(&mut self, event: &ProgressEvent) {
+            self.0.push(event.clone());
+        }
+    }
+
+    #[test]
+    fn reports_progress_events() {
+        let esp = VirtualEsp::new(Chip::Esp32c3);
+        let mut flasher = connect(&esp, true);
+
+        let data = vec![0x5a; 0x100];
+        let mut events = Events::default();
+        flasher
+            .write_bin_to_flash(0x10000, &data, Some(&mut events))
+            .unwrap();
+        assert_eq!(
+            events.0,
+            [
+                ProgressEvent::SegmentStarted {
+                    addr: 0x10000,
+                    len: 0x100
+                },
+                ProgressEvent::BlockWritten {
+                    addr: 0x10000,
+                    current: 1,
+                    total: 1
+                },
+                ProgressEvent::VerifyStarted {
+                    addr: 0x10000,
+                    len: 0x100
+                },
+                ProgressEvent::SegmentFinished { addr: 0x10000 },
+                ProgressEvent::Finished,
+            ]
+        );
+
+        let mut events = Events::default();
+        flasher
+            .write_bin_to_flash(0x10000, &data, Some(&mut events))
+            .unwrap();
+        assert_eq!(events.0[1], ProgressEvent::SegmentSkipped { addr: 0x10000 });
+    }
+
+    /// Passes data between espflash and the device, other than the reads made
+    /// while faults are armed, which go through a [ChaosPort]
+    struct FaultyPort {
+        port: Port,
+        chaos: ChaosPort,
+        armed: Arc<AtomicUsize>,
+    }
+
+    impl Read for FaultyPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let armed = self
+                .armed
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |reads| {
+                    reads.checked_sub(1)
+                });
+
+            match armed {
+                Ok(_) => self.chaos.read(buf),
+                Err(_) => self.port.read(buf),
+            }
+        }
+    }
+
+    impl Write for FaultyPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.port.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.port.flush()
+        }
+    }
+
+    impl FlasherTransport for FaultyPort {
+        fn baud_rate(&self) -> io::Result<u32> {
+            self.port.baud_rate()
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            self.port.set_baud_rate(baud_rate)
+        }
+
+        fn timeout(&self) -> Duration {
+            self.port.timeout()
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.port.set_timeout(timeout)
+        }
+
+        fn set_rts(&mut self, level: bool) -> io::Result<()> {
+            self.port.set_rts(level)
+        }
+
+        fn set_dtr(&mut self, level: bool) -> io::Result<()> {
+            self.port.set_dtr(level)
+        }
+
+        fn clear_input(&mut self) -> io::Result<()> {
+            self.port.clear_input()
+        }
+    }
+
+    /// Arms faults for the given number of reads once a block of a segment has
+    /// been written, and records where the write then resumed, along with how
+    /// much of the segment was intact in the flash at that point
+    struct FaultAfterBlock {
+        esp: VirtualEsp,
+        data: Vec<u8>,
+        block: usize,
+        arm: Option<(Arc<AtomicUsize>, usize)>,
+        resumed: Vec<(usize, usize)>,
+    }
+
+    impl ProgressCallbacks for FaultAfterBlock {
+        fn init(&mut self, _addr: u32, _total: usize) {}
+        fn update(&mut self, _current: usize) {}
+        fn finish(&mut self) {}
+
+        fn event(&mut self, event: &ProgressEvent) {
+            match *event {
+                ProgressEvent::BlockWritten { current, .. } if current >= self.block => {
+                    if let Some((armed, reads)) = self.arm.take() {
+                        armed.store(reads, Ordering::SeqCst);
+                    }
+                }
+                ProgressEvent::Retrying {
+                    addr, resume_addr, ..
+                } => {
+                    let flash = self.esp.flash();
+                    let intact = self
+                        .data
+                        .chunks_exact(RESUME_CHUNK_SIZE)
+                        .zip(flash[addr as usize..].chunks_exact(RESUME_CHUNK_SIZE))
+                        .take_while(|(data, flash)| data == flash)
+                        .count()
+                        * RESUME_CHUNK_SIZE;
+                    self.resumed.push(((resume_addr - addr) as usize, intact));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Write a segment, injecting faults into the response to the command
+    /// following its 100th block
+    fn write_with_fault(
+        config: ChaosConfig,
+        reads: usize,
+        retry_policy: RetryPolicy,
+    ) -> (VirtualEsp, FaultAfterBlock, Result<(), Error>) {
+        let esp = VirtualEsp::new(Chip::Esp32c3);
+        let armed = Arc::new(AtomicUsize::new(0));
+        let port = FaultyPort {
+            port: esp.port(),
+            chaos: ChaosPort::new(esp.port(), config.with_seed(1)),
+            armed: armed.clone(),
+        };
+        let mut flasher = Flasher::connect(
+            Box::new(port),
+            esp.port_info(),
+            ConnectOptions::new().with_after_operation(ResetAfterOperation::NoResetNoStub),
+        )
+        .unwrap();
+        flasher.set_retry_policy(retry_policy);
+
+        let mut progress = FaultAfterBlock {
+            esp: esp.clone(),
+            data: random_data(0x40000),
+            block: 100,
+            arm: Some((armed, reads)),
+            resumed: Vec::new(),
+        };
+        let data = progress.data.clone();
+        let result = flasher.write_bin_to_flash(0x20000, &data, Some(&mut progress));
+
+        (esp, progress, result)
+    }
+
+    #[test]
+    fn resumes_write_after_lost_response() {
+        let config = ChaosConfig::default().with_drop(1.0);
+        let (esp, progress, result) =
+            write_with_fault(config, 1, RetryPolicy::none().with_retries(3));
+        result.unwrap();
+
+        // About 100KB were written before the fault, of which the whole chunks
+        // are not written again
+        assert_eq!(progress.resumed.len(), 1);
+        let (resumed, intact) = progress.resumed[0];
+        assert_eq!(resumed, intact);
+        assert!(resumed >= 3 * RESUME_CHUNK_SIZE);
+        assert_eq!(
+            &esp.flash()[0x20000..][..progress.data.len()],
+            &progress.data[..]
+        );
+    }
+
+    #[test]
+    fn resumes_write_after_corrupted_response() {
+        // Corrupting the start of the frame merges it into garbage, which is
+        // not recognised as the response
+        let config = ChaosConfig::default().with_corrupt(1.0);
+        let (esp, progress, result) =
+            write_with_fault(config, 1, RetryPolicy::none().with_retries(3));
+        result.unwrap();
+
+        assert_eq!(progress.resumed.len(), 1);
+        let (resumed, intact) = progress.resumed[0];
+        assert_eq!(resumed, intact);
+        assert!(resumed >= 3 * RESUME_CHUNK_SIZE);
+        assert_eq!(
+            &esp.flash()[0x20000..][..progress.data.len()],
+            &progress.data[..]
+        );
+    }
+
+    #[test]
+    fn gives_up_without_retries() {
+        let config = ChaosConfig::default().with_drop(1.0);
+        let (esp, progress, result) = write_with_fault(config, 1, RetryPolicy::none());
+
+        assert!(result.is_err());
+        assert!(progress.resumed.is_empty());
+        assert_ne!(
+            &esp.flash()[0x20000..][..progress.data.len()],
+            &progress.data[..]
+        );
+    }
+
+    #[test]
+    fn cancels_operations() {
+        let esp = VirtualEsp::new(Chip::Esp32c3);
+        let mut flasher = connect(&esp, true);
+
+        let token = CancellationToken::new();
+        flasher.set_cancellation_token(token.clone());
+        flasher
+            .write_bin_to_flash(0x10000, b"firmware", None)
+            .unwrap();
+        flasher.erase_flash().unwrap();
+        assert!(esp.flash().iter().all(|b| *b == 0xFF));
+
+        token.cancel();
+        assert!(matches!(
+            flasher.write_bin_to_flash(0x10000, b"firmware", None),
+            Err(Error::Cancelled)
+        ));
+        assert!(esp.flash()[0x10000..][..8].iter().all(|b| *b == 0xFF));
+        assert!(matches!(flasher.erase_flash(), Err(Error::Cancelled)));
+        assert!(matches!(
+            flasher.read_flash_data(0x10000, 0x1000, 0x1000, 64),
+            Err(Error::Cancelled)
+        ));
+    }
+}